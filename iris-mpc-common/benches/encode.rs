@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use iris_mpc_common::{
+    galois_engine::degree4::{encode_iris_codes_batch, GaloisRingIrisCodeShare},
+    iris_db::iris::IrisCode,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_iris_codes");
+    group.sample_size(10);
+
+    for batch_size in [64_usize, 1024] {
+        let mut rng = StdRng::seed_from_u64(0);
+        let irises = (0..batch_size)
+            .map(|_| IrisCode::random_rng(&mut rng))
+            .collect::<Vec<_>>();
+
+        group.bench_function(BenchmarkId::new("sequential", batch_size), |b| {
+            b.iter(|| {
+                for iris in &irises {
+                    black_box(GaloisRingIrisCodeShare::encode_iris_code(
+                        &iris.code, &iris.mask, &mut rng,
+                    ));
+                }
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("batch", batch_size), |b| {
+            b.iter(|| black_box(encode_iris_codes_batch(&irises, &mut rng)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use iris_mpc_common::{
+    galois::degree4::ShamirGaloisRingShare,
+    galois_engine::degree4::{lagrange_coeffs, GaloisRingIrisCodeShare},
+    id::PartyID,
+    iris_db::iris::IrisCode,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn bench_lagrange_coeffs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lagrange_coeffs");
+    group.bench_function("fresh", |b| {
+        b.iter(|| {
+            black_box((
+                ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID0, PartyID::ID1),
+                ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID1, PartyID::ID0),
+            ))
+        })
+    });
+    group.bench_function("cached", |b| {
+        b.iter(|| black_box(lagrange_coeffs(&[PartyID::ID0, PartyID::ID1])))
+    });
+    group.finish();
+}
+
+fn bench_reconstruct(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reconstruct");
+    let mut rng = StdRng::seed_from_u64(0);
+    // Many small rows, like the upgrade tooling processes them.
+    let shares = (0..64)
+        .map(|_| {
+            let iris = IrisCode::random_rng(&mut rng);
+            GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, &mut rng)
+        })
+        .collect::<Vec<_>>();
+    group.bench_function("reconstruct_mask", |b| {
+        b.iter(|| {
+            for shares in &shares {
+                black_box(GaloisRingIrisCodeShare::reconstruct_mask(shares).unwrap());
+            }
+        })
+    });
+    group.bench_function("preprocess_query", |b| {
+        b.iter(|| {
+            for shares in &shares {
+                let mut share = shares[0].clone();
+                share.preprocess_iris_code_query_share();
+                black_box(share);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_lagrange_coeffs, bench_reconstruct);
+criterion_main!(benches);
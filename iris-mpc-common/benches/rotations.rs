@@ -0,0 +1,46 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use iris_mpc_common::{
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    iris_db::iris::IrisCode,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+/// The query preprocessing of a batch entry, like the server does it for the
+/// given rotation window.
+fn bench_query_rotations(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query_rotations");
+    let mut rng = StdRng::seed_from_u64(0);
+    let iris = IrisCode::random_rng(&mut rng);
+    let mut code =
+        GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)[0].clone();
+    let mut mask = GaloisRingTrimmedMaskCodeShare::from(&code);
+    code.preprocess_iris_code_query_share();
+    mask.preprocess_mask_code_query_share();
+
+    for window in [15_usize, 4] {
+        group.bench_function(BenchmarkId::new("padded", window), |b| {
+            b.iter(|| {
+                let codes = code.padded_rotations(window);
+                let masks = mask.padded_rotations(window);
+                for (code, mask) in codes.iter().zip(masks.iter()) {
+                    black_box(code.preprocess_for_query());
+                    black_box(mask.preprocess_for_query());
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("within", window), |b| {
+            b.iter(|| {
+                let codes = code.rotations_within(window);
+                let masks = mask.rotations_within(window);
+                for (code, mask) in codes.iter().zip(masks.iter()) {
+                    black_box(code.preprocess_for_query());
+                    black_box(mask.preprocess_for_query());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_query_rotations);
+criterion_main!(benches);
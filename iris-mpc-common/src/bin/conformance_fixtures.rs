@@ -0,0 +1,34 @@
+use clap::Parser;
+use iris_mpc_common::helpers::conformance::{ConformanceFixtures, FIXTURE_PATH};
+use rand::{rngs::StdRng, SeedableRng};
+use std::{fs, path::PathBuf};
+
+/// Regenerates the golden vectors of the threshold comparison that the GPU
+/// and CPU test suites check against. Only needed when the fixture format
+/// changes, together with a bump of the fixture version.
+#[derive(Debug, Parser)]
+#[command(name = "conformance-fixtures")]
+struct Args {
+    /// Where to write the fixtures, the committed ones by default.
+    #[arg(long, default_value = FIXTURE_PATH)]
+    output: PathBuf,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let fixtures = ConformanceFixtures::generate(&mut StdRng::seed_from_u64(args.seed));
+    fs::write(&args.output, serde_json::to_string(&fixtures)? + "\n")?;
+    for set in &fixtures.sets {
+        println!(
+            "{}: {} lanes, {} matches",
+            set.name,
+            set.len(),
+            set.expected().iter().filter(|&&m| m).count()
+        );
+    }
+    println!("Wrote {}", args.output.display());
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use clap::Parser;
+use eyre::eyre;
+use iris_mpc_common::helpers::decision_export::{read_rows, DecisionTotals};
+use std::path::PathBuf;
+
+/// Reads the Parquet files exported by the server and cross-checks their
+/// totals against the metrics counters of the same period.
+#[derive(Debug, Parser)]
+#[command(name = "decision-verify")]
+struct Args {
+    /// Exported files, or directories whose `.parquet` files to read.
+    #[arg(long, required = true)]
+    path: Vec<PathBuf>,
+
+    /// The `result.sent{type=uniqueness_result}` counter.
+    #[arg(long)]
+    results_sent: Option<u64>,
+
+    /// The `decision_export.batches` counter.
+    #[arg(long)]
+    exported_batches: Option<u64>,
+
+    /// The `decision_export.rows` counter.
+    #[arg(long)]
+    exported_rows: Option<u64>,
+
+    /// The `decision_export.matches` counter.
+    #[arg(long)]
+    exported_matches: Option<u64>,
+}
+
+fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let mut files = vec![];
+    for path in args.path.iter() {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<eyre::Result<Vec<_>>>()?;
+            entries.retain(|entry| entry.extension().is_some_and(|ext| ext == "parquet"));
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let mut totals = DecisionTotals::default();
+    for file in files.iter() {
+        totals.add_file(&read_rows(file)?);
+    }
+    println!(
+        "Read {} files: {} batches, {} decisions, {} matches",
+        files.len(),
+        totals.batches,
+        totals.rows,
+        totals.matches
+    );
+
+    let checks = [
+        (
+            "result.sent{type=uniqueness_result}",
+            args.results_sent,
+            totals.rows,
+        ),
+        (
+            "decision_export.batches",
+            args.exported_batches,
+            totals.batches,
+        ),
+        ("decision_export.rows", args.exported_rows, totals.rows),
+        (
+            "decision_export.matches",
+            args.exported_matches,
+            totals.matches,
+        ),
+    ];
+    let mismatches = checks
+        .iter()
+        .filter_map(|&(counter, expected, actual)| {
+            let expected = expected?;
+            (expected != actual)
+                .then(|| format!("{} is {}, the files hold {}", counter, expected, actual))
+        })
+        .collect::<Vec<_>>();
+    if !mismatches.is_empty() {
+        return Err(eyre!("Totals do not match: {}", mismatches.join("; ")));
+    }
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use iris_mpc_common::{
-    galois_engine::degree4::GaloisRingIrisCodeShare, iris_db::iris::IrisCodeArray,
+    galois_engine::degree4::{GaloisRingIrisCodeShare, ShareKind},
+    iris_db::iris::IrisCodeArray,
 };
 use rand::SeedableRng;
 use serde::{Deserialize, Serialize};
@@ -39,22 +40,26 @@ impl Signup {
         // Encode iris code shares left
         let shares_iris_code_left =
             GaloisRingIrisCodeShare::encode_iris_code(&iris_code_left, &mask_code_left, &mut rng);
-        self.iris_code_shares_left = Some(shares_iris_code_left.map(|x| x.to_base64()));
+        self.iris_code_shares_left =
+            Some(shares_iris_code_left.map(|x| x.to_base64(ShareKind::Code)));
 
         // Encode iris code shares right
         let shares_iris_code_right =
             GaloisRingIrisCodeShare::encode_iris_code(&iris_code_right, &mask_code_right, &mut rng);
-        self.iris_code_shares_right = Some(shares_iris_code_right.map(|x| x.to_base64()));
+        self.iris_code_shares_right =
+            Some(shares_iris_code_right.map(|x| x.to_base64(ShareKind::Code)));
 
         // Encode mask code shares left
         let shares_mask_code_left =
             GaloisRingIrisCodeShare::encode_mask_code(&mask_code_left, &mut rng);
-        self.mask_code_shares_left = Some(shares_mask_code_left.map(|x| x.to_base64()));
+        self.mask_code_shares_left =
+            Some(shares_mask_code_left.map(|x| x.to_base64(ShareKind::Mask)));
 
         // Encode mask code shares right
         let shares_mask_code_right =
             GaloisRingIrisCodeShare::encode_mask_code(&mask_code_right, &mut rng);
-        self.mask_code_shares_right = Some(shares_mask_code_right.map(|x| x.to_base64()));
+        self.mask_code_shares_right =
+            Some(shares_mask_code_right.map(|x| x.to_base64(ShareKind::Mask)));
     }
 }
 
@@ -1,7 +1,8 @@
 use base64::prelude::{Engine, BASE64_STANDARD};
 use data_encoding::HEXLOWER;
 use iris_mpc_common::{
-    galois_engine::degree4::GaloisRingIrisCodeShare, iris_db::iris::IrisCodeArray,
+    galois_engine::degree4::{GaloisRingIrisCodeShare, ShareKind},
+    iris_db::iris::IrisCodeArray,
 };
 use rand::{prelude::StdRng, SeedableRng};
 use ring::digest::{digest, SHA256};
@@ -89,15 +90,15 @@ fn main() {
 
     let shares_left =
         GaloisRingIrisCodeShare::encode_iris_code(&iris_code_left, &mask_code_left, &mut rng)
-            .map(|x| x.to_base64());
+            .map(|x| x.to_base64(ShareKind::Code));
 
-    let masks_left =
-        GaloisRingIrisCodeShare::encode_mask_code(&mask_code_left, &mut rng).map(|x| x.to_base64());
+    let masks_left = GaloisRingIrisCodeShare::encode_mask_code(&mask_code_left, &mut rng)
+        .map(|x| x.to_base64(ShareKind::Mask));
     let shares_right =
         GaloisRingIrisCodeShare::encode_iris_code(&iris_code_right, &mask_code_right, &mut rng)
-            .map(|x| x.to_base64());
+            .map(|x| x.to_base64(ShareKind::Code));
     let masks_right = GaloisRingIrisCodeShare::encode_mask_code(&mask_code_right, &mut rng)
-        .map(|x| x.to_base64());
+        .map(|x| x.to_base64(ShareKind::Mask));
 
     let mut iris_code_shares_jsons = Vec::new();
     let mut iris_code_shares_file_output = BTreeMap::new();
@@ -1,12 +1,36 @@
-use crate::config::json_wrapper::JsonStrWrapper;
+use crate::{
+    config::json_wrapper::JsonStrWrapper,
+    helpers::{
+        key_provider::KeyRefreshConfig,
+        latency_budget::LatencyBudget,
+        replay,
+        result_signature::{ResultSignatureError, ResultSigningKey},
+        sha256::calculate_sha256,
+        share_download::ShareDownloadConfig,
+        smpc_request::{ReceiveRetryConfig, SupportedIrisVersion},
+        state_exchange::StateSyncTransport,
+        threshold::{MatchThreshold, ThresholdError},
+        visibility::VisibilityPolicy,
+    },
+    iris_db::iris::{IrisCodeArray, MATCH_THRESHOLD_RATIO},
+    IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use clap::Parser;
+use eyre::eyre;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::fmt;
+use std::{fmt, path::PathBuf, time::Duration};
 
 pub mod json_wrapper;
+pub mod node;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Default, Parser)]
 pub struct Opt {
+    /// Settings file, overridden by the environment and the other flags, see
+    /// [`node::NodeConfig::load`].
+    #[structopt(long)]
+    config_file: Option<PathBuf>,
+
     #[structopt(long)]
     requests_queue_url: Option<String>,
 
@@ -28,15 +52,37 @@ pub struct Config {
     #[serde(default)]
     pub requests_queue_url: String,
 
+    /// Optional dedicated queue for interactive requests. If unset, the lane
+    /// of a request is taken from its `priority` message attribute.
+    #[serde(default)]
+    pub interactive_requests_queue_url: Option<String>,
+
+    /// Minimum share of each batch reserved for interactive requests whenever
+    /// any of them are waiting.
+    #[serde(default = "default_interactive_min_share")]
+    pub interactive_min_share: f64,
+
     #[serde(default)]
     pub results_topic_arn: String,
 
     #[serde(default)]
     pub kms_key_arns: JsonStrWrapper<Vec<String>>,
 
+    /// Seeds the correlated randomness with constants derived from the party
+    /// ids instead of running the key exchange. The randomness is then known
+    /// to anyone, only use this in tests.
+    #[serde(default)]
+    pub insecure_deterministic_seeds: bool,
+
     #[serde(default)]
     pub service: Option<ServiceConfig>,
 
+    /// Emit logs as JSON lines instead of the human readable format. Only
+    /// applies when no `service` is configured, the Datadog setup has its own
+    /// log format.
+    #[serde(default)]
+    pub json_logs: bool,
+
     #[serde(default)]
     pub database: Option<DbConfig>,
 
@@ -46,6 +92,25 @@ pub struct Config {
     #[serde(default = "default_processing_timeout_secs")]
     pub processing_timeout_secs: u64,
 
+    /// Deadlines of the batch phases, see [`crate::helpers::latency_budget`].
+    #[serde(default)]
+    pub latency_budget: LatencyBudget,
+
+    /// Limits and retries of the share downloads, see
+    /// [`crate::helpers::share_download`].
+    #[serde(default)]
+    pub share_download: ShareDownloadConfig,
+
+    /// Reloading of the share encryption keys, see
+    /// [`crate::helpers::key_provider`].
+    #[serde(default)]
+    pub key_refresh: KeyRefreshConfig,
+
+    /// The `IRIS_version`s of the share files that are accepted, as a JSON
+    /// list like `["1.0", "1.1"]`.
+    #[serde(default = "default_supported_iris_versions")]
+    pub supported_iris_versions: JsonStrWrapper<Vec<SupportedIrisVersion>>,
+
     #[serde(default)]
     pub public_key_base_url: String,
 
@@ -76,6 +141,18 @@ pub struct Config {
     #[serde(default)]
     pub return_partial_results: bool,
 
+    /// Runs the mirrored check for requests that ask for it: the mirrored
+    /// irises with swapped eyes are compared in addition to the irises
+    /// themselves. Each check takes an extra slot in the batch.
+    #[serde(default)]
+    pub enable_mirrored_checks: bool,
+
+    /// The largest rotation window a request can ask for, in columns either
+    /// way. Requests without a window are matched with this one, see
+    /// [`crate::helpers::smpc_request::UniquenessRequest::rotation_window`].
+    #[serde(default = "default_max_rotation_window")]
+    pub max_rotation_window: usize,
+
     #[serde(default)]
     pub disable_persistence: bool,
 
@@ -85,6 +162,22 @@ pub struct Config {
     #[serde(default = "default_shutdown_last_results_sync_timeout_secs")]
     pub shutdown_last_results_sync_timeout_secs: u64,
 
+    /// How long the startup waits for the other parties to exchange their
+    /// state. A party that never comes up fails the startup after this.
+    #[serde(default = "default_state_sync_timeout_secs")]
+    pub state_sync_timeout_secs: u64,
+
+    /// The transport of the startup state exchange. Over TCP, the parties
+    /// compare their state before NCCL is started.
+    #[serde(default)]
+    pub state_sync_transport: StateSyncTransport,
+
+    /// The `host:port` addresses of all parties for the state exchange over
+    /// TCP, by party id. A party listens on its own address and only accepts
+    /// connections from the others.
+    #[serde(default, deserialize_with = "deserialize_yaml_json_string")]
+    pub state_sync_addresses: Vec<String>,
+
     #[serde(default)]
     pub image_name: String,
 
@@ -105,6 +198,259 @@ pub struct Config {
 
     #[serde(default)]
     pub db_chunks_folder_name: String,
+
+    /// Size of the pieces in which the DB is uploaded to and the results are
+    /// fetched from the devices.
+    #[serde(default = "default_transfer_chunk_size_bytes")]
+    pub transfer_chunk_size_bytes: usize,
+
+    /// Stages the chunked transfers through page-locked host buffers.
+    #[serde(default)]
+    pub pinned_transfer_staging: bool,
+
+    /// Device memory to keep free while a batch is processed. Below it, the
+    /// batch size shrinks, see [`crate::helpers::memory_pressure`]. 0 disables
+    /// shrinking.
+    #[serde(default)]
+    pub memory_headroom_bytes: usize,
+
+    /// Below this much free device memory, the party takes no requests until
+    /// memory is freed. 0 disables pausing.
+    #[serde(default)]
+    pub memory_critical_bytes: usize,
+
+    /// Uniqueness requests carrying share files already seen under another
+    /// signup id within this window are rejected as replays. 0 disables the
+    /// check.
+    #[serde(default = "default_replay_window_secs")]
+    pub replay_window_secs: u64,
+
+    /// What the parties open of the comparison results, see [`ResultMode`].
+    #[serde(default)]
+    pub result_mode: ResultMode,
+
+    /// In [`ResultMode::CountOnly`], additionally opens the matching entries
+    /// so that the results carry `matched_serial_ids`.
+    #[serde(default)]
+    pub reveal_matched_serial_ids: bool,
+
+    /// How the matches of the two eyes combine into the match of a request,
+    /// see [`MatchPolicy`].
+    #[serde(default)]
+    pub match_policy: MatchPolicy,
+
+    /// Appends a record of every processed batch to this file, see
+    /// [`crate::helpers::audit`].
+    #[serde(default)]
+    pub audit_log_path: Option<String>,
+
+    /// Appends the decisions of every processed batch to daily Parquet files
+    /// in this directory, see `helpers::decision_export`. Needs
+    /// `decision_export_hmac_key` and a build with the `parquet_export`
+    /// feature.
+    #[serde(default)]
+    pub decision_export_dir: Option<String>,
+
+    /// Base64 encoded key of at least 32 bytes the signup ids are hashed with
+    /// in the decision export.
+    #[serde(default)]
+    pub decision_export_hmac_key: Option<String>,
+
+    /// Records the inputs and decisions of every processed batch into this
+    /// directory, to replay them with the `replay` binary, see
+    /// [`crate::helpers::replay`]. Needs `replay_debug_public_key`.
+    #[serde(default)]
+    pub replay_recording_dir: Option<String>,
+
+    /// Base64 encoded public key the recorded batches are sealed to. They
+    /// hold shares, so the secret key stays offline.
+    #[serde(default)]
+    pub replay_debug_public_key: Option<String>,
+
+    /// The match threshold the party starts with, in (0, 0.5), see
+    /// [`MatchThreshold`]. Has to be the same at all parties.
+    #[serde(default = "default_match_threshold_ratio")]
+    pub match_threshold_ratio: f64,
+
+    /// Base64 encoded Ed25519 public key of the operator allowed to change the
+    /// match threshold at runtime, see [`crate::helpers::threshold`]. Without
+    /// it, threshold updates are ignored.
+    #[serde(default)]
+    pub threshold_operator_public_key: Option<String>,
+
+    /// Base64 encoded 32 byte Ed25519 seed the party signs its uniqueness
+    /// results with, see [`crate::helpers::result_signature`]. Without it,
+    /// the results are not signed.
+    #[serde(default)]
+    pub result_signing_key: Option<String>,
+
+    /// Number of dummy batches run after the DB is loaded and before the
+    /// party reports ready, to initialize the GPU pipeline. 0 disables the
+    /// warmup.
+    #[serde(default = "default_warmup_rounds")]
+    pub warmup_rounds: usize,
+
+    /// Uploads only the un-rotated mask of each query and materializes its
+    /// rotations on the GPUs. Does not change any result.
+    #[serde(default)]
+    pub device_mask_rotations: bool,
+
+    /// Directory of the local DB snapshot. If set, a restart restores the DB
+    /// from the snapshot and only loads the newer entries from the store.
+    #[serde(default)]
+    pub db_snapshot_dir: Option<String>,
+
+    /// Maximum number of received requests that are not yet in a batch, see
+    /// [`crate::helpers::visibility`]. Further messages stay in the queue.
+    /// Defaults to two batches: the one being assembled and the next one.
+    #[serde(default)]
+    pub max_in_flight_messages: Option<usize>,
+
+    /// Visibility timeout of the request queues.
+    #[serde(default = "default_queue_visibility_timeout_secs")]
+    pub queue_visibility_timeout_secs: u64,
+
+    /// Visibility timeout set when extending a waiting request.
+    #[serde(default = "default_visibility_extension_secs")]
+    pub visibility_extension_secs: u64,
+
+    /// Waiting requests are extended once their visibility ends within this
+    /// margin. It has to cover the processing of a batch.
+    #[serde(default = "default_visibility_extension_margin_secs")]
+    pub visibility_extension_margin_secs: u64,
+
+    /// Failed attempts to parse a request message after which it is given
+    /// up, see [`crate::helpers::queue::PoisonMessages`].
+    #[serde(default = "default_poison_message_max_attempts")]
+    pub poison_message_max_attempts: u32,
+
+    /// Queue the request messages that were given up are forwarded to. They
+    /// are only deleted when unset.
+    #[serde(default)]
+    pub dead_letter_queue_url: Option<String>,
+
+    /// Retries of the request receiving after transient SQS failures, see
+    /// [`crate::helpers::queue::receive_with_retry`].
+    #[serde(default)]
+    pub receive_retry: ReceiveRetryConfig,
+
+    /// Number of serial ids whose shares are checked for consistency across
+    /// the parties at startup, see [`crate::helpers::share_audit`]. 0 disables
+    /// the audit.
+    #[serde(default = "default_share_audit_sample_size")]
+    pub share_audit_sample_size: usize,
+
+    /// Local address accepting backfill entries, see
+    /// [`crate::helpers::backfill`]. The endpoint is disabled when unset and
+    /// should not be reachable from outside the host.
+    #[serde(default)]
+    pub backfill_listen_address: Option<String>,
+
+    /// Maximum number of accepted backfill entries that are not yet in a
+    /// batch. Further entries are refused until the queue drains.
+    #[serde(default = "default_backfill_queue_size")]
+    pub backfill_queue_size: usize,
+
+    /// The engine that compares the irises.
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// Ordinals of the devices the GPU backend uses, as a JSON list. All
+    /// present devices are used when unset.
+    #[serde(default)]
+    pub gpu_device_ids: Option<JsonStrWrapper<Vec<usize>>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Gpu,
+    Cpu,
+}
+
+/// How much of the comparison results is revealed to the parties.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultMode {
+    /// Every comparison bit is opened, the matching entries are known to all
+    /// parties.
+    #[default]
+    FullOpen,
+    /// Only the number of matching entries per query is opened. The match bits
+    /// are summed up under the secret sharing.
+    CountOnly,
+    /// Like [`ResultMode::FullOpen`], and the dot products of the matching
+    /// entries are opened as well, so that the results carry their distances.
+    WithDistances,
+}
+
+/// How the matches of the left and the right eye combine into the match of a
+/// request. The matches of each eye are reported either way.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchPolicy {
+    /// An entry matches if both of its eyes match.
+    #[default]
+    And,
+    /// An entry matches if either of its eyes matches.
+    Or,
+}
+
+impl MatchPolicy {
+    pub fn combine(&self, left: bool, right: bool) -> bool {
+        match self {
+            MatchPolicy::And => left && right,
+            MatchPolicy::Or => left || right,
+        }
+    }
+
+    /// The distance of an entry from the distances of its eyes: the one that
+    /// decides whether the entry matches.
+    pub fn combine_distances(&self, left: f64, right: f64) -> f64 {
+        match self {
+            MatchPolicy::And => left.max(right),
+            MatchPolicy::Or => left.min(right),
+        }
+    }
+}
+
+fn default_transfer_chunk_size_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_share_audit_sample_size() -> usize {
+    64
+}
+
+fn default_backfill_queue_size() -> usize {
+    1024
+}
+
+fn default_warmup_rounds() -> usize {
+    1
+}
+
+fn default_queue_visibility_timeout_secs() -> u64 {
+    30
+}
+
+fn default_visibility_extension_secs() -> u64 {
+    180
+}
+
+fn default_visibility_extension_margin_secs() -> u64 {
+    90
+}
+
+fn default_poison_message_max_attempts() -> u32 {
+    5
+}
+
+fn default_replay_window_secs() -> u64 {
+    24 * 60 * 60
 }
 
 fn default_load_chunks_parallelism() -> usize {
@@ -115,10 +461,26 @@ fn default_processing_timeout_secs() -> u64 {
     60
 }
 
+fn default_supported_iris_versions() -> JsonStrWrapper<Vec<SupportedIrisVersion>> {
+    JsonStrWrapper(SupportedIrisVersion::ALL.to_vec())
+}
+
 fn default_max_batch_size() -> usize {
     64
 }
 
+fn default_max_rotation_window() -> usize {
+    IrisCodeArray::MAX_ROTATION
+}
+
+fn default_match_threshold_ratio() -> f64 {
+    MATCH_THRESHOLD_RATIO
+}
+
+fn default_interactive_min_share() -> f64 {
+    0.25
+}
+
 fn default_heartbeat_interval_secs() -> u64 {
     2
 }
@@ -131,6 +493,10 @@ fn default_shutdown_last_results_sync_timeout_secs() -> u64 {
     10
 }
 
+fn default_state_sync_timeout_secs() -> u64 {
+    300
+}
+
 fn default_shares_bucket_name() -> String {
     "wf-mpc-prod-smpcv2-sns-requests".to_string()
 }
@@ -168,6 +534,241 @@ impl Config {
             self.party_id = party_id;
         }
     }
+
+    /// Rejects settings that contradict each other. Meant to run before
+    /// anything is allocated, so that a bad deployment fails fast.
+    pub fn visibility_policy(&self) -> VisibilityPolicy {
+        VisibilityPolicy {
+            max_in_flight:      self
+                .max_in_flight_messages
+                .unwrap_or(2 * self.max_batch_size),
+            visibility_timeout: Duration::from_secs(self.queue_visibility_timeout_secs),
+            extension:          Duration::from_secs(self.visibility_extension_secs),
+            extension_margin:   Duration::from_secs(self.visibility_extension_margin_secs),
+        }
+    }
+
+    pub fn match_threshold(&self) -> Result<MatchThreshold, ThresholdError> {
+        MatchThreshold::new(self.match_threshold_ratio)
+    }
+
+    pub fn result_signing_key(&self) -> Result<Option<ResultSigningKey>, ResultSignatureError> {
+        self.result_signing_key
+            .as_deref()
+            .map(ResultSigningKey::from_base64_seed)
+            .transpose()
+    }
+
+    pub fn validate(&self) -> eyre::Result<()> {
+        let errors = self.violations();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("Invalid configuration: {}", errors.join("; ")))
+        }
+    }
+
+    /// Every setting that is invalid on its own or contradicts another one.
+    pub fn violations(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if self.party_id > 2 {
+            errors.push(format!("party_id must be 0, 1 or 2, got {}", self.party_id));
+        }
+        if self.max_batch_size == 0 {
+            errors.push("max_batch_size must not be 0".to_string());
+        }
+        if self.share_download.retry.max_attempts == 0 {
+            errors.push("share_download.retry.max_attempts must not be 0".to_string());
+        }
+        if self.share_download.max_response_bytes == 0 {
+            errors.push("share_download.max_response_bytes must not be 0".to_string());
+        }
+        if self.supported_iris_versions.0.is_empty() {
+            errors.push("supported_iris_versions must not be empty".to_string());
+        }
+        if self.enable_mirrored_checks && self.max_batch_size < 2 {
+            errors.push("enable_mirrored_checks needs a max_batch_size of at least 2".to_string());
+        }
+        if self.max_rotation_window > IrisCodeArray::MAX_ROTATION {
+            errors.push(format!(
+                "max_rotation_window must be at most {}, got {}",
+                IrisCodeArray::MAX_ROTATION,
+                self.max_rotation_window
+            ));
+        }
+        if self.init_db_size > self.max_db_size {
+            errors.push(format!(
+                "init_db_size ({}) exceeds max_db_size ({})",
+                self.init_db_size, self.max_db_size
+            ));
+        }
+        if self.fake_db_size > self.max_db_size {
+            errors.push(format!(
+                "fake_db_size ({}) exceeds max_db_size ({})",
+                self.fake_db_size, self.max_db_size
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.interactive_min_share) {
+            errors.push(format!(
+                "interactive_min_share must be within [0, 1], got {}",
+                self.interactive_min_share
+            ));
+        }
+        if let Err(e) = self.match_threshold() {
+            errors.push(e.to_string());
+        }
+        if self.transfer_chunk_size_bytes == 0 {
+            errors.push("transfer_chunk_size_bytes must not be 0".to_string());
+        }
+        if self.memory_headroom_bytes > 0 && self.memory_critical_bytes > self.memory_headroom_bytes
+        {
+            errors.push(format!(
+                "memory_critical_bytes ({}) exceeds memory_headroom_bytes ({})",
+                self.memory_critical_bytes, self.memory_headroom_bytes
+            ));
+        }
+        if !self.node_hostnames.is_empty() && self.node_hostnames.len() != 3 {
+            errors.push(format!(
+                "node_hostnames must list all 3 parties, got {}",
+                self.node_hostnames.len()
+            ));
+        }
+        if let Some(max_in_flight) = self.max_in_flight_messages {
+            if max_in_flight < self.max_batch_size {
+                errors.push(format!(
+                    "max_in_flight_messages must be at least max_batch_size ({}), got {}",
+                    self.max_batch_size, max_in_flight
+                ));
+            }
+        }
+        if let Err(e) = self.latency_budget.validate() {
+            errors.push(e);
+        }
+        if self.visibility_extension_secs <= self.visibility_extension_margin_secs {
+            errors.push(
+                "visibility_extension_secs must be larger than visibility_extension_margin_secs"
+                    .to_string(),
+            );
+        }
+        if self.poison_message_max_attempts == 0 {
+            errors.push("poison_message_max_attempts must be at least 1".to_string());
+        }
+        if self.state_sync_timeout_secs == 0 {
+            errors.push("state_sync_timeout_secs must not be 0".to_string());
+        }
+        if self.state_sync_transport == StateSyncTransport::Tcp
+            && self.state_sync_addresses.len() != 3
+        {
+            errors.push(format!(
+                "state_sync_addresses must list all 3 parties for the TCP transport, got {}",
+                self.state_sync_addresses.len()
+            ));
+        }
+        if self.state_sync_transport == StateSyncTransport::Tcp
+            && self.insecure_deterministic_seeds
+        {
+            errors.push(
+                "state_sync_transport tcp needs the shared secrets, so it cannot be used \
+                 with insecure_deterministic_seeds"
+                    .to_string(),
+            );
+        }
+        if self.receive_retry.max_attempts == 0 {
+            errors.push("receive_retry.max_attempts must be at least 1".to_string());
+        }
+        if let Some(key) = &self.threshold_operator_public_key {
+            match STANDARD.decode(key) {
+                Ok(key) if key.len() == 32 => {}
+                _ => errors.push(
+                    "threshold_operator_public_key must be a base64 encoded Ed25519 key"
+                        .to_string(),
+                ),
+            }
+        }
+        if let Err(e) = self.result_signing_key() {
+            errors.push(e.to_string());
+        }
+        match (&self.decision_export_dir, &self.decision_export_hmac_key) {
+            (Some(_), None) => {
+                errors.push("decision_export_dir needs a decision_export_hmac_key".to_string())
+            }
+            (_, Some(key)) if !STANDARD.decode(key).is_ok_and(|key| key.len() >= 32) => errors
+                .push(
+                    "decision_export_hmac_key must be a base64 encoded key of at least 32 bytes"
+                        .to_string(),
+                ),
+            _ => {}
+        }
+        match (&self.replay_recording_dir, &self.replay_debug_public_key) {
+            (Some(_), None) => {
+                errors.push("replay_recording_dir needs a replay_debug_public_key".to_string())
+            }
+            (_, Some(key)) if replay::decode_public_key(key).is_err() => errors.push(
+                "replay_debug_public_key must be a base64 encoded Curve25519 key".to_string(),
+            ),
+            _ => {}
+        }
+        errors
+    }
+}
+
+/// The settings that have to be identical at all parties for the results to
+/// be correct. Its fingerprint is compared in the startup sync.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommonConfig {
+    pub match_threshold_ratio:     f64,
+    pub iris_code_length:          usize,
+    pub mask_code_length:          usize,
+    pub max_batch_size:            usize,
+    pub max_db_size:               usize,
+    pub interactive_min_share:     f64,
+    pub return_partial_results:    bool,
+    pub enable_mirrored_checks:    bool,
+    pub max_rotation_window:       usize,
+    pub disable_persistence:       bool,
+    pub replay_window_secs:        u64,
+    pub result_mode:               ResultMode,
+    pub reveal_matched_serial_ids: bool,
+    pub match_policy:              MatchPolicy,
+    pub warmup_rounds:             usize,
+    pub share_audit_sample_size:   usize,
+    pub schema_version:            i64,
+    pub device_count:              usize,
+}
+
+impl CommonConfig {
+    pub fn new(config: &Config, schema_version: i64, device_count: usize) -> Self {
+        Self {
+            match_threshold_ratio: config.match_threshold_ratio,
+            iris_code_length: IRIS_CODE_LENGTH,
+            mask_code_length: MASK_CODE_LENGTH,
+            max_batch_size: config.max_batch_size,
+            max_db_size: config.max_db_size,
+            interactive_min_share: config.interactive_min_share,
+            return_partial_results: config.return_partial_results,
+            enable_mirrored_checks: config.enable_mirrored_checks,
+            max_rotation_window: config.max_rotation_window,
+            disable_persistence: config.disable_persistence,
+            replay_window_secs: config.replay_window_secs,
+            result_mode: config.result_mode,
+            reveal_matched_serial_ids: config.reveal_matched_serial_ids,
+            match_policy: config.match_policy,
+            warmup_rounds: config.warmup_rounds,
+            share_audit_sample_size: config.share_audit_sample_size,
+            schema_version,
+            device_count,
+        }
+    }
+
+    /// Serializes the settings as JSON, in field declaration order.
+    pub fn canonical(&self) -> String {
+        serde_json::to_string(self).expect("CommonConfig serializes to JSON")
+    }
+
+    /// SHA-256 of the canonical serialization, hex encoded.
+    pub fn fingerprint(&self) -> String {
+        calculate_sha256(self.canonical())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Default)]
@@ -0,0 +1,445 @@
+//! Layered loading of the [`Config`] of a party into a validated
+//! [`NodeConfig`].
+//!
+//! The layers override each other in this order: the defaults of [`Config`],
+//! the settings file given with `--config-file`, the environment variables
+//! with the binary's prefix, and the command line flags of [`Opt`]. The merged
+//! settings are validated as a whole, and every violation is reported at once.
+
+use super::{AwsConfig, Backend, CommonConfig, Config, DbConfig, MatchPolicy, Opt, ResultMode};
+use crate::{helpers::threshold::MatchThreshold, IRIS_CODE_LENGTH, MASK_CODE_LENGTH};
+use config::{Environment, File};
+use itertools::Itertools;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum NodeConfigError {
+    #[error("Failed to load the configuration: {0}")]
+    Source(#[from] config::ConfigError),
+    #[error("Invalid configuration: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+/// The queues, topics and buckets a party talks to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueConfig {
+    pub requests_queue_url:             String,
+    pub interactive_requests_queue_url: Option<String>,
+    pub results_topic_arn:              String,
+    pub shares_bucket_name:             String,
+    pub public_key_base_url:            String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpuConfig {
+    /// The devices to use, or all present ones if `None`.
+    pub device_ids:                Option<Vec<usize>>,
+    pub transfer_chunk_size_bytes: usize,
+    pub pinned_transfer_staging:   bool,
+    pub device_mask_rotations:     bool,
+    /// See [`Config::memory_headroom_bytes`].
+    pub memory_headroom_bytes:     usize,
+    /// See [`Config::memory_critical_bytes`].
+    pub memory_critical_bytes:     usize,
+}
+
+/// The settings that decide the results of a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchingConfig {
+    pub match_threshold:           MatchThreshold,
+    pub max_batch_size:            usize,
+    pub max_db_size:               usize,
+    pub interactive_min_share:     f64,
+    pub return_partial_results:    bool,
+    pub enable_mirrored_checks:    bool,
+    pub max_rotation_window:       usize,
+    pub replay_window_secs:        u64,
+    pub result_mode:               ResultMode,
+    pub reveal_matched_serial_ids: bool,
+    pub match_policy:              MatchPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncConfig {
+    /// The hostnames of all three parties, in party order.
+    pub node_hostnames:                     Vec<String>,
+    pub heartbeat_interval:                 Duration,
+    pub heartbeat_initial_retries:          u64,
+    pub shutdown_last_results_sync_timeout: Duration,
+}
+
+/// The validated settings of a party, grouped by concern.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub party_id: usize,
+    pub backend:  Backend,
+    pub aws:      AwsConfig,
+    pub database: DbConfig,
+    pub queues:   QueueConfig,
+    pub gpu:      GpuConfig,
+    pub matching: MatchingConfig,
+    pub sync:     SyncConfig,
+    /// All settings as they were loaded, including the ones without a
+    /// sub-struct.
+    pub base:     Config,
+}
+
+impl NodeConfig {
+    /// Loads the layers described in the module docs, reading the environment
+    /// variables with the given prefix, e.g. `SMPC__PARTY_ID`.
+    pub fn load(prefix: &str, opts: Opt) -> Result<Self, NodeConfigError> {
+        Self::load_from(Environment::with_prefix(prefix), opts)
+    }
+
+    fn load_from(environment: Environment, opts: Opt) -> Result<Self, NodeConfigError> {
+        let mut builder = config::Config::builder();
+        if let Some(path) = &opts.config_file {
+            builder = builder.add_source(File::from(path.clone()));
+        }
+        let settings = builder
+            .add_source(environment.separator("__").try_parsing(true))
+            .set_override_option("requests_queue_url", opts.requests_queue_url)?
+            .set_override_option("results_topic_arn", opts.results_topic_arn)?
+            .set_override_option("party_id", opts.party_id.map(|id| id as u64))?
+            .build()?;
+        Self::try_from(settings.try_deserialize::<Config>()?)
+    }
+}
+
+impl TryFrom<Config> for NodeConfig {
+    type Error = NodeConfigError;
+
+    /// Checks [`Config::violations`] and the settings a party cannot start
+    /// without.
+    fn try_from(config: Config) -> Result<Self, Self::Error> {
+        let mut errors = config.violations();
+        let required = [
+            ("requests_queue_url", &config.requests_queue_url),
+            ("results_topic_arn", &config.results_topic_arn),
+            ("public_key_base_url", &config.public_key_base_url),
+        ];
+        for (name, value) in required {
+            if value.is_empty() {
+                errors.push(format!("{} is required", name));
+            }
+        }
+        if config.database.is_none() {
+            errors.push("database is required".to_string());
+        }
+        if config.heartbeat_interval_secs == 0 {
+            errors.push("heartbeat_interval_secs must not be 0".to_string());
+        }
+        let device_ids = config.gpu_device_ids.clone().map(|ids| ids.0);
+        match (config.backend, &device_ids) {
+            (Backend::Gpu, Some(ids)) if ids.is_empty() => {
+                errors.push("the gpu backend needs at least one device".to_string())
+            }
+            (Backend::Gpu, Some(ids)) if !ids.iter().all_unique() => {
+                errors.push(format!("gpu_device_ids lists a device twice: {:?}", ids))
+            }
+            (Backend::Cpu, Some(_)) => {
+                errors.push("gpu_device_ids is set, but the backend is cpu".to_string())
+            }
+            _ => {}
+        }
+        // The count-only mode is only implemented by the CPU protocol so far.
+        if config.backend == Backend::Gpu && config.result_mode != ResultMode::FullOpen {
+            errors.push(format!(
+                "result_mode {:?} is not supported by the gpu backend",
+                config.result_mode
+            ));
+        }
+        if !errors.is_empty() {
+            return Err(NodeConfigError::Invalid(errors));
+        }
+
+        Ok(Self {
+            party_id: config.party_id,
+            backend:  config.backend,
+            aws:      config.aws.clone().unwrap_or_default(),
+            database: config.database.clone().unwrap_or_default(),
+            queues:   QueueConfig {
+                requests_queue_url:             config.requests_queue_url.clone(),
+                interactive_requests_queue_url: config.interactive_requests_queue_url.clone(),
+                results_topic_arn:              config.results_topic_arn.clone(),
+                shares_bucket_name:             config.shares_bucket_name.clone(),
+                public_key_base_url:            config.public_key_base_url.clone(),
+            },
+            gpu:      GpuConfig {
+                device_ids,
+                transfer_chunk_size_bytes: config.transfer_chunk_size_bytes,
+                pinned_transfer_staging: config.pinned_transfer_staging,
+                device_mask_rotations: config.device_mask_rotations,
+                memory_headroom_bytes: config.memory_headroom_bytes,
+                memory_critical_bytes: config.memory_critical_bytes,
+            },
+            matching: MatchingConfig {
+                // Checked with the violations above.
+                match_threshold:           config.match_threshold().unwrap_or_default(),
+                max_batch_size:            config.max_batch_size,
+                max_db_size:               config.max_db_size,
+                interactive_min_share:     config.interactive_min_share,
+                return_partial_results:    config.return_partial_results,
+                enable_mirrored_checks:    config.enable_mirrored_checks,
+                max_rotation_window:       config.max_rotation_window,
+                replay_window_secs:        config.replay_window_secs,
+                result_mode:               config.result_mode,
+                reveal_matched_serial_ids: config.reveal_matched_serial_ids,
+                match_policy:              config.match_policy,
+            },
+            sync:     SyncConfig {
+                node_hostnames:                     config.node_hostnames.clone(),
+                heartbeat_interval:                 Duration::from_secs(
+                    config.heartbeat_interval_secs,
+                ),
+                heartbeat_initial_retries:          config.heartbeat_initial_retries,
+                shutdown_last_results_sync_timeout: Duration::from_secs(
+                    config.shutdown_last_results_sync_timeout_secs,
+                ),
+            },
+            base:     config,
+        })
+    }
+}
+
+impl CommonConfig {
+    /// The settings of `node` that have to agree between the parties.
+    pub fn from_node(node: &NodeConfig, schema_version: i64, device_count: usize) -> Self {
+        let matching = &node.matching;
+        Self {
+            match_threshold_ratio: matching.match_threshold.ratio(),
+            iris_code_length: IRIS_CODE_LENGTH,
+            mask_code_length: MASK_CODE_LENGTH,
+            max_batch_size: matching.max_batch_size,
+            max_db_size: matching.max_db_size,
+            interactive_min_share: matching.interactive_min_share,
+            return_partial_results: matching.return_partial_results,
+            enable_mirrored_checks: matching.enable_mirrored_checks,
+            max_rotation_window: matching.max_rotation_window,
+            disable_persistence: node.base.disable_persistence,
+            replay_window_secs: matching.replay_window_secs,
+            result_mode: matching.result_mode,
+            reveal_matched_serial_ids: matching.reveal_matched_serial_ids,
+            match_policy: matching.match_policy,
+            warmup_rounds: node.base.warmup_rounds,
+            share_audit_sample_size: node.base.share_audit_sample_size,
+            schema_version,
+            device_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use std::{collections::HashMap, path::PathBuf};
+
+    const PREFIX: &str = "SMPC";
+
+    fn environment(vars: &[(&str, &str)]) -> Environment {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (format!("{}__{}", PREFIX, key), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        Environment::with_prefix(PREFIX).source(Some(vars))
+    }
+
+    fn required() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("REQUESTS_QUEUE_URL", "http://queue/env"),
+            (
+                "RESULTS_TOPIC_ARN",
+                "arn:aws:sns:eu-north-1:000000000000:results",
+            ),
+            ("PUBLIC_KEY_BASE_URL", "http://keys"),
+            ("DATABASE__URL", "postgres://localhost"),
+        ]
+    }
+
+    fn write_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn violations(result: Result<NodeConfig, NodeConfigError>) -> Vec<String> {
+        match result {
+            Err(NodeConfigError::Invalid(errors)) => errors,
+            other => panic!("expected violations, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let path = write_file(
+            "node-config-precedence",
+            "party_id = 1\nmax_batch_size = 32\nwarmup_rounds = 3\nrequests_queue_url = \
+             \"http://queue/file\"\n",
+        );
+
+        // The file overrides the defaults.
+        let opts = Opt {
+            config_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let node = NodeConfig::load_from(environment(&required()[1..]), opts).unwrap();
+        assert_eq!(node.party_id, 1);
+        assert_eq!(node.base.warmup_rounds, 3);
+        assert_eq!(node.queues.requests_queue_url, "http://queue/file");
+        assert_eq!(node.base.heartbeat_initial_retries, 10);
+
+        // The environment overrides the file, the flags override both.
+        let mut vars = required();
+        vars.extend([("PARTY_ID", "2"), ("MAX_BATCH_SIZE", "16")]);
+        let opts = Opt {
+            config_file: Some(path.clone()),
+            party_id: Some(0),
+            ..Default::default()
+        };
+        let node = NodeConfig::load_from(environment(&vars), opts).unwrap();
+        assert_eq!(node.party_id, 0);
+        assert_eq!(node.matching.max_batch_size, 16);
+        assert_eq!(node.queues.requests_queue_url, "http://queue/env");
+        assert_eq!(node.base.warmup_rounds, 3);
+        assert_eq!(node.database.url, "postgres://localhost");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_required() {
+        let errors = violations(NodeConfig::load_from(environment(&[]), Opt::default()));
+        for name in [
+            "requests_queue_url",
+            "results_topic_arn",
+            "public_key_base_url",
+            "database",
+        ] {
+            assert!(
+                errors.contains(&format!("{} is required", name)),
+                "{:?}",
+                errors
+            );
+        }
+
+        // A missing settings file is an error of its own.
+        let opts = Opt {
+            config_file: Some(PathBuf::from("/nonexistent/node-config.toml")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            NodeConfig::load_from(environment(&required()), opts),
+            Err(NodeConfigError::Source(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_combinations() {
+        let mut vars = required();
+        vars.extend([
+            ("GPU_DEVICE_IDS", "[]"),
+            ("RESULT_MODE", "count_only"),
+            ("PARTY_ID", "3"),
+            ("MAX_BATCH_SIZE", "0"),
+        ]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors.contains(&"the gpu backend needs at least one device".to_string()));
+        assert!(errors
+            .contains(&"result_mode CountOnly is not supported by the gpu backend".to_string()));
+
+        let mut vars = required();
+        vars.extend([("BACKEND", "cpu"), ("GPU_DEVICE_IDS", "[0]")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "gpu_device_ids is set, but the backend is cpu"
+        ]);
+
+        let mut vars = required();
+        vars.extend([("GPU_DEVICE_IDS", "[1, 0, 1]")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors.len(), 1);
+
+        let mut vars = required();
+        vars.extend([("REPLAY_RECORDING_DIR", "/tmp/replay")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "replay_recording_dir needs a replay_debug_public_key"
+        ]);
+        vars.extend([("REPLAY_DEBUG_PUBLIC_KEY", "c2hvcnQ=")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors.len(), 1);
+
+        let mut vars = required();
+        vars.extend([("DECISION_EXPORT_DIR", "/tmp/decisions")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "decision_export_dir needs a decision_export_hmac_key"
+        ]);
+        vars.extend([("DECISION_EXPORT_HMAC_KEY", "c2hvcnQ=")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors.len(), 1);
+        let key = STANDARD.encode([0u8; 32]);
+        vars.extend([("DECISION_EXPORT_HMAC_KEY", key.as_str())]);
+        NodeConfig::load_from(environment(&vars), Opt::default()).unwrap();
+
+        let mut vars = required();
+        vars.extend([("RESULT_SIGNING_KEY", "c2hvcnQ=")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "Result signing key must be a base64 encoded 32 byte Ed25519 seed"
+        ]);
+        vars.extend([("RESULT_SIGNING_KEY", key.as_str())]);
+        NodeConfig::load_from(environment(&vars), Opt::default()).unwrap();
+
+        let mut vars = required();
+        vars.extend([
+            ("MEMORY_HEADROOM_BYTES", "1000"),
+            ("MEMORY_CRITICAL_BYTES", "2000"),
+        ]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "memory_critical_bytes (2000) exceeds memory_headroom_bytes (1000)"
+        ]);
+
+        let mut vars = required();
+        vars.extend([("MATCH_THRESHOLD_RATIO", "0.5")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "Match threshold ratio 0.5 is outside of (0, 0.5)"
+        ]);
+        vars.extend([("MATCH_THRESHOLD_RATIO", "0.34")]);
+        let node = NodeConfig::load_from(environment(&vars), Opt::default()).unwrap();
+        assert_eq!(node.matching.match_threshold.ratio(), 0.34);
+
+        let mut vars = required();
+        vars.extend([("INTERACTIVE_MIN_SHARE", "1.5")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "interactive_min_share must be within [0, 1], got 1.5"
+        ]);
+        vars.extend([("INTERACTIVE_MIN_SHARE", "1.0")]);
+        NodeConfig::load_from(environment(&vars), Opt::default()).unwrap();
+
+        let mut vars = required();
+        vars.extend([("MAX_ROTATION_WINDOW", "16")]);
+        let errors = violations(NodeConfig::load_from(environment(&vars), Opt::default()));
+        assert_eq!(errors, vec![
+            "max_rotation_window must be at most 15, got 16"
+        ]);
+        vars.extend([("MAX_ROTATION_WINDOW", "4")]);
+        let node = NodeConfig::load_from(environment(&vars), Opt::default()).unwrap();
+        assert_eq!(node.matching.max_rotation_window, 4);
+
+        let mut vars = required();
+        vars.extend([("GPU_DEVICE_IDS", "[1, 0]"), ("RESULT_MODE", "full_open")]);
+        let node = NodeConfig::load_from(environment(&vars), Opt::default()).unwrap();
+        assert_eq!(node.gpu.device_ids, Some(vec![1, 0]));
+        assert_eq!(
+            CommonConfig::from_node(&node, 1, 2).fingerprint(),
+            CommonConfig::new(&node.base, 1, 2).fingerprint()
+        );
+    }
+}
@@ -0,0 +1,631 @@
+//! Stable codes for the errors of this crate, used as failure reasons in
+//! results and as metrics labels.
+//!
+//! Every code has a number and a snake_case name, neither of which may change
+//! once released. The [`HasErrorCode`] implementations below match on every
+//! variant without a wildcard arm, so a new variant without a code does not
+//! compile.
+
+#[cfg(feature = "aws")]
+use crate::helpers::smpc_request::ReceiveRequestError;
+use crate::{
+    error::Error,
+    galois_engine::degree4::{ReconstructionError, ShareEncodingError, ShareValidationError},
+    helpers::{
+        audit::AuditError,
+        backfill::BackfillError,
+        canary::CanaryFailure,
+        key_pair::SharesDecodingError,
+        share_audit::ShareAuditError,
+        smpc_request::{IdentityDeletionBatchError, ShareHashMismatch},
+        threshold::ThresholdError,
+    },
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+macro_rules! error_codes {
+    ($($(#[$doc:meta])* $variant:ident = $number:literal => $name:literal,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u16)]
+        pub enum ErrorCode {
+            $($(#[$doc])* $variant = $number,)*
+        }
+
+        impl ErrorCode {
+            pub const ALL: &'static [ErrorCode] = &[$(ErrorCode::$variant,)*];
+
+            pub const fn as_str(self) -> &'static str {
+                match self {
+                    $(ErrorCode::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    /// An error without a more specific code.
+    Internal = 1 => "internal",
+    InvalidPartyId = 2 => "invalid_party_id",
+
+    ReplayedRequest = 100 => "replayed_request",
+    /// The shares of a uniqueness request could not be used, see the
+    /// `error_code` of the result for the reason.
+    FailedToProcessIrisShares = 101 => "failed_to_process_iris_shares",
+    QueueReceiveFailed = 102 => "queue_receive_failed",
+    QueueDeleteFailed = 103 => "queue_delete_failed",
+    QueueVisibilityFailed = 104 => "queue_visibility_failed",
+    QueueRequeueFailed = 105 => "queue_requeue_failed",
+    ExpiredReceiptHandle = 106 => "expired_receipt_handle",
+    DatabaseFailed = 107 => "database_failed",
+    InvalidRequestJson = 108 => "invalid_request_json",
+    InvalidMessageType = 109 => "invalid_message_type",
+    TaskFailed = 110 => "task_failed",
+    /// Not all parties received the request within
+    /// [`MAX_BATCH_DEFERRALS`](crate::helpers::sync::MAX_BATCH_DEFERRALS)
+    /// batches.
+    RequestNotAgreed = 111 => "request_not_agreed",
+    /// A target of a reauth request is deleted or beyond the database.
+    UnknownReauthTarget = 112 => "unknown_reauth_target",
+    /// An identity deletion batch holds more than
+    /// [`MAX_IDENTITY_DELETION_BATCH_SIZE`](crate::helpers::smpc_request::MAX_IDENTITY_DELETION_BATCH_SIZE)
+    /// serial ids.
+    TooManyDeletionSerialIds = 113 => "too_many_deletion_serial_ids",
+    /// The schema version attribute of a request is not
+    /// [`REQUEST_SCHEMA_VERSION`](crate::helpers::smpc_request::REQUEST_SCHEMA_VERSION).
+    UnsupportedSchemaVersion = 114 => "unsupported_schema_version",
+
+    SecretsUnavailable = 200 => "secrets_unavailable",
+    KeyNotFound = 201 => "key_not_found",
+    InvalidKey = 202 => "invalid_key",
+    ShareDownloadFailed = 203 => "share_download_failed",
+    ShareUploadFailed = 204 => "share_upload_failed",
+    ShareDecryptionFailed = 205 => "share_decryption_failed",
+    InvalidShareEncoding = 206 => "invalid_share_encoding",
+    UnsupportedShareVersion = 207 => "unsupported_share_version",
+    WrongShareParty = 208 => "wrong_share_party",
+    WrongShareKind = 209 => "wrong_share_kind",
+    InconsistentShares = 210 => "inconsistent_shares",
+    /// The share decodes, but not to something the encoder produces.
+    InvalidShareContent = 211 => "invalid_share_content",
+    /// The decrypted share does not match the hash the request was sent with.
+    ShareHashMismatch = 212 => "share_hash_mismatch",
+    /// A request names neither or both of a presigned URL and an S3 object
+    /// for its shares.
+    InvalidShareSource = 213 => "invalid_share_source",
+
+    InvalidThresholdSignature = 300 => "invalid_threshold_signature",
+    InvalidThresholdParams = 301 => "invalid_threshold_params",
+    StaleThresholdVersion = 302 => "stale_threshold_version",
+    ThresholdActivationPassed = 303 => "threshold_activation_passed",
+    TooManyPendingThresholds = 304 => "too_many_pending_thresholds",
+
+    AuditLogModified = 400 => "audit_log_modified",
+    ShareAuditMismatch = 401 => "share_audit_mismatch",
+    BackfillIo = 402 => "backfill_io",
+    InvalidBackfillEntry = 403 => "invalid_backfill_entry",
+    CanaryFailed = 404 => "canary_failed",
+}
+
+impl ErrorCode {
+    pub const fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_u16(number: u16) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|code| code.as_u16() == number)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Unknown error code {0}")]
+pub struct UnknownErrorCode(pub String);
+
+impl FromStr for ErrorCode {
+    type Err = UnknownErrorCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|code| code.as_str() == s)
+            .ok_or_else(|| UnknownErrorCode(s.to_string()))
+    }
+}
+
+/// Serialized as the name, deserialized from the name or the number.
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CodeVisitor;
+
+        impl de::Visitor<'_> for CodeVisitor {
+            type Value = ErrorCode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an error code name or number")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<ErrorCode, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<ErrorCode, E> {
+                u16::try_from(v)
+                    .ok()
+                    .and_then(ErrorCode::from_u16)
+                    .ok_or_else(|| E::custom(UnknownErrorCode(v.to_string())))
+            }
+        }
+
+        deserializer.deserialize_any(CodeVisitor)
+    }
+}
+
+pub trait HasErrorCode {
+    fn error_code(&self) -> ErrorCode;
+}
+
+/// The code of the outermost error in the chain of `report` that has one.
+pub fn error_code_of(report: &eyre::Report) -> ErrorCode {
+    report
+        .chain()
+        .find_map(code_of)
+        .unwrap_or(ErrorCode::Internal)
+}
+
+/// The message of the error that [`error_code_of`] takes the code from, or of
+/// the outermost error if none has a code.
+pub fn error_message_of(report: &eyre::Report) -> String {
+    report
+        .chain()
+        .find(|error| code_of(*error).is_some())
+        .map_or_else(|| report.to_string(), ToString::to_string)
+}
+
+fn code_of(error: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+    macro_rules! downcast {
+        ($($error:ty),*) => {
+            $(
+                if let Some(error) = error.downcast_ref::<$error>() {
+                    return Some(error.error_code());
+                }
+            )*
+        };
+    }
+    #[cfg(feature = "aws")]
+    downcast!(ReceiveRequestError);
+    downcast!(
+        Error,
+        SharesDecodingError,
+        ShareEncodingError,
+        ShareValidationError,
+        ShareHashMismatch,
+        IdentityDeletionBatchError,
+        ReconstructionError,
+        ThresholdError,
+        AuditError,
+        ShareAuditError,
+        BackfillError,
+        CanaryFailure
+    );
+    None
+}
+
+impl HasErrorCode for Error {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Error::Id(_) => ErrorCode::InvalidPartyId,
+            Error::Other(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+#[cfg(feature = "aws")]
+impl HasErrorCode for ReceiveRequestError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            ReceiveRequestError::FailedToReadFromSQS(_) => ErrorCode::QueueReceiveFailed,
+            ReceiveRequestError::FailedToDeleteFromSQS(_)
+            | ReceiveRequestError::FailedToDeleteBatchFromSQS(_)
+            | ReceiveRequestError::BatchDeleteIncomplete(_) => ErrorCode::QueueDeleteFailed,
+            ReceiveRequestError::FailedToChangeVisibility(_)
+            | ReceiveRequestError::FailedToChangeVisibilityBatch(_)
+            | ReceiveRequestError::BatchVisibilityIncomplete(_) => ErrorCode::QueueVisibilityFailed,
+            ReceiveRequestError::FailedToRequeue(_)
+            | ReceiveRequestError::FailedToDeadLetter(_) => ErrorCode::QueueRequeueFailed,
+            ReceiveRequestError::ExpiredReceiptHandle(_) => ErrorCode::ExpiredReceiptHandle,
+            ReceiveRequestError::FailedToMarkRequestAsDeleted(_)
+            | ReceiveRequestError::FailedToCheckReplay(_) => ErrorCode::DatabaseFailed,
+            ReceiveRequestError::JsonParseError { .. } => ErrorCode::InvalidRequestJson,
+            ReceiveRequestError::NoMessageTypeAttribute
+            | ReceiveRequestError::NoStringMessageTypeAttribute
+            | ReceiveRequestError::InvalidMessageType => ErrorCode::InvalidMessageType,
+            ReceiveRequestError::UnsupportedSchemaVersion { .. } => {
+                ErrorCode::UnsupportedSchemaVersion
+            }
+            ReceiveRequestError::FailedToJoinHandle(_) => ErrorCode::TaskFailed,
+            ReceiveRequestError::ExhaustedRetries { source, .. } => source.error_code(),
+        }
+    }
+}
+
+impl HasErrorCode for SharesDecodingError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "aws")]
+            SharesDecodingError::SecretsManagerError(_) => ErrorCode::SecretsUnavailable,
+            SharesDecodingError::SecretStringNotFound => ErrorCode::SecretsUnavailable,
+            SharesDecodingError::PreviousKeyNotFound
+            | SharesDecodingError::PublicKeyNotFound
+            | SharesDecodingError::PrivateKeyNotFound => ErrorCode::KeyNotFound,
+            SharesDecodingError::ParsingKeyError => ErrorCode::InvalidKey,
+            #[cfg(feature = "aws")]
+            SharesDecodingError::RequestError(_)
+            | SharesDecodingError::ResponseContent { .. }
+            | SharesDecodingError::PresigningConfigError(_)
+            | SharesDecodingError::PresignedRequestError(_) => ErrorCode::ShareDownloadFailed,
+            SharesDecodingError::S3ResponseContent { .. }
+            | SharesDecodingError::DownloadTimeout(_)
+            | SharesDecodingError::BodyChecksumMismatch { .. }
+            | SharesDecodingError::ResponseTooLarge { .. } => ErrorCode::ShareDownloadFailed,
+            SharesDecodingError::UploadS3Error => ErrorCode::ShareUploadFailed,
+            SharesDecodingError::SealedBoxOpenError => ErrorCode::ShareDecryptionFailed,
+            SharesDecodingError::DecodingError(_)
+            | SharesDecodingError::DecodedShareParsingToUTF8Error(_)
+            | SharesDecodingError::Base64DecodeError
+            | SharesDecodingError::SerdeError(_) => ErrorCode::InvalidShareEncoding,
+            SharesDecodingError::WrongShareLength { .. } => ErrorCode::InvalidShareEncoding,
+            SharesDecodingError::UnsupportedVersion { .. } => ErrorCode::UnsupportedShareVersion,
+            SharesDecodingError::HashMismatch(e) => e.error_code(),
+            SharesDecodingError::PartyShareNotFound { .. } => ErrorCode::WrongShareParty,
+            SharesDecodingError::InvalidShareSource => ErrorCode::InvalidShareSource,
+        }
+    }
+}
+
+impl HasErrorCode for ShareEncodingError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            ShareEncodingError::Base64(_)
+            | ShareEncodingError::Truncated(_)
+            | ShareEncodingError::WrongLength { .. }
+            | ShareEncodingError::TrailingBytes(_) => ErrorCode::InvalidShareEncoding,
+            ShareEncodingError::UnsupportedVersion(_) => ErrorCode::UnsupportedShareVersion,
+            ShareEncodingError::InvalidPartyId(_) => ErrorCode::WrongShareParty,
+            ShareEncodingError::WrongKind { .. } => ErrorCode::WrongShareKind,
+        }
+    }
+}
+
+impl HasErrorCode for ShareValidationError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            ShareValidationError::Encoding(e) => e.error_code(),
+            ShareValidationError::InvalidPartyId(_) | ShareValidationError::WrongParty { .. } => {
+                ErrorCode::WrongShareParty
+            }
+            ShareValidationError::Constant(_) | ShareValidationError::DuplicatedMaskHalves => {
+                ErrorCode::InvalidShareContent
+            }
+        }
+    }
+}
+
+impl HasErrorCode for ShareHashMismatch {
+    fn error_code(&self) -> ErrorCode {
+        ErrorCode::ShareHashMismatch
+    }
+}
+
+impl HasErrorCode for IdentityDeletionBatchError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            IdentityDeletionBatchError::Json(_) => ErrorCode::InvalidRequestJson,
+            IdentityDeletionBatchError::TooManySerialIds { .. } => {
+                ErrorCode::TooManyDeletionSerialIds
+            }
+        }
+    }
+}
+
+impl HasErrorCode for ReconstructionError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            ReconstructionError::PartyIds(_) => ErrorCode::WrongShareParty,
+            ReconstructionError::Inconsistent(_) | ReconstructionError::InvalidValue { .. } => {
+                ErrorCode::InconsistentShares
+            }
+            ReconstructionError::UntrimmableMask(_) => ErrorCode::InvalidShareContent,
+        }
+    }
+}
+
+impl HasErrorCode for ThresholdError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            ThresholdError::InvalidSignature => ErrorCode::InvalidThresholdSignature,
+            ThresholdError::InvalidParams(_) | ThresholdError::InvalidRatio(_) => {
+                ErrorCode::InvalidThresholdParams
+            }
+            ThresholdError::StaleVersion { .. } => ErrorCode::StaleThresholdVersion,
+            ThresholdError::ActivationPassed { .. } => ErrorCode::ThresholdActivationPassed,
+            ThresholdError::TooManyPending => ErrorCode::TooManyPendingThresholds,
+        }
+    }
+}
+
+impl HasErrorCode for AuditError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            AuditError::Modified { .. } | AuditError::BrokenLink { .. } => {
+                ErrorCode::AuditLogModified
+            }
+        }
+    }
+}
+
+impl HasErrorCode for ShareAuditError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            ShareAuditError::WrongParties(_) | ShareAuditError::SampleMismatch { .. } => {
+                ErrorCode::ShareAuditMismatch
+            }
+        }
+    }
+}
+
+impl HasErrorCode for BackfillError {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            BackfillError::Io { .. } => ErrorCode::BackfillIo,
+            BackfillError::InvalidLine { .. } | BackfillError::Decode { .. } => {
+                ErrorCode::InvalidBackfillEntry
+            }
+            BackfillError::WrongParty { .. } => ErrorCode::WrongShareParty,
+        }
+    }
+}
+
+impl HasErrorCode for CanaryFailure {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            CanaryFailure::Target { .. } | CanaryFailure::UnexpectedResult { .. } => {
+                ErrorCode::CanaryFailed
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "aws")]
+    use crate::{
+        galois_engine::degree4::ShareKind,
+        helpers::{canary::CanaryStep, smpc_request::SupportedIrisVersion},
+    };
+    use eyre::WrapErr;
+    use std::collections::HashSet;
+
+    #[cfg(feature = "aws")]
+    fn sample_hash_mismatch() -> ShareHashMismatch {
+        ShareHashMismatch {
+            party_id: 0,
+            expected: "00".repeat(32),
+            computed: "11".repeat(32),
+        }
+    }
+
+    /// One error of every variant that can be constructed in a test. Together
+    /// they must use every code apart from the ones set by the server itself.
+    #[cfg(feature = "aws")]
+    fn sample_errors() -> Vec<Box<dyn HasErrorCode>> {
+        let json_error = || serde_json::from_str::<u32>("x").unwrap_err();
+        let join_error = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let handle = tokio::spawn(std::future::pending::<()>());
+                handle.abort();
+                handle.await.unwrap_err()
+            });
+        vec![
+            Box::new(Error::Id(3)),
+            Box::new(Error::Other("other".to_string())),
+            Box::new(ReceiveRequestError::FailedToReadFromSQS(
+                aws_sdk_sqs::error::SdkError::construction_failure("sqs"),
+            )),
+            Box::new(ReceiveRequestError::FailedToDeleteFromSQS(
+                aws_sdk_sqs::error::SdkError::construction_failure("sqs"),
+            )),
+            Box::new(ReceiveRequestError::FailedToChangeVisibility(
+                aws_sdk_sqs::error::SdkError::construction_failure("sqs"),
+            )),
+            Box::new(ReceiveRequestError::FailedToRequeue(
+                aws_sdk_sqs::error::SdkError::construction_failure("sqs"),
+            )),
+            Box::new(ReceiveRequestError::FailedToDeleteBatchFromSQS(
+                aws_sdk_sqs::error::SdkError::construction_failure("sqs"),
+            )),
+            Box::new(ReceiveRequestError::FailedToChangeVisibilityBatch(
+                aws_sdk_sqs::error::SdkError::construction_failure("sqs"),
+            )),
+            Box::new(ReceiveRequestError::BatchDeleteIncomplete(vec![
+                "handle".to_string()
+            ])),
+            Box::new(ReceiveRequestError::BatchVisibilityIncomplete(vec![
+                "handle".to_string(),
+            ])),
+            Box::new(ReceiveRequestError::ExpiredReceiptHandle("id".to_string())),
+            Box::new(ReceiveRequestError::FailedToCheckReplay(eyre::eyre!("db"))),
+            Box::new(ReceiveRequestError::json_parse_error("body", json_error())),
+            Box::new(ReceiveRequestError::InvalidMessageType),
+            Box::new(ReceiveRequestError::FailedToJoinHandle(join_error)),
+            Box::new(SharesDecodingError::SecretStringNotFound),
+            Box::new(SharesDecodingError::PrivateKeyNotFound),
+            Box::new(SharesDecodingError::ParsingKeyError),
+            Box::new(SharesDecodingError::S3ResponseContent {
+                key:     "key".to_string(),
+                message: "missing".to_string(),
+            }),
+            Box::new(SharesDecodingError::UploadS3Error),
+            Box::new(SharesDecodingError::SealedBoxOpenError),
+            Box::new(SharesDecodingError::SerdeError(json_error())),
+            Box::new(sample_hash_mismatch()),
+            Box::new(SharesDecodingError::HashMismatch(sample_hash_mismatch())),
+            Box::new(SharesDecodingError::ResponseTooLarge { max_bytes: 1 << 20 }),
+            Box::new(SharesDecodingError::BodyChecksumMismatch {
+                expected: "a".to_string(),
+                computed: "b".to_string(),
+            }),
+            Box::new(SharesDecodingError::DownloadTimeout(
+                std::time::Duration::from_secs(30),
+            )),
+            Box::new(SharesDecodingError::UnsupportedVersion {
+                got:       "2.0".to_string(),
+                supported: SupportedIrisVersion::ALL.to_vec(),
+            }),
+            Box::new(SharesDecodingError::WrongShareLength {
+                version:  SupportedIrisVersion::V1_1,
+                expected: 1,
+                got:      0,
+            }),
+            Box::new(SharesDecodingError::PartyShareNotFound {
+                party_id:    3,
+                party_count: 3,
+            }),
+            Box::new(SharesDecodingError::InvalidShareSource),
+            Box::new(IdentityDeletionBatchError::Json(json_error())),
+            Box::new(IdentityDeletionBatchError::TooManySerialIds {
+                count: 1001,
+                max:   1000,
+            }),
+            Box::new(ShareEncodingError::Truncated(0)),
+            Box::new(ShareEncodingError::UnsupportedVersion(2)),
+            Box::new(ShareEncodingError::InvalidPartyId(0)),
+            Box::new(ShareEncodingError::WrongKind {
+                expected: ShareKind::Code,
+                got:      1,
+            }),
+            Box::new(ShareValidationError::Constant(0)),
+            Box::new(ReconstructionError::Inconsistent(0)),
+            Box::new(ThresholdError::InvalidSignature),
+            Box::new(ThresholdError::InvalidParams(0.7)),
+            Box::new(ThresholdError::StaleVersion {
+                version: 1,
+                latest:  2,
+            }),
+            Box::new(ThresholdError::ActivationPassed {
+                activation_batch: 1,
+                next_batch:       2,
+            }),
+            Box::new(ThresholdError::TooManyPending),
+            Box::new(AuditError::Modified { seq: 0 }),
+            Box::new(ShareAuditError::WrongParties(vec![])),
+            Box::new(BackfillError::Io {
+                path:   "path".to_string(),
+                source: std::io::ErrorKind::NotFound.into(),
+            }),
+            Box::new(BackfillError::InvalidLine {
+                line:   0,
+                source: json_error(),
+            }),
+            Box::new(CanaryFailure::UnexpectedResult {
+                round:  0,
+                step:   CanaryStep::Enroll,
+                detail: "detail".to_string(),
+            }),
+        ]
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        let numbers = ErrorCode::ALL
+            .iter()
+            .map(|c| c.as_u16())
+            .collect::<HashSet<_>>();
+        let names = ErrorCode::ALL
+            .iter()
+            .map(|c| c.as_str())
+            .collect::<HashSet<_>>();
+        assert_eq!(numbers.len(), ErrorCode::ALL.len());
+        assert_eq!(names.len(), ErrorCode::ALL.len());
+        for code in ErrorCode::ALL {
+            assert!(code
+                .as_str()
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c == '_'));
+            assert_eq!(ErrorCode::from_u16(code.as_u16()), Some(*code));
+            assert_eq!(code.as_str().parse::<ErrorCode>(), Ok(*code));
+        }
+        assert_eq!(ErrorCode::from_u16(0), None);
+    }
+
+    #[cfg(feature = "aws")]
+    #[test]
+    fn every_code_is_used() {
+        let mut unused = ErrorCode::ALL.iter().copied().collect::<HashSet<_>>();
+        for error in sample_errors() {
+            unused.remove(&error.error_code());
+        }
+        // Set by the server for requests, not derived from an error.
+        unused.remove(&ErrorCode::ReplayedRequest);
+        unused.remove(&ErrorCode::FailedToProcessIrisShares);
+        unused.remove(&ErrorCode::RequestNotAgreed);
+        unused.remove(&ErrorCode::UnknownReauthTarget);
+        assert_eq!(unused, HashSet::new());
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        for code in ErrorCode::ALL {
+            let json = serde_json::to_string(code).unwrap();
+            assert_eq!(json, format!("\"{}\"", code.as_str()));
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), *code);
+            let number = code.as_u16().to_string();
+            assert_eq!(serde_json::from_str::<ErrorCode>(&number).unwrap(), *code);
+        }
+        assert!(serde_json::from_str::<ErrorCode>("\"no_such_code\"").is_err());
+        assert!(serde_json::from_str::<ErrorCode>("65536").is_err());
+    }
+
+    #[test]
+    fn code_of_report() {
+        let report = eyre::Report::new(ShareEncodingError::UnsupportedVersion(2))
+            .wrap_err("Failed to base64 parse iris code");
+        assert_eq!(error_code_of(&report), ErrorCode::UnsupportedShareVersion);
+        let report = Err::<(), _>(SharesDecodingError::SealedBoxOpenError)
+            .context("Failed to decrypt iris shares")
+            .unwrap_err();
+        assert_eq!(error_code_of(&report), ErrorCode::ShareDecryptionFailed);
+        assert_eq!(
+            error_message_of(&report),
+            SharesDecodingError::SealedBoxOpenError.to_string()
+        );
+        assert_eq!(
+            error_message_of(&eyre::eyre!("Replayed request")),
+            "Replayed request"
+        );
+        assert_eq!(
+            error_code_of(&eyre::eyre!("Replayed request")),
+            ErrorCode::Internal
+        );
+    }
+}
@@ -3,18 +3,234 @@ pub type CompactGaloisRingShares = Vec<Vec<u8>>;
 pub mod degree4 {
     use crate::{
         galois::degree4::{basis, GaloisRingElement, ShamirGaloisRingShare},
+        id::PartyID,
         iris_db::iris::{IrisCode, IrisCodeArray},
         IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
     };
     use base64::{prelude::BASE64_STANDARD, Engine};
-    use rand::{CryptoRng, Rng};
+    use rand::{rngs::StdRng, CryptoRng, Rng, SeedableRng};
+    use rayon::prelude::*;
     use serde::{Deserialize, Serialize};
     use serde_big_array::BigArray;
+    use sha2::{Digest, Sha256};
+    use std::sync::LazyLock;
+    use thiserror::Error;
 
     const CODE_COLS: usize = 200;
 
+    /// Version of the encoding written by
+    /// [`GaloisRingIrisCodeShare::to_bytes`].
+    pub const SHARE_ENCODING_VERSION: u8 = 1;
+    /// The version, the party id, the [`ShareKind`] and the number of
+    /// coefficients as u32, followed by the little-endian coefficients.
+    const SHARE_HEADER_LEN: usize = 7;
+    /// The length of a share of [`GaloisRingIrisCodeShare::to_bytes`].
+    pub const SHARE_LEN: usize = SHARE_HEADER_LEN + 2 * IRIS_CODE_LENGTH;
+    /// Shares encoded before the header was introduced are the bincode
+    /// encoding of the struct: the id as u64 followed by the coefficients.
+    pub const LEGACY_SHARE_LEN: usize = 8 + 2 * IRIS_CODE_LENGTH;
+
+    /// Whether an encoded share holds an iris code or a mask.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(u8)]
+    pub enum ShareKind {
+        Code = 0,
+        Mask = 1,
+    }
+
+    #[derive(Error, Debug, Clone, PartialEq, Eq)]
+    pub enum ShareEncodingError {
+        #[error("Invalid base64: {0}")]
+        Base64(String),
+        #[error("Truncated share of {0} bytes")]
+        Truncated(usize),
+        #[error("Unsupported share encoding version {0}")]
+        UnsupportedVersion(u8),
+        #[error("Invalid party id {0}")]
+        InvalidPartyId(usize),
+        #[error("Expected a {expected:?} share, got kind {got}")]
+        WrongKind { expected: ShareKind, got: u8 },
+        #[error("Expected {expected} coefficients, got {got}")]
+        WrongLength { expected: usize, got: usize },
+        #[error("{0} bytes after the coefficients")]
+        TrailingBytes(usize),
+    }
+
+    /// Why a decoded share is rejected before it enters a batch, see
+    /// [`GaloisRingIrisCodeShare::validate`].
+    #[derive(Error, Debug, Clone, PartialEq, Eq)]
+    pub enum ShareValidationError {
+        #[error(transparent)]
+        Encoding(#[from] ShareEncodingError),
+        #[error("Invalid party id {0}")]
+        InvalidPartyId(usize),
+        #[error("Share of party {got}, expected party {expected}")]
+        WrongParty { expected: usize, got: usize },
+        #[error("All coefficients of the share are {0}")]
+        Constant(u16),
+        #[error("The two halves of the mask share are equal")]
+        DuplicatedMaskHalves,
+    }
+
+    fn encode_share(id: usize, kind: ShareKind, coefs: &[u16]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SHARE_HEADER_LEN + 2 * coefs.len());
+        bytes.push(SHARE_ENCODING_VERSION);
+        bytes.push(u8::try_from(id).expect("party ids fit into a byte"));
+        bytes.push(kind as u8);
+        bytes.extend_from_slice(&(coefs.len() as u32).to_le_bytes());
+        for coef in coefs {
+            bytes.extend_from_slice(&coef.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Checks the header and fills in the coefficients. Returns the party id.
+    fn decode_share(
+        bytes: &[u8],
+        kind: ShareKind,
+        coefs: &mut [u16],
+    ) -> Result<usize, ShareEncodingError> {
+        if bytes.len() < SHARE_HEADER_LEN {
+            return Err(ShareEncodingError::Truncated(bytes.len()));
+        }
+        let (header, body) = bytes.split_at(SHARE_HEADER_LEN);
+        if header[0] != SHARE_ENCODING_VERSION {
+            return Err(ShareEncodingError::UnsupportedVersion(header[0]));
+        }
+        let id = decode_party_id(header[1] as usize)?;
+        if header[2] != kind as u8 {
+            return Err(ShareEncodingError::WrongKind {
+                expected: kind,
+                got:      header[2],
+            });
+        }
+        let count = u32::from_le_bytes(header[3..7].try_into().unwrap()) as usize;
+        if count != coefs.len() {
+            return Err(ShareEncodingError::WrongLength {
+                expected: coefs.len(),
+                got:      count,
+            });
+        }
+        match body.len().cmp(&(2 * count)) {
+            std::cmp::Ordering::Less => return Err(ShareEncodingError::Truncated(bytes.len())),
+            std::cmp::Ordering::Greater => {
+                return Err(ShareEncodingError::TrailingBytes(body.len() - 2 * count))
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+        decode_coefs(body, coefs);
+        Ok(id)
+    }
+
+    fn decode_party_id(id: usize) -> Result<usize, ShareEncodingError> {
+        if (1..=3).contains(&id) {
+            Ok(id)
+        } else {
+            Err(ShareEncodingError::InvalidPartyId(id))
+        }
+    }
+
+    fn decode_coefs(bytes: &[u8], coefs: &mut [u16]) {
+        for (coef, bytes) in coefs.iter_mut().zip(bytes.chunks_exact(2)) {
+            *coef = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+
+    /// The Lagrange coefficients at zero of every ordered subset of the
+    /// parties, see [`lagrange_coeffs`].
+    struct LagrangeTable {
+        /// The coefficients of the parties `a` and `b` at `[a][b]`, of the
+        /// sharing of degree 1. The diagonal is unused.
+        pairs:   [[[GaloisRingElement<basis::Monomial>; 2]; 3]; 3],
+        /// The coefficients of the parties `a`, `b` and the third party at
+        /// `[a][b]`, of the sharing of degree 2. The diagonal is unused.
+        triples: [[[GaloisRingElement<basis::Monomial>; 3]; 3]; 3],
+    }
+
+    static LAGRANGE_TABLE: LazyLock<LagrangeTable> = LazyLock::new(|| {
+        let party = |i: usize| PartyID::try_from(i).unwrap();
+        let deg_2 = ShamirGaloisRingShare::deg_2_lagrange_polys_at_zero();
+        LagrangeTable {
+            pairs:   std::array::from_fn(|a| {
+                std::array::from_fn(|b| {
+                    if a == b {
+                        return [GaloisRingElement::ZERO; 2];
+                    }
+                    [
+                        ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(party(a), party(b)),
+                        ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(party(b), party(a)),
+                    ]
+                })
+            }),
+            triples: std::array::from_fn(|a| {
+                std::array::from_fn(|b| {
+                    if a == b {
+                        return [GaloisRingElement::ZERO; 3];
+                    }
+                    [deg_2[a], deg_2[b], deg_2[3 - a - b]]
+                })
+            }),
+        }
+    });
+
+    /// The Lagrange coefficients at zero of the given parties, in the same
+    /// order: of the sharing of degree 1 for two parties and of the sharing of
+    /// degree 2 for all three. They are computed on the first call only.
+    ///
+    /// Panics unless the parties are two or three different ones.
+    pub fn lagrange_coeffs(parties: &[PartyID]) -> &'static [GaloisRingElement<basis::Monomial>] {
+        let table = &*LAGRANGE_TABLE;
+        match *parties {
+            [a, b] if a != b => &table.pairs[usize::from(a)][usize::from(b)],
+            [a, b, c] if a != b && a != c && b != c => {
+                &table.triples[usize::from(a)][usize::from(b)]
+            }
+            _ => panic!("No Lagrange coefficients for the parties {:?}", parties),
+        }
+    }
+
+    /// The coefficient of the party with the given share id in the sharing of
+    /// degree 2.
+    fn deg_2_lagrange_coeff(id: usize) -> GaloisRingElement<basis::Monomial> {
+        lagrange_coeffs(&[PartyID::ID0, PartyID::ID1, PartyID::ID2])[id - 1]
+    }
+
+    fn decode_base64(s: &str) -> Result<Vec<u8>, ShareEncodingError> {
+        BASE64_STANDARD
+            .decode(s)
+            .map_err(|e| ShareEncodingError::Base64(e.to_string()))
+    }
+
+    /// A query share split into the byte limbs the GPU multiplies, with the
+    /// sums of the limbs. See
+    /// [`GaloisRingIrisCodeShare::preprocess_for_query`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct PreprocessedQueryShare {
+        /// The low and the high byte of every coefficient, shifted by -128 so
+        /// that they are read as i8.
+        pub limbs: [Vec<u8>; 2],
+        /// The sum of each limb read as i8, as the bits of an i32.
+        pub sums:  [u32; 2],
+    }
+
+    fn preprocess_limbs(coefs: &[u16]) -> PreprocessedQueryShare {
+        let mut limbs = [vec![0u8; coefs.len()], vec![0u8; coefs.len()]];
+        let mut sums = [0i32; 2];
+        for (i, &coef) in coefs.iter().enumerate() {
+            for (limb, (limbs, sum)) in limbs.iter_mut().zip(sums.iter_mut()).enumerate() {
+                let byte = (coef >> (8 * limb)) as u8;
+                limbs[i] = (byte as i32 - 128) as u8;
+                *sum += byte as i32 - 128;
+            }
+        }
+        PreprocessedQueryShare {
+            limbs,
+            sums: sums.map(|sum| sum as u32),
+        }
+    }
+
     fn preprocess_coefs(id: usize, coefs: &mut [u16]) {
-        let lagrange_coeffs = ShamirGaloisRingShare::deg_2_lagrange_polys_at_zero();
+        let lagrange_coeff = deg_2_lagrange_coeff(id);
         for i in (0..coefs.len()).step_by(4) {
             let element = GaloisRingElement::<basis::Monomial>::from_coefs([
                 coefs[i],
@@ -23,7 +239,7 @@ pub mod degree4 {
                 coefs[i + 3],
             ]);
             // include lagrange coeffs
-            let element: GaloisRingElement<basis::Monomial> = element * lagrange_coeffs[id - 1];
+            let element: GaloisRingElement<basis::Monomial> = element * lagrange_coeff;
             let element = element.to_basis_B();
             coefs[i] = element.coefs[0];
             coefs[i + 1] = element.coefs[1];
@@ -32,6 +248,28 @@ pub mod degree4 {
         }
     }
 
+    /// The RNG of the refresh of the `index`-th share of a column, see
+    /// [`GaloisRingIrisCodeShare::rerandomize`].
+    fn refresh_rng(refresh_key: &[u8; 16], counter: u64, index: u64) -> StdRng {
+        let mut hasher = Sha256::new();
+        hasher.update(refresh_key);
+        hasher.update(counter.to_le_bytes());
+        hasher.update(index.to_le_bytes());
+        StdRng::from_seed(hasher.finalize().into())
+    }
+
+    // Adds the share of party `id` of the sharing of zero `a * x` with a random
+    // `a` per Galois ring element. All parties draw the same `a` from `rng`.
+    fn add_zero_sharing(id: usize, coefs: &mut [u16], rng: &mut StdRng) {
+        let point = GaloisRingElement::EXCEPTIONAL_SEQUENCE[id];
+        for element in coefs.chunks_exact_mut(4) {
+            let zero = GaloisRingElement::<basis::Monomial>::random(rng) * point;
+            for (coef, zero) in element.iter_mut().zip(zero.coefs) {
+                *coef = coef.wrapping_add(zero);
+            }
+        }
+    }
+
     fn rotate_coefs_right(coefs: &mut [u16], by: usize) {
         coefs
             .chunks_exact_mut(CODE_COLS * 4)
@@ -44,6 +282,18 @@ pub mod degree4 {
             .for_each(|chunk| chunk.rotate_left(by * 4));
     }
 
+    // Every chunk of CODE_COLS * 4 coefficients holds one row of the code, with
+    // the 4 coefficients of a Galois ring element per column. Reversing the
+    // elements within each chunk reverses the columns, see `remap_index`.
+    fn mirror_coefs(coefs: &mut [u16]) {
+        coefs.chunks_exact_mut(CODE_COLS * 4).for_each(|chunk| {
+            chunk.reverse();
+            chunk
+                .chunks_exact_mut(4)
+                .for_each(|element| element.reverse());
+        });
+    }
+
     #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
     pub struct GaloisRingTrimmedMaskCodeShare {
         pub id:    usize,
@@ -76,6 +326,50 @@ pub mod degree4 {
     }
 
     impl GaloisRingTrimmedMaskCodeShare {
+        /// Keeps the first half of a share of [`encode_mask_code`], the mask
+        /// is duplicated in the second half.
+        ///
+        /// [`encode_mask_code`]: GaloisRingIrisCodeShare::encode_mask_code
+        pub fn from_full(share: &GaloisRingIrisCodeShare) -> Self {
+            share.into()
+        }
+
+        /// The full width share of the mask, with the coefficients in both
+        /// halves. It shares the same mask as the share it was trimmed from,
+        /// but repeats the randomness of the first half, so it fails
+        /// [`GaloisRingIrisCodeShare::validate_mask`] and is only meant for
+        /// local use.
+        pub fn to_full(&self) -> GaloisRingIrisCodeShare {
+            let mut coefs = [0; IRIS_CODE_LENGTH];
+            coefs[..MASK_CODE_LENGTH].copy_from_slice(&self.coefs);
+            coefs[MASK_CODE_LENGTH..].copy_from_slice(&self.coefs);
+            GaloisRingIrisCodeShare::new(self.id, coefs)
+        }
+
+        /// Trims the mask shares of all three parties. Fails if the mask bits
+        /// are not set pairwise like those of a valid mask, as trimming would
+        /// lose the second bit of a pair. Reconstructs the mask to check
+        /// this, so only use it where the mask may be revealed.
+        pub fn trim_checked(
+            shares: &[GaloisRingIrisCodeShare; 3],
+        ) -> Result<[Self; 3], ReconstructionError> {
+            let values = GaloisRingIrisCodeShare::reconstruct_encoded(shares)?;
+            // The pairs are adjacent in the original layout, and the halves of
+            // the share layout split every pair.
+            for index in (0..IRIS_CODE_LENGTH).step_by(2) {
+                if values[index] != values[index + 1] {
+                    return Err(ReconstructionError::UntrimmableMask(index + 1));
+                }
+            }
+            Ok(shares.each_ref().map(Self::from_full))
+        }
+
+        /// Reconstructs the mask from trimmed shares, see
+        /// [`GaloisRingIrisCodeShare::reconstruct_mask`].
+        pub fn reconstruct(shares: &[Self; 3]) -> Result<IrisCodeArray, ReconstructionError> {
+            GaloisRingIrisCodeShare::reconstruct_mask(&shares.each_ref().map(Self::to_full))
+        }
+
         pub fn default_for_party(party_id: usize) -> Self {
             GaloisRingTrimmedMaskCodeShare {
                 id:    party_id,
@@ -87,16 +381,73 @@ pub mod degree4 {
             preprocess_coefs(self.id, &mut self.coefs);
         }
 
+        /// Like [`GaloisRingIrisCodeShare::rerandomize`].
+        pub fn rerandomize(&mut self, refresh_key: &[u8; 16], counter: u64) {
+            add_zero_sharing(
+                self.id,
+                &mut self.coefs,
+                &mut refresh_rng(refresh_key, counter, 0),
+            );
+        }
+
+        /// Like [`GaloisRingIrisCodeShare::rerandomize_column`].
+        pub fn rerandomize_column(shares: &mut [Self], refresh_key: &[u8; 16], counter: u64) {
+            shares
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(index, share)| {
+                    add_zero_sharing(
+                        share.id,
+                        &mut share.coefs,
+                        &mut refresh_rng(refresh_key, counter, index as u64),
+                    );
+                });
+        }
+
+        /// The rotations of the share, in the order of
+        /// [`GaloisRingIrisCodeShare::all_rotations`].
         pub fn all_rotations(&self) -> Vec<GaloisRingTrimmedMaskCodeShare> {
+            self.rotations_within(IrisCodeArray::MAX_ROTATION)
+        }
+
+        /// Like [`GaloisRingIrisCodeShare::rotations_within`].
+        pub fn rotations_within(&self, window: usize) -> Vec<GaloisRingTrimmedMaskCodeShare> {
+            assert!(
+                window <= IrisCodeArray::MAX_ROTATION,
+                "rotation window too large"
+            );
             let mut reference = self.clone();
             let mut result = vec![];
-            rotate_coefs_left(&mut reference.coefs, 16);
-            for _ in 0..31 {
+            rotate_coefs_left(&mut reference.coefs, window + 1);
+            for _ in 0..2 * window + 1 {
                 rotate_coefs_right(&mut reference.coefs, 1);
                 result.push(reference.clone());
             }
             result
         }
+
+        /// Like [`GaloisRingIrisCodeShare::padded_rotations`].
+        pub fn padded_rotations(&self, window: usize) -> Vec<GaloisRingTrimmedMaskCodeShare> {
+            let padding = IrisCodeArray::MAX_ROTATION.saturating_sub(window);
+            let zero = Self::default_for_party(self.id);
+            let mut result = vec![zero.clone(); padding];
+            result.extend(self.rotations_within(window));
+            result.resize(IrisCodeArray::ROTATIONS, zero);
+            result
+        }
+
+        /// Share of the mirrored mask, see [`IrisCodeArray::mirrored`].
+        pub fn mirrored(&self) -> Self {
+            let mut mirrored = self.clone();
+            mirror_coefs(&mut mirrored.coefs);
+            mirrored
+        }
+
+        /// Like [`GaloisRingIrisCodeShare::preprocess_for_query`].
+        pub fn preprocess_for_query(&self) -> PreprocessedQueryShare {
+            preprocess_limbs(&self.coefs)
+        }
+
         pub fn trick_dot(&self, other: &GaloisRingTrimmedMaskCodeShare) -> u16 {
             let mut sum = 0u16;
             for i in 0..MASK_CODE_LENGTH {
@@ -104,6 +455,26 @@ pub mod degree4 {
             }
             sum
         }
+
+        /// Encodes the trimmed share like [`GaloisRingIrisCodeShare::to_bytes`]
+        /// encodes a mask, with fewer coefficients.
+        pub fn to_bytes(&self) -> Vec<u8> {
+            encode_share(self.id, ShareKind::Mask, &self.coefs)
+        }
+
+        pub fn from_bytes(bytes: &[u8]) -> Result<Self, ShareEncodingError> {
+            let mut coefs = [0; MASK_CODE_LENGTH];
+            let id = decode_share(bytes, ShareKind::Mask, &mut coefs)?;
+            Ok(Self { id, coefs })
+        }
+
+        pub fn to_base64(&self) -> String {
+            BASE64_STANDARD.encode(self.to_bytes())
+        }
+
+        pub fn from_base64(s: &str) -> Result<Self, ShareEncodingError> {
+            Self::from_bytes(&decode_base64(s)?)
+        }
     }
 
     #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -221,6 +592,28 @@ pub mod degree4 {
             shares
         }
 
+        /// Encodes the code and the mask like [`Self::encode_iris_code`] and
+        /// [`Self::encode_mask_code`], and returns the rotations of the shares
+        /// of every party. The i-th code share and the i-th mask share of a
+        /// party are rotated by the same number of columns, see
+        /// [`Self::all_rotations`].
+        #[allow(clippy::type_complexity)]
+        pub fn encode_iris_code_all_rotations<R: CryptoRng + Rng>(
+            iris_code: &IrisCodeArray,
+            mask_code: &IrisCodeArray,
+            rng: &mut R,
+        ) -> [(
+            Vec<GaloisRingIrisCodeShare>,
+            Vec<GaloisRingTrimmedMaskCodeShare>,
+        ); 3] {
+            let code_shares = Self::encode_iris_code(iris_code, mask_code, rng);
+            let mask_shares = Self::encode_mask_code(mask_code, rng);
+            std::array::from_fn(|i| {
+                let mask_share = GaloisRingTrimmedMaskCodeShare::from(&mask_shares[i]);
+                (code_shares[i].all_rotations(), mask_share.all_rotations())
+            })
+        }
+
         #[allow(clippy::assertions_on_constants)]
         pub fn reencode_extended_iris_code<R: CryptoRng + Rng>(
             iris_code: &[u16; IRIS_CODE_LENGTH],
@@ -264,9 +657,18 @@ pub mod degree4 {
             preprocess_coefs(self.id, &mut self.coefs);
         }
 
+        /// Splits the share into the limbs of `preprocess_query` in the GPU
+        /// crate and sums them like `ShareDB::query_sums`, so the sums need not
+        /// be computed on the devices. Call it on a share that went through
+        /// [`Self::preprocess_iris_code_query_share`]. A rotation permutes the
+        /// coefficients, so all rotations of the share have the same sums.
+        pub fn preprocess_for_query(&self) -> PreprocessedQueryShare {
+            preprocess_limbs(&self.coefs)
+        }
+
         pub fn full_dot(&self, other: &GaloisRingIrisCodeShare) -> u16 {
             let mut sum = 0u16;
-            let lagrange_coeffs = ShamirGaloisRingShare::deg_2_lagrange_polys_at_zero();
+            let lagrange_coeff = deg_2_lagrange_coeff(self.id);
             for i in (0..IRIS_CODE_LENGTH).step_by(4) {
                 let x = GaloisRingElement::from_coefs([
                     self.coefs[i],
@@ -281,7 +683,7 @@ pub mod degree4 {
                     other.coefs[i + 3],
                 ]);
                 let z = x * y;
-                let z = z * lagrange_coeffs[self.id - 1];
+                let z = z * lagrange_coeff;
                 let z = z.to_basis_B();
                 sum = sum.wrapping_add(z.coefs[0]);
             }
@@ -295,26 +697,300 @@ pub mod degree4 {
             sum
         }
 
+        /// The 31 rotations of the share by -15 to 15 columns, in that order.
+        /// Rotating by `k` columns moves column `c` of every row to column
+        /// `c + k`, wrapping around. The middle entry is the unrotated share.
         pub fn all_rotations(&self) -> Vec<GaloisRingIrisCodeShare> {
+            self.rotations_within(IrisCodeArray::MAX_ROTATION)
+        }
+
+        /// The rotations of the share by `-window` to `window` columns, in
+        /// the order of [`Self::all_rotations`]. Panics if `window` is above
+        /// [`IrisCodeArray::MAX_ROTATION`].
+        pub fn rotations_within(&self, window: usize) -> Vec<GaloisRingIrisCodeShare> {
+            assert!(
+                window <= IrisCodeArray::MAX_ROTATION,
+                "rotation window too large"
+            );
             let mut reference = self.clone();
             let mut result = vec![];
-            rotate_coefs_left(&mut reference.coefs, 16);
-            for _ in 0..31 {
+            rotate_coefs_left(&mut reference.coefs, window + 1);
+            for _ in 0..2 * window + 1 {
                 rotate_coefs_right(&mut reference.coefs, 1);
                 result.push(reference.clone());
             }
             result
         }
 
-        pub fn to_base64(&self) -> String {
-            let as_vec_u8 = bincode::serialize(&self).expect("to serialize");
-            BASE64_STANDARD.encode::<Vec<u8>>(as_vec_u8)
+        /// The rotations of [`Self::rotations_within`] in the layout of
+        /// [`Self::all_rotations`], with shares of zero in place of the
+        /// rotations beyond the window. A query with a code of zero never
+        /// matches, whatever its mask.
+        pub fn padded_rotations(&self, window: usize) -> Vec<GaloisRingIrisCodeShare> {
+            let padding = IrisCodeArray::MAX_ROTATION.saturating_sub(window);
+            let zero = Self::default_for_party(self.id);
+            let mut result = vec![zero.clone(); padding];
+            result.extend(self.rotations_within(window));
+            result.resize(IrisCodeArray::ROTATIONS, zero);
+            result
+        }
+
+        /// Share of the mirrored code, see [`IrisCodeArray::mirrored`]. The
+        /// mirroring permutes whole Galois ring elements, so it commutes with
+        /// the sharing. Mirroring the rotation by `k` columns gives the
+        /// rotation of the mirrored share by `-k` columns, so the rotations of
+        /// a mirrored share are its mirrored rotations in reverse order.
+        pub fn mirrored(&self) -> Self {
+            let mut mirrored = self.clone();
+            mirror_coefs(&mut mirrored.coefs);
+            mirrored
         }
 
-        pub fn from_base64(s: &str) -> eyre::Result<Self> {
-            let decoded_bytes = BASE64_STANDARD.decode(s)?;
-            Ok(bincode::deserialize(&decoded_bytes)?)
+        /// Refreshes the share by adding a pseudorandom sharing of zero, so the
+        /// shares reconstruct the same values but are independent of the
+        /// previous ones. All three parties must use the same `refresh_key`
+        /// and `counter`, and never reuse a counter with the same key.
+        ///
+        /// A sharing of zero of degree 1 is `a * x` for some `a`, and every
+        /// party needs `a` to compute its share. Keys of pairs of parties thus
+        /// do not suffice, the key has to be common to all parties.
+        pub fn rerandomize(&mut self, refresh_key: &[u8; 16], counter: u64) {
+            add_zero_sharing(
+                self.id,
+                &mut self.coefs,
+                &mut refresh_rng(refresh_key, counter, 0),
+            );
         }
+
+        /// [`Self::rerandomize`] for a column of shares, e.g. the shares of a
+        /// party in the DB. The parties must pass the shares in the same
+        /// order, every share gets its own sharing of zero.
+        pub fn rerandomize_column(shares: &mut [Self], refresh_key: &[u8; 16], counter: u64) {
+            shares
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(index, share)| {
+                    add_zero_sharing(
+                        share.id,
+                        &mut share.coefs,
+                        &mut refresh_rng(refresh_key, counter, index as u64),
+                    );
+                });
+        }
+
+        /// Reconstructs the encoded values, in the original code layout, from
+        /// the shares of two different parties. The sharing has degree 1, so
+        /// any two shares determine the values.
+        pub fn reconstruct_encoded_pair(a: &Self, b: &Self) -> Vec<u16> {
+            assert_ne!(a.id, b.id, "shares must be from different parties");
+            let party = |id: usize| PartyID::try_from(id - 1).expect("share id out of range");
+            let lagrange = lagrange_coeffs(&[party(a.id), party(b.id)]);
+            let (lagrange_a, lagrange_b) = (lagrange[0], lagrange[1]);
+            let mut values = vec![0u16; IRIS_CODE_LENGTH];
+            for i in (0..IRIS_CODE_LENGTH).step_by(4) {
+                let element = |share: &Self| {
+                    GaloisRingElement::<basis::Monomial>::from_coefs([
+                        share.coefs[i],
+                        share.coefs[i + 1],
+                        share.coefs[i + 2],
+                        share.coefs[i + 3],
+                    ])
+                };
+                let element = (element(a) * lagrange_a + element(b) * lagrange_b).to_basis_A();
+                for j in 0..4 {
+                    values[Self::remap_index(i + j)] = element.coefs[j];
+                }
+            }
+            values
+        }
+
+        /// Reconstructs the encoded values, in the original code layout, from
+        /// the shares of all three parties. Fails unless the shares belong to
+        /// different parties and every pair of them reconstructs the same
+        /// values.
+        pub fn reconstruct_encoded(shares: &[Self; 3]) -> Result<Vec<u16>, ReconstructionError> {
+            let ids = [shares[0].id, shares[1].id, shares[2].id];
+            let mut sorted_ids = ids;
+            sorted_ids.sort_unstable();
+            if sorted_ids != [1, 2, 3] {
+                return Err(ReconstructionError::PartyIds(ids));
+            }
+            let values = Self::reconstruct_encoded_pair(&shares[0], &shares[1]);
+            let check = Self::reconstruct_encoded_pair(&shares[0], &shares[2]);
+            if let Some(index) = values.iter().zip(&check).position(|(a, b)| a != b) {
+                return Err(ReconstructionError::Inconsistent(index));
+            }
+            Ok(values)
+        }
+
+        /// Reconstructs the iris code from the shares produced by
+        /// [`Self::encode_iris_code`]. Only the bits within the mask are
+        /// encoded, the others come out as 0.
+        pub fn reconstruct(shares: &[Self; 3]) -> Result<IrisCodeArray, ReconstructionError> {
+            let mut code = IrisCodeArray::ZERO;
+            for (index, value) in Self::reconstruct_encoded(shares)?.into_iter().enumerate() {
+                match value {
+                    0 | 1 => {}
+                    u16::MAX => code.set_bit(index, true),
+                    _ => {
+                        return Err(ReconstructionError::InvalidValue {
+                            index,
+                            value,
+                            kind: "code",
+                        })
+                    }
+                }
+            }
+            Ok(code)
+        }
+
+        /// Reconstructs the mask from the shares produced by
+        /// [`Self::encode_mask_code`].
+        pub fn reconstruct_mask(shares: &[Self; 3]) -> Result<IrisCodeArray, ReconstructionError> {
+            let mut mask = IrisCodeArray::ZERO;
+            for (index, value) in Self::reconstruct_encoded(shares)?.into_iter().enumerate() {
+                match value {
+                    0 => {}
+                    1 => mask.set_bit(index, true),
+                    _ => {
+                        return Err(ReconstructionError::InvalidValue {
+                            index,
+                            value,
+                            kind: "mask",
+                        })
+                    }
+                }
+            }
+            Ok(mask)
+        }
+
+        /// Encodes the share with a header, see [`SHARE_ENCODING_VERSION`].
+        /// The kind is not part of the share, it has to be given by the
+        /// caller.
+        pub fn to_bytes(&self, kind: ShareKind) -> Vec<u8> {
+            encode_share(self.id, kind, &self.coefs)
+        }
+
+        /// Decodes a share written by [`GaloisRingIrisCodeShare::to_bytes`].
+        /// Shares without a header, as written before it was introduced, are
+        /// accepted as well.
+        pub fn from_bytes(bytes: &[u8], kind: ShareKind) -> Result<Self, ShareEncodingError> {
+            let mut coefs = [0; IRIS_CODE_LENGTH];
+            // A share with a header has an odd length.
+            let id = if bytes.len() == LEGACY_SHARE_LEN {
+                let (id, body) = bytes.split_at(8);
+                let id = u64::from_le_bytes(id.try_into().unwrap());
+                decode_coefs(body, &mut coefs);
+                decode_party_id(id.try_into().unwrap_or(usize::MAX))?
+            } else {
+                decode_share(bytes, kind, &mut coefs)?
+            };
+            Ok(Self { id, coefs })
+        }
+
+        pub fn to_base64(&self, kind: ShareKind) -> String {
+            BASE64_STANDARD.encode(self.to_bytes(kind))
+        }
+
+        pub fn from_base64(s: &str, kind: ShareKind) -> Result<Self, ShareEncodingError> {
+            Self::from_bytes(&decode_base64(s)?, kind)
+        }
+
+        /// Checks the invariants of a share that the encoder guarantees: a
+        /// party id of 1 to 3 and coefficients that are not all the same, as
+        /// in a zeroed or filled buffer. The number of coefficients is fixed by
+        /// the type, decoding rejects any other count with
+        /// [`ShareEncodingError::WrongLength`].
+        pub fn validate(&self) -> Result<(), ShareValidationError> {
+            if !(1..=3).contains(&self.id) {
+                return Err(ShareValidationError::InvalidPartyId(self.id));
+            }
+            if self.coefs.iter().all(|&coef| coef == self.coefs[0]) {
+                return Err(ShareValidationError::Constant(self.coefs[0]));
+            }
+            Ok(())
+        }
+
+        /// [`Self::validate`] for a share of [`Self::encode_mask_code`]. The
+        /// mask is duplicated in its two halves, which the encoder shares with
+        /// independent randomness, so the halves of a share never agree.
+        pub fn validate_mask(&self) -> Result<(), ShareValidationError> {
+            self.validate()?;
+            let (first, second) = self.coefs.split_at(MASK_CODE_LENGTH);
+            if first == second {
+                return Err(ShareValidationError::DuplicatedMaskHalves);
+            }
+            Ok(())
+        }
+
+        /// Decodes a share received by the party and validates it. The party id
+        /// tag of a share with a header has to be the party's, shares without
+        /// a header are accepted as they are.
+        pub fn decode_validated(
+            s: &str,
+            kind: ShareKind,
+            party_id: usize,
+        ) -> Result<Self, ShareValidationError> {
+            let bytes = decode_base64(s)?;
+            let share = Self::from_bytes(&bytes, kind)?;
+            if bytes.len() != LEGACY_SHARE_LEN && share.id != party_id + 1 {
+                return Err(ShareValidationError::WrongParty {
+                    expected: party_id + 1,
+                    got:      share.id,
+                });
+            }
+            match kind {
+                ShareKind::Code => share.validate()?,
+                ShareKind::Mask => share.validate_mask()?,
+            }
+            Ok(share)
+        }
+    }
+
+    /// Encodes the irises like [`GaloisRingIrisCodeShare::encode_iris_code`],
+    /// in parallel. Every iris is encoded with its own RNG, seeded from a seed
+    /// drawn from `rng` and its index, so the shares do not depend on the
+    /// number of threads.
+    pub fn encode_iris_codes_batch(
+        irises: &[IrisCode],
+        rng: &mut (impl Rng + CryptoRng),
+    ) -> [Vec<GaloisRingIrisCodeShare>; 3] {
+        let seed: [u8; 32] = rng.gen();
+        let shares = irises
+            .par_iter()
+            .enumerate()
+            .map(|(index, iris)| {
+                let mut hasher = Sha256::new();
+                hasher.update(seed);
+                hasher.update((index as u64).to_le_bytes());
+                let mut rng = StdRng::from_seed(hasher.finalize().into());
+                GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)
+            })
+            .collect::<Vec<_>>();
+
+        let mut batch = [0; 3].map(|_| Vec::with_capacity(irises.len()));
+        for [share0, share1, share2] in shares {
+            batch[0].push(share0);
+            batch[1].push(share1);
+            batch[2].push(share2);
+        }
+        batch
+    }
+
+    #[derive(Error, Debug, Clone, PartialEq, Eq)]
+    pub enum ReconstructionError {
+        #[error("Expected one share of each of the parties 1, 2 and 3, got {0:?}")]
+        PartyIds([usize; 3]),
+        #[error("The shares reconstruct to different values at index {0}")]
+        Inconsistent(usize),
+        #[error("Value {value} at index {index} is not a valid {kind} encoding")]
+        InvalidValue {
+            index: usize,
+            value: u16,
+            kind:  &'static str,
+        },
+        #[error("The mask differs from its pair at index {0}, it cannot be trimmed")]
+        UntrimmableMask(usize),
     }
 
     pub struct FullGaloisRingIrisCodeShare {
@@ -350,12 +1026,262 @@ pub mod degree4 {
     #[cfg(test)]
     mod tests {
         use crate::{
-            galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
-            iris_db::iris::IrisCodeArray,
-            MASK_CODE_LENGTH,
+            galois::degree4::{basis, GaloisRingElement, ShamirGaloisRingShare},
+            galois_engine::degree4::{
+                encode_iris_codes_batch, lagrange_coeffs, GaloisRingIrisCodeShare,
+                GaloisRingTrimmedMaskCodeShare, ReconstructionError, ShareEncodingError, ShareKind,
+                ShareValidationError, LEGACY_SHARE_LEN, SHARE_HEADER_LEN,
+            },
+            id::PartyID,
+            iris_db::iris::{IrisCode, IrisCodeArray},
+            IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
         };
+        use base64::{prelude::BASE64_STANDARD, Engine};
         use float_eq::assert_float_eq;
-        use rand::thread_rng;
+        use rand::{rngs::StdRng, thread_rng, SeedableRng};
+
+        /// Reconstructs the encoded values in the original code layout.
+        fn reconstruct(shares: &[GaloisRingIrisCodeShare; 3]) -> Vec<u16> {
+            let mut values = vec![0u16; IRIS_CODE_LENGTH];
+            for i in (0..IRIS_CODE_LENGTH).step_by(4) {
+                let element_shares = shares.clone().map(|share| ShamirGaloisRingShare {
+                    id: share.id,
+                    y:  GaloisRingElement::<basis::Monomial>::from_coefs([
+                        share.coefs[i],
+                        share.coefs[i + 1],
+                        share.coefs[i + 2],
+                        share.coefs[i + 3],
+                    ]),
+                });
+                let element =
+                    ShamirGaloisRingShare::reconstruct_deg_2_shares(&element_shares).to_basis_A();
+                for j in 0..4 {
+                    values[GaloisRingIrisCodeShare::remap_index(i + j)] = element.coefs[j];
+                }
+            }
+            values
+        }
+
+        #[test]
+        fn mirroring_commutes_with_sharing() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let mirrored = iris.mirrored();
+
+            let code_shares =
+                GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng)
+                    .map(|share| share.mirrored());
+            let expected = (0..IRIS_CODE_LENGTH)
+                .map(|i| {
+                    let mask = mirrored.mask.get_bit(i) as u16;
+                    let code = mirrored.code.get_bit(i) as u16;
+                    mask.wrapping_sub(2 * (code & mask))
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(reconstruct(&code_shares), expected);
+
+            let mask_shares = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng);
+            let mirrored_mask_shares = mask_shares.clone().map(|share| share.mirrored());
+            let expected = (0..IRIS_CODE_LENGTH)
+                .map(|i| mirrored.mask.get_bit(i) as u16)
+                .collect::<Vec<_>>();
+            assert_eq!(reconstruct(&mirrored_mask_shares), expected);
+
+            // The trimmed mask shares are mirrored the same way.
+            for (share, mirrored_share) in mask_shares.iter().zip(mirrored_mask_shares) {
+                assert_eq!(
+                    GaloisRingTrimmedMaskCodeShare::from(share).mirrored(),
+                    GaloisRingTrimmedMaskCodeShare::from(mirrored_share)
+                );
+            }
+        }
+
+        /// Rotates every row of the code by `by` columns to the right.
+        fn rotate_columns(code: &IrisCodeArray, by: isize) -> IrisCodeArray {
+            let cols = IrisCodeArray::IRIS_CODE_COLS;
+            let col_bits = 4;
+            let mut res = IrisCodeArray::ZERO;
+            for i in 0..IrisCodeArray::IRIS_CODE_SIZE {
+                let row = i / (cols * col_bits);
+                let col = i % (cols * col_bits) / col_bits;
+                let rotated_col = (col as isize + by).rem_euclid(cols as isize) as usize;
+                res.set_bit(
+                    (row * cols + rotated_col) * col_bits + i % col_bits,
+                    code.get_bit(i),
+                );
+            }
+            res
+        }
+
+        #[test]
+        fn all_rotations_match_plaintext_rotations() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let [party0, party1, party2] = GaloisRingIrisCodeShare::encode_iris_code_all_rotations(
+                &iris.code, &iris.mask, rng,
+            );
+            for party in [&party0, &party1, &party2] {
+                assert_eq!(party.0.len(), 31);
+                assert_eq!(party.1.len(), 31);
+            }
+
+            for (rot_idx, by) in (-15isize..=15).enumerate() {
+                let code = rotate_columns(&iris.code, by);
+                let mask = rotate_columns(&iris.mask, by);
+
+                let code_shares = [&party0, &party1, &party2].map(|party| party.0[rot_idx].clone());
+                let expected = (0..IRIS_CODE_LENGTH)
+                    .map(|i| {
+                        let mask = mask.get_bit(i) as u16;
+                        let code = code.get_bit(i) as u16;
+                        mask.wrapping_sub(2 * (code & mask))
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(reconstruct(&code_shares), expected, "rotation {}", by);
+
+                // The trimmed mask shares hold the first half of the mask.
+                let mask_shares = [&party0, &party1, &party2].map(|party| {
+                    let mut coefs = [0; IRIS_CODE_LENGTH];
+                    coefs[..MASK_CODE_LENGTH].copy_from_slice(&party.1[rot_idx].coefs);
+                    GaloisRingIrisCodeShare::new(party.1[rot_idx].id, coefs)
+                });
+                let reconstructed = reconstruct(&mask_shares);
+                for i in (0..MASK_CODE_LENGTH).map(GaloisRingIrisCodeShare::remap_index) {
+                    assert_eq!(reconstructed[i], mask.get_bit(i) as u16, "rotation {}", by);
+                }
+            }
+        }
+
+        #[test]
+        fn mirroring_composes_with_rotations() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let [(codes, masks), ..] = GaloisRingIrisCodeShare::encode_iris_code_all_rotations(
+                &iris.code, &iris.mask, rng,
+            );
+            let mirrored_codes = codes[15].mirrored().all_rotations();
+            let mirrored_masks = masks[15].mirrored().all_rotations();
+            for rot_idx in 0..31 {
+                assert_eq!(codes[rot_idx].mirrored(), mirrored_codes[30 - rot_idx]);
+                assert_eq!(masks[rot_idx].mirrored(), mirrored_masks[30 - rot_idx]);
+            }
+
+            // The same holds for the plaintext.
+            let mirrored = iris.code.mirrored();
+            for by in -15isize..=15 {
+                assert_eq!(
+                    rotate_columns(&iris.code, by).mirrored(),
+                    rotate_columns(&mirrored, -by)
+                );
+            }
+        }
+
+        #[test]
+        fn rotations_within_window() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let code =
+                GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng)[1].clone();
+            let mask = GaloisRingTrimmedMaskCodeShare::from(&code);
+            let codes = code.all_rotations();
+            let masks = mask.all_rotations();
+            for window in [0, 4, 15] {
+                let lanes = 15 - window..=15 + window;
+                assert_eq!(code.rotations_within(window), codes[lanes.clone()]);
+                assert_eq!(mask.rotations_within(window), masks[lanes.clone()]);
+
+                let padded_codes = code.padded_rotations(window);
+                let padded_masks = mask.padded_rotations(window);
+                assert_eq!(padded_codes.len(), 31);
+                assert_eq!(padded_masks.len(), 31);
+                for lane in 0..31 {
+                    if lanes.contains(&lane) {
+                        assert_eq!(padded_codes[lane], codes[lane]);
+                        assert_eq!(padded_masks[lane], masks[lane]);
+                    } else {
+                        assert_eq!(
+                            padded_codes[lane],
+                            GaloisRingIrisCodeShare::default_for_party(2)
+                        );
+                        assert_eq!(
+                            padded_masks[lane],
+                            GaloisRingTrimmedMaskCodeShare::default_for_party(2)
+                        );
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn reconstruct_iris_code_and_mask() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let mut code_shares =
+                GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng);
+            let mask_shares = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng);
+            assert_eq!(
+                GaloisRingIrisCodeShare::reconstruct(&code_shares),
+                Ok(iris.code & iris.mask)
+            );
+            assert_eq!(
+                GaloisRingIrisCodeShare::reconstruct_mask(&mask_shares),
+                Ok(iris.mask)
+            );
+
+            // The order of the shares does not matter.
+            code_shares.swap(0, 2);
+            assert_eq!(
+                GaloisRingIrisCodeShare::reconstruct(&code_shares),
+                Ok(iris.code & iris.mask)
+            );
+
+            // Code shares do not decode as a mask.
+            assert!(matches!(
+                GaloisRingIrisCodeShare::reconstruct_mask(&code_shares),
+                Err(ReconstructionError::InvalidValue { kind: "mask", .. })
+            ));
+        }
+
+        #[test]
+        fn reconstruct_rejects_bad_shares() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let shares = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng);
+
+            let mut duplicated = shares.clone();
+            duplicated[2] = duplicated[1].clone();
+            assert_eq!(
+                GaloisRingIrisCodeShare::reconstruct_mask(&duplicated),
+                Err(ReconstructionError::PartyIds([1, 2, 2]))
+            );
+
+            let mut corrupted = shares.clone();
+            corrupted[2].coefs[0] ^= 1;
+            assert!(matches!(
+                GaloisRingIrisCodeShare::reconstruct_mask(&corrupted),
+                Err(ReconstructionError::Inconsistent(_))
+            ));
+        }
+
+        #[test]
+        fn reconstruct_from_any_pair() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let shares = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng);
+            let expected = reconstruct(&shares);
+            for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+                assert_eq!(
+                    GaloisRingIrisCodeShare::reconstruct_encoded_pair(&shares[a], &shares[b]),
+                    expected
+                );
+            }
+            assert_eq!(
+                expected,
+                (0..IRIS_CODE_LENGTH)
+                    .map(|i| iris.mask.get_bit(i) as u16)
+                    .collect::<Vec<_>>()
+            );
+        }
 
         #[test]
         fn galois_dot_trick() {
@@ -395,6 +1321,141 @@ pub mod degree4 {
             }
         }
 
+        #[test]
+        fn rerandomize_keeps_values() {
+            let rng = &mut thread_rng();
+            let key = [7u8; 16];
+            let irises = (0..4)
+                .map(|_| IrisCode::random_rng(rng))
+                .collect::<Vec<_>>();
+            let [mut codes0, mut codes1, mut codes2] = encode_iris_codes_batch(&irises, rng);
+            let original = [codes0.clone(), codes1.clone(), codes2.clone()];
+            for column in [&mut codes0, &mut codes1, &mut codes2] {
+                GaloisRingIrisCodeShare::rerandomize_column(column, &key, 1);
+            }
+            for (i, iris) in irises.iter().enumerate() {
+                let shares = [codes0[i].clone(), codes1[i].clone(), codes2[i].clone()];
+                for (share, original) in shares.iter().zip(&original) {
+                    assert_ne!(share, &original[i]);
+                }
+                assert_eq!(
+                    GaloisRingIrisCodeShare::reconstruct(&shares).unwrap(),
+                    iris.code & iris.mask
+                );
+            }
+
+            // The single share variant refreshes like the first share of a
+            // column.
+            let mut masks = GaloisRingIrisCodeShare::encode_mask_code(&irises[0].mask, rng)
+                .map(|share| GaloisRingTrimmedMaskCodeShare::from(&share));
+            let mut column = masks.clone();
+            for (mask, column) in masks.iter_mut().zip(column.iter_mut()) {
+                mask.rerandomize(&key, 2);
+                GaloisRingTrimmedMaskCodeShare::rerandomize_column(
+                    std::slice::from_mut(column),
+                    &key,
+                    2,
+                );
+            }
+            assert_eq!(masks, column);
+            assert_eq!(
+                GaloisRingTrimmedMaskCodeShare::reconstruct(&masks).unwrap(),
+                irises[0].mask
+            );
+
+            // A party that refreshes with another counter breaks the sharing.
+            let mut codes =
+                GaloisRingIrisCodeShare::encode_iris_code(&irises[0].code, &irises[0].mask, rng);
+            codes[0].rerandomize(&key, 3);
+            codes[1].rerandomize(&key, 3);
+            codes[2].rerandomize(&key, 4);
+            assert!(matches!(
+                GaloisRingIrisCodeShare::reconstruct(&codes),
+                Err(ReconstructionError::Inconsistent(_))
+            ));
+        }
+
+        #[test]
+        fn trimmed_mask_roundtrip() {
+            let rng = &mut thread_rng();
+            let iris = IrisCode::random_rng(rng);
+            let shares = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng);
+            let trimmed = GaloisRingTrimmedMaskCodeShare::trim_checked(&shares).unwrap();
+            for (share, trimmed) in shares.iter().zip(&trimmed) {
+                assert_eq!(trimmed, &GaloisRingTrimmedMaskCodeShare::from(share));
+                assert_eq!(
+                    GaloisRingTrimmedMaskCodeShare::from_full(&trimmed.to_full()),
+                    *trimmed
+                );
+            }
+            assert_eq!(
+                GaloisRingTrimmedMaskCodeShare::reconstruct(&trimmed).unwrap(),
+                iris.mask
+            );
+
+            // A mask whose bits are not set pairwise loses the second bit.
+            let mut mask = iris.mask;
+            mask.set_bit(0, true);
+            mask.set_bit(1, false);
+            let shares = GaloisRingIrisCodeShare::encode_mask_code(&mask, rng);
+            assert_eq!(
+                GaloisRingTrimmedMaskCodeShare::trim_checked(&shares),
+                Err(ReconstructionError::UntrimmableMask(1))
+            );
+            let trimmed = shares
+                .each_ref()
+                .map(GaloisRingTrimmedMaskCodeShare::from_full);
+            assert_ne!(
+                GaloisRingTrimmedMaskCodeShare::reconstruct(&trimmed).unwrap(),
+                mask
+            );
+        }
+
+        #[test]
+        fn trimmed_mask_distances() {
+            let rng = &mut thread_rng();
+            for _ in 0..10 {
+                let db = IrisCode::random_rng(rng);
+                let query = IrisCode::random_rng(rng);
+                let (_, _, expected) = db.masked_distance(&query).unwrap();
+
+                let db_codes = GaloisRingIrisCodeShare::encode_iris_code(&db.code, &db.mask, rng);
+                let db_masks = GaloisRingIrisCodeShare::encode_mask_code(&db.mask, rng);
+                let mut query_codes =
+                    GaloisRingIrisCodeShare::encode_iris_code(&query.code, &query.mask, rng);
+                let mut query_masks = GaloisRingIrisCodeShare::encode_mask_code(&query.mask, rng);
+                let db_trimmed = GaloisRingTrimmedMaskCodeShare::trim_checked(&db_masks).unwrap();
+                let mut query_trimmed =
+                    GaloisRingTrimmedMaskCodeShare::trim_checked(&query_masks).unwrap();
+                for i in 0..3 {
+                    query_codes[i].preprocess_iris_code_query_share();
+                    query_masks[i].preprocess_iris_code_query_share();
+                    query_trimmed[i].preprocess_mask_code_query_share();
+                }
+
+                let sum = |dots: [u16; 3]| dots.into_iter().fold(0u16, u16::wrapping_add);
+                let dot_codes = sum(std::array::from_fn(|i| {
+                    db_codes[i].trick_dot(&query_codes[i])
+                }));
+                let dot_masks_full = sum(std::array::from_fn(|i| {
+                    db_masks[i].trick_dot(&query_masks[i])
+                }));
+                let dot_masks_trimmed = sum(std::array::from_fn(|i| {
+                    db_trimmed[i].trick_dot(&query_trimmed[i])
+                }));
+                assert_eq!(dot_masks_full, dot_masks_trimmed.wrapping_mul(2));
+
+                let distance =
+                    |dot_masks: u16| 0.5f64 - (dot_codes as i16) as f64 / (2f64 * dot_masks as f64);
+                assert_float_eq!(distance(dot_masks_full), expected, abs <= 1e-6);
+                assert_float_eq!(
+                    distance(dot_masks_trimmed.wrapping_mul(2)),
+                    expected,
+                    abs <= 1e-6
+                );
+            }
+        }
+
         #[test]
         fn hamming_distance_galois() {
             let rng = &mut thread_rng();
@@ -412,9 +1473,15 @@ pub mod degree4 {
             let dist_0 = lines[4].parse::<f64>().unwrap();
             let dist_15 = lines[5].parse::<f64>().unwrap();
 
-            let mask = t1_mask & t2_mask;
-            let plain_distance =
-                ((t1_code ^ t2_code) & mask).count_ones() as f64 / mask.count_ones() as f64;
+            let t1 = IrisCode {
+                code: t1_code,
+                mask: t1_mask,
+            };
+            let t2 = IrisCode {
+                code: t2_code,
+                mask: t2_mask,
+            };
+            let (_, _, plain_distance) = t1.masked_distance(&t2).unwrap();
 
             let t1_code_shares = GaloisRingIrisCodeShare::encode_iris_code(&t1_code, &t1_mask, rng);
             let t1_mask_shares = GaloisRingIrisCodeShare::encode_mask_code(&t1_mask, rng);
@@ -494,16 +1561,242 @@ pub mod degree4 {
             assert_float_eq!(dist_15, min_dist, abs <= 1e-6);
         }
 
+        #[test]
+        fn encode_batch() {
+            let mut rng = StdRng::seed_from_u64(5);
+            let irises = (0..20)
+                .map(|_| IrisCode::random_rng(&mut rng))
+                .collect::<Vec<_>>();
+            let encode = |threads: usize| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap()
+                    .install(|| encode_iris_codes_batch(&irises, &mut StdRng::seed_from_u64(6)))
+            };
+            let batch = encode(1);
+            assert_eq!(batch, encode(4));
+            assert_ne!(
+                batch,
+                encode_iris_codes_batch(&irises, &mut StdRng::seed_from_u64(7))
+            );
+
+            for (i, iris) in irises.iter().enumerate() {
+                let shares = [0, 1, 2].map(|party| batch[party][i].clone());
+                let expected =
+                    GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng);
+                assert_eq!(reconstruct(&shares), reconstruct(&expected));
+            }
+        }
+
+        #[test]
+        fn preprocess_for_query() {
+            let mut rng = StdRng::seed_from_u64(8);
+            let iris = IrisCode::random_rng(&mut rng);
+            let mut share =
+                GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)[2]
+                    .clone();
+            share.preprocess_iris_code_query_share();
+
+            let preprocessed = share.preprocess_for_query();
+            for (i, &coef) in share.coefs.iter().enumerate() {
+                let [low, high] = preprocessed.limbs.clone().map(|limb| limb[i] ^ 0x80);
+                assert_eq!(u16::from_le_bytes([low, high]), coef);
+            }
+            for (limb, &sum) in preprocessed.limbs.iter().zip(preprocessed.sums.iter()) {
+                let expected = limb.iter().map(|&byte| byte as i8 as i32).sum::<i32>();
+                assert_eq!(sum, expected as u32);
+            }
+            for rotation in share.all_rotations() {
+                assert_eq!(rotation.preprocess_for_query().sums, preprocessed.sums);
+            }
+
+            let mask = GaloisRingTrimmedMaskCodeShare::from(&share);
+            let preprocessed = mask.preprocess_for_query();
+            assert_eq!(preprocessed.limbs[0].len(), MASK_CODE_LENGTH);
+            for rotation in mask.all_rotations() {
+                assert_eq!(rotation.preprocess_for_query().sums, preprocessed.sums);
+            }
+        }
+
         #[test]
         fn base64_shares() {
             let mut rng = thread_rng();
             let code = IrisCodeArray::random_rng(&mut rng);
             let shares = GaloisRingIrisCodeShare::encode_mask_code(&code, &mut rng);
             for i in 0..3 {
-                let s = shares[i].to_base64();
-                let decoded = GaloisRingIrisCodeShare::from_base64(&s).unwrap();
+                let s = shares[i].to_base64(ShareKind::Mask);
+                let decoded = GaloisRingIrisCodeShare::from_base64(&s, ShareKind::Mask).unwrap();
                 assert_eq!(shares[i].coefs, decoded.coefs);
+                assert_eq!(decoded.id, i + 1);
+
+                let trimmed = GaloisRingTrimmedMaskCodeShare::from(&shares[i]);
+                let decoded =
+                    GaloisRingTrimmedMaskCodeShare::from_base64(&trimmed.to_base64()).unwrap();
+                assert_eq!(decoded, trimmed);
+            }
+        }
+
+        #[test]
+        fn share_encoding() {
+            let mut rng = thread_rng();
+            let iris = IrisCode::random_rng(&mut rng);
+            let share = GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)
+                [1]
+            .clone();
+            let bytes = share.to_bytes(ShareKind::Code);
+            assert_eq!(bytes.len(), SHARE_HEADER_LEN + 2 * IRIS_CODE_LENGTH);
+            assert_eq!(&bytes[..7], &[1, 2, 0, 0, 50, 0, 0]);
+            assert_eq!(
+                GaloisRingIrisCodeShare::from_bytes(&bytes, ShareKind::Code).unwrap(),
+                share
+            );
+
+            // The encoding without a header is still accepted.
+            let legacy = bincode::serialize(&share).unwrap();
+            assert_eq!(legacy.len(), LEGACY_SHARE_LEN);
+            assert_eq!(
+                GaloisRingIrisCodeShare::from_bytes(&legacy, ShareKind::Code).unwrap(),
+                share
+            );
+
+            let decode = |bytes: &[u8]| GaloisRingIrisCodeShare::from_bytes(bytes, ShareKind::Code);
+            assert_eq!(decode(&bytes[..5]), Err(ShareEncodingError::Truncated(5)));
+            assert_eq!(
+                decode(&bytes[..bytes.len() - 1]),
+                Err(ShareEncodingError::Truncated(bytes.len() - 1))
+            );
+            let mut longer = bytes.clone();
+            longer.extend_from_slice(&[0, 0]);
+            assert_eq!(decode(&longer), Err(ShareEncodingError::TrailingBytes(2)));
+            let mut modified = bytes.clone();
+            modified[0] = 2;
+            assert_eq!(
+                decode(&modified),
+                Err(ShareEncodingError::UnsupportedVersion(2))
+            );
+            let mut modified = bytes.clone();
+            modified[1] = 4;
+            assert_eq!(
+                decode(&modified),
+                Err(ShareEncodingError::InvalidPartyId(4))
+            );
+            assert_eq!(
+                GaloisRingIrisCodeShare::from_bytes(&bytes, ShareKind::Mask),
+                Err(ShareEncodingError::WrongKind {
+                    expected: ShareKind::Mask,
+                    got:      0,
+                })
+            );
+            // A full mask is not a trimmed one.
+            let mask = share.to_bytes(ShareKind::Mask);
+            assert_eq!(
+                GaloisRingTrimmedMaskCodeShare::from_bytes(&mask),
+                Err(ShareEncodingError::WrongLength {
+                    expected: MASK_CODE_LENGTH,
+                    got:      IRIS_CODE_LENGTH,
+                })
+            );
+            assert!(matches!(
+                GaloisRingIrisCodeShare::from_base64("not base64!", ShareKind::Code),
+                Err(ShareEncodingError::Base64(_))
+            ));
+        }
+
+        #[test]
+        fn test_validate() {
+            let mut rng = StdRng::seed_from_u64(0);
+            let iris = IrisCode::random_rng(&mut rng);
+            let codes = GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng);
+            let masks = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, &mut rng);
+            for (party_id, (code, mask)) in codes.iter().zip(&masks).enumerate() {
+                code.validate().unwrap();
+                mask.validate_mask().unwrap();
+                let decoded = GaloisRingIrisCodeShare::decode_validated(
+                    &mask.to_base64(ShareKind::Mask),
+                    ShareKind::Mask,
+                    party_id,
+                );
+                assert_eq!(decoded.as_ref(), Ok(mask));
+            }
+
+            let zeros = GaloisRingIrisCodeShare::new(1, [0; IRIS_CODE_LENGTH]);
+            assert_eq!(zeros.validate(), Err(ShareValidationError::Constant(0)));
+            let filled = GaloisRingIrisCodeShare::new(2, [0xffff; IRIS_CODE_LENGTH]);
+            assert_eq!(
+                filled.validate(),
+                Err(ShareValidationError::Constant(0xffff))
+            );
+            let mut code = codes[0].clone();
+            code.id = 0;
+            assert_eq!(
+                code.validate(),
+                Err(ShareValidationError::InvalidPartyId(0))
+            );
+
+            let mut mask = masks[0].clone();
+            let (first, second) = mask.coefs.split_at_mut(MASK_CODE_LENGTH);
+            second.copy_from_slice(first);
+            assert_eq!(
+                mask.validate_mask(),
+                Err(ShareValidationError::DuplicatedMaskHalves)
+            );
+            // Only masks have two halves.
+            mask.validate().unwrap();
+
+            // The party id tag of a share with a header has to match.
+            let encoded = codes[1].to_base64(ShareKind::Code);
+            assert_eq!(
+                GaloisRingIrisCodeShare::decode_validated(&encoded, ShareKind::Code, 0),
+                Err(ShareValidationError::WrongParty {
+                    expected: 1,
+                    got:      2,
+                })
+            );
+            assert_eq!(
+                GaloisRingIrisCodeShare::decode_validated(&encoded, ShareKind::Mask, 1),
+                Err(ShareValidationError::Encoding(
+                    ShareEncodingError::WrongKind {
+                        expected: ShareKind::Mask,
+                        got:      0,
+                    }
+                ))
+            );
+            // Without a header, there is no tag to check.
+            let legacy = BASE64_STANDARD.encode(bincode::serialize(&codes[1]).unwrap());
+            assert_eq!(
+                GaloisRingIrisCodeShare::decode_validated(&legacy, ShareKind::Code, 0).as_ref(),
+                Ok(&codes[1])
+            );
+        }
+
+        #[test]
+        fn test_lagrange_coeffs_match_fresh() {
+            let parties = [PartyID::ID0, PartyID::ID1, PartyID::ID2];
+            let deg_2 = ShamirGaloisRingShare::deg_2_lagrange_polys_at_zero();
+            for a in parties {
+                for b in parties {
+                    if a == b {
+                        continue;
+                    }
+                    assert_eq!(lagrange_coeffs(&[a, b]), [
+                        ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(a, b),
+                        ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(b, a),
+                    ]);
+                    let c = parties.into_iter().find(|&c| c != a && c != b).unwrap();
+                    assert_eq!(lagrange_coeffs(&[a, b, c]), [
+                        deg_2[usize::from(a)],
+                        deg_2[usize::from(b)],
+                        deg_2[usize::from(c)],
+                    ]);
+                }
             }
         }
+
+        #[test]
+        #[should_panic = "No Lagrange coefficients"]
+        fn test_lagrange_coeffs_repeated_party() {
+            lagrange_coeffs(&[PartyID::ID1, PartyID::ID0, PartyID::ID1]);
+        }
     }
 }
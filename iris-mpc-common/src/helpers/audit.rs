@@ -0,0 +1,404 @@
+//! Append-only audit log of the processed batches.
+//!
+//! Every batch is recorded with the content hashes of its requests, the opened
+//! per-query decisions and the database digests before and after the batch.
+//! Each record commits to the hash of its predecessor, so that modifying or
+//! removing a record breaks the chain.
+//!
+//! Records only ever hold ids, decisions and SHA-256 hashes: [`AuditHash`]
+//! accepts nothing but a hex encoded digest, and deserialization rejects
+//! unknown fields, so there is no place for share material or iris codes.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// A hex encoded SHA-256 digest.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AuditHash(String);
+
+impl AuditHash {
+    pub fn zero() -> Self {
+        Self::from_digest(&[0; 32])
+    }
+
+    pub fn from_digest(digest: &[u8; 32]) -> Self {
+        Self(hex::encode(digest))
+    }
+
+    /// Hashes the given parts, each prefixed with its length.
+    pub fn of_parts<T: AsRef<[u8]>>(parts: &[T]) -> Self {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update((part.as_ref().len() as u64).to_le_bytes());
+            hasher.update(part.as_ref());
+        }
+        Self(hex::encode(hasher.finalize()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for AuditHash {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.len() == 64
+            && value
+                .bytes()
+                .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            Ok(Self(value))
+        } else {
+            Err(format!("not a hex encoded SHA-256 digest: {:?}", value))
+        }
+    }
+}
+
+impl From<AuditHash> for String {
+    fn from(value: AuditHash) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for AuditHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The opened decision on a single request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditDecision {
+    pub request_id:   String,
+    /// Hash of the request content as received by this party.
+    pub request_hash: AuditHash,
+    pub is_match:     bool,
+    /// The serial id assigned to the request, if it was inserted.
+    pub serial_id:    Option<u32>,
+}
+
+/// What gets recorded of a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditBatch {
+    pub batch_id:           u64,
+    pub decisions:          Vec<AuditDecision>,
+    pub deleted_serial_ids: Vec<u32>,
+    pub db_digest_before:   AuditHash,
+    pub db_digest_after:    AuditHash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditRecord {
+    /// Position of the record in the log, starting at 0.
+    pub seq:                u64,
+    /// Unix timestamp in seconds.
+    pub timestamp:          i64,
+    pub party_id:           usize,
+    pub batch_id:           u64,
+    pub decisions:          Vec<AuditDecision>,
+    pub deleted_serial_ids: Vec<u32>,
+    pub db_digest_before:   AuditHash,
+    pub db_digest_after:    AuditHash,
+    /// Hash of the previous record, zero for the first one.
+    pub prev_hash:          AuditHash,
+    /// Hash over all other fields of this record.
+    pub hash:               AuditHash,
+}
+
+impl AuditRecord {
+    pub fn new(
+        seq: u64,
+        timestamp: i64,
+        party_id: usize,
+        batch: AuditBatch,
+        prev_hash: AuditHash,
+    ) -> Self {
+        let mut record = Self {
+            seq,
+            timestamp,
+            party_id,
+            batch_id: batch.batch_id,
+            decisions: batch.decisions,
+            deleted_serial_ids: batch.deleted_serial_ids,
+            db_digest_before: batch.db_digest_before,
+            db_digest_after: batch.db_digest_after,
+            prev_hash,
+            hash: AuditHash::zero(),
+        };
+        record.hash = record.compute_hash();
+        record
+    }
+
+    pub fn compute_hash(&self) -> AuditHash {
+        let body = serde_json::to_vec(&(
+            self.seq,
+            self.timestamp,
+            self.party_id,
+            self.batch_id,
+            &self.decisions,
+            &self.deleted_serial_ids,
+            &self.db_digest_before,
+            &self.db_digest_after,
+            &self.prev_hash,
+        ))
+        .expect("audit records serialize to JSON");
+        AuditHash::of_parts(&[body])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditError {
+    /// The stored hash of the record does not match its content.
+    Modified { seq: u64 },
+    /// The record does not follow the one before it.
+    BrokenLink {
+        seq:          u64,
+        expected_seq: u64,
+    },
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Modified { seq } => write!(f, "audit record {} was modified", seq),
+            AuditError::BrokenLink { seq, expected_seq } => write!(
+                f,
+                "audit record {} found where record {} was expected, records are missing or \
+                 reordered",
+                seq, expected_seq
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// Checks the hash chain of the records with a timestamp within `[from, to]`.
+/// The first record in the range is also checked against the record before
+/// it, if there is one. Returns the number of checked records.
+pub fn verify_chain(records: &[AuditRecord], from: i64, to: i64) -> Result<usize, AuditError> {
+    let Some(start) = records.iter().position(|r| r.timestamp >= from) else {
+        return Ok(0);
+    };
+    let mut prev = start.checked_sub(1).map(|i| &records[i]);
+    let mut checked = 0;
+    for record in records[start..].iter().take_while(|r| r.timestamp <= to) {
+        if record.compute_hash() != record.hash {
+            return Err(AuditError::Modified { seq: record.seq });
+        }
+        let (expected_seq, expected_prev_hash) = match prev {
+            Some(prev) => (prev.seq + 1, prev.hash.clone()),
+            None => (0, AuditHash::zero()),
+        };
+        if record.seq != expected_seq || record.prev_hash != expected_prev_hash {
+            return Err(AuditError::BrokenLink {
+                seq: record.seq,
+                expected_seq,
+            });
+        }
+        prev = Some(record);
+        checked += 1;
+    }
+    Ok(checked)
+}
+
+/// Reads all records of a log file, one JSON record per line.
+pub fn read_records(path: impl AsRef<Path>) -> eyre::Result<Vec<AuditRecord>> {
+    let file = File::open(path)?;
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(records)
+}
+
+/// Writer appending to a log file, continuing the chain of the records
+/// already in it.
+#[derive(Debug)]
+pub struct AuditLog {
+    path:      PathBuf,
+    party_id:  usize,
+    next_seq:  u64,
+    prev_hash: AuditHash,
+}
+
+impl AuditLog {
+    pub fn open(path: impl Into<PathBuf>, party_id: usize) -> eyre::Result<Self> {
+        let path = path.into();
+        let last = if path.exists() {
+            read_records(&path)?.pop()
+        } else {
+            None
+        };
+        let (next_seq, prev_hash) = match last {
+            Some(last) => (last.seq + 1, last.hash),
+            None => (0, AuditHash::zero()),
+        };
+        Ok(Self {
+            path,
+            party_id,
+            next_seq,
+            prev_hash,
+        })
+    }
+
+    /// Appends the record of a batch and returns it.
+    pub fn append(&mut self, batch: AuditBatch, timestamp: i64) -> eyre::Result<AuditRecord> {
+        let record = AuditRecord::new(
+            self.next_seq,
+            timestamp,
+            self.party_id,
+            batch,
+            self.prev_hash.clone(),
+        );
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        self.next_seq += 1;
+        self.prev_hash = record.hash.clone();
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(batch_id: u64) -> AuditBatch {
+        AuditBatch {
+            batch_id,
+            decisions: vec![
+                AuditDecision {
+                    request_id:   format!("request-{}-a", batch_id),
+                    request_hash: AuditHash::of_parts(&[b"a"]),
+                    is_match:     false,
+                    serial_id:    Some(batch_id as u32 + 1),
+                },
+                AuditDecision {
+                    request_id:   format!("request-{}-b", batch_id),
+                    request_hash: AuditHash::of_parts(&[b"b"]),
+                    is_match:     true,
+                    serial_id:    None,
+                },
+            ],
+            deleted_serial_ids: vec![],
+            db_digest_before: AuditHash::of_parts(&[batch_id.to_le_bytes()]),
+            db_digest_after: AuditHash::of_parts(&[(batch_id + 1).to_le_bytes()]),
+        }
+    }
+
+    fn chain(n: u64) -> Vec<AuditRecord> {
+        let mut records: Vec<AuditRecord> = vec![];
+        for i in 0..n {
+            let prev_hash = records
+                .last()
+                .map(|r| r.hash.clone())
+                .unwrap_or_else(AuditHash::zero);
+            records.push(AuditRecord::new(i, 100 + i as i64, 0, batch(i), prev_hash));
+        }
+        records
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let records = chain(5);
+        assert_eq!(verify_chain(&records, i64::MIN, i64::MAX), Ok(5));
+        assert_eq!(verify_chain(&records, 101, 103), Ok(3));
+        assert_eq!(verify_chain(&records, 200, 300), Ok(0));
+    }
+
+    #[test]
+    fn test_modified_record_is_detected() {
+        let mut records = chain(5);
+        records[2].decisions[1].is_match = false;
+        assert_eq!(
+            verify_chain(&records, i64::MIN, i64::MAX),
+            Err(AuditError::Modified { seq: 2 })
+        );
+
+        // Rehashing the modified record breaks the link of the next one.
+        records[2].hash = records[2].compute_hash();
+        assert_eq!(
+            verify_chain(&records, i64::MIN, i64::MAX),
+            Err(AuditError::BrokenLink {
+                seq:          3,
+                expected_seq: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_removed_record_is_detected() {
+        for removed in 0..4 {
+            let mut records = chain(5);
+            records.remove(removed);
+            assert!(matches!(
+                verify_chain(&records, i64::MIN, i64::MAX),
+                Err(AuditError::BrokenLink { .. })
+            ));
+        }
+        // Also when only the range after the removed record is checked.
+        let mut records = chain(5);
+        records.remove(1);
+        assert!(verify_chain(&records, 102, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_record_serde_roundtrip() {
+        for record in chain(3) {
+            let json = serde_json::to_string(&record).unwrap();
+            assert_eq!(serde_json::from_str::<AuditRecord>(&json).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_record_rejects_other_fields() {
+        let record = chain(1).pop().unwrap();
+        let mut value = serde_json::to_value(&record).unwrap();
+        value["left_code"] = serde_json::json!([1, 2, 3]);
+        assert!(serde_json::from_value::<AuditRecord>(value).is_err());
+
+        let mut value = serde_json::to_value(&record).unwrap();
+        value["decisions"][0]["request_hash"] = serde_json::json!("not a hash");
+        assert!(serde_json::from_value::<AuditRecord>(value).is_err());
+    }
+
+    #[test]
+    fn test_log_continues_chain() {
+        let path =
+            std::env::temp_dir().join(format!("audit-log-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut log = AuditLog::open(&path, 1).unwrap();
+        log.append(batch(0), 100).unwrap();
+        log.append(batch(1), 101).unwrap();
+        // A restarted server picks up where the log ends.
+        let mut log = AuditLog::open(&path, 1).unwrap();
+        log.append(batch(2), 102).unwrap();
+
+        let records = read_records(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(verify_chain(&records, i64::MIN, i64::MAX), Ok(3));
+    }
+}
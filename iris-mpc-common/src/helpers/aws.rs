@@ -1,38 +1,151 @@
-use aws_sdk_sns::types::MessageAttributeValue;
-use std::collections::HashMap;
+//! The trace context of the requests and results, carried in their message
+//! attributes. A message carries the ids both as a W3C `traceparent` and, for
+//! the consumers that predate it, as the decimal `TraceID` and `SpanID`.
+//!
+//! Also the batched publishing to SNS. SNS answers a batch publish with the
+//! entries that failed. Those are retried on their own with a backoff, unless
+//! SNS blames the request for the failure, like for an invalid attribute.
+
+use async_trait::async_trait;
+use aws_sdk_sns::{
+    error::{BuildError, SdkError},
+    operation::publish_batch::PublishBatchError,
+    types::{MessageAttributeValue, PublishBatchRequestEntry},
+    Client as SNSClient,
+};
+use rand::Rng;
+use std::{collections::HashMap, time::Duration};
 use telemetry_batteries::reexports::opentelemetry::trace::{
     SpanContext, SpanId, TraceFlags, TraceId, TraceState,
 };
+use thiserror::Error;
+use tracing::Span;
 
 pub const TRACE_ID_MESSAGE_ATTRIBUTE_NAME: &str = "TraceID";
 pub const SPAN_ID_MESSAGE_ATTRIBUTE_NAME: &str = "SpanID";
 pub const NODE_ID_MESSAGE_ATTRIBUTE_NAME: &str = "NodeID";
+pub const TRACEPARENT_MESSAGE_ATTRIBUTE_NAME: &str = "traceparent";
 
-pub fn construct_message_attributes(
-    trace_id: &String,
-    span_id: &String,
-) -> eyre::Result<HashMap<String, MessageAttributeValue>> {
-    let mut message_attributes = HashMap::new();
+/// The version of the `traceparent` format, and the flags of a sampled trace.
+const TRACEPARENT_VERSION: &str = "00";
+const TRACEPARENT_SAMPLED: &str = "01";
 
-    let trace_id_message_attribute = MessageAttributeValue::builder()
-        .data_type("String")
-        .string_value(trace_id)
-        .build()?;
+/// The trace a message belongs to and the span that sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id:  u64,
+}
 
-    message_attributes.insert(
-        TRACE_ID_MESSAGE_ATTRIBUTE_NAME.to_string(),
-        trace_id_message_attribute,
-    );
+impl TraceContext {
+    /// A new trace, for a message that starts one.
+    pub fn random() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            trace_id: rng.gen_range(1..=u128::MAX),
+            span_id:  rng.gen_range(1..=u64::MAX),
+        }
+    }
+
+    /// Parses the decimal ids of the `TraceID` and `SpanID` attributes.
+    pub fn from_ids(trace_id: &str, span_id: &str) -> Option<Self> {
+        Some(Self {
+            trace_id: trace_id.parse().ok()?,
+            span_id:  span_id.parse().ok()?,
+        })
+    }
 
-    let span_id_message_attribute = MessageAttributeValue::builder()
-        .data_type("String")
-        .string_value(span_id)
-        .build()?;
+    /// Like `00-<trace id>-<span id>-01`, with the ids in lowercase hex.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "{}-{:032x}-{:016x}-{}",
+            TRACEPARENT_VERSION, self.trace_id, self.span_id, TRACEPARENT_SAMPLED
+        )
+    }
 
-    message_attributes.insert(
-        SPAN_ID_MESSAGE_ATTRIBUTE_NAME.to_string(),
-        span_id_message_attribute,
-    );
+    /// Parses a `traceparent`. The all-zero ids are invalid.
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != TRACEPARENT_VERSION
+            || trace_id.len() != 32
+            || span_id.len() != 16
+            || flags.len() != 2
+            || parts.next().is_some()
+        {
+            return None;
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id:  u64::from_str_radix(span_id, 16).ok()?,
+        };
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+
+    /// The context of a received message, from its `traceparent` or else from
+    /// its `TraceID` and `SpanID`.
+    pub fn from_message_attributes(
+        message_attributes: &HashMap<String, MessageAttributeValue>,
+    ) -> Option<Self> {
+        let attribute = |name| {
+            message_attributes
+                .get(name)
+                .and_then(MessageAttributeValue::string_value)
+        };
+        attribute(TRACEPARENT_MESSAGE_ATTRIBUTE_NAME)
+            .and_then(Self::from_traceparent)
+            .or_else(|| {
+                Self::from_ids(
+                    attribute(TRACE_ID_MESSAGE_ATTRIBUTE_NAME)?,
+                    attribute(SPAN_ID_MESSAGE_ATTRIBUTE_NAME)?,
+                )
+            })
+    }
+
+    /// Makes the span that sent the message the parent of `span`, so that
+    /// `span` joins the trace.
+    pub fn attach(&self, span: &Span) {
+        let parent_ctx = SpanContext::new(
+            TraceId::from(self.trace_id),
+            SpanId::from(self.span_id),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        span.in_scope(|| telemetry_batteries::tracing::trace_from_ctx(parent_ctx));
+    }
+}
+
+/// The attributes that carry `trace_context`, none without one.
+pub fn construct_message_attributes(
+    trace_context: Option<&TraceContext>,
+) -> eyre::Result<HashMap<String, MessageAttributeValue>> {
+    let mut message_attributes = HashMap::new();
+    let Some(trace_context) = trace_context else {
+        return Ok(message_attributes);
+    };
+
+    for (name, value) in [
+        (
+            TRACE_ID_MESSAGE_ATTRIBUTE_NAME,
+            trace_context.trace_id.to_string(),
+        ),
+        (
+            SPAN_ID_MESSAGE_ATTRIBUTE_NAME,
+            trace_context.span_id.to_string(),
+        ),
+        (
+            TRACEPARENT_MESSAGE_ATTRIBUTE_NAME,
+            trace_context.traceparent(),
+        ),
+    ] {
+        let attribute = MessageAttributeValue::builder()
+            .data_type("String")
+            .string_value(value)
+            .build()?;
+        message_attributes.insert(name.to_string(), attribute);
+    }
 
     Ok(message_attributes)
 }
@@ -43,34 +156,479 @@ pub fn trace_from_message_attributes(
     message_attributes: &HashMap<String, MessageAttributeValue>,
     receipt_handle: &str,
 ) -> eyre::Result<()> {
-    if let Some(trace_id) = message_attributes.get(TRACE_ID_MESSAGE_ATTRIBUTE_NAME) {
-        if let Some(span_id) = message_attributes.get(SPAN_ID_MESSAGE_ATTRIBUTE_NAME) {
-            let trace_id = trace_id
-                .string_value()
-                .expect("Could not parse TraceID")
-                .parse::<u128>()?;
-
-            let span_id = span_id
-                .string_value()
-                .expect("Could not parse SpanID")
-                .parse::<u64>()?;
-
-            // Create and set the span parent context
-            let parent_ctx = SpanContext::new(
-                TraceId::from(trace_id),
-                SpanId::from(span_id),
-                TraceFlags::default(),
-                true,
-                TraceState::default(),
-            );
+    match TraceContext::from_message_attributes(message_attributes) {
+        Some(trace_context) => trace_context.attach(&Span::current()),
+        None => tracing::warn!(?receipt_handle, "SQS message missing trace context"),
+    }
+
+    Ok(())
+}
+
+/// The most entries SNS takes in a batch publish.
+pub const MAX_PUBLISH_BATCH_ENTRIES: usize = 10;
+/// The most bytes of the messages and attributes of a batch publish together.
+pub const MAX_PUBLISH_BATCH_BYTES: usize = 256 * 1024;
+
+/// A message of a batch publish. The `id` identifies it among the entries of
+/// a [`publish_batch_with_retry`], so it has to be unique among them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishEntry {
+    pub id:                 String,
+    pub message:            String,
+    pub message_group_id:   Option<String>,
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+}
+
+impl PublishEntry {
+    /// The bytes SNS counts against [`MAX_PUBLISH_BATCH_BYTES`]: the message,
+    /// and the names, types and values of the attributes.
+    pub fn size(&self) -> usize {
+        self.message.len()
+            + self
+                .message_attributes
+                .iter()
+                .map(|(name, value)| {
+                    name.len()
+                        + value.data_type().len()
+                        + value.string_value().map_or(0, str::len)
+                        + value.binary_value().map_or(0, |blob| blob.as_ref().len())
+                })
+                .sum::<usize>()
+    }
+}
+
+/// An entry that failed in a batch publish.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishEntryFailure {
+    pub id:           String,
+    pub code:         String,
+    /// Whether SNS blames the request, in which case a retry fails as well.
+    pub sender_fault: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum PublishError {
+    #[error("Failed to publish a batch to SNS: {0}")]
+    Sns(#[from] SdkError<PublishBatchError>),
+
+    #[error("Failed to build a batch publish entry: {0}")]
+    Build(#[from] BuildError),
+
+    #[error("Failed to publish {} messages to SNS: {:?}", .0.len(), .0)]
+    Incomplete(Vec<String>),
+}
+
+/// The batch publish of SNS, see [`publish_batch_with_retry`]. A call takes at
+/// most [`MAX_PUBLISH_BATCH_ENTRIES`] entries of at most
+/// [`MAX_PUBLISH_BATCH_BYTES`] together, and returns the ones that failed.
+#[async_trait]
+pub trait BatchPublisher: Send + Sync {
+    async fn publish_batch(
+        &self,
+        topic_arn: &str,
+        entries: &[PublishEntry],
+    ) -> Result<Vec<PublishEntryFailure>, PublishError>;
+}
+
+#[async_trait]
+impl BatchPublisher for SNSClient {
+    async fn publish_batch(
+        &self,
+        topic_arn: &str,
+        entries: &[PublishEntry],
+    ) -> Result<Vec<PublishEntryFailure>, PublishError> {
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                PublishBatchRequestEntry::builder()
+                    .id(&entry.id)
+                    .message(&entry.message)
+                    .set_message_group_id(entry.message_group_id.clone())
+                    .set_message_attributes(Some(entry.message_attributes.clone()))
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let output = self
+            .publish_batch()
+            .topic_arn(topic_arn)
+            .set_publish_batch_request_entries(Some(entries))
+            .send()
+            .await?;
+        Ok(output
+            .failed()
+            .iter()
+            .map(|failed| PublishEntryFailure {
+                id:           failed.id().to_string(),
+                code:         failed.code().to_string(),
+                sender_fault: failed.sender_fault(),
+            })
+            .collect())
+    }
+}
+
+/// How [`publish_batch_with_retry`] retries failed entries. Each retry waits
+/// twice as long as the one before, starting at `base_delay` and capped at
+/// `max_delay`, less a random jitter of up to half the wait.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishRetryPolicy {
+    /// The publishes per entry, including the first one.
+    pub max_attempts: usize,
+    pub base_delay:   Duration,
+    pub max_delay:    Duration,
+}
+
+impl Default for PublishRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay:   Duration::from_millis(100),
+            max_delay:    Duration::from_secs(5),
+        }
+    }
+}
 
-            telemetry_batteries::tracing::trace_from_ctx(parent_ctx);
-        } else {
-            tracing::warn!(?receipt_handle, "SQS message missing SpanID");
+impl PublishRetryPolicy {
+    /// The wait before the `retry`-th retry, counting from 1.
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        delay - delay.mul_f64(rng.gen_range(0.0..=0.5))
+    }
+}
+
+/// Splits the entries into batches within the limits of a batch publish, in
+/// their order.
+fn split_batches(entries: Vec<PublishEntry>) -> Vec<Vec<PublishEntry>> {
+    let mut batches: Vec<Vec<PublishEntry>> = vec![];
+    let mut batch_bytes = 0;
+    for entry in entries {
+        let size = entry.size();
+        match batches.last_mut() {
+            Some(batch)
+                if batch.len() < MAX_PUBLISH_BATCH_ENTRIES
+                    && batch_bytes + size <= MAX_PUBLISH_BATCH_BYTES =>
+            {
+                batch_bytes += size;
+                batch.push(entry);
+            }
+            _ => {
+                batch_bytes = size;
+                batches.push(vec![entry]);
+            }
         }
-    } else {
-        tracing::warn!(?receipt_handle, "SQS message missing TraceID");
     }
+    batches
+}
 
+/// Publishes the entries to the topic, in batches within the limits of SNS.
+/// The entries that fail through no fault of the request are published again
+/// under `policy`. An entry larger than a whole batch is not published at
+/// all. Fails with the ids of the entries that were not published.
+pub async fn publish_batch_with_retry(
+    client: &impl BatchPublisher,
+    topic_arn: &str,
+    entries: Vec<PublishEntry>,
+    policy: &PublishRetryPolicy,
+) -> Result<(), PublishError> {
+    let max_attempts = policy.max_attempts.max(1);
+    let (mut pending, too_large): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|entry| entry.size() <= MAX_PUBLISH_BATCH_BYTES);
+    let mut failed_for_good = too_large
+        .into_iter()
+        .map(|entry| {
+            tracing::warn!(
+                "Not publishing message {} of {} bytes, SNS takes at most {}",
+                entry.id,
+                entry.size(),
+                MAX_PUBLISH_BATCH_BYTES
+            );
+            entry.id
+        })
+        .collect::<Vec<_>>();
+    for attempt in 1..=max_attempts {
+        if pending.is_empty() {
+            break;
+        }
+        if attempt > 1 {
+            let delay = policy.delay(attempt as u32 - 1, &mut rand::thread_rng());
+            tracing::warn!(
+                "Retrying {} entries of an SNS batch publish in {:?}",
+                pending.len(),
+                delay
+            );
+            metrics::counter!("sns.publish_retry").increment(pending.len() as u64);
+            tokio::time::sleep(delay).await;
+        }
+        let mut retried = vec![];
+        for batch in split_batches(pending) {
+            for failure in client.publish_batch(topic_arn, &batch).await? {
+                let Some(entry) = batch.iter().find(|entry| entry.id == failure.id) else {
+                    continue;
+                };
+                if failure.sender_fault || attempt == max_attempts {
+                    tracing::warn!(
+                        "Failed to publish message {} to SNS: {}",
+                        entry.id,
+                        failure.code
+                    );
+                    failed_for_good.push(entry.id.clone());
+                } else {
+                    retried.push(entry.clone());
+                }
+            }
+        }
+        pending = retried;
+    }
+    if !failed_for_good.is_empty() {
+        metrics::counter!("sns.publish_failed").increment(failed_for_good.len() as u64);
+        return Err(PublishError::Incomplete(failed_for_good));
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_traceparent() {
+        let context = TraceContext {
+            trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+            span_id:  0x00f067aa0ba902b7,
+        };
+        assert_eq!(
+            context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+        assert_eq!(
+            TraceContext::from_traceparent(&context.traceparent()),
+            Some(context)
+        );
+        for invalid in [
+            "",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00",
+            "00-xbf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_message_attributes_round_trip() {
+        let context = TraceContext::random();
+        let attributes = construct_message_attributes(Some(&context)).unwrap();
+        assert_eq!(
+            attributes[TRACE_ID_MESSAGE_ATTRIBUTE_NAME].string_value(),
+            Some(context.trace_id.to_string().as_str())
+        );
+        assert_eq!(
+            TraceContext::from_message_attributes(&attributes),
+            Some(context)
+        );
+
+        // Producers that only set the decimal ids.
+        let mut legacy = attributes.clone();
+        legacy.remove(TRACEPARENT_MESSAGE_ATTRIBUTE_NAME);
+        assert_eq!(
+            TraceContext::from_message_attributes(&legacy),
+            Some(context)
+        );
+        assert_eq!(
+            TraceContext::from_ids(&context.trace_id.to_string(), &context.span_id.to_string()),
+            Some(context)
+        );
+
+        assert!(construct_message_attributes(None).unwrap().is_empty());
+        assert_eq!(TraceContext::from_message_attributes(&HashMap::new()), None);
+    }
+
+    /// SNS client that records the ids of every batch publish. The ids in
+    /// `failures` fail as often as given, with the given sender fault.
+    #[derive(Default)]
+    struct MockPublisher {
+        calls:    Mutex<Vec<Vec<String>>>,
+        failures: Mutex<HashMap<String, (usize, bool)>>,
+    }
+
+    impl MockPublisher {
+        fn fail(&self, id: &str, times: usize, sender_fault: bool) {
+            self.failures
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), (times, sender_fault));
+        }
+
+        fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl BatchPublisher for MockPublisher {
+        async fn publish_batch(
+            &self,
+            _topic_arn: &str,
+            entries: &[PublishEntry],
+        ) -> Result<Vec<PublishEntryFailure>, PublishError> {
+            assert!(entries.len() <= MAX_PUBLISH_BATCH_ENTRIES);
+            assert!(
+                entries.iter().map(PublishEntry::size).sum::<usize>() <= MAX_PUBLISH_BATCH_BYTES
+            );
+            self.calls
+                .lock()
+                .unwrap()
+                .push(entries.iter().map(|entry| entry.id.clone()).collect());
+            let mut failures = self.failures.lock().unwrap();
+            Ok(entries
+                .iter()
+                .filter_map(|entry| {
+                    let (times, sender_fault) = failures.get_mut(&entry.id)?;
+                    if *times == 0 {
+                        return None;
+                    }
+                    *times -= 1;
+                    Some(PublishEntryFailure {
+                        id:           entry.id.clone(),
+                        code:         "Throttled".to_string(),
+                        sender_fault: *sender_fault,
+                    })
+                })
+                .collect())
+        }
+    }
+
+    fn entries(range: std::ops::Range<usize>, message_len: usize) -> Vec<PublishEntry> {
+        range
+            .map(|i| PublishEntry {
+                id:                 i.to_string(),
+                message:            "x".repeat(message_len),
+                message_group_id:   None,
+                message_attributes: HashMap::new(),
+            })
+            .collect()
+    }
+
+    fn ids(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn test_entry_size() {
+        let mut entry = entries(0..1, 100).remove(0);
+        assert_eq!(entry.size(), 100);
+        entry.message_attributes =
+            construct_message_attributes(Some(&TraceContext::random())).unwrap();
+        let attributes = entry
+            .message_attributes
+            .iter()
+            .map(|(name, value)| name.len() + "String".len() + value.string_value().unwrap().len())
+            .sum::<usize>();
+        assert_eq!(entry.size(), 100 + attributes);
+    }
+
+    #[tokio::test]
+    async fn test_publish_in_batches() {
+        let publisher = MockPublisher::default();
+        let policy = PublishRetryPolicy::default();
+        publish_batch_with_retry(&publisher, "topic", entries(0..23, 10), &policy)
+            .await
+            .unwrap();
+        assert_eq!(publisher.calls(), vec![
+            ids(0..10),
+            ids(10..20),
+            ids(20..23)
+        ]);
+
+        // Three entries of 100 KB exceed the bytes of a batch.
+        let publisher = MockPublisher::default();
+        publish_batch_with_retry(&publisher, "topic", entries(0..3, 100 * 1024), &policy)
+            .await
+            .unwrap();
+        assert_eq!(publisher.calls(), vec![ids(0..2), ids(2..3)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_publish_retries_failed_entries() {
+        let publisher = MockPublisher::default();
+        publisher.fail("3", 1, false);
+        publisher.fail("11", 2, false);
+        publish_batch_with_retry(
+            &publisher,
+            "topic",
+            entries(0..12, 10),
+            &PublishRetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(publisher.calls(), vec![
+            ids(0..10),
+            ids(10..12),
+            vec!["3".to_string(), "11".to_string()],
+            vec!["11".to_string()],
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_publish_gives_up() {
+        let publisher = MockPublisher::default();
+        // Not retried, SNS blames the request.
+        publisher.fail("1", 1, true);
+        // Fails more often than retried.
+        publisher.fail("2", 5, false);
+        let mut all = entries(0..4, 10);
+        all.extend(entries(4..5, MAX_PUBLISH_BATCH_BYTES + 1));
+        let policy = PublishRetryPolicy {
+            max_attempts: 3,
+            ..PublishRetryPolicy::default()
+        };
+        let result = publish_batch_with_retry(&publisher, "topic", all, &policy).await;
+        match result {
+            Err(PublishError::Incomplete(mut failed)) => {
+                failed.sort();
+                assert_eq!(
+                    failed,
+                    ids(1..3).into_iter().chain(ids(4..5)).collect::<Vec<_>>()
+                );
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // The entry larger than a batch is never sent.
+        assert_eq!(publisher.calls(), vec![
+            ids(0..4),
+            vec!["2".to_string()],
+            vec!["2".to_string()],
+        ]);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = PublishRetryPolicy {
+            max_attempts: 10,
+            base_delay:   Duration::from_millis(100),
+            max_delay:    Duration::from_millis(1_000),
+        };
+        let mut rng = rand::thread_rng();
+        for (retry, full) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1_000),
+            (9, 1_000),
+        ] {
+            let full = Duration::from_millis(full);
+            for _ in 0..20 {
+                let delay = policy.delay(retry, &mut rng);
+                assert!(delay <= full && delay >= full / 2, "{:?}", delay);
+            }
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Bulk enrollment of identities whose shares already exist.
+//!
+//! A backfill feeds pre-encoded shares into the batch pipeline of the parties,
+//! bypassing the request queue and the shares bucket. Every party runs the
+//! `backfill` tool against its own share file or database and its own server,
+//! which accepts the entries on a local endpoint when
+//! `backfill_listen_address` is configured. The entries are processed in
+//! regular batches, so the parties agree on them like on any other request
+//! and publish the usual results, flagged with `backfill`.
+//!
+//! The signup ids of backfilled entries start with
+//! [`BACKFILL_SIGNUP_ID_PREFIX`]. A backfilled identity that is sent again
+//! matches its earlier enrollment, so resuming from an older checkpoint never
+//! enrolls an identity twice.
+
+use crate::galois_engine::degree4::{
+    GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare, ShareKind,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::time::Instant;
+
+pub const BACKFILL_SIGNUP_ID_PREFIX: &str = "backfill-";
+/// Path of the endpoint accepting backfill entries.
+pub const BACKFILL_PATH: &str = "/backfill";
+
+/// The signup id a backfilled entry is processed under.
+pub fn backfill_signup_id(id: &str) -> String {
+    if is_backfill_request(id) {
+        id.to_string()
+    } else {
+        format!("{}{}", BACKFILL_SIGNUP_ID_PREFIX, id)
+    }
+}
+
+/// Whether the request was sent by a backfill.
+pub fn is_backfill_request(signup_id: &str) -> bool {
+    signup_id.starts_with(BACKFILL_SIGNUP_ID_PREFIX)
+}
+
+#[derive(Error, Debug)]
+pub enum BackfillError {
+    #[error("Failed to access {path}: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("Invalid entry at line {line}: {source}")]
+    InvalidLine {
+        line:   usize,
+        source: serde_json::Error,
+    },
+    #[error("Failed to decode the {share} share: {reason}")]
+    Decode {
+        share:  &'static str,
+        reason: String,
+    },
+    #[error("The {share} share belongs to party id {got}, expected {expected}")]
+    WrongParty {
+        share:    &'static str,
+        expected: usize,
+        got:      usize,
+    },
+}
+
+impl BackfillError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        BackfillError::Io {
+            path: path.display().to_string(),
+            source,
+        }
+    }
+}
+
+/// The code and trimmed mask share of one eye.
+pub type EyeShares = (GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare);
+
+/// The shares of one identity held by one party, base64 encoded like the
+/// shares of a uniqueness request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackfillEntry {
+    pub signup_id:  String,
+    pub left_code:  String,
+    pub left_mask:  String,
+    pub right_code: String,
+    pub right_mask: String,
+}
+
+impl BackfillEntry {
+    pub fn new(signup_id: &str, left: &EyeShares, right: &EyeShares) -> Self {
+        Self {
+            signup_id:  signup_id.to_string(),
+            left_code:  left.0.to_base64(ShareKind::Code),
+            left_mask:  left.1.to_base64(),
+            right_code: right.0.to_base64(ShareKind::Code),
+            right_mask: right.1.to_base64(),
+        }
+    }
+
+    /// An entry from the coefficients of a party's stored iris, as found in
+    /// the `irises` table.
+    pub fn from_stored(
+        signup_id: &str,
+        party_id: usize,
+        left: (&[u16], &[u16]),
+        right: (&[u16], &[u16]),
+    ) -> Result<Self, BackfillError> {
+        Ok(Self::new(
+            signup_id,
+            &stored_eye(party_id, left, "left")?,
+            &stored_eye(party_id, right, "right")?,
+        ))
+    }
+
+    /// Decodes the shares of the left and right eye, which must belong to the
+    /// given party.
+    pub fn decode(&self, party_id: usize) -> Result<(EyeShares, EyeShares), BackfillError> {
+        let check_party = |share: &'static str, id: usize| {
+            if id == party_id + 1 {
+                Ok(())
+            } else {
+                Err(BackfillError::WrongParty {
+                    share,
+                    expected: party_id + 1,
+                    got: id,
+                })
+            }
+        };
+        let decode_code = |share: &'static str, encoded: &str| {
+            let code =
+                GaloisRingIrisCodeShare::from_base64(encoded, ShareKind::Code).map_err(|e| {
+                    BackfillError::Decode {
+                        share,
+                        reason: e.to_string(),
+                    }
+                })?;
+            check_party(share, code.id)?;
+            Ok::<_, BackfillError>(code)
+        };
+        let decode_mask = |share: &'static str, encoded: &str| {
+            let mask = GaloisRingTrimmedMaskCodeShare::from_base64(encoded).map_err(|e| {
+                BackfillError::Decode {
+                    share,
+                    reason: e.to_string(),
+                }
+            })?;
+            check_party(share, mask.id)?;
+            Ok::<_, BackfillError>(mask)
+        };
+        Ok((
+            (
+                decode_code("left code", &self.left_code)?,
+                decode_mask("left mask", &self.left_mask)?,
+            ),
+            (
+                decode_code("right code", &self.right_code)?,
+                decode_mask("right mask", &self.right_mask)?,
+            ),
+        ))
+    }
+}
+
+fn stored_eye(
+    party_id: usize,
+    (code, mask): (&[u16], &[u16]),
+    side: &'static str,
+) -> Result<EyeShares, BackfillError> {
+    let invalid_length = |len: usize| BackfillError::Decode {
+        share:  side,
+        reason: format!("unexpected number of coefficients {}", len),
+    };
+    Ok((
+        GaloisRingIrisCodeShare {
+            id:    party_id + 1,
+            coefs: code.try_into().map_err(|_| invalid_length(code.len()))?,
+        },
+        GaloisRingTrimmedMaskCodeShare {
+            id:    party_id + 1,
+            coefs: mask.try_into().map_err(|_| invalid_length(mask.len()))?,
+        },
+    ))
+}
+
+/// Reads a share file holding one JSON encoded [`BackfillEntry`] per line,
+/// starting at the given line. Empty lines are skipped.
+pub fn read_share_file(
+    path: &Path,
+    start: usize,
+) -> Result<impl Iterator<Item = Result<(usize, BackfillEntry), BackfillError>>, BackfillError> {
+    let file = File::open(path).map_err(|e| BackfillError::io(path, e))?;
+    let path = path.to_path_buf();
+    Ok(BufReader::new(file)
+        .lines()
+        .enumerate()
+        .skip(start)
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(move |(line_index, line)| {
+            let line = line.map_err(|e| BackfillError::io(&path, e))?;
+            let entry =
+                serde_json::from_str(&line).map_err(|source| BackfillError::InvalidLine {
+                    line: line_index + 1,
+                    source,
+                })?;
+            Ok((line_index, entry))
+        }))
+}
+
+/// Where a backfill resumes. Only entries accepted by the server are counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackfillCheckpoint {
+    /// Position of the next entry in the source: a line of the share file or
+    /// a serial id of the database.
+    pub next:     u64,
+    /// Number of entries accepted so far.
+    pub accepted: u64,
+}
+
+impl BackfillCheckpoint {
+    /// Loads the checkpoint, or starts from the beginning if there is none.
+    pub fn load(path: &Path) -> Result<Self, BackfillError> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|source| BackfillError::InvalidLine { line: 1, source }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(BackfillError::io(path, e)),
+        }
+    }
+
+    /// Replaces the stored checkpoint. The file is renamed into place, so an
+    /// interrupted write keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> Result<(), BackfillError> {
+        let tmp = path.with_extension("tmp");
+        let bytes = serde_json::to_vec(self).expect("checkpoints serialize to JSON");
+        std::fs::write(&tmp, bytes).map_err(|e| BackfillError::io(&tmp, e))?;
+        std::fs::rename(&tmp, path).map_err(|e| BackfillError::io(path, e))
+    }
+}
+
+/// The answer of the server to a chunk of entries. Only the first `accepted`
+/// entries were queued, the rest has to be sent again later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackfillResponse {
+    pub accepted: usize,
+}
+
+/// Spaces out the entries sent by a backfill.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next:     Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Allows `per_second` entries per second. A rate of 0 disables the limit.
+    pub fn new(per_second: f64) -> Self {
+        let interval = if per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / per_second)
+        } else {
+            Duration::ZERO
+        };
+        Self {
+            interval,
+            next: None,
+        }
+    }
+
+    /// Waits until `n` entries may be sent. Time spent idle is not saved up
+    /// for later bursts.
+    pub async fn acquire(&mut self, n: usize) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let start = self.next.map_or(now, |next| next.max(now));
+        tokio::time::sleep_until(start).await;
+        self.next = Some(start + self.interval * n as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        galois_engine::degree4::GaloisRingIrisCodeShare as Share, iris_db::iris::IrisCode,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn shares(rng: &mut StdRng, party_id: usize) -> EyeShares {
+        let iris = IrisCode::random_rng(rng);
+        (
+            Share::encode_iris_code(&iris.code, &iris.mask, rng)[party_id].clone(),
+            (&Share::encode_mask_code(&iris.mask, rng)[party_id]).into(),
+        )
+    }
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (left, right) = (shares(&mut rng, 1), shares(&mut rng, 1));
+        let entry = BackfillEntry::new("a", &left, &right);
+        assert_eq!(entry.decode(1).unwrap(), (left.clone(), right.clone()));
+        assert!(matches!(
+            entry.decode(2),
+            Err(BackfillError::WrongParty {
+                expected: 3,
+                got: 2,
+                ..
+            })
+        ));
+
+        let stored = BackfillEntry::from_stored(
+            "a",
+            1,
+            (&left.0.coefs, &left.1.coefs),
+            (&right.0.coefs, &right.1.coefs),
+        )
+        .unwrap();
+        assert_eq!(stored, entry);
+        assert!(BackfillEntry::from_stored("a", 1, (&[0; 3], &[0; 3]), (&[], &[])).is_err());
+
+        assert_eq!(backfill_signup_id("a"), "backfill-a");
+        assert_eq!(backfill_signup_id("backfill-a"), "backfill-a");
+        assert!(!is_backfill_request("a"));
+    }
+
+    #[test]
+    fn test_share_file_and_checkpoint() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let entries = (0..3)
+            .map(|i| BackfillEntry::new(&i.to_string(), &shares(&mut rng, 0), &shares(&mut rng, 0)))
+            .collect::<Vec<_>>();
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("backfill-test-{}.jsonl", std::process::id()));
+        let lines = entries
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap())
+            .collect::<Vec<_>>();
+        std::fs::write(&path, lines.join("\n\n")).unwrap();
+
+        let read = read_share_file(&path, 2)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read, vec![(2, entries[1].clone()), (4, entries[2].clone())]);
+        std::fs::remove_file(&path).unwrap();
+
+        let path = dir.join(format!("backfill-test-{}.checkpoint", std::process::id()));
+        assert_eq!(
+            BackfillCheckpoint::load(&path).unwrap(),
+            BackfillCheckpoint::default()
+        );
+        let checkpoint = BackfillCheckpoint {
+            next:     5,
+            accepted: 2,
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(BackfillCheckpoint::load(&path).unwrap(), checkpoint);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(10.0);
+        limiter.acquire(10).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(5).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        limiter.acquire(1).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+
+        let mut unlimited = RateLimiter::new(0.0);
+        unlimited.acquire(1000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+}
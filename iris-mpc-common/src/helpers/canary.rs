@@ -0,0 +1,365 @@
+//! End-to-end canary with synthetic identities.
+//!
+//! The canary keeps a small set of synthetic irises derived from a reserved
+//! seed. Every round enrolls one of them, probes it with a noisy capture of
+//! the same irises and deletes it again, checking the result of every step.
+//! The outcome of the last round is exported as the `canary_healthy` gauge.
+//!
+//! The signup ids of all canary requests start with
+//! [`CANARY_SIGNUP_ID_PREFIX`], so that the parties can tag the serial ids they
+//! assign to them. Tagged serial ids are excluded from the statistics and can
+//! be purged, see [`is_canary_request`].
+
+use super::smpc_response::{IdentityDeletionResult, UniquenessResult};
+use crate::iris_db::iris::IrisCode;
+use async_trait::async_trait;
+use rand::{rngs::StdRng, SeedableRng};
+use std::{fmt, future::Future, time::Duration};
+use thiserror::Error;
+
+/// Seed of the synthetic identities. No real request uses irises derived from
+/// it.
+pub const CANARY_SEED: u64 = 0x6361_6e61_7279;
+pub const CANARY_SIGNUP_ID_PREFIX: &str = "canary-";
+
+/// Whether the request was sent by the canary.
+pub fn is_canary_request(signup_id: &str) -> bool {
+    signup_id.starts_with(CANARY_SIGNUP_ID_PREFIX)
+}
+
+/// The steps of a canary round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CanaryStep {
+    /// Enrolls a synthetic identity, which must not match.
+    Enroll,
+    /// Sends a noisy capture of the enrolled identity, which must match it.
+    Probe,
+    /// Deletes the enrolled identity, which must succeed.
+    Delete,
+    /// Deletes the identities left over by failed rounds.
+    Purge,
+}
+
+impl CanaryStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CanaryStep::Enroll => "enroll",
+            CanaryStep::Probe => "probe",
+            CanaryStep::Delete => "delete",
+            CanaryStep::Purge => "purge",
+        }
+    }
+}
+
+impl fmt::Display for CanaryStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CanaryFailure {
+    #[error("Canary round {round} failed to {step}: {source:?}")]
+    Target {
+        round:  u64,
+        step:   CanaryStep,
+        source: eyre::Report,
+    },
+    #[error("Canary round {round} got an unexpected {step} result: {detail}")]
+    UnexpectedResult {
+        round:  u64,
+        step:   CanaryStep,
+        detail: String,
+    },
+}
+
+impl CanaryFailure {
+    pub fn step(&self) -> CanaryStep {
+        match self {
+            CanaryFailure::Target { step, .. } | CanaryFailure::UnexpectedResult { step, .. } => {
+                *step
+            }
+        }
+    }
+}
+
+/// The system under test. Every call returns once the result of the request
+/// is in.
+#[async_trait]
+pub trait CanaryTarget: Send {
+    async fn uniqueness(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+    ) -> eyre::Result<UniquenessResult>;
+
+    async fn delete(&mut self, serial_id: u32) -> eyre::Result<IdentityDeletionResult>;
+}
+
+pub struct Canary {
+    seed:       u64,
+    identities: Vec<(IrisCode, IrisCode)>,
+    round:      u64,
+    /// Serial ids assigned to canary requests that were not deleted yet.
+    purge:      Vec<u32>,
+}
+
+impl Canary {
+    /// A canary cycling through `n_identities` identities derived from the
+    /// seed, which should be [`CANARY_SEED`] outside of tests.
+    pub fn new(seed: u64, n_identities: usize) -> Self {
+        assert!(n_identities > 0, "the canary needs at least one identity");
+        let mut rng = StdRng::seed_from_u64(seed);
+        let identities = (0..n_identities)
+            .map(|_| {
+                (
+                    IrisCode::random_rng(&mut rng),
+                    IrisCode::random_rng(&mut rng),
+                )
+            })
+            .collect();
+        Self {
+            seed,
+            identities,
+            round: 0,
+            purge: vec![],
+        }
+    }
+
+    pub fn identities(&self) -> &[(IrisCode, IrisCode)] {
+        &self.identities
+    }
+
+    /// The number of finished rounds, successful or not.
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// Serial ids of canary identities that still have to be deleted.
+    pub fn pending_purge(&self) -> &[u32] {
+        &self.purge
+    }
+
+    /// Runs one round and exports its outcome.
+    pub async fn run_round(&mut self, target: &mut impl CanaryTarget) -> Result<(), CanaryFailure> {
+        let round = self.round;
+        self.round += 1;
+        let result = self.try_round(round, target).await;
+        match &result {
+            Ok(()) => {
+                tracing::info!(round, "Canary round passed");
+                metrics::gauge!("canary_healthy").set(1.0);
+            }
+            Err(failure) => {
+                tracing::error!(
+                    round,
+                    step = failure.step().as_str(),
+                    pending_purge = ?self.purge,
+                    "{}",
+                    failure
+                );
+                metrics::gauge!("canary_healthy").set(0.0);
+                metrics::counter!("canary.failures", "step" => failure.step().as_str())
+                    .increment(1);
+            }
+        }
+        result
+    }
+
+    /// Runs rounds every `interval` until `shutdown` completes.
+    pub async fn run(
+        &mut self,
+        target: &mut impl CanaryTarget,
+        interval: Duration,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        tokio::pin!(shutdown);
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = ticks.tick() => {
+                    // Failures are exported by the round.
+                    let _ = self.run_round(target).await;
+                }
+            }
+        }
+    }
+
+    async fn try_round(
+        &mut self,
+        round: u64,
+        target: &mut impl CanaryTarget,
+    ) -> Result<(), CanaryFailure> {
+        let unexpected = |step, detail: String| CanaryFailure::UnexpectedResult {
+            round,
+            step,
+            detail,
+        };
+        let target_error = |step| {
+            move |source| CanaryFailure::Target {
+                round,
+                step,
+                source,
+            }
+        };
+
+        // A leftover identity would match the enrollment below.
+        while let Some(&serial_id) = self.purge.first() {
+            target
+                .delete(serial_id)
+                .await
+                .map_err(target_error(CanaryStep::Purge))?;
+            self.purge.remove(0);
+        }
+
+        let (left, right) =
+            self.identities[(round % self.identities.len() as u64) as usize].clone();
+        let enrolled = target
+            .uniqueness(
+                &signup_id(round, CanaryStep::Enroll),
+                left.clone(),
+                right.clone(),
+            )
+            .await
+            .map_err(target_error(CanaryStep::Enroll))?;
+        self.track(&enrolled);
+        let serial_id = match (enrolled.is_match, enrolled.serial_id) {
+            (false, Some(serial_id)) => serial_id,
+            _ => {
+                return Err(unexpected(
+                    CanaryStep::Enroll,
+                    format!(
+                        "expected a new serial id, got match {} with {:?}",
+                        enrolled.is_match, enrolled.matched_serial_ids
+                    ),
+                ))
+            }
+        };
+
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(round));
+        let probe = target
+            .uniqueness(
+                &signup_id(round, CanaryStep::Probe),
+                left.get_similar_iris(&mut rng),
+                right.get_similar_iris(&mut rng),
+            )
+            .await
+            .map_err(target_error(CanaryStep::Probe))?;
+        self.track(&probe);
+        let matched = probe.matched_serial_ids.clone().unwrap_or_default();
+        if !probe.is_match || !matched.contains(&serial_id) {
+            return Err(unexpected(
+                CanaryStep::Probe,
+                format!(
+                    "expected a match with serial id {}, got match {} with {:?}",
+                    serial_id, probe.is_match, matched
+                ),
+            ));
+        }
+
+        let deleted = target
+            .delete(serial_id)
+            .await
+            .map_err(target_error(CanaryStep::Delete))?;
+        if !deleted.success || deleted.serial_id != serial_id {
+            return Err(unexpected(
+                CanaryStep::Delete,
+                format!(
+                    "expected serial id {} to be deleted, got {:?}",
+                    serial_id, deleted
+                ),
+            ));
+        }
+        self.purge.retain(|&id| id != serial_id);
+        Ok(())
+    }
+
+    /// Remembers the serial id assigned to a canary request for the purge.
+    fn track(&mut self, result: &UniquenessResult) {
+        if let Some(serial_id) = result.serial_id {
+            self.purge.push(serial_id);
+        }
+    }
+}
+
+/// The signup id of a canary request.
+pub fn signup_id(round: u64, step: CanaryStep) -> String {
+    format!(
+        "{}{}-{}-{}",
+        CANARY_SIGNUP_ID_PREFIX,
+        round,
+        step,
+        uuid::Uuid::new_v4()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enrolls every identity under a new serial id and never matches.
+    #[derive(Default)]
+    struct NeverMatches {
+        enrolled: u32,
+        deleted:  Vec<u32>,
+    }
+
+    #[async_trait]
+    impl CanaryTarget for NeverMatches {
+        async fn uniqueness(
+            &mut self,
+            signup_id: &str,
+            _left: IrisCode,
+            _right: IrisCode,
+        ) -> eyre::Result<UniquenessResult> {
+            self.enrolled += 1;
+            Ok(UniquenessResult::new(
+                0,
+                Some(self.enrolled),
+                false,
+                signup_id.to_string(),
+                None,
+                None,
+                None,
+                None,
+            ))
+        }
+
+        async fn delete(&mut self, serial_id: u32) -> eyre::Result<IdentityDeletionResult> {
+            self.deleted.push(serial_id);
+            Ok(IdentityDeletionResult::new(0, serial_id, true))
+        }
+    }
+
+    #[test]
+    fn test_identities_are_deterministic() {
+        let canary = Canary::new(CANARY_SEED, 3);
+        assert_eq!(
+            canary.identities(),
+            Canary::new(CANARY_SEED, 3).identities()
+        );
+        assert_ne!(canary.identities()[0], canary.identities()[1]);
+        assert!(is_canary_request(&signup_id(7, CanaryStep::Probe)));
+        assert!(!is_canary_request("0f7e4c1a-canary"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_round_is_purged() {
+        let mut canary = Canary::new(CANARY_SEED, 2);
+        let mut target = NeverMatches::default();
+
+        let failure = canary.run_round(&mut target).await.unwrap_err();
+        assert_eq!(failure.step(), CanaryStep::Probe);
+        // Both the enrollment and the probe were inserted.
+        assert_eq!(canary.pending_purge(), &[1, 2]);
+
+        let failure = canary.run_round(&mut target).await.unwrap_err();
+        assert_eq!(failure.step(), CanaryStep::Probe);
+        assert_eq!(target.deleted, vec![1, 2]);
+        assert_eq!(canary.pending_purge(), &[3, 4]);
+        assert_eq!(canary.round(), 2);
+    }
+}
@@ -0,0 +1,541 @@
+//! Golden vectors for the threshold comparison.
+//!
+//! The GPU circuits and the CPU protocol implement the same comparison, see
+//! [`MatchThreshold`], and must open the same decisions. Both are run against
+//! the fixtures committed at [`FIXTURE_PATH`]: replicated shares of code and
+//! mask dot products, with the decisions of the plaintext matcher
+//! [`IrisCode::masked_distance`]. The shares are in the `(a, b) = (x_i,
+//! x_{i-1})` layout of the GPU `ChunkShare`.
+//!
+//! The lanes of a [`ConformanceSet`] cover distances at the threshold, full
+//! and zero masks, all rotations of noisy rotated codes, and the lanes next to
+//! the 64-lane words and 2048-lane device chunks. A backend pads the lanes to
+//! its own chunk size with zero shares, and these padding lanes must open as
+//! non-matches, see [`ConformanceSet::check`].
+//!
+//! The fixtures are regenerated with the `conformance-fixtures` binary. A
+//! change of the format bumps [`FIXTURE_VERSION`], loading fixtures of another
+//! version fails.
+
+use super::{reference, threshold::MatchThreshold};
+use crate::iris_db::iris::{IrisCode, IrisCodeArray};
+use eyre::{bail, WrapErr};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+pub const FIXTURE_VERSION: u32 = 1;
+
+/// The committed fixtures, shared by the GPU and CPU test suites.
+pub const FIXTURE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/conformance.json");
+
+/// Lanes per device chunk of the GPU circuits, the fixtures cover the lanes
+/// around the first chunk boundary.
+const DEVICE_CHUNK_LANES: usize = 2048;
+/// Flipped code bits of the noisy codes of the rotation cases, about 5%.
+const NOISE_FLIPS: usize = 640;
+/// Rotations of the database code in the rotation cases. The last one is
+/// outside the rotations the comparison tries.
+const ROTATION_SHIFTS: [isize; 4] = [0, 7, -15, 16];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConformanceFixtures {
+    pub version: u32,
+    pub sets:    Vec<ConformanceSet>,
+}
+
+/// The lanes of one comparison at a fixed threshold.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConformanceSet {
+    pub name:      String,
+    pub threshold: MatchThreshold,
+    /// Labelled ranges of lanes, the remaining lanes are random filler.
+    pub cases:     Vec<ConformanceCase>,
+    pub lanes:     Vec<ConformanceLane>,
+    /// The shares of each party, indexed by party id.
+    pub shares:    [DotShares; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConformanceCase {
+    pub label: String,
+    pub start: usize,
+    pub len:   usize,
+}
+
+/// The plaintext of a lane: the masked Hamming distance and the number of
+/// bits unmasked in both codes. The shared code dot product is `mask_len - 2
+/// * code_distance`, the mask dot product is `mask_len`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConformanceLane {
+    pub code_distance: u16,
+    pub mask_len:      u16,
+    pub is_match:      bool,
+}
+
+/// A party's replicated shares of the code and mask dot products in Z_{2^16}.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DotShares {
+    pub code_a: Vec<u16>,
+    pub code_b: Vec<u16>,
+    pub mask_a: Vec<u16>,
+    pub mask_b: Vec<u16>,
+}
+
+impl ConformanceFixtures {
+    /// Loads the committed fixtures from [`FIXTURE_PATH`].
+    pub fn load() -> eyre::Result<Self> {
+        Self::from_path(FIXTURE_PATH)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).wrap_err_with(|| format!("Cannot read {}", path.display()))?;
+        let fixtures: Self = serde_json::from_slice(&data)?;
+        if fixtures.version != FIXTURE_VERSION {
+            bail!(
+                "Fixtures of version {} but expected {}, regenerate them with conformance-fixtures",
+                fixtures.version,
+                FIXTURE_VERSION
+            );
+        }
+        Ok(fixtures)
+    }
+
+    /// Generates fresh fixtures, with the expected decisions of the plaintext
+    /// matcher.
+    pub fn generate<R: Rng>(rng: &mut R) -> Self {
+        let default = MatchThreshold::default();
+        let strict = MatchThreshold::new(0.25).unwrap();
+
+        let mut sets = vec![];
+
+        let mut builder = SetBuilder::new(default);
+        builder.fill_to(62, rng);
+        builder.word_boundary(rng);
+        builder.boundary_distances();
+        builder.full_masks();
+        builder.zero_masks();
+        builder.rotations(rng);
+        builder.fill_to(DEVICE_CHUNK_LANES - 2, rng);
+        builder.chunk_boundary(rng);
+        builder.last_lane(rng);
+        sets.push(builder.build("default", rng));
+
+        let mut builder = SetBuilder::new(strict);
+        builder.fill_to(62, rng);
+        builder.word_boundary(rng);
+        builder.boundary_distances();
+        builder.full_masks();
+        builder.zero_masks();
+        builder.last_lane(rng);
+        sets.push(builder.build("strict", rng));
+
+        Self {
+            version: FIXTURE_VERSION,
+            sets,
+        }
+    }
+}
+
+impl ConformanceSet {
+    pub fn len(&self) -> usize {
+        self.lanes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.is_empty()
+    }
+
+    pub fn expected(&self) -> Vec<bool> {
+        self.lanes.iter().map(|lane| lane.is_match).collect()
+    }
+
+    /// The label of the case the lane belongs to.
+    pub fn describe(&self, lane: usize) -> &str {
+        if lane >= self.lanes.len() {
+            return "padding";
+        }
+        self.cases
+            .iter()
+            .find(|case| (case.start..case.start + case.len).contains(&lane))
+            .map_or("filler", |case| case.label.as_str())
+    }
+
+    /// Compares the opened decisions of a backend with the expected ones. The
+    /// decisions may extend beyond the lanes of the set with padding lanes,
+    /// which must not match.
+    pub fn check(&self, decisions: &[bool]) -> eyre::Result<()> {
+        if decisions.len() < self.lanes.len() {
+            bail!(
+                "{}: {} decisions for {} lanes",
+                self.name,
+                decisions.len(),
+                self.lanes.len()
+            );
+        }
+        let mismatches = decisions
+            .iter()
+            .enumerate()
+            .filter(|&(i, &decision)| {
+                decision != self.lanes.get(i).is_some_and(|lane| lane.is_match)
+            })
+            .map(|(i, &decision)| format!("lane {} ({}): opened {}", i, self.describe(i), decision))
+            .collect::<Vec<_>>();
+        if !mismatches.is_empty() {
+            bail!(
+                "{}: {} lanes differ from the plaintext matcher, {}",
+                self.name,
+                mismatches.len(),
+                mismatches.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ConformanceLane {
+    /// The code dot product in Z_{2^16}.
+    pub fn code_dot(&self) -> u16 {
+        (self.mask_len as i32 - 2 * self.code_distance as i32) as u16
+    }
+
+    pub fn mask_dot(&self) -> u16 {
+        self.mask_len
+    }
+}
+
+struct SetBuilder {
+    threshold: MatchThreshold,
+    cases:     Vec<ConformanceCase>,
+    lanes:     Vec<ConformanceLane>,
+}
+
+impl SetBuilder {
+    fn new(threshold: MatchThreshold) -> Self {
+        Self {
+            threshold,
+            cases: vec![],
+            lanes: vec![],
+        }
+    }
+
+    fn build<R: Rng>(self, name: &str, rng: &mut R) -> ConformanceSet {
+        let mut shares: [DotShares; 3] = Default::default();
+        for lane in &self.lanes {
+            let code = rep_share(lane.code_dot(), rng);
+            let mask = rep_share(lane.mask_dot(), rng);
+            for (party, (code, mask)) in shares.iter_mut().zip(code.into_iter().zip(mask)) {
+                party.code_a.push(code.0);
+                party.code_b.push(code.1);
+                party.mask_a.push(mask.0);
+                party.mask_b.push(mask.1);
+            }
+        }
+        ConformanceSet {
+            name: name.to_string(),
+            threshold: self.threshold,
+            cases: self.cases,
+            lanes: self.lanes,
+            shares,
+        }
+    }
+
+    fn case(&mut self, label: String, pairs: Vec<(IrisCode, IrisCode)>) {
+        self.cases.push(ConformanceCase {
+            label,
+            start: self.lanes.len(),
+            len: pairs.len(),
+        });
+        for (query, db) in pairs {
+            let lane = self.lane(&query, &db);
+            self.lanes.push(lane);
+        }
+    }
+
+    /// The lane of a pair, with the decision of the plaintext matcher.
+    fn lane(&self, query: &IrisCode, db: &IrisCode) -> ConformanceLane {
+        let (code_distance, mask_len, is_match) = match query.masked_distance(db) {
+            Some((code_distance, mask_len, distance)) => {
+                (code_distance, mask_len, distance < self.threshold.ratio())
+            }
+            None => (0, 0, false),
+        };
+        let lane = ConformanceLane {
+            code_distance: code_distance as u16,
+            mask_len: mask_len as u16,
+            is_match,
+        };
+        // The fixtures only hold thresholds where the fixed point comparison
+        // agrees with the plaintext one.
+        let fixed_point = reference::masked_match(lane.code_dot(), lane.mask_dot(), self.threshold);
+        assert_eq!(
+            fixed_point,
+            is_match,
+            "threshold {} is not exact in fixed point",
+            self.threshold.ratio()
+        );
+        lane
+    }
+
+    fn distance_lanes(&mut self, label: &str, distances: &[(usize, usize)]) {
+        let pairs = distances
+            .iter()
+            .map(|&(code_distance, mask_len)| pair_at_distance(code_distance, mask_len))
+            .collect();
+        self.case(label.to_string(), pairs);
+    }
+
+    fn random_pair<R: Rng>(&self, is_match: bool, rng: &mut R) -> (IrisCode, IrisCode) {
+        let mask_len = rng.gen_range(10000..=IrisCodeArray::IRIS_CODE_SIZE);
+        // The largest matching distance is the one below ratio * mask_len.
+        let largest_match = (self.threshold.ratio() * mask_len as f64).ceil() as usize - 1;
+        let code_distance = if is_match {
+            rng.gen_range(0..=largest_match)
+        } else {
+            rng.gen_range(largest_match + 1..=mask_len)
+        };
+        pair_at_distance(code_distance, mask_len)
+    }
+
+    fn fill_to<R: Rng>(&mut self, len: usize, rng: &mut R) {
+        while self.lanes.len() < len {
+            let pair = self.random_pair(rng.gen_bool(0.5), rng);
+            let lane = self.lane(&pair.0, &pair.1);
+            self.lanes.push(lane);
+        }
+    }
+
+    /// Matches on both sides of a boundary, between non-matches.
+    fn matches_across<R: Rng>(&mut self, label: String, rng: &mut R) {
+        let pairs = [false, true, true, false]
+            .into_iter()
+            .map(|is_match| self.random_pair(is_match, rng))
+            .collect();
+        self.case(label, pairs);
+    }
+
+    fn word_boundary<R: Rng>(&mut self, rng: &mut R) {
+        let label = format!(
+            "matches at lanes {} and {}",
+            self.lanes.len() + 1,
+            self.lanes.len() + 2
+        );
+        self.matches_across(label, rng);
+    }
+
+    fn chunk_boundary<R: Rng>(&mut self, rng: &mut R) {
+        let label = format!(
+            "matches across the device chunk at lane {}",
+            self.lanes.len() + 2
+        );
+        self.matches_across(label, rng);
+    }
+
+    fn boundary_distances(&mut self) {
+        let ratio = self.threshold.ratio();
+        let mut distances = vec![];
+        for mask_len in [IrisCodeArray::IRIS_CODE_SIZE, 12799, 11520, 8, 1] {
+            let at = ratio * mask_len as f64;
+            let below = at.ceil() as usize - 1;
+            for code_distance in [below.saturating_sub(1), below, below + 1, below + 2] {
+                if code_distance <= mask_len && !distances.contains(&(code_distance, mask_len)) {
+                    distances.push((code_distance, mask_len));
+                }
+            }
+        }
+        self.distance_lanes("distances at the threshold", &distances);
+    }
+
+    fn full_masks(&mut self) {
+        let size = IrisCodeArray::IRIS_CODE_SIZE;
+        let distances = [0, 1, size / 4, size / 2, size - 1, size].map(|d| (d, size));
+        self.distance_lanes("full masks", &distances);
+    }
+
+    fn zero_masks(&mut self) {
+        let pairs = vec![
+            pair_at_distance(0, 0),
+            (
+                IrisCode {
+                    code: IrisCodeArray::ONES,
+                    mask: IrisCodeArray::ZERO,
+                },
+                IrisCode {
+                    code: IrisCodeArray::ZERO,
+                    mask: IrisCodeArray::ONES,
+                },
+            ),
+        ];
+        self.case("zero masks".to_string(), pairs);
+    }
+
+    /// All rotations of a query against a noisy copy rotated by the shift.
+    fn rotations<R: Rng>(&mut self, rng: &mut R) {
+        for shift in ROTATION_SHIFTS {
+            let query = IrisCode::random_rng(rng);
+            let rotate = |code: &IrisCodeArray| {
+                if shift < 0 {
+                    code.rotate_left(shift.unsigned_abs())
+                } else {
+                    code.rotate_right(shift as usize)
+                }
+            };
+            let mut db = IrisCode {
+                code: rotate(&query.code),
+                mask: rotate(&query.mask),
+            };
+            for _ in 0..NOISE_FLIPS {
+                db.code
+                    .flip_bit(rng.gen_range(0..IrisCodeArray::IRIS_CODE_SIZE));
+            }
+            let pairs = query
+                .code
+                .all_rotations()
+                .zip(query.mask.all_rotations())
+                .map(|(code, mask)| (IrisCode { code, mask }, db.clone()))
+                .collect();
+            self.case(format!("rotations of a code rotated by {}", shift), pairs);
+        }
+    }
+
+    /// A match in the last lane, next to the padding of the backends.
+    fn last_lane<R: Rng>(&mut self, rng: &mut R) {
+        let pairs = vec![pair_at_distance(0, 0), self.random_pair(true, rng)];
+        self.case("last lane before the padding".to_string(), pairs);
+    }
+}
+
+/// A pair with `mask_len` bits unmasked in both codes, of which the first
+/// `code_distance` differ.
+fn pair_at_distance(code_distance: usize, mask_len: usize) -> (IrisCode, IrisCode) {
+    let mut mask = IrisCodeArray::ZERO;
+    let mut code = IrisCodeArray::ZERO;
+    for i in 0..mask_len {
+        mask.set_bit(i, true);
+        code.set_bit(i, i < code_distance);
+    }
+    let query = IrisCode {
+        code: IrisCodeArray::ZERO,
+        mask,
+    };
+    (query, IrisCode { code, mask })
+}
+
+/// Replicated shares `(x_i, x_{i-1})` of a value in Z_{2^16}.
+fn rep_share<R: Rng>(value: u16, rng: &mut R) -> [(u16, u16); 3] {
+    let x0 = rng.gen::<u16>();
+    let x1 = rng.gen::<u16>();
+    let x2 = value.wrapping_sub(x0).wrapping_sub(x1);
+    [(x0, x2), (x1, x0), (x2, x1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn reconstruct(shares: &[DotShares; 3], i: usize) -> (u16, u16) {
+        let code = shares
+            .iter()
+            .fold(0u16, |acc, party| acc.wrapping_add(party.code_a[i]));
+        let mask = shares
+            .iter()
+            .fold(0u16, |acc, party| acc.wrapping_add(party.mask_a[i]));
+        (code, mask)
+    }
+
+    fn assert_consistent(fixtures: &ConformanceFixtures) {
+        assert_eq!(fixtures.version, FIXTURE_VERSION);
+        for set in &fixtures.sets {
+            for (party, shares) in set.shares.iter().enumerate() {
+                let prev = &set.shares[(party + 2) % 3];
+                assert_eq!(shares.code_b, prev.code_a);
+                assert_eq!(shares.mask_b, prev.mask_a);
+            }
+            for (i, lane) in set.lanes.iter().enumerate() {
+                assert_eq!(
+                    reconstruct(&set.shares, i),
+                    (lane.code_dot(), lane.mask_dot())
+                );
+                let expected = lane.mask_len > 0
+                    && (lane.code_distance as f64 / lane.mask_len as f64) < set.threshold.ratio();
+                assert_eq!(lane.is_match, expected, "{}: lane {}", set.name, i);
+            }
+            set.check(&set.expected()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_committed_fixtures() {
+        let fixtures = ConformanceFixtures::load().unwrap();
+        assert_consistent(&fixtures);
+        let names = fixtures
+            .sets
+            .iter()
+            .map(|set| &set.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["default", "strict"]);
+        assert!(fixtures.sets[0].len() > DEVICE_CHUNK_LANES);
+    }
+
+    #[test]
+    fn test_generate() {
+        let fixtures = ConformanceFixtures::generate(&mut StdRng::seed_from_u64(0));
+        assert_consistent(&fixtures);
+
+        let set = &fixtures.sets[0];
+        assert_eq!(set.len(), DEVICE_CHUNK_LANES + 4);
+        assert!(set.lanes[63].is_match && set.lanes[64].is_match);
+        assert!(set.lanes[DEVICE_CHUNK_LANES - 1].is_match);
+        assert!(set.lanes[DEVICE_CHUNK_LANES].is_match);
+        assert!(set.lanes.last().unwrap().is_match);
+        // Only the rotations undoing the shift match.
+        for (case, shift) in set
+            .cases
+            .iter()
+            .filter(|c| c.label.starts_with("rotations"))
+            .zip(ROTATION_SHIFTS)
+        {
+            let matches = (case.start..case.start + case.len)
+                .filter(|&i| set.lanes[i].is_match)
+                .map(|i| i - case.start)
+                .collect::<Vec<_>>();
+            if shift.unsigned_abs() <= IrisCodeArray::MAX_ROTATION {
+                let expected = (IrisCodeArray::MAX_ROTATION as isize + shift) as usize;
+                assert_eq!(matches, [expected], "{}", case.label);
+            } else {
+                assert!(matches.is_empty(), "{}", case.label);
+            }
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let fixtures = ConformanceFixtures::generate(&mut StdRng::seed_from_u64(1));
+        let set = &fixtures.sets[1];
+        let mut decisions = set.expected();
+        decisions.resize(set.len().next_multiple_of(64), false);
+        set.check(&decisions).unwrap();
+
+        let mut flipped = decisions.clone();
+        flipped[63] = !flipped[63];
+        let err = set.check(&flipped).unwrap_err().to_string();
+        assert!(
+            err.contains("lane 63 (matches at lanes 63 and 64)"),
+            "{}",
+            err
+        );
+
+        let mut padding = decisions.clone();
+        *padding.last_mut().unwrap() = true;
+        let err = set.check(&padding).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("lane {} (padding)", decisions.len() - 1)),
+            "{}",
+            err
+        );
+
+        assert!(set.check(&decisions[..set.len() - 1]).is_err());
+    }
+}
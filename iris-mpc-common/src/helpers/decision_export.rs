@@ -0,0 +1,496 @@
+//! Export of the opened per-batch decisions to Parquet, for offline analytics.
+//!
+//! Every processed batch is appended as one row group, one row per query, to a
+//! Parquet file in the export directory. Files rotate daily (UTC) and are named
+//! `decisions-YYYY-MM-DD.parquet`; a file of the same day left by an earlier
+//! run is never reopened, the new one gets a `-1`, `-2`, ... suffix instead.
+//! A file is only readable once it is closed, at the rotation or on shutdown.
+//!
+//! Rows carry no share data and no raw signup ids: the signup id is replaced
+//! by its HMAC-SHA256 under the configured key, so that rows of the same
+//! signup can be joined without revealing it. The `decision-verify` binary
+//! reads the files back and cross-checks the totals against the metrics
+//! counters.
+
+use super::latency_budget::BudgetPhase;
+use arrow_array::{
+    Array, RecordBatch, StringArray, TimestampMillisecondArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use eyre::{eyre, Context};
+use hmac::{Hmac, Mac};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::Compression,
+    file::properties::WriterProperties,
+};
+use sha2::Sha256;
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use time::{macros::format_description, OffsetDateTime};
+
+/// Minimum length of the HMAC key, in bytes.
+pub const MIN_HMAC_KEY_LEN: usize = 32;
+
+const TIMEZONE: &str = "UTC";
+
+/// The opened decision on a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportedDecision {
+    Unique,
+    Match,
+}
+
+impl ExportedDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportedDecision::Unique => "unique",
+            ExportedDecision::Match => "match",
+        }
+    }
+
+    fn parse(value: &str) -> eyre::Result<Self> {
+        match value {
+            "unique" => Ok(ExportedDecision::Unique),
+            "match" => Ok(ExportedDecision::Match),
+            _ => Err(eyre!("Unknown decision {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedQuery {
+    /// Hashed before it is written.
+    pub signup_id:     String,
+    pub decision:      ExportedDecision,
+    /// Number of matched DB entries and batch requests.
+    pub matched_count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedBatch {
+    pub batch_id:          u64,
+    /// Unix timestamps in milliseconds.
+    pub started_at_ms:     i64,
+    pub finished_at_ms:    i64,
+    pub threshold_version: u32,
+    /// The phase timings in milliseconds, in the order of [`BudgetPhase::ALL`].
+    pub phase_timings_ms:  [u64; BudgetPhase::ALL.len()],
+    pub queries:           Vec<ExportedQuery>,
+}
+
+/// A row as it is stored, see [`decision_schema`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionRow {
+    pub batch_id:          u64,
+    pub started_at_ms:     i64,
+    pub finished_at_ms:    i64,
+    /// Hex encoded HMAC-SHA256 of the signup id.
+    pub signup_id_hash:    String,
+    pub decision:          ExportedDecision,
+    pub matched_count:     u32,
+    pub threshold_version: u32,
+    pub phase_timings_ms:  [u64; BudgetPhase::ALL.len()],
+}
+
+/// The schema of the exported files. Columns may be added at the end, but
+/// never renamed, retyped or reordered.
+pub fn decision_schema() -> SchemaRef {
+    let timestamp = DataType::Timestamp(TimeUnit::Millisecond, Some(TIMEZONE.into()));
+    let mut fields = vec![
+        Field::new("batch_id", DataType::UInt64, false),
+        Field::new("started_at", timestamp.clone(), false),
+        Field::new("finished_at", timestamp, false),
+        Field::new("signup_id_hash", DataType::Utf8, false),
+        Field::new("decision", DataType::Utf8, false),
+        Field::new("matched_count", DataType::UInt32, false),
+        Field::new("threshold_version", DataType::UInt32, false),
+    ];
+    fields.extend(
+        BudgetPhase::ALL
+            .iter()
+            .map(|phase| Field::new(timing_column(*phase), DataType::UInt64, false)),
+    );
+    Arc::new(Schema::new(fields))
+}
+
+fn timing_column(phase: BudgetPhase) -> String {
+    format!("{}_ms", phase.as_str())
+}
+
+/// Appends the batches to the daily files in a directory.
+pub struct DecisionExporter {
+    dir:      PathBuf,
+    hmac_key: Vec<u8>,
+    /// The day and the writer of the open file.
+    current:  Option<(String, ArrowWriter<File>)>,
+}
+
+impl DecisionExporter {
+    pub fn new(dir: impl Into<PathBuf>, hmac_key: &[u8]) -> eyre::Result<Self> {
+        if hmac_key.len() < MIN_HMAC_KEY_LEN {
+            return Err(eyre!(
+                "The HMAC key needs at least {} bytes, got {}",
+                MIN_HMAC_KEY_LEN,
+                hmac_key.len()
+            ));
+        }
+        let dir = dir.into();
+        fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            hmac_key: hmac_key.to_vec(),
+            current: None,
+        })
+    }
+
+    /// Hex encoded HMAC-SHA256 of the signup id.
+    pub fn hash_signup_id(&self, signup_id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.hmac_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(signup_id.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Writes the batch as a row group into the file of the day it finished,
+    /// rotating the file if the day changed.
+    pub fn append(&mut self, batch: &ExportedBatch) -> eyre::Result<()> {
+        let day = day_of(batch.finished_at_ms)?;
+        if self.current.as_ref().map(|(current, _)| current) != Some(&day) {
+            self.close()?;
+            let path = self.next_path(&day);
+            let file = File::create_new(&path)
+                .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let writer = ArrowWriter::try_new(file, decision_schema(), Some(properties))?;
+            tracing::info!("Exporting the decisions into {}", path.display());
+            self.current = Some((day, writer));
+        }
+
+        let rows = batch
+            .queries
+            .iter()
+            .map(|query| DecisionRow {
+                batch_id:          batch.batch_id,
+                started_at_ms:     batch.started_at_ms,
+                finished_at_ms:    batch.finished_at_ms,
+                signup_id_hash:    self.hash_signup_id(&query.signup_id),
+                decision:          query.decision,
+                matched_count:     query.matched_count,
+                threshold_version: batch.threshold_version,
+                phase_timings_ms:  batch.phase_timings_ms,
+            })
+            .collect::<Vec<_>>();
+        let (_, writer) = self.current.as_mut().expect("a file is open");
+        writer.write(&to_record_batch(&rows)?)?;
+        writer.flush()?;
+
+        let matches = rows
+            .iter()
+            .filter(|row| row.decision == ExportedDecision::Match)
+            .count();
+        metrics::counter!("decision_export.batches").increment(1);
+        metrics::counter!("decision_export.rows").increment(rows.len() as u64);
+        metrics::counter!("decision_export.matches").increment(matches as u64);
+        Ok(())
+    }
+
+    /// Finishes the open file, if any, so that it can be read.
+    pub fn close(&mut self) -> eyre::Result<()> {
+        if let Some((_, writer)) = self.current.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+
+    fn next_path(&self, day: &str) -> PathBuf {
+        (0..)
+            .map(|n| match n {
+                0 => self.dir.join(format!("decisions-{}.parquet", day)),
+                n => self.dir.join(format!("decisions-{}-{}.parquet", day, n)),
+            })
+            .find(|path| !path.exists())
+            .expect("a free file name exists")
+    }
+}
+
+impl Drop for DecisionExporter {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            tracing::error!("Failed to close the decision export: {}", e);
+        }
+    }
+}
+
+fn day_of(timestamp_ms: i64) -> eyre::Result<String> {
+    let time = OffsetDateTime::from_unix_timestamp(timestamp_ms.div_euclid(1000))?;
+    Ok(time
+        .date()
+        .format(format_description!("[year]-[month]-[day]"))?)
+}
+
+fn to_record_batch(rows: &[DecisionRow]) -> eyre::Result<RecordBatch> {
+    let timestamps = |f: fn(&DecisionRow) -> i64| {
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(rows.iter().map(f)).with_timezone(TIMEZONE),
+        ) as Arc<dyn Array>
+    };
+    let mut columns: Vec<Arc<dyn Array>> = vec![
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|row| row.batch_id),
+        )),
+        timestamps(|row| row.started_at_ms),
+        timestamps(|row| row.finished_at_ms),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| row.signup_id_hash.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| row.decision.as_str()),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|row| row.matched_count),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|row| row.threshold_version),
+        )),
+    ];
+    for i in 0..BudgetPhase::ALL.len() {
+        columns.push(Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|row| row.phase_timings_ms[i]),
+        )));
+    }
+    Ok(RecordBatch::try_new(decision_schema(), columns)?)
+}
+
+fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> eyre::Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .and_then(|column| column.as_any().downcast_ref::<T>())
+        .ok_or_else(|| eyre!("Column {} is missing or has the wrong type", name))
+}
+
+/// Reads all rows of an exported file. Fails if its schema is not the one of
+/// [`decision_schema`].
+pub fn read_rows(path: impl AsRef<Path>) -> eyre::Result<Vec<DecisionRow>> {
+    let path = path.as_ref();
+    let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    let mut rows = vec![];
+    for batch in reader {
+        let batch = batch?;
+        if batch.schema().fields() != decision_schema().fields() {
+            return Err(eyre!(
+                "{} does not have the decision schema: {:?}",
+                path.display(),
+                batch.schema()
+            ));
+        }
+        let batch_ids = column::<UInt64Array>(&batch, "batch_id")?;
+        let started_at = column::<TimestampMillisecondArray>(&batch, "started_at")?;
+        let finished_at = column::<TimestampMillisecondArray>(&batch, "finished_at")?;
+        let signup_id_hashes = column::<StringArray>(&batch, "signup_id_hash")?;
+        let decisions = column::<StringArray>(&batch, "decision")?;
+        let matched_counts = column::<UInt32Array>(&batch, "matched_count")?;
+        let threshold_versions = column::<UInt32Array>(&batch, "threshold_version")?;
+        let timings = BudgetPhase::ALL
+            .iter()
+            .map(|phase| column::<UInt64Array>(&batch, &timing_column(*phase)))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        for i in 0..batch.num_rows() {
+            rows.push(DecisionRow {
+                batch_id:          batch_ids.value(i),
+                started_at_ms:     started_at.value(i),
+                finished_at_ms:    finished_at.value(i),
+                signup_id_hash:    signup_id_hashes.value(i).to_string(),
+                decision:          ExportedDecision::parse(decisions.value(i))?,
+                matched_count:     matched_counts.value(i),
+                threshold_version: threshold_versions.value(i),
+                phase_timings_ms:  std::array::from_fn(|phase| timings[phase].value(i)),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// The totals the `decision_export.*` counters and the
+/// `result.sent{type=uniqueness_result}` counter are checked against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecisionTotals {
+    pub batches: u64,
+    pub rows:    u64,
+    pub matches: u64,
+}
+
+impl DecisionTotals {
+    /// Adds the rows of one file. A batch is counted once per file and run, as
+    /// its rows form one row group.
+    pub fn add_file(&mut self, rows: &[DecisionRow]) {
+        let mut batches = rows
+            .iter()
+            .map(|row| (row.batch_id, row.started_at_ms, row.finished_at_ms))
+            .collect::<Vec<_>>();
+        batches.dedup();
+        self.batches += batches.len() as u64;
+        self.rows += rows.len() as u64;
+        self.matches += rows
+            .iter()
+            .filter(|row| row.decision == ExportedDecision::Match)
+            .count() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+    // 2024-10-16T12:00:00Z
+    const NOON_MS: i64 = 1_729_080_000_000;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("decision-export-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn batch(batch_id: u64, finished_at_ms: i64, decisions: &[ExportedDecision]) -> ExportedBatch {
+        ExportedBatch {
+            batch_id,
+            started_at_ms: finished_at_ms - 250,
+            finished_at_ms,
+            threshold_version: 3,
+            phase_timings_ms: [1, 2, 120, 30, 5],
+            queries: decisions
+                .iter()
+                .enumerate()
+                .map(|(i, &decision)| ExportedQuery {
+                    signup_id: format!("signup-{}-{}", batch_id, i),
+                    decision,
+                    matched_count: (decision == ExportedDecision::Match) as u32,
+                })
+                .collect(),
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_schema_is_stable() {
+        let columns = decision_schema()
+            .fields()
+            .iter()
+            .map(|field| format!("{}: {}", field.name(), field.data_type()))
+            .collect::<Vec<_>>();
+        assert_eq!(columns, vec![
+            "batch_id: UInt64",
+            "started_at: Timestamp(Millisecond, Some(\"UTC\"))",
+            "finished_at: Timestamp(Millisecond, Some(\"UTC\"))",
+            "signup_id_hash: Utf8",
+            "decision: Utf8",
+            "matched_count: UInt32",
+            "threshold_version: UInt32",
+            "fetch_ms: UInt64",
+            "validate_ms: UInt64",
+            "gpu_ms: UInt64",
+            "open_ms: UInt64",
+            "publish_ms: UInt64",
+        ]);
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        use ExportedDecision::*;
+        let dir = temp_dir("roundtrip");
+        let batches = [
+            batch(0, NOON_MS, &[Unique, Match, Unique]),
+            batch(1, NOON_MS + 1000, &[Match]),
+            // The next day goes into a new file.
+            batch(2, NOON_MS + DAY_MS, &[Unique, Unique]),
+        ];
+        let mut exporter = DecisionExporter::new(&dir, &KEY).unwrap();
+        for batch in batches.iter() {
+            exporter.append(batch).unwrap();
+        }
+        drop(exporter);
+
+        let files = files(&dir);
+        assert_eq!(files, vec![
+            dir.join("decisions-2024-10-16.parquet"),
+            dir.join("decisions-2024-10-17.parquet"),
+        ]);
+        let first = read_rows(&files[0]).unwrap();
+        assert_eq!(first.len(), 4);
+        assert_eq!(first[1], DecisionRow {
+            batch_id:          0,
+            started_at_ms:     NOON_MS - 250,
+            finished_at_ms:    NOON_MS,
+            signup_id_hash:    DecisionExporter::new(&dir, &KEY)
+                .unwrap()
+                .hash_signup_id("signup-0-1"),
+            decision:          Match,
+            matched_count:     1,
+            threshold_version: 3,
+            phase_timings_ms:  [1, 2, 120, 30, 5],
+        });
+        let second = read_rows(&files[1]).unwrap();
+        assert_eq!(second.len(), 2);
+
+        // No raw signup ids in the files.
+        for file in files.iter() {
+            let content = fs::read(file).unwrap();
+            assert!(!content.windows(7).any(|w| w == b"signup-"));
+        }
+
+        let mut totals = DecisionTotals::default();
+        totals.add_file(&first);
+        totals.add_file(&second);
+        assert_eq!(totals, DecisionTotals {
+            batches: 3,
+            rows:    6,
+            matches: 2,
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restart_does_not_reopen_files() {
+        let dir = temp_dir("restart");
+        for batch_id in 0..2 {
+            let mut exporter = DecisionExporter::new(&dir, &KEY).unwrap();
+            exporter
+                .append(&batch(batch_id, NOON_MS, &[ExportedDecision::Unique]))
+                .unwrap();
+        }
+        let files = files(&dir);
+        assert_eq!(files, vec![
+            dir.join("decisions-2024-10-16-1.parquet"),
+            dir.join("decisions-2024-10-16.parquet"),
+        ]);
+        for file in files.iter() {
+            assert_eq!(read_rows(file).unwrap().len(), 1);
+        }
+
+        assert!(DecisionExporter::new(&dir, &KEY[..16]).is_err());
+        let other_key = DecisionExporter::new(&dir, &[8; 32]).unwrap();
+        let exporter = DecisionExporter::new(&dir, &KEY).unwrap();
+        assert_ne!(
+            exporter.hash_signup_id("signup"),
+            other_key.hash_signup_id("signup")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
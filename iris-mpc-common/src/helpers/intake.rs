@@ -0,0 +1,1062 @@
+//! The intake of the request messages: receiving and parsing them, holding
+//! the batch requests until their batch and settling their messages once the
+//! batch is processed. The server and the in-process harness run the same
+//! intake over the [`RequestReceiver`] and [`ResultPublisher`] traits, see
+//! [`queue`](super::queue).
+//!
+//! Control requests, like deletions or threshold updates, are deleted from
+//! their queue when they are received and handed to the caller in
+//! [`ReceivedRequests`]. Uniqueness, reauth and reset check requests wait in
+//! the [`PriorityLanes`] until they fit into a batch, and their messages stay
+//! held in the [`InFlightMessages`] until the batch is through.
+
+use super::{
+    aws::{construct_message_attributes, TraceContext},
+    canary::is_canary_request,
+    key_pair::SharesDecodingError,
+    key_provider::KeyPairProvider,
+    latency_budget::{requeue_requests, CommittedMessage},
+    priority_lanes::{ComposedEntry, PriorityLanes, RequestLane, PRIORITY_MESSAGE_ATTRIBUTE},
+    queue::{
+        receive_with_retry, PoisonMessagePolicy, PoisonMessages, RequestReceiver, ResultPublisher,
+    },
+    share_download::ShareDownloadConfig,
+    smpc_request::{
+        parse_incoming_body, IdentityDeletionBatchRequest, IdentityDeletionRequest,
+        IdentityRestoreRequest, IrisCodesJSON, ParsedRequest, ReAuthRequest, ReceiveRequestError,
+        RequestKind, ResetCheckRequest, ResetUpdateRequest, SQSMessage, ShareHashMismatch,
+        SupportedIrisVersion, UniquenessRequest, IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+        REAUTH_MESSAGE_TYPE, RESET_CHECK_MESSAGE_TYPE, UNIQUENESS_MESSAGE_TYPE,
+    },
+    smpc_response::{
+        create_message_type_attribute_map, IdentityDeletionBatchResult, ReAuthResult,
+        ResetCheckResult, UniquenessResult,
+    },
+    sync::{BatchDeferrals, MAX_BATCH_DEFERRALS},
+    threshold::{ScheduledThreshold, ThresholdError, ThresholdUpdateRequest},
+    visibility::InFlightMessages,
+};
+use crate::{
+    config::Config,
+    errors::{ErrorCode, HasErrorCode},
+};
+use aws_sdk_s3::Client as S3Client;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{collections::HashSet, sync::Arc};
+
+/// A request that is compared in a batch.
+#[derive(Debug, Clone)]
+pub enum BatchRequest {
+    Uniqueness(UniquenessRequest),
+    ReAuth(ReAuthRequest),
+    ResetCheck(ResetCheckRequest),
+}
+
+impl BatchRequest {
+    /// The request, if it is of a kind that enters the batches.
+    pub fn from_parsed(request: ParsedRequest) -> Option<Self> {
+        match request {
+            ParsedRequest::Uniqueness(request) => Some(BatchRequest::Uniqueness(request)),
+            ParsedRequest::ReAuth(request) => Some(BatchRequest::ReAuth(request)),
+            ParsedRequest::ResetCheck(request) => Some(BatchRequest::ResetCheck(request)),
+            _ => None,
+        }
+    }
+
+    pub fn request_id(&self) -> &str {
+        match self {
+            BatchRequest::Uniqueness(request) => &request.signup_id,
+            BatchRequest::ReAuth(request) => &request.reauth_id,
+            BatchRequest::ResetCheck(request) => &request.reset_id,
+        }
+    }
+
+    pub fn batch_size(&self) -> Option<usize> {
+        match self {
+            BatchRequest::Uniqueness(request) => request.batch_size,
+            BatchRequest::ReAuth(request) => request.batch_size,
+            BatchRequest::ResetCheck(request) => request.batch_size,
+        }
+    }
+
+    pub fn iris_shares_file_hashes(&self) -> &[String; 3] {
+        match self {
+            BatchRequest::Uniqueness(request) => &request.iris_shares_file_hashes,
+            BatchRequest::ReAuth(request) => &request.iris_shares_file_hashes,
+            BatchRequest::ResetCheck(request) => &request.iris_shares_file_hashes,
+        }
+    }
+
+    pub async fn get_iris_data_by_party_id(
+        &self,
+        party_id: usize,
+        bucket_name: &String,
+        s3_client: &Arc<S3Client>,
+        http_client: &reqwest::Client,
+        download: &ShareDownloadConfig,
+    ) -> Result<String, SharesDecodingError> {
+        match self {
+            BatchRequest::Uniqueness(request) => {
+                request
+                    .get_iris_data_by_party_id(party_id, bucket_name, s3_client, download)
+                    .await
+            }
+            BatchRequest::ReAuth(request) => {
+                request
+                    .get_iris_data_by_party_id(party_id, s3_client, http_client, download)
+                    .await
+            }
+            BatchRequest::ResetCheck(request) => {
+                request
+                    .get_iris_data_by_party_id(party_id, s3_client, http_client, download)
+                    .await
+            }
+        }
+    }
+
+    pub async fn decrypt_iris_share(
+        &self,
+        share: String,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        match self {
+            BatchRequest::Uniqueness(request) => {
+                request
+                    .decrypt_iris_share(share, key_pairs, supported_versions)
+                    .await
+            }
+            BatchRequest::ReAuth(request) => {
+                request
+                    .decrypt_iris_share(share, key_pairs, supported_versions)
+                    .await
+            }
+            BatchRequest::ResetCheck(request) => {
+                request
+                    .decrypt_iris_share(share, key_pairs, supported_versions)
+                    .await
+            }
+        }
+    }
+
+    pub fn validate_iris_share(
+        &self,
+        party_id: usize,
+        share: IrisCodesJSON,
+    ) -> Result<(), ShareHashMismatch> {
+        match self {
+            BatchRequest::Uniqueness(request) => request.validate_iris_share(party_id, share),
+            BatchRequest::ReAuth(request) => request.validate_iris_share(party_id, share),
+            BatchRequest::ResetCheck(request) => request.validate_iris_share(party_id, share),
+        }
+    }
+
+    /// The share of this party, whether the request carries the shares file
+    /// inline or names one to download: fetched, opened and checked against
+    /// the hashes of the request.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn resolve_shares(
+        &self,
+        party_id: usize,
+        bucket_name: &String,
+        s3_client: &Arc<S3Client>,
+        http_client: &reqwest::Client,
+        download: &ShareDownloadConfig,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        let share = self
+            .get_iris_data_by_party_id(party_id, bucket_name, s3_client, http_client, download)
+            .await?;
+        let share = self
+            .decrypt_iris_share(share, key_pairs, supported_versions)
+            .await?;
+        self.validate_iris_share(party_id, share.clone())?;
+        Ok(share)
+    }
+
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            BatchRequest::Uniqueness(_) => UNIQUENESS_MESSAGE_TYPE,
+            BatchRequest::ReAuth(_) => REAUTH_MESSAGE_TYPE,
+            BatchRequest::ResetCheck(_) => RESET_CHECK_MESSAGE_TYPE,
+        }
+    }
+}
+
+/// A request that was received but not yet included in a batch. Its message
+/// stays in the queue until its batch is processed, tracked by `message_id` in
+/// the [`InFlightMessages`].
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub request:       BatchRequest,
+    pub trace_context: Option<TraceContext>,
+    pub message_id:    String,
+    /// The queue and body of the message, to requeue it if its batch is shed.
+    pub queue:         usize,
+    pub body:          String,
+}
+
+impl PendingRequest {
+    /// The message of the request, once it is committed to a batch.
+    pub fn committed(&self) -> CommittedMessage {
+        CommittedMessage {
+            request_id: self.request.request_id().to_string(),
+            message_id: self.message_id.clone(),
+            queue:      self.queue,
+            body:       self.body.clone(),
+        }
+    }
+}
+
+/// A control request. Its message is deleted once it is received.
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest<T> {
+    pub request:       T,
+    /// The SNS message id, the same at every party.
+    pub message_id:    String,
+    pub trace_context: Option<TraceContext>,
+}
+
+/// The control requests received by [`RequestIntake::poll`], for the caller to
+/// apply with the next batch.
+#[derive(Debug, Default)]
+pub struct ReceivedRequests {
+    pub deletions:           Vec<ReceivedRequest<IdentityDeletionRequest>>,
+    /// Already validated, the rejected ones were answered.
+    pub deletion_batches:    Vec<ReceivedRequest<IdentityDeletionBatchRequest>>,
+    pub restores:            Vec<ReceivedRequest<IdentityRestoreRequest>>,
+    /// Not yet checked, see [`verify_threshold_update`].
+    pub threshold_updates:   Vec<ReceivedRequest<ThresholdUpdateRequest>>,
+    pub reset_updates:       Vec<ReceivedRequest<ResetUpdateRequest>>,
+    /// The last batch size asked for, within `max_batch_size`.
+    pub batch_size:          Option<usize>,
+    /// The batch requests left out because of the startup sync, see
+    /// [`RequestIntake::poll`].
+    pub skipped_request_ids: Vec<String>,
+}
+
+/// What became of the messages of a processed batch, see
+/// [`RequestIntake::settle`].
+#[derive(Debug, Default)]
+pub struct SettledBatch {
+    /// Number of requeued messages of shed requests.
+    pub shed:                 usize,
+    /// The requests put back into their queues as new messages, shed or
+    /// deferred.
+    pub requeued_request_ids: Vec<String>,
+    /// The requests deferred more than [`MAX_BATCH_DEFERRALS`] times, see
+    /// [`publish_request_not_agreed`].
+    pub dropped_request_ids:  Vec<String>,
+}
+
+impl SettledBatch {
+    /// The requests of the batch that are through, i.e. not requeued.
+    pub fn completed_request_ids(&self, committed: &[CommittedMessage]) -> Vec<String> {
+        committed
+            .iter()
+            .filter(|message| !self.requeued_request_ids.contains(&message.request_id))
+            .map(|message| message.request_id.clone())
+            .collect()
+    }
+}
+
+/// The batch requests held by a party, from their receipt until their batch
+/// is through.
+pub struct RequestIntake {
+    pending:   PriorityLanes<PendingRequest>,
+    in_flight: InFlightMessages,
+    poison:    PoisonMessages,
+    deferrals: BatchDeferrals,
+}
+
+impl RequestIntake {
+    pub fn new(config: &Config, poison_policy: PoisonMessagePolicy) -> Self {
+        Self {
+            pending:   PriorityLanes::new(config.interactive_min_share),
+            in_flight: InFlightMessages::new(config.visibility_policy()),
+            poison:    PoisonMessages::new(poison_policy),
+            deferrals: BatchDeferrals::default(),
+        }
+    }
+
+    /// Number of requests waiting for a batch.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Whether the message with the given SNS id is held, waiting for a batch
+    /// or in the batch that is being processed.
+    pub fn holds(&self, message_id: &str) -> bool {
+        self.in_flight.contains(message_id)
+    }
+
+    /// The requests waiting for a batch, interactive ones first.
+    pub fn waiting(&self) -> impl Iterator<Item = &PendingRequest> {
+        self.pending.iter()
+    }
+
+    /// Extends the visibility of the held messages that are about to become
+    /// visible again. The waiting requests whose messages are no longer held
+    /// are dropped, they are received again once visible.
+    pub async fn keep_alive<R: RequestReceiver + ?Sized>(&mut self, receivers: &[&R]) {
+        let expired = self.in_flight.extend_due(receivers).await;
+        if expired.is_empty() {
+            return;
+        }
+        let expired: HashSet<_> = expired.into_iter().collect();
+        self.pending
+            .retain(|pending| !expired.contains(&pending.message_id));
+    }
+
+    /// Makes all held messages visible again right away, e.g. on shutdown,
+    /// and forgets the waiting requests.
+    pub async fn release_all<R: RequestReceiver + ?Sized>(
+        &mut self,
+        receivers: &[&R],
+    ) -> Result<(), ReceiveRequestError> {
+        self.pending.retain(|_| false);
+        self.in_flight.release_all(receivers).await
+    }
+
+    /// Receives at most one message from every queue, as long as there is room
+    /// to hold it. Batch requests join the waiting ones, control requests are
+    /// added to `received`. Requests in `skip_request_ids` were already
+    /// deleted by some party, see the startup sync, and are only deleted from
+    /// the queue. Returns whether any message was received.
+    pub async fn poll<R: RequestReceiver, P: ResultPublisher + ?Sized>(
+        &mut self,
+        queues: &[(R, Option<RequestLane>)],
+        publisher: &P,
+        config: &Config,
+        skip_request_ids: &[String],
+        received: &mut ReceivedRequests,
+    ) -> Result<bool, ReceiveRequestError> {
+        let mut received_messages = false;
+        for (queue_index, (request_receiver, queue_lane)) in queues.iter().enumerate() {
+            // Only receive as many messages as can be held until their batch.
+            if self.in_flight.capacity() == 0 {
+                break;
+            }
+            let messages = receive_with_retry(request_receiver, 1, &config.receive_retry).await?;
+            received_messages |= !messages.is_empty();
+
+            for queue_message in messages {
+                // Messages arrive to SQS through SNS, which moves the attributes set in SNS
+                // into the SQS body. With raw message delivery, they are SQS attributes.
+                let (envelope, raw_message) = match parse_incoming_body(&queue_message.body) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        self.poison
+                            .reject(request_receiver, &queue_message, e)
+                            .await?;
+                        continue;
+                    }
+                };
+                let (message, body) = match envelope {
+                    Some(envelope) => (envelope, queue_message.body.clone()),
+                    None => {
+                        // Kept as an envelope, so that its attributes survive a requeue.
+                        let message = SQSMessage::from_raw(
+                            queue_message.message_id.clone(),
+                            raw_message,
+                            queue_message.message_attributes.clone(),
+                        );
+                        let body = serde_json::to_string(&message)
+                            .map_err(|e| ReceiveRequestError::json_parse_error("SQS body", e))?;
+                        (message, body)
+                    }
+                };
+                let message_attributes = message.message_attributes;
+                let trace_context = TraceContext::from_message_attributes(&message_attributes);
+
+                let kind = match RequestKind::from_attributes(&message_attributes) {
+                    Ok(kind) => kind,
+                    Err(e) => {
+                        self.poison
+                            .reject(request_receiver, &queue_message, e)
+                            .await?;
+                        continue;
+                    }
+                };
+                let request = match ParsedRequest::parse(kind, &message.message) {
+                    Ok(request) => request,
+                    Err(e)
+                        if matches!(
+                            kind,
+                            RequestKind::Uniqueness | RequestKind::ReAuth | RequestKind::ResetCheck
+                        ) =>
+                    {
+                        // The request never enters a batch, so every party answers it on its
+                        // own, if its id can be read.
+                        tracing::error!("Rejecting malformed request: {}", e);
+                        metrics::counter!("request.failed", "code" => e.error_code().as_str())
+                            .increment(1);
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                        if let Some(result) =
+                            malformed_request_result(config, kind, &message.message, &e)
+                        {
+                            publish_error_result(
+                                result,
+                                kind.message_type(),
+                                trace_context.as_ref(),
+                                publisher,
+                            )
+                            .await?;
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        self.poison
+                            .reject(request_receiver, &queue_message, e)
+                            .await?;
+                        continue;
+                    }
+                };
+
+                match request {
+                    ParsedRequest::CircuitBreaker(circuit_breaker_request) => {
+                        metrics::counter!("request.received", "type" => "circuit_breaker")
+                            .increment(1);
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                        if let Some(batch_size) = circuit_breaker_request.batch_size {
+                            // Updating the batch size to ensure we process the messages in the next
+                            // loop
+                            received.batch_size = Some(batch_size.clamp(1, config.max_batch_size));
+                            tracing::info!(
+                                "Updating batch size to {} due to circuit breaker message",
+                                batch_size
+                            );
+                        }
+                    }
+                    ParsedRequest::IdentityDeletion(identity_deletion_request) => {
+                        // Deletions take place when the batch process starts.
+                        metrics::counter!("request.received", "type" => "identity_deletion")
+                            .increment(1);
+                        received.deletions.push(ReceivedRequest {
+                            request: identity_deletion_request,
+                            message_id: message.message_id,
+                            trace_context,
+                        });
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                    }
+                    ParsedRequest::IdentityDeletionBatch(identity_deletion_batch_request) => {
+                        // Unlike single deletions, a deletion batch is applied only once all
+                        // parties hold it, see `agreed_deletion_batches`.
+                        metrics::counter!("request.received", "type" => "identity_deletion_batch")
+                            .increment(1);
+                        match identity_deletion_batch_request.validate() {
+                            Ok(request) => received.deletion_batches.push(ReceivedRequest {
+                                request,
+                                message_id: message.message_id,
+                                trace_context,
+                            }),
+                            Err(e) => {
+                                tracing::warn!("Rejecting identity deletion batch: {}", e);
+                                metrics::counter!(
+                                    "request.failed",
+                                    "code" => e.error_code().as_str()
+                                )
+                                .increment(1);
+                                let result = IdentityDeletionBatchResult::error(
+                                    config.party_id,
+                                    vec![],
+                                    e.error_code(),
+                                );
+                                publish_error_result(
+                                    serde_json::to_string(&result).map_err(|e| {
+                                        ReceiveRequestError::json_parse_error(
+                                            "Identity deletion batch result",
+                                            e,
+                                        )
+                                    })?,
+                                    IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+                                    trace_context.as_ref(),
+                                    publisher,
+                                )
+                                .await?;
+                            }
+                        }
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                    }
+                    ParsedRequest::IdentityRestore(identity_restore_request) => {
+                        // Like deletions, restores take place when the batch process starts.
+                        metrics::counter!("request.received", "type" => "identity_restore")
+                            .increment(1);
+                        received.restores.push(ReceivedRequest {
+                            request: identity_restore_request,
+                            message_id: message.message_id,
+                            trace_context,
+                        });
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                    }
+                    ParsedRequest::ThresholdUpdate(threshold_update) => {
+                        metrics::counter!("request.received", "type" => "threshold_update")
+                            .increment(1);
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                        received.threshold_updates.push(ReceivedRequest {
+                            request: threshold_update,
+                            message_id: message.message_id,
+                            trace_context,
+                        });
+                    }
+                    ParsedRequest::ResetUpdate(reset_update_request) => {
+                        // Like deletions, updates take place when the batch process starts.
+                        metrics::counter!("request.received", "type" => "reset_update")
+                            .increment(1);
+                        request_receiver
+                            .delete(&queue_message.receipt_handle)
+                            .await?;
+                        received.reset_updates.push(ReceivedRequest {
+                            request: reset_update_request,
+                            message_id: message.message_id,
+                            trace_context,
+                        });
+                    }
+                    ParsedRequest::Uniqueness(_)
+                    | ParsedRequest::ReAuth(_)
+                    | ParsedRequest::ResetCheck(_) => {
+                        let smpc_request = BatchRequest::from_parsed(request)
+                            .expect("uniqueness, reauth and reset check requests enter batches");
+                        let request_id = smpc_request.request_id().to_string();
+                        let lane = queue_lane.unwrap_or_else(|| {
+                            RequestLane::from_priority_attribute(
+                                message_attributes
+                                    .get(PRIORITY_MESSAGE_ATTRIBUTE)
+                                    .and_then(|priority| priority.string_value()),
+                            )
+                        });
+                        // Canary requests are left out of the request statistics.
+                        if is_canary_request(&request_id) {
+                            metrics::counter!("canary.request_received").increment(1);
+                        } else {
+                            let request_type = match smpc_request {
+                                BatchRequest::Uniqueness(_) => "uniqueness_verification",
+                                BatchRequest::ReAuth(_) => "reauth",
+                                BatchRequest::ResetCheck(_) => "reset_check",
+                            };
+                            metrics::counter!(
+                                "request.received",
+                                "type" => request_type,
+                                "lane" => lane.as_str()
+                            )
+                            .increment(1);
+                        }
+
+                        if skip_request_ids.contains(&request_id) {
+                            // Some party (maybe us) already meant to delete this request, so we
+                            // skip it.
+                            request_receiver
+                                .delete(&queue_message.receipt_handle)
+                                .await?;
+                            received.skipped_request_ids.push(request_id);
+                            continue;
+                        }
+
+                        // The request is deleted from the queue once its batch is processed.
+                        // Until then, a second delivery of it is ignored.
+                        if !self.in_flight.track(
+                            &message.message_id,
+                            queue_index,
+                            queue_message.receipt_handle,
+                        ) {
+                            metrics::counter!("queue.redelivered").increment(1);
+                            continue;
+                        }
+
+                        if let Some(batch_size) = smpc_request.batch_size() {
+                            // Updating the batch size instantly makes it a bit unpredictable, since
+                            // if we're already above the new limit, we'll still process the current
+                            // batch at the higher limit. On the other
+                            // hand, updating it after the batch is
+                            // processed would not let us "unblock" the protocol if we're stuck with
+                            // low throughput.
+                            received.batch_size = Some(batch_size.clamp(1, config.max_batch_size));
+                            tracing::info!("Updating batch size to {}", batch_size);
+                        }
+
+                        self.pending.push(lane, PendingRequest {
+                            request: smpc_request,
+                            trace_context,
+                            message_id: message.message_id,
+                            queue: queue_index,
+                            body,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(received_messages)
+    }
+
+    /// Reports how many requests wait in every lane.
+    pub fn report_lane_depths(&self) {
+        for lane in RequestLane::ALL {
+            metrics::gauge!("request_lane.depth", "lane" => lane.as_str())
+                .set(self.pending.depth(lane) as f64);
+        }
+    }
+
+    /// Takes the requests of the next batch of at most `batch_size` requests
+    /// and commits their messages. They stay in their queues until the batch
+    /// is processed, see [`Self::complete`].
+    pub async fn compose<R: RequestReceiver + ?Sized>(
+        &mut self,
+        batch_size: usize,
+        receivers: &[&R],
+    ) -> Result<Vec<ComposedEntry<PendingRequest>>, ReceiveRequestError> {
+        // Drop what ran out while polling, so that every composed request is
+        // still held until its batch is processed.
+        self.keep_alive(receivers).await;
+        let entries = self.pending.compose(batch_size);
+        for entry in entries.iter() {
+            if !self.in_flight.commit(&entry.item.message_id) {
+                return Err(ReceiveRequestError::ExpiredReceiptHandle(
+                    entry.item.message_id.clone(),
+                ));
+            }
+        }
+        // The batch and the requests left waiting have to stay hidden while the
+        // batch is processed.
+        self.keep_alive(receivers).await;
+        metrics::gauge!("queue.in_flight").set(self.in_flight.len() as f64);
+        Ok(entries)
+    }
+
+    /// Puts the messages of the shed requests of a processed batch back into
+    /// their queues, for the next batch. The deferred requests, which not all
+    /// parties held, wait for them in the queues as well, until they were
+    /// deferred too often.
+    pub async fn settle<R: RequestReceiver + ?Sized>(
+        &mut self,
+        committed: &[CommittedMessage],
+        shed_request_ids: &[String],
+        deferred_request_ids: &[String],
+        receivers: &[&R],
+    ) -> Result<SettledBatch, ReceiveRequestError> {
+        let shed = requeue_requests(receivers, committed, shed_request_ids).await?;
+        for message in committed.iter() {
+            if !deferred_request_ids.contains(&message.request_id) {
+                self.deferrals.clear(&message.request_id);
+            }
+        }
+        let (deferred, dropped): (Vec<_>, Vec<_>) = deferred_request_ids
+            .iter()
+            .cloned()
+            .partition(|request_id| self.deferrals.defer(request_id));
+        requeue_requests(receivers, committed, &deferred).await?;
+        Ok(SettledBatch {
+            shed,
+            requeued_request_ids: [shed_request_ids.to_vec(), deferred].concat(),
+            dropped_request_ids: dropped,
+        })
+    }
+
+    /// Deletes the messages of a settled batch from their queues. Only now
+    /// they leave the queues, a party that fails before receives them again.
+    pub async fn complete<R: RequestReceiver + ?Sized>(
+        &mut self,
+        committed: &[CommittedMessage],
+        receivers: &[&R],
+    ) -> Result<(), ReceiveRequestError> {
+        if committed.is_empty() {
+            return Ok(());
+        }
+        for committed in committed {
+            let message = self
+                .in_flight
+                .complete(&committed.message_id)
+                .ok_or_else(|| {
+                    ReceiveRequestError::ExpiredReceiptHandle(committed.message_id.clone())
+                })?;
+            receivers[message.queue]
+                .delete(&message.receipt_handle)
+                .await?;
+        }
+        metrics::gauge!("queue.in_flight").set(self.in_flight.len() as f64);
+        Ok(())
+    }
+
+    /// Splits the deletion batches that not all parties held into the ones
+    /// offered again with the next batch and the ones deferred too often, see
+    /// [`publish_deletion_batch_not_agreed`]. The deferrals of the applied
+    /// ones are forgotten.
+    pub fn defer_deletion_batches<'a, T>(
+        &mut self,
+        applied_message_ids: impl IntoIterator<Item = &'a str>,
+        deferred: Vec<T>,
+        message_id: impl Fn(&T) -> &str,
+    ) -> (Vec<T>, Vec<T>) {
+        for applied in applied_message_ids {
+            self.deferrals.clear(applied);
+        }
+        deferred
+            .into_iter()
+            .partition(|deletion_batch| self.deferrals.defer(message_id(deletion_batch)))
+    }
+}
+
+/// Checks the signature of a threshold change against the configured operator
+/// key. Without a key, every change is rejected.
+pub fn verify_threshold_update(
+    config: &Config,
+    update: &ThresholdUpdateRequest,
+) -> Result<ScheduledThreshold, ThresholdError> {
+    let operator_key = config
+        .threshold_operator_public_key
+        .as_ref()
+        .and_then(|key| STANDARD.decode(key).ok())
+        .ok_or(ThresholdError::InvalidSignature)?;
+    update.verify(&operator_key)
+}
+
+/// The error result of a batch request whose JSON does not parse, if its id
+/// can be read from it.
+pub fn malformed_request_result(
+    config: &Config,
+    kind: RequestKind,
+    message: &str,
+    error: &ReceiveRequestError,
+) -> Option<String> {
+    let party_id = config.party_id;
+    let message: serde_json::Value = serde_json::from_str(message).ok()?;
+    let id_field = match kind {
+        RequestKind::ReAuth => "reauth_id",
+        RequestKind::ResetCheck => "reset_id",
+        _ => "signup_id",
+    };
+    let request_id = message.get(id_field)?.as_str()?.to_string();
+    let error_code = error.error_code();
+    let result = match kind {
+        RequestKind::ReAuth => serde_json::to_string(&ReAuthResult::error(
+            party_id,
+            request_id,
+            vec![],
+            error_code,
+        )),
+        RequestKind::ResetCheck => {
+            serde_json::to_string(&ResetCheckResult::error(party_id, request_id, error_code))
+        }
+        _ => {
+            let mut result = UniquenessResult::error(party_id, request_id, error_code);
+            result.error_message = Some(error.to_string());
+            if let Ok(Some(key)) = config.result_signing_key() {
+                result.sign(&key);
+            }
+            serde_json::to_string(&result)
+        }
+    };
+    result.ok()
+}
+
+/// Publishes an error result with the attributes of `message_type`.
+pub async fn publish_error_result<P: ResultPublisher + ?Sized>(
+    message: String,
+    message_type: &str,
+    trace_context: Option<&TraceContext>,
+    result_publisher: &P,
+) -> eyre::Result<()> {
+    let mut message_attributes = create_message_type_attribute_map(message_type);
+    let trace_attributes = construct_message_attributes(trace_context)?;
+    message_attributes.extend(trace_attributes);
+    result_publisher
+        .publish(message, message_attributes)
+        .await?;
+    metrics::counter!("result.sent", "type" => message_type.to_owned()+"_error").increment(1);
+    Ok(())
+}
+
+/// Publishes the error result of a batch request that could not be compared.
+pub async fn publish_request_error<P: ResultPublisher + ?Sized>(
+    request: &BatchRequest,
+    trace_context: Option<&TraceContext>,
+    result_publisher: &P,
+    config: &Config,
+    error_code: ErrorCode,
+    error_message: Option<String>,
+) -> eyre::Result<()> {
+    let message = match request {
+        BatchRequest::Uniqueness(request) => {
+            uniqueness_error(config, request.signup_id.clone(), error_code, error_message)?
+        }
+        BatchRequest::ReAuth(request) => serde_json::to_string(&ReAuthResult::error(
+            config.party_id,
+            request.reauth_id.clone(),
+            request.target_serial_ids.clone(),
+            error_code,
+        ))?,
+        BatchRequest::ResetCheck(request) => serde_json::to_string(&ResetCheckResult::error(
+            config.party_id,
+            request.reset_id.clone(),
+            error_code,
+        ))?,
+    };
+    publish_error_result(
+        message,
+        request.message_type(),
+        trace_context,
+        result_publisher,
+    )
+    .await
+}
+
+/// Publishes the error result of a request that was dropped because not all
+/// parties received it, see [`SettledBatch::dropped_request_ids`]. Only its id
+/// is known by then, so it is answered as a uniqueness request.
+pub async fn publish_request_not_agreed<P: ResultPublisher + ?Sized>(
+    request_id: String,
+    result_publisher: &P,
+    config: &Config,
+) -> eyre::Result<()> {
+    tracing::error!(
+        "Dropping {}, not all parties received it in {} batches",
+        request_id,
+        MAX_BATCH_DEFERRALS
+    );
+    metrics::counter!("batch.request_not_agreed").increment(1);
+    let message = uniqueness_error(
+        config,
+        request_id,
+        ErrorCode::RequestNotAgreed,
+        Some(format!(
+            "Not all parties received the request in {} batches",
+            MAX_BATCH_DEFERRALS
+        )),
+    )?;
+    publish_error_result(message, UNIQUENESS_MESSAGE_TYPE, None, result_publisher).await
+}
+
+/// Publishes the error result of a deletion batch that was dropped because
+/// not all parties received it, see [`RequestIntake::defer_deletion_batches`].
+pub async fn publish_deletion_batch_not_agreed<P: ResultPublisher + ?Sized>(
+    message_id: &str,
+    serial_ids: Vec<u32>,
+    trace_context: Option<&TraceContext>,
+    result_publisher: &P,
+    config: &Config,
+) -> eyre::Result<()> {
+    tracing::error!(
+        "Dropping deletion batch {}, not all parties received it in {} batches",
+        message_id,
+        MAX_BATCH_DEFERRALS
+    );
+    metrics::counter!("batch.request_not_agreed").increment(1);
+    let result = IdentityDeletionBatchResult::error(
+        config.party_id,
+        serial_ids,
+        ErrorCode::RequestNotAgreed,
+    );
+    publish_error_result(
+        serde_json::to_string(&result)?,
+        IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+        trace_context,
+        result_publisher,
+    )
+    .await
+}
+
+/// The signed error result of a uniqueness request.
+fn uniqueness_error(
+    config: &Config,
+    signup_id: String,
+    error_code: ErrorCode,
+    error_message: Option<String>,
+) -> eyre::Result<String> {
+    let mut message = UniquenessResult::error(config.party_id, signup_id, error_code);
+    message.error_message = error_message;
+    if let Some(key) = config.result_signing_key()? {
+        message.sign(&key);
+    }
+    Ok(serde_json::to_string(&message)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::{
+        queue::{ChannelRequestReceiver, ChannelRequestSender, ChannelResultPublisher},
+        smpc_request::{IDENTITY_DELETION_MESSAGE_TYPE, MAX_IDENTITY_DELETION_BATCH_SIZE},
+    };
+
+    fn config() -> Config {
+        serde_json::from_value(serde_json::json!({ "party_id": 1 })).unwrap()
+    }
+
+    fn intake(config: &Config) -> RequestIntake {
+        RequestIntake::new(config, PoisonMessagePolicy {
+            max_attempts: 3,
+            dead_letter:  None,
+        })
+    }
+
+    fn send(sender: &ChannelRequestSender, message_id: &str, message_type: &str, message: String) {
+        let envelope = SQSMessage {
+            notification_type: "Notification".to_string(),
+            message_id: message_id.to_string(),
+            sequence_number: message_id.to_string(),
+            topic_arn: "local".to_string(),
+            message,
+            timestamp: String::new(),
+            unsubscribe_url: String::new(),
+            message_attributes: create_message_type_attribute_map(message_type),
+        };
+        sender
+            .send(serde_json::to_string(&envelope).unwrap())
+            .unwrap();
+    }
+
+    fn uniqueness(signup_id: &str) -> String {
+        serde_json::to_string(&UniquenessRequest {
+            batch_size:              None,
+            signup_id:               signup_id.to_string(),
+            s3_key:                  format!("{}.json", signup_id),
+            iris_shares:             None,
+            iris_shares_file_hashes: Default::default(),
+            mirrored_check:          None,
+            rotation_window:         None,
+            or_rule_serial_ids:      None,
+        })
+        .unwrap()
+    }
+
+    async fn poll_all(
+        intake: &mut RequestIntake,
+        queues: &[(ChannelRequestReceiver, Option<RequestLane>)],
+        publisher: &ChannelResultPublisher,
+        config: &Config,
+    ) -> ReceivedRequests {
+        let mut received = ReceivedRequests::default();
+        while intake
+            .poll(queues, publisher, config, &[], &mut received)
+            .await
+            .unwrap()
+        {}
+        received
+    }
+
+    #[tokio::test]
+    async fn test_poll_holds_batch_requests() {
+        let config = config();
+        let mut intake = intake(&config);
+        let (sender, receiver) = ChannelRequestReceiver::new();
+        let queues = [(receiver, None)];
+        let publisher = ChannelResultPublisher::new();
+
+        send(&sender, "1", UNIQUENESS_MESSAGE_TYPE, uniqueness("alice"));
+        send(
+            &sender,
+            "2",
+            IDENTITY_DELETION_MESSAGE_TYPE,
+            serde_json::to_string(&IdentityDeletionRequest {
+                serial_id:         7,
+                grace_period_secs: None,
+            })
+            .unwrap(),
+        );
+        send(
+            &sender,
+            "3",
+            IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            serde_json::to_string(&IdentityDeletionBatchRequest {
+                serial_ids: (1..=MAX_IDENTITY_DELETION_BATCH_SIZE as u32 + 1).collect(),
+            })
+            .unwrap(),
+        );
+        // A second delivery of a held message.
+        send(&sender, "1", UNIQUENESS_MESSAGE_TYPE, uniqueness("alice"));
+        send(&sender, "4", UNIQUENESS_MESSAGE_TYPE, "{".to_string());
+
+        let received = poll_all(&mut intake, &queues, &publisher, &config).await;
+        assert_eq!(intake.len(), 1);
+        assert!(intake.holds("1"));
+        assert_eq!(received.deletions.len(), 1);
+        assert_eq!(received.deletions[0].request.serial_id, 7);
+        assert_eq!(received.deletions[0].message_id, "2");
+        assert!(received.deletion_batches.is_empty());
+        // The oversized deletion batch is answered right away, the malformed
+        // request is not, since its id cannot be read.
+        let published = publisher.drain();
+        assert_eq!(published.len(), 1);
+        let result: IdentityDeletionBatchResult =
+            serde_json::from_str(&published[0].message).unwrap();
+        assert_eq!(result.node_id, 1);
+        assert_eq!(result.error, Some(true));
+
+        let receivers = queues
+            .iter()
+            .map(|(receiver, _)| receiver)
+            .collect::<Vec<_>>();
+        let entries = intake.compose(8, &receivers).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].item.request.request_id(), "alice");
+        let committed = vec![entries[0].item.committed()];
+        let settled = intake
+            .settle(&committed, &[], &[], &receivers)
+            .await
+            .unwrap();
+        assert_eq!(settled.completed_request_ids(&committed), vec!["alice"]);
+        intake.complete(&committed, &receivers).await.unwrap();
+        assert!(!intake.holds("1"));
+    }
+
+    #[tokio::test]
+    async fn test_settle_drops_requests_deferred_too_often() {
+        let config = config();
+        let mut intake = intake(&config);
+        let (sender, receiver) = ChannelRequestReceiver::new();
+        let queues = [(receiver, None)];
+        let receivers = queues
+            .iter()
+            .map(|(receiver, _)| receiver)
+            .collect::<Vec<_>>();
+        let publisher = ChannelResultPublisher::new();
+        send(&sender, "1", UNIQUENESS_MESSAGE_TYPE, uniqueness("alice"));
+
+        let deferred = vec!["alice".to_string()];
+        for _ in 0..MAX_BATCH_DEFERRALS {
+            poll_all(&mut intake, &queues, &publisher, &config).await;
+            let entries = intake.compose(8, &receivers).await.unwrap();
+            let committed = entries
+                .iter()
+                .map(|entry| entry.item.committed())
+                .collect::<Vec<_>>();
+            let settled = intake
+                .settle(&committed, &[], &deferred, &receivers)
+                .await
+                .unwrap();
+            assert_eq!(settled.requeued_request_ids, deferred);
+            assert!(settled.completed_request_ids(&committed).is_empty());
+            intake.complete(&committed, &receivers).await.unwrap();
+        }
+
+        // The requeued message is received once more and then dropped.
+        poll_all(&mut intake, &queues, &publisher, &config).await;
+        let entries = intake.compose(8, &receivers).await.unwrap();
+        let committed = vec![entries[0].item.committed()];
+        let settled = intake
+            .settle(&committed, &[], &deferred, &receivers)
+            .await
+            .unwrap();
+        assert!(settled.requeued_request_ids.is_empty());
+        assert_eq!(settled.dropped_request_ids, deferred);
+        intake.complete(&committed, &receivers).await.unwrap();
+        poll_all(&mut intake, &queues, &publisher, &config).await;
+        assert!(intake.is_empty());
+    }
+}
@@ -1,28 +1,40 @@
+#[cfg(feature = "aws")]
 use crate::config::Config;
+use crate::helpers::smpc_request::{
+    IrisCodesJSON, ShareHashMismatch, SharesS3Object, SupportedIrisVersion,
+};
+#[cfg(feature = "aws")]
 use aws_config::Region;
+#[cfg(feature = "aws")]
 use aws_sdk_secretsmanager::{
     error::SdkError, operation::get_secret_value::GetSecretValueError,
     Client as SecretsManagerClient,
 };
 use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
 use sodiumoxide::crypto::{
-    box_::{PublicKey, SecretKey},
+    box_::{self, PublicKey, SecretKey},
     sealedbox,
 };
 use std::string::FromUtf8Error;
 use thiserror::Error;
 use zeroize::Zeroize;
 
+#[cfg(feature = "aws")]
 const REGION: &str = "eu-north-1";
+#[cfg(feature = "aws")]
 const CURRENT_SECRET_LABEL: &str = "AWSCURRENT";
+#[cfg(feature = "aws")]
 const PREVIOUS_SECRET_LABEL: &str = "AWSPREVIOUS";
 
 #[derive(Error, Debug)]
 pub enum SharesDecodingError {
+    #[cfg(feature = "aws")]
     #[error("Secrets Manager error: {0}")]
     SecretsManagerError(#[from] SdkError<GetSecretValueError>),
     #[error("Secret string not found")]
     SecretStringNotFound,
+    #[cfg(feature = "aws")]
     #[error(transparent)]
     RequestError(#[from] reqwest::Error),
     #[error("Decoding error: {0}")]
@@ -41,6 +53,7 @@ pub enum SharesDecodingError {
     PrivateKeyNotFound,
     #[error("Base64 decoding error")]
     Base64DecodeError,
+    #[cfg(feature = "aws")]
     #[error("Received error message from server: [{}] {}", .status, .message)]
     ResponseContent {
         status:  reqwest::StatusCode,
@@ -51,73 +64,158 @@ pub enum SharesDecodingError {
     S3ResponseContent { key: String, message: String },
     #[error(transparent)]
     SerdeError(#[from] serde_json::error::Error),
+    #[cfg(feature = "aws")]
     #[error(transparent)]
     PresigningConfigError(#[from] aws_sdk_s3::presigning::PresigningConfigError),
+    #[cfg(feature = "aws")]
     #[error(transparent)]
     PresignedRequestError(
         #[from] aws_sdk_s3::error::SdkError<aws_sdk_s3::operation::get_object::GetObjectError>,
     ),
     #[error("Upload share file error")]
     UploadS3Error,
+    #[error("Response exceeds the limit of {max_bytes} bytes")]
+    ResponseTooLarge { max_bytes: usize },
+    #[error("Download did not complete within {0:?}")]
+    DownloadTimeout(std::time::Duration),
+    #[error("Downloaded body has SHA-256 {computed}, the store announced {expected}")]
+    BodyChecksumMismatch { expected: String, computed: String },
+    #[error(transparent)]
+    HashMismatch(#[from] ShareHashMismatch),
+    #[error(
+        "Unsupported iris version {got:?}, supported are {}",
+        display_versions(.supported)
+    )]
+    UnsupportedVersion {
+        got:       String,
+        supported: Vec<SupportedIrisVersion>,
+    },
+    #[error("Shares of iris version {version} take {expected} bytes, got {got}")]
+    WrongShareLength {
+        version:  SupportedIrisVersion,
+        expected: usize,
+        got:      usize,
+    },
+    #[error("Share of party {party_id} not found, the file holds {party_count} shares")]
+    PartyShareNotFound {
+        party_id:    usize,
+        party_count: usize,
+    },
+    #[error("Either a presigned URL or an S3 bucket and key must be given, not both")]
+    InvalidShareSource,
+}
+
+fn display_versions(versions: &[SupportedIrisVersion]) -> String {
+    versions
+        .iter()
+        .map(|version| version.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
+/// The current key pair of this party and the ones it replaced, so that
+/// shares sealed before a rotation can still be opened.
 #[derive(Clone, Debug)]
 pub struct SharesEncryptionKeyPairs {
-    pub current_key_pair:  SharesEncryptionKeyPair,
-    pub previous_key_pair: Option<SharesEncryptionKeyPair>,
+    pub current_key_pair:   SharesEncryptionKeyPair,
+    /// Newest first.
+    pub previous_key_pairs: Vec<PreviousKeyPair>,
+    /// The generation of the keys, bumped with every rotation at all parties
+    /// at once. Only the key chain layout carries it.
+    pub generation:         Option<u64>,
+}
+
+/// A key pair replaced by a rotation.
+#[derive(Clone, Debug)]
+pub struct PreviousKeyPair {
+    pub key_pair:    SharesEncryptionKeyPair,
+    /// Unix timestamp in seconds after which the key pair is no longer used.
+    pub valid_until: Option<i64>,
+}
+
+impl PreviousKeyPair {
+    pub fn is_valid_at(&self, now: i64) -> bool {
+        self.valid_until
+            .map_or(true, |valid_until| now <= valid_until)
+    }
+}
+
+/// The layout of a secret that holds the whole chain of keys, as opposed to
+/// the legacy layout of one private key per secret version.
+#[derive(Deserialize)]
+struct KeyChainSecret {
+    current:    String,
+    /// Newest first.
+    #[serde(default)]
+    previous:   Vec<PreviousKeySecret>,
+    #[serde(default)]
+    generation: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PreviousKeySecret {
+    private_key: String,
+    #[serde(default)]
+    valid_until: Option<i64>,
 }
 
 impl Zeroize for SharesEncryptionKeyPairs {
     fn zeroize(&mut self) {
         self.current_key_pair.zeroize();
-        self.previous_key_pair.zeroize();
+        for previous in &mut self.previous_key_pairs {
+            previous.key_pair.zeroize();
+        }
     }
 }
 
 impl Drop for SharesEncryptionKeyPairs {
     fn drop(&mut self) {
-        self.current_key_pair.zeroize();
-        self.current_key_pair.zeroize();
+        self.zeroize();
     }
 }
 
 impl SharesEncryptionKeyPairs {
+    /// Loads the keys from the secrets manager, see
+    /// [`Self::from_secrets_manager`].
+    #[cfg(feature = "aws")]
     pub async fn from_storage(config: Config) -> Result<Self, SharesDecodingError> {
-        let region_provider = Region::new(REGION);
-        let shared_config = aws_config::from_env().region(region_provider).load().await;
-        let client = SecretsManagerClient::new(&shared_config);
-
-        let current_sk_b64_string = match download_private_key_from_asm(
-            &client,
-            &config.environment,
-            &config.party_id.to_string(),
+        let client = secrets_manager_client().await;
+        Self::from_secrets_manager(&client, &config.environment, config.party_id).await
+    }
+
+    /// Loads the keys from the secrets manager. The current version of the
+    /// secret either holds the whole chain of keys, see
+    /// [`Self::from_key_chain_json`], or only the current private key, with
+    /// the previous one in the previous version.
+    #[cfg(feature = "aws")]
+    pub async fn from_secrets_manager(
+        client: &SecretsManagerClient,
+        environment: &str,
+        party_id: usize,
+    ) -> Result<Self, SharesDecodingError> {
+        let current_secret = download_private_key_from_asm(
+            client,
+            environment,
+            &party_id.to_string(),
             CURRENT_SECRET_LABEL,
         )
-        .await
-        {
-            Ok(sk) => sk,
-            Err(e) => return Err(e),
-        };
+        .await?;
+        if is_key_chain(&current_secret) {
+            return Self::from_key_chain_json(&current_secret);
+        }
 
-        let previous_sk_b64_string = match download_private_key_from_asm(
-            &client,
-            &config.environment,
-            &config.party_id.to_string(),
+        let previous_sk_b64_string = download_private_key_from_asm(
+            client,
+            environment,
+            &party_id.to_string(),
             PREVIOUS_SECRET_LABEL,
         )
-        .await
-        {
-            Ok(sk) => sk,
-            Err(e) => return Err(e),
-        };
+        .await?;
 
-        match SharesEncryptionKeyPairs::from_b64_private_key_strings(
-            current_sk_b64_string,
+        SharesEncryptionKeyPairs::from_b64_private_key_strings(
+            current_secret,
             previous_sk_b64_string,
-        ) {
-            Ok(key_pairs) => Ok(key_pairs),
-            Err(e) => Err(e),
-        }
+        )
     }
 
     pub fn from_b64_private_key_strings(
@@ -129,7 +227,8 @@ impl SharesEncryptionKeyPairs {
         if previous_sk_b64_string.is_empty() {
             return Ok(SharesEncryptionKeyPairs {
                 current_key_pair,
-                previous_key_pair: None,
+                previous_key_pairs: vec![],
+                generation: None,
             });
         }
 
@@ -137,9 +236,66 @@ impl SharesEncryptionKeyPairs {
             SharesEncryptionKeyPair::from_b64_private_key_string(previous_sk_b64_string)?;
         Ok(SharesEncryptionKeyPairs {
             current_key_pair,
-            previous_key_pair: Some(previous_key_pair),
+            previous_key_pairs: vec![PreviousKeyPair {
+                key_pair:    previous_key_pair,
+                valid_until: None,
+            }],
+            generation: None,
         })
     }
+
+    /// Parses a secret with the whole chain of keys, like
+    /// `{"current": "<key>", "previous": [{"private_key": "<key>",
+    /// "valid_until": 1735689600}], "generation": 4}` with the previous keys
+    /// newest first and the private keys in base64.
+    pub fn from_key_chain_json(secret: &str) -> Result<Self, SharesDecodingError> {
+        let chain: KeyChainSecret = serde_json::from_str(secret)?;
+        let current_key_pair = SharesEncryptionKeyPair::from_b64_private_key_string(chain.current)?;
+        let previous_key_pairs = chain
+            .previous
+            .into_iter()
+            .map(|previous| {
+                Ok(PreviousKeyPair {
+                    key_pair:    SharesEncryptionKeyPair::from_b64_private_key_string(
+                        previous.private_key,
+                    )?,
+                    valid_until: previous.valid_until,
+                })
+            })
+            .collect::<Result<_, SharesDecodingError>>()?;
+        Ok(SharesEncryptionKeyPairs {
+            current_key_pair,
+            previous_key_pairs,
+            generation: chain.generation,
+        })
+    }
+
+    /// Opens a sealed box with the current key pair, then with the previous
+    /// ones from newest to oldest, skipping the ones no longer valid at `now`.
+    /// Returns the contents and the index of the key pair that opened it: 0
+    /// for the current one and `i + 1` for `previous_key_pairs[i]`.
+    pub fn open_sealed_box(
+        &self,
+        sealed: &[u8],
+        now: i64,
+    ) -> Result<(Vec<u8>, usize), SharesDecodingError> {
+        if let Some(bytes) = self.current_key_pair.open(sealed) {
+            return Ok((bytes, 0));
+        }
+        self.previous_key_pairs
+            .iter()
+            .enumerate()
+            .filter(|(_, previous)| previous.is_valid_at(now))
+            .find_map(|(i, previous)| Some((previous.key_pair.open(sealed)?, i + 1)))
+            .ok_or(SharesDecodingError::SealedBoxOpenError)
+    }
+}
+
+/// Whether a secret holds the whole chain of keys. A base64 key never starts
+/// with a brace.
+#[cfg(feature = "aws")]
+fn is_key_chain(secret: &str) -> bool {
+    secret.trim_start().starts_with('{')
 }
 
 #[derive(Clone, Debug)]
@@ -178,15 +334,77 @@ impl SharesEncryptionKeyPair {
         Ok(Self { pk: pk_from_sk, sk })
     }
 
+    /// Generates a fresh random key pair.
+    pub fn generate() -> Self {
+        let (pk, sk) = box_::gen_keypair();
+        Self { pk, sk }
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.pk
+    }
+
     pub fn open_sealed_box(&self, code: Vec<u8>) -> Result<Vec<u8>, SharesDecodingError> {
-        let decrypted = sealedbox::open(&code, &self.pk, &self.sk);
-        match decrypted {
-            Ok(bytes) => Ok(bytes),
-            Err(_) => Err(SharesDecodingError::SealedBoxOpenError),
+        self.open(&code)
+            .ok_or(SharesDecodingError::SealedBoxOpenError)
+    }
+
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        sealedbox::open(sealed, &self.pk, &self.sk).ok()
+    }
+}
+
+/// Encrypts the share file of a party for its public key, as the uploaders
+/// do. The sealed box holds the canonical JSON of the file, whose hash the
+/// requests carry, and is opened by
+/// [`SharesEncryptionKeyPairs::open_sealed_box`]. Returns it base64 encoded.
+pub fn seal_share(share: &IrisCodesJSON, public_key: &PublicKey) -> String {
+    STANDARD.encode(sealedbox::seal(
+        share.canonical_json().as_bytes(),
+        public_key,
+    ))
+}
+
+/// The share encryption public keys of the three parties, by party index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKeySet([PublicKey; 3]);
+
+impl PublicKeySet {
+    pub fn new(public_keys: [PublicKey; 3]) -> Self {
+        Self(public_keys)
+    }
+
+    /// Parses the base64 encoded keys the parties publish.
+    pub fn from_b64_strings(public_keys: [&str; 3]) -> Result<Self, SharesDecodingError> {
+        let mut parsed = Vec::with_capacity(3);
+        for public_key in public_keys {
+            let bytes = STANDARD.decode(public_key)?;
+            parsed.push(PublicKey::from_slice(&bytes).ok_or(SharesDecodingError::ParsingKeyError)?);
         }
+        let [pk0, pk1, pk2] = parsed.try_into().expect("three keys were parsed");
+        Ok(Self([pk0, pk1, pk2]))
+    }
+
+    pub fn get(&self, party_id: usize) -> Option<&PublicKey> {
+        self.0.get(party_id)
     }
+
+    /// Encrypts the share file of every party with [`seal_share`]. Returns the
+    /// sealed files along with their hashes, see [`SharesS3Object::seal`].
+    pub fn seal(&self, shares: &[IrisCodesJSON; 3]) -> (SharesS3Object, [String; 3]) {
+        let [pk0, pk1, pk2] = &self.0;
+        SharesS3Object::seal(shares, [pk0, pk1, pk2])
+    }
+}
+
+#[cfg(feature = "aws")]
+pub async fn secrets_manager_client() -> SecretsManagerClient {
+    let region_provider = Region::new(REGION);
+    let shared_config = aws_config::from_env().region(region_provider).load().await;
+    SecretsManagerClient::new(&shared_config)
 }
 
+#[cfg(feature = "aws")]
 async fn download_private_key_from_asm(
     client: &SecretsManagerClient,
     env: &str,
@@ -209,6 +427,7 @@ async fn download_private_key_from_asm(
     }
 }
 
+#[cfg(feature = "aws")]
 pub async fn download_public_key(
     base_url: String,
     node_id: String,
@@ -0,0 +1,219 @@
+//! Reloading of the share encryption keys, so that a rotation in the secrets
+//! manager takes effect without a restart. The keys are reloaded on an
+//! interval and, as a fallback, when a share opens with none of them. The
+//! reloaded keys replace the old ones at once, decryptions in progress keep
+//! the keys they started with.
+
+#[cfg(feature = "aws")]
+use super::key_pair::secrets_manager_client;
+use super::key_pair::{SharesDecodingError, SharesEncryptionKeyPairs};
+#[cfg(feature = "aws")]
+use crate::config::Config;
+use async_trait::async_trait;
+#[cfg(feature = "aws")]
+use aws_sdk_secretsmanager::Client as SecretsManagerClient;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::Instant};
+
+/// When the keys are reloaded. The intervals are in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyRefreshConfig {
+    /// The time between two reloads, 0 disables them.
+    pub interval_secs:         u64,
+    /// Whether a share that opens with none of the keys reloads them.
+    pub on_decryption_failure: bool,
+    /// The least time between a reload and a reload after a failure, so that
+    /// shares sealed to unknown keys do not flood the secrets manager.
+    pub min_interval_secs:     u64,
+}
+
+impl Default for KeyRefreshConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs:         300,
+            on_decryption_failure: true,
+            min_interval_secs:     30,
+        }
+    }
+}
+
+impl KeyRefreshConfig {
+    /// Never reloads the keys.
+    pub fn disabled() -> Self {
+        Self {
+            interval_secs: 0,
+            on_decryption_failure: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn interval(&self) -> Option<Duration> {
+        (self.interval_secs > 0).then(|| Duration::from_secs(self.interval_secs))
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_secs(self.min_interval_secs)
+    }
+}
+
+/// Where the keys are loaded from.
+#[async_trait]
+pub trait KeyPairSource: Send + Sync {
+    async fn load(&self) -> Result<SharesEncryptionKeyPairs, SharesDecodingError>;
+}
+
+/// Keys that never change.
+#[async_trait]
+impl KeyPairSource for SharesEncryptionKeyPairs {
+    async fn load(&self) -> Result<SharesEncryptionKeyPairs, SharesDecodingError> {
+        Ok(self.clone())
+    }
+}
+
+/// The keys of this party in the secrets manager, see
+/// [`SharesEncryptionKeyPairs::from_secrets_manager`].
+#[cfg(feature = "aws")]
+pub struct SecretsManagerKeySource {
+    client:      SecretsManagerClient,
+    environment: String,
+    party_id:    usize,
+}
+
+#[cfg(feature = "aws")]
+impl SecretsManagerKeySource {
+    pub async fn new(config: &Config) -> Self {
+        Self {
+            client:      secrets_manager_client().await,
+            environment: config.environment.clone(),
+            party_id:    config.party_id,
+        }
+    }
+}
+
+#[cfg(feature = "aws")]
+#[async_trait]
+impl KeyPairSource for SecretsManagerKeySource {
+    async fn load(&self) -> Result<SharesEncryptionKeyPairs, SharesDecodingError> {
+        SharesEncryptionKeyPairs::from_secrets_manager(
+            &self.client,
+            &self.environment,
+            self.party_id,
+        )
+        .await
+    }
+}
+
+/// The keys of this party, reloaded from a [`KeyPairSource`].
+pub struct KeyPairProvider {
+    source:       Box<dyn KeyPairSource>,
+    config:       KeyRefreshConfig,
+    key_pairs:    RwLock<Arc<SharesEncryptionKeyPairs>>,
+    /// Held during a reload, so that only one runs at a time.
+    last_refresh: Mutex<Instant>,
+}
+
+impl KeyPairProvider {
+    /// Loads the keys from `source`.
+    pub async fn new(
+        source: impl KeyPairSource + 'static,
+        config: KeyRefreshConfig,
+    ) -> Result<Self, SharesDecodingError> {
+        let key_pairs = source.load().await?;
+        Ok(Self {
+            source: Box::new(source),
+            config,
+            key_pairs: RwLock::new(Arc::new(key_pairs)),
+            last_refresh: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Keys that are never reloaded.
+    pub fn fixed(key_pairs: SharesEncryptionKeyPairs) -> Self {
+        Self {
+            source:       Box::new(key_pairs.clone()),
+            config:       KeyRefreshConfig::disabled(),
+            key_pairs:    RwLock::new(Arc::new(key_pairs)),
+            last_refresh: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// The keys as of the last reload.
+    pub fn key_pairs(&self) -> Arc<SharesEncryptionKeyPairs> {
+        Arc::clone(&self.key_pairs.read().unwrap())
+    }
+
+    /// Reloads the keys from the source. If that fails, the old keys stay in
+    /// use.
+    pub async fn refresh(&self) -> Result<(), SharesDecodingError> {
+        let mut last_refresh = self.last_refresh.lock().await;
+        self.reload(&mut last_refresh).await
+    }
+
+    async fn reload(&self, last_refresh: &mut Instant) -> Result<(), SharesDecodingError> {
+        let key_pairs = self.source.load().await?;
+        *self.key_pairs.write().unwrap() = Arc::new(key_pairs);
+        *last_refresh = Instant::now();
+        metrics::counter!("share_keys.refreshed").increment(1);
+        Ok(())
+    }
+
+    /// Reloads the keys after `failed` opened no share, unless they were
+    /// reloaded less than `min_interval_secs` ago. Returns whether the keys
+    /// changed since `failed`.
+    async fn refresh_after_failure(&self, failed: &Arc<SharesEncryptionKeyPairs>) -> bool {
+        if !self.config.on_decryption_failure {
+            return false;
+        }
+        let mut last_refresh = self.last_refresh.lock().await;
+        // Another decryption reloaded the keys while this one waited.
+        if !Arc::ptr_eq(failed, &self.key_pairs()) {
+            return true;
+        }
+        if last_refresh.elapsed() < self.config.min_interval() {
+            return false;
+        }
+        match self.reload(&mut last_refresh).await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Failed to refresh the share encryption keys: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Opens a sealed box like [`SharesEncryptionKeyPairs::open_sealed_box`].
+    /// If none of the keys opens it, they are reloaded and tried once more,
+    /// in case the box was sealed to a key that was rotated in since.
+    pub async fn open_sealed_box(
+        &self,
+        sealed: &[u8],
+        now: i64,
+    ) -> Result<(Vec<u8>, usize), SharesDecodingError> {
+        let key_pairs = self.key_pairs();
+        let result = key_pairs.open_sealed_box(sealed, now);
+        if matches!(result, Err(SharesDecodingError::SealedBoxOpenError))
+            && self.refresh_after_failure(&key_pairs).await
+        {
+            return self.key_pairs().open_sealed_box(sealed, now);
+        }
+        result
+    }
+
+    /// Reloads the keys every `interval`, forever.
+    pub async fn refresh_every(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes at once, the keys were just loaded.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.refresh().await {
+                tracing::warn!("Failed to refresh the share encryption keys: {}", e);
+            }
+        }
+    }
+}
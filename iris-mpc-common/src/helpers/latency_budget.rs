@@ -0,0 +1,484 @@
+//! Latency budget of a batch, from composing the batch to publishing its
+//! results.
+//!
+//! Every phase has a soft deadline. Exceeding it emits a warning with the
+//! timings of all phases and the `latency_budget.soft_breach` metric, but the
+//! batch goes on. The total budget is hard: before the GPU work starts, the
+//! time spent so far plus the soft deadlines of the remaining phases must fit
+//! into it, see [`BatchDeadline::check_before_gpu`]. A batch that cannot make
+//! it is shed, its requests go back into their queues instead of holding up
+//! the GPU for results nobody waits for anymore.
+//!
+//! Shedding has to happen at all parties or none. A party only flags the
+//! entries it wants to shed, the batch sync then drops an entry flagged by any
+//! party and every party requeues it.
+
+use super::spans::Phase;
+#[cfg(feature = "aws")]
+use super::{queue::RequestReceiver, smpc_request::ReceiveRequestError};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+use tokio::time::Instant;
+
+/// The phases with a soft deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BudgetPhase {
+    Fetch,
+    Validate,
+    /// Both GPU phases, the dot products and the threshold comparison.
+    Gpu,
+    Open,
+    Publish,
+}
+
+impl BudgetPhase {
+    pub const ALL: [BudgetPhase; 5] = [
+        BudgetPhase::Fetch,
+        BudgetPhase::Validate,
+        BudgetPhase::Gpu,
+        BudgetPhase::Open,
+        BudgetPhase::Publish,
+    ];
+
+    /// The phase whose deadline covers a tracing phase, if any.
+    pub fn of(phase: Phase) -> Option<Self> {
+        match phase {
+            Phase::Fetch => Some(BudgetPhase::Fetch),
+            Phase::Validate => Some(BudgetPhase::Validate),
+            Phase::GpuDot | Phase::GpuThreshold => Some(BudgetPhase::Gpu),
+            Phase::Open => Some(BudgetPhase::Open),
+            Phase::Persist => None,
+            Phase::Publish => Some(BudgetPhase::Publish),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetPhase::Fetch => "fetch",
+            BudgetPhase::Validate => "validate",
+            BudgetPhase::Gpu => "gpu",
+            BudgetPhase::Open => "open",
+            BudgetPhase::Publish => "publish",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for BudgetPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The latency budget of a batch in milliseconds. A value of 0 disables the
+/// respective deadline, the default disables all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LatencyBudget {
+    /// Hard budget of the whole batch.
+    #[serde(default)]
+    pub total_ms:    u64,
+    #[serde(default)]
+    pub fetch_ms:    u64,
+    #[serde(default)]
+    pub validate_ms: u64,
+    #[serde(default)]
+    pub gpu_ms:      u64,
+    #[serde(default)]
+    pub open_ms:     u64,
+    #[serde(default)]
+    pub publish_ms:  u64,
+}
+
+impl LatencyBudget {
+    pub fn soft_deadline(&self, phase: BudgetPhase) -> Option<Duration> {
+        let ms = match phase {
+            BudgetPhase::Fetch => self.fetch_ms,
+            BudgetPhase::Validate => self.validate_ms,
+            BudgetPhase::Gpu => self.gpu_ms,
+            BudgetPhase::Open => self.open_ms,
+            BudgetPhase::Publish => self.publish_ms,
+        };
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        (self.total_ms > 0).then(|| Duration::from_millis(self.total_ms))
+    }
+
+    /// The time the phases after the validation are expected to take at most.
+    fn reserved_from_gpu(&self) -> Duration {
+        Duration::from_millis(self.gpu_ms + self.open_ms + self.publish_ms)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let phases =
+            self.fetch_ms + self.validate_ms + self.gpu_ms + self.open_ms + self.publish_ms;
+        if self.total_ms > 0 && phases > self.total_ms {
+            return Err(format!(
+                "the phase deadlines of the latency budget add up to {} ms, more than its total \
+                 of {} ms",
+                phases, self.total_ms
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A phase that took longer than its soft deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftBreach {
+    pub phase:    BudgetPhase,
+    pub took:     Duration,
+    pub deadline: Duration,
+}
+
+/// Whether a batch may start its GPU work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetDecision {
+    Proceed,
+    /// The batch would end at `projected`, past the total budget.
+    Shed {
+        elapsed:   Duration,
+        projected: Duration,
+    },
+}
+
+/// Tracks a batch against its latency budget. It travels with the batch
+/// through the pipeline and collects the timings of the phases.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BatchDeadline {
+    budget:  LatencyBudget,
+    started: Instant,
+    timings: [Duration; BudgetPhase::ALL.len()],
+}
+
+impl BatchDeadline {
+    pub fn start(budget: LatencyBudget) -> Self {
+        Self {
+            budget,
+            started: Instant::now(),
+            timings: Default::default(),
+        }
+    }
+
+    pub fn budget(&self) -> &LatencyBudget {
+        &self.budget
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Records how long a phase took. The requests of a batch run through the
+    /// phases concurrently, so the longest of the measurements is kept.
+    pub fn record(&mut self, phase: BudgetPhase, took: Duration) {
+        let timing = &mut self.timings[phase.index()];
+        *timing = (*timing).max(took);
+    }
+
+    pub fn timing(&self, phase: BudgetPhase) -> Duration {
+        self.timings[phase.index()]
+    }
+
+    pub fn soft_breach(&self, phase: BudgetPhase) -> Option<SoftBreach> {
+        let deadline = self.budget.soft_deadline(phase)?;
+        let took = self.timing(phase);
+        (took > deadline).then_some(SoftBreach {
+            phase,
+            took,
+            deadline,
+        })
+    }
+
+    /// Emits a warning with all phase timings and a metric if the phase
+    /// exceeded its soft deadline.
+    pub fn report_soft_breach(&self, phase: BudgetPhase) -> Option<SoftBreach> {
+        let breach = self.soft_breach(phase)?;
+        let ms = |phase: BudgetPhase| self.timing(phase).as_millis() as u64;
+        tracing::warn!(
+            phase = breach.phase.as_str(),
+            took_ms = breach.took.as_millis() as u64,
+            deadline_ms = breach.deadline.as_millis() as u64,
+            elapsed_ms = self.elapsed().as_millis() as u64,
+            fetch_ms = ms(BudgetPhase::Fetch),
+            validate_ms = ms(BudgetPhase::Validate),
+            gpu_ms = ms(BudgetPhase::Gpu),
+            open_ms = ms(BudgetPhase::Open),
+            publish_ms = ms(BudgetPhase::Publish),
+            "Batch exceeded the soft deadline of the {} phase",
+            breach.phase
+        );
+        metrics::counter!("latency_budget.soft_breach", "phase" => breach.phase.as_str())
+            .increment(1);
+        Some(breach)
+    }
+
+    /// Decides, after the validation, whether the batch can still finish within
+    /// the total budget if the remaining phases take as long as their soft
+    /// deadlines.
+    pub fn check_before_gpu(&self) -> BudgetDecision {
+        let Some(total) = self.budget.total() else {
+            return BudgetDecision::Proceed;
+        };
+        let elapsed = self.elapsed();
+        let projected = elapsed + self.budget.reserved_from_gpu();
+        if projected > total {
+            BudgetDecision::Shed { elapsed, projected }
+        } else {
+            BudgetDecision::Proceed
+        }
+    }
+}
+
+/// Emits the degraded-mode metrics of a batch: whether it was shed, and how
+/// many of its requests were requeued.
+pub fn report_shedding(shed: bool, requeued: usize) {
+    metrics::gauge!("latency_budget.degraded_mode").set(if shed { 1.0 } else { 0.0 });
+    if shed {
+        metrics::counter!("latency_budget.shed_batches").increment(1);
+    }
+    if requeued > 0 {
+        tracing::warn!(
+            "Requeued {} requests of a batch over its latency budget",
+            requeued
+        );
+        metrics::counter!("latency_budget.requeued_requests").increment(requeued as u64);
+    }
+}
+
+/// A request message that was committed to a batch. It is deleted from its
+/// queue once the batch is through, and put back first if it was shed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommittedMessage {
+    pub request_id: String,
+    /// The SQS message id, see
+    /// [`InFlightMessages`](crate::helpers::visibility::InFlightMessages).
+    pub message_id: String,
+    /// Index of the queue the message came from.
+    pub queue:      usize,
+    pub body:       String,
+}
+
+/// Puts the messages of the given requests back into their queues. Returns the
+/// number of requeued messages.
+#[cfg(feature = "aws")]
+pub async fn requeue_requests<R: RequestReceiver + ?Sized>(
+    receivers: &[&R],
+    committed: &[CommittedMessage],
+    request_ids: &[String],
+) -> Result<usize, ReceiveRequestError> {
+    let mut requeued = 0;
+    for message in committed {
+        if request_ids.contains(&message.request_id) {
+            receivers[message.queue].requeue(&message.body).await?;
+            requeued += 1;
+        }
+    }
+    Ok(requeued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "aws")]
+    use crate::helpers::queue::ChannelRequestReceiver;
+    use metrics::{
+        Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{atomic::AtomicU64, Arc, Mutex},
+    };
+
+    /// Collects the counters and gauges, keyed by name and labels.
+    #[derive(Default)]
+    struct TestRecorder {
+        values: Mutex<HashMap<String, Arc<AtomicU64>>>,
+    }
+
+    impl TestRecorder {
+        fn handle(&self, key: &Key) -> Arc<AtomicU64> {
+            let name = key.labels().fold(key.name().to_string(), |name, label| {
+                format!("{}[{}={}]", name, label.key(), label.value())
+            });
+            self.values.lock().unwrap().entry(name).or_default().clone()
+        }
+
+        fn counter(&self, name: &str) -> u64 {
+            self.values
+                .lock()
+                .unwrap()
+                .get(name)
+                .map_or(0, |value| value.load(std::sync::atomic::Ordering::Relaxed))
+        }
+
+        fn gauge(&self, name: &str) -> f64 {
+            f64::from_bits(self.counter(name))
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.handle(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.handle(key))
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    fn budget() -> LatencyBudget {
+        LatencyBudget {
+            total_ms:    1000,
+            fetch_ms:    200,
+            validate_ms: 100,
+            gpu_ms:      300,
+            open_ms:     100,
+            publish_ms:  100,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(LatencyBudget::default().validate().is_ok());
+        assert!(budget().validate().is_ok());
+        let too_tight = LatencyBudget {
+            total_ms: 500,
+            ..budget()
+        };
+        assert!(too_tight.validate().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_soft_breaches() {
+        let mut deadline = BatchDeadline::start(budget());
+        deadline.record(BudgetPhase::Fetch, Duration::from_millis(150));
+        deadline.record(BudgetPhase::Fetch, Duration::from_millis(250));
+        deadline.record(BudgetPhase::Fetch, Duration::from_millis(50));
+        deadline.record(BudgetPhase::Gpu, Duration::from_millis(300));
+        assert_eq!(
+            deadline.timing(BudgetPhase::Fetch),
+            Duration::from_millis(250)
+        );
+
+        let recorder = TestRecorder::default();
+        let breaches = metrics::with_local_recorder(&recorder, || {
+            BudgetPhase::ALL
+                .iter()
+                .filter_map(|&phase| deadline.report_soft_breach(phase))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(breaches, vec![SoftBreach {
+            phase:    BudgetPhase::Fetch,
+            took:     Duration::from_millis(250),
+            deadline: Duration::from_millis(200),
+        }]);
+        assert_eq!(
+            recorder.counter("latency_budget.soft_breach[phase=fetch]"),
+            1
+        );
+        assert_eq!(recorder.counter("latency_budget.soft_breach[phase=gpu]"), 0);
+
+        // Without a budget, nothing is ever breached.
+        let mut deadline = BatchDeadline::start(LatencyBudget::default());
+        deadline.record(BudgetPhase::Open, Duration::from_secs(60));
+        assert_eq!(deadline.soft_breach(BudgetPhase::Open), None);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(deadline.check_before_gpu(), BudgetDecision::Proceed);
+    }
+
+    /// A fetch that takes so long that the remaining phases no longer fit into
+    /// the budget sheds the batch, and its requests are requeued.
+    #[cfg(feature = "aws")]
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_fetch_requeues_batch() {
+        let (sender, receiver) = ChannelRequestReceiver::new();
+        let committed = ["a", "b"]
+            .iter()
+            .map(|id| CommittedMessage {
+                request_id: id.to_string(),
+                message_id: format!("message-{}", id),
+                queue:      0,
+                body:       format!("body-{}", id),
+            })
+            .collect::<Vec<_>>();
+        for message in &committed {
+            sender.send(message.body.clone()).unwrap();
+        }
+        // The batch takes the messages off the queue.
+        assert_eq!(receiver.receive(10).await.unwrap().len(), 2);
+
+        let mut deadline = BatchDeadline::start(budget());
+        let slow_fetch = async {
+            let started = Instant::now();
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            started.elapsed()
+        };
+        let took = slow_fetch.await;
+        deadline.record(BudgetPhase::Fetch, took);
+        deadline.record(BudgetPhase::Validate, Duration::from_millis(10));
+
+        let decision = deadline.check_before_gpu();
+        assert_eq!(decision, BudgetDecision::Shed {
+            elapsed:   Duration::from_millis(600),
+            projected: Duration::from_millis(1100),
+        });
+
+        // All parties requeue the requests the batch sync dropped, "c" was
+        // never in this batch.
+        let shed = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let requeued = requeue_requests(&[&receiver], &committed, &shed)
+            .await
+            .unwrap();
+        assert_eq!(requeued, 2);
+        let bodies = receiver
+            .receive(10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.body)
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, vec!["body-a", "body-b"]);
+
+        let recorder = TestRecorder::default();
+        metrics::with_local_recorder(&recorder, || {
+            deadline.report_soft_breach(BudgetPhase::Fetch);
+            report_shedding(true, requeued);
+        });
+        assert_eq!(
+            recorder.counter("latency_budget.soft_breach[phase=fetch]"),
+            1
+        );
+        assert_eq!(recorder.counter("latency_budget.shed_batches"), 1);
+        assert_eq!(recorder.counter("latency_budget.requeued_requests"), 2);
+        assert_eq!(recorder.gauge("latency_budget.degraded_mode"), 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fast_batch_proceeds() {
+        let mut deadline = BatchDeadline::start(budget());
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        deadline.record(BudgetPhase::Fetch, Duration::from_millis(400));
+        // Over the soft deadline of the fetch, but 400 + 500 ms still fit.
+        assert!(deadline.soft_breach(BudgetPhase::Fetch).is_some());
+        assert_eq!(deadline.check_before_gpu(), BudgetDecision::Proceed);
+
+        let recorder = TestRecorder::default();
+        metrics::with_local_recorder(&recorder, || report_shedding(false, 0));
+        assert_eq!(recorder.gauge("latency_budget.degraded_mode"), 0.0);
+        assert_eq!(recorder.counter("latency_budget.shed_batches"), 0);
+    }
+}
@@ -0,0 +1,246 @@
+//! Adapts the batch size to the free device memory.
+//!
+//! Close to the device capacity, the transient allocations of a batch start
+//! failing. Before a batch is formed, [`MemoryMonitor::sample`] compares the
+//! free memory of the devices with the [`BatchCostModel`] of a batch. Below
+//! the headroom, the batch size is shrunk to what still leaves the headroom
+//! free, and the `memory.batch_shrunk` metric is emitted. Every party puts its
+//! limit into the batch sync, and the parties take the smallest one, see
+//! [`compose_batch`](crate::helpers::sync::compose_batch), so they shrink
+//! identically. Below the critical threshold, the party takes no requests at
+//! all until memory is freed.
+
+/// Reports the free memory of the devices, see [`MemoryMonitor`].
+pub trait DeviceMemoryInfo: Send {
+    /// The free and the total memory of every device, in bytes.
+    fn mem_info(&self) -> eyre::Result<Vec<(usize, usize)>>;
+}
+
+/// Estimated memory a batch takes on every device while it is processed, on
+/// top of the buffers allocated at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchCostModel {
+    /// Memory a batch takes regardless of its size.
+    pub fixed_bytes:     usize,
+    pub bytes_per_entry: usize,
+}
+
+impl BatchCostModel {
+    pub fn cost(&self, batch_size: usize) -> usize {
+        self.fixed_bytes + batch_size * self.bytes_per_entry
+    }
+
+    /// The largest batch size whose cost fits into `budget`.
+    pub fn max_batch_size(&self, budget: usize) -> usize {
+        budget.checked_sub(self.fixed_bytes).map_or(0, |rest| {
+            rest.checked_div(self.bytes_per_entry).unwrap_or(usize::MAX)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPressure {
+    /// Batches of the maximum size fit.
+    Normal,
+    /// Batches are limited to this size to keep the headroom free.
+    Shrunk(usize),
+    /// Less memory than the critical threshold is free, intake is paused.
+    Paused,
+}
+
+impl MemoryPressure {
+    /// The batch size to form batches with, 0 while paused.
+    pub fn batch_size(&self, max_batch_size: usize) -> usize {
+        match self {
+            MemoryPressure::Normal => max_batch_size,
+            MemoryPressure::Shrunk(batch_size) => *batch_size,
+            MemoryPressure::Paused => 0,
+        }
+    }
+}
+
+pub struct MemoryMonitor {
+    devices:        Box<dyn DeviceMemoryInfo>,
+    cost:           BatchCostModel,
+    headroom_bytes: usize,
+    critical_bytes: usize,
+    max_batch_size: usize,
+    pressure:       MemoryPressure,
+}
+
+impl MemoryMonitor {
+    pub fn new(
+        devices: Box<dyn DeviceMemoryInfo>,
+        cost: BatchCostModel,
+        headroom_bytes: usize,
+        critical_bytes: usize,
+        max_batch_size: usize,
+    ) -> Self {
+        Self {
+            devices,
+            cost,
+            headroom_bytes,
+            critical_bytes,
+            max_batch_size,
+            pressure: MemoryPressure::Normal,
+        }
+    }
+
+    /// The pressure of the last sample.
+    pub fn pressure(&self) -> MemoryPressure {
+        self.pressure
+    }
+
+    /// Samples the device with the least free memory and returns the new
+    /// pressure. Intake pauses below the critical threshold and resumes once
+    /// the free memory is above it again. A shrunk batch holds at least one
+    /// entry.
+    pub fn sample(&mut self) -> eyre::Result<MemoryPressure> {
+        let free = self
+            .devices
+            .mem_info()?
+            .iter()
+            .map(|&(free, _)| free)
+            .min()
+            .unwrap_or(usize::MAX);
+        let pressure = if free < self.critical_bytes {
+            MemoryPressure::Paused
+        } else {
+            let fitting = self
+                .cost
+                .max_batch_size(free.saturating_sub(self.headroom_bytes));
+            if fitting >= self.max_batch_size {
+                MemoryPressure::Normal
+            } else {
+                MemoryPressure::Shrunk(fitting.max(1))
+            }
+        };
+
+        metrics::gauge!("memory.free_bytes").set(free as f64);
+        metrics::gauge!("memory.batch_size_limit")
+            .set(pressure.batch_size(self.max_batch_size) as f64);
+        match (self.pressure, pressure) {
+            (MemoryPressure::Paused, MemoryPressure::Paused) => {}
+            (_, MemoryPressure::Paused) => {
+                tracing::error!(
+                    "Pausing intake, only {} bytes of device memory are free",
+                    free
+                );
+                metrics::counter!("memory.intake_paused").increment(1);
+            }
+            (MemoryPressure::Paused, _) => {
+                tracing::info!("Resuming intake, {} bytes of device memory are free", free);
+            }
+            _ => {}
+        }
+        if let MemoryPressure::Shrunk(batch_size) = pressure {
+            tracing::warn!(
+                "Shrinking the batch size to {}, only {} bytes of device memory are free",
+                batch_size,
+                free
+            );
+            metrics::counter!("memory.batch_shrunk").increment(1);
+        }
+        self.pressure = pressure;
+        Ok(pressure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const MB: usize = 1 << 20;
+
+    /// Devices whose free memory the test sets.
+    #[derive(Clone, Default)]
+    struct MockDevices(Arc<Mutex<Vec<usize>>>);
+
+    impl MockDevices {
+        fn set_free(&self, free: &[usize]) {
+            *self.0.lock().unwrap() = free.to_vec();
+        }
+    }
+
+    impl DeviceMemoryInfo for MockDevices {
+        fn mem_info(&self) -> eyre::Result<Vec<(usize, usize)>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|&free| (free, 1024 * MB))
+                .collect())
+        }
+    }
+
+    fn monitor(devices: &MockDevices) -> MemoryMonitor {
+        let cost = BatchCostModel {
+            fixed_bytes:     8 * MB,
+            bytes_per_entry: MB,
+        };
+        MemoryMonitor::new(Box::new(devices.clone()), cost, 100 * MB, 50 * MB, 64)
+    }
+
+    #[test]
+    fn test_batch_cost_model() {
+        let cost = BatchCostModel {
+            fixed_bytes:     10,
+            bytes_per_entry: 4,
+        };
+        assert_eq!(cost.cost(3), 22);
+        assert_eq!(cost.max_batch_size(22), 3);
+        assert_eq!(cost.max_batch_size(25), 3);
+        assert_eq!(cost.max_batch_size(9), 0);
+        let free = BatchCostModel {
+            fixed_bytes:     0,
+            bytes_per_entry: 0,
+        };
+        assert_eq!(free.max_batch_size(0), usize::MAX);
+    }
+
+    #[test]
+    fn test_shrinks_with_headroom() {
+        let devices = MockDevices::default();
+        let mut monitor = monitor(&devices);
+
+        // A full batch leaves the headroom free.
+        devices.set_free(&[500 * MB, 400 * MB]);
+        assert_eq!(monitor.sample().unwrap(), MemoryPressure::Normal);
+        devices.set_free(&[172 * MB]);
+        assert_eq!(monitor.sample().unwrap(), MemoryPressure::Normal);
+
+        // The device with the least free memory decides.
+        devices.set_free(&[500 * MB, 140 * MB]);
+        assert_eq!(monitor.sample().unwrap(), MemoryPressure::Shrunk(32));
+        assert_eq!(monitor.pressure().batch_size(64), 32);
+
+        // Above the critical threshold, a batch holds at least one entry.
+        devices.set_free(&[60 * MB]);
+        assert_eq!(monitor.sample().unwrap(), MemoryPressure::Shrunk(1));
+
+        devices.set_free(&[300 * MB]);
+        assert_eq!(monitor.sample().unwrap(), MemoryPressure::Normal);
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let devices = MockDevices::default();
+        let mut monitor = monitor(&devices);
+        let mut transitions = vec![];
+        for free in [200, 120, 49, 10, 50, 180] {
+            devices.set_free(&[free * MB, 1024 * MB]);
+            transitions.push(monitor.sample().unwrap());
+        }
+        assert_eq!(transitions, vec![
+            MemoryPressure::Normal,
+            MemoryPressure::Shrunk(12),
+            MemoryPressure::Paused,
+            MemoryPressure::Paused,
+            MemoryPressure::Shrunk(1),
+            MemoryPressure::Normal,
+        ]);
+        assert_eq!(MemoryPressure::Paused.batch_size(64), 0);
+    }
+}
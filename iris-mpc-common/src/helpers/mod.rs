@@ -1,11 +1,42 @@
+pub mod audit;
+#[cfg(feature = "aws")]
 pub mod aws;
 pub mod aws_sigv4;
+pub mod backfill;
+pub mod canary;
+pub mod conformance;
+#[cfg(feature = "parquet_export")]
+pub mod decision_export;
+#[cfg(feature = "aws")]
+pub mod intake;
 pub mod key_pair;
+pub mod key_provider;
+#[cfg(feature = "aws")]
 pub mod kms_dh;
+pub mod latency_budget;
+pub mod memory_pressure;
+pub mod priority_lanes;
+pub mod reference;
+#[cfg(feature = "aws")]
+pub mod queue;
+pub mod replay;
+pub mod result_signature;
+#[cfg(feature = "aws")]
+pub mod results_consumer;
 pub mod sha256;
+pub mod share_audit;
+pub mod share_download;
 pub mod shutdown_handler;
 pub mod smpc_request;
 pub mod smpc_response;
+pub mod soft_delete;
+pub mod spans;
+pub mod state_exchange;
+#[cfg(feature = "aws")]
+pub mod sqs;
+#[cfg(feature = "aws")]
 pub mod sqs_s3_helper;
 pub mod sync;
 pub mod task_monitor;
+pub mod threshold;
+pub mod visibility;
@@ -0,0 +1,275 @@
+//! Separate pending pools for interactive and bulk uniqueness requests.
+//!
+//! Bulk backfill traffic and interactive signups share the same pipeline. To
+//! keep interactive latency bounded during backfill bursts, requests are kept
+//! in two FIFO pools and every batch reserves a minimum share of its slots for
+//! interactive requests whenever any of them are waiting.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Name of the message attribute carrying the request priority.
+pub const PRIORITY_MESSAGE_ATTRIBUTE: &str = "priority";
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum RequestLane {
+    /// Latency sensitive requests, e.g. user signups.
+    Interactive = 1,
+    /// Everything else, including backfills. This is the default lane.
+    #[default]
+    Bulk        = 2,
+}
+
+impl RequestLane {
+    pub const ALL: [RequestLane; 2] = [RequestLane::Interactive, RequestLane::Bulk];
+
+    /// Maps the value of the `priority` message attribute to a lane. Missing or
+    /// unknown values are treated as normal priority and go to the bulk lane.
+    pub fn from_priority_attribute(priority: Option<&str>) -> Self {
+        match priority {
+            Some("interactive") | Some("high") => RequestLane::Interactive,
+            _ => RequestLane::Bulk,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestLane::Interactive => "interactive",
+            RequestLane::Bulk => "bulk",
+        }
+    }
+}
+
+impl fmt::Display for RequestLane {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct PendingEntry<T> {
+    item:        T,
+    enqueued_at: Instant,
+}
+
+/// An entry taken out of the pools to be included in a batch.
+#[derive(Debug)]
+pub struct ComposedEntry<T> {
+    pub item:       T,
+    pub lane:       RequestLane,
+    /// Time spent waiting in the pending pool.
+    pub queued_for: Duration,
+}
+
+#[derive(Debug)]
+pub struct PriorityLanes<T> {
+    interactive:           VecDeque<PendingEntry<T>>,
+    bulk:                  VecDeque<PendingEntry<T>>,
+    interactive_min_share: f64,
+}
+
+impl<T> PriorityLanes<T> {
+    /// The share is expected within [0, 1], which the config loader checks,
+    /// see [`Config::violations`](crate::config::Config::violations).
+    pub fn new(interactive_min_share: f64) -> Self {
+        Self {
+            interactive: VecDeque::new(),
+            bulk: VecDeque::new(),
+            interactive_min_share,
+        }
+    }
+
+    pub fn push(&mut self, lane: RequestLane, item: T) {
+        self.push_at(lane, item, Instant::now());
+    }
+
+    pub fn push_at(&mut self, lane: RequestLane, item: T, enqueued_at: Instant) {
+        let entry = PendingEntry { item, enqueued_at };
+        match lane {
+            RequestLane::Interactive => self.interactive.push_back(entry),
+            RequestLane::Bulk => self.bulk.push_back(entry),
+        }
+    }
+
+    /// Number of requests waiting in the given lane.
+    pub fn depth(&self, lane: RequestLane) -> usize {
+        match lane {
+            RequestLane::Interactive => self.interactive.len(),
+            RequestLane::Bulk => self.bulk.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.interactive.len() + self.bulk.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots reserved for interactive requests in a batch of the
+    /// given size. At least one slot is reserved if the share is non-zero.
+    pub fn reserved_interactive_slots(&self, batch_size: usize) -> usize {
+        if self.interactive_min_share == 0.0 {
+            return 0;
+        }
+        ((batch_size as f64 * self.interactive_min_share).ceil() as usize).clamp(1, batch_size)
+    }
+
+    /// Returns the number of entries to take from each lane, as
+    /// `(interactive, bulk)`, for a batch of at most `batch_size` entries.
+    ///
+    /// Interactive requests get their reserved share first, bulk requests fill
+    /// the remaining slots and any slots left over go back to interactive.
+    /// The plan is a pure function of the pool depths, so parties with the
+    /// same pool contents compose the same batch.
+    pub fn plan(&self, batch_size: usize) -> (usize, usize) {
+        let interactive_pending = self.interactive.len();
+        let bulk_pending = self.bulk.len();
+
+        let mut n_interactive =
+            interactive_pending.min(self.reserved_interactive_slots(batch_size));
+        let n_bulk = bulk_pending.min(batch_size - n_interactive);
+        n_interactive +=
+            (interactive_pending - n_interactive).min(batch_size - n_interactive - n_bulk);
+
+        (n_interactive, n_bulk)
+    }
+
+    /// Drops the waiting entries for which `keep` returns false.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.interactive.retain(|e| keep(&e.item));
+        self.bulk.retain(|e| keep(&e.item));
+    }
+
+    /// The waiting entries, interactive ones first, each lane in FIFO order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.interactive
+            .iter()
+            .chain(self.bulk.iter())
+            .map(|e| &e.item)
+    }
+
+    /// Takes the entries for the next batch out of the pools. Interactive
+    /// entries come first, each lane in FIFO order.
+    pub fn compose(&mut self, batch_size: usize) -> Vec<ComposedEntry<T>> {
+        let (n_interactive, n_bulk) = self.plan(batch_size);
+        let now = Instant::now();
+        let interactive = self
+            .interactive
+            .drain(..n_interactive)
+            .map(|e| (RequestLane::Interactive, e));
+        let bulk = self.bulk.drain(..n_bulk).map(|e| (RequestLane::Bulk, e));
+        interactive
+            .chain(bulk)
+            .map(|(lane, e)| ComposedEntry {
+                item: e.item,
+                lane,
+                queued_for: now.saturating_duration_since(e.enqueued_at),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_attribute() {
+        assert_eq!(
+            RequestLane::from_priority_attribute(Some("interactive")),
+            RequestLane::Interactive
+        );
+        assert_eq!(
+            RequestLane::from_priority_attribute(Some("normal")),
+            RequestLane::Bulk
+        );
+        assert_eq!(
+            RequestLane::from_priority_attribute(None),
+            RequestLane::Bulk
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut lanes = PriorityLanes::new(0.25);
+        for i in 0..100 {
+            lanes.push(RequestLane::Bulk, i);
+        }
+        // Only bulk waiting: the whole batch goes to bulk.
+        assert_eq!(lanes.plan(8), (0, 8));
+
+        for i in 0..100 {
+            lanes.push(RequestLane::Interactive, i);
+        }
+        // Both waiting: interactive gets its reserved share.
+        assert_eq!(lanes.plan(8), (2, 6));
+
+        let mut lanes = PriorityLanes::new(0.25);
+        for i in 0..3 {
+            lanes.push(RequestLane::Bulk, i);
+        }
+        for i in 0..10 {
+            lanes.push(RequestLane::Interactive, i);
+        }
+        // Not enough bulk: interactive fills the rest.
+        assert_eq!(lanes.plan(8), (5, 3));
+
+        let batch = lanes.compose(8);
+        assert_eq!(batch.len(), 8);
+        assert!(batch[..5]
+            .iter()
+            .all(|e| e.lane == RequestLane::Interactive));
+        assert_eq!(batch.iter().map(|e| e.item).collect::<Vec<_>>(), vec![
+            0, 1, 2, 3, 4, 0, 1, 2
+        ]);
+        assert_eq!(lanes.depth(RequestLane::Interactive), 5);
+        assert_eq!(lanes.depth(RequestLane::Bulk), 0);
+    }
+
+    /// Floods the bulk lane while interactive requests trickle in and checks
+    /// that every interactive request is processed within a bounded number of
+    /// batches.
+    #[test]
+    fn test_interactive_latency_under_bulk_flood() {
+        const BATCH_SIZE: usize = 64;
+        const BULK_PER_TICK: usize = 500;
+        const INTERACTIVE_PER_TICK: usize = 10;
+        const TICKS: usize = 200;
+        // Interactive requests must be processed within this many batches.
+        const MAX_WAIT_TICKS: usize = 1;
+
+        let mut lanes = PriorityLanes::new(0.25);
+        let mut max_wait = 0;
+        let mut n_interactive_done = 0;
+
+        for tick in 0..TICKS {
+            for _ in 0..BULK_PER_TICK {
+                lanes.push(RequestLane::Bulk, tick);
+            }
+            for _ in 0..INTERACTIVE_PER_TICK {
+                lanes.push(RequestLane::Interactive, tick);
+            }
+
+            let batch = lanes.compose(BATCH_SIZE);
+            assert_eq!(batch.len(), BATCH_SIZE);
+            for entry in batch {
+                if entry.lane == RequestLane::Interactive {
+                    max_wait = max_wait.max(tick - entry.item);
+                    n_interactive_done += 1;
+                }
+            }
+        }
+
+        assert!(max_wait <= MAX_WAIT_TICKS, "max wait was {}", max_wait);
+        assert_eq!(n_interactive_done, TICKS * INTERACTIVE_PER_TICK);
+        assert!(lanes.depth(RequestLane::Bulk) > 0);
+    }
+}
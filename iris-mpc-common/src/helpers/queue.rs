@@ -0,0 +1,875 @@
+//! Abstractions over the request queue (SQS) and the result topic (SNS).
+//!
+//! The server only needs to receive and delete request messages, and to
+//! publish result messages. Both sides are behind traits so that tests can run
+//! the full pipeline against the channel-backed implementations below instead
+//! of real AWS services.
+//!
+//! A request message that fails to parse is left in the queue, so that it is
+//! received again once its visibility times out. [`PoisonMessages`] counts the
+//! failed attempts per message and gives up on a message after
+//! [`PoisonMessagePolicy::max_attempts`] of them: it is forwarded to the
+//! dead-letter queue, if one is configured, and deleted. A failure to receive
+//! that may pass on its own, like a timeout or throttling, is retried instead,
+//! see [`receive_with_retry`].
+
+use super::smpc_request::{ReceiveRequestError, ReceiveRetryConfig};
+use crate::errors::HasErrorCode;
+use async_trait::async_trait;
+use aws_sdk_sns::{types::MessageAttributeValue, Client as SNSClient};
+use aws_sdk_sqs::{types::MessageAttributeValue as SqsMessageAttributeValue, Client as SQSClient};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// A message received from the request queue.
+#[derive(Debug, Clone, Default)]
+pub struct QueueMessage {
+    pub body:               String,
+    pub receipt_handle:     String,
+    /// The id the queue assigned to the message. Messages delivered through
+    /// SNS carry the id of the SNS message in their body as well.
+    pub message_id:         String,
+    /// The string attributes of the message in the queue. Messages delivered
+    /// through SNS carry theirs in the body instead, unless the subscription
+    /// uses raw message delivery.
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+}
+
+#[async_trait]
+pub trait RequestReceiver: Send + Sync {
+    /// Receives up to `max_messages` messages. Returns an empty vector if no
+    /// messages are available.
+    async fn receive(&self, max_messages: i32) -> Result<Vec<QueueMessage>, ReceiveRequestError>;
+
+    /// Deletes a received message from the queue.
+    async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError>;
+
+    /// Keeps a received message hidden from other consumers for `timeout`
+    /// from now on. A zero timeout makes it visible again right away.
+    async fn change_visibility(
+        &self,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), ReceiveRequestError>;
+
+    /// Puts a message that was already deleted back into the queue, as a new
+    /// message with the same body.
+    async fn requeue(&self, body: &str) -> Result<(), ReceiveRequestError>;
+}
+
+#[async_trait]
+pub trait ResultPublisher: Send + Sync {
+    async fn publish(
+        &self,
+        message: String,
+        message_attributes: HashMap<String, MessageAttributeValue>,
+    ) -> eyre::Result<()>;
+}
+
+/// Receives the request messages that were given up, see [`PoisonMessages`].
+#[async_trait]
+pub trait DeadLetterSender: Send + Sync {
+    async fn send(&self, message: &QueueMessage) -> Result<(), ReceiveRequestError>;
+}
+
+/// Receives like [`RequestReceiver::receive`], retrying the failures that may
+/// pass on their own under `config`. Any other failure, or the last one, is
+/// returned.
+pub async fn receive_with_retry<R: RequestReceiver + ?Sized>(
+    receiver: &R,
+    max_messages: i32,
+    config: &ReceiveRetryConfig,
+) -> Result<Vec<QueueMessage>, ReceiveRequestError> {
+    let max_attempts = config.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match receiver.receive(max_messages).await {
+            Err(e) if e.is_retryable() && attempt < max_attempts => {
+                let delay = config.delay(attempt, &mut rand::thread_rng());
+                tracing::warn!(
+                    "Failed to receive requests (attempt {} of {}), retrying in {:?}: {}",
+                    attempt,
+                    max_attempts,
+                    delay,
+                    e
+                );
+                metrics::counter!("request.receive_retry").increment(1);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Converts the attributes of an SQS message to the SNS ones the requests
+/// carry in their envelope. Like the envelope, only string attributes are
+/// kept.
+fn string_attributes(
+    attributes: HashMap<String, SqsMessageAttributeValue>,
+) -> HashMap<String, MessageAttributeValue> {
+    attributes
+        .into_iter()
+        .filter_map(|(name, value)| {
+            if value.data_type() != "String" {
+                tracing::warn!(
+                    "Skipped SQS attribute {} of type {}",
+                    name,
+                    value.data_type()
+                );
+                return None;
+            }
+            let value = MessageAttributeValue::builder()
+                .data_type("String")
+                .set_string_value(value.string_value)
+                .build()
+                .ok()?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Message group of the requeued messages in FIFO queues.
+const REQUEUE_MESSAGE_GROUP_ID: &str = "requeued";
+
+/// Message group of the given up messages in FIFO dead-letter queues.
+const DEAD_LETTER_MESSAGE_GROUP_ID: &str = "dead-letter";
+
+#[derive(Debug, Clone)]
+pub struct SqsRequestReceiver {
+    client:    SQSClient,
+    queue_url: String,
+}
+
+impl SqsRequestReceiver {
+    pub fn new(client: SQSClient, queue_url: String) -> Self {
+        Self { client, queue_url }
+    }
+
+    pub fn queue_url(&self) -> &str {
+        &self.queue_url
+    }
+}
+
+#[async_trait]
+impl RequestReceiver for SqsRequestReceiver {
+    async fn receive(&self, max_messages: i32) -> Result<Vec<QueueMessage>, ReceiveRequestError> {
+        let rcv_message_output = self
+            .client
+            .receive_message()
+            .max_number_of_messages(max_messages)
+            .message_attribute_names("All")
+            .queue_url(&self.queue_url)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToReadFromSQS)?;
+
+        Ok(rcv_message_output
+            .messages
+            .unwrap_or_default()
+            .into_iter()
+            .map(|message| QueueMessage {
+                body:               message.body.unwrap_or_default(),
+                receipt_handle:     message.receipt_handle.unwrap_or_default(),
+                message_id:         message.message_id.unwrap_or_default(),
+                message_attributes: string_attributes(
+                    message.message_attributes.unwrap_or_default(),
+                ),
+            })
+            .collect())
+    }
+
+    async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+        self.client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToDeleteFromSQS)?;
+        Ok(())
+    }
+
+    async fn change_visibility(
+        &self,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), ReceiveRequestError> {
+        self.client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(timeout.as_secs().min(i32::MAX as u64) as i32)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToChangeVisibility)?;
+        Ok(())
+    }
+
+    async fn requeue(&self, body: &str) -> Result<(), ReceiveRequestError> {
+        let mut request = self
+            .client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body);
+        // FIFO queues would drop a body seen within the deduplication interval.
+        if self.queue_url.ends_with(".fifo") {
+            request = request
+                .message_group_id(REQUEUE_MESSAGE_GROUP_ID)
+                .message_deduplication_id(uuid::Uuid::new_v4().to_string());
+        }
+        request
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToRequeue)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SqsDeadLetterSender {
+    client:    SQSClient,
+    queue_url: String,
+}
+
+impl SqsDeadLetterSender {
+    pub fn new(client: SQSClient, queue_url: String) -> Self {
+        Self { client, queue_url }
+    }
+}
+
+#[async_trait]
+impl DeadLetterSender for SqsDeadLetterSender {
+    async fn send(&self, message: &QueueMessage) -> Result<(), ReceiveRequestError> {
+        // Attributes of raw deliveries are kept; those of enveloped messages
+        // are in the body.
+        let message_attributes = message
+            .message_attributes
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = SqsMessageAttributeValue::builder()
+                    .data_type(value.data_type())
+                    .set_string_value(value.string_value().map(ToString::to_string))
+                    .build()
+                    .ok()?;
+                Some((name.clone(), value))
+            })
+            .collect();
+        let mut request = self
+            .client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(&message.body)
+            .set_message_attributes(Some(message_attributes));
+        if self.queue_url.ends_with(".fifo") {
+            request = request
+                .message_group_id(DEAD_LETTER_MESSAGE_GROUP_ID)
+                .message_deduplication_id(uuid::Uuid::new_v4().to_string());
+        }
+        request
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToDeadLetter)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnsResultPublisher {
+    client:           SNSClient,
+    topic_arn:        String,
+    message_group_id: String,
+}
+
+impl SnsResultPublisher {
+    pub fn new(client: SNSClient, topic_arn: String, party_id: usize) -> Self {
+        Self {
+            client,
+            topic_arn,
+            message_group_id: format!("party-id-{}", party_id),
+        }
+    }
+}
+
+#[async_trait]
+impl ResultPublisher for SnsResultPublisher {
+    async fn publish(
+        &self,
+        message: String,
+        message_attributes: HashMap<String, MessageAttributeValue>,
+    ) -> eyre::Result<()> {
+        self.client
+            .publish()
+            .topic_arn(&self.topic_arn)
+            .message(message)
+            .message_group_id(&self.message_group_id)
+            .set_message_attributes(Some(message_attributes))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// In-memory request queue backed by a channel. Messages are deleted as soon
+/// as they are received.
+#[derive(Debug)]
+pub struct ChannelRequestReceiver {
+    receiver: Mutex<mpsc::UnboundedReceiver<String>>,
+    sender:   ChannelRequestSender,
+    counter:  AtomicU64,
+}
+
+/// Sending side of a [`ChannelRequestReceiver`].
+pub type ChannelRequestSender = mpsc::UnboundedSender<String>;
+
+impl ChannelRequestReceiver {
+    pub fn new() -> (ChannelRequestSender, Self) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx.clone(), Self {
+            receiver: Mutex::new(rx),
+            sender:   tx,
+            counter:  AtomicU64::new(0),
+        })
+    }
+}
+
+#[async_trait]
+impl RequestReceiver for ChannelRequestReceiver {
+    async fn receive(&self, max_messages: i32) -> Result<Vec<QueueMessage>, ReceiveRequestError> {
+        let mut receiver = self.receiver.lock().unwrap();
+        let mut messages = vec![];
+        while messages.len() < max_messages.max(0) as usize {
+            match receiver.try_recv() {
+                Ok(body) => messages.push(QueueMessage {
+                    body,
+                    receipt_handle: self.counter.fetch_add(1, Ordering::Relaxed).to_string(),
+                    ..Default::default()
+                }),
+                Err(_) => break,
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn delete(&self, _receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+        Ok(())
+    }
+
+    async fn change_visibility(
+        &self,
+        _receipt_handle: &str,
+        _timeout: Duration,
+    ) -> Result<(), ReceiveRequestError> {
+        Ok(())
+    }
+
+    async fn requeue(&self, body: &str) -> Result<(), ReceiveRequestError> {
+        // The receiver holds a sender itself, so the channel is never closed.
+        self.sender.send(body.to_string()).unwrap();
+        Ok(())
+    }
+}
+
+/// A message published through a [`ChannelResultPublisher`].
+#[derive(Debug, Clone)]
+pub struct PublishedMessage {
+    pub message:            String,
+    pub message_attributes: HashMap<String, MessageAttributeValue>,
+}
+
+/// In-memory result topic that records all published messages.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelResultPublisher {
+    published: Arc<Mutex<Vec<PublishedMessage>>>,
+}
+
+impl ChannelResultPublisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns and clears the messages published so far.
+    pub fn drain(&self) -> Vec<PublishedMessage> {
+        std::mem::take(&mut *self.published.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl ResultPublisher for ChannelResultPublisher {
+    async fn publish(
+        &self,
+        message: String,
+        message_attributes: HashMap<String, MessageAttributeValue>,
+    ) -> eyre::Result<()> {
+        self.published.lock().unwrap().push(PublishedMessage {
+            message,
+            message_attributes,
+        });
+        Ok(())
+    }
+}
+
+/// How request messages that fail to parse are handled.
+#[derive(Clone)]
+pub struct PoisonMessagePolicy {
+    /// Failed attempts after which a message is given up.
+    pub max_attempts: u32,
+    /// Receives the given up messages. They are only deleted if unset.
+    pub dead_letter:  Option<Arc<dyn DeadLetterSender>>,
+}
+
+/// What [`PoisonMessages::reject`] did with a message.
+#[derive(Debug)]
+pub enum PoisonMessageAction {
+    /// The message is left in the queue, to be received again.
+    Retry,
+    /// The message was forwarded to the dead-letter queue and deleted.
+    DeadLettered(ReceiveRequestError),
+    /// The message was deleted.
+    Deleted(ReceiveRequestError),
+}
+
+/// The failed attempts to parse the request messages, keyed by their SQS
+/// message id. Messages without one are told apart by their body.
+pub struct PoisonMessages {
+    policy:   PoisonMessagePolicy,
+    attempts: HashMap<String, u32>,
+}
+
+impl PoisonMessages {
+    pub fn new(policy: PoisonMessagePolicy) -> Self {
+        Self {
+            policy,
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Records that `message` failed to parse with `error`. The message is
+    /// left in the queue until it failed [`PoisonMessagePolicy::max_attempts`]
+    /// times, and then given up with a
+    /// [`ReceiveRequestError::ExhaustedRetries`] error.
+    pub async fn reject<R: RequestReceiver + ?Sized>(
+        &mut self,
+        receiver: &R,
+        message: &QueueMessage,
+        error: ReceiveRequestError,
+    ) -> Result<PoisonMessageAction, ReceiveRequestError> {
+        let message_id = if message.message_id.is_empty() {
+            &message.body
+        } else {
+            &message.message_id
+        };
+        metrics::counter!("request.parse_failed", "code" => error.error_code().as_str())
+            .increment(1);
+        let attempts = self.attempts.entry(message_id.clone()).or_default();
+        *attempts += 1;
+        if *attempts < self.policy.max_attempts {
+            tracing::warn!(
+                "Failed to parse message {} (attempt {} of {}): {}",
+                message_id,
+                attempts,
+                self.policy.max_attempts,
+                error
+            );
+            return Ok(PoisonMessageAction::Retry);
+        }
+
+        let attempts = self.attempts.remove(message_id).unwrap_or_default();
+        let dead_lettered = match &self.policy.dead_letter {
+            Some(dead_letter) => {
+                dead_letter.send(message).await?;
+                true
+            }
+            None => false,
+        };
+        receiver.delete(&message.receipt_handle).await?;
+        let action = if dead_lettered {
+            "dead_lettered"
+        } else {
+            "deleted"
+        };
+        let error = ReceiveRequestError::ExhaustedRetries {
+            message_id: message_id.clone(),
+            attempts,
+            source: Box::new(error),
+        };
+        tracing::error!(
+            message_id = %message_id,
+            attempts,
+            code = error.error_code().as_str(),
+            action,
+            "Giving up on poison message: {}",
+            error
+        );
+        metrics::counter!("request.poison_message", "action" => action).increment(1);
+        Ok(if dead_lettered {
+            PoisonMessageAction::DeadLettered(error)
+        } else {
+            PoisonMessageAction::Deleted(error)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::{
+        smpc_request::parse_incoming_body,
+        smpc_response::{create_message_type_attribute_map, SMPC_MESSAGE_TYPE_ATTRIBUTE},
+    };
+    use aws_sdk_s3::primitives::SdkBody;
+    use aws_sdk_sqs::{
+        config::http::HttpResponse,
+        error::{ErrorMetadata, SdkError},
+        operation::receive_message::ReceiveMessageError,
+    };
+
+    /// A queue that delivers the same message until it is deleted.
+    struct MockQueue {
+        body:       String,
+        deliveries: AtomicU64,
+        deleted:    Mutex<Vec<String>>,
+    }
+
+    impl MockQueue {
+        fn new(body: &str) -> Self {
+            Self {
+                body:       body.to_string(),
+                deliveries: AtomicU64::new(0),
+                deleted:    Mutex::new(vec![]),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl RequestReceiver for MockQueue {
+        async fn receive(
+            &self,
+            _max_messages: i32,
+        ) -> Result<Vec<QueueMessage>, ReceiveRequestError> {
+            if !self.deleted.lock().unwrap().is_empty() {
+                return Ok(vec![]);
+            }
+            let delivery = self.deliveries.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![QueueMessage {
+                body: self.body.clone(),
+                receipt_handle: format!("receipt-{}", delivery),
+                message_id: "poison".to_string(),
+                ..Default::default()
+            }])
+        }
+
+        async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+            self.deleted
+                .lock()
+                .unwrap()
+                .push(receipt_handle.to_string());
+            Ok(())
+        }
+
+        async fn change_visibility(
+            &self,
+            _receipt_handle: &str,
+            _timeout: Duration,
+        ) -> Result<(), ReceiveRequestError> {
+            Ok(())
+        }
+
+        async fn requeue(&self, _body: &str) -> Result<(), ReceiveRequestError> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockDeadLetter {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl DeadLetterSender for MockDeadLetter {
+        async fn send(&self, message: &QueueMessage) -> Result<(), ReceiveRequestError> {
+            self.sent.lock().unwrap().push(message.body.clone());
+            Ok(())
+        }
+    }
+
+    /// Receives and fails to parse the message of `queue` until it is no
+    /// longer delivered. Returns the actions taken.
+    async fn reject_until_given_up(
+        queue: &MockQueue,
+        poison_messages: &mut PoisonMessages,
+    ) -> Vec<PoisonMessageAction> {
+        let mut actions = vec![];
+        loop {
+            let messages = queue.receive(1).await.unwrap();
+            let Some(message) = messages.first() else {
+                return actions;
+            };
+            let error = parse_incoming_body(&message.body).unwrap_err();
+            actions.push(poison_messages.reject(queue, message, error).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poison_message_dead_lettered() {
+        let queue = MockQueue::new("not json");
+        let dead_letter = Arc::new(MockDeadLetter::default());
+        let mut poison_messages = PoisonMessages::new(PoisonMessagePolicy {
+            max_attempts: 3,
+            dead_letter:  Some(dead_letter.clone()),
+        });
+
+        let actions = reject_until_given_up(&queue, &mut poison_messages).await;
+        assert_eq!(actions.len(), 3);
+        assert!(matches!(actions[0], PoisonMessageAction::Retry));
+        assert!(matches!(actions[1], PoisonMessageAction::Retry));
+        assert!(matches!(
+            &actions[2],
+            PoisonMessageAction::DeadLettered(ReceiveRequestError::ExhaustedRetries {
+                message_id,
+                attempts: 3,
+                source,
+            }) if message_id == "poison"
+                && matches!(**source, ReceiveRequestError::JsonParseError { .. })
+        ));
+        assert_eq!(*dead_letter.sent.lock().unwrap(), vec!["not json"]);
+        assert_eq!(*queue.deleted.lock().unwrap(), vec!["receipt-2"]);
+        assert!(poison_messages.attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_poison_message_deleted() {
+        let queue = MockQueue::new("{\"Type\": \"Notification\", \"Message\": 1}");
+        let mut poison_messages = PoisonMessages::new(PoisonMessagePolicy {
+            max_attempts: 1,
+            dead_letter:  None,
+        });
+
+        let actions = reject_until_given_up(&queue, &mut poison_messages).await;
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(
+            &actions[0],
+            PoisonMessageAction::Deleted(error @ ReceiveRequestError::ExhaustedRetries { .. })
+                if error.error_code() == crate::errors::ErrorCode::InvalidRequestJson
+        ));
+        assert_eq!(*queue.deleted.lock().unwrap(), vec!["receipt-0"]);
+    }
+
+    #[tokio::test]
+    async fn test_channel_request_receiver() {
+        let (tx, receiver) = ChannelRequestReceiver::new();
+        for i in 0..3 {
+            tx.send(format!("message-{}", i)).unwrap();
+        }
+
+        let messages = receiver.receive(2).await.unwrap();
+        assert_eq!(
+            messages.iter().map(|m| m.body.as_str()).collect::<Vec<_>>(),
+            vec!["message-0", "message-1"]
+        );
+        let messages = receiver.receive(10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body, "message-2");
+        assert!(receiver.receive(10).await.unwrap().is_empty());
+
+        receiver.requeue("message-1").await.unwrap();
+        let messages = receiver.receive(10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body, "message-1");
+    }
+
+    #[test]
+    fn test_string_attributes() {
+        let attribute = |data_type: &str, value: &str| {
+            SqsMessageAttributeValue::builder()
+                .data_type(data_type)
+                .string_value(value)
+                .build()
+                .unwrap()
+        };
+        let attributes = string_attributes(HashMap::from([
+            (
+                SMPC_MESSAGE_TYPE_ATTRIBUTE.to_string(),
+                attribute("String", "uniqueness"),
+            ),
+            ("count".to_string(), attribute("Number", "3")),
+        ]));
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(
+            attributes[SMPC_MESSAGE_TYPE_ATTRIBUTE].string_value(),
+            Some("uniqueness")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_result_publisher() {
+        let publisher = ChannelResultPublisher::new();
+        publisher
+            .publish(
+                "result".to_string(),
+                create_message_type_attribute_map("uniqueness"),
+            )
+            .await
+            .unwrap();
+
+        let published = publisher.drain();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].message, "result");
+        assert_eq!(
+            published[0].message_attributes[SMPC_MESSAGE_TYPE_ATTRIBUTE].string_value(),
+            Some("uniqueness")
+        );
+        assert!(publisher.drain().is_empty());
+    }
+
+    /// A failed receive call, answered by SQS with `status` and the error
+    /// `code`.
+    fn sqs_error(status: u16, code: &str) -> ReceiveRequestError {
+        ReceiveRequestError::FailedToReadFromSQS(SdkError::service_error(
+            ReceiveMessageError::generic(ErrorMetadata::builder().code(code).build()),
+            HttpResponse::new(status.try_into().unwrap(), SdkBody::empty()),
+        ))
+    }
+
+    #[test]
+    fn test_receive_error_is_retryable() {
+        let timeout = ReceiveRequestError::FailedToReadFromSQS(SdkError::timeout_error("timeout"));
+        assert!(timeout.is_retryable());
+        assert!(sqs_error(400, "ThrottlingException").is_retryable());
+        assert!(sqs_error(400, "RequestThrottled").is_retryable());
+        assert!(sqs_error(429, "SlowDown").is_retryable());
+        assert!(sqs_error(500, "InternalError").is_retryable());
+        assert!(
+            ReceiveRequestError::FailedToDeleteFromSQS(SdkError::timeout_error("timeout"))
+                .is_retryable()
+        );
+
+        assert!(!sqs_error(400, "AWS.SimpleQueueService.NonExistentQueue").is_retryable());
+        assert!(!sqs_error(403, "AccessDenied").is_retryable());
+        assert!(
+            !ReceiveRequestError::FailedToReadFromSQS(SdkError::construction_failure("sqs"))
+                .is_retryable()
+        );
+        assert!(!parse_incoming_body("not json").unwrap_err().is_retryable());
+        assert!(!ReceiveRequestError::InvalidMessageType.is_retryable());
+        assert!(!ReceiveRequestError::NoStringMessageTypeAttribute.is_retryable());
+        assert!(!ReceiveRequestError::ExhaustedRetries {
+            message_id: "id".to_string(),
+            attempts:   3,
+            source:     Box::new(sqs_error(500, "InternalError")),
+        }
+        .is_retryable());
+    }
+
+    /// A queue that fails with the given errors before it delivers a message.
+    struct FlakyQueue {
+        errors:   Mutex<Vec<ReceiveRequestError>>,
+        receives: AtomicU64,
+    }
+
+    impl FlakyQueue {
+        fn new(errors: Vec<ReceiveRequestError>) -> Self {
+            Self {
+                errors:   Mutex::new(errors),
+                receives: AtomicU64::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl RequestReceiver for FlakyQueue {
+        async fn receive(
+            &self,
+            _max_messages: i32,
+        ) -> Result<Vec<QueueMessage>, ReceiveRequestError> {
+            self.receives.fetch_add(1, Ordering::Relaxed);
+            let mut errors = self.errors.lock().unwrap();
+            if errors.is_empty() {
+                return Ok(vec![QueueMessage {
+                    body: "message".to_string(),
+                    ..Default::default()
+                }]);
+            }
+            Err(errors.remove(0))
+        }
+
+        async fn delete(&self, _receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+            Ok(())
+        }
+
+        async fn change_visibility(
+            &self,
+            _receipt_handle: &str,
+            _timeout: Duration,
+        ) -> Result<(), ReceiveRequestError> {
+            Ok(())
+        }
+
+        async fn requeue(&self, _body: &str) -> Result<(), ReceiveRequestError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receive_with_retry() {
+        let config = ReceiveRetryConfig {
+            max_attempts:  3,
+            base_delay_ms: 100,
+            max_delay_ms:  1_000,
+        };
+
+        // Transient failures are retried.
+        let queue = FlakyQueue::new(vec![
+            sqs_error(500, "InternalError"),
+            sqs_error(400, "ThrottlingException"),
+        ]);
+        let messages = receive_with_retry(&queue, 1, &config).await.unwrap();
+        assert_eq!(messages[0].body, "message");
+        assert_eq!(queue.receives.load(Ordering::Relaxed), 3);
+
+        // Until the attempts run out.
+        let queue = FlakyQueue::new(
+            (0..3)
+                .map(|_| sqs_error(503, "ServiceUnavailable"))
+                .collect(),
+        );
+        let result = receive_with_retry(&queue, 1, &config).await;
+        assert!(matches!(
+            result,
+            Err(ReceiveRequestError::FailedToReadFromSQS(_))
+        ));
+        assert_eq!(queue.receives.load(Ordering::Relaxed), 3);
+
+        // Permanent failures are not retried.
+        let queue = FlakyQueue::new(vec![sqs_error(403, "AccessDenied")]);
+        assert!(receive_with_retry(&queue, 1, &config).await.is_err());
+        assert_eq!(queue.receives.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_receive_retry_delay() {
+        let config = ReceiveRetryConfig {
+            max_attempts:  10,
+            base_delay_ms: 100,
+            max_delay_ms:  1_000,
+        };
+        let mut rng = rand::thread_rng();
+        for (retry, full) in [(1, 100), (2, 200), (4, 800), (5, 1_000), (9, 1_000)] {
+            let delay = config.delay(retry, &mut rng);
+            let full = Duration::from_millis(full);
+            assert!(
+                delay <= full && delay >= full / 2,
+                "{:?} of {:?}",
+                delay,
+                full
+            );
+        }
+    }
+}
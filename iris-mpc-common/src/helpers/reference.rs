@@ -0,0 +1,148 @@
+//! Plaintext reference of the threshold comparison, the one the GPU circuits
+//! and the CPU protocol are tested against.
+//!
+//! The comparison takes the dot products of a pair of irises: the code dot
+//! `mask_len - 2 * hamming` and the mask dot `mask_len`, both as the `u16`
+//! values the parties share. The code dot is negative for a distance above
+//! one half and then holds its two's complement, e.g. `-5` as `65531`. The
+//! parties compute `mask_dot * A - code_dot * 2^B_BITS` in `Z_{2^32}`, see
+//! [`MatchThreshold`], and the pair matches if the most significant bit is
+//! set. The code dot only enters multiplied by `2^B_BITS = 2^16`, which
+//! discards its upper bits, so reading it as unsigned or as sign extended
+//! gives the same result.
+
+use super::threshold::MatchThreshold;
+use crate::iris_db::iris::IrisCode;
+
+/// The code dot and the mask dot of a pair of irises, as the parties compute
+/// them on shares.
+pub fn dots(query: &IrisCode, db: &IrisCode) -> (u16, u16) {
+    let (hamming, mask_len) = query.hamming_and_mask_bits(db);
+    let code_dot = (mask_len as u16).wrapping_sub(2 * hamming as u16);
+    (code_dot, mask_len as u16)
+}
+
+/// The fractional hamming distance of a pair with the given dot products, or
+/// `None` for a pair without common unmasked bits.
+pub fn distance(code_dot: u16, mask_dot: u16) -> Option<f64> {
+    (mask_dot > 0).then(|| (mask_dot as f64 - code_dot as i16 as f64) / (2.0 * mask_dot as f64))
+}
+
+/// `mask_dot * A - code_dot * 2^B_BITS` in `Z_{2^32}`.
+pub fn threshold_diff(code_dot: u16, mask_dot: u16, threshold: MatchThreshold) -> u32 {
+    (mask_dot as u32)
+        .wrapping_mul(threshold.a() as u32)
+        .wrapping_sub((code_dot as u32) << MatchThreshold::B_BITS)
+}
+
+/// Whether the pair with the given dot products matches under `threshold`. A
+/// pair without common unmasked bits never matches.
+pub fn masked_match(code_dot: u16, mask_dot: u16, threshold: MatchThreshold) -> bool {
+    threshold_diff(code_dot, mask_dot, threshold) >> 31 == 1
+}
+
+/// [`masked_match`] of every pair.
+pub fn masked_match_many(
+    code_dots: &[u16],
+    mask_dots: &[u16],
+    threshold: MatchThreshold,
+) -> Vec<bool> {
+    assert_eq!(code_dots.len(), mask_dots.len());
+    code_dots
+        .iter()
+        .zip(mask_dots)
+        .map(|(&code_dot, &mask_dot)| masked_match(code_dot, mask_dot, threshold))
+        .collect()
+}
+
+/// Packs the decisions like the GPU circuits open them: every device holds
+/// `inputs_per_gpu` lanes, packed into `u64` words with lane `i` at bit
+/// `i % 64`, and the words of a device are padded to a whole word with
+/// non-matches.
+pub fn pack_with_device_padding(decisions: &[bool], inputs_per_gpu: usize) -> Vec<u64> {
+    assert_eq!(decisions.len() % inputs_per_gpu, 0);
+    decisions
+        .chunks_exact(inputs_per_gpu)
+        .flat_map(|device| device.chunks(64))
+        .map(|lanes| {
+            lanes
+                .iter()
+                .enumerate()
+                .fold(0u64, |word, (i, &lane)| word | (u64::from(lane) << i))
+        })
+        .collect()
+}
+
+/// [`masked_match_many`], packed with [`pack_with_device_padding`].
+pub fn masked_match_packed(
+    code_dots: &[u16],
+    mask_dots: &[u16],
+    threshold: MatchThreshold,
+    inputs_per_gpu: usize,
+) -> Vec<u64> {
+    pack_with_device_padding(
+        &masked_match_many(code_dots, mask_dots, threshold),
+        inputs_per_gpu,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_masked_match() {
+        // Exact in fixed point: A = 2^15.
+        let threshold = MatchThreshold::new(0.25).unwrap();
+        // Distances 0.2, exactly 0.25 and 0.3 over 1000 bits.
+        assert!(masked_match(600, 1000, threshold));
+        assert!(!masked_match(500, 1000, threshold));
+        assert!(!masked_match(400, 1000, threshold));
+        // A distance of 0.6 gives a negative code dot.
+        assert!(!masked_match((-200i16) as u16, 1000, threshold));
+        assert_eq!(
+            threshold_diff((-200i16) as u16, 1000, threshold),
+            (1000u32 << 15).wrapping_add(200 << 16)
+        );
+        // No common unmasked bits.
+        assert!(!masked_match(0, 0, threshold));
+        assert_eq!(distance(0, 0), None);
+        assert_eq!(distance((-200i16) as u16, 1000), Some(0.6));
+    }
+
+    #[test]
+    fn test_dots() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let iris = IrisCode::random_rng(&mut rng);
+        for noise in [0.1, 0.6] {
+            let (noisy, _) = iris.with_noise(noise, 0.0, &mut rng);
+            let (code_dot, mask_dot) = dots(&iris, &noisy);
+            let (_, _, distance) = iris.masked_distance(&noisy).unwrap();
+            assert_eq!((code_dot as i16 as i64) < 0, distance > 0.5);
+            assert!((super::distance(code_dot, mask_dot).unwrap() - distance).abs() < 1e-9);
+            for threshold in [0.25, 0.375] {
+                let threshold = MatchThreshold::new(threshold).unwrap();
+                assert_eq!(
+                    masked_match(code_dot, mask_dot, threshold),
+                    distance < threshold.ratio()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pack_with_device_padding() {
+        let mut decisions = vec![false; 2 * 70];
+        decisions[0] = true;
+        decisions[65] = true;
+        decisions[70] = true;
+        decisions[139] = true;
+        assert_eq!(pack_with_device_padding(&decisions, 70), vec![
+            1,
+            1 << 1,
+            1,
+            1 << 5
+        ]);
+    }
+}
@@ -0,0 +1,392 @@
+//! Recordings of processed batches, to replay them on a dev machine.
+//!
+//! With recording enabled, a party writes a [`RecordedBatch`] per batch: the
+//! requests the parties agreed on, with this party's shares and the error the
+//! parties agreed on, and the opened decisions. The batches of a party form a
+//! [`ReplayBundle`], which is sealed to an offline debugging key before it is
+//! written, as it holds share material.
+//!
+//! A replay feeds the bundles of all three parties through the pipeline again
+//! and compares the decisions, see [`first_divergence`].
+
+use super::{
+    audit::{AuditDecision, AuditHash},
+    threshold::MatchThreshold,
+};
+use crate::{
+    config::{MatchPolicy, ResultMode},
+    errors::ErrorCode,
+    galois_engine::degree4::{
+        GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare, ShareEncodingError, ShareKind,
+    },
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{
+    box_::{self, PublicKey, SecretKey},
+    sealedbox,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+pub const REPLAY_BUNDLE_VERSION: u32 = 1;
+/// Message type of the backfilled entries in a [`RecordedBatch`].
+pub const BACKFILL_MESSAGE_TYPE: &str = "backfill";
+const BATCH_FILE_EXTENSION: &str = "replay";
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid debugging key")]
+    InvalidKey,
+    #[error("the bundle cannot be opened with this key")]
+    Decryption,
+    #[error("invalid replay bundle: {0}")]
+    Format(String),
+    #[error(transparent)]
+    Shares(#[from] ShareEncodingError),
+}
+
+/// The shares of a uniqueness request held by the recording party, in the
+/// versioned share encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedShares {
+    pub left_code:  String,
+    pub left_mask:  String,
+    pub right_code: String,
+    pub right_mask: String,
+}
+
+pub type DecodedShares = (
+    (GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare),
+    (GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare),
+);
+
+impl RecordedShares {
+    pub fn new(
+        left_code: &GaloisRingIrisCodeShare,
+        left_mask: &GaloisRingTrimmedMaskCodeShare,
+        right_code: &GaloisRingIrisCodeShare,
+        right_mask: &GaloisRingTrimmedMaskCodeShare,
+    ) -> Self {
+        Self {
+            left_code:  left_code.to_base64(ShareKind::Code),
+            left_mask:  left_mask.to_base64(),
+            right_code: right_code.to_base64(ShareKind::Code),
+            right_mask: right_mask.to_base64(),
+        }
+    }
+
+    pub fn decode(&self) -> Result<DecodedShares, ShareEncodingError> {
+        Ok((
+            (
+                GaloisRingIrisCodeShare::from_base64(&self.left_code, ShareKind::Code)?,
+                GaloisRingTrimmedMaskCodeShare::from_base64(&self.left_mask)?,
+            ),
+            (
+                GaloisRingIrisCodeShare::from_base64(&self.right_code, ShareKind::Code)?,
+                GaloisRingTrimmedMaskCodeShare::from_base64(&self.right_mask)?,
+            ),
+        ))
+    }
+}
+
+/// A request of a batch, after the parties agreed on it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub request_id:   String,
+    /// The message type attribute, or [`BACKFILL_MESSAGE_TYPE`].
+    pub message_type: String,
+    /// The request message, if the recording party still had it.
+    pub message:      Option<String>,
+    pub shares:       Option<RecordedShares>,
+    /// The error the parties agreed on for the request.
+    pub error:        Option<ErrorCode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedBatch {
+    pub batch_id:           u64,
+    pub requests:           Vec<RecordedRequest>,
+    pub decisions:          Vec<AuditDecision>,
+    pub deleted_serial_ids: Vec<u32>,
+    /// The soft-deletes the parties agreed to purge with the batch, see
+    /// [`soft_delete`](crate::helpers::soft_delete). Missing in recordings
+    /// made before soft-deletes.
+    #[serde(default)]
+    pub purged_serial_ids:  Vec<u32>,
+    pub db_digest_before:   AuditHash,
+    pub db_digest_after:    AuditHash,
+}
+
+/// The settings of the recording party that change the decisions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedSettings {
+    pub enable_mirrored_checks:    bool,
+    pub result_mode:               ResultMode,
+    pub reveal_matched_serial_ids: bool,
+    /// Missing in recordings made before the policy was configurable, which
+    /// all used [`MatchPolicy::And`].
+    #[serde(default)]
+    pub match_policy:              MatchPolicy,
+    /// The threshold the party started with. Missing in recordings made
+    /// before it was configurable, which all used the default.
+    #[serde(default)]
+    pub match_threshold:           MatchThreshold,
+    /// The `max_rotation_window` of the party. Missing in recordings made
+    /// before it was configurable, which all tried every rotation.
+    #[serde(default)]
+    pub max_rotation_window:       Option<usize>,
+    /// The key threshold updates are checked against.
+    pub threshold_operator_key:    Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayBundle {
+    pub version:  u32,
+    pub party_id: usize,
+    pub settings: RecordedSettings,
+    pub batches:  Vec<RecordedBatch>,
+}
+
+impl ReplayBundle {
+    pub fn new(party_id: usize, settings: RecordedSettings) -> Self {
+        Self {
+            version: REPLAY_BUNDLE_VERSION,
+            party_id,
+            settings,
+            batches: vec![],
+        }
+    }
+
+    /// Serializes the bundle and seals it to the debugging key.
+    pub fn seal(&self, debug_key: &PublicKey) -> Vec<u8> {
+        let plain = serde_json::to_vec(self).expect("replay bundles serialize to JSON");
+        sealedbox::seal(&plain, debug_key)
+    }
+
+    pub fn open(sealed: &[u8], debug_key: &SecretKey) -> Result<Self, ReplayError> {
+        let plain = sealedbox::open(sealed, &debug_key.public_key(), debug_key)
+            .map_err(|_| ReplayError::Decryption)?;
+        let bundle: Self =
+            serde_json::from_slice(&plain).map_err(|e| ReplayError::Format(e.to_string()))?;
+        if bundle.version != REPLAY_BUNDLE_VERSION {
+            return Err(ReplayError::Format(format!(
+                "version {}, expected {}",
+                bundle.version, REPLAY_BUNDLE_VERSION
+            )));
+        }
+        Ok(bundle)
+    }
+
+    /// Merges the sealed single-batch bundles a [`BatchRecorder`] wrote for
+    /// the given party into one bundle, in batch order.
+    pub fn load_dir(
+        dir: impl AsRef<Path>,
+        party_id: usize,
+        debug_key: &SecretKey,
+    ) -> Result<Self, ReplayError> {
+        let prefix = format!("party-{}-", party_id);
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| {
+            path.extension().is_some_and(|e| e == BATCH_FILE_EXTENSION)
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(&prefix))
+        });
+        paths.sort();
+
+        let mut merged: Option<Self> = None;
+        for path in paths {
+            let bundle = Self::open(&fs::read(&path)?, debug_key)?;
+            match merged.as_mut() {
+                None => merged = Some(bundle),
+                Some(merged) => {
+                    if bundle.party_id != merged.party_id || bundle.settings != merged.settings {
+                        return Err(ReplayError::Format(format!(
+                            "{} was recorded by another party or with other settings",
+                            path.display()
+                        )));
+                    }
+                    merged.batches.extend(bundle.batches);
+                }
+            }
+        }
+        merged.ok_or_else(|| ReplayError::Format(format!("no batches of party {}", party_id)))
+    }
+}
+
+/// Writes every recorded batch of a party to its own sealed file.
+pub struct BatchRecorder {
+    dir:       PathBuf,
+    party_id:  usize,
+    settings:  RecordedSettings,
+    debug_key: PublicKey,
+}
+
+impl BatchRecorder {
+    /// `debug_key` is the base64 encoded public debugging key.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        party_id: usize,
+        settings: RecordedSettings,
+        debug_key: &str,
+    ) -> Result<Self, ReplayError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            party_id,
+            settings,
+            debug_key: decode_public_key(debug_key)?,
+        })
+    }
+
+    pub fn record(&self, batch: RecordedBatch) -> Result<PathBuf, ReplayError> {
+        let path = self.dir.join(format!(
+            "party-{}-batch-{:010}.{}",
+            self.party_id, batch.batch_id, BATCH_FILE_EXTENSION
+        ));
+        let mut bundle = ReplayBundle::new(self.party_id, self.settings.clone());
+        bundle.batches.push(batch);
+        fs::write(&path, bundle.seal(&self.debug_key))?;
+        Ok(path)
+    }
+}
+
+/// Generates a debugging key pair, base64 encoded as (public, secret).
+pub fn generate_debug_keys() -> (String, String) {
+    let (public, secret) = box_::gen_keypair();
+    (STANDARD.encode(public.0), STANDARD.encode(secret.0))
+}
+
+pub fn decode_public_key(key: &str) -> Result<PublicKey, ReplayError> {
+    STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|key| PublicKey::from_slice(&key))
+        .ok_or(ReplayError::InvalidKey)
+}
+
+pub fn decode_secret_key(key: &str) -> Result<SecretKey, ReplayError> {
+    STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|key| SecretKey::from_slice(&key))
+        .ok_or(ReplayError::InvalidKey)
+}
+
+/// Where a replayed batch first differs from its recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub batch_id:   u64,
+    pub party_id:   usize,
+    /// Position of the decision in the batch.
+    pub index:      usize,
+    pub request_id: Option<String>,
+    pub recorded:   Option<AuditDecision>,
+    pub replayed:   Option<AuditDecision>,
+}
+
+/// Compares the decisions of a replayed batch with the recorded ones, in
+/// order. A missing decision on either side diverges as well.
+pub fn first_divergence(
+    party_id: usize,
+    recorded: &RecordedBatch,
+    replayed: &[AuditDecision],
+) -> Option<Divergence> {
+    let len = recorded.decisions.len().max(replayed.len());
+    (0..len).find_map(|index| {
+        let (lhs, rhs) = (recorded.decisions.get(index), replayed.get(index));
+        (lhs != rhs).then(|| Divergence {
+            batch_id: recorded.batch_id,
+            party_id,
+            index,
+            request_id: lhs.or(rhs).map(|d| d.request_id.clone()),
+            recorded: lhs.cloned(),
+            replayed: rhs.cloned(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decision(request_id: &str, is_match: bool) -> AuditDecision {
+        AuditDecision {
+            request_id: request_id.to_string(),
+            request_hash: AuditHash::of_parts(&[request_id]),
+            is_match,
+            serial_id: (!is_match).then_some(1),
+        }
+    }
+
+    fn batch(batch_id: u64) -> RecordedBatch {
+        RecordedBatch {
+            batch_id,
+            requests: vec![RecordedRequest {
+                request_id:   "a".to_string(),
+                message_type: "identity_deletion".to_string(),
+                message:      Some("{\"serial_id\":1}".to_string()),
+                shares:       None,
+                error:        Some(ErrorCode::ShareDownloadFailed),
+            }],
+            decisions: vec![decision("a", false), decision("b", true)],
+            deleted_serial_ids: vec![],
+            purged_serial_ids: vec![],
+            db_digest_before: AuditHash::zero(),
+            db_digest_after: AuditHash::zero(),
+        }
+    }
+
+    #[test]
+    fn test_seal_and_load() {
+        let (public, secret) = generate_debug_keys();
+        let secret = decode_secret_key(&secret).unwrap();
+        let dir = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        let recorder = BatchRecorder::new(&dir, 1, RecordedSettings::default(), &public).unwrap();
+        for batch_id in [1, 0] {
+            recorder.record(batch(batch_id)).unwrap();
+        }
+
+        let bundle = ReplayBundle::load_dir(&dir, 1, &secret).unwrap();
+        assert_eq!(bundle.batches, vec![batch(0), batch(1)]);
+        assert!(ReplayBundle::load_dir(&dir, 0, &secret).is_err());
+
+        let (_, other) = generate_debug_keys();
+        assert!(matches!(
+            ReplayBundle::load_dir(&dir, 1, &decode_secret_key(&other).unwrap()),
+            Err(ReplayError::Decryption)
+        ));
+        assert!(matches!(
+            decode_public_key("not a key"),
+            Err(ReplayError::InvalidKey)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let recorded = batch(3);
+        assert_eq!(first_divergence(0, &recorded, &recorded.decisions), None);
+
+        let replayed = vec![decision("a", false), decision("b", false)];
+        let divergence = first_divergence(2, &recorded, &replayed).unwrap();
+        assert_eq!(divergence.batch_id, 3);
+        assert_eq!(divergence.party_id, 2);
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.request_id.as_deref(), Some("b"));
+
+        let divergence = first_divergence(0, &recorded, &replayed[..1]).unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.replayed, None);
+    }
+}
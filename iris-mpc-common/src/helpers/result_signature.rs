@@ -0,0 +1,332 @@
+//! Signatures of the published results, so that a consumer can check which
+//! party a result comes from and compare the outcomes of the three parties
+//! without trusting the results topic.
+//!
+//! A party signs the canonical JSON of a result with its Ed25519 key and adds
+//! the base64 encoded signature as `signature`, next to the `public_key_id` of
+//! the key. The canonical JSON is spelled out in [`canonical_json`], so that
+//! it can be reproduced in other languages and does not depend on the
+//! formatting of serde.
+
+use super::{sha256::sha256_digest, smpc_response::UniquenessResult};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write;
+use thiserror::Error;
+
+/// The field of a result that holds the signature, the only one that is not
+/// signed.
+pub const SIGNATURE_FIELD: &str = "signature";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ResultSignatureError {
+    #[error("Result signing key must be a base64 encoded 32 byte Ed25519 seed")]
+    InvalidSigningKey,
+    #[error("Result is not a JSON object")]
+    NotAnObject,
+    #[error("Result is not signed")]
+    Unsigned,
+    #[error("Result is signed by the unknown key {0}")]
+    UnknownKey(String),
+    #[error("Result has an invalid signature")]
+    InvalidSignature,
+}
+
+/// The Ed25519 key a party signs its results with.
+pub struct ResultSigningKey {
+    key_pair:      Ed25519KeyPair,
+    public_key_id: String,
+}
+
+impl ResultSigningKey {
+    pub fn from_seed(seed: &[u8]) -> Result<Self, ResultSignatureError> {
+        if seed.len() != 32 {
+            return Err(ResultSignatureError::InvalidSigningKey);
+        }
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|_| ResultSignatureError::InvalidSigningKey)?;
+        let public_key_id = public_key_id(key_pair.public_key().as_ref());
+        Ok(Self {
+            key_pair,
+            public_key_id,
+        })
+    }
+
+    /// Reads the base64 encoded seed, see `result_signing_key` in the config.
+    pub fn from_base64_seed(seed: &str) -> Result<Self, ResultSignatureError> {
+        let seed = STANDARD
+            .decode(seed.trim())
+            .map_err(|_| ResultSignatureError::InvalidSigningKey)?;
+        Self::from_seed(&seed)
+    }
+
+    /// The raw 32 byte public key.
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub fn public_key_id(&self) -> &str {
+        &self.public_key_id
+    }
+
+    /// The base64 encoded signature of the canonical JSON of `result`, which
+    /// has to carry the `public_key_id` of this key already.
+    pub fn sign<T: Serialize>(&self, result: &T) -> String {
+        let value = serde_json::to_value(result).expect("results serialize to JSON");
+        let signature = self.key_pair.sign(canonical_json(&value).as_bytes());
+        STANDARD.encode(signature.as_ref())
+    }
+}
+
+/// The id a result names its signing key with: the hex encoded first 8 bytes
+/// of the SHA-256 of the raw public key.
+pub fn public_key_id(public_key: &[u8]) -> String {
+    hex::encode(&sha256_digest(public_key)[..8])
+}
+
+/// The serialization a result is signed over: the JSON of the result without
+/// its `signature` field, where
+/// - the keys of every object are sorted by their UTF-8 bytes,
+/// - there is no whitespace outside of strings,
+/// - strings escape `"` and `\` with a backslash, backspace, form feed, line
+///   feed, carriage return and tab as `\b`, `\f`, `\n`, `\r` and `\t`, the
+///   other control characters as `\u00xx` in lower case hex, and keep
+///   everything else as is,
+/// - integers are written in decimal and other numbers in the shortest decimal
+///   form that reads back to the same double, without an exponent,
+/// - `null` fields are kept, absent ones stay absent.
+pub fn canonical_json(result: &Value) -> String {
+    let mut out = String::new();
+    match result {
+        Value::Object(fields) => write_object(
+            &mut out,
+            fields
+                .iter()
+                .filter(|(name, _)| name.as_str() != SIGNATURE_FIELD),
+        ),
+        other => write_value(&mut out, other),
+    }
+    out
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+        Value::Number(number) => {
+            if let Some(number) = number.as_u64() {
+                write!(out, "{}", number).unwrap();
+            } else if let Some(number) = number.as_i64() {
+                write!(out, "{}", number).unwrap();
+            } else {
+                // Display of f64 is the shortest round trip form, without an
+                // exponent.
+                write!(out, "{}", number.as_f64().unwrap_or_default()).unwrap();
+            }
+        }
+        Value::String(string) => write_string(out, string),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, value);
+            }
+            out.push(']');
+        }
+        Value::Object(fields) => write_object(out, fields.iter()),
+    }
+}
+
+fn write_object<'a>(out: &mut String, fields: impl Iterator<Item = (&'a String, &'a Value)>) {
+    let mut fields = fields.collect::<Vec<_>>();
+    fields.sort_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+    out.push('{');
+    for (i, (name, value)) in fields.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(out, name);
+        out.push(':');
+        write_value(out, value);
+    }
+    out.push('}');
+}
+
+fn write_string(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Checks the signature of a result as published, against the raw Ed25519
+/// public keys of the parties. Fields unknown to this version are signed as
+/// well, so prefer this over [`verify_result_event`] for results of newer
+/// parties.
+pub fn verify_result_json(
+    result: &Value,
+    allowed_keys: &[Vec<u8>],
+) -> Result<(), ResultSignatureError> {
+    let fields = result
+        .as_object()
+        .ok_or(ResultSignatureError::NotAnObject)?;
+    let (Some(Value::String(key_id)), Some(Value::String(signature))) =
+        (fields.get("public_key_id"), fields.get(SIGNATURE_FIELD))
+    else {
+        return Err(ResultSignatureError::Unsigned);
+    };
+    let public_key = allowed_keys
+        .iter()
+        .find(|key| public_key_id(key) == *key_id)
+        .ok_or_else(|| ResultSignatureError::UnknownKey(key_id.clone()))?;
+    let signature = STANDARD
+        .decode(signature)
+        .map_err(|_| ResultSignatureError::InvalidSignature)?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(canonical_json(result).as_bytes(), &signature)
+        .map_err(|_| ResultSignatureError::InvalidSignature)
+}
+
+/// Checks the signature of a uniqueness result, see [`verify_result_json`].
+pub fn verify_result_event(
+    event: &UniquenessResult,
+    allowed_keys: &[Vec<u8>],
+) -> Result<(), ResultSignatureError> {
+    let value = serde_json::to_value(event).expect("results serialize to JSON");
+    verify_result_json(&value, allowed_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MatchPolicy;
+    use serde_json::json;
+
+    /// The key of the test vectors, from the seed 0, 1, ..., 31.
+    fn test_key() -> ResultSigningKey {
+        ResultSigningKey::from_seed(&(0..32).collect::<Vec<u8>>()).unwrap()
+    }
+
+    const TEST_PUBLIC_KEY: &str = "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=";
+    const TEST_CANONICAL_JSON: &str = r#"{"backfill":null,"error":null,"error_code":null,"error_reason":null,"is_match":true,"match_policy":"or","matched_batch_request_ids":[],"matched_distances":[0.3,0.125],"matched_serial_ids":[7,12],"matched_serial_ids_left":[7],"matched_serial_ids_mirror":null,"matched_serial_ids_right":[7,12],"min_distance":0.125,"node_id":1,"public_key_id":"56475aa75463474c","serial_id":null,"signup_id":"signup-1","threshold_version":2}"#;
+    const TEST_SIGNATURE: &str =
+        "WwIpF0W2ja7W5YgwpYA8YM3ISGRnaYzI7wn/7YvlBTbLxL19edqYcF9Pca+Pf3soUjNhkCyiDde3Y+FIsKDBCQ==";
+
+    fn signed_result() -> UniquenessResult {
+        let mut result = UniquenessResult::new(
+            1,
+            None,
+            true,
+            "signup-1".to_string(),
+            Some(vec![7, 12]),
+            Some(vec![7]),
+            Some(vec![7, 12]),
+            Some(vec![]),
+        );
+        result.match_policy = Some(MatchPolicy::Or);
+        result.threshold_version = Some(2);
+        result.set_matched_distances(vec![0.3, 0.125]);
+        result.sign(&test_key());
+        result
+    }
+
+    fn allowed_keys() -> Vec<Vec<u8>> {
+        vec![
+            ResultSigningKey::from_seed(&[9; 32])
+                .unwrap()
+                .public_key()
+                .to_vec(),
+            STANDARD.decode(TEST_PUBLIC_KEY).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        let value = json!({
+            "b": "a\"\\\u{1}\n\u{1f}é",
+            "a": [1, -2, 0.5, 2.0, null, true, {"y": 1, "x": false}],
+            "signature": "dropped",
+        });
+        assert_eq!(
+            canonical_json(&value),
+            r#"{"a":[1,-2,0.5,2,null,true,{"x":false,"y":1}],"b":"a\"\\\u0001\n\u001fé"}"#
+        );
+        assert_eq!(canonical_json(&json!(1e-7)), "0.0000001");
+    }
+
+    #[test]
+    fn test_vectors() {
+        let key = test_key();
+        assert_eq!(STANDARD.encode(key.public_key()), TEST_PUBLIC_KEY);
+        assert_eq!(key.public_key_id(), "56475aa75463474c");
+
+        let result = signed_result();
+        let value = serde_json::to_value(&result).unwrap();
+        assert_eq!(canonical_json(&value), TEST_CANONICAL_JSON);
+        assert_eq!(result.signature.as_deref(), Some(TEST_SIGNATURE));
+        assert_eq!(verify_result_event(&result, &allowed_keys()), Ok(()));
+
+        // As a consumer in another language reads it from the topic.
+        let published = serde_json::to_string(&result).unwrap();
+        let parsed: Value = serde_json::from_str(&published).unwrap();
+        assert_eq!(verify_result_json(&parsed, &allowed_keys()), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_result_event() {
+        let result = signed_result();
+
+        let mut tampered = result.clone();
+        tampered.matched_serial_ids = Some(vec![7]);
+        assert_eq!(
+            verify_result_event(&tampered, &allowed_keys()),
+            Err(ResultSignatureError::InvalidSignature)
+        );
+        let mut renamed = result.clone();
+        renamed.public_key_id = Some(public_key_id(&allowed_keys()[0]));
+        assert_eq!(
+            verify_result_event(&renamed, &allowed_keys()),
+            Err(ResultSignatureError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_result_event(&result, &allowed_keys()[..1]),
+            Err(ResultSignatureError::UnknownKey(
+                "56475aa75463474c".to_string()
+            ))
+        );
+        let mut unsigned = result.clone();
+        unsigned.signature = None;
+        assert_eq!(
+            verify_result_event(&unsigned, &allowed_keys()),
+            Err(ResultSignatureError::Unsigned)
+        );
+        assert_eq!(
+            verify_result_json(&json!([]), &allowed_keys()),
+            Err(ResultSignatureError::NotAnObject)
+        );
+    }
+
+    #[test]
+    fn test_signing_key_from_base64() {
+        let seed = STANDARD.encode((0..32).collect::<Vec<u8>>());
+        let key = ResultSigningKey::from_base64_seed(&seed).unwrap();
+        assert_eq!(key.public_key(), test_key().public_key());
+        assert!(ResultSigningKey::from_base64_seed("AAAA").is_err());
+        assert!(ResultSigningKey::from_base64_seed("not base64").is_err());
+    }
+}
@@ -0,0 +1,613 @@
+//! Consumer of the result topic (SNS), through an SQS queue subscribed to it.
+//!
+//! A [`ResultStream`] polls the queue, keeps the result kinds the consumer
+//! asked for and parses them into a [`ResultMessage`]. Messages of other kinds
+//! are deleted right away, and so are malformed ones after they were handed to
+//! the error callback, so that neither comes back after its visibility timeout.
+//! A delivered message is deleted once the consumer acks it.
+
+use super::{
+    smpc_request::{
+        ReceiveRequestError, IDENTITY_DELETION_BATCH_MESSAGE_TYPE, IDENTITY_DELETION_MESSAGE_TYPE,
+        IDENTITY_RESTORE_MESSAGE_TYPE, UNIQUENESS_MESSAGE_TYPE,
+    },
+    smpc_response::{
+        IdentityDeletionBatchResult, IdentityDeletionResult, IdentityRestoreResult,
+        UniquenessResult, SMPC_MESSAGE_TYPE_ATTRIBUTE,
+    },
+};
+use async_trait::async_trait;
+use aws_sdk_sqs::Client as SQSClient;
+use futures::Stream;
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const STATS_MESSAGE_TYPE: &str = "stats";
+
+/// The kinds of messages published to the result topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResultKind {
+    Uniqueness,
+    IdentityDeletion,
+    IdentityDeletionBatch,
+    IdentityRestore,
+    Stats,
+}
+
+impl ResultKind {
+    pub const ALL: [ResultKind; 5] = [
+        ResultKind::Uniqueness,
+        ResultKind::IdentityDeletion,
+        ResultKind::IdentityDeletionBatch,
+        ResultKind::IdentityRestore,
+        ResultKind::Stats,
+    ];
+
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            ResultKind::Uniqueness => UNIQUENESS_MESSAGE_TYPE,
+            ResultKind::IdentityDeletion => IDENTITY_DELETION_MESSAGE_TYPE,
+            ResultKind::IdentityDeletionBatch => IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            ResultKind::IdentityRestore => IDENTITY_RESTORE_MESSAGE_TYPE,
+            ResultKind::Stats => STATS_MESSAGE_TYPE,
+        }
+    }
+
+    pub fn from_message_type(message_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.message_type() == message_type)
+    }
+
+    /// Tells the kind of a result without the message type attribute apart by
+    /// its fields. Only uniqueness and deletion results are recognized.
+    pub fn from_body(body: &str) -> Option<Self> {
+        [ResultKind::Uniqueness, ResultKind::IdentityDeletion]
+            .into_iter()
+            .find(|kind| ResultMessage::parse(*kind, body).is_ok())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ResultMessage {
+    Uniqueness(UniquenessResult),
+    IdentityDeletion(IdentityDeletionResult),
+    IdentityDeletionBatch(IdentityDeletionBatchResult),
+    IdentityRestore(IdentityRestoreResult),
+    /// Statistics events have no fixed schema yet.
+    Stats(serde_json::Value),
+}
+
+impl ResultMessage {
+    pub fn kind(&self) -> ResultKind {
+        match self {
+            ResultMessage::Uniqueness(_) => ResultKind::Uniqueness,
+            ResultMessage::IdentityDeletion(_) => ResultKind::IdentityDeletion,
+            ResultMessage::IdentityDeletionBatch(_) => ResultKind::IdentityDeletionBatch,
+            ResultMessage::IdentityRestore(_) => ResultKind::IdentityRestore,
+            ResultMessage::Stats(_) => ResultKind::Stats,
+        }
+    }
+
+    /// Parses the body of a result of the given kind.
+    pub fn parse(kind: ResultKind, body: &str) -> serde_json::Result<Self> {
+        Ok(match kind {
+            ResultKind::Uniqueness => ResultMessage::Uniqueness(serde_json::from_str(body)?),
+            ResultKind::IdentityDeletion => {
+                ResultMessage::IdentityDeletion(serde_json::from_str(body)?)
+            }
+            ResultKind::IdentityDeletionBatch => {
+                ResultMessage::IdentityDeletionBatch(serde_json::from_str(body)?)
+            }
+            ResultKind::IdentityRestore => {
+                ResultMessage::IdentityRestore(serde_json::from_str(body)?)
+            }
+            ResultKind::Stats => ResultMessage::Stats(serde_json::from_str(body)?),
+        })
+    }
+}
+
+/// A message received from the results queue.
+#[derive(Debug, Clone)]
+pub struct ResultQueueMessage {
+    pub body:           String,
+    pub receipt_handle: String,
+    /// The [`SMPC_MESSAGE_TYPE_ATTRIBUTE`] of the message.
+    pub message_type:   Option<String>,
+}
+
+#[async_trait]
+pub trait ResultQueue: Send + Sync {
+    /// Receives up to `max_messages` messages, waiting at most `wait_time` for
+    /// the first one.
+    async fn receive(
+        &self,
+        max_messages: i32,
+        wait_time: Duration,
+    ) -> Result<Vec<ResultQueueMessage>, ReceiveRequestError>;
+
+    async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError>;
+
+    async fn change_visibility(
+        &self,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), ReceiveRequestError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SqsResultQueue {
+    client:    SQSClient,
+    queue_url: String,
+}
+
+impl SqsResultQueue {
+    pub fn new(client: SQSClient, queue_url: String) -> Self {
+        Self { client, queue_url }
+    }
+}
+
+#[async_trait]
+impl ResultQueue for SqsResultQueue {
+    async fn receive(
+        &self,
+        max_messages: i32,
+        wait_time: Duration,
+    ) -> Result<Vec<ResultQueueMessage>, ReceiveRequestError> {
+        let output = self
+            .client
+            .receive_message()
+            .max_number_of_messages(max_messages)
+            .wait_time_seconds(wait_time.as_secs().min(20) as i32)
+            .message_attribute_names(SMPC_MESSAGE_TYPE_ATTRIBUTE)
+            .queue_url(&self.queue_url)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToReadFromSQS)?;
+
+        Ok(output
+            .messages
+            .unwrap_or_default()
+            .into_iter()
+            .map(|message| ResultQueueMessage {
+                message_type:   message
+                    .message_attributes
+                    .as_ref()
+                    .and_then(|attributes| attributes.get(SMPC_MESSAGE_TYPE_ATTRIBUTE))
+                    .and_then(|attribute| attribute.string_value())
+                    .map(str::to_string),
+                body:           message.body.unwrap_or_default(),
+                receipt_handle: message.receipt_handle.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+        self.client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToDeleteFromSQS)?;
+        Ok(())
+    }
+
+    async fn change_visibility(
+        &self,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), ReceiveRequestError> {
+        self.client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(timeout.as_secs().min(i32::MAX as u64) as i32)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToChangeVisibility)?;
+        Ok(())
+    }
+}
+
+/// A message that could not be parsed. It is deleted from the queue after the
+/// error callback returns.
+#[derive(Debug, Clone)]
+pub struct MalformedResult {
+    pub message_type: Option<String>,
+    pub body:         String,
+    pub reason:       String,
+}
+
+impl fmt::Display for MalformedResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed result of type {:?}: {}",
+            self.message_type, self.reason
+        )
+    }
+}
+
+type ErrorCallback = Box<dyn Fn(MalformedResult) + Send + Sync>;
+
+/// How a [`ResultStream`] polls the queue.
+#[derive(Debug, Clone)]
+pub struct ResultStreamConfig {
+    /// The kinds to deliver, all others are deleted unseen.
+    pub kinds:              HashSet<ResultKind>,
+    pub max_messages:       i32,
+    /// Long polling wait time of a receive.
+    pub wait_time:          Duration,
+    /// If set, the visibility of every delivered message is extended to this
+    /// timeout, to give the consumer time to process it before acking.
+    pub visibility_timeout: Option<Duration>,
+}
+
+impl Default for ResultStreamConfig {
+    fn default() -> Self {
+        Self {
+            kinds:              ResultKind::ALL.into_iter().collect(),
+            max_messages:       10,
+            wait_time:          Duration::from_secs(20),
+            visibility_timeout: None,
+        }
+    }
+}
+
+/// A delivered result. It stays in the queue until [`Self::ack`] is called.
+pub struct ReceivedResult {
+    pub message:    ResultMessage,
+    receipt_handle: String,
+    queue:          Arc<dyn ResultQueue>,
+}
+
+impl fmt::Debug for ReceivedResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReceivedResult")
+            .field("message", &self.message)
+            .field("receipt_handle", &self.receipt_handle)
+            .finish()
+    }
+}
+
+impl ReceivedResult {
+    /// Deletes the message from the queue.
+    pub async fn ack(self) -> Result<(), ReceiveRequestError> {
+        self.queue.delete(&self.receipt_handle).await
+    }
+
+    /// Keeps the message hidden from other consumers for `timeout` from now
+    /// on.
+    pub async fn extend_visibility(&self, timeout: Duration) -> Result<(), ReceiveRequestError> {
+        self.queue
+            .change_visibility(&self.receipt_handle, timeout)
+            .await
+    }
+}
+
+/// Ends a [`ResultStream`] after the messages it already received.
+#[derive(Debug, Clone, Default)]
+pub struct ResultStreamShutdown(Arc<AtomicBool>);
+
+impl ResultStreamShutdown {
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ResultStream {
+    queue:    Arc<dyn ResultQueue>,
+    config:   ResultStreamConfig,
+    on_error: ErrorCallback,
+    shutdown: ResultStreamShutdown,
+    buffered: VecDeque<ReceivedResult>,
+}
+
+impl ResultStream {
+    pub fn new(queue: Arc<dyn ResultQueue>, config: ResultStreamConfig) -> Self {
+        Self {
+            queue,
+            config,
+            on_error: Box::new(|malformed| tracing::warn!("Dropping {}", malformed)),
+            shutdown: ResultStreamShutdown::default(),
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// Sets the callback for malformed messages, which are logged by default.
+    pub fn on_error(mut self, on_error: impl Fn(MalformedResult) + Send + Sync + 'static) -> Self {
+        self.on_error = Box::new(on_error);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ResultStreamShutdown {
+        self.shutdown.clone()
+    }
+
+    /// Returns the next delivered result, or `None` once shut down.
+    pub async fn next_result(&mut self) -> Option<Result<ReceivedResult, ReceiveRequestError>> {
+        loop {
+            if let Some(result) = self.buffered.pop_front() {
+                return Some(Ok(result));
+            }
+            if self.shutdown.is_shut_down() {
+                return None;
+            }
+            if let Err(e) = self.poll_queue().await {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// The results as a [`Stream`].
+    pub fn into_stream(self) -> impl Stream<Item = Result<ReceivedResult, ReceiveRequestError>> {
+        futures::stream::unfold(self, |mut stream| async move {
+            let item = stream.next_result().await?;
+            Some((item, stream))
+        })
+    }
+
+    async fn poll_queue(&mut self) -> Result<(), ReceiveRequestError> {
+        let messages = self
+            .queue
+            .receive(self.config.max_messages, self.config.wait_time)
+            .await?;
+        for message in messages {
+            match self.parse(&message) {
+                Ok(Some(result)) => {
+                    if let Some(timeout) = self.config.visibility_timeout {
+                        self.queue
+                            .change_visibility(&message.receipt_handle, timeout)
+                            .await?;
+                    }
+                    self.buffered.push_back(ReceivedResult {
+                        message:        result,
+                        receipt_handle: message.receipt_handle,
+                        queue:          self.queue.clone(),
+                    });
+                }
+                Ok(None) => self.queue.delete(&message.receipt_handle).await?,
+                Err(malformed) => {
+                    (self.on_error)(malformed);
+                    self.queue.delete(&message.receipt_handle).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a message, or returns `None` if it is of a kind that is not
+    /// consumed.
+    fn parse(
+        &self,
+        message: &ResultQueueMessage,
+    ) -> Result<Option<ResultMessage>, MalformedResult> {
+        let malformed = |reason: String| MalformedResult {
+            message_type: message.message_type.clone(),
+            body: message.body.clone(),
+            reason,
+        };
+        let kind = match message.message_type.as_deref() {
+            Some(message_type) => ResultKind::from_message_type(message_type)
+                .ok_or_else(|| malformed("unknown message type".to_string()))?,
+            None => ResultKind::from_body(&message.body)
+                .ok_or_else(|| malformed("no message type attribute".to_string()))?,
+        };
+        if !self.config.kinds.contains(&kind) {
+            return Ok(None);
+        }
+        ResultMessage::parse(kind, &message.body)
+            .map(Some)
+            .map_err(|e| malformed(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::smpc_response::{IdentityDeletionResult, UniquenessResult};
+    use futures::StreamExt;
+    use std::sync::Mutex;
+
+    /// In-memory queue that records deletions and visibility changes.
+    #[derive(Default)]
+    struct MockQueue {
+        messages:   Mutex<VecDeque<ResultQueueMessage>>,
+        deleted:    Mutex<Vec<String>>,
+        visibility: Mutex<Vec<String>>,
+    }
+
+    impl MockQueue {
+        fn push(&self, message_type: Option<&str>, body: String) {
+            let mut messages = self.messages.lock().unwrap();
+            let receipt_handle = format!("handle-{}", messages.len());
+            messages.push_back(ResultQueueMessage {
+                body,
+                receipt_handle,
+                message_type: message_type.map(str::to_string),
+            });
+        }
+
+        fn deleted(&self) -> Vec<String> {
+            self.deleted.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl ResultQueue for MockQueue {
+        async fn receive(
+            &self,
+            max_messages: i32,
+            wait_time: Duration,
+        ) -> Result<Vec<ResultQueueMessage>, ReceiveRequestError> {
+            let received = {
+                let mut messages = self.messages.lock().unwrap();
+                let n = messages.len().min(max_messages as usize);
+                messages.drain(..n).collect::<Vec<_>>()
+            };
+            if received.is_empty() {
+                tokio::time::sleep(wait_time).await;
+            }
+            Ok(received)
+        }
+
+        async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+            self.deleted
+                .lock()
+                .unwrap()
+                .push(receipt_handle.to_string());
+            Ok(())
+        }
+
+        async fn change_visibility(
+            &self,
+            receipt_handle: &str,
+            _timeout: Duration,
+        ) -> Result<(), ReceiveRequestError> {
+            self.visibility
+                .lock()
+                .unwrap()
+                .push(receipt_handle.to_string());
+            Ok(())
+        }
+    }
+
+    fn uniqueness(signup_id: &str) -> String {
+        serde_json::to_string(&UniquenessResult::new(
+            0,
+            Some(1),
+            false,
+            signup_id.to_string(),
+            None,
+            None,
+            None,
+            None,
+        ))
+        .unwrap()
+    }
+
+    fn deletion(serial_id: u32) -> String {
+        serde_json::to_string(&IdentityDeletionResult::new(1, serial_id, true)).unwrap()
+    }
+
+    fn config(kinds: &[ResultKind]) -> ResultStreamConfig {
+        ResultStreamConfig {
+            kinds:              kinds.iter().copied().collect(),
+            max_messages:       2,
+            wait_time:          Duration::from_millis(10),
+            visibility_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixed_kinds() {
+        let queue = Arc::new(MockQueue::default());
+        queue.push(Some(UNIQUENESS_MESSAGE_TYPE), uniqueness("a"));
+        queue.push(Some(IDENTITY_DELETION_MESSAGE_TYPE), deletion(7));
+        queue.push(Some(STATS_MESSAGE_TYPE), r#"{"batch_size": 3}"#.to_string());
+        queue.push(None, deletion(8));
+        queue.push(None, uniqueness("b"));
+
+        let mut stream = ResultStream::new(
+            queue.clone(),
+            config(&[ResultKind::Uniqueness, ResultKind::Stats]),
+        )
+        .into_stream()
+        .boxed();
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            let result = stream.next().await.unwrap().unwrap();
+            received.push(match &result.message {
+                ResultMessage::Uniqueness(result) => result.signup_id.clone(),
+                ResultMessage::Stats(stats) => stats["batch_size"].to_string(),
+                ResultMessage::IdentityDeletion(_)
+                | ResultMessage::IdentityDeletionBatch(_)
+                | ResultMessage::IdentityRestore(_) => {
+                    panic!("deletions and restores are filtered out")
+                }
+            });
+            result.ack().await.unwrap();
+        }
+        assert_eq!(received, vec!["a", "3", "b"]);
+
+        // Filtered messages are deleted unseen, delivered ones once acked.
+        let mut deleted = queue.deleted();
+        deleted.sort();
+        assert_eq!(deleted, vec![
+            "handle-0", "handle-1", "handle-2", "handle-3", "handle-4"
+        ]);
+        assert_eq!(queue.visibility.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_bodies() {
+        let queue = Arc::new(MockQueue::default());
+        queue.push(Some(UNIQUENESS_MESSAGE_TYPE), "not json".to_string());
+        queue.push(Some("unknown"), uniqueness("a"));
+        queue.push(None, "{}".to_string());
+        queue.push(Some(IDENTITY_DELETION_MESSAGE_TYPE), deletion(7));
+
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors_clone = errors.clone();
+        let mut stream = ResultStream::new(queue.clone(), config(&ResultKind::ALL))
+            .on_error(move |malformed| errors_clone.lock().unwrap().push(malformed));
+
+        let result = stream.next_result().await.unwrap().unwrap();
+        assert!(matches!(
+            result.message,
+            ResultMessage::IdentityDeletion(IdentityDeletionResult { serial_id: 7, .. })
+        ));
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| e.message_type.as_deref())
+                .collect::<Vec<_>>(),
+            vec![Some(UNIQUENESS_MESSAGE_TYPE), Some("unknown"), None]
+        );
+        assert_eq!(errors[0].body, "not json");
+        // The malformed messages are deleted, the delivered one is not acked.
+        assert_eq!(queue.deleted(), vec!["handle-0", "handle-1", "handle-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let queue = Arc::new(MockQueue::default());
+        queue.push(Some(UNIQUENESS_MESSAGE_TYPE), uniqueness("a"));
+        queue.push(Some(UNIQUENESS_MESSAGE_TYPE), uniqueness("b"));
+        queue.push(Some(UNIQUENESS_MESSAGE_TYPE), uniqueness("c"));
+
+        let stream = ResultStream::new(queue.clone(), config(&ResultKind::ALL));
+        let shutdown = stream.shutdown_handle();
+        let mut stream = stream.into_stream().boxed();
+
+        let first = stream.next().await.unwrap().unwrap();
+        first.ack().await.unwrap();
+        shutdown.shutdown();
+
+        // The already received message is still delivered, then the stream
+        // ends without receiving more.
+        let second = stream.next().await.unwrap().unwrap();
+        assert!(matches!(
+            &second.message,
+            ResultMessage::Uniqueness(result) if result.signup_id == "b"
+        ));
+        assert!(stream.next().await.is_none());
+        assert_eq!(queue.messages.lock().unwrap().len(), 1);
+        assert_eq!(queue.deleted(), vec!["handle-0"]);
+    }
+}
@@ -1,5 +1,62 @@
 use sha2::{Digest, Sha256};
+use std::io;
 
 pub fn calculate_sha256<T: AsRef<[u8]>>(data: T) -> String {
-    hex::encode(Sha256::digest(data.as_ref()))
+    hex::encode(sha256_digest(data))
+}
+
+pub fn sha256_digest<T: AsRef<[u8]>>(data: T) -> [u8; 32] {
+    Sha256::digest(data.as_ref()).into()
+}
+
+/// SHA-256 over data that arrives in chunks, like a response body, without
+/// holding all of it. Equal to [`sha256_digest`] of the concatenated chunks.
+#[derive(Debug, Clone, Default)]
+pub struct Sha256Stream {
+    hasher: Sha256,
+}
+
+impl Sha256Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update<T: AsRef<[u8]>>(&mut self, chunk: T) {
+        self.hasher.update(chunk.as_ref());
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+
+    /// Like [`calculate_sha256`].
+    pub fn finalize_hex(self) -> String {
+        hex::encode(self.finalize())
+    }
+}
+
+/// Hashes everything written, so that serializers can write into it.
+impl io::Write for Sha256Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `digest` equals the hex encoded `expected`, compared in constant
+/// time. A malformed `expected` never matches.
+pub fn digest_eq_hex(digest: &[u8; 32], expected: &str) -> bool {
+    let mut decoded = [0u8; 32];
+    if hex::decode_to_slice(expected, &mut decoded).is_err() {
+        return false;
+    }
+    let diff = digest
+        .iter()
+        .zip(decoded.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
 }
@@ -0,0 +1,352 @@
+//! Consistency audit of the stored shares.
+//!
+//! The code and mask shares are Shamir shares of degree 1, so the shares of
+//! the three parties for the same serial id are redundant: reconstructing from
+//! parties 0 and 1 must give the same values as reconstructing from parties 0
+//! and 2. With the Lagrange coefficients at zero `l_{i,j}` of party `i` in the
+//! pair `(i, j)`, this means
+//!
+//! ```text
+//! (l_{0,1} - l_{0,2}) s_0 + l_{1,0} s_1 - l_{2,0} s_2 = 0
+//! ```
+//!
+//! for every Galois ring element of the shares. A party whose stored row is
+//! corrupted breaks this equation for that row.
+//!
+//! The parties derive a sample of serial ids and random weights from a shared
+//! seed and the batch counter, see [`ShareAuditChallenge`]. Each party sends
+//! the weighted sums of its term of the equation, blinded with a sharing of
+//! zero, see [`ShareAuditContribution`]. The blinded values of all parties sum
+//! to zero for consistent rows, and reveal nothing else: the shares themselves
+//! never leave the party.
+
+use crate::{
+    galois::degree4::{basis::Monomial, GaloisRingElement, ShamirGaloisRingShare},
+    id::PartyID,
+};
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Number of independent weighted sums per serial id. A corrupted row goes
+/// unnoticed by a single sum with probability at most 1/2.
+pub const SHARE_AUDIT_CHECKS: usize = 16;
+
+const N_PARTIES: usize = 3;
+
+/// The sample and the weights of one audit, derived from the shared seed and
+/// the batch counter. All parties derive the same challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareAuditChallenge {
+    nonce: [u8; 32],
+}
+
+impl ShareAuditChallenge {
+    /// Derives the shared seed from a random contribution of every party, so
+    /// that no party controls the sample.
+    pub fn joint_seed(contributions: &[[u8; 32]]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"iris-mpc share audit seed");
+        for contribution in contributions {
+            hasher.update(contribution);
+        }
+        hasher.finalize().into()
+    }
+
+    pub fn new(seed: &[u8; 32], batch_counter: u64) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"iris-mpc share audit");
+        hasher.update(seed);
+        hasher.update(batch_counter.to_le_bytes());
+        Self {
+            nonce: hasher.finalize().into(),
+        }
+    }
+
+    /// Up to `sample_size` distinct serial ids of a database with `db_len`
+    /// entries, in ascending order.
+    pub fn sample(&self, db_len: usize, sample_size: usize) -> Vec<u32> {
+        let mut rng = StdRng::from_seed(self.nonce);
+        let mut serial_ids = index::sample(&mut rng, db_len, sample_size.min(db_len))
+            .into_iter()
+            .map(|index| index as u32 + 1)
+            .collect::<Vec<_>>();
+        serial_ids.sort_unstable();
+        serial_ids
+    }
+
+    fn weights(&self, serial_id: u32) -> StdRng {
+        let mut hasher = Sha256::new();
+        hasher.update(self.nonce);
+        hasher.update(serial_id.to_le_bytes());
+        StdRng::from_seed(hasher.finalize().into())
+    }
+
+    /// The weighted sums of the term of `party_id` in the consistency
+    /// equation, for the shares stored under `serial_id`. Every slice holds
+    /// Galois ring elements in the monomial basis, like the coefficients of
+    /// the code and mask shares, and all parties must pass the same kind of
+    /// shares in the same order.
+    pub fn check_values(
+        &self,
+        party_id: PartyID,
+        serial_id: u32,
+        shares: &[&[u16]],
+    ) -> [u16; SHARE_AUDIT_CHECKS] {
+        let factor = consistency_factor(party_id);
+        let mut weights = self.weights(serial_id);
+        let mut values = [0u16; SHARE_AUDIT_CHECKS];
+        for share in shares {
+            assert_eq!(share.len() % 4, 0, "shares hold whole ring elements");
+            for element in share.chunks_exact(4) {
+                let term = GaloisRingElement::<Monomial>::from_coefs([
+                    element[0], element[1], element[2], element[3],
+                ]) * factor;
+                for coef in term.coefs {
+                    for value in values.iter_mut() {
+                        *value = value.wrapping_add(weights.gen::<u16>().wrapping_mul(coef));
+                    }
+                }
+            }
+        }
+        values
+    }
+}
+
+/// The factor of the share of `party_id` in the consistency equation.
+fn consistency_factor(party_id: PartyID) -> GaloisRingElement<Monomial> {
+    use PartyID::{ID0, ID1, ID2};
+    let lagrange = ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero;
+    match party_id {
+        ID0 => lagrange(ID0, ID1) - lagrange(ID0, ID2),
+        ID1 => lagrange(ID1, ID0),
+        ID2 => -lagrange(ID2, ID0),
+    }
+}
+
+/// What a party sends in an audit: its blinded check values for every sampled
+/// serial id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareAuditContribution {
+    pub party_id:   usize,
+    pub serial_ids: Vec<u32>,
+    pub values:     Vec<[u16; SHARE_AUDIT_CHECKS]>,
+}
+
+impl ShareAuditContribution {
+    /// Computes the contribution for the sampled rows. `rows` yields the
+    /// serial id and the shares of every sampled row, see
+    /// [`ShareAuditChallenge::check_values`]. `zero_share` returns the next
+    /// share of zero, so that the values of all parties sum to zero, e.g. the
+    /// difference of the own and the previous party's correlated randomness.
+    pub fn new<'a>(
+        challenge: &ShareAuditChallenge,
+        party_id: PartyID,
+        rows: impl IntoIterator<Item = (u32, Vec<&'a [u16]>)>,
+        mut zero_share: impl FnMut() -> u16,
+    ) -> Self {
+        let (serial_ids, values) = rows
+            .into_iter()
+            .map(|(serial_id, shares)| {
+                let mut values = challenge.check_values(party_id, serial_id, &shares);
+                for value in values.iter_mut() {
+                    *value = value.wrapping_add(zero_share());
+                }
+                (serial_id, values)
+            })
+            .unzip();
+        Self {
+            party_id: usize::from(party_id),
+            serial_ids,
+            values,
+        }
+    }
+}
+
+/// Shares of zero from the correlated randomness: `own_seed` is shared with
+/// the next party and `prev_seed` with the previous one. The streams are
+/// derived from the seeds, so that they differ from those of the protocols
+/// using the same seeds.
+pub fn zero_shares(own_seed: &[u8], prev_seed: &[u8]) -> impl FnMut() -> u16 {
+    let stream = |seed: &[u8]| {
+        let mut hasher = Sha256::new();
+        hasher.update(b"iris-mpc share audit zero shares");
+        hasher.update(seed);
+        StdRng::from_seed(hasher.finalize().into())
+    };
+    let (mut own, mut prev) = (stream(own_seed), stream(prev_seed));
+    move || own.gen::<u16>().wrapping_sub(prev.gen::<u16>())
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ShareAuditError {
+    #[error("Expected the contributions of parties 0, 1 and 2, got {0:?}")]
+    WrongParties(Vec<usize>),
+    #[error("Party {party_id} audited serial ids {serial_ids:?}, expected {expected:?}")]
+    SampleMismatch {
+        party_id:   usize,
+        serial_ids: Vec<u32>,
+        expected:   Vec<u32>,
+    },
+}
+
+/// Returns the serial ids whose shares are inconsistent across the parties.
+pub fn find_inconsistent(
+    contributions: &[ShareAuditContribution],
+) -> Result<Vec<u32>, ShareAuditError> {
+    let mut parties = contributions.iter().map(|c| c.party_id).collect::<Vec<_>>();
+    parties.sort_unstable();
+    if parties != (0..N_PARTIES).collect::<Vec<_>>() {
+        return Err(ShareAuditError::WrongParties(parties));
+    }
+    let expected = &contributions[0].serial_ids;
+    for contribution in contributions {
+        if &contribution.serial_ids != expected || contribution.values.len() != expected.len() {
+            return Err(ShareAuditError::SampleMismatch {
+                party_id:   contribution.party_id,
+                serial_ids: contribution.serial_ids.clone(),
+                expected:   expected.clone(),
+            });
+        }
+    }
+    Ok(expected
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| {
+            (0..SHARE_AUDIT_CHECKS).any(|k| {
+                contributions
+                    .iter()
+                    .fold(0u16, |sum, c| sum.wrapping_add(c.values[i][k]))
+                    != 0
+            })
+        })
+        .map(|(_, &serial_id)| serial_id)
+        .collect())
+}
+
+/// Exports the outcome of an audit of `n_audited` serial ids and alerts on
+/// inconsistent ones.
+pub fn report_audit(n_audited: usize, inconsistent: &[u32]) {
+    metrics::counter!("share_audit.audited").increment(n_audited as u64);
+    if inconsistent.is_empty() {
+        tracing::info!("Share audit of {} serial ids passed", n_audited);
+    } else {
+        metrics::counter!("share_audit.mismatches").increment(inconsistent.len() as u64);
+        tracing::error!(
+            serial_ids = ?inconsistent,
+            "Share audit found inconsistent shares for {} of {} serial ids",
+            inconsistent.len(),
+            n_audited
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+        iris_db::iris::IrisCode,
+    };
+    use rand::thread_rng;
+
+    type Row = (GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare);
+
+    fn random_rows(n: usize) -> Vec<[Row; 3]> {
+        let rng = &mut thread_rng();
+        (0..n)
+            .map(|_| {
+                let iris = IrisCode::random_rng(rng);
+                let codes = GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng);
+                let masks = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, rng);
+                std::array::from_fn(|i| (codes[i].clone(), (&masks[i]).into()))
+            })
+            .collect()
+    }
+
+    fn audit(rows: &[[Row; 3]], sample: &[u32]) -> Vec<ShareAuditContribution> {
+        let challenge = ShareAuditChallenge::new(&[7; 32], 3);
+        (0..N_PARTIES)
+            .map(|i| {
+                // Party i shares its own seed with party i + 1.
+                let prev = (i + N_PARTIES - 1) % N_PARTIES;
+                ShareAuditContribution::new(
+                    &challenge,
+                    PartyID::try_from(i).unwrap(),
+                    sample.iter().map(|&serial_id| {
+                        let (code, mask) = &rows[serial_id as usize - 1][i];
+                        (serial_id, vec![&code.coefs[..], &mask.coefs[..]])
+                    }),
+                    zero_shares(&[i as u8], &[prev as u8]),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_is_shared() {
+        let challenge = ShareAuditChallenge::new(&[1; 32], 10);
+        let sample = challenge.sample(100, 8);
+        assert_eq!(sample.len(), 8);
+        assert!(sample.windows(2).all(|w| w[0] < w[1]));
+        assert!(sample.iter().all(|&id| (1..=100).contains(&id)));
+        assert_eq!(
+            sample,
+            ShareAuditChallenge::new(&[1; 32], 10).sample(100, 8)
+        );
+        assert_ne!(
+            sample,
+            ShareAuditChallenge::new(&[1; 32], 11).sample(100, 8)
+        );
+        assert_eq!(challenge.sample(3, 8), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_consistent_shares_pass() {
+        let rows = random_rows(4);
+        let contributions = audit(&rows, &[1, 2, 3, 4]);
+        assert_eq!(find_inconsistent(&contributions), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_corrupted_row_is_flagged() {
+        for party in 0..N_PARTIES {
+            let mut rows = random_rows(4);
+            rows[2][party].0.coefs[1000] ^= 1;
+            let contributions = audit(&rows, &[1, 2, 3, 4]);
+            assert_eq!(find_inconsistent(&contributions), Ok(vec![3]));
+
+            let mut rows = random_rows(4);
+            rows[0][party].1.coefs[17] = rows[0][party].1.coefs[17].wrapping_add(1 << 15);
+            let contributions = audit(&rows, &[1, 2, 3, 4]);
+            assert_eq!(find_inconsistent(&contributions), Ok(vec![1]));
+        }
+    }
+
+    #[test]
+    fn test_values_are_blinded() {
+        let rows = random_rows(1);
+        let challenge = ShareAuditChallenge::new(&[7; 32], 3);
+        let contributions = audit(&rows, &[1]);
+        let (code, mask) = &rows[0][1];
+        let unblinded =
+            challenge.check_values(PartyID::ID1, 1, &[&code.coefs[..], &mask.coefs[..]]);
+        assert_ne!(contributions[1].values[0], unblinded);
+    }
+
+    #[test]
+    fn test_sample_mismatch() {
+        let rows = random_rows(2);
+        let mut contributions = audit(&rows, &[1, 2]);
+        contributions[2].serial_ids = vec![1, 3];
+        assert!(matches!(
+            find_inconsistent(&contributions),
+            Err(ShareAuditError::SampleMismatch { party_id: 2, .. })
+        ));
+        assert_eq!(
+            find_inconsistent(&contributions[..2]),
+            Err(ShareAuditError::WrongParties(vec![0, 1]))
+        );
+    }
+}
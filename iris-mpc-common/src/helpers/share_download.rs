@@ -0,0 +1,302 @@
+//! Limits and retries of the share downloads. A download is capped in size
+//! and time, so that a broken or malicious upload cannot stall a batch. Only
+//! failures that may pass on their own are retried: server errors, failed
+//! connections and timeouts. A file that is denied, missing or too large fails
+//! at once.
+
+#[cfg(feature = "aws")]
+use super::{key_pair::SharesDecodingError, sha256::Sha256Stream};
+#[cfg(feature = "aws")]
+use aws_sdk_s3::{config::http::HttpResponse, error::SdkError};
+#[cfg(feature = "aws")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "aws")]
+use std::future::Future;
+use std::time::Duration;
+#[cfg(feature = "aws")]
+use tokio_retry::{
+    strategy::{ExponentialBackoff, FixedInterval},
+    RetryIf,
+};
+
+/// The size and time limits of the share downloads, and their retries. The
+/// timeouts are in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShareDownloadConfig {
+    pub retry:              DownloadRetryConfig,
+    /// The largest shares file that is read. A shares file of three parties
+    /// takes about 100 KB.
+    pub max_response_bytes: usize,
+    pub connect_timeout_ms: u64,
+    /// The longest wait for the next bytes of a response.
+    pub read_timeout_ms:    u64,
+}
+
+impl Default for ShareDownloadConfig {
+    fn default() -> Self {
+        Self {
+            retry:              DownloadRetryConfig::default(),
+            max_response_bytes: 4 << 20,
+            connect_timeout_ms: 5_000,
+            read_timeout_ms:    10_000,
+        }
+    }
+}
+
+impl ShareDownloadConfig {
+    /// The client of the presigned URL downloads, with the timeouts set.
+    #[cfg(feature = "aws")]
+    pub fn http_client(&self) -> Result<reqwest::Client, reqwest::Error> {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(self.connect_timeout_ms))
+            .read_timeout(Duration::from_millis(self.read_timeout_ms))
+            .build()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadBackoff {
+    /// Every retry waits `base_delay_ms`.
+    Fixed,
+    /// The retries wait `base_delay_ms`, then twice as long each time.
+    #[default]
+    Exponential,
+}
+
+/// The retry policy of the share downloads. The delays are in milliseconds and
+/// capped at `max_delay_ms`. A `total_timeout_ms` of 0 disables the timeout of
+/// the download including its retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadRetryConfig {
+    /// The attempts including the first one.
+    pub max_attempts:     usize,
+    pub base_delay_ms:    u64,
+    pub max_delay_ms:     u64,
+    pub backoff:          DownloadBackoff,
+    pub total_timeout_ms: u64,
+}
+
+impl Default for DownloadRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts:     5,
+            base_delay_ms:    200,
+            max_delay_ms:     5_000,
+            backoff:          DownloadBackoff::Exponential,
+            total_timeout_ms: 30_000,
+        }
+    }
+}
+
+impl DownloadRetryConfig {
+    /// A single attempt, without a timeout.
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            total_timeout_ms: 0,
+            ..Self::default()
+        }
+    }
+
+    pub fn total_timeout(&self) -> Option<Duration> {
+        (self.total_timeout_ms > 0).then(|| Duration::from_millis(self.total_timeout_ms))
+    }
+
+    /// The delays before the retries, one less than the attempts.
+    #[cfg(feature = "aws")]
+    pub fn delays(&self) -> Vec<Duration> {
+        let retries = self.max_attempts.saturating_sub(1);
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+        match self.backoff {
+            DownloadBackoff::Fixed => FixedInterval::from_millis(self.base_delay_ms)
+                .take(retries)
+                .map(|delay| delay.min(max_delay))
+                .collect(),
+            // Yields 2 * base, 4 * base, ..., halved to start at the base.
+            DownloadBackoff::Exponential => ExponentialBackoff::from_millis(2)
+                .factor(self.base_delay_ms)
+                .take(retries)
+                .map(|delay| (delay / 2).min(max_delay))
+                .collect(),
+        }
+    }
+}
+
+/// A failed download attempt, and whether another one may succeed.
+#[cfg(feature = "aws")]
+#[derive(Debug)]
+pub(crate) struct DownloadFailure {
+    pub error:     SharesDecodingError,
+    pub retryable: bool,
+}
+
+#[cfg(feature = "aws")]
+impl DownloadFailure {
+    pub fn fatal(error: impl Into<SharesDecodingError>) -> Self {
+        Self {
+            error:     error.into(),
+            retryable: false,
+        }
+    }
+}
+
+/// Fails the download if the `content_length` the server announced exceeds
+/// `max_bytes`.
+#[cfg(feature = "aws")]
+pub(crate) fn check_content_length(
+    content_length: Option<u64>,
+    max_bytes: usize,
+) -> Result<(), DownloadFailure> {
+    match content_length {
+        Some(length) if length > max_bytes as u64 => Err(DownloadFailure::fatal(
+            SharesDecodingError::ResponseTooLarge { max_bytes },
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A response body read in chunks of at most `max_bytes` in total, hashed
+/// with SHA-256 as the chunks arrive.
+#[cfg(feature = "aws")]
+pub(crate) struct HashedBody {
+    bytes:     Vec<u8>,
+    hasher:    Sha256Stream,
+    max_bytes: usize,
+}
+
+#[cfg(feature = "aws")]
+impl HashedBody {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            bytes: vec![],
+            hasher: Sha256Stream::new(),
+            max_bytes,
+        }
+    }
+
+    /// Appends a chunk, unless the body grows beyond `max_bytes`.
+    pub fn append(&mut self, chunk: &[u8]) -> Result<(), DownloadFailure> {
+        if self.bytes.len() + chunk.len() > self.max_bytes {
+            return Err(DownloadFailure::fatal(
+                SharesDecodingError::ResponseTooLarge {
+                    max_bytes: self.max_bytes,
+                },
+            ));
+        }
+        self.hasher.update(chunk);
+        self.bytes.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    /// Returns the body, unless it differs from `checksum`, the base64 SHA-256
+    /// the store announced for the object. A mismatch is retried, the body
+    /// was damaged on the way. Composite checksums of multipart uploads,
+    /// `<base64>-<parts>`, do not cover the body as a whole and are skipped.
+    pub fn finish(self, checksum: Option<&str>) -> Result<Vec<u8>, DownloadFailure> {
+        let computed = STANDARD.encode(self.hasher.finalize());
+        match checksum {
+            Some(expected) if !expected.contains('-') && expected != computed => {
+                Err(DownloadFailure {
+                    error:     SharesDecodingError::BodyChecksumMismatch {
+                        expected: expected.to_string(),
+                        computed,
+                    },
+                    retryable: true,
+                })
+            }
+            _ => Ok(self.bytes),
+        }
+    }
+}
+
+/// Whether an S3 request failed with a timeout, a failed connection or a
+/// server error.
+#[cfg(feature = "aws")]
+pub(crate) fn is_retryable_sdk_error<E>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        _ => err
+            .raw_response()
+            .is_some_and(|response| response.status().is_server_error()),
+    }
+}
+
+/// Runs `attempt` until it succeeds, fails for good or runs out of attempts
+/// under `config`.
+#[cfg(feature = "aws")]
+pub(crate) async fn retry_download<T, A, F>(
+    config: &DownloadRetryConfig,
+    attempt: A,
+) -> Result<T, SharesDecodingError>
+where
+    A: FnMut() -> F,
+    F: Future<Output = Result<T, DownloadFailure>>,
+{
+    let download = RetryIf::spawn(config.delays(), attempt, |failure: &DownloadFailure| {
+        if failure.retryable {
+            tracing::warn!("Retrying share download: {}", failure.error);
+            metrics::counter!("share_download.retry").increment(1);
+        }
+        failure.retryable
+    });
+    let result = match config.total_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, download)
+            .await
+            .map_err(|_| SharesDecodingError::DownloadTimeout(timeout))?,
+        None => download.await,
+    };
+    result.map_err(|failure| failure.error)
+}
+
+#[cfg(all(test, feature = "aws"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays() {
+        let ms = Duration::from_millis;
+        let mut config = DownloadRetryConfig {
+            max_attempts:     5,
+            base_delay_ms:    200,
+            max_delay_ms:     1_000,
+            backoff:          DownloadBackoff::Exponential,
+            total_timeout_ms: 0,
+        };
+        assert_eq!(config.delays(), vec![ms(200), ms(400), ms(800), ms(1_000)]);
+        config.backoff = DownloadBackoff::Fixed;
+        assert_eq!(config.delays(), vec![ms(200); 4]);
+        assert!(DownloadRetryConfig::no_retries().delays().is_empty());
+    }
+
+    #[test]
+    fn test_hashed_body() {
+        // SHA-256 of "Hello, world!".
+        let checksum = "MV9b23bQeMQ7isAGTkoBZGErH853yGk0W/yUx1iU7dM=";
+        let body = || {
+            let mut body = HashedBody::new(13);
+            body.append(b"Hello, ").unwrap();
+            body.append(b"world!").unwrap();
+            body
+        };
+        assert_eq!(body().finish(None).unwrap(), b"Hello, world!");
+        assert_eq!(body().finish(Some(checksum)).unwrap(), b"Hello, world!");
+        // Composite checksums are not checked.
+        assert!(body().finish(Some("AAAA-2")).is_ok());
+
+        let failure = body()
+            .finish(Some(&checksum.replace('M', "N")))
+            .unwrap_err();
+        assert!(failure.retryable);
+        assert!(matches!(
+            failure.error,
+            SharesDecodingError::BodyChecksumMismatch { .. }
+        ));
+
+        let failure = body().append(b"!").unwrap_err();
+        assert!(!failure.retryable);
+    }
+}
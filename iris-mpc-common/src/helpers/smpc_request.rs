@@ -1,18 +1,51 @@
-use super::{key_pair::SharesDecodingError, sha256::calculate_sha256};
-use crate::helpers::key_pair::SharesEncryptionKeyPairs;
-use aws_sdk_s3::Client as S3Client;
+#[cfg(feature = "aws")]
+use super::share_download::{
+    check_content_length, is_retryable_sdk_error, retry_download, DownloadFailure, HashedBody,
+    ShareDownloadConfig,
+};
+#[cfg(feature = "aws")]
+use super::smpc_response::SMPC_MESSAGE_TYPE_ATTRIBUTE;
+use super::{
+    key_pair::{seal_share, SharesDecodingError},
+    sha256::{calculate_sha256, digest_eq_hex, Sha256Stream},
+    threshold::ThresholdUpdateRequest,
+};
+use crate::{
+    galois_engine::degree4::{GaloisRingIrisCodeShare, ShareKind, LEGACY_SHARE_LEN, SHARE_LEN},
+    helpers::key_provider::KeyPairProvider,
+    iris_db::iris::IrisCode,
+};
+#[cfg(feature = "aws")]
+use aws_sdk_s3::{types::ChecksumMode, Client as S3Client};
+#[cfg(feature = "aws")]
 use aws_sdk_sns::types::MessageAttributeValue;
+#[cfg(feature = "aws")]
 use aws_sdk_sqs::{
-    error::SdkError,
-    operation::{delete_message::DeleteMessageError, receive_message::ReceiveMessageError},
+    config::http::HttpResponse,
+    error::{ProvideErrorMetadata, SdkError},
+    operation::{
+        change_message_visibility::ChangeMessageVisibilityError,
+        change_message_visibility_batch::ChangeMessageVisibilityBatchError,
+        delete_message::DeleteMessageError, delete_message_batch::DeleteMessageBatchError,
+        receive_message::ReceiveMessageError, send_message::SendMessageError,
+    },
 };
 use base64::{engine::general_purpose::STANDARD, Engine};
+#[cfg(feature = "aws")]
 use eyre::Report;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use rand::{CryptoRng, Rng};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "aws")]
+use serde::{Deserializer, Serializer};
+#[cfg(feature = "aws")]
 use serde_json::Value;
+use sodiumoxide::crypto::box_::PublicKey;
+use std::{collections::BTreeMap, fmt, io, time::Duration};
+#[cfg(feature = "aws")]
 use std::{collections::HashMap, sync::Arc};
 use thiserror::Error;
 
+#[cfg(feature = "aws")]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SQSMessage {
     #[serde(rename = "Type")]
@@ -37,6 +70,7 @@ pub struct SQSMessage {
     pub message_attributes: HashMap<String, MessageAttributeValue>,
 }
 
+#[cfg(feature = "aws")]
 // Deserialize message attributes map from SQS body.
 // For simplicity, it only deserializes attributes of type String.
 // Update this function if other types are needed (String.Array, Number, and
@@ -62,6 +96,7 @@ where
     Ok(result)
 }
 
+#[cfg(feature = "aws")]
 fn process_attribute(
     key: &String,
     attr_type: Option<&str>,
@@ -88,28 +123,300 @@ fn process_attribute(
     }
 }
 
-// MessageAttributes serialization placeholder. It's left empty as we do not
-// send messages to SQS.
+#[cfg(feature = "aws")]
+// Serialize message attributes map into the SNS envelope format. Like the
+// deserialization, only attributes with a string value are supported.
 fn serialize_message_attributes<S>(
-    _: &HashMap<String, MessageAttributeValue>,
+    attributes: &HashMap<String, MessageAttributeValue>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let map = serde_json::map::Map::new();
+    let mut map = serde_json::map::Map::new();
+    for (key, value) in attributes.iter() {
+        if let Some(string_value) = value.string_value() {
+            map.insert(
+                key.clone(),
+                serde_json::json!({
+                    "Type": value.data_type(),
+                    "Value": string_value,
+                }),
+            );
+        }
+    }
     map.serialize(serializer)
 }
 
+#[cfg(feature = "aws")]
+impl SQSMessage {
+    /// The envelope of a message delivered without one, see
+    /// [`parse_incoming_body`]. The fields only SNS sets are left empty.
+    pub fn from_raw(
+        message_id: String,
+        message: String,
+        message_attributes: HashMap<String, MessageAttributeValue>,
+    ) -> Self {
+        Self {
+            notification_type: "Notification".to_string(),
+            message_id,
+            sequence_number: String::new(),
+            topic_arn: String::new(),
+            message,
+            timestamp: String::new(),
+            unsubscribe_url: String::new(),
+            message_attributes,
+        }
+    }
+}
+
+/// Parses the body of a request queue message. Queues subscribed to the
+/// request topic receive the SNS envelope, which is returned with its inner
+/// message. Queues with raw message delivery receive the inner message as the
+/// body, whose attributes are then the SQS message attributes.
+#[cfg(feature = "aws")]
+pub fn parse_incoming_body(
+    body: &str,
+) -> Result<(Option<SQSMessage>, String), ReceiveRequestError> {
+    let value: Value = serde_json::from_str(body)
+        .map_err(|e| ReceiveRequestError::json_parse_error("SQS body", e))?;
+    if value.get("Type").is_none() || value.get("Message").is_none() {
+        return Ok((None, body.to_string()));
+    }
+    let envelope: SQSMessage = serde_json::from_value(value)
+        .map_err(|e| ReceiveRequestError::json_parse_error("SNS envelope", e))?;
+    let message = envelope.message.clone();
+    Ok((Some(envelope), message))
+}
+
 pub const IDENTITY_DELETION_MESSAGE_TYPE: &str = "identity_deletion";
+pub const IDENTITY_DELETION_BATCH_MESSAGE_TYPE: &str = "identity_deletion_batch";
+pub const IDENTITY_RESTORE_MESSAGE_TYPE: &str = "identity_restore";
 pub const CIRCUIT_BREAKER_MESSAGE_TYPE: &str = "circuit_breaker";
 pub const UNIQUENESS_MESSAGE_TYPE: &str = "uniqueness";
+pub const THRESHOLD_UPDATE_MESSAGE_TYPE: &str = "threshold_update";
+pub const REAUTH_MESSAGE_TYPE: &str = "reauth";
+pub const RESET_CHECK_MESSAGE_TYPE: &str = "reset_check";
+pub const RESET_UPDATE_MESSAGE_TYPE: &str = "reset_update";
+
+/// Optional message attribute with the schema version of the request.
+/// Requests without it are read as [`REQUEST_SCHEMA_VERSION`].
+pub const SMPC_SCHEMA_VERSION_ATTRIBUTE: &str = "schema_version";
+/// The schema version of the requests this server reads.
+pub const REQUEST_SCHEMA_VERSION: &str = "1";
+
+/// The kinds of request messages, by their message type attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    Uniqueness,
+    ReAuth,
+    ResetCheck,
+    ResetUpdate,
+    IdentityDeletion,
+    IdentityDeletionBatch,
+    IdentityRestore,
+    CircuitBreaker,
+    ThresholdUpdate,
+}
+
+impl RequestKind {
+    pub const ALL: &'static [RequestKind] = &[
+        RequestKind::Uniqueness,
+        RequestKind::ReAuth,
+        RequestKind::ResetCheck,
+        RequestKind::ResetUpdate,
+        RequestKind::IdentityDeletion,
+        RequestKind::IdentityDeletionBatch,
+        RequestKind::IdentityRestore,
+        RequestKind::CircuitBreaker,
+        RequestKind::ThresholdUpdate,
+    ];
+
+    /// The message type attribute of the requests of this kind.
+    pub const fn message_type(self) -> &'static str {
+        match self {
+            RequestKind::Uniqueness => UNIQUENESS_MESSAGE_TYPE,
+            RequestKind::ReAuth => REAUTH_MESSAGE_TYPE,
+            RequestKind::ResetCheck => RESET_CHECK_MESSAGE_TYPE,
+            RequestKind::ResetUpdate => RESET_UPDATE_MESSAGE_TYPE,
+            RequestKind::IdentityDeletion => IDENTITY_DELETION_MESSAGE_TYPE,
+            RequestKind::IdentityDeletionBatch => IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            RequestKind::IdentityRestore => IDENTITY_RESTORE_MESSAGE_TYPE,
+            RequestKind::CircuitBreaker => CIRCUIT_BREAKER_MESSAGE_TYPE,
+            RequestKind::ThresholdUpdate => THRESHOLD_UPDATE_MESSAGE_TYPE,
+        }
+    }
+
+    pub fn from_message_type(message_type: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.message_type() == message_type)
+    }
+
+    /// Reads the kind of a request from its message type attribute, and checks
+    /// its [`SMPC_SCHEMA_VERSION_ATTRIBUTE`] if set.
+    #[cfg(feature = "aws")]
+    pub fn from_attributes(
+        attributes: &HashMap<String, MessageAttributeValue>,
+    ) -> Result<Self, ReceiveRequestError> {
+        let message_type = attributes
+            .get(SMPC_MESSAGE_TYPE_ATTRIBUTE)
+            .ok_or(ReceiveRequestError::NoMessageTypeAttribute)?
+            .string_value()
+            .ok_or(ReceiveRequestError::NoStringMessageTypeAttribute)?;
+        let kind =
+            Self::from_message_type(message_type).ok_or(ReceiveRequestError::InvalidMessageType)?;
+        if let Some(version) = attributes.get(SMPC_SCHEMA_VERSION_ATTRIBUTE) {
+            let version = version.string_value().unwrap_or_default();
+            if version != REQUEST_SCHEMA_VERSION {
+                return Err(ReceiveRequestError::UnsupportedSchemaVersion {
+                    kind,
+                    version: version.to_string(),
+                });
+            }
+        }
+        Ok(kind)
+    }
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message_type())
+    }
+}
+
+/// A request message with its payload deserialized according to its kind.
+#[derive(Debug, Clone)]
+pub enum ParsedRequest {
+    Uniqueness(UniquenessRequest),
+    ReAuth(ReAuthRequest),
+    ResetCheck(ResetCheckRequest),
+    ResetUpdate(ResetUpdateRequest),
+    IdentityDeletion(IdentityDeletionRequest),
+    /// Not yet checked, see [`IdentityDeletionBatchRequest::validate`].
+    IdentityDeletionBatch(IdentityDeletionBatchRequest),
+    IdentityRestore(IdentityRestoreRequest),
+    CircuitBreaker(CircuitBreakerRequest),
+    ThresholdUpdate(ThresholdUpdateRequest),
+}
+
+impl ParsedRequest {
+    /// Deserializes the payload `message` of a request of the given kind.
+    #[cfg(feature = "aws")]
+    pub fn parse(kind: RequestKind, message: &str) -> Result<Self, ReceiveRequestError> {
+        fn parse<T: serde::de::DeserializeOwned>(
+            kind: RequestKind,
+            message: &str,
+        ) -> Result<T, ReceiveRequestError> {
+            serde_json::from_str(message)
+                .map_err(|e| ReceiveRequestError::json_parse_error(&format!("{} request", kind), e))
+        }
+        Ok(match kind {
+            RequestKind::Uniqueness => ParsedRequest::Uniqueness(parse(kind, message)?),
+            RequestKind::ReAuth => ParsedRequest::ReAuth(parse(kind, message)?),
+            RequestKind::ResetCheck => ParsedRequest::ResetCheck(parse(kind, message)?),
+            RequestKind::ResetUpdate => ParsedRequest::ResetUpdate(parse(kind, message)?),
+            RequestKind::IdentityDeletion => ParsedRequest::IdentityDeletion(parse(kind, message)?),
+            RequestKind::IdentityDeletionBatch => {
+                ParsedRequest::IdentityDeletionBatch(parse(kind, message)?)
+            }
+            RequestKind::IdentityRestore => ParsedRequest::IdentityRestore(parse(kind, message)?),
+            RequestKind::CircuitBreaker => ParsedRequest::CircuitBreaker(parse(kind, message)?),
+            RequestKind::ThresholdUpdate => ParsedRequest::ThresholdUpdate(parse(kind, message)?),
+        })
+    }
+
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            ParsedRequest::Uniqueness(_) => RequestKind::Uniqueness,
+            ParsedRequest::ReAuth(_) => RequestKind::ReAuth,
+            ParsedRequest::ResetCheck(_) => RequestKind::ResetCheck,
+            ParsedRequest::ResetUpdate(_) => RequestKind::ResetUpdate,
+            ParsedRequest::IdentityDeletion(_) => RequestKind::IdentityDeletion,
+            ParsedRequest::IdentityDeletionBatch(_) => RequestKind::IdentityDeletionBatch,
+            ParsedRequest::IdentityRestore(_) => RequestKind::IdentityRestore,
+            ParsedRequest::CircuitBreaker(_) => RequestKind::CircuitBreaker,
+            ParsedRequest::ThresholdUpdate(_) => RequestKind::ThresholdUpdate,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UniquenessRequest {
     pub batch_size:              Option<usize>,
     pub signup_id:               String,
+    /// Key of the shares file in the shares bucket. Empty if the shares come
+    /// inline, see [`Self::share_source`].
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub s3_key:                  String,
+    /// The shares file itself instead of `s3_key`, sealed the same way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iris_shares:             Option<SharesS3Object>,
+    pub iris_shares_file_hashes: [String; 3],
+    /// Additionally compares the mirrored irises with swapped eyes against
+    /// the database, if the server has mirrored checks enabled.
+    pub mirrored_check:          Option<bool>,
+    /// Only tries the rotations by at most this many columns either way, see
+    /// [`Self::rotation_window`]. All rotations are tried if not set.
+    #[serde(default)]
+    pub rotation_window:         Option<usize>,
+    /// Serial ids a match against does not block the signup, e.g. the
+    /// identity of an applicant re-enrolling after consent. Their matches
+    /// are still reported, see [`Self::or_rule_indices`].
+    #[serde(default)]
+    pub or_rule_serial_ids:      Option<Vec<u32>>,
+}
+
+/// Compares the irises only against the given identities, to confirm that
+/// the person is one of them. Nothing is inserted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReAuthRequest {
+    pub reauth_id:               String,
+    /// Presigned URL of the shares file, see [`SharesS3Object`]. Either this
+    /// or a bucket and key, see [`ShareSource::from_fields`].
+    #[serde(default)]
+    pub s3_presigned_url:        Option<String>,
+    #[serde(default)]
+    pub s3_bucket:               Option<String>,
+    #[serde(default)]
+    pub s3_key:                  Option<String>,
+    pub iris_shares_file_hashes: [String; 3],
+    /// The 1-indexed serial ids the irises are compared against.
+    pub target_serial_ids:       Vec<u32>,
+    pub batch_size:              Option<usize>,
+}
+
+/// Compares the irises against the database like a uniqueness request, but
+/// never inserts them. Used to check a re-enrollment before its
+/// [`ResetUpdateRequest`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResetCheckRequest {
+    pub reset_id:                String,
+    /// Presigned URL of the shares file, see [`SharesS3Object`]. Either this
+    /// or a bucket and key, see [`ShareSource::from_fields`].
+    #[serde(default)]
+    pub s3_presigned_url:        Option<String>,
+    #[serde(default)]
+    pub s3_bucket:               Option<String>,
+    #[serde(default)]
+    pub s3_key:                  Option<String>,
+    pub iris_shares_file_hashes: [String; 3],
+    pub batch_size:              Option<usize>,
+}
+
+/// Replaces the shares stored for `serial_id`, keeping the serial id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResetUpdateRequest {
+    pub serial_id:               u32,
+    /// Presigned URL of the shares file, see [`SharesS3Object`]. Either this
+    /// or a bucket and key, see [`ShareSource::from_fields`].
+    #[serde(default)]
+    pub s3_presigned_url:        Option<String>,
+    #[serde(default)]
+    pub s3_bucket:               Option<String>,
+    #[serde(default)]
+    pub s3_key:                  Option<String>,
     pub iris_shares_file_hashes: [String; 3],
 }
 
@@ -120,9 +427,64 @@ pub struct CircuitBreakerRequest {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IdentityDeletionRequest {
+    pub serial_id:         u32,
+    /// Keeps the shares for this long before they are purged, the identity
+    /// can be restored until then. Deletes right away if not set.
+    #[serde(default)]
+    pub grace_period_secs: Option<u64>,
+}
+
+/// Restores an identity that was deleted with a grace period that is not over
+/// yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdentityRestoreRequest {
     pub serial_id: u32,
 }
 
+/// The most serial ids an [`IdentityDeletionBatchRequest`] may hold.
+pub const MAX_IDENTITY_DELETION_BATCH_SIZE: usize = 1000;
+
+/// Deletes several identities at once. The parties delete either all of them
+/// with the same batch or none, see
+/// [`agreed_deletion_batches`](crate::helpers::sync::agreed_deletion_batches).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IdentityDeletionBatchRequest {
+    pub serial_ids: Vec<u32>,
+}
+
+impl IdentityDeletionBatchRequest {
+    /// Parses a request and checks it against
+    /// [`MAX_IDENTITY_DELETION_BATCH_SIZE`]. The serial ids are sorted and
+    /// deduplicated.
+    pub fn parse(message: &str) -> Result<Self, IdentityDeletionBatchError> {
+        let request: Self = serde_json::from_str(message)?;
+        request.validate()
+    }
+
+    /// Checks a deserialized request like [`Self::parse`].
+    pub fn validate(mut self) -> Result<Self, IdentityDeletionBatchError> {
+        if self.serial_ids.len() > MAX_IDENTITY_DELETION_BATCH_SIZE {
+            return Err(IdentityDeletionBatchError::TooManySerialIds {
+                count: self.serial_ids.len(),
+                max:   MAX_IDENTITY_DELETION_BATCH_SIZE,
+            });
+        }
+        self.serial_ids.sort_unstable();
+        self.serial_ids.dedup();
+        Ok(self)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum IdentityDeletionBatchError {
+    #[error("Failed to parse identity deletion batch JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Identity deletion batch holds {count} serial ids, at most {max} are allowed")]
+    TooManySerialIds { count: usize, max: usize },
+}
+
+#[cfg(feature = "aws")]
 #[derive(Error, Debug)]
 pub enum ReceiveRequestError {
     #[error("Failed to read from request SQS: {0}")]
@@ -131,9 +493,36 @@ pub enum ReceiveRequestError {
     #[error("Failed to delete request from SQS: {0}")]
     FailedToDeleteFromSQS(#[from] SdkError<DeleteMessageError>),
 
+    #[error("Failed to change the visibility of a request in SQS: {0}")]
+    FailedToChangeVisibility(#[from] SdkError<ChangeMessageVisibilityError>),
+
+    #[error("Failed to delete a batch of messages from SQS: {0}")]
+    FailedToDeleteBatchFromSQS(#[from] SdkError<DeleteMessageBatchError>),
+
+    #[error("Failed to change the visibility of a batch of messages in SQS: {0}")]
+    FailedToChangeVisibilityBatch(#[from] SdkError<ChangeMessageVisibilityBatchError>),
+
+    #[error("Failed to delete {} messages from SQS", .0.len())]
+    BatchDeleteIncomplete(Vec<String>),
+
+    #[error("Failed to change the visibility of {} messages in SQS", .0.len())]
+    BatchVisibilityIncomplete(Vec<String>),
+
+    #[error("Failed to requeue request in SQS: {0}")]
+    FailedToRequeue(#[from] SdkError<SendMessageError>),
+
+    #[error("Failed to forward request to the dead-letter queue: {0}")]
+    FailedToDeadLetter(SdkError<SendMessageError>),
+
+    #[error("Receipt handle is no longer valid: {0}")]
+    ExpiredReceiptHandle(String),
+
     #[error("Failed to mark request as deleted in the database: {0}")]
     FailedToMarkRequestAsDeleted(#[from] Report),
 
+    #[error("Failed to check the request for a replay: {0}")]
+    FailedToCheckReplay(Report),
+
     #[error("Failed to parse {json_name} JSON: {err}")]
     JsonParseError {
         json_name: String,
@@ -149,10 +538,27 @@ pub enum ReceiveRequestError {
     #[error("Message type attribute is not valid")]
     InvalidMessageType,
 
+    #[error(
+        "Request of type {kind} has schema version {version}, only {} is supported",
+        REQUEST_SCHEMA_VERSION
+    )]
+    UnsupportedSchemaVersion {
+        kind:    RequestKind,
+        version: String,
+    },
+
     #[error("Failed to join receive handle: {0}")]
     FailedToJoinHandle(#[from] tokio::task::JoinError),
+
+    #[error("Gave up on message {message_id} after {attempts} attempts: {source}")]
+    ExhaustedRetries {
+        message_id: String,
+        attempts:   u32,
+        source:     Box<ReceiveRequestError>,
+    },
 }
 
+#[cfg(feature = "aws")]
 impl ReceiveRequestError {
     pub fn json_parse_error(json_name: &str, err: serde_json::error::Error) -> Self {
         ReceiveRequestError::JsonParseError {
@@ -160,13 +566,100 @@ impl ReceiveRequestError {
             err,
         }
     }
+
+    /// Whether the failure may pass on its own, so that the operation is
+    /// worth retrying: an SQS call that timed out, was not sent, was
+    /// throttled or failed on the side of SQS. A message that does not parse
+    /// fails the same way every time.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ReceiveRequestError::FailedToReadFromSQS(e) => is_transient_sdk_error(e),
+            ReceiveRequestError::FailedToDeleteFromSQS(e) => is_transient_sdk_error(e),
+            ReceiveRequestError::FailedToChangeVisibility(e) => is_transient_sdk_error(e),
+            ReceiveRequestError::FailedToDeleteBatchFromSQS(e) => is_transient_sdk_error(e),
+            ReceiveRequestError::FailedToChangeVisibilityBatch(e) => is_transient_sdk_error(e),
+            ReceiveRequestError::FailedToRequeue(e)
+            | ReceiveRequestError::FailedToDeadLetter(e) => is_transient_sdk_error(e),
+            ReceiveRequestError::BatchDeleteIncomplete(_)
+            | ReceiveRequestError::BatchVisibilityIncomplete(_)
+            | ReceiveRequestError::ExpiredReceiptHandle(_)
+            | ReceiveRequestError::FailedToMarkRequestAsDeleted(_)
+            | ReceiveRequestError::FailedToCheckReplay(_)
+            | ReceiveRequestError::JsonParseError { .. }
+            | ReceiveRequestError::NoMessageTypeAttribute
+            | ReceiveRequestError::NoStringMessageTypeAttribute
+            | ReceiveRequestError::InvalidMessageType
+            | ReceiveRequestError::UnsupportedSchemaVersion { .. }
+            | ReceiveRequestError::FailedToJoinHandle(_)
+            | ReceiveRequestError::ExhaustedRetries { .. } => false,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The error codes AWS services answer throttled calls with.
+#[cfg(feature = "aws")]
+const THROTTLING_ERROR_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "ThrottledException",
+    "RequestThrottled",
+    "RequestThrottledException",
+    "TooManyRequestsException",
+];
+
+/// Whether an SQS call failed with a timeout, a failed connection, a server
+/// error or throttling.
+#[cfg(feature = "aws")]
+fn is_transient_sdk_error<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    is_retryable_sdk_error(err)
+        || err
+            .code()
+            .is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code))
+        || err
+            .raw_response()
+            .is_some_and(|response| response.status().as_u16() == 429)
+}
+
+/// The retries of the request receiving after the failures that may pass on
+/// their own, see [`ReceiveRequestError::is_retryable`]. The delays are in
+/// milliseconds: they double with every retry up to `max_delay_ms`, less a
+/// random jitter of up to a half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReceiveRetryConfig {
+    /// The attempts including the first one.
+    pub max_attempts:  u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms:  u64,
+}
+
+impl Default for ReceiveRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts:  5,
+            base_delay_ms: 200,
+            max_delay_ms:  5_000,
+        }
+    }
+}
+
+impl ReceiveRetryConfig {
+    /// The wait before the `retry`-th retry, counting from 1.
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        let delay = Duration::from_millis(self.base_delay_ms)
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(Duration::from_millis(self.max_delay_ms));
+        delay - delay.mul_f64(rng.gen_range(0.0..=0.5))
+    }
+}
+
+/// The encrypted share files of the parties, by party index. A file of the
+/// three parties is serialized with the `iris_share_{i}` fields the uploaders
+/// write, any other one by party index; both forms are read. Fields that are
+/// neither are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SharesS3Object {
-    pub iris_share_0: String,
-    pub iris_share_1: String,
-    pub iris_share_2: String,
+    shares: BTreeMap<usize, String>,
 }
 
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
@@ -181,110 +674,787 @@ pub struct IrisCodesJSON {
     pub right_mask_code_shares: String, // these are base64 encoded strings
 }
 
+pub const IRIS_VERSION: &str = "1.1";
+pub const IRIS_SHARES_VERSION: &str = "1.3";
+
+/// The `IRIS_version`s of the share files the parties decode. The version
+/// fixes the encoding of the shares and with it their length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SupportedIrisVersion {
+    /// Shares without a header, the bincode encoding of
+    /// [`GaloisRingIrisCodeShare`].
+    #[serde(rename = "1.0")]
+    V1_0,
+    /// Shares of [`GaloisRingIrisCodeShare::to_bytes`], with a header.
+    #[serde(rename = "1.1")]
+    V1_1,
+}
+
+impl SupportedIrisVersion {
+    pub const ALL: [Self; 2] = [Self::V1_0, Self::V1_1];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::V1_0 => "1.0",
+            Self::V1_1 => "1.1",
+        }
+    }
+
+    pub fn parse(version: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|supported| supported.as_str() == version)
+    }
+
+    /// The length of every share of a file of this version, base64 decoded.
+    pub fn share_len(self) -> usize {
+        match self {
+            Self::V1_0 => LEGACY_SHARE_LEN,
+            Self::V1_1 => SHARE_LEN,
+        }
+    }
+}
+
+impl fmt::Display for SupportedIrisVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl IrisCodesJSON {
+    /// The share file of one party, from its shares of the code and the mask
+    /// of both eyes.
+    pub fn new(
+        left_code: &GaloisRingIrisCodeShare,
+        left_mask: &GaloisRingIrisCodeShare,
+        right_code: &GaloisRingIrisCodeShare,
+        right_mask: &GaloisRingIrisCodeShare,
+    ) -> Self {
+        Self {
+            iris_version:           IRIS_VERSION.to_string(),
+            iris_shares_version:    IRIS_SHARES_VERSION.to_string(),
+            left_iris_code_shares:  left_code.to_base64(ShareKind::Code),
+            right_iris_code_shares: right_code.to_base64(ShareKind::Code),
+            left_mask_code_shares:  left_mask.to_base64(ShareKind::Mask),
+            right_mask_code_shares: right_mask.to_base64(ShareKind::Mask),
+        }
+    }
+
+    /// Checks that `IRIS_version` is one of `supported` and that the four
+    /// shares have the length of its encoding. Returns the version.
+    pub fn validate_version(
+        &self,
+        supported: &[SupportedIrisVersion],
+    ) -> Result<SupportedIrisVersion, SharesDecodingError> {
+        let version = SupportedIrisVersion::parse(&self.iris_version)
+            .filter(|version| supported.contains(version))
+            .ok_or_else(|| SharesDecodingError::UnsupportedVersion {
+                got:       self.iris_version.clone(),
+                supported: supported.to_vec(),
+            })?;
+        let expected = version.share_len();
+        for share in [
+            &self.left_iris_code_shares,
+            &self.right_iris_code_shares,
+            &self.left_mask_code_shares,
+            &self.right_mask_code_shares,
+        ] {
+            let got = base64_decoded_len(share).ok_or(SharesDecodingError::Base64DecodeError)?;
+            if got != expected {
+                return Err(SharesDecodingError::WrongShareLength {
+                    version,
+                    expected,
+                    got,
+                });
+            }
+        }
+        Ok(version)
+    }
+
+    /// The serialization the hashes of the share files are taken over: the
+    /// fields in declaration order, without whitespace. It is spelled out, so
+    /// that the hashes do not depend on the formatting of serde.
+    pub fn canonical_json(&self) -> String {
+        let mut json = vec![];
+        self.write_canonical_json(&mut json)
+            .expect("writing to a vector does not fail");
+        String::from_utf8(json).expect("JSON is UTF-8")
+    }
+
+    /// Writes [`Self::canonical_json`] to `out`.
+    pub fn write_canonical_json<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        let fields = [
+            ("IRIS_version", &self.iris_version),
+            ("IRIS_shares_version", &self.iris_shares_version),
+            ("left_iris_code_shares", &self.left_iris_code_shares),
+            ("right_iris_code_shares", &self.right_iris_code_shares),
+            ("left_mask_code_shares", &self.left_mask_code_shares),
+            ("right_mask_code_shares", &self.right_mask_code_shares),
+        ];
+        out.write_all(b"{")?;
+        for (i, (name, value)) in fields.into_iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            write!(out, "\"{}\":", name)?;
+            // The JSON string escaping is fixed by the JSON grammar.
+            serde_json::to_writer(&mut *out, value)?;
+        }
+        out.write_all(b"}")
+    }
+
+    /// The SHA-256 of [`Self::canonical_json`], hashed as it is written
+    /// instead of over a copy of the shares.
+    pub fn canonical_json_sha256(&self) -> [u8; 32] {
+        let mut hasher = Sha256Stream::new();
+        self.write_canonical_json(&mut hasher)
+            .expect("hashing does not fail");
+        hasher.finalize()
+    }
+
+    /// Secret shares the irises of both eyes into the share files of the
+    /// three parties.
+    pub fn share_irises<R: CryptoRng + Rng>(
+        left: &IrisCode,
+        right: &IrisCode,
+        rng: &mut R,
+    ) -> [Self; 3] {
+        let left_code = GaloisRingIrisCodeShare::encode_iris_code(&left.code, &left.mask, rng);
+        let left_mask = GaloisRingIrisCodeShare::encode_mask_code(&left.mask, rng);
+        let right_code = GaloisRingIrisCodeShare::encode_iris_code(&right.code, &right.mask, rng);
+        let right_mask = GaloisRingIrisCodeShare::encode_mask_code(&right.mask, rng);
+        std::array::from_fn(|i| {
+            Self::new(&left_code[i], &left_mask[i], &right_code[i], &right_mask[i])
+        })
+    }
+}
+
 impl SharesS3Object {
+    const LEGACY_FIELD_PREFIX: &'static str = "iris_share_";
+
+    pub fn from_shares(shares: impl IntoIterator<Item = (usize, String)>) -> Self {
+        Self {
+            shares: shares.into_iter().collect(),
+        }
+    }
+
     pub fn get(&self, party_id: usize) -> Option<&String> {
-        match party_id {
-            0 => Some(&self.iris_share_0),
-            1 => Some(&self.iris_share_1),
-            2 => Some(&self.iris_share_2),
-            _ => None,
+        self.shares.get(&party_id)
+    }
+
+    pub fn party_count(&self) -> usize {
+        self.shares.len()
+    }
+
+    /// Whether the file holds exactly the shares of parties 0, 1 and 2.
+    fn is_legacy(&self) -> bool {
+        self.shares.keys().copied().eq(0..3)
+    }
+
+    /// The share of `party_id`, or an error naming the parties the file holds.
+    pub fn share_of(&self, party_id: usize) -> Result<&String, SharesDecodingError> {
+        self.get(party_id).ok_or_else(|| {
+            tracing::error!(
+                "Failed to find the share of party {} among {:?}",
+                party_id,
+                self.shares.keys().collect::<Vec<_>>()
+            );
+            SharesDecodingError::PartyShareNotFound {
+                party_id,
+                party_count: self.party_count(),
+            }
+        })
+    }
+
+    /// Encrypts the share file of every party for its public key. Returns the
+    /// object along with the hashes of the share files, as checked by
+    /// [`UniquenessRequest::validate_iris_share`].
+    pub fn seal(shares: &[IrisCodesJSON; 3], public_keys: [&PublicKey; 3]) -> (Self, [String; 3]) {
+        let hashes = std::array::from_fn(|i| calculate_sha256(shares[i].canonical_json()));
+        let sealed: [String; 3] = std::array::from_fn(|i| seal_share(&shares[i], public_keys[i]));
+        (Self::from_shares(sealed.into_iter().enumerate()), hashes)
+    }
+}
+
+impl Serialize for SharesS3Object {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let legacy = self.is_legacy();
+        let mut map = serializer.serialize_map(Some(self.shares.len()))?;
+        for (party_id, share) in &self.shares {
+            let key = match legacy {
+                true => format!("{}{}", Self::LEGACY_FIELD_PREFIX, party_id),
+                false => party_id.to_string(),
+            };
+            map.serialize_entry(&key, share)?;
         }
+        map.end()
     }
 }
 
+impl<'de> Deserialize<'de> for SharesS3Object {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{self, IgnoredAny, MapAccess, Visitor};
+
+        struct SharesVisitor;
+
+        impl<'de> Visitor<'de> for SharesVisitor {
+            type Value = SharesS3Object;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of the shares by party index")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut shares = BTreeMap::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    let party_id = key
+                        .strip_prefix(SharesS3Object::LEGACY_FIELD_PREFIX)
+                        .unwrap_or(&key)
+                        .parse::<usize>();
+                    let Ok(party_id) = party_id else {
+                        map.next_value::<IgnoredAny>()?;
+                        continue;
+                    };
+                    if shares.insert(party_id, map.next_value()?).is_some() {
+                        return Err(de::Error::custom(format!(
+                            "duplicate share of party {}",
+                            party_id
+                        )));
+                    }
+                }
+                Ok(SharesS3Object { shares })
+            }
+        }
+
+        deserializer.deserialize_map(SharesVisitor)
+    }
+}
+
+/// Secret shares the irises and encrypts the share files for the parties, see
+/// [`IrisCodesJSON::share_irises`] and [`SharesS3Object::seal`].
+pub fn iris_to_encrypted_shares<R: CryptoRng + Rng>(
+    iris_left: &IrisCode,
+    iris_right: &IrisCode,
+    public_keys: [&PublicKey; 3],
+    rng: &mut R,
+) -> (SharesS3Object, [String; 3]) {
+    let shares = IrisCodesJSON::share_irises(iris_left, iris_right, rng);
+    SharesS3Object::seal(&shares, public_keys)
+}
+
 impl UniquenessRequest {
+    /// The rotation window the request is matched with: the hinted one,
+    /// capped at the configured `max_rotation_window`.
+    pub fn rotation_window(&self, max_rotation_window: usize) -> usize {
+        self.rotation_window.map_or(max_rotation_window, |window| {
+            window.min(max_rotation_window)
+        })
+    }
+
+    /// The OR-rule serial ids as sorted 0-indexed serial ids without
+    /// duplicates, or `None` if the request has none. A serial id of 0 maps
+    /// to an index beyond any DB.
+    pub fn or_rule_indices(&self) -> Option<Vec<u32>> {
+        let mut indices = self
+            .or_rule_serial_ids
+            .as_ref()?
+            .iter()
+            .map(|serial_id| serial_id.wrapping_sub(1))
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+        Some(indices)
+    }
+
+    /// Where the shares file is: inline in the request, or under `s3_key` in
+    /// the shares bucket. A request with both or neither fails with
+    /// [`SharesDecodingError::InvalidShareSource`].
+    pub fn share_source<'a>(
+        &'a self,
+        bucket_name: &'a str,
+    ) -> Result<ShareSource<'a>, SharesDecodingError> {
+        match (&self.iris_shares, self.s3_key.as_str()) {
+            (Some(shares), "") => Ok(ShareSource::Inline(shares)),
+            (None, key) if !key.is_empty() => Ok(ShareSource::S3Object {
+                bucket: bucket_name,
+                key,
+            }),
+            _ => Err(SharesDecodingError::InvalidShareSource),
+        }
+    }
+
+    /// Returns the share of this party, downloaded from S3 within the limits
+    /// of `download` unless the shares came inline.
+    #[cfg(feature = "aws")]
     pub async fn get_iris_data_by_party_id(
         &self,
         party_id: usize,
         bucket_name: &String,
         s3_client: &Arc<S3Client>,
+        download: &ShareDownloadConfig,
     ) -> Result<String, SharesDecodingError> {
+        match self.share_source(bucket_name)? {
+            ShareSource::Inline(shares) => shares.share_of(party_id).cloned(),
+            ShareSource::S3Object { bucket, key } => {
+                get_iris_data_by_s3_object(bucket, key, party_id, s3_client, download).await
+            }
+            ShareSource::PresignedUrl(_) => Err(SharesDecodingError::InvalidShareSource),
+        }
+    }
+
+    pub async fn decrypt_iris_share(
+        &self,
+        share: String,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        decrypt_iris_share(share, key_pairs, supported_versions).await
+    }
+
+    pub fn validate_iris_share(
+        &self,
+        party_id: usize,
+        share: IrisCodesJSON,
+    ) -> Result<(), ShareHashMismatch> {
+        validate_iris_share(&self.iris_shares_file_hashes, party_id, share)
+    }
+
+    /// Fetches and opens the share of this party. The share is sealed as a
+    /// whole, so it is opened once; then the hash check and the base64
+    /// decoding of the left and the right eye run concurrently, off the async
+    /// workers.
+    /// A share that does not match the hash of the request fails with
+    /// [`SharesDecodingError::HashMismatch`].
+    #[cfg(feature = "aws")]
+    pub async fn fetch_and_decrypt(
+        &self,
+        party_id: usize,
+        bucket_name: &String,
+        s3_client: &Arc<S3Client>,
+        download: &ShareDownloadConfig,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        let share = self
+            .get_iris_data_by_party_id(party_id, bucket_name, s3_client, download)
+            .await?;
+        let iris_share = decrypt_iris_share(share, key_pairs, supported_versions).await?;
+
+        let hashes = self.iris_shares_file_hashes.clone();
+        let validate = {
+            let iris_share = iris_share.clone();
+            run_blocking(move || validate_iris_share(&hashes, party_id, iris_share))
+        };
+        let decode_eye = |code: &String, mask: &String| {
+            let (code, mask) = (code.clone(), mask.clone());
+            run_blocking(move || {
+                for share in [code, mask] {
+                    STANDARD
+                        .decode(share)
+                        .map_err(|_| SharesDecodingError::Base64DecodeError)?;
+                }
+                Ok::<_, SharesDecodingError>(())
+            })
+        };
+        let (validated, left, right) = tokio::join!(
+            validate,
+            decode_eye(
+                &iris_share.left_iris_code_shares,
+                &iris_share.left_mask_code_shares
+            ),
+            decode_eye(
+                &iris_share.right_iris_code_shares,
+                &iris_share.right_mask_code_shares
+            ),
+        );
+        validated?;
+        left?;
+        right?;
+        Ok(iris_share)
+    }
+}
+
+/// Runs `f` on a blocking worker. A panic of `f` is resumed in the caller.
+#[cfg(feature = "aws")]
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+impl ReAuthRequest {
+    /// The targets as 0-indexed serial ids. A serial id of 0 maps to an
+    /// index beyond any DB.
+    pub fn target_indices(&self) -> Vec<u32> {
+        self.target_serial_ids
+            .iter()
+            .map(|serial_id| serial_id.wrapping_sub(1))
+            .collect()
+    }
+
+    /// Where the shares file is, see [`ShareSource::from_fields`].
+    pub fn share_source(&self) -> Result<ShareSource<'_>, SharesDecodingError> {
+        ShareSource::from_fields(
+            self.s3_presigned_url.as_deref(),
+            self.s3_bucket.as_deref(),
+            self.s3_key.as_deref(),
+        )
+    }
+
+    #[cfg(feature = "aws")]
+    pub async fn get_iris_data_by_party_id(
+        &self,
+        party_id: usize,
+        s3_client: &Arc<S3Client>,
+        http_client: &reqwest::Client,
+        download: &ShareDownloadConfig,
+    ) -> Result<String, SharesDecodingError> {
+        self.share_source()?
+            .get_iris_data_by_party_id(party_id, s3_client, http_client, download)
+            .await
+    }
+
+    pub async fn decrypt_iris_share(
+        &self,
+        share: String,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        decrypt_iris_share(share, key_pairs, supported_versions).await
+    }
+
+    pub fn validate_iris_share(
+        &self,
+        party_id: usize,
+        share: IrisCodesJSON,
+    ) -> Result<(), ShareHashMismatch> {
+        validate_iris_share(&self.iris_shares_file_hashes, party_id, share)
+    }
+}
+
+impl ResetCheckRequest {
+    /// Where the shares file is, see [`ShareSource::from_fields`].
+    pub fn share_source(&self) -> Result<ShareSource<'_>, SharesDecodingError> {
+        ShareSource::from_fields(
+            self.s3_presigned_url.as_deref(),
+            self.s3_bucket.as_deref(),
+            self.s3_key.as_deref(),
+        )
+    }
+
+    #[cfg(feature = "aws")]
+    pub async fn get_iris_data_by_party_id(
+        &self,
+        party_id: usize,
+        s3_client: &Arc<S3Client>,
+        http_client: &reqwest::Client,
+        download: &ShareDownloadConfig,
+    ) -> Result<String, SharesDecodingError> {
+        self.share_source()?
+            .get_iris_data_by_party_id(party_id, s3_client, http_client, download)
+            .await
+    }
+
+    pub async fn decrypt_iris_share(
+        &self,
+        share: String,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        decrypt_iris_share(share, key_pairs, supported_versions).await
+    }
+
+    pub fn validate_iris_share(
+        &self,
+        party_id: usize,
+        share: IrisCodesJSON,
+    ) -> Result<(), ShareHashMismatch> {
+        validate_iris_share(&self.iris_shares_file_hashes, party_id, share)
+    }
+}
+
+impl ResetUpdateRequest {
+    /// The 0-indexed serial id. A serial id of 0 maps to an index beyond any
+    /// DB.
+    pub fn index(&self) -> u32 {
+        self.serial_id.wrapping_sub(1)
+    }
+
+    /// Where the shares file is, see [`ShareSource::from_fields`].
+    pub fn share_source(&self) -> Result<ShareSource<'_>, SharesDecodingError> {
+        ShareSource::from_fields(
+            self.s3_presigned_url.as_deref(),
+            self.s3_bucket.as_deref(),
+            self.s3_key.as_deref(),
+        )
+    }
+
+    #[cfg(feature = "aws")]
+    pub async fn get_iris_data_by_party_id(
+        &self,
+        party_id: usize,
+        s3_client: &Arc<S3Client>,
+        http_client: &reqwest::Client,
+        download: &ShareDownloadConfig,
+    ) -> Result<String, SharesDecodingError> {
+        self.share_source()?
+            .get_iris_data_by_party_id(party_id, s3_client, http_client, download)
+            .await
+    }
+
+    pub async fn decrypt_iris_share(
+        &self,
+        share: String,
+        key_pairs: &KeyPairProvider,
+        supported_versions: &[SupportedIrisVersion],
+    ) -> Result<IrisCodesJSON, SharesDecodingError> {
+        decrypt_iris_share(share, key_pairs, supported_versions).await
+    }
+
+    pub fn validate_iris_share(
+        &self,
+        party_id: usize,
+        share: IrisCodesJSON,
+    ) -> Result<(), ShareHashMismatch> {
+        validate_iris_share(&self.iris_shares_file_hashes, party_id, share)
+    }
+}
+
+/// Where the shares file of a request is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareSource<'a> {
+    /// A presigned URL minted by the sender.
+    PresignedUrl(&'a str),
+    /// An object the parties read with their own credentials.
+    S3Object { bucket: &'a str, key: &'a str },
+    /// The shares file sent in the request itself.
+    Inline(&'a SharesS3Object),
+}
+
+impl<'a> ShareSource<'a> {
+    /// The source named by the fields of a request: either a presigned URL,
+    /// or a bucket and a key. Anything else fails with
+    /// [`SharesDecodingError::InvalidShareSource`].
+    pub fn from_fields(
+        s3_presigned_url: Option<&'a str>,
+        s3_bucket: Option<&'a str>,
+        s3_key: Option<&'a str>,
+    ) -> Result<Self, SharesDecodingError> {
+        match (s3_presigned_url, s3_bucket, s3_key) {
+            (Some(url), None, None) => Ok(ShareSource::PresignedUrl(url)),
+            (None, Some(bucket), Some(key)) => Ok(ShareSource::S3Object { bucket, key }),
+            _ => Err(SharesDecodingError::InvalidShareSource),
+        }
+    }
+
+    /// Downloads the shares file and returns the share of this party, with
+    /// the same limits and retries whichever the source.
+    #[cfg(feature = "aws")]
+    pub async fn get_iris_data_by_party_id(
+        self,
+        party_id: usize,
+        s3_client: &Arc<S3Client>,
+        http_client: &reqwest::Client,
+        download: &ShareDownloadConfig,
+    ) -> Result<String, SharesDecodingError> {
+        match self {
+            ShareSource::PresignedUrl(url) => {
+                get_iris_data_by_presigned_url(url, party_id, http_client, download).await
+            }
+            ShareSource::S3Object { bucket, key } => {
+                get_iris_data_by_s3_object(bucket, key, party_id, s3_client, download).await
+            }
+            ShareSource::Inline(shares) => shares.share_of(party_id).cloned(),
+        }
+    }
+}
+
+/// Downloads the shares file from S3 and returns the share of this party,
+/// within the limits of `download`.
+#[cfg(feature = "aws")]
+async fn get_iris_data_by_s3_object(
+    bucket: &str,
+    key: &str,
+    party_id: usize,
+    s3_client: &S3Client,
+    download: &ShareDownloadConfig,
+) -> Result<String, SharesDecodingError> {
+    let bytes = retry_download(&download.retry, || async move {
         let response = s3_client
             .get_object()
-            .bucket(bucket_name)
-            .key(self.s3_key.as_str())
+            .bucket(bucket)
+            .key(key)
+            .checksum_mode(ChecksumMode::Enabled)
             .send()
             .await
             .map_err(|err| {
                 tracing::error!("Failed to download file: {}", err);
-                SharesDecodingError::S3ResponseContent {
-                    key:     self.s3_key.clone(),
-                    message: err.to_string(),
+                DownloadFailure {
+                    retryable: is_retryable_sdk_error(&err),
+                    error:     SharesDecodingError::S3ResponseContent {
+                        key:     key.to_string(),
+                        message: err.to_string(),
+                    },
                 }
             })?;
 
-        let object_body = response.body.collect().await.map_err(|e| {
+        check_content_length(
+            response.content_length().map(|length| length.max(0) as u64),
+            download.max_response_bytes,
+        )?;
+        let checksum = response.checksum_sha256().map(str::to_string);
+        let mut object_body = response.body;
+        let mut body = HashedBody::new(download.max_response_bytes);
+        while let Some(chunk) = object_body.try_next().await.map_err(|e| {
             tracing::error!("Failed to get object body: {}", e);
-            SharesDecodingError::S3ResponseContent {
-                key:     self.s3_key.clone(),
+            DownloadFailure::fatal(SharesDecodingError::S3ResponseContent {
+                key:     key.to_string(),
                 message: e.to_string(),
-            }
+            })
+        })? {
+            body.append(&chunk)?;
+        }
+
+        body.finish(checksum.as_deref())
+    })
+    .await?;
+
+    let shares_file: SharesS3Object = serde_json::from_slice(&bytes)?;
+
+    shares_file.share_of(party_id).cloned()
+}
+
+/// Downloads the shares file and returns the share of this party, within the
+/// limits of `download`. The timeouts are those of `client`, see
+/// [`ShareDownloadConfig::http_client`].
+#[cfg(feature = "aws")]
+async fn get_iris_data_by_presigned_url(
+    url: &str,
+    party_id: usize,
+    client: &reqwest::Client,
+    download: &ShareDownloadConfig,
+) -> Result<String, SharesDecodingError> {
+    let bytes = retry_download(&download.retry, || async move {
+        let response = client.get(url).send().await.map_err(|e| DownloadFailure {
+            retryable: e.is_connect() || e.is_timeout(),
+            error:     e.into(),
         })?;
+        let status = response.status();
+        if !status.is_success() {
+            // The message is kept short, whatever the server sends.
+            let message = read_limited(response, MAX_ERROR_MESSAGE_BYTES)
+                .await
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .unwrap_or_default();
+            return Err(DownloadFailure {
+                retryable: status.is_server_error(),
+                error:     SharesDecodingError::ResponseContent {
+                    status,
+                    url: url.to_string(),
+                    message,
+                },
+            });
+        }
+        read_limited(response, download.max_response_bytes).await
+    })
+    .await?;
 
-        let bytes = object_body.into_bytes();
+    let shares_file: SharesS3Object = serde_json::from_slice(&bytes)?;
 
-        let shares_file: SharesS3Object = serde_json::from_slice(&bytes)?;
+    shares_file.share_of(party_id).cloned()
+}
 
-        let field_name = format!("iris_share_{}", party_id);
+#[cfg(feature = "aws")]
+const MAX_ERROR_MESSAGE_BYTES: usize = 1024;
+#[cfg(feature = "aws")]
+const S3_CHECKSUM_SHA256_HEADER: &str = "x-amz-checksum-sha256";
 
-        shares_file.get(party_id).cloned().ok_or_else(|| {
-            tracing::error!("Failed to find field: {}", field_name);
-            SharesDecodingError::SecretStringNotFound
-        })
+/// Reads the body of `response`, failing if it exceeds `max_bytes` or differs
+/// from the SHA-256 checksum S3 sent along. The announced length is checked
+/// before anything is read.
+#[cfg(feature = "aws")]
+async fn read_limited(
+    mut response: reqwest::Response,
+    max_bytes: usize,
+) -> Result<Vec<u8>, DownloadFailure> {
+    check_content_length(response.content_length(), max_bytes)?;
+    let checksum = response
+        .headers()
+        .get(S3_CHECKSUM_SHA256_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut body = HashedBody::new(max_bytes);
+    while let Some(chunk) = response.chunk().await.map_err(|e| DownloadFailure {
+        retryable: e.is_timeout(),
+        error:     e.into(),
+    })? {
+        body.append(&chunk)?;
+    }
+    body.finish(checksum.as_deref())
+}
+
+/// Opens the sealed share of this party, with the current key or else the
+/// previous one.
+/// The length of the padded base64 string `s` once decoded, without decoding
+/// it. `None` if `s` is not a whole number of blocks.
+fn base64_decoded_len(s: &str) -> Option<usize> {
+    if s.len() % 4 != 0 {
+        return None;
     }
+    let padding = s.bytes().rev().take(2).take_while(|&b| b == b'=').count();
+    Some(s.len() / 4 * 3 - padding)
+}
 
-    pub fn decrypt_iris_share(
-        &self,
-        share: String,
-        key_pairs: SharesEncryptionKeyPairs,
-    ) -> Result<IrisCodesJSON, SharesDecodingError> {
-        let share_bytes = STANDARD
-            .decode(share.as_bytes())
-            .map_err(|_| SharesDecodingError::Base64DecodeError)?;
-
-        // try decrypting with key_pairs.current_key_pair, if it fails, try decrypting
-        // with key_pairs.previous_key_pair (if it exists, otherwise, return an error)
-        let decrypted = match key_pairs
-            .current_key_pair
-            .open_sealed_box(share_bytes.clone())
-        {
-            Ok(bytes) => Ok(bytes),
-            Err(_) => {
-                match if let Some(key_pair) = key_pairs.previous_key_pair.clone() {
-                    key_pair.open_sealed_box(share_bytes)
-                } else {
-                    Err(SharesDecodingError::PreviousKeyNotFound)
-                } {
-                    Ok(bytes) => Ok(bytes),
-                    Err(_) => Err(SharesDecodingError::SealedBoxOpenError),
-                }
-            }
-        };
+/// Opens the sealed share file, with the previous keys after a rotation, and
+/// checks its version against `supported`.
+async fn decrypt_iris_share(
+    share: String,
+    key_pairs: &KeyPairProvider,
+    supported_versions: &[SupportedIrisVersion],
+) -> Result<IrisCodesJSON, SharesDecodingError> {
+    let share_bytes = STANDARD
+        .decode(share.as_bytes())
+        .map_err(|_| SharesDecodingError::Base64DecodeError)?;
 
-        let iris_share = match decrypted {
-            Ok(bytes) => {
-                let json_string = String::from_utf8(bytes)
-                    .map_err(SharesDecodingError::DecodedShareParsingToUTF8Error)?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let (decrypted, key_index) = key_pairs.open_sealed_box(&share_bytes, now).await?;
+    metrics::counter!("share_decryption.key", "index" => key_index.to_string()).increment(1);
 
-                let iris_share: IrisCodesJSON =
-                    serde_json::from_str(&json_string).map_err(SharesDecodingError::SerdeError)?;
-                iris_share
-            }
-            Err(e) => return Err(e),
-        };
+    let json_string = String::from_utf8(decrypted)
+        .map_err(SharesDecodingError::DecodedShareParsingToUTF8Error)?;
+    let iris_share: IrisCodesJSON =
+        serde_json::from_str(&json_string).map_err(SharesDecodingError::SerdeError)?;
+    iris_share.validate_version(supported_versions)?;
 
-        Ok(iris_share)
-    }
+    Ok(iris_share)
+}
 
-    pub fn validate_iris_share(
-        &self,
-        party_id: usize,
-        share: IrisCodesJSON,
-    ) -> Result<bool, SharesDecodingError> {
-        let stringified_share = serde_json::to_string(&share)
-            .map_err(SharesDecodingError::SerdeError)?
-            .into_bytes();
+/// A share that does not match the hash the request was sent with.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Share of party {party_id} hashes to {computed}, expected {expected}")]
+pub struct ShareHashMismatch {
+    pub party_id: usize,
+    pub expected: String,
+    pub computed: String,
+}
 
-        Ok(self.iris_shares_file_hashes[party_id] == calculate_sha256(stringified_share))
+/// Checks the share against the hash the request was sent with, over its
+/// [`IrisCodesJSON::canonical_json`]. The digests are compared in constant
+/// time.
+fn validate_iris_share(
+    iris_shares_file_hashes: &[String; 3],
+    party_id: usize,
+    share: IrisCodesJSON,
+) -> Result<(), ShareHashMismatch> {
+    let digest = share.canonical_json_sha256();
+    let expected = &iris_shares_file_hashes[party_id];
+    if digest_eq_hex(&digest, expected) {
+        return Ok(());
     }
+    Err(ShareHashMismatch {
+        party_id,
+        expected: expected.clone(),
+        computed: hex::encode(digest),
+    })
 }
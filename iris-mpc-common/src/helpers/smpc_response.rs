@@ -1,10 +1,15 @@
+use super::result_signature::ResultSigningKey;
+use crate::{config::MatchPolicy, errors::ErrorCode};
+#[cfg(feature = "aws")]
 use aws_sdk_sns::types::MessageAttributeValue;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "aws")]
 use std::collections::HashMap;
 
 pub const SMPC_MESSAGE_TYPE_ATTRIBUTE: &str = "message_type";
 // Error Reasons
-pub const ERROR_FAILED_TO_PROCESS_IRIS_SHARES: &str = "failed_to_process_iris_shares";
+pub const ERROR_FAILED_TO_PROCESS_IRIS_SHARES: &str = ErrorCode::FailedToProcessIrisShares.as_str();
+pub const ERROR_REPLAYED_REQUEST: &str = ErrorCode::ReplayedRequest.as_str();
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UniquenessResult {
@@ -16,8 +21,41 @@ pub struct UniquenessResult {
     pub matched_serial_ids_left:   Option<Vec<u32>>,
     pub matched_serial_ids_right:  Option<Vec<u32>>,
     pub matched_batch_request_ids: Option<Vec<String>>,
+    /// The serial ids in `matched_serial_ids` found by the mirrored check.
+    pub matched_serial_ids_mirror: Option<Vec<u32>>,
+    /// The distances of the entries in `matched_serial_ids`, in the same
+    /// order. Only set in [`crate::config::ResultMode::WithDistances`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_distances:         Option<Vec<f64>>,
+    /// The smallest of `matched_distances`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_distance:              Option<f64>,
+    /// How the matches of the two eyes were combined into `is_match`. The
+    /// matches of each eye on its own are in `matched_serial_ids_left` and
+    /// `matched_serial_ids_right`.
+    pub match_policy:              Option<MatchPolicy>,
+    /// Version of the threshold parameters the request was matched with, see
+    /// [`crate::helpers::threshold`].
+    pub threshold_version:         Option<u32>,
+    /// Set for the results of backfilled identities, see
+    /// [`crate::helpers::backfill`].
+    pub backfill:                  Option<bool>,
     pub error:                     Option<bool>,
     pub error_reason:              Option<String>,
+    /// The specific reason of an error, `error_reason` is one of
+    /// [`ERROR_FAILED_TO_PROCESS_IRIS_SHARES`] and [`ERROR_REPLAYED_REQUEST`].
+    pub error_code:                Option<ErrorCode>,
+    /// A description of the error for humans, which may change between
+    /// releases unlike `error_code`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_message:             Option<String>,
+    /// The id of the key of the party that signed the result, see
+    /// [`crate::helpers::result_signature`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key_id:             Option<String>,
+    /// The base64 encoded Ed25519 signature over all other fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature:                 Option<String>,
 }
 
 impl UniquenessResult {
@@ -41,10 +79,48 @@ impl UniquenessResult {
             matched_serial_ids_left,
             matched_serial_ids_right,
             matched_batch_request_ids,
+            matched_serial_ids_mirror: None,
+            matched_distances: None,
+            min_distance: None,
+            match_policy: None,
+            threshold_version: None,
+            backfill: None,
             error: None,
             error_reason: None,
+            error_code: None,
+            error_message: None,
+            public_key_id: None,
+            signature: None,
         }
     }
+
+    /// A request that was not compared, see [`ReAuthResult::error`].
+    pub fn error(node_id: usize, signup_id: String, error_code: ErrorCode) -> Self {
+        let error_reason = match error_code {
+            ErrorCode::ReplayedRequest => error_code,
+            _ => ErrorCode::FailedToProcessIrisShares,
+        };
+        Self {
+            error: Some(true),
+            error_reason: Some(error_reason.to_string()),
+            error_code: Some(error_code),
+            ..Self::new(node_id, None, false, signup_id, None, None, None, None)
+        }
+    }
+
+    /// Signs the result with `key`. Fields changed afterwards invalidate the
+    /// signature.
+    pub fn sign(&mut self, key: &ResultSigningKey) {
+        self.public_key_id = Some(key.public_key_id().to_string());
+        self.signature = None;
+        self.signature = Some(key.sign(self));
+    }
+
+    /// Sets `matched_distances` and their minimum.
+    pub fn set_matched_distances(&mut self, distances: Vec<f64>) {
+        self.min_distance = distances.iter().copied().reduce(f64::min);
+        self.matched_distances = Some(distances);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -64,6 +140,204 @@ impl IdentityDeletionResult {
     }
 }
 
+/// The outcome of an identity deletion batch, one event for the whole batch.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdentityDeletionBatchResult {
+    pub node_id:              usize,
+    /// The serial ids of the request, sorted and deduplicated. Empty if the
+    /// request was rejected.
+    pub serial_ids:           Vec<u32>,
+    pub deleted_serial_ids:   Vec<u32>,
+    /// The serial ids beyond the database, which were ignored.
+    pub not_found_serial_ids: Vec<u32>,
+    pub error:                Option<bool>,
+    /// Why nothing was deleted, if the batch was rejected or not all parties
+    /// received it.
+    pub error_code:           Option<ErrorCode>,
+}
+
+impl IdentityDeletionBatchResult {
+    pub fn new(
+        node_id: usize,
+        serial_ids: Vec<u32>,
+        deleted_serial_ids: Vec<u32>,
+        not_found_serial_ids: Vec<u32>,
+    ) -> Self {
+        Self {
+            node_id,
+            serial_ids,
+            deleted_serial_ids,
+            not_found_serial_ids,
+            error: None,
+            error_code: None,
+        }
+    }
+
+    /// A batch of which nothing was deleted.
+    pub fn error(node_id: usize, serial_ids: Vec<u32>, error_code: ErrorCode) -> Self {
+        Self {
+            error: Some(true),
+            error_code: Some(error_code),
+            ..Self::new(node_id, serial_ids, vec![], vec![])
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdentityRestoreResult {
+    pub node_id:   usize,
+    pub serial_id: u32,
+    pub success:   bool,
+}
+
+impl IdentityRestoreResult {
+    pub fn new(node_id: usize, serial_id: u32, success: bool) -> Self {
+        Self {
+            node_id,
+            serial_id,
+            success,
+        }
+    }
+}
+
+/// The decision of a reauth request: whether the irises match one of its
+/// targets. No serial id is assigned.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReAuthResult {
+    pub node_id:            usize,
+    pub reauth_id:          String,
+    /// The targets of the request.
+    pub serial_ids:         Vec<u32>,
+    pub is_match:           bool,
+    /// The targets the irises matched.
+    pub matched_serial_ids: Vec<u32>,
+    pub match_policy:       Option<MatchPolicy>,
+    pub threshold_version:  Option<u32>,
+    pub error:              Option<bool>,
+    pub error_reason:       Option<String>,
+    pub error_code:         Option<ErrorCode>,
+    /// The targets that are deleted or beyond the database, with
+    /// [`ErrorCode::UnknownReauthTarget`].
+    pub unknown_serial_ids: Option<Vec<u32>>,
+}
+
+impl ReAuthResult {
+    pub fn new(
+        node_id: usize,
+        reauth_id: String,
+        serial_ids: Vec<u32>,
+        matched_serial_ids: Vec<u32>,
+    ) -> Self {
+        Self {
+            node_id,
+            reauth_id,
+            serial_ids,
+            is_match: !matched_serial_ids.is_empty(),
+            matched_serial_ids,
+            match_policy: None,
+            threshold_version: None,
+            error: None,
+            error_reason: None,
+            error_code: None,
+            unknown_serial_ids: None,
+        }
+    }
+
+    /// A request that was not compared. The reason is
+    /// [`ERROR_FAILED_TO_PROCESS_IRIS_SHARES`] unless the shares were replayed
+    /// or a target is unknown.
+    pub fn error(
+        node_id: usize,
+        reauth_id: String,
+        serial_ids: Vec<u32>,
+        error_code: ErrorCode,
+    ) -> Self {
+        let error_reason = match error_code {
+            ErrorCode::ReplayedRequest | ErrorCode::UnknownReauthTarget => error_code,
+            _ => ErrorCode::FailedToProcessIrisShares,
+        };
+        Self {
+            error: Some(true),
+            error_reason: Some(error_reason.to_string()),
+            error_code: Some(error_code),
+            ..Self::new(node_id, reauth_id, serial_ids, vec![])
+        }
+    }
+}
+
+/// The matches of a reset check. Nothing is inserted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResetCheckResult {
+    pub node_id:                  usize,
+    pub reset_id:                 String,
+    pub is_match:                 bool,
+    pub matched_serial_ids:       Vec<u32>,
+    pub matched_serial_ids_left:  Vec<u32>,
+    pub matched_serial_ids_right: Vec<u32>,
+    pub threshold_version:        Option<u32>,
+    pub error:                    Option<bool>,
+    pub error_reason:             Option<String>,
+    pub error_code:               Option<ErrorCode>,
+}
+
+impl ResetCheckResult {
+    pub fn new(
+        node_id: usize,
+        reset_id: String,
+        matched_serial_ids: Vec<u32>,
+        matched_serial_ids_left: Vec<u32>,
+        matched_serial_ids_right: Vec<u32>,
+    ) -> Self {
+        Self {
+            node_id,
+            reset_id,
+            is_match: !matched_serial_ids.is_empty(),
+            matched_serial_ids,
+            matched_serial_ids_left,
+            matched_serial_ids_right,
+            threshold_version: None,
+            error: None,
+            error_reason: None,
+            error_code: None,
+        }
+    }
+
+    /// A check that was not compared, see [`ReAuthResult::error`].
+    pub fn error(node_id: usize, reset_id: String, error_code: ErrorCode) -> Self {
+        let error_reason = match error_code {
+            ErrorCode::ReplayedRequest => error_code,
+            _ => ErrorCode::FailedToProcessIrisShares,
+        };
+        Self {
+            error: Some(true),
+            error_reason: Some(error_reason.to_string()),
+            error_code: Some(error_code),
+            ..Self::new(node_id, reset_id, vec![], vec![], vec![])
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResetUpdateResult {
+    pub node_id:    usize,
+    pub serial_id:  u32,
+    pub success:    bool,
+    /// Why the shares were not replaced, if they were not.
+    pub error_code: Option<ErrorCode>,
+}
+
+impl ResetUpdateResult {
+    pub fn new(node_id: usize, serial_id: u32, success: bool) -> Self {
+        Self {
+            node_id,
+            serial_id,
+            success,
+            error_code: None,
+        }
+    }
+}
+
+#[cfg(feature = "aws")]
 pub fn create_message_type_attribute_map(
     message_type: &str,
 ) -> HashMap<String, MessageAttributeValue> {
@@ -76,3 +350,166 @@ pub fn create_message_type_attribute_map(
     message_attributes_map.insert(SMPC_MESSAGE_TYPE_ATTRIBUTE.to_string(), message_type_value);
     message_attributes_map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A consumer that only knows the fields from before the per-eye matches
+    /// and the match policy.
+    #[derive(Debug, Deserialize)]
+    struct LegacyUniquenessResult {
+        node_id:            usize,
+        serial_id:          Option<u32>,
+        is_match:           bool,
+        signup_id:          String,
+        matched_serial_ids: Option<Vec<u32>>,
+    }
+
+    #[test]
+    fn test_legacy_consumers_parse_per_eye_results() {
+        let mut result = UniquenessResult::new(
+            2,
+            None,
+            true,
+            "signup".to_string(),
+            Some(vec![7]),
+            Some(vec![7, 9]),
+            Some(vec![]),
+            Some(vec![]),
+        );
+        result.match_policy = Some(MatchPolicy::Or);
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains(r#""match_policy":"or""#));
+
+        let legacy: LegacyUniquenessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(legacy.node_id, 2);
+        assert_eq!(legacy.serial_id, None);
+        assert!(legacy.is_match);
+        assert_eq!(legacy.signup_id, "signup");
+        assert_eq!(legacy.matched_serial_ids, Some(vec![7]));
+    }
+
+    #[test]
+    fn test_results_with_distances() {
+        let mut result = UniquenessResult::new(
+            0,
+            None,
+            true,
+            "signup".to_string(),
+            Some(vec![4, 9]),
+            None,
+            None,
+            None,
+        );
+        result.set_matched_distances(vec![0.31, 0.02]);
+        assert_eq!(result.min_distance, Some(0.02));
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains(r#""matched_distances":[0.31,0.02]"#));
+        assert!(json.contains(r#""min_distance":0.02"#));
+
+        let parsed: UniquenessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.matched_serial_ids, Some(vec![4, 9]));
+        assert_eq!(parsed.matched_distances, Some(vec![0.31, 0.02]));
+        assert_eq!(parsed.min_distance, Some(0.02));
+        let legacy: LegacyUniquenessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(legacy.matched_serial_ids, Some(vec![4, 9]));
+    }
+
+    #[test]
+    fn test_results_without_distances() {
+        let result = UniquenessResult::new(
+            0,
+            Some(5),
+            false,
+            "signup".to_string(),
+            Some(vec![]),
+            None,
+            None,
+            None,
+        );
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(!json.contains("matched_distances"));
+        assert!(!json.contains("min_distance"));
+        assert!(json.contains(r#""matched_serial_ids":[]"#));
+
+        let parsed: UniquenessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.serial_id, Some(5));
+        assert_eq!(parsed.matched_distances, None);
+        assert_eq!(parsed.min_distance, None);
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn test_error_results() {
+        let mut result =
+            UniquenessResult::error(1, "signup".to_string(), ErrorCode::ShareHashMismatch);
+        result.error_message = Some("hash mismatch".to_string());
+        assert!(!result.is_match);
+        assert_eq!(result.serial_id, None);
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains(r#""error":true"#));
+        assert!(json.contains(r#""error_reason":"failed_to_process_iris_shares""#));
+        assert!(json.contains(r#""error_code":"share_hash_mismatch""#));
+        assert!(json.contains(r#""error_message":"hash mismatch""#));
+        let parsed: UniquenessResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.error_code, Some(ErrorCode::ShareHashMismatch));
+        assert_eq!(parsed.error_message.as_deref(), Some("hash mismatch"));
+
+        let result = UniquenessResult::error(1, "signup".to_string(), ErrorCode::ReplayedRequest);
+        assert_eq!(result.error_reason.as_deref(), Some(ERROR_REPLAYED_REQUEST));
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(!json.contains("error_message"));
+    }
+
+    #[test]
+    fn test_reauth_results() {
+        let result = ReAuthResult::new(1, "reauth".to_string(), vec![3, 8], vec![8]);
+        assert!(result.is_match);
+        assert_eq!(result.error, None);
+
+        let mut result = ReAuthResult::error(
+            1,
+            "reauth".to_string(),
+            vec![3, 8],
+            ErrorCode::UnknownReauthTarget,
+        );
+        result.unknown_serial_ids = Some(vec![8]);
+        assert!(!result.is_match);
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains(r#""error_reason":"unknown_reauth_target""#));
+        let parsed: ReAuthResult = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.error_code, Some(ErrorCode::UnknownReauthTarget));
+        assert_eq!(parsed.unknown_serial_ids, Some(vec![8]));
+
+        let result = ReAuthResult::error(
+            1,
+            "reauth".to_string(),
+            vec![3],
+            ErrorCode::ShareDecryptionFailed,
+        );
+        assert_eq!(
+            result.error_reason.as_deref(),
+            Some(ERROR_FAILED_TO_PROCESS_IRIS_SHARES)
+        );
+    }
+
+    #[test]
+    fn test_results_without_per_eye_fields_parse() {
+        let json = r#"{
+            "node_id": 0,
+            "serial_id": 12,
+            "is_match": false,
+            "signup_id": "signup",
+            "matched_serial_ids": null
+        }"#;
+        let result: UniquenessResult = serde_json::from_str(json).unwrap();
+        assert_eq!(result.serial_id, Some(12));
+        assert!(!result.is_match);
+        assert_eq!(result.matched_serial_ids_left, None);
+        assert_eq!(result.matched_serial_ids_right, None);
+        assert_eq!(result.match_policy, None);
+        assert_eq!(result.matched_distances, None);
+        assert_eq!(result.min_distance, None);
+    }
+}
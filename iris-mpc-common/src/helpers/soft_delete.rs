@@ -0,0 +1,124 @@
+//! Identity deletions with a grace period.
+//!
+//! A deletion request with a `grace_period_secs` does not overwrite the shares
+//! of the identity right away. The serial id is tombstoned for matching, its
+//! shares stay in the database together with the time they are purged at, and
+//! an [`IdentityRestoreRequest`](super::smpc_request::IdentityRestoreRequest)
+//! until then brings the identity back.
+//!
+//! Purging has to happen at all parties or none, and the clocks of the parties
+//! differ. Every party offers the soft-deletes that are due by its own clock
+//! in the batch sync, and only the ones offered by all parties are purged, see
+//! [`agreed_purges`](super::sync::agreed_purges).
+
+use std::collections::BTreeMap;
+
+/// The most soft-deletes a party offers to purge with a single batch.
+pub const MAX_PURGES_PER_BATCH: usize = 64;
+
+/// The pending soft-deletes of a party, by serial id.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SoftDeletions {
+    purge_at: BTreeMap<u32, u64>,
+}
+
+impl SoftDeletions {
+    /// The soft-deletes loaded from the database, as pairs of serial id and
+    /// purge time in seconds since the epoch.
+    pub fn from_pending(pending: impl IntoIterator<Item = (u32, u64)>) -> Self {
+        let deletions = Self {
+            purge_at: pending.into_iter().collect(),
+        };
+        deletions.report();
+        deletions
+    }
+
+    /// Soft-deletes the serial id until `purge_at`. Deleting it again moves
+    /// the purge time.
+    pub fn soft_delete(&mut self, serial_id: u32, purge_at: u64) {
+        self.purge_at.insert(serial_id, purge_at);
+        self.report();
+    }
+
+    /// Restores the serial id. Returns false if it is not soft-deleted,
+    /// because it was never deleted or it was purged already.
+    pub fn restore(&mut self, serial_id: u32) -> bool {
+        let restored = self.purge_at.remove(&serial_id).is_some();
+        self.report();
+        restored
+    }
+
+    pub fn is_soft_deleted(&self, serial_id: u32) -> bool {
+        self.purge_at.contains_key(&serial_id)
+    }
+
+    /// The serial ids whose grace period is over at `now`, in ascending order
+    /// and at most [`MAX_PURGES_PER_BATCH`] of them.
+    pub fn due(&self, now: u64) -> Vec<u32> {
+        self.purge_at
+            .iter()
+            .filter(|(_, &purge_at)| purge_at <= now)
+            .map(|(&serial_id, _)| serial_id)
+            .take(MAX_PURGES_PER_BATCH)
+            .collect()
+    }
+
+    /// Forgets the serial ids the parties agreed to purge.
+    pub fn purged(&mut self, serial_ids: &[u32]) {
+        for serial_id in serial_ids {
+            self.purge_at.remove(serial_id);
+        }
+        self.report();
+    }
+
+    pub fn len(&self) -> usize {
+        self.purge_at.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.purge_at.is_empty()
+    }
+
+    fn report(&self) {
+        metrics::gauge!("soft_delete.pending").set(self.purge_at.len() as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_deletions() {
+        let mut deletions = SoftDeletions::from_pending([(7, 100), (3, 50)]);
+        deletions.soft_delete(5, 200);
+        assert_eq!(deletions.len(), 3);
+        assert!(deletions.is_soft_deleted(5));
+
+        assert!(deletions.due(49).is_empty());
+        assert_eq!(deletions.due(100), vec![3, 7]);
+
+        // Restoring takes the serial id out of the purge.
+        assert!(deletions.restore(7));
+        assert!(!deletions.restore(7));
+        assert_eq!(deletions.due(100), vec![3]);
+
+        // Deleting again moves the purge time.
+        deletions.soft_delete(3, 300);
+        assert_eq!(deletions.due(200), vec![5]);
+
+        deletions.purged(&[5]);
+        assert!(!deletions.is_soft_deleted(5));
+        assert!(!deletions.restore(5));
+        assert_eq!(deletions.len(), 1);
+    }
+
+    #[test]
+    fn test_due_is_capped() {
+        let deletions =
+            SoftDeletions::from_pending((0..2 * MAX_PURGES_PER_BATCH as u32).map(|i| (i, 0)));
+        let due = deletions.due(0);
+        assert_eq!(due.len(), MAX_PURGES_PER_BATCH);
+        assert_eq!(due, (0..MAX_PURGES_PER_BATCH as u32).collect::<Vec<_>>());
+    }
+}
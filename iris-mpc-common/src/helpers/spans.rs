@@ -0,0 +1,245 @@
+//! Tracing spans following a batch from SQS receive to result publish.
+//!
+//! Every batch gets a `batch` span carrying the batch id, size and party id.
+//! The processing phases are recorded as child spans of it, and the per
+//! request work (fetching and validating the shares) runs in `request` spans
+//! carrying the signup id and the trace id from the message attributes.
+
+use std::fmt;
+use tracing::{field, info_span, Span};
+
+pub const BATCH_SPAN_NAME: &str = "batch";
+pub const REQUEST_SPAN_NAME: &str = "request";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Download of the encrypted shares from S3.
+    Fetch,
+    /// Decryption and validation of the shares.
+    Validate,
+    /// Dot products and their resharing on the GPU.
+    GpuDot,
+    /// Threshold comparison of the dot products on the GPU.
+    GpuThreshold,
+    /// Opening of the comparison results.
+    Open,
+    /// Writing the new irises to the store.
+    Persist,
+    /// Publishing the results to SNS.
+    Publish,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 7] = [
+        Phase::Fetch,
+        Phase::Validate,
+        Phase::GpuDot,
+        Phase::GpuThreshold,
+        Phase::Open,
+        Phase::Persist,
+        Phase::Publish,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Fetch => "fetch",
+            Phase::Validate => "validate",
+            Phase::GpuDot => "gpu_dot",
+            Phase::GpuThreshold => "gpu_threshold",
+            Phase::Open => "open",
+            Phase::Persist => "persist",
+            Phase::Publish => "publish",
+        }
+    }
+
+    /// Creates the span of this phase as a child of the current span.
+    pub fn span(&self) -> Span {
+        self.child_of(&Span::current())
+    }
+
+    /// Creates the span of this phase as a child of the given span.
+    pub fn child_of(&self, parent: &Span) -> Span {
+        // Span names have to be static, hence the match.
+        match self {
+            Phase::Fetch => info_span!(parent: parent, "fetch"),
+            Phase::Validate => info_span!(parent: parent, "validate"),
+            Phase::GpuDot => info_span!(parent: parent, "gpu_dot"),
+            Phase::GpuThreshold => info_span!(parent: parent, "gpu_threshold"),
+            Phase::Open => info_span!(parent: parent, "open"),
+            Phase::Persist => info_span!(parent: parent, "persist"),
+            Phase::Publish => info_span!(parent: parent, "publish"),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Creates the span of a batch. The batch size is only known once the batch
+/// is composed and is recorded with [`record_batch_size`].
+pub fn batch_span(batch_id: u64, party_id: usize) -> Span {
+    info_span!(
+        BATCH_SPAN_NAME,
+        batch_id,
+        party_id,
+        batch_size = field::Empty
+    )
+}
+
+pub fn record_batch_size(span: &Span, batch_size: usize) {
+    span.record("batch_size", batch_size);
+}
+
+/// Creates the span of a single request as a child of the current span.
+pub fn request_span(signup_id: &str, trace_id: &str) -> Span {
+    info_span!(REQUEST_SPAN_NAME, signup_id, trace_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Instrument, Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer,
+    };
+
+    #[derive(Debug, Clone, Default)]
+    struct RecordedSpan {
+        name:   &'static str,
+        parent: Option<&'static str>,
+        fields: HashMap<String, String>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    /// Records every span together with the name of its parent. Span ids are
+    /// reused once a span is closed, so they only index the open spans.
+    #[derive(Clone, Default)]
+    struct RecordingLayer {
+        spans:     Arc<Mutex<Vec<RecordedSpan>>>,
+        open_span: Arc<Mutex<HashMap<u64, usize>>>,
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for RecordingLayer {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut recorded = RecordedSpan {
+                name: span.name(),
+                parent: span.parent().map(|parent| parent.name()),
+                ..Default::default()
+            };
+            attrs.record(&mut FieldVisitor(&mut recorded.fields));
+            let mut spans = self.spans.lock().unwrap();
+            self.open_span
+                .lock()
+                .unwrap()
+                .insert(id.into_u64(), spans.len());
+            spans.push(recorded);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            let index = self.open_span.lock().unwrap()[&id.into_u64()];
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut FieldVisitor(&mut spans[index].fields));
+        }
+
+        fn on_close(&self, id: Id, _ctx: Context<'_, S>) {
+            self.open_span.lock().unwrap().remove(&id.into_u64());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_span_hierarchy() {
+        let layer = RecordingLayer::default();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        // A synthetic batch of two requests, following the same structure as
+        // the server.
+        let batch = batch_span(7, 1);
+        async {
+            for (signup_id, trace_id) in [("signup-a", "trace-a"), ("signup-b", "trace-b")] {
+                async {
+                    async {}.instrument(Phase::Fetch.span()).await;
+                    Phase::Validate.span().in_scope(|| {});
+                }
+                .instrument(request_span(signup_id, trace_id))
+                .await;
+            }
+            record_batch_size(&Span::current(), 2);
+            for phase in [Phase::GpuDot, Phase::GpuThreshold, Phase::Open] {
+                phase.span().in_scope(|| {});
+            }
+        }
+        .instrument(batch.clone())
+        .await;
+        // Persisting and publishing happen outside of the batch span, in the
+        // result sender task.
+        async {}.instrument(Phase::Persist.child_of(&batch)).await;
+        async {}.instrument(Phase::Publish.child_of(&batch)).await;
+
+        let spans = layer.spans.lock().unwrap();
+        let batches = spans
+            .iter()
+            .filter(|s| s.name == BATCH_SPAN_NAME)
+            .collect::<Vec<_>>();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].parent, None);
+        assert_eq!(batches[0].fields["batch_id"], "7");
+        assert_eq!(batches[0].fields["party_id"], "1");
+        assert_eq!(batches[0].fields["batch_size"], "2");
+
+        let mut requests = spans
+            .iter()
+            .filter(|s| s.name == REQUEST_SPAN_NAME)
+            .map(|s| {
+                assert_eq!(s.parent, Some(BATCH_SPAN_NAME));
+                (s.fields["signup_id"].clone(), s.fields["trace_id"].clone())
+            })
+            .collect::<Vec<_>>();
+        requests.sort();
+        assert_eq!(requests, vec![
+            ("signup-a".to_string(), "trace-a".to_string()),
+            ("signup-b".to_string(), "trace-b".to_string()),
+        ]);
+
+        for phase in Phase::ALL {
+            let expected_parent = match phase {
+                Phase::Fetch | Phase::Validate => REQUEST_SPAN_NAME,
+                _ => BATCH_SPAN_NAME,
+            };
+            let phase_spans = spans
+                .iter()
+                .filter(|s| s.name == phase.as_str())
+                .collect::<Vec<_>>();
+            assert!(!phase_spans.is_empty(), "missing {} span", phase);
+            assert!(phase_spans
+                .iter()
+                .all(|s| s.parent == Some(expected_parent)));
+        }
+    }
+}
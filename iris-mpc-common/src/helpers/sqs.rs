@@ -0,0 +1,518 @@
+//! Batched access to an SQS queue: long-polled receives of up to ten messages,
+//! and the deletion or visibility change of many messages with one call per
+//! ten. SQS answers a batch call with the entries that failed. Those are
+//! retried on their own, unless SQS blames the request for the failure, like
+//! for an expired receipt handle.
+
+use super::smpc_request::ReceiveRequestError;
+use async_trait::async_trait;
+use aws_sdk_sqs::{
+    error::SdkError,
+    types::{ChangeMessageVisibilityBatchRequestEntry, DeleteMessageBatchRequestEntry, Message},
+    Client as SQSClient,
+};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// The most messages SQS returns from a receive or takes in a batch call.
+pub const MAX_BATCH_ENTRIES: usize = 10;
+/// The longest long polling wait SQS allows.
+const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+
+/// A message in a batch call, identified by `id` within the batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchEntry {
+    pub id:             String,
+    pub receipt_handle: String,
+}
+
+/// An entry that failed in a batch call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchEntryFailure {
+    pub id:           String,
+    /// Whether SQS blames the request, in which case a retry fails as well.
+    pub sender_fault: bool,
+}
+
+/// The SQS calls of a [`BatchReceiver`]. The batch calls take at most
+/// [`MAX_BATCH_ENTRIES`] entries and return the ones that failed.
+#[async_trait]
+pub trait BatchQueue: Send + Sync {
+    /// Receives up to `max_messages` messages with all their attributes,
+    /// waiting at most `wait_time` for the first one.
+    async fn receive(
+        &self,
+        max_messages: i32,
+        wait_time: Duration,
+    ) -> Result<Vec<Message>, ReceiveRequestError>;
+
+    async fn delete_batch(
+        &self,
+        entries: &[BatchEntry],
+    ) -> Result<Vec<BatchEntryFailure>, ReceiveRequestError>;
+
+    async fn change_visibility_batch(
+        &self,
+        entries: &[BatchEntry],
+        timeout: Duration,
+    ) -> Result<Vec<BatchEntryFailure>, ReceiveRequestError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct SqsBatchQueue {
+    client:    SQSClient,
+    queue_url: String,
+}
+
+impl SqsBatchQueue {
+    pub fn new(client: SQSClient, queue_url: String) -> Self {
+        Self { client, queue_url }
+    }
+}
+
+#[async_trait]
+impl BatchQueue for SqsBatchQueue {
+    async fn receive(
+        &self,
+        max_messages: i32,
+        wait_time: Duration,
+    ) -> Result<Vec<Message>, ReceiveRequestError> {
+        let output = self
+            .client
+            .receive_message()
+            .max_number_of_messages(max_messages)
+            .wait_time_seconds(wait_time.min(MAX_WAIT_TIME).as_secs() as i32)
+            .message_attribute_names("All")
+            .queue_url(&self.queue_url)
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToReadFromSQS)?;
+        Ok(output.messages.unwrap_or_default())
+    }
+
+    async fn delete_batch(
+        &self,
+        entries: &[BatchEntry],
+    ) -> Result<Vec<BatchEntryFailure>, ReceiveRequestError> {
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                DeleteMessageBatchRequestEntry::builder()
+                    .id(&entry.id)
+                    .receipt_handle(&entry.receipt_handle)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                ReceiveRequestError::FailedToDeleteBatchFromSQS(SdkError::construction_failure(e))
+            })?;
+        let output = self
+            .client
+            .delete_message_batch()
+            .queue_url(&self.queue_url)
+            .set_entries(Some(entries))
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToDeleteBatchFromSQS)?;
+        Ok(output
+            .failed()
+            .iter()
+            .map(|failed| BatchEntryFailure {
+                id:           failed.id().to_string(),
+                sender_fault: failed.sender_fault(),
+            })
+            .collect())
+    }
+
+    async fn change_visibility_batch(
+        &self,
+        entries: &[BatchEntry],
+        timeout: Duration,
+    ) -> Result<Vec<BatchEntryFailure>, ReceiveRequestError> {
+        let timeout = timeout.as_secs().min(i32::MAX as u64) as i32;
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                ChangeMessageVisibilityBatchRequestEntry::builder()
+                    .id(&entry.id)
+                    .receipt_handle(&entry.receipt_handle)
+                    .visibility_timeout(timeout)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                ReceiveRequestError::FailedToChangeVisibilityBatch(SdkError::construction_failure(
+                    e,
+                ))
+            })?;
+        let output = self
+            .client
+            .change_message_visibility_batch()
+            .queue_url(&self.queue_url)
+            .set_entries(Some(entries))
+            .send()
+            .await
+            .map_err(ReceiveRequestError::FailedToChangeVisibilityBatch)?;
+        Ok(output
+            .failed()
+            .iter()
+            .map(|failed| BatchEntryFailure {
+                id:           failed.id().to_string(),
+                sender_fault: failed.sender_fault(),
+            })
+            .collect())
+    }
+}
+
+/// How a [`BatchReceiver`] polls the queue and retries failed entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchReceiverConfig {
+    pub max_messages: i32,
+    /// Long polling wait time of a receive, at most 20 seconds.
+    pub wait_time:    Duration,
+    /// The calls per entry of a batch, including the first one.
+    pub max_attempts: usize,
+}
+
+impl Default for BatchReceiverConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: MAX_BATCH_ENTRIES as i32,
+            wait_time:    MAX_WAIT_TIME,
+            max_attempts: 3,
+        }
+    }
+}
+
+/// Receives messages in batches and deletes them or extends their visibility
+/// by their receipt handles, [`MAX_BATCH_ENTRIES`] per call.
+pub struct BatchReceiver {
+    queue:  Arc<dyn BatchQueue>,
+    config: BatchReceiverConfig,
+}
+
+impl BatchReceiver {
+    pub fn new(queue: Arc<dyn BatchQueue>, config: BatchReceiverConfig) -> Self {
+        Self { queue, config }
+    }
+
+    /// Receives the next messages, waiting up to the configured wait time.
+    pub async fn receive(&self) -> Result<Vec<Message>, ReceiveRequestError> {
+        self.queue
+            .receive(self.config.max_messages, self.config.wait_time)
+            .await
+    }
+
+    /// Like [`Self::receive`], but waits no longer than until `deadline`, and
+    /// at least a second.
+    pub async fn receive_until(
+        &self,
+        deadline: Instant,
+    ) -> Result<Vec<Message>, ReceiveRequestError> {
+        let wait_time = deadline
+            .saturating_duration_since(Instant::now())
+            .min(self.config.wait_time)
+            .max(Duration::from_secs(1));
+        self.queue
+            .receive(self.config.max_messages, wait_time)
+            .await
+    }
+
+    /// Keeps the messages hidden from other consumers for `timeout` from now
+    /// on.
+    pub async fn extend_visibility(
+        &self,
+        receipt_handles: &[String],
+        timeout: Duration,
+    ) -> Result<(), ReceiveRequestError> {
+        let failed = self
+            .for_each_batch(receipt_handles, |entries| {
+                let queue = Arc::clone(&self.queue);
+                async move { queue.change_visibility_batch(&entries, timeout).await }
+            })
+            .await?;
+        if !failed.is_empty() {
+            return Err(ReceiveRequestError::BatchVisibilityIncomplete(failed));
+        }
+        Ok(())
+    }
+
+    /// Deletes the messages from the queue.
+    pub async fn delete_batch(
+        &self,
+        receipt_handles: &[String],
+    ) -> Result<(), ReceiveRequestError> {
+        let failed = self
+            .for_each_batch(receipt_handles, |entries| {
+                let queue = Arc::clone(&self.queue);
+                async move { queue.delete_batch(&entries).await }
+            })
+            .await?;
+        if !failed.is_empty() {
+            return Err(ReceiveRequestError::BatchDeleteIncomplete(failed));
+        }
+        Ok(())
+    }
+
+    /// Runs `call` on the receipt handles, [`MAX_BATCH_ENTRIES`] at a time,
+    /// and again on the entries that failed through no fault of the request.
+    /// Returns the receipt handles that failed for good.
+    async fn for_each_batch<F, Fut>(
+        &self,
+        receipt_handles: &[String],
+        mut call: F,
+    ) -> Result<Vec<String>, ReceiveRequestError>
+    where
+        F: FnMut(Vec<BatchEntry>) -> Fut,
+        Fut: Future<Output = Result<Vec<BatchEntryFailure>, ReceiveRequestError>>,
+    {
+        let max_attempts = self.config.max_attempts.max(1);
+        let mut failed_for_good = vec![];
+        for chunk in receipt_handles.chunks(MAX_BATCH_ENTRIES) {
+            let mut entries = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, receipt_handle)| BatchEntry {
+                    id:             i.to_string(),
+                    receipt_handle: receipt_handle.clone(),
+                })
+                .collect::<Vec<_>>();
+            for attempt in 1..=max_attempts {
+                let mut retried = vec![];
+                for failure in call(entries.clone()).await? {
+                    let Some(entry) = entries.iter().find(|entry| entry.id == failure.id) else {
+                        continue;
+                    };
+                    if failure.sender_fault || attempt == max_attempts {
+                        failed_for_good.push(entry.receipt_handle.clone());
+                    } else {
+                        retried.push(entry.clone());
+                    }
+                }
+                if retried.is_empty() {
+                    break;
+                }
+                tracing::warn!("Retrying {} entries of an SQS batch call", retried.len());
+                metrics::counter!("sqs.batch_retry").increment(retried.len() as u64);
+                entries = retried;
+            }
+        }
+        Ok(failed_for_good)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::Mutex,
+    };
+
+    /// A batch call the mock queue received.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Call {
+        Receive(i32, Duration),
+        Delete(Vec<String>),
+        ChangeVisibility(Vec<String>, Duration),
+    }
+
+    /// In-memory queue that records its calls. The receipt handles in
+    /// `failures` fail as often as given, with the given sender fault.
+    #[derive(Default)]
+    struct MockQueue {
+        messages: Mutex<VecDeque<Message>>,
+        calls:    Mutex<Vec<Call>>,
+        failures: Mutex<HashMap<String, (usize, bool)>>,
+    }
+
+    impl MockQueue {
+        fn push(&self, n: usize) {
+            let mut messages = self.messages.lock().unwrap();
+            for _ in 0..n {
+                let receipt_handle = format!("handle-{}", messages.len());
+                messages.push_back(
+                    Message::builder()
+                        .body("{}")
+                        .receipt_handle(receipt_handle)
+                        .build(),
+                );
+            }
+        }
+
+        fn fail(&self, receipt_handle: &str, times: usize, sender_fault: bool) {
+            self.failures
+                .lock()
+                .unwrap()
+                .insert(receipt_handle.to_string(), (times, sender_fault));
+        }
+
+        fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn failed(&self, entries: &[BatchEntry]) -> Vec<BatchEntryFailure> {
+            let mut failures = self.failures.lock().unwrap();
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    let (times, sender_fault) = failures.get_mut(&entry.receipt_handle)?;
+                    if *times == 0 {
+                        return None;
+                    }
+                    *times -= 1;
+                    Some(BatchEntryFailure {
+                        id:           entry.id.clone(),
+                        sender_fault: *sender_fault,
+                    })
+                })
+                .collect()
+        }
+    }
+
+    fn receipt_handles(entries: &[BatchEntry]) -> Vec<String> {
+        entries.iter().map(|e| e.receipt_handle.clone()).collect()
+    }
+
+    #[async_trait]
+    impl BatchQueue for MockQueue {
+        async fn receive(
+            &self,
+            max_messages: i32,
+            wait_time: Duration,
+        ) -> Result<Vec<Message>, ReceiveRequestError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Receive(max_messages, wait_time));
+            let mut messages = self.messages.lock().unwrap();
+            let n = messages.len().min(max_messages as usize);
+            Ok(messages.drain(..n).collect())
+        }
+
+        async fn delete_batch(
+            &self,
+            entries: &[BatchEntry],
+        ) -> Result<Vec<BatchEntryFailure>, ReceiveRequestError> {
+            assert!(entries.len() <= MAX_BATCH_ENTRIES);
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Delete(receipt_handles(entries)));
+            Ok(self.failed(entries))
+        }
+
+        async fn change_visibility_batch(
+            &self,
+            entries: &[BatchEntry],
+            timeout: Duration,
+        ) -> Result<Vec<BatchEntryFailure>, ReceiveRequestError> {
+            assert!(entries.len() <= MAX_BATCH_ENTRIES);
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::ChangeVisibility(receipt_handles(entries), timeout));
+            Ok(self.failed(entries))
+        }
+    }
+
+    fn handles(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| format!("handle-{}", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_receive_in_batches() {
+        let queue = Arc::new(MockQueue::default());
+        queue.push(13);
+        let receiver = BatchReceiver::new(queue.clone(), BatchReceiverConfig::default());
+
+        assert_eq!(receiver.receive().await.unwrap().len(), 10);
+        let messages = receiver.receive().await.unwrap();
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| m.receipt_handle().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["handle-10", "handle-11", "handle-12"]
+        );
+        assert_eq!(queue.calls(), vec![
+            Call::Receive(10, Duration::from_secs(20)),
+            Call::Receive(10, Duration::from_secs(20)),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receive_until() {
+        let queue = Arc::new(MockQueue::default());
+        let receiver = BatchReceiver::new(queue.clone(), BatchReceiverConfig::default());
+        let now = Instant::now();
+        receiver
+            .receive_until(now + Duration::from_secs(5))
+            .await
+            .unwrap();
+        receiver.receive_until(now).await.unwrap();
+        receiver
+            .receive_until(now + Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(queue.calls(), vec![
+            Call::Receive(10, Duration::from_secs(5)),
+            Call::Receive(10, Duration::from_secs(1)),
+            Call::Receive(10, Duration::from_secs(20)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_extend_visibility() {
+        let queue = Arc::new(MockQueue::default());
+        let receiver = BatchReceiver::new(queue.clone(), BatchReceiverConfig::default());
+        let timeout = Duration::from_secs(60);
+        receiver
+            .extend_visibility(&handles(0..12), timeout)
+            .await
+            .unwrap();
+        assert_eq!(queue.calls(), vec![
+            Call::ChangeVisibility(handles(0..10), timeout),
+            Call::ChangeVisibility(handles(10..12), timeout),
+        ]);
+        receiver.extend_visibility(&[], timeout).await.unwrap();
+        assert_eq!(queue.calls().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_delete_batch_retries_failed_entries() {
+        let queue = Arc::new(MockQueue::default());
+        let receiver = BatchReceiver::new(queue.clone(), BatchReceiverConfig::default());
+        queue.fail("handle-3", 1, false);
+        queue.fail("handle-11", 2, false);
+        receiver.delete_batch(&handles(0..12)).await.unwrap();
+        assert_eq!(queue.calls(), vec![
+            Call::Delete(handles(0..10)),
+            Call::Delete(vec!["handle-3".to_string()]),
+            Call::Delete(handles(10..12)),
+            Call::Delete(vec!["handle-11".to_string()]),
+            Call::Delete(vec!["handle-11".to_string()]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_batch_gives_up() {
+        let queue = Arc::new(MockQueue::default());
+        let receiver = BatchReceiver::new(queue.clone(), BatchReceiverConfig::default());
+        // Expired, never retried.
+        queue.fail("handle-0", 1, true);
+        // Fails more often than attempted.
+        queue.fail("handle-1", 5, false);
+        let err = receiver.delete_batch(&handles(0..3)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ReceiveRequestError::BatchDeleteIncomplete(failed)
+                if failed == handles(0..2)
+        ));
+        assert_eq!(queue.calls(), vec![
+            Call::Delete(handles(0..3)),
+            Call::Delete(vec!["handle-1".to_string()]),
+            Call::Delete(vec!["handle-1".to_string()]),
+        ]);
+    }
+}
@@ -45,6 +45,26 @@ pub async fn upload_file_and_generate_presigned_url(
 
     tracing::info!("File uploaded successfully.");
 
+    presign_get_object(&client, bucket, key).await
+}
+
+/// Generates a presigned URL to download `key`, without checking that it
+/// exists.
+pub async fn generate_presigned_url(
+    bucket: &str,
+    key: &str,
+    region: &'static str,
+) -> Result<String, SharesDecodingError> {
+    let region_provider = RegionProviderChain::first_try(region).or_default_provider();
+    let config = aws_config::from_env().region(region_provider).load().await;
+    presign_get_object(&Client::new(&config), bucket, key).await
+}
+
+async fn presign_get_object(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+) -> Result<String, SharesDecodingError> {
     // Create a presigned URL for the uploaded file
     let presigning_config = match PresigningConfig::expires_in(Duration::from_secs(36000)) {
         Ok(config) => config,
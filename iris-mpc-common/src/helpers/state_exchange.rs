@@ -0,0 +1,513 @@
+//! Exchange of the [`SyncState`] between the parties at startup, independent
+//! of the transport. The GPU server exchanges it over NCCL, see
+//! `iris_mpc_gpu::server::sync_nccl::NcclStateExchange`, or over plain TCP
+//! with [`TcpStateExchange`], which works while the GPUs or NCCL are offline.
+//!
+//! Over TCP, every party listens on its own sync address and sends its state
+//! to each other party over a connection it opens itself. The receiving party
+//! only accepts connections from the addresses of the other parties, and
+//! starts each one with a random nonce. A message is the id of the sending
+//! party and the length of the state, both as little-endian `u64`, followed by
+//! the bincode serialization of the state and an HMAC-SHA256 tag. The tag
+//! covers the nonce, the id of the receiving party and the message, keyed by
+//! the long-term secret of the pair, so that a message is neither forged nor
+//! replayed from an earlier startup.
+
+use super::sync::{SyncResult, SyncState};
+use eyre::{bail, eyre, Result};
+use ring::{
+    digest::SHA256_OUTPUT_LEN,
+    hkdf::{Salt, HKDF_SHA256},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The sanity limit of a serialized [`SyncState`]. The states are exchanged
+/// with their lengths first, so they have no fixed size.
+pub const MAX_SYNC_STATE_LEN: usize = 256 << 20;
+
+const N_PARTIES: usize = 3;
+/// Party id and state length.
+const HEADER_LEN: usize = 2 * size_of::<u64>();
+const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = SHA256_OUTPUT_LEN;
+const MAC_KDF_SALT: &[u8] = b"iris-mpc/state-sync-mac/v1";
+/// How long to wait before connecting again to a party that is not up yet.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait before polling the listener again for a connection.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The transport of the startup [`SyncState`] exchange.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateSyncTransport {
+    #[default]
+    Nccl,
+    Tcp,
+}
+
+pub trait StateExchange {
+    /// Sends `state` to the other parties and returns the states of all
+    /// parties, including `state`, ordered by party id.
+    fn exchange(&self, state: &SyncState) -> Result<Vec<SyncState>>;
+
+    fn sync(&self, state: &SyncState) -> Result<SyncResult> {
+        Ok(SyncResult::new(state.clone(), self.exchange(state)?))
+    }
+}
+
+/// [`StateExchange`] over TCP between the 3 parties, see the module docs. The
+/// exchange fails if it does not complete within `timeout`, e.g. because
+/// another party never came up.
+pub struct TcpStateExchange {
+    party_id:  usize,
+    /// The `host:port` sync addresses of all parties, by party id.
+    addresses: Vec<String>,
+    listener:  TcpListener,
+    timeout:   Duration,
+    /// The MAC keys shared with the other parties, by party id.
+    keys:      Vec<Option<hmac::Key>>,
+    rng:       SystemRandom,
+}
+
+impl TcpStateExchange {
+    /// `static_secrets` are the long-term secrets shared with the next and the
+    /// previous party.
+    pub fn new(
+        party_id: usize,
+        addresses: Vec<String>,
+        listener: TcpListener,
+        timeout: Duration,
+        static_secrets: &([u8; 32], [u8; 32]),
+    ) -> Self {
+        let mut keys = vec![None; N_PARTIES];
+        if party_id < N_PARTIES {
+            keys[(party_id + 1) % N_PARTIES] = Some(mac_key(&static_secrets.0));
+            keys[(party_id + N_PARTIES - 1) % N_PARTIES] = Some(mac_key(&static_secrets.1));
+        }
+        Self {
+            party_id,
+            addresses,
+            listener,
+            timeout,
+            keys,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Listens on the sync address of this party.
+    pub fn bind(
+        party_id: usize,
+        addresses: Vec<String>,
+        timeout: Duration,
+        static_secrets: &([u8; 32], [u8; 32]),
+    ) -> Result<Self> {
+        let address = addresses
+            .get(party_id)
+            .ok_or_else(|| eyre!("No state sync address for party {}", party_id))?;
+        let listener = TcpListener::bind(address.as_str())
+            .map_err(|e| eyre!("Failed to listen on state sync address {}: {}", address, e))?;
+        Ok(Self::new(
+            party_id,
+            addresses,
+            listener,
+            timeout,
+            static_secrets,
+        ))
+    }
+
+    fn world_size(&self) -> usize {
+        self.addresses.len()
+    }
+
+    fn key(&self, peer: usize) -> &hmac::Key {
+        self.keys[peer]
+            .as_ref()
+            .expect("a key for every other party")
+    }
+
+    /// Connects to `peer`, retrying until it listens and answers with a
+    /// nonce, and sends `message`.
+    fn send(&self, peer: usize, message: &[u8], deadline: Instant) -> Result<()> {
+        let address = &self.addresses[peer];
+        loop {
+            let remaining = remaining(deadline)?;
+            match self.try_send(peer, message, remaining) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    tracing::debug!("Party {} at {} not reachable yet: {}", peer, address, e);
+                    thread::sleep(CONNECT_RETRY_INTERVAL.min(remaining));
+                }
+            }
+        }
+    }
+
+    fn try_send(&self, peer: usize, message: &[u8], timeout: Duration) -> io::Result<()> {
+        // Resolved on every attempt, the name may only appear once the party is
+        // up.
+        let addr = self.addresses[peer]
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "address resolves to nothing")
+            })?;
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        stream.read_exact(&mut nonce)?;
+        let tag = sign(self.key(peer), &nonce, peer, message);
+        stream.write_all(message)?;
+        stream.write_all(tag.as_ref())?;
+        stream.flush()
+    }
+
+    /// Whether `addr` is the sync address of another party. Names that do not
+    /// resolve yet match nothing.
+    fn is_peer(&self, addr: &SocketAddr) -> bool {
+        let peer_ips = (0..self.world_size())
+            .filter(|&peer| peer != self.party_id)
+            .filter_map(|peer| self.addresses[peer].to_socket_addrs().ok())
+            .flatten()
+            .map(|addr| addr.ip())
+            .collect::<HashSet<IpAddr>>();
+        peer_ips.contains(&addr.ip())
+    }
+
+    /// Accepts a connection from each other party and reads its state.
+    /// Connections from elsewhere, and messages that fail to authenticate or
+    /// repeat a party, are dropped.
+    fn receive(&self, deadline: Instant) -> Result<Vec<Option<SyncState>>> {
+        let mut states = vec![None; self.world_size()];
+        let mut pending = self.world_size() - 1;
+        self.listener.set_nonblocking(true)?;
+        while pending > 0 {
+            let (mut stream, addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    remaining(deadline)?;
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !self.is_peer(&addr) {
+                tracing::warn!("Dropped a state sync connection from {}", addr);
+                continue;
+            }
+            let (party_id, state) = match self.receive_from(&mut stream, deadline) {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::warn!("Dropped a state sync message from {}: {}", addr, e);
+                    continue;
+                }
+            };
+            if states[party_id].is_some() {
+                tracing::warn!("Dropped a repeated state of party {}", party_id);
+                continue;
+            }
+            states[party_id] = Some(state);
+            pending -= 1;
+        }
+        Ok(states)
+    }
+
+    /// Sends a fresh nonce over `stream`, and reads and authenticates the
+    /// message of another party.
+    fn receive_from(
+        &self,
+        stream: &mut TcpStream,
+        deadline: Instant,
+    ) -> Result<(usize, SyncState)> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(remaining(deadline)?))?;
+        stream.set_write_timeout(Some(remaining(deadline)?))?;
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| eyre!("Failed to generate a nonce"))?;
+        stream.write_all(&nonce)?;
+
+        let (party_id, message, tag) = read_message(stream)?;
+        if party_id >= self.world_size() || party_id == self.party_id {
+            bail!("State from invalid party {}", party_id);
+        }
+        verify(self.key(party_id), &nonce, self.party_id, &message, &tag)?;
+        Ok((party_id, decode_state(&message)?))
+    }
+}
+
+impl StateExchange for TcpStateExchange {
+    fn exchange(&self, state: &SyncState) -> Result<Vec<SyncState>> {
+        if self.world_size() != N_PARTIES {
+            bail!(
+                "State exchange needs {} parties, got {}",
+                N_PARTIES,
+                self.world_size()
+            );
+        }
+        if self.party_id >= self.world_size() {
+            bail!("No state sync address for party {}", self.party_id);
+        }
+        let deadline = Instant::now() + self.timeout;
+        let message = encode_message(self.party_id, state)?;
+
+        let (sent, received) = thread::scope(|scope| {
+            let receiver = scope.spawn(|| self.receive(deadline));
+            let sent = (0..self.world_size())
+                .filter(|&peer| peer != self.party_id)
+                .try_for_each(|peer| self.send(peer, &message, deadline));
+            let received = receiver.join().expect("the receiver does not panic");
+            (sent, received)
+        });
+        sent?;
+        let mut states = received?;
+        states[self.party_id] = Some(state.clone());
+        Ok(states.into_iter().map(Option::unwrap).collect())
+    }
+}
+
+/// Fails once `deadline` has passed, otherwise returns the time left.
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        bail!("State exchange over TCP timed out");
+    }
+    Ok(remaining)
+}
+
+/// Derives the MAC key of a pair from its long-term secret, separate from the
+/// seeds derived from the same secret.
+fn mac_key(static_secret: &[u8; 32]) -> hmac::Key {
+    Salt::new(HKDF_SHA256, MAC_KDF_SALT)
+        .extract(static_secret)
+        .expand(&[], hmac::HMAC_SHA256)
+        .expect("the output length of HMAC-SHA256 is valid for HKDF")
+        .into()
+}
+
+fn sign(key: &hmac::Key, nonce: &[u8; NONCE_LEN], receiver: usize, message: &[u8]) -> hmac::Tag {
+    let mut context = hmac::Context::with_key(key);
+    context.update(nonce);
+    context.update(&(receiver as u64).to_le_bytes());
+    context.update(message);
+    context.sign()
+}
+
+fn verify(
+    key: &hmac::Key,
+    nonce: &[u8; NONCE_LEN],
+    receiver: usize,
+    message: &[u8],
+    tag: &[u8],
+) -> Result<()> {
+    let mut signed = nonce.to_vec();
+    signed.extend_from_slice(&(receiver as u64).to_le_bytes());
+    signed.extend_from_slice(message);
+    hmac::verify(key, &signed, tag).map_err(|_| eyre!("State failed to authenticate"))
+}
+
+fn encode_message(party_id: usize, state: &SyncState) -> Result<Vec<u8>> {
+    let state_ser = bincode::serialize(state)?;
+    if state_ser.len() > MAX_SYNC_STATE_LEN {
+        bail!(
+            "State of {} bytes exceeds the limit of {} bytes",
+            state_ser.len(),
+            MAX_SYNC_STATE_LEN
+        );
+    }
+    let mut message = Vec::with_capacity(HEADER_LEN + state_ser.len());
+    message.extend_from_slice(&(party_id as u64).to_le_bytes());
+    message.extend_from_slice(&(state_ser.len() as u64).to_le_bytes());
+    message.extend_from_slice(&state_ser);
+    Ok(message)
+}
+
+/// Reads a message and its tag, returning the claimed sender, the message
+/// including its header, and the tag.
+fn read_message(reader: &mut impl Read) -> Result<(usize, Vec<u8>, [u8; TAG_LEN])> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let party_id = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+    if len > MAX_SYNC_STATE_LEN as u64 {
+        bail!(
+            "State of {} bytes exceeds the limit of {} bytes",
+            len,
+            MAX_SYNC_STATE_LEN
+        );
+    }
+    let mut message = vec![0u8; HEADER_LEN + len as usize];
+    message[..HEADER_LEN].copy_from_slice(&header);
+    reader.read_exact(&mut message[HEADER_LEN..])?;
+    let mut tag = [0u8; TAG_LEN];
+    reader.read_exact(&mut tag)?;
+    Ok((party_id as usize, message, tag))
+}
+
+fn decode_state(message: &[u8]) -> Result<SyncState> {
+    Ok(bincode::deserialize(&message[HEADER_LEN..])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::threshold::ThresholdSchedule;
+
+    /// `SECRETS[i]` is shared between party i and party i + 1.
+    const SECRETS: [[u8; 32]; N_PARTIES] = [[1; 32], [2; 32], [3; 32]];
+
+    fn some_state(db_len: u64) -> SyncState {
+        SyncState {
+            db_len,
+            deleted_request_ids: vec![format!("deleted-{}", db_len)],
+            config_fingerprint: "fingerprint".to_string(),
+            common_config: "{}".to_string(),
+            threshold: ThresholdSchedule::default().sync_state(0),
+            key_generation: None,
+        }
+    }
+
+    fn local_parties(timeout: Duration) -> Vec<TcpStateExchange> {
+        let listeners = (0..N_PARTIES)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect::<Vec<_>>();
+        let addresses = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap().to_string())
+            .collect::<Vec<_>>();
+        listeners
+            .into_iter()
+            .enumerate()
+            .map(|(party_id, listener)| {
+                let secrets = (SECRETS[party_id], SECRETS[(party_id + 2) % N_PARTIES]);
+                TcpStateExchange::new(party_id, addresses.clone(), listener, timeout, &secrets)
+            })
+            .collect()
+    }
+
+    fn run_exchange(parties: &[TcpStateExchange], states: &[SyncState]) -> Vec<Vec<SyncState>> {
+        thread::scope(|scope| {
+            let tasks = parties
+                .iter()
+                .zip(states)
+                .map(|(party, state)| scope.spawn(move || party.exchange(state)))
+                .collect::<Vec<_>>();
+            tasks
+                .into_iter()
+                .map(|task| task.join().unwrap().unwrap())
+                .collect::<Vec<_>>()
+        })
+    }
+
+    #[test]
+    fn test_tcp_exchange() {
+        let parties = local_parties(Duration::from_secs(10));
+        let states = (0..3).map(|i| some_state(100 + i)).collect::<Vec<_>>();
+
+        for (party, all_states) in run_exchange(&parties, &states).into_iter().enumerate() {
+            assert_eq!(all_states, states);
+            let result = SyncResult::new(states[party].clone(), all_states);
+            assert_eq!(result.must_rollback_storage(), Some(100));
+            assert_eq!(result.deleted_request_ids().len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_tcp_exchange_timeout() {
+        let parties = local_parties(Duration::from_millis(500));
+
+        // The last party never joins.
+        let results = thread::scope(|scope| {
+            let tasks = parties[..2]
+                .iter()
+                .map(|party| scope.spawn(move || party.exchange(&some_state(1))))
+                .collect::<Vec<_>>();
+            tasks
+                .into_iter()
+                .map(|task| task.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        for result in results {
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_forged_state_is_rejected() {
+        let parties = local_parties(Duration::from_secs(10));
+        let states = (0..3).map(|i| some_state(100 + i)).collect::<Vec<_>>();
+
+        // Claims to be party 1 with a tiny database, which would force party 0
+        // to roll back, but does not know the secret of the pair.
+        let address = parties[0].listener.local_addr().unwrap();
+        let forger = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut nonce = [0u8; NONCE_LEN];
+            stream.read_exact(&mut nonce).unwrap();
+            let message = encode_message(1, &some_state(1)).unwrap();
+            let tag = sign(&mac_key(&[0xff; 32]), &nonce, 0, &message);
+            stream.write_all(&message).unwrap();
+            stream.write_all(tag.as_ref()).unwrap();
+        });
+        // The forger is the first connection in the backlog of party 0.
+        thread::sleep(Duration::from_millis(100));
+
+        let results = run_exchange(&parties, &states);
+        forger.join().unwrap();
+        assert_eq!(results[0], states);
+        let result = SyncResult::new(states[0].clone(), results[0].clone());
+        assert_eq!(result.must_rollback_storage(), Some(100));
+    }
+
+    #[test]
+    fn test_message_authentication() {
+        let key = mac_key(&SECRETS[0]);
+        let nonce = [7u8; NONCE_LEN];
+        let message = encode_message(1, &some_state(7)).unwrap();
+        let tag = sign(&key, &nonce, 0, &message);
+        assert!(verify(&key, &nonce, 0, &message, tag.as_ref()).is_ok());
+
+        // Another key, nonce, receiver or message.
+        let other_key = mac_key(&SECRETS[1]);
+        assert!(verify(&other_key, &nonce, 0, &message, tag.as_ref()).is_err());
+        assert!(verify(&key, &[8u8; NONCE_LEN], 0, &message, tag.as_ref()).is_err());
+        assert!(verify(&key, &nonce, 2, &message, tag.as_ref()).is_err());
+        let mut altered = message.clone();
+        altered[HEADER_LEN] ^= 1;
+        assert!(verify(&key, &nonce, 0, &altered, tag.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let state = some_state(7);
+        let message = encode_message(2, &state).unwrap();
+        let frame = [message.as_slice(), &[9u8; TAG_LEN]].concat();
+        let (party_id, read, tag) = read_message(&mut &frame[..]).unwrap();
+        assert_eq!((party_id, &read, tag), (2, &message, [9u8; TAG_LEN]));
+        assert_eq!(decode_state(&read).unwrap(), state);
+
+        let mut too_long = frame.clone();
+        too_long[8..16].copy_from_slice(&(MAX_SYNC_STATE_LEN as u64 + 1).to_le_bytes());
+        assert!(read_message(&mut &too_long[..]).is_err());
+
+        assert!(read_message(&mut &frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_only_peers_connect() {
+        let parties = local_parties(Duration::from_secs(1));
+        let local = "127.0.0.1:1".parse().unwrap();
+        assert!(parties[0].is_peer(&local));
+        assert!(!parties[0].is_peer(&"10.1.2.3:1".parse().unwrap()));
+    }
+}
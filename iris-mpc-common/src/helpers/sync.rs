@@ -1,10 +1,169 @@
+use crate::{
+    config::CommonConfig,
+    errors::ErrorCode,
+    helpers::threshold::{
+        check_agreement, ThresholdDivergence, ThresholdParams, ThresholdSchedule,
+        ThresholdSyncState,
+    },
+};
 use itertools::Itertools;
+use ring::digest::{digest, SHA256};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// Number of batches a request is deferred for because not all parties
+/// received it in time, before it is dropped.
+pub const MAX_BATCH_DEFERRALS: u32 = 3;
+
+/// The most identity deletion batches a party offers to apply with a single
+/// batch, see [`agreed_deletion_batches`].
+pub const MAX_DELETION_BATCHES: usize = 16;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SyncState {
     pub db_len:              u64,
     pub deleted_request_ids: Vec<String>,
+    /// Fingerprint of the [`CommonConfig`] of the party.
+    pub config_fingerprint:  String,
+    /// Canonical serialization of the [`CommonConfig`], to report the
+    /// differing fields on a fingerprint mismatch.
+    pub common_config:       String,
+    /// The match threshold of the party and its scheduled changes.
+    pub threshold:           ThresholdSyncState,
+    /// The generation of the share encryption keys of the party, if its key
+    /// chain carries one, see
+    /// [`SharesEncryptionKeyPairs::generation`](crate::helpers::key_pair::SharesEncryptionKeyPairs::generation).
+    pub key_generation:      Option<u64>,
+}
+
+impl SyncState {
+    pub fn new(db_len: u64, deleted_request_ids: Vec<String>, config: &CommonConfig) -> Self {
+        Self {
+            db_len,
+            deleted_request_ids,
+            config_fingerprint: config.fingerprint(),
+            common_config: config.canonical(),
+            threshold: ThresholdSchedule::default().sync_state(0),
+            key_generation: None,
+        }
+    }
+}
+
+/// A setting that differs between the parties, with the value of each party.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field:  String,
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigMismatch {
+    pub fingerprints: Vec<String>,
+    pub diffs:        Vec<FieldDiff>,
+}
+
+impl fmt::Display for ConfigMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "config fingerprints differ between parties: [{}]",
+            self.fingerprints.join(", ")
+        )?;
+        for diff in &self.diffs {
+            write!(f, "\n  {}:", diff.field)?;
+            for (party_id, value) in diff.values.iter().enumerate() {
+                write!(f, " party {} = {}", party_id, value)?;
+                if party_id + 1 < diff.values.len() {
+                    write!(f, ",")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigMismatch {}
+
+/// Why the parties cannot run together, see
+/// [`SyncResult::check_compatibility`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncIncompatibility {
+    Config(ConfigMismatch),
+    /// The share encryption key generation of each party.
+    KeyGeneration(Vec<Option<u64>>),
+}
+
+impl fmt::Display for SyncIncompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(mismatch) => mismatch.fmt(f),
+            Self::KeyGeneration(generations) => {
+                write!(
+                    f,
+                    "share encryption key generations differ between parties:"
+                )?;
+                for (party_id, generation) in generations.iter().enumerate() {
+                    let generation = generation.map_or("<unknown>".to_string(), |g| g.to_string());
+                    write!(f, " party {} = {}", party_id, generation)?;
+                    if party_id + 1 < generations.len() {
+                        write!(f, ",")?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncIncompatibility {}
+
+impl From<ConfigMismatch> for SyncIncompatibility {
+    fn from(mismatch: ConfigMismatch) -> Self {
+        Self::Config(mismatch)
+    }
+}
+
+/// The state of a party where it differs from the others, see
+/// [`SyncResult::divergence_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartyDivergence {
+    pub party_id:                  usize,
+    pub db_len:                    u64,
+    /// The deleted request ids of the party that not all parties hold.
+    pub extra_deleted_request_ids: Vec<String>,
+}
+
+/// How the states of the parties differ, for the operators to tell which
+/// party is behind without inspecting every node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DivergenceReport {
+    /// In party id order.
+    pub parties:         Vec<PartyDivergence>,
+    pub config_mismatch: Option<ConfigMismatch>,
+}
+
+impl fmt::Display for DivergenceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "party states diverge:")?;
+        for party in &self.parties {
+            write!(f, "\n  party {}: db_len = {}", party.party_id, party.db_len)?;
+            if !party.extra_deleted_request_ids.is_empty() {
+                write!(
+                    f,
+                    ", deleted request ids not held by all parties = [{}]",
+                    party.extra_deleted_request_ids.join(", ")
+                )?;
+            }
+        }
+        if let Some(mismatch) = &self.config_mismatch {
+            write!(f, "\n{}", mismatch)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -31,6 +190,117 @@ impl SyncResult {
         }
     }
 
+    /// Fails if the config fingerprint of any party differs, listing the
+    /// settings that differ.
+    pub fn check_config(&self) -> Result<(), ConfigMismatch> {
+        let fingerprints = self
+            .all_states
+            .iter()
+            .map(|s| s.config_fingerprint.clone())
+            .collect::<Vec<_>>();
+        if fingerprints.iter().all_equal() {
+            return Ok(());
+        }
+
+        let configs = self
+            .all_states
+            .iter()
+            .map(|s| match serde_json::from_str::<Value>(&s.common_config) {
+                Ok(Value::Object(map)) => map,
+                _ => Map::new(),
+            })
+            .collect::<Vec<_>>();
+        let diffs = configs
+            .iter()
+            .flat_map(|c| c.keys())
+            .sorted()
+            .dedup()
+            .filter_map(|field| {
+                let values = configs
+                    .iter()
+                    .map(|c| {
+                        c.get(field)
+                            .map_or("<missing>".to_string(), Value::to_string)
+                    })
+                    .collect::<Vec<_>>();
+                (!values.iter().all_equal()).then(|| FieldDiff {
+                    field: field.clone(),
+                    values,
+                })
+            })
+            .collect();
+        Err(ConfigMismatch {
+            fingerprints,
+            diffs,
+        })
+    }
+
+    /// Fails if the parties differ in their config, see [`Self::check_config`],
+    /// or in the generation of their share encryption keys. The config
+    /// fingerprint covers the runtime parameters and the schema version. A
+    /// party whose key chain carries no generation is not compared, so that
+    /// the parties can move to the key chain layout one at a time.
+    pub fn check_compatibility(&self) -> Result<(), SyncIncompatibility> {
+        self.check_config()?;
+        let generations = self
+            .all_states
+            .iter()
+            .map(|s| s.key_generation)
+            .collect::<Vec<_>>();
+        if !generations.iter().flatten().all_equal() {
+            return Err(SyncIncompatibility::KeyGeneration(generations));
+        }
+        Ok(())
+    }
+
+    /// Fails if the parties would start with different match thresholds.
+    pub fn check_threshold(&self) -> Result<ThresholdParams, ThresholdDivergence> {
+        let states = self
+            .all_states
+            .iter()
+            .map(|s| s.threshold.clone())
+            .collect::<Vec<_>>();
+        check_agreement(&states)
+    }
+
+    /// How the states of the parties differ, or `None` if they agree on the
+    /// database length, the deleted request ids and the config.
+    pub fn divergence_report(&self) -> Option<DivergenceReport> {
+        let config_mismatch = self.check_config().err();
+        let common_deleted = self
+            .all_states
+            .iter()
+            .map(|s| s.deleted_request_ids.iter().collect::<HashSet<_>>())
+            .reduce(|common, ids| &common & &ids)
+            .unwrap_or_default();
+        let parties = self
+            .all_states
+            .iter()
+            .enumerate()
+            .map(|(party_id, s)| PartyDivergence {
+                party_id,
+                db_len: s.db_len,
+                extra_deleted_request_ids: s
+                    .deleted_request_ids
+                    .iter()
+                    .filter(|id| !common_deleted.contains(id))
+                    .sorted()
+                    .dedup()
+                    .cloned()
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        let diverged = config_mismatch.is_some()
+            || !parties.iter().map(|p| p.db_len).all_equal()
+            || parties
+                .iter()
+                .any(|p| !p.extra_deleted_request_ids.is_empty());
+        diverged.then_some(DivergenceReport {
+            parties,
+            config_mismatch,
+        })
+    }
+
     pub fn deleted_request_ids(&self) -> Vec<String> {
         // Merge request IDs.
         self.all_states
@@ -42,9 +312,224 @@ impl SyncResult {
     }
 }
 
+/// Canonical key of a request in the batch composition: a hash of its
+/// request id, so that the order of a batch does not depend on the arrival
+/// order of its requests at any party.
+pub fn batch_key(request_id: &str) -> u64 {
+    let digest = digest(&SHA256, request_id.as_bytes());
+    u64::from_le_bytes(digest.as_ref()[..8].try_into().unwrap())
+}
+
+/// Digest of the OR-rule serial ids of a request as sorted 0-indexed ids, see
+/// [`UniquenessRequest::or_rule_indices`](crate::helpers::smpc_request::UniquenessRequest::or_rule_indices),
+/// so that the parties make sure they decide the request with the same ones.
+pub fn or_rule_key(serial_ids: &[u32]) -> u64 {
+    let bytes = serial_ids
+        .iter()
+        .flat_map(|serial_id| serial_id.to_le_bytes())
+        .collect::<Vec<_>>();
+    let digest = digest(&SHA256, &bytes);
+    u64::from_le_bytes(digest.as_ref()[..8].try_into().unwrap())
+}
+
+/// A request a party can put into the next batch, see [`BatchSyncState`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchCandidate {
+    /// See [`batch_key`].
+    pub key:            u64,
+    /// Whether this is the mirrored check of the request with the same key.
+    pub mirrored_check: bool,
+    /// The priority lane the party assigned the request to.
+    pub lane:           u8,
+    /// Why the party cannot process the request, as an [`ErrorCode`] number.
+    pub error:          Option<u16>,
+    /// Whether the party wants to shed the request, see
+    /// [`latency_budget`](crate::helpers::latency_budget).
+    pub shed:           bool,
+    /// See [`or_rule_key`], `None` for a request without OR-rule serial ids.
+    #[serde(default)]
+    pub or_rule:        Option<u64>,
+}
+
+/// The candidates of a party for the next batch, exchanged before the batch
+/// is processed. The batch is made of the candidates held by all parties, in
+/// the order of their keys, see [`compose_batch`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchSyncState {
+    /// Sorted by key, with a mirrored check following its request.
+    pub candidates:       Vec<BatchCandidate>,
+    /// The batch size the party is limited to by its free device memory, see
+    /// [`memory_pressure`](crate::helpers::memory_pressure).
+    #[serde(default)]
+    pub batch_size_limit: Option<usize>,
+    /// The soft-deletes that are due by the clock of the party, see
+    /// [`soft_delete`](crate::helpers::soft_delete).
+    #[serde(default)]
+    pub purge_due:        Vec<u32>,
+    /// The [`batch_key`]s of the identity deletion batches the party holds,
+    /// at most [`MAX_DELETION_BATCHES`] of them.
+    #[serde(default)]
+    pub deletion_batches: Vec<u64>,
+}
+
+impl BatchSyncState {
+    pub fn new(mut candidates: Vec<BatchCandidate>) -> Self {
+        candidates.sort_by_key(|c| (c.key, c.mirrored_check));
+        Self {
+            candidates,
+            batch_size_limit: None,
+            purge_due: vec![],
+            deletion_batches: vec![],
+        }
+    }
+}
+
+/// What becomes of a candidate of the batch, see [`compose_batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchDecision {
+    Process,
+    /// The request failed at some party, with the error of the lowest party
+    /// id. The lanes or the OR-rule serial ids of the parties differing count
+    /// as an internal error.
+    Fail(u16),
+    /// Some party sheds the request, all parties requeue it.
+    Shed,
+    /// Some party does not hold the request yet, the parties that do requeue
+    /// it for a later batch, up to [`MAX_BATCH_DEFERRALS`] times.
+    Defer,
+}
+
+/// Decides on the candidates of `own` given the states of all parties, in
+/// party id order. Returns one decision per candidate of `own`, in its order.
+/// The candidates held by all parties are in the same order at every party,
+/// so the parties agree on the batch regardless of the order in which their
+/// requests arrived.
+///
+/// The batch holds at most as many requests as the smallest
+/// [`BatchSyncState::batch_size_limit`], the requests beyond it are shed
+/// together with their mirrored checks.
+pub fn compose_batch(own: &BatchSyncState, all_states: &[BatchSyncState]) -> Vec<BatchDecision> {
+    let held = all_states
+        .iter()
+        .map(|state| {
+            state
+                .candidates
+                .iter()
+                .map(|c| ((c.key, c.mirrored_check), c))
+                .collect::<HashMap<_, _>>()
+        })
+        .collect::<Vec<_>>();
+    let mut decisions = own
+        .candidates
+        .iter()
+        .map(|candidate| {
+            let views = held
+                .iter()
+                .map(|held| held.get(&(candidate.key, candidate.mirrored_check)))
+                .collect::<Option<Vec<_>>>();
+            let Some(views) = views else {
+                return BatchDecision::Defer;
+            };
+            if let Some(error) = views.iter().find_map(|c| c.error) {
+                BatchDecision::Fail(error)
+            } else if !views.iter().map(|c| c.lane).all_equal()
+                || !views.iter().map(|c| c.or_rule).all_equal()
+            {
+                BatchDecision::Fail(ErrorCode::Internal.as_u16())
+            } else if views.iter().any(|c| c.shed) {
+                BatchDecision::Shed
+            } else {
+                BatchDecision::Process
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(limit) = all_states.iter().filter_map(|s| s.batch_size_limit).min() {
+        let mut admitted = 0;
+        let mut over_limit = None;
+        for (candidate, decision) in own.candidates.iter().zip(decisions.iter_mut()) {
+            if !matches!(decision, BatchDecision::Process | BatchDecision::Fail(_)) {
+                continue;
+            }
+            if candidate.mirrored_check {
+                if over_limit == Some(candidate.key) {
+                    *decision = BatchDecision::Shed;
+                }
+            } else if admitted < limit {
+                admitted += 1;
+            } else {
+                *decision = BatchDecision::Shed;
+                over_limit = Some(candidate.key);
+            }
+        }
+    }
+    decisions
+}
+
+/// The soft-deletes to purge with the batch: the ones that are due at all
+/// parties, in ascending order. A soft-delete that is not due at some party yet
+/// is offered again with a later batch.
+pub fn agreed_purges(all_states: &[BatchSyncState]) -> Vec<u32> {
+    let Some((first, rest)) = all_states.split_first() else {
+        return vec![];
+    };
+    first
+        .purge_due
+        .iter()
+        .copied()
+        .filter(|serial_id| rest.iter().all(|s| s.purge_due.contains(serial_id)))
+        .sorted()
+        .dedup()
+        .collect()
+}
+
+/// The identity deletion batches to apply with the batch: the ones held by all
+/// parties, in ascending order of their keys. A deletion batch is applied as a
+/// whole, so the parties delete either the same serial ids or none. One that
+/// some party does not hold yet is offered again with a later batch.
+pub fn agreed_deletion_batches(all_states: &[BatchSyncState]) -> Vec<u64> {
+    let Some((first, rest)) = all_states.split_first() else {
+        return vec![];
+    };
+    first
+        .deletion_batches
+        .iter()
+        .copied()
+        .filter(|key| rest.iter().all(|s| s.deletion_batches.contains(key)))
+        .sorted()
+        .dedup()
+        .collect()
+}
+
+/// Counts how often requests were deferred, see [`BatchDecision::Defer`].
+#[derive(Debug, Clone, Default)]
+pub struct BatchDeferrals {
+    counts: HashMap<String, u32>,
+}
+
+impl BatchDeferrals {
+    /// Records a deferral of the request. Returns false once it was deferred
+    /// [`MAX_BATCH_DEFERRALS`] times, the request is then dropped.
+    pub fn defer(&mut self, request_id: &str) -> bool {
+        let count = self.counts.entry(request_id.to_string()).or_default();
+        *count += 1;
+        if *count > MAX_BATCH_DEFERRALS {
+            self.counts.remove(request_id);
+            return false;
+        }
+        true
+    }
+
+    /// Forgets the deferrals of a request that made it into a batch.
+    pub fn clear(&mut self, request_id: &str) {
+        self.counts.remove(request_id);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{MatchPolicy, ResultMode};
 
     #[test]
     fn test_compare_states_sync() {
@@ -58,18 +543,9 @@ mod tests {
     #[test]
     fn test_compare_states_out_of_sync() {
         let states = vec![
-            SyncState {
-                db_len:              123,
-                deleted_request_ids: vec!["most late".to_string()],
-            },
-            SyncState {
-                db_len:              456,
-                deleted_request_ids: vec!["x".to_string(), "y".to_string()],
-            },
-            SyncState {
-                db_len:              789,
-                deleted_request_ids: vec!["most ahead".to_string()],
-            },
+            SyncState::new(123, vec!["most late".to_string()], &some_config()),
+            SyncState::new(456, vec!["x".to_string(), "y".to_string()], &some_config()),
+            SyncState::new(789, vec!["most ahead".to_string()], &some_config()),
         ];
         let deleted_request_ids = vec![
             "most ahead".to_string(),
@@ -86,10 +562,414 @@ mod tests {
         assert_eq!(sync_res.deleted_request_ids(), deleted_request_ids);
     }
 
-    fn some_state() -> SyncState {
-        SyncState {
-            db_len:              123,
-            deleted_request_ids: vec!["abc".to_string(), "def".to_string()],
+    #[test]
+    fn test_divergence_report_one_ahead() {
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![
+                some_state(),
+                SyncState {
+                    db_len: 125,
+                    ..some_state()
+                },
+                some_state(),
+            ],
+        };
+        assert_eq!(sync_res.must_rollback_storage(), Some(123));
+        let report = sync_res.divergence_report().unwrap();
+        assert_eq!(
+            report.parties.iter().map(|p| p.db_len).collect::<Vec<_>>(),
+            vec![123, 125, 123]
+        );
+        assert!(report
+            .parties
+            .iter()
+            .all(|p| p.extra_deleted_request_ids.is_empty()));
+        assert_eq!(report.config_mismatch, None);
+        assert_eq!(
+            report.to_string(),
+            "party states diverge:\n  party 0: db_len = 123\n  party 1: db_len = 125\n  party 2: \
+             db_len = 123"
+        );
+
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![some_state(), some_state(), some_state()],
+        };
+        assert_eq!(sync_res.divergence_report(), None);
+    }
+
+    #[test]
+    fn test_divergence_report_deleted_ids() {
+        let state = |deleted: &[&str]| {
+            SyncState::new(
+                123,
+                deleted.iter().map(|id| id.to_string()).collect(),
+                &some_config(),
+            )
+        };
+        let sync_res = SyncResult {
+            my_state:   state(&["a", "b"]),
+            all_states: vec![state(&["a", "b"]), state(&["b", "a", "c"]), state(&["a"])],
+        };
+        assert_eq!(sync_res.must_rollback_storage(), None);
+        let report = sync_res.divergence_report().unwrap();
+        assert_eq!(report.parties, vec![
+            PartyDivergence {
+                party_id:                  0,
+                db_len:                    123,
+                extra_deleted_request_ids: vec!["b".to_string()],
+            },
+            PartyDivergence {
+                party_id:                  1,
+                db_len:                    123,
+                extra_deleted_request_ids: vec!["b".to_string(), "c".to_string()],
+            },
+            PartyDivergence {
+                party_id:                  2,
+                db_len:                    123,
+                extra_deleted_request_ids: vec![],
+            },
+        ]);
+        assert!(report.to_string().contains(
+            "\n  party 1: db_len = 123, deleted request ids not held by all parties = [b, c]"
+        ));
+    }
+
+    #[test]
+    fn test_divergence_report_config_mismatch() {
+        let mut config = some_config();
+        config.max_batch_size = 32;
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![
+                some_state(),
+                some_state(),
+                SyncState::new(123, vec!["abc".to_string(), "def".to_string()], &config),
+            ],
+        };
+        let report = sync_res.divergence_report().unwrap();
+        assert_eq!(report.config_mismatch, sync_res.check_config().err());
+        assert!(report
+            .to_string()
+            .contains("\n  max_batch_size: party 0 = 64"));
+    }
+
+    #[test]
+    fn test_matching_configs() {
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![some_state(), some_state(), some_state()],
+        };
+        assert_eq!(sync_res.check_config(), Ok(()));
+    }
+
+    #[test]
+    fn test_config_mismatch() {
+        let mut config = some_config();
+        config.max_batch_size = 32;
+        let other = SyncState::new(123, vec![], &config);
+        assert_ne!(other.config_fingerprint, some_state().config_fingerprint);
+
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![some_state(), other, some_state()],
+        };
+        let err = sync_res.check_config().unwrap_err();
+        assert_eq!(err.diffs, vec![FieldDiff {
+            field:  "max_batch_size".to_string(),
+            values: vec!["64".to_string(), "32".to_string(), "64".to_string()],
+        }]);
+    }
+
+    #[test]
+    fn test_config_mismatch_report() {
+        let mut config = some_config();
+        config.device_count = 4;
+        config.enable_mirrored_checks = true;
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![
+                some_state(),
+                some_state(),
+                SyncState::new(123, vec![], &config),
+            ],
+        };
+        let report = sync_res.check_config().unwrap_err().to_string();
+        assert!(report.starts_with("config fingerprints differ between parties"));
+        assert!(report.contains(&some_config().fingerprint()));
+        assert!(report.contains(&config.fingerprint()));
+        assert!(report.contains("\n  device_count: party 0 = 8, party 1 = 8, party 2 = 4"));
+        assert!(report.contains(
+            "\n  enable_mirrored_checks: party 0 = false, party 1 = false, party 2 = true"
+        ));
+        assert!(!report.contains("max_batch_size"));
+    }
+
+    #[test]
+    fn test_key_generation_mismatch() {
+        let with_generation = |generation| SyncState {
+            key_generation: generation,
+            ..some_state()
+        };
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![
+                with_generation(Some(2)),
+                with_generation(None),
+                with_generation(Some(2)),
+            ],
+        };
+        assert_eq!(sync_res.check_compatibility(), Ok(()));
+
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![
+                with_generation(Some(2)),
+                with_generation(None),
+                with_generation(Some(3)),
+            ],
+        };
+        let err = sync_res.check_compatibility().unwrap_err();
+        assert_eq!(
+            err,
+            SyncIncompatibility::KeyGeneration(vec![Some(2), None, Some(3)])
+        );
+        assert_eq!(
+            err.to_string(),
+            "share encryption key generations differ between parties: party 0 = 2, party 1 = \
+             <unknown>, party 2 = 3"
+        );
+
+        // A config mismatch is reported first.
+        let mut config = some_config();
+        config.schema_version = 2;
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![
+                with_generation(Some(2)),
+                SyncState::new(123, vec![], &config),
+            ],
+        };
+        assert!(matches!(
+            sync_res.check_compatibility(),
+            Err(SyncIncompatibility::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_threshold_mismatch() {
+        let mut other = some_state();
+        other.threshold.active.version = 1;
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![some_state(), some_state(), other],
+        };
+        assert!(sync_res.check_threshold().is_err());
+
+        let sync_res = SyncResult {
+            my_state:   some_state(),
+            all_states: vec![some_state(), some_state(), some_state()],
+        };
+        assert_eq!(
+            sync_res.check_threshold(),
+            Ok(some_state().threshold.active)
+        );
+    }
+
+    fn candidates(request_ids: &[&str]) -> BatchSyncState {
+        BatchSyncState::new(
+            request_ids
+                .iter()
+                .map(|id| BatchCandidate {
+                    key:            batch_key(id),
+                    mirrored_check: false,
+                    lane:           0,
+                    error:          None,
+                    shed:           false,
+                    or_rule:        None,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_compose_batch_ignores_arrival_order() {
+        let states = vec![
+            candidates(&["a", "b", "c", "d"]),
+            candidates(&["d", "c", "b", "a"]),
+            // The third party has not received "b" yet.
+            candidates(&["c", "a", "d", "e"]),
+        ];
+        let batches = states
+            .iter()
+            .map(|own| {
+                own.candidates
+                    .iter()
+                    .zip(compose_batch(own, &states))
+                    .filter(|(_, decision)| *decision == BatchDecision::Process)
+                    .map(|(c, _)| c.key)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(batches.iter().all_equal());
+        let mut expected = ["a", "c", "d"].map(batch_key).to_vec();
+        expected.sort();
+        assert_eq!(batches[0], expected);
+
+        let deferred = |own: &BatchSyncState| {
+            own.candidates
+                .iter()
+                .zip(compose_batch(own, &states))
+                .filter(|(_, decision)| *decision == BatchDecision::Defer)
+                .map(|(c, _)| c.key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(deferred(&states[0]), vec![batch_key("b")]);
+        assert_eq!(deferred(&states[2]), vec![batch_key("e")]);
+    }
+
+    #[test]
+    fn test_compose_batch_merges_flags() {
+        let mut states = vec![candidates(&["a", "b", "c", "d", "e"]); 3];
+        let index = |state: &BatchSyncState, id| {
+            state
+                .candidates
+                .iter()
+                .position(|c| c.key == batch_key(id))
+                .unwrap()
+        };
+        let (a, b, c, d, e) = (
+            index(&states[0], "a"),
+            index(&states[0], "b"),
+            index(&states[0], "c"),
+            index(&states[0], "d"),
+            index(&states[0], "e"),
+        );
+        states[2].candidates[a].error = Some(203);
+        states[1].candidates[a].error = Some(210);
+        states[1].candidates[b].shed = true;
+        states[2].candidates[c].lane = 1;
+        for state in states.iter_mut() {
+            state.candidates[d].or_rule = Some(or_rule_key(&[2, 6]));
+            state.candidates[e].or_rule = Some(or_rule_key(&[2, 6]));
+        }
+        states[1].candidates[e].or_rule = Some(or_rule_key(&[2]));
+
+        let decisions = compose_batch(&states[0], &states);
+        assert_eq!(decisions[a], BatchDecision::Fail(210));
+        assert_eq!(decisions[b], BatchDecision::Shed);
+        assert_eq!(
+            decisions[c],
+            BatchDecision::Fail(ErrorCode::Internal.as_u16())
+        );
+        assert_eq!(decisions[d], BatchDecision::Process);
+        assert_eq!(
+            decisions[e],
+            BatchDecision::Fail(ErrorCode::Internal.as_u16())
+        );
+    }
+
+    #[test]
+    fn test_compose_batch_applies_smallest_limit() {
+        let mut states = vec![candidates(&["a", "b", "c", "d"]); 3];
+        // The mirrored check of the third request in key order.
+        let mut mirrored = states[0].candidates[2];
+        mirrored.mirrored_check = true;
+        for state in states.iter_mut() {
+            state.candidates.insert(3, mirrored);
+        }
+        states[0].candidates[0].error = Some(203);
+        states[1].batch_size_limit = Some(3);
+        states[2].batch_size_limit = Some(2);
+
+        for own in states.iter() {
+            assert_eq!(compose_batch(own, &states), vec![
+                BatchDecision::Fail(203),
+                BatchDecision::Process,
+                BatchDecision::Shed,
+                BatchDecision::Shed,
+                BatchDecision::Shed,
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_agreed_purges() {
+        let mut states = vec![BatchSyncState::default(); 3];
+        assert!(agreed_purges(&states).is_empty());
+
+        states[0].purge_due = vec![1, 4, 9];
+        states[1].purge_due = vec![4, 9];
+        states[2].purge_due = vec![1, 9, 4];
+        assert_eq!(agreed_purges(&states), vec![4, 9]);
+
+        // A party whose clock is behind holds the purge back.
+        states[2].purge_due.clear();
+        assert!(agreed_purges(&states).is_empty());
+    }
+
+    #[test]
+    fn test_agreed_deletion_batches() {
+        let mut states = vec![BatchSyncState::default(); 3];
+        assert!(agreed_deletion_batches(&states).is_empty());
+
+        let (a, b) = (batch_key("a"), batch_key("b"));
+        states[0].deletion_batches = vec![a, b];
+        states[1].deletion_batches = vec![b, a];
+        states[2].deletion_batches = vec![b];
+        assert_eq!(agreed_deletion_batches(&states), vec![b]);
+
+        // Once the last party received it, the other batch is applied too.
+        states[2].deletion_batches.push(a);
+        assert_eq!(agreed_deletion_batches(&states), vec![a.min(b), a.max(b)]);
+    }
+
+    #[test]
+    fn test_deferral_limit() {
+        let mut deferrals = BatchDeferrals::default();
+        for _ in 0..MAX_BATCH_DEFERRALS {
+            assert!(deferrals.defer("a"));
+        }
+        assert!(!deferrals.defer("a"));
+        // A dropped request starts over if it is sent again.
+        assert!(deferrals.defer("a"));
+
+        assert!(deferrals.defer("b"));
+        deferrals.clear("b");
+        for _ in 0..MAX_BATCH_DEFERRALS {
+            assert!(deferrals.defer("b"));
         }
     }
+
+    fn some_config() -> CommonConfig {
+        CommonConfig {
+            match_threshold_ratio:     0.375,
+            iris_code_length:          12800,
+            mask_code_length:          12800,
+            max_batch_size:            64,
+            max_db_size:               1000,
+            interactive_min_share:     0.5,
+            return_partial_results:    false,
+            enable_mirrored_checks:    false,
+            max_rotation_window:       15,
+            disable_persistence:       false,
+            replay_window_secs:        86400,
+            result_mode:               ResultMode::FullOpen,
+            reveal_matched_serial_ids: false,
+            match_policy:              MatchPolicy::And,
+            warmup_rounds:             1,
+            share_audit_sample_size:   64,
+            schema_version:            1,
+            device_count:              8,
+        }
+    }
+
+    fn some_state() -> SyncState {
+        SyncState::new(
+            123,
+            vec!["abc".to_string(), "def".to_string()],
+            &some_config(),
+        )
+    }
 }
@@ -0,0 +1,552 @@
+//! Runtime changes of the match threshold.
+//!
+//! The threshold is changed through a signed control message on the request
+//! topic, carrying the new [`ThresholdParams`] and the batch from which on
+//! they apply. Each party checks the signature against the configured operator
+//! key and schedules the change. Before every batch, the parties exchange
+//! their [`ThresholdSyncState`], and the batch is only processed if all of them
+//! are about to use the same parameters.
+//!
+//! [`MatchThreshold`] is the validated ratio with its fixed-point form, the
+//! one both the GPU circuits and the CPU protocol compare with.
+
+use crate::iris_db::iris::MATCH_THRESHOLD_RATIO;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Upper bound of the scheduled but not yet active changes, to keep the sync
+/// state bounded.
+pub const MAX_PENDING_THRESHOLDS: usize = 16;
+
+/// A match threshold ratio in (0, 0.5), a distance below it is a match.
+///
+/// The comparison `code_dot / mask_dot > 1 - 2 * ratio` runs on secret shares
+/// in fixed point as `mask_dot * A < code_dot * 2^B_BITS`, with
+/// `A = (1 - 2 * ratio) * 2^B_BITS`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct MatchThreshold {
+    ratio: f64,
+}
+
+impl MatchThreshold {
+    pub const B_BITS: u64 = 16;
+    pub const B: u64 = 1 << Self::B_BITS;
+
+    pub fn new(ratio: f64) -> Result<Self, ThresholdError> {
+        if ratio > 0. && ratio < 0.5 {
+            Ok(Self { ratio })
+        } else {
+            Err(ThresholdError::InvalidParams(ratio))
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// The factor of the mask dot product in the comparison
+    /// `mask_dot * A < code_dot * 2^B_BITS`.
+    pub fn a(&self) -> u64 {
+        ((1. - 2. * self.ratio) * Self::B as f64) as u64
+    }
+}
+
+// A valid threshold never holds a NaN ratio.
+impl Eq for MatchThreshold {}
+
+impl Default for MatchThreshold {
+    fn default() -> Self {
+        Self {
+            ratio: MATCH_THRESHOLD_RATIO,
+        }
+    }
+}
+
+impl TryFrom<f64> for MatchThreshold {
+    type Error = ThresholdError;
+
+    fn try_from(ratio: f64) -> Result<Self, Self::Error> {
+        Self::new(ratio)
+    }
+}
+
+impl From<MatchThreshold> for f64 {
+    fn from(threshold: MatchThreshold) -> Self {
+        threshold.ratio
+    }
+}
+
+/// Parses the ratio, e.g. from an environment variable or a CLI argument.
+impl FromStr for MatchThreshold {
+    type Err = ThresholdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ratio = s
+            .trim()
+            .parse()
+            .map_err(|_| ThresholdError::InvalidRatio(s.to_string()))?;
+        Self::new(ratio)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdParams {
+    /// Increases with every change, reported in the results.
+    pub version:               u32,
+    pub match_threshold_ratio: f64,
+}
+
+impl Default for ThresholdParams {
+    fn default() -> Self {
+        Self {
+            version:               0,
+            match_threshold_ratio: MATCH_THRESHOLD_RATIO,
+        }
+    }
+}
+
+// Valid params never hold a NaN ratio.
+impl Eq for ThresholdParams {}
+
+impl Hash for ThresholdParams {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.version.hash(state);
+        self.match_threshold_ratio.to_bits().hash(state);
+    }
+}
+
+impl ThresholdParams {
+    /// The initial parameters, before any runtime change.
+    pub fn initial(threshold: MatchThreshold) -> Self {
+        Self {
+            version:               0,
+            match_threshold_ratio: threshold.ratio(),
+        }
+    }
+
+    /// The threshold of valid params, see [`Self::validate`].
+    pub fn match_threshold(&self) -> MatchThreshold {
+        MatchThreshold {
+            ratio: self.match_threshold_ratio,
+        }
+    }
+
+    /// The factor of the mask dot product in the comparison
+    /// `mask_dot * A < code_dot * 2^16`.
+    pub fn a(&self) -> u64 {
+        self.match_threshold().a()
+    }
+
+    pub fn validate(&self) -> Result<(), ThresholdError> {
+        MatchThreshold::new(self.match_threshold_ratio).map(|_| ())
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ThresholdError {
+    #[error("Threshold update has an invalid signature")]
+    InvalidSignature,
+    #[error("Match threshold ratio {0} is outside of (0, 0.5)")]
+    InvalidParams(f64),
+    #[error("Match threshold ratio {0:?} is not a number")]
+    InvalidRatio(String),
+    #[error("Threshold version {version} is not newer than version {latest}")]
+    StaleVersion { version: u32, latest: u32 },
+    #[error("Activation batch {activation_batch} has already passed, next batch is {next_batch}")]
+    ActivationPassed {
+        activation_batch: u64,
+        next_batch:       u64,
+    },
+    #[error("Too many pending threshold updates")]
+    TooManyPending,
+}
+
+/// A change of the threshold parameters, taking effect with the batch
+/// `activation_batch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ScheduledThreshold {
+    pub activation_batch: u64,
+    pub params:           ThresholdParams,
+}
+
+/// The control message changing the threshold, published with the message
+/// type [`crate::helpers::smpc_request::THRESHOLD_UPDATE_MESSAGE_TYPE`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdUpdateRequest {
+    pub params:           ThresholdParams,
+    pub activation_batch: u64,
+    /// Base64 encoded Ed25519 signature of the operator over the params and
+    /// the activation batch.
+    pub signature:        String,
+}
+
+impl ThresholdUpdateRequest {
+    fn signing_payload(params: &ThresholdParams, activation_batch: u64) -> Vec<u8> {
+        serde_json::to_vec(&(params, activation_batch)).expect("params serialize to JSON")
+    }
+
+    pub fn new_signed(
+        params: ThresholdParams,
+        activation_batch: u64,
+        operator_key: &Ed25519KeyPair,
+    ) -> Self {
+        let signature = operator_key.sign(&Self::signing_payload(&params, activation_batch));
+        Self {
+            params,
+            activation_batch,
+            signature: STANDARD.encode(signature.as_ref()),
+        }
+    }
+
+    /// Checks the signature against the raw Ed25519 public key of the
+    /// operator.
+    pub fn verify(&self, operator_public_key: &[u8]) -> Result<ScheduledThreshold, ThresholdError> {
+        let signature = STANDARD
+            .decode(&self.signature)
+            .map_err(|_| ThresholdError::InvalidSignature)?;
+        UnparsedPublicKey::new(&ED25519, operator_public_key)
+            .verify(
+                &Self::signing_payload(&self.params, self.activation_batch),
+                &signature,
+            )
+            .map_err(|_| ThresholdError::InvalidSignature)?;
+        self.params.validate()?;
+        Ok(ScheduledThreshold {
+            activation_batch: self.activation_batch,
+            params:           self.params,
+        })
+    }
+}
+
+/// What a party exchanges before a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThresholdSyncState {
+    /// The number of the batch about to be processed.
+    pub batch:   u64,
+    /// The parameters the party will use for the batch.
+    pub active:  ThresholdParams,
+    /// Changes scheduled for later batches.
+    pub pending: Vec<ScheduledThreshold>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThresholdDivergence {
+    pub states: Vec<ThresholdSyncState>,
+}
+
+impl fmt::Display for ThresholdDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "parties disagree on the threshold:")?;
+        for (party_id, state) in self.states.iter().enumerate() {
+            write!(
+                f,
+                " party {} = version {} at batch {}",
+                party_id, state.active.version, state.batch
+            )?;
+            if party_id + 1 < self.states.len() {
+                write!(f, ",")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ThresholdDivergence {}
+
+/// Returns the parameters for the batch if all parties are at the same batch
+/// and use the same parameters. Differences in the pending changes are
+/// tolerated, as the control message may reach the parties at different
+/// batches.
+pub fn check_agreement(
+    states: &[ThresholdSyncState],
+) -> Result<ThresholdParams, ThresholdDivergence> {
+    match states.first() {
+        Some(first)
+            if states
+                .iter()
+                .all(|s| s.batch == first.batch && s.active == first.active) =>
+        {
+            Ok(first.active)
+        }
+        _ => Err(ThresholdDivergence {
+            states: states.to_vec(),
+        }),
+    }
+}
+
+/// The active threshold parameters and the scheduled changes of a party.
+#[derive(Debug, Clone, Default)]
+pub struct ThresholdSchedule {
+    active:  ThresholdParams,
+    /// Ordered by activation batch and version.
+    pending: Vec<ScheduledThreshold>,
+}
+
+impl ThresholdSchedule {
+    pub fn new(active: ThresholdParams) -> Self {
+        Self {
+            active,
+            pending: vec![],
+        }
+    }
+
+    pub fn active(&self) -> ThresholdParams {
+        self.active
+    }
+
+    /// Schedules a verified change. `next_batch` is the number of the next
+    /// batch to be processed; a change for an earlier batch is rejected.
+    /// Scheduling the same change again is a no-op, so redelivered messages
+    /// do no harm.
+    pub fn schedule(
+        &mut self,
+        update: ScheduledThreshold,
+        next_batch: u64,
+    ) -> Result<(), ThresholdError> {
+        if self.pending.contains(&update) {
+            return Ok(());
+        }
+        update.params.validate()?;
+        let latest = self
+            .pending
+            .last()
+            .map_or(self.active.version, |p| p.params.version);
+        if update.params.version <= latest {
+            return Err(ThresholdError::StaleVersion {
+                version: update.params.version,
+                latest,
+            });
+        }
+        if update.activation_batch < next_batch {
+            return Err(ThresholdError::ActivationPassed {
+                activation_batch: update.activation_batch,
+                next_batch,
+            });
+        }
+        if let Some(last) = self.pending.last() {
+            // Newer versions may not activate before older ones.
+            if update.activation_batch < last.activation_batch {
+                return Err(ThresholdError::ActivationPassed {
+                    activation_batch: update.activation_batch,
+                    next_batch:       last.activation_batch,
+                });
+            }
+        }
+        if self.pending.len() >= MAX_PENDING_THRESHOLDS {
+            return Err(ThresholdError::TooManyPending);
+        }
+        self.pending.push(update);
+        Ok(())
+    }
+
+    /// The parameters to use for the given batch.
+    pub fn params_for(&self, batch: u64) -> ThresholdParams {
+        self.pending
+            .iter()
+            .rev()
+            .find(|p| p.activation_batch <= batch)
+            .map_or(self.active, |p| p.params)
+    }
+
+    pub fn sync_state(&self, batch: u64) -> ThresholdSyncState {
+        ThresholdSyncState {
+            batch,
+            active: self.params_for(batch),
+            pending: self
+                .pending
+                .iter()
+                .filter(|p| p.activation_batch > batch)
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// Makes the changes due at the given batch active and returns the
+    /// parameters for it.
+    pub fn activate(&mut self, batch: u64) -> ThresholdParams {
+        self.active = self.params_for(batch);
+        self.pending.retain(|p| p.activation_batch > batch);
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{rand::SystemRandom, signature::KeyPair};
+
+    fn operator_key() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn params(version: u32, match_threshold_ratio: f64) -> ThresholdParams {
+        ThresholdParams {
+            version,
+            match_threshold_ratio,
+        }
+    }
+
+    #[test]
+    fn test_default_matches_constant() {
+        let a = ((1. - 2. * MATCH_THRESHOLD_RATIO) * (1u64 << 16) as f64) as u64;
+        assert_eq!(ThresholdParams::default().a(), a);
+        assert_eq!(MatchThreshold::default().a(), a);
+        assert_eq!(
+            ThresholdParams::initial(MatchThreshold::default()),
+            ThresholdParams::default()
+        );
+    }
+
+    #[test]
+    fn test_match_threshold() {
+        let tight = MatchThreshold::new(0.34).unwrap();
+        let loose: MatchThreshold = " 0.38".parse().unwrap();
+        assert_eq!(tight.a(), 20971);
+        assert_eq!(loose.a(), 15728);
+        assert!(tight.a() > MatchThreshold::default().a());
+        assert!(loose.a() < MatchThreshold::default().a());
+
+        for ratio in [0., 0.5, -0.1, 1., f64::NAN] {
+            assert!(MatchThreshold::new(ratio).is_err(), "{}", ratio);
+        }
+        assert_eq!(
+            "0.375x".parse::<MatchThreshold>(),
+            Err(ThresholdError::InvalidRatio("0.375x".to_string()))
+        );
+
+        assert_eq!(serde_json::to_string(&loose).unwrap(), "0.38");
+        assert_eq!(
+            serde_json::from_str::<MatchThreshold>("0.38").unwrap(),
+            loose
+        );
+        assert!(serde_json::from_str::<MatchThreshold>("0.6").is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        let key = operator_key();
+        let public_key = key.public_key().as_ref().to_vec();
+        let request = ThresholdUpdateRequest::new_signed(params(1, 0.35), 10, &key);
+        assert_eq!(
+            request.verify(&public_key),
+            Ok(ScheduledThreshold {
+                activation_batch: 10,
+                params:           params(1, 0.35),
+            })
+        );
+
+        // Unsigned, tampered or signed by someone else.
+        let mut unsigned = request.clone();
+        unsigned.signature = String::new();
+        assert_eq!(
+            unsigned.verify(&public_key),
+            Err(ThresholdError::InvalidSignature)
+        );
+        let mut tampered = request.clone();
+        tampered.activation_batch = 9;
+        assert_eq!(
+            tampered.verify(&public_key),
+            Err(ThresholdError::InvalidSignature)
+        );
+        let forged = ThresholdUpdateRequest::new_signed(params(1, 0.35), 10, &operator_key());
+        assert_eq!(
+            forged.verify(&public_key),
+            Err(ThresholdError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_activation_boundary() {
+        let mut schedule = ThresholdSchedule::default();
+        let update = ScheduledThreshold {
+            activation_batch: 5,
+            params:           params(1, 0.3),
+        };
+        schedule.schedule(update, 3).unwrap();
+
+        assert_eq!(schedule.activate(3), ThresholdParams::default());
+        assert_eq!(schedule.activate(4), ThresholdParams::default());
+        // The batch exactly at the activation point uses the new params.
+        assert_eq!(schedule.sync_state(5).active, params(1, 0.3));
+        assert!(schedule.sync_state(5).pending.is_empty());
+        assert_eq!(schedule.activate(5), params(1, 0.3));
+        assert_eq!(schedule.activate(6), params(1, 0.3));
+
+        // Too late, stale or redelivered.
+        let late = ScheduledThreshold {
+            activation_batch: 6,
+            params:           params(2, 0.3),
+        };
+        assert_eq!(
+            schedule.schedule(late, 7),
+            Err(ThresholdError::ActivationPassed {
+                activation_batch: 6,
+                next_batch:       7,
+            })
+        );
+        assert_eq!(
+            schedule.schedule(update, 7),
+            Err(ThresholdError::StaleVersion {
+                version: 1,
+                latest:  1,
+            })
+        );
+        let next = ScheduledThreshold {
+            activation_batch: 9,
+            params:           params(2, 0.32),
+        };
+        schedule.schedule(next, 7).unwrap();
+        schedule.schedule(next, 8).unwrap();
+        assert_eq!(schedule.sync_state(8).pending, vec![next]);
+    }
+
+    #[test]
+    fn test_agreement_on_activation_batch() {
+        let update = ScheduledThreshold {
+            activation_batch: 5,
+            params:           params(1, 0.3),
+        };
+        let mut schedules = vec![ThresholdSchedule::default(); 3];
+        // Party 2 receives the control message a batch later.
+        schedules[0].schedule(update, 3).unwrap();
+        schedules[1].schedule(update, 3).unwrap();
+        for batch in 3..7 {
+            if batch == 4 {
+                schedules[2].schedule(update, batch).unwrap();
+            }
+            let states = schedules
+                .iter()
+                .map(|s| s.sync_state(batch))
+                .collect::<Vec<_>>();
+            let expected = if batch < 5 {
+                ThresholdParams::default()
+            } else {
+                params(1, 0.3)
+            };
+            assert_eq!(check_agreement(&states), Ok(expected));
+            for schedule in schedules.iter_mut() {
+                schedule.activate(batch);
+            }
+        }
+
+        // A party that missed the message diverges at the activation batch.
+        let mut schedules = vec![ThresholdSchedule::default(); 3];
+        schedules[0].schedule(update, 3).unwrap();
+        schedules[1].schedule(update, 3).unwrap();
+        let states = schedules
+            .iter()
+            .map(|s| s.sync_state(5))
+            .collect::<Vec<_>>();
+        let err = check_agreement(&states).unwrap_err();
+        assert_eq!(err.states[2].active.version, 0);
+    }
+}
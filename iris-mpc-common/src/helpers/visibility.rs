@@ -0,0 +1,478 @@
+//! Bookkeeping for request messages that were received but not yet processed.
+//!
+//! A received SQS message stays hidden from other consumers only for the
+//! visibility timeout of the queue. Uniqueness requests wait in the pending
+//! pools until they fit into a batch, which may take longer than that. The
+//! [`InFlightMessages`] tracker bounds how many messages are held at once and
+//! extends the visibility of the held messages before it runs out. A message
+//! committed to a batch stays held until the batch is processed and is only
+//! deleted from the queue then, so a party that fails in between receives it
+//! again. A waiting message whose visibility ran out is dropped here and
+//! received again later, but never processed twice.
+
+#[cfg(feature = "aws")]
+use super::{queue::RequestReceiver, smpc_request::ReceiveRequestError};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// How long received messages are held and how their visibility is extended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VisibilityPolicy {
+    /// Maximum number of received messages waiting for a batch.
+    pub max_in_flight:      usize,
+    /// Visibility timeout the queue applies to a received message.
+    pub visibility_timeout: Duration,
+    /// New visibility timeout set on each extension.
+    pub extension:          Duration,
+    /// A message is extended once its visibility ends within this margin. It
+    /// has to cover the time between two calls to
+    /// [`InFlightMessages::extend_due`], i.e. the processing of a batch.
+    pub extension_margin:   Duration,
+}
+
+/// A received message that is not yet deleted from its queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlightMessage {
+    /// Index of the queue the message was received from.
+    pub queue:          usize,
+    pub receipt_handle: String,
+    /// When the message becomes visible to other consumers again.
+    pub visible_at:     Instant,
+    /// Whether the message is part of a batch that is being processed.
+    pub committed:      bool,
+}
+
+/// The messages held by the server, keyed by their SQS message id.
+#[derive(Debug)]
+pub struct InFlightMessages {
+    policy:   VisibilityPolicy,
+    messages: HashMap<String, InFlightMessage>,
+}
+
+impl InFlightMessages {
+    pub fn new(policy: VisibilityPolicy) -> Self {
+        Self {
+            policy,
+            messages: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// How many more messages may be received. The messages of a batch that
+    /// is being processed do not count.
+    pub fn capacity(&self) -> usize {
+        let waiting = self.messages.values().filter(|m| !m.committed).count();
+        self.policy.max_in_flight.saturating_sub(waiting)
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.messages.contains_key(message_id)
+    }
+
+    /// Starts tracking a message that was just received. Returns false if the
+    /// message is already held, i.e. it was delivered again; only its receipt
+    /// handle is updated then, since the previous one is no longer valid.
+    pub fn track(&mut self, message_id: &str, queue: usize, receipt_handle: String) -> bool {
+        let visible_at = Instant::now() + self.policy.visibility_timeout;
+        match self.messages.get_mut(message_id) {
+            Some(message) => {
+                message.queue = queue;
+                message.receipt_handle = receipt_handle;
+                message.visible_at = visible_at;
+                false
+            }
+            None => {
+                self.messages
+                    .insert(message_id.to_string(), InFlightMessage {
+                        queue,
+                        receipt_handle,
+                        visible_at,
+                        committed: false,
+                    });
+                true
+            }
+        }
+    }
+
+    /// Marks a message as part of a batch. It stays hidden until the batch is
+    /// processed, see [`complete`](Self::complete). Returns false if the
+    /// message is no longer held.
+    pub fn commit(&mut self, message_id: &str) -> bool {
+        match self.messages.get_mut(message_id) {
+            Some(message) => {
+                message.committed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops tracking a message whose batch was processed. The caller deletes
+    /// it from its queue.
+    pub fn complete(&mut self, message_id: &str) -> Option<InFlightMessage> {
+        self.messages.remove(message_id)
+    }
+
+    /// Extends the visibility of the messages that are about to become
+    /// visible again, and drops the waiting messages whose visibility already
+    /// ran out or could not be extended. Returns the ids of the dropped
+    /// messages, which the caller must not commit anymore.
+    ///
+    /// Committed messages are kept even then, so that a second delivery of
+    /// one is still recognized and its batch deletes it with the new receipt
+    /// handle.
+    #[cfg(feature = "aws")]
+    pub async fn extend_due<R: RequestReceiver + ?Sized>(
+        &mut self,
+        receivers: &[&R],
+    ) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = vec![];
+        let mut lapsed = 0;
+        for (message_id, message) in self.messages.iter_mut() {
+            if message.visible_at <= now {
+                if message.committed {
+                    lapsed += 1;
+                } else {
+                    expired.push(message_id.clone());
+                }
+                continue;
+            }
+            if message.visible_at > now + self.policy.extension_margin {
+                continue;
+            }
+            match receivers[message.queue]
+                .change_visibility(&message.receipt_handle, self.policy.extension)
+                .await
+            {
+                Ok(()) => {
+                    message.visible_at = now + self.policy.extension;
+                    metrics::counter!("queue.visibility_extended").increment(1);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to extend visibility of {}: {}", message_id, e);
+                    if message.committed {
+                        lapsed += 1;
+                    } else {
+                        expired.push(message_id.clone());
+                    }
+                }
+            }
+        }
+        if lapsed > 0 {
+            tracing::warn!(
+                "Visibility of {} messages of the batch in process ran out",
+                lapsed
+            );
+            metrics::counter!("queue.committed_visibility_expired").increment(lapsed);
+        }
+
+        for message_id in expired.iter() {
+            self.messages.remove(message_id);
+        }
+        if !expired.is_empty() {
+            tracing::warn!("Visibility of {} held messages ran out", expired.len());
+            metrics::counter!("queue.visibility_expired").increment(expired.len() as u64);
+        }
+        expired
+    }
+
+    /// Makes all held messages visible again right away, e.g. on shutdown.
+    #[cfg(feature = "aws")]
+    pub async fn release_all<R: RequestReceiver + ?Sized>(
+        &mut self,
+        receivers: &[&R],
+    ) -> Result<(), ReceiveRequestError> {
+        for (_, message) in self.messages.drain() {
+            receivers[message.queue]
+                .change_visibility(&message.receipt_handle, Duration::ZERO)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "aws"))]
+mod tests {
+    use super::*;
+    use crate::helpers::queue::QueueMessage;
+    use async_trait::async_trait;
+    use std::{
+        collections::{HashSet, VecDeque},
+        sync::Mutex,
+    };
+
+    const VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+    const BATCH_SIZE: usize = 4;
+    const PROCESSING_TIME: Duration = Duration::from_secs(45);
+
+    #[derive(Debug, Default)]
+    struct MockQueue {
+        state: Mutex<MockQueueState>,
+    }
+
+    #[derive(Debug, Default)]
+    struct MockQueueState {
+        visible:           VecDeque<String>,
+        /// Receipt handle -> (message id, visible again at).
+        received:          HashMap<String, (String, Instant)>,
+        deliveries:        u64,
+        deleted:           Vec<String>,
+        /// Deletions with a receipt handle whose visibility had run out.
+        expired_deletions: usize,
+    }
+
+    impl MockQueue {
+        fn with_messages(n: usize) -> Self {
+            let queue = Self::default();
+            queue.state.lock().unwrap().visible = (0..n).map(|i| i.to_string()).collect();
+            queue
+        }
+
+        /// Returns messages whose visibility ran out to the queue.
+        fn expire(state: &mut MockQueueState) {
+            let now = Instant::now();
+            let expired: Vec<_> = state
+                .received
+                .iter()
+                .filter(|(_, (_, visible_at))| *visible_at <= now)
+                .map(|(handle, _)| handle.clone())
+                .collect();
+            for handle in expired {
+                let (message_id, _) = state.received.remove(&handle).unwrap();
+                state.visible.push_back(message_id);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl RequestReceiver for MockQueue {
+        async fn receive(
+            &self,
+            max_messages: i32,
+        ) -> Result<Vec<QueueMessage>, ReceiveRequestError> {
+            let mut state = self.state.lock().unwrap();
+            Self::expire(&mut state);
+            let mut messages = vec![];
+            while messages.len() < max_messages as usize {
+                let Some(message_id) = state.visible.pop_front() else {
+                    break;
+                };
+                state.deliveries += 1;
+                let receipt_handle = format!("{}-{}", message_id, state.deliveries);
+                state.received.insert(
+                    receipt_handle.clone(),
+                    (message_id.clone(), Instant::now() + VISIBILITY_TIMEOUT),
+                );
+                messages.push(QueueMessage {
+                    body: message_id,
+                    receipt_handle,
+                    ..Default::default()
+                });
+            }
+            Ok(messages)
+        }
+
+        async fn delete(&self, receipt_handle: &str) -> Result<(), ReceiveRequestError> {
+            let mut state = self.state.lock().unwrap();
+            match state.received.remove(receipt_handle) {
+                Some((message_id, visible_at)) => {
+                    if visible_at <= Instant::now() {
+                        state.expired_deletions += 1;
+                    }
+                    state.deleted.push(message_id);
+                    Ok(())
+                }
+                None => Err(ReceiveRequestError::ExpiredReceiptHandle(
+                    receipt_handle.to_string(),
+                )),
+            }
+        }
+
+        async fn change_visibility(
+            &self,
+            receipt_handle: &str,
+            timeout: Duration,
+        ) -> Result<(), ReceiveRequestError> {
+            let mut state = self.state.lock().unwrap();
+            Self::expire(&mut state);
+            match state.received.get_mut(receipt_handle) {
+                Some((_, visible_at)) => {
+                    *visible_at = Instant::now() + timeout;
+                    Ok(())
+                }
+                None => Err(ReceiveRequestError::ExpiredReceiptHandle(
+                    receipt_handle.to_string(),
+                )),
+            }
+        }
+
+        async fn requeue(&self, body: &str) -> Result<(), ReceiveRequestError> {
+            self.state
+                .lock()
+                .unwrap()
+                .visible
+                .push_back(body.to_string());
+            Ok(())
+        }
+    }
+
+    fn policy(extension_margin: Duration) -> VisibilityPolicy {
+        VisibilityPolicy {
+            max_in_flight: 2 * BATCH_SIZE,
+            visibility_timeout: VISIBILITY_TIMEOUT,
+            extension: Duration::from_secs(120),
+            extension_margin,
+        }
+    }
+
+    /// Runs the receive loop of the server against the queue, with batches
+    /// that take longer to process than the visibility timeout. Returns the
+    /// processed messages in order.
+    async fn run(queue: &MockQueue, in_flight: &mut InFlightMessages, n: usize) -> Vec<String> {
+        let receivers = [queue];
+        let mut pending = VecDeque::new();
+        let mut processed = vec![];
+        while processed.len() < n {
+            // Drop the messages that ran out during the last batch before
+            // committing any.
+            for message_id in in_flight.extend_due(&receivers).await {
+                pending.retain(|id| id != &message_id);
+            }
+            let capacity = in_flight.capacity() as i32;
+            for message in queue.receive(capacity).await.unwrap() {
+                if in_flight.track(&message.body, 0, message.receipt_handle) {
+                    pending.push_back(message.body);
+                }
+            }
+            let batch: Vec<_> = pending.drain(..BATCH_SIZE.min(pending.len())).collect();
+            for message_id in batch.iter() {
+                assert!(in_flight.commit(message_id));
+            }
+            // Extend the batch and the waiting messages for the processing
+            // time.
+            for message_id in in_flight.extend_due(&receivers).await {
+                pending.retain(|id| id != &message_id);
+            }
+            tokio::time::sleep(PROCESSING_TIME).await;
+            for message_id in batch {
+                let message = in_flight.complete(&message_id).unwrap();
+                queue.delete(&message.receipt_handle).await.unwrap();
+                processed.push(message_id);
+            }
+        }
+        processed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_processing_extends_visibility() {
+        let n = 5 * BATCH_SIZE;
+        let queue = MockQueue::with_messages(n);
+        let mut in_flight = InFlightMessages::new(policy(Duration::from_secs(60)));
+
+        let processed = run(&queue, &mut in_flight, n).await;
+
+        let unique: HashSet<_> = processed.iter().collect();
+        assert_eq!(unique.len(), processed.len());
+        assert_eq!(processed.len(), n);
+
+        let state = queue.state.lock().unwrap();
+        assert_eq!(state.expired_deletions, 0);
+        assert_eq!(state.deleted, processed);
+        // Nothing was delivered twice, all waiting messages were extended.
+        assert_eq!(state.deliveries, n as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_messages_are_dropped() {
+        let n = 5 * BATCH_SIZE;
+        let queue = MockQueue::with_messages(n);
+        // The margin does not cover the processing time, so waiting messages
+        // run out of visibility and are delivered again.
+        let mut in_flight = InFlightMessages::new(policy(Duration::from_secs(5)));
+
+        let processed = run(&queue, &mut in_flight, n).await;
+
+        let unique: HashSet<_> = processed.iter().collect();
+        assert_eq!(unique.len(), processed.len());
+
+        let state = queue.state.lock().unwrap();
+        // The batches ran out of visibility too, but are deleted before they
+        // are received again.
+        assert!(state.expired_deletions > 0);
+        assert_eq!(state.deleted, processed);
+        assert!(state.deliveries > n as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_flight_bound() {
+        let queue = MockQueue::with_messages(100);
+        let mut in_flight = InFlightMessages::new(policy(Duration::from_secs(60)));
+        let receivers = [&queue];
+
+        for message in queue.receive(in_flight.capacity() as i32).await.unwrap() {
+            assert!(in_flight.track(&message.body, 0, message.receipt_handle));
+        }
+        assert_eq!(in_flight.len(), 2 * BATCH_SIZE);
+        assert_eq!(in_flight.capacity(), 0);
+
+        in_flight.release_all(&receivers).await.unwrap();
+        assert!(in_flight.is_empty());
+        // Released messages are delivered again right away.
+        let messages = queue.receive(100).await.unwrap();
+        assert_eq!(messages.len(), 100);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_redelivery_is_deduplicated() {
+        let mut in_flight = InFlightMessages::new(policy(Duration::from_secs(60)));
+        assert!(in_flight.track("a", 0, "a-1".to_string()));
+        assert!(!in_flight.track("a", 0, "a-2".to_string()));
+        assert_eq!(in_flight.len(), 1);
+        assert!(in_flight.commit("a"));
+        assert_eq!(in_flight.complete("a").unwrap().receipt_handle, "a-2");
+        assert!(in_flight.complete("a").is_none());
+        assert!(!in_flight.commit("a"));
+    }
+
+    /// The messages of a batch stay in the queue until the batch is through,
+    /// so a party that fails while processing it receives them again.
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_is_deleted_once_processed() {
+        let queue = MockQueue::with_messages(2 * BATCH_SIZE);
+        let mut in_flight = InFlightMessages::new(policy(Duration::from_secs(60)));
+        let receivers = [&queue];
+
+        for message in queue.receive(BATCH_SIZE as i32).await.unwrap() {
+            assert!(in_flight.track(&message.body, 0, message.receipt_handle));
+            assert!(in_flight.commit(&message.body));
+        }
+        // The batch does not hold back the next one.
+        assert_eq!(in_flight.capacity(), 2 * BATCH_SIZE);
+        for _ in 0..4 {
+            assert!(in_flight.extend_due(&receivers).await.is_empty());
+            tokio::time::sleep(PROCESSING_TIME).await;
+        }
+        {
+            let state = queue.state.lock().unwrap();
+            assert!(state.deleted.is_empty());
+            assert_eq!(state.received.len(), BATCH_SIZE);
+        }
+
+        // The party fails: the messages of the batch are delivered again.
+        in_flight.release_all(&receivers).await.unwrap();
+        let messages = queue.receive(2 * BATCH_SIZE as i32).await.unwrap();
+        assert_eq!(messages.len(), 2 * BATCH_SIZE);
+        assert_eq!(
+            queue.state.lock().unwrap().deliveries,
+            3 * BATCH_SIZE as u64
+        );
+    }
+}
@@ -30,6 +30,17 @@ impl PartyID {
             PartyID::ID2 => PartyID::ID1,
         }
     }
+
+    /// The id of the party in its Galois ring shares, which counts from 1
+    pub fn share_id(&self) -> usize {
+        *self as usize + 1
+    }
+
+    /// The ids of the correlated randomness seeds the party shares with the
+    /// next and the previous party, as used for the insecure test seeds
+    pub fn seed_ids(&self) -> (u32, u32) {
+        ((*self).into(), self.prev_id().into())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -51,7 +62,7 @@ impl TryFrom<usize> for PartyID {
             0 => Ok(PartyID::ID0),
             1 => Ok(PartyID::ID1),
             2 => Ok(PartyID::ID2),
-            i => Err(PartyIDError(format!("Invalid party ID: {}", i))),
+            i => Err(PartyIDError(i.to_string())),
         }
     }
 }
@@ -82,6 +93,13 @@ impl From<PartyID> for u8 {
     }
 }
 
+impl From<PartyID> for u32 {
+    #[inline(always)]
+    fn from(other: PartyID) -> Self {
+        other as u32
+    }
+}
+
 impl From<PartyID> for usize {
     #[inline(always)]
     fn from(other: PartyID) -> Self {
@@ -95,3 +113,33 @@ impl std::fmt::Display for PartyID {
         write!(f, "{}", *self as usize)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PartyID;
+
+    #[test]
+    fn test_neighbors() {
+        for i in 0..3usize {
+            let id = PartyID::try_from(i).unwrap();
+            assert_eq!(usize::from(id.next_id()), (i + 1) % 3);
+            assert_eq!(usize::from(id.prev_id()), (i + 2) % 3);
+            assert_eq!(id.next_id().prev_id(), id);
+            assert_eq!(id.share_id(), i + 1);
+            // The seed shared with the next party is its seed with the previous.
+            assert_eq!(id.seed_ids().0, id.next_id().seed_ids().1);
+            assert_eq!(id.to_string().parse::<PartyID>().unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert!(PartyID::try_from(3usize).is_err());
+        assert!(PartyID::try_from(255u8).is_err());
+        assert_eq!(
+            "3".parse::<PartyID>().unwrap_err().to_string(),
+            "Invalid party ID: 3"
+        );
+        assert!("-1".parse::<PartyID>().is_err());
+    }
+}
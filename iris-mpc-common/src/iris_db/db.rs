@@ -1,6 +1,66 @@
-use super::iris::IrisCode;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use super::iris::{IrisCode, IrisCodeArray, MATCH_THRESHOLD_RATIO};
+use eyre::ensure;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path};
+use thiserror::Error;
+
+const DB_FILE_MAGIC: &[u8; 8] = b"IRISPLDB";
+pub const DB_FILE_VERSION: u32 = 1;
+/// Magic, version and record count.
+const DB_FILE_HEADER_LEN: usize = 8 + 4 + 8;
+const DB_FILE_CHECKSUM_LEN: usize = 32;
+const DB_FILE_RECORD_LEN: usize = 2 * IrisCodeArray::IRIS_CODE_SIZE_U64 * 8;
+
+#[derive(Debug, Error)]
+pub enum IrisDbFileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a DB file or unsupported version: {0}")]
+    Format(String),
+    #[error("DB file announces {expected} records, but holds {got} bytes of records")]
+    WrongCount { expected: u64, got: usize },
+    #[error("checksum mismatch in DB file")]
+    Corrupted,
+}
+
+/// A group of entries of [`IrisDB::new_with_distance_profile`] that each have
+/// a sibling at a fractional Hamming distance within the given range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiblingCluster {
+    /// Fraction of the entries of the DB that are siblings in this cluster.
+    pub fraction:     f64,
+    pub min_distance: f64,
+    pub max_distance: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DistanceProfile {
+    pub clusters: Vec<SiblingCluster>,
+}
+
+/// How an entry of [`IrisDB::new_with_distance_profile`] was generated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileEntry {
+    /// An independent random code.
+    Base,
+    Sibling {
+        /// The index of the cluster in the profile.
+        cluster:  usize,
+        /// The index of the base entry the sibling was derived from.
+        base:     usize,
+        /// The achieved distance to the base, over the common unmasked bits.
+        distance: f64,
+    },
+}
+
+impl ProfileEntry {
+    /// Whether the entry matches its base under the default threshold.
+    pub fn matches_base(&self) -> bool {
+        matches!(self, ProfileEntry::Sibling { distance, .. } if *distance < MATCH_THRESHOLD_RATIO)
+    }
+}
 
 #[derive(Default)]
 pub struct IrisDB {
@@ -49,6 +109,184 @@ impl IrisDB {
         Self { db }
     }
 
+    /// Only use for testing. Entry `i` is generated from an RNG seeded with the
+    /// hash of a seed drawn from `rng` and `i`, so any entry can be generated
+    /// on its own and the DB does not depend on the number of threads. Unlike
+    /// [`Self::new_random_par`], `rng` is only drawn from once.
+    pub fn new_random_par_indexed<R: Rng>(size: usize, rng: &mut R) -> Self {
+        let seed: [u8; 32] = rng.gen();
+        let db = (0..size)
+            .into_par_iter()
+            .map(|i| IrisCode::random_rng(&mut Self::entry_rng(&seed, i)))
+            .collect::<Vec<_>>();
+
+        Self { db }
+    }
+
+    /// The RNG of entry `index` of [`Self::new_random_par_indexed`].
+    fn entry_rng(seed: &[u8; 32], index: usize) -> StdRng {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        hasher.update((index as u64).to_le_bytes());
+        StdRng::from_seed(hasher.finalize().into())
+    }
+
+    /// Writes the DB to `path`: a magic, the format version and the number of
+    /// records, then the code and the mask words of every iris in little
+    /// endian, and the SHA-256 of all of the above.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), IrisDbFileError> {
+        let mut bytes = Vec::with_capacity(
+            DB_FILE_HEADER_LEN + self.db.len() * DB_FILE_RECORD_LEN + DB_FILE_CHECKSUM_LEN,
+        );
+        bytes.extend_from_slice(DB_FILE_MAGIC);
+        bytes.extend_from_slice(&DB_FILE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.db.len() as u64).to_le_bytes());
+        for iris in self.db.iter() {
+            for word in iris.code.0.iter().chain(iris.mask.0.iter()) {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Reads a DB written by [`Self::save`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IrisDbFileError> {
+        let bytes = fs::read(path)?;
+        if bytes.len() < DB_FILE_HEADER_LEN + DB_FILE_CHECKSUM_LEN {
+            return Err(IrisDbFileError::Format(format!(
+                "file of {} bytes",
+                bytes.len()
+            )));
+        }
+        let (data, checksum) = bytes.split_at(bytes.len() - DB_FILE_CHECKSUM_LEN);
+        let (header, records) = data.split_at(DB_FILE_HEADER_LEN);
+        if &header[..8] != DB_FILE_MAGIC {
+            return Err(IrisDbFileError::Format("bad magic".to_string()));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != DB_FILE_VERSION {
+            return Err(IrisDbFileError::Format(format!(
+                "version {}, expected {}",
+                version, DB_FILE_VERSION
+            )));
+        }
+        if Sha256::digest(data).as_slice() != checksum {
+            return Err(IrisDbFileError::Corrupted);
+        }
+        let count = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        if (records.len() / DB_FILE_RECORD_LEN) as u64 != count
+            || records.len() % DB_FILE_RECORD_LEN != 0
+        {
+            return Err(IrisDbFileError::WrongCount {
+                expected: count,
+                got:      records.len(),
+            });
+        }
+
+        let read_array = |bytes: &[u8]| {
+            let mut array = IrisCodeArray::ZERO;
+            for (word, bytes) in array.0.iter_mut().zip(bytes.chunks_exact(8)) {
+                *word = u64::from_le_bytes(bytes.try_into().unwrap());
+            }
+            array
+        };
+        let db = records
+            .chunks_exact(DB_FILE_RECORD_LEN)
+            .map(|record| {
+                let (code, mask) = record.split_at(DB_FILE_RECORD_LEN / 2);
+                IrisCode {
+                    code: read_array(code),
+                    mask: read_array(mask),
+                }
+            })
+            .collect();
+        Ok(Self { db })
+    }
+
+    /// Only use for testing. Generates a DB in which the clusters of the
+    /// profile take their fraction of the entries, each entry of a cluster
+    /// being a sibling of a random base entry. A sibling keeps the mask of its
+    /// base and has a number of unmasked code bits flipped that hits a random
+    /// distance within the range of its cluster. Returns how every entry was
+    /// generated, in the order of the DB.
+    pub fn new_with_distance_profile<R: Rng>(
+        size: usize,
+        rng: &mut R,
+        profile: &DistanceProfile,
+    ) -> eyre::Result<(Self, Vec<ProfileEntry>)> {
+        let mut n_siblings = vec![];
+        for cluster in profile.clusters.iter() {
+            ensure!(
+                (0.0..=1.0).contains(&cluster.min_distance)
+                    && cluster.min_distance <= cluster.max_distance
+                    && cluster.max_distance <= 1.0,
+                "Invalid distance range {}..={}",
+                cluster.min_distance,
+                cluster.max_distance
+            );
+            ensure!(
+                (0.0..=1.0).contains(&cluster.fraction),
+                "Invalid fraction {}",
+                cluster.fraction
+            );
+            n_siblings.push((cluster.fraction * size as f64).round() as usize);
+        }
+        let n_bases = size.saturating_sub(n_siblings.iter().sum());
+        ensure!(
+            n_bases > 0 || size == 0,
+            "The clusters leave no base entries for {} entries",
+            size
+        );
+
+        let mut entries = Self::new_random_par(n_bases, rng)
+            .db
+            .into_iter()
+            .map(|iris| (iris, ProfileEntry::Base))
+            .collect::<Vec<_>>();
+        for (cluster_index, (cluster, n)) in profile.clusters.iter().zip(n_siblings).enumerate() {
+            for _ in 0..n {
+                let base = rng.gen_range(0..n_bases);
+                let target = rng.gen_range(cluster.min_distance..=cluster.max_distance);
+                let (sibling, distance) = sibling_at(&entries[base].0, target, rng);
+                entries.push((sibling, ProfileEntry::Sibling {
+                    cluster: cluster_index,
+                    base,
+                    distance,
+                }));
+            }
+        }
+
+        // Shuffle, so that siblings are not grouped at the end.
+        let mut order = (0..entries.len()).collect::<Vec<_>>();
+        order.shuffle(rng);
+        let mut position = vec![0; entries.len()];
+        for (new, &old) in order.iter().enumerate() {
+            position[old] = new;
+        }
+        let (db, metadata) = order
+            .iter()
+            .map(|&old| match entries[old] {
+                (
+                    ref iris,
+                    ProfileEntry::Sibling {
+                        cluster,
+                        base,
+                        distance,
+                    },
+                ) => (iris.clone(), ProfileEntry::Sibling {
+                    cluster,
+                    base: position[base],
+                    distance,
+                }),
+                (ref iris, ProfileEntry::Base) => (iris.clone(), ProfileEntry::Base),
+            })
+            .unzip();
+        Ok((Self { db }, metadata))
+    }
+
     pub fn iris_in_db(&self, iris: &IrisCode) -> bool {
         self.db.iter().any(|x| iris.is_close(x))
     }
@@ -61,6 +299,23 @@ impl IrisDB {
     }
 }
 
+/// Flips the share of the unmasked code bits of `base` closest to
+/// `distance`, and returns the sibling with its exact distance.
+fn sibling_at<R: Rng>(base: &IrisCode, distance: f64, rng: &mut R) -> (IrisCode, f64) {
+    let unmasked = base.mask.set_bits().collect::<Vec<_>>();
+    let n_flips = (distance * unmasked.len() as f64).round() as usize;
+    let mut sibling = base.clone();
+    for &i in unmasked.choose_multiple(rng, n_flips) {
+        sibling.code.flip_bit(i);
+    }
+    let distance = if unmasked.is_empty() {
+        0.0
+    } else {
+        n_flips as f64 / unmasked.len() as f64
+    };
+    (sibling, distance)
+}
+
 #[cfg(test)]
 mod iris_test {
     use super::*;
@@ -82,4 +337,130 @@ mod iris_test {
             assert_eq!(in_db, db.db.iter().any(|x| iris.is_close(x)));
         }
     }
+
+    #[test]
+    fn distance_profile() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let profile = DistanceProfile {
+            clusters: vec![
+                SiblingCluster {
+                    fraction:     0.2,
+                    min_distance: 0.25,
+                    max_distance: 0.35,
+                },
+                // Near misses, just above the threshold.
+                SiblingCluster {
+                    fraction:     0.1,
+                    min_distance: 0.38,
+                    max_distance: 0.42,
+                },
+            ],
+        };
+        let (db, metadata) =
+            IrisDB::new_with_distance_profile(DB_SIZE, &mut rng, &profile).unwrap();
+        assert_eq!(db.len(), DB_SIZE);
+        assert_eq!(metadata.len(), DB_SIZE);
+
+        let mut counts = [0; 2];
+        for (iris, entry) in db.db.iter().zip(metadata.iter()) {
+            let ProfileEntry::Sibling {
+                cluster,
+                base,
+                distance,
+            } = *entry
+            else {
+                continue;
+            };
+            counts[cluster] += 1;
+            assert_eq!(metadata[base], ProfileEntry::Base);
+            let cluster = &profile.clusters[cluster];
+            assert!((cluster.min_distance - 1e-3..=cluster.max_distance + 1e-3).contains(&distance));
+            assert_eq!(iris.get_distance(&db.db[base]), distance);
+            assert_eq!(iris.is_close(&db.db[base]), entry.matches_base());
+        }
+        assert_eq!(counts, [20, 10]);
+
+        let invalid = DistanceProfile {
+            clusters: vec![SiblingCluster {
+                fraction:     1.0,
+                min_distance: 0.1,
+                max_distance: 0.2,
+            }],
+        };
+        assert!(IrisDB::new_with_distance_profile(DB_SIZE, &mut rng, &invalid).is_err());
+    }
+
+    #[test]
+    fn new_random_par_independent_of_threads() {
+        let generate = |threads, indexed| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut rng = StdRng::seed_from_u64(42);
+            pool.install(|| {
+                if indexed {
+                    IrisDB::new_random_par_indexed(DB_SIZE, &mut rng).db
+                } else {
+                    IrisDB::new_random_par(DB_SIZE, &mut rng).db
+                }
+            })
+        };
+        let db = generate(1, false);
+        assert_eq!(db, generate(8, false));
+        // One seed per entry, drawn from the RNG in order.
+        let mut rng = StdRng::seed_from_u64(42);
+        let seeds = (0..4).map(|_| rng.gen()).collect::<Vec<_>>();
+        assert_eq!(
+            db[3],
+            IrisCode::random_rng(&mut StdRng::from_seed(seeds[3]))
+        );
+
+        let indexed = generate(1, true);
+        assert_eq!(indexed, generate(8, true));
+        assert_eq!(
+            indexed[3],
+            IrisCode::random_rng(&mut IrisDB::entry_rng(&StdRng::seed_from_u64(42).gen(), 3))
+        );
+        assert_ne!(indexed, db);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let db = IrisDB::new_random_par(DB_SIZE, &mut rng);
+        let path = std::env::temp_dir().join(format!("iris-db-test-{}.bin", std::process::id()));
+        db.save(&path).unwrap();
+        let loaded = IrisDB::load(&path).unwrap();
+        assert_eq!(loaded.db, db.db);
+
+        let bytes = fs::read(&path).unwrap();
+        // A flipped bit in a record fails the checksum.
+        let mut corrupted = bytes.clone();
+        corrupted[DB_FILE_HEADER_LEN + 5] ^= 1;
+        fs::write(&path, &corrupted).unwrap();
+        assert!(matches!(
+            IrisDB::load(&path),
+            Err(IrisDbFileError::Corrupted)
+        ));
+        // A record count that does not fit the records, with a valid checksum.
+        let mut wrong_count = bytes[..bytes.len() - DB_FILE_CHECKSUM_LEN].to_vec();
+        wrong_count[12..20].copy_from_slice(&(DB_SIZE as u64 + 1).to_le_bytes());
+        let checksum = Sha256::digest(&wrong_count);
+        wrong_count.extend_from_slice(&checksum);
+        fs::write(&path, &wrong_count).unwrap();
+        assert!(matches!(
+            IrisDB::load(&path),
+            Err(IrisDbFileError::WrongCount { .. })
+        ));
+        // A truncated file.
+        fs::write(&path, &bytes[..10]).unwrap();
+        assert!(matches!(
+            IrisDB::load(&path),
+            Err(IrisDbFileError::Format(_))
+        ));
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(IrisDB::load(&path), Err(IrisDbFileError::Io(_))));
+    }
 }
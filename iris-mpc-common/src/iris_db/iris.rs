@@ -1,7 +1,8 @@
 use base64::{prelude::BASE64_STANDARD, Engine};
-use eyre::bail;
+use eyre::{bail, WrapErr};
 use rand::{
     distributions::{Bernoulli, Distribution},
+    seq::SliceRandom,
     Rng,
 };
 use serde::{Deserialize, Serialize};
@@ -24,8 +25,25 @@ impl IrisCodeArray {
     pub const IRIS_CODE_SIZE_U64: usize = (Self::IRIS_CODE_SIZE + 63) / 64;
     pub const ZERO: Self = IrisCodeArray([0; Self::IRIS_CODE_SIZE_U64]);
     pub const ONES: Self = IrisCodeArray([u64::MAX; Self::IRIS_CODE_SIZE_U64]);
+    /// Number of columns of the code. The code is laid out as (rows, columns,
+    /// wavelets, bits) = (16, 200, 2, 2).
+    pub const IRIS_CODE_COLS: usize = 200;
+    /// Number of consecutive bits making up one column of a row.
+    const IRIS_CODE_COL_BITS: usize = 4;
+    /// Largest rotation, in columns, that the rotation comparison tries in
+    /// either direction.
+    pub const MAX_ROTATION: usize = 15;
+    /// Number of rotations of the rotation comparison, see
+    /// [`Self::all_rotations`].
+    pub const ROTATIONS: usize = 2 * Self::MAX_ROTATION + 1;
+    /// The bits of the last word that belong to the code, the others are
+    /// padding.
+    const LAST_WORD_MASK: u64 = u64::MAX >> (Self::IRIS_CODE_SIZE_U64 * 64 - Self::IRIS_CODE_SIZE);
+
+    /// Sets bit `i`, panics if `i` is not below [`Self::IRIS_CODE_SIZE`].
     #[inline]
     pub fn set_bit(&mut self, i: usize, val: bool) {
+        Self::check_index(i);
         let word = i / 64;
         let bit = i % 64;
         if val {
@@ -34,21 +52,36 @@ impl IrisCodeArray {
             self.0[word] &= !(1u64 << bit);
         }
     }
-    pub fn bits(&self) -> Bits<'_> {
+    /// Iterates over exactly the [`Self::IRIS_CODE_SIZE`] bits of the code.
+    pub fn iter_bits(&self) -> Bits<'_> {
         Bits {
             code:    self,
             current: 0,
             index:   0,
         }
     }
+    #[deprecated(note = "use `iter_bits`")]
+    pub fn bits(&self) -> Bits<'_> {
+        self.iter_bits()
+    }
+    /// Iterates over the indices of the set bits.
+    pub fn set_bits(&self) -> impl Iterator<Item = usize> + '_ {
+        self.iter_bits()
+            .enumerate()
+            .filter_map(|(i, bit)| bit.then_some(i))
+    }
+    /// Panics if `i` is not below [`Self::IRIS_CODE_SIZE`].
     #[inline]
     pub fn get_bit(&self, i: usize) -> bool {
+        Self::check_index(i);
         let word = i / 64;
         let bit = i % 64;
         (self.0[word] >> bit) & 1 == 1
     }
+    /// Panics if `i` is not below [`Self::IRIS_CODE_SIZE`].
     #[inline]
     pub fn flip_bit(&mut self, i: usize) {
+        Self::check_index(i);
         let word = i / 64;
         let bit = i % 64;
         self.0[word] ^= 1u64 << bit;
@@ -58,11 +91,93 @@ impl IrisCodeArray {
     pub fn random_rng<R: Rng>(rng: &mut R) -> Self {
         let mut code = IrisCodeArray::ZERO;
         rng.fill(code.as_raw_mut_slice());
+        code.0[Self::IRIS_CODE_SIZE_U64 - 1] &= Self::LAST_WORD_MASK;
         code
     }
 
+    #[inline]
+    fn check_index(i: usize) {
+        assert!(
+            i < Self::IRIS_CODE_SIZE,
+            "Bit index {} out of range for {} bits",
+            i,
+            Self::IRIS_CODE_SIZE
+        );
+    }
+
+    /// Number of set bits, not counting the padding of the last word.
     pub fn count_ones(&self) -> usize {
-        self.0.iter().map(|c| c.count_ones() as usize).sum()
+        let (last, words) = self.0.split_last().unwrap();
+        words.iter().map(|c| c.count_ones() as usize).sum::<usize>()
+            + (last & Self::LAST_WORD_MASK).count_ones() as usize
+    }
+
+    pub fn and(&self, other: &Self) -> Self {
+        let mut res = *self;
+        res &= *other;
+        res
+    }
+
+    pub fn xor(&self, other: &Self) -> Self {
+        let mut res = *self;
+        res ^= *other;
+        res
+    }
+
+    /// Mirrors the code horizontally by reversing the order of the columns in
+    /// every row. The bits within a column keep their order.
+    pub fn mirrored(&self) -> Self {
+        let row_bits = Self::IRIS_CODE_COLS * Self::IRIS_CODE_COL_BITS;
+        let mut res = Self::ZERO;
+        for i in 0..Self::IRIS_CODE_SIZE {
+            let row = i / row_bits;
+            let col = i % row_bits / Self::IRIS_CODE_COL_BITS;
+            let bit = i % Self::IRIS_CODE_COL_BITS;
+            let mirrored_col = Self::IRIS_CODE_COLS - 1 - col;
+            res.set_bit(
+                row * row_bits + mirrored_col * Self::IRIS_CODE_COL_BITS + bit,
+                self.get_bit(i),
+            );
+        }
+        res
+    }
+
+    /// Rotates every row by `n` columns to the left, i.e. column `c` moves to
+    /// column `c - n`, wrapping around. The bits within a column keep their
+    /// order.
+    pub fn rotate_left(&self, n: usize) -> Self {
+        self.rotate_right(Self::IRIS_CODE_COLS - n % Self::IRIS_CODE_COLS)
+    }
+
+    /// Rotates every row by `n` columns to the right, i.e. column `c` moves to
+    /// column `c + n`, wrapping around. This is the rotation by `+n` of
+    /// [`Self::all_rotations`].
+    pub fn rotate_right(&self, n: usize) -> Self {
+        let row_bits = Self::IRIS_CODE_COLS * Self::IRIS_CODE_COL_BITS;
+        let shift = n % Self::IRIS_CODE_COLS * Self::IRIS_CODE_COL_BITS;
+        let mut res = Self::ZERO;
+        for i in 0..Self::IRIS_CODE_SIZE {
+            let row_start = i - i % row_bits;
+            res.set_bit(
+                row_start + (i % row_bits + shift) % row_bits,
+                self.get_bit(i),
+            );
+        }
+        res
+    }
+
+    /// Yields the rotations by `-MAX_ROTATION` to `+MAX_ROTATION` columns, in
+    /// this order. This is the order of the rotations that the GPU compares,
+    /// see `GaloisRingIrisCodeShare::all_rotations`.
+    pub fn all_rotations(&self) -> impl Iterator<Item = Self> {
+        let code = *self;
+        (0..Self::ROTATIONS).map(move |i| {
+            if i < Self::MAX_ROTATION {
+                code.rotate_left(Self::MAX_ROTATION - i)
+            } else {
+                code.rotate_right(i - Self::MAX_ROTATION)
+            }
+        })
     }
 
     pub fn as_raw_slice(&self) -> &[u8] {
@@ -72,25 +187,27 @@ impl IrisCodeArray {
         bytemuck::cast_slice_mut(&mut self.0)
     }
 
-    /// Decode from base64 string compatible with Open IRIS
+    /// Decode from base64 string compatible with Open IRIS. Open IRIS packs
+    /// its (16, 200, 2, 2) bit array in row-major order with `np.packbits`, so
+    /// bit `i` of the code is the `i % 8`-th most significant bit of byte
+    /// `i / 8`.
     pub fn from_base64(s: &str) -> eyre::Result<Self> {
         let decoded_bytes = BASE64_STANDARD.decode(s)?;
-        if decoded_bytes.len() % 8 != 0 {
-            bail!("Invalid length for u64 array");
+        if decoded_bytes.len() != Self::IRIS_CODE_SIZE_BYTES {
+            bail!(
+                "Expected {} bytes of (16, 200, 2, 2) bits, got {}",
+                Self::IRIS_CODE_SIZE_BYTES,
+                decoded_bytes.len()
+            );
         }
 
-        Ok(Self(
-            decoded_bytes
-                .chunks_exact(8)
-                .map(|chunk| {
-                    let mut arr = [0u8; 8];
-                    arr.copy_from_slice(chunk);
-                    u64::from_be_bytes(arr).reverse_bits()
-                })
-                .collect::<Vec<u64>>()
-                .try_into()
-                .map_err(|_| eyre::eyre!("Expected exactly 200 elements"))?,
-        ))
+        let mut res = Self::ZERO;
+        for (word, chunk) in res.0.iter_mut().zip(decoded_bytes.chunks_exact(8)) {
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(chunk);
+            *word = u64::from_be_bytes(arr).reverse_bits();
+        }
+        Ok(res)
     }
 
     /// Encode to base64 string compatible with Open IRIS
@@ -116,11 +233,7 @@ impl std::ops::BitAnd for IrisCodeArray {
     type Output = Self;
     #[inline]
     fn bitand(self, rhs: Self) -> Self::Output {
-        let mut res = IrisCodeArray::ZERO;
-        for i in 0..Self::IRIS_CODE_SIZE_U64 {
-            res.0[i] = self.0[i] & rhs.0[i];
-        }
-        res
+        self.and(&rhs)
     }
 }
 impl std::ops::BitXorAssign for IrisCodeArray {
@@ -135,11 +248,7 @@ impl std::ops::BitXor for IrisCodeArray {
     type Output = Self;
     #[inline]
     fn bitxor(self, rhs: Self) -> Self::Output {
-        let mut res = IrisCodeArray::ZERO;
-        for i in 0..Self::IRIS_CODE_SIZE_U64 {
-            res.0[i] = self.0[i] ^ rhs.0[i];
-        }
-        res
+        self.xor(&rhs)
     }
 }
 
@@ -178,17 +287,74 @@ impl IrisCode {
         code
     }
 
+    /// The ground truth the parties' comparisons are checked against: the
+    /// Hamming weight of the differing bits that are unmasked in both codes,
+    /// the number of bits unmasked in both codes, and their ratio. Returns
+    /// `None` if no bit is unmasked in both codes.
+    pub fn masked_distance(&self, other: &Self) -> Option<(u32, u32, f64)> {
+        let (code_distance, combined_mask_len) = self.hamming_and_mask_bits(other);
+        if combined_mask_len == 0 {
+            return None;
+        }
+        Some((
+            code_distance,
+            combined_mask_len,
+            code_distance as f64 / combined_mask_len as f64,
+        ))
+    }
+
+    /// The Hamming weight of the differing bits that are unmasked in both
+    /// codes, and the number of bits unmasked in both codes. A distance over
+    /// few common bits is unreliable, whatever its value.
+    pub fn hamming_and_mask_bits(&self, other: &Self) -> (u32, u32) {
+        let combined_mask = self.mask.and(&other.mask);
+        let combined_code = self.code.xor(&other.code).and(&combined_mask);
+        (
+            combined_code.count_ones() as u32,
+            combined_mask.count_ones() as u32,
+        )
+    }
+
+    /// Whether the masked distance is below [`MATCH_THRESHOLD_RATIO`]. Codes
+    /// without common unmasked bits never match.
+    pub fn is_match(&self, other: &Self) -> bool {
+        self.masked_distance(other)
+            .is_some_and(|(_, _, distance)| distance < MATCH_THRESHOLD_RATIO)
+    }
+
+    /// The fractional masked distance, NaN if no bit is unmasked in both
+    /// codes, see [`IrisCode::masked_distance`].
     pub fn get_distance(&self, other: &Self) -> f64 {
-        let combined_mask = self.mask & other.mask;
-        let combined_mask_len = combined_mask.count_ones();
+        self.masked_distance(other)
+            .map_or(f64::NAN, |(_, _, distance)| distance)
+    }
+
+    /// Decodes a template of the open-iris pipeline from its base64
+    /// `iris_codes` and `mask_codes`, see [`IrisCodeArray::from_base64`] for
+    /// the layout.
+    pub fn from_open_iris_template(code_b64: &str, mask_b64: &str) -> eyre::Result<Self> {
+        Ok(Self {
+            code: IrisCodeArray::from_base64(code_b64).wrap_err("Invalid iris code")?,
+            mask: IrisCodeArray::from_base64(mask_b64).wrap_err("Invalid mask code")?,
+        })
+    }
+
+    /// Encodes to the base64 `iris_codes` and `mask_codes` of an open-iris
+    /// template, the inverse of [`IrisCode::from_open_iris_template`].
+    pub fn to_open_iris_template(&self) -> eyre::Result<(String, String)> {
+        Ok((self.code.to_base64()?, self.mask.to_base64()?))
+    }
 
-        let combined_code = (self.code ^ other.code) & combined_mask;
-        let code_distance = combined_code.count_ones();
-        code_distance as f64 / combined_mask_len as f64
+    /// Mirrors both the code and the mask, see [`IrisCodeArray::mirrored`].
+    pub fn mirrored(&self) -> Self {
+        Self {
+            code: self.code.mirrored(),
+            mask: self.mask.mirrored(),
+        }
     }
 
     pub fn is_close(&self, other: &Self) -> bool {
-        self.get_distance(other) < MATCH_THRESHOLD_RATIO
+        self.is_match(other)
     }
 
     pub fn get_similar_iris<R: Rng>(&self, rng: &mut R) -> IrisCode {
@@ -206,6 +372,81 @@ impl IrisCode {
 
         res
     }
+
+    /// Simulates a new capture of the same eye: clears pairs of mask bits with
+    /// probability `mask_drop_prob`, like the masks of
+    /// [`IrisCode::random_rng`], then flips each code bit that is still
+    /// unmasked with probability `flip_prob`. Returns the noisy code and the
+    /// number of flipped bits, so its distance to this code is that number
+    /// over the number of its unmasked bits.
+    ///
+    /// The probabilities are clamped to `[0, 1]`.
+    pub fn with_noise<R: Rng>(
+        &self,
+        flip_prob: f64,
+        mask_drop_prob: f64,
+        rng: &mut R,
+    ) -> (IrisCode, usize) {
+        let bernoulli = |p: f64| Bernoulli::new(if p.is_nan() { 0.0 } else { p.clamp(0.0, 1.0) });
+        let (flip, mask_drop) = (
+            bernoulli(flip_prob).unwrap(),
+            bernoulli(mask_drop_prob).unwrap(),
+        );
+
+        let mut res = self.clone();
+        for i in 0..Self::IRIS_CODE_SIZE / 2 {
+            if mask_drop.sample(rng) {
+                res.mask.set_bit(2 * i, false);
+                res.mask.set_bit(2 * i + 1, false);
+            }
+        }
+        let mut flips = 0;
+        for i in res.mask.set_bits().collect::<Vec<_>>() {
+            if flip.sample(rng) {
+                res.code.flip_bit(i);
+                flips += 1;
+            }
+        }
+        (res, flips)
+    }
+
+    /// Returns `steps + 1` codes that degrade from this one: the code of step
+    /// `i` differs from it in about `i * max_distance / steps` of the unmasked
+    /// bits, so step 0 is this code. Every step keeps the bits flipped by the
+    /// previous one, and all steps keep the mask.
+    ///
+    /// `max_distance` is clamped to `[0, 1]`. The distances are multiples of
+    /// one over the number of unmasked bits, so they are coarse for masks with
+    /// few set bits, and without set bits all steps equal this code.
+    pub fn morph_sequence<R: Rng>(
+        &self,
+        steps: usize,
+        max_distance: f64,
+        rng: &mut R,
+    ) -> Vec<IrisCode> {
+        let max_distance = if max_distance.is_nan() {
+            0.0
+        } else {
+            max_distance.clamp(0.0, 1.0)
+        };
+        let mut unmasked = self.mask.set_bits().collect::<Vec<_>>();
+        unmasked.shuffle(rng);
+
+        let mut sequence = Vec::with_capacity(steps + 1);
+        let mut current = self.clone();
+        sequence.push(current.clone());
+        let mut flipped = 0;
+        for step in 1..=steps {
+            let distance = step as f64 * max_distance / steps as f64;
+            let target = ((distance * unmasked.len() as f64).round() as usize).min(unmasked.len());
+            for &i in &unmasked[flipped..target] {
+                current.code.flip_bit(i);
+            }
+            flipped = target;
+            sequence.push(current.clone());
+        }
+        sequence
+    }
 }
 
 pub struct Bits<'a> {
@@ -243,19 +484,62 @@ impl ExactSizeIterator for Bits<'_> {}
 
 #[cfg(test)]
 mod tests {
-    use super::IrisCodeArray;
+    use super::{IrisCode, IrisCodeArray};
+    use base64::{prelude::BASE64_STANDARD, Engine};
     use eyre::{Context, ContextCompat};
+    use rand::{rngs::StdRng, SeedableRng};
     use std::collections::HashMap;
 
     #[test]
     fn bit_iter_eq_get_bit() {
         let mut rng = rand::thread_rng();
         let iris = super::IrisCode::random_rng(&mut rng);
-        for (i, bit) in iris.code.bits().enumerate() {
+        for (i, bit) in iris.code.iter_bits().enumerate() {
             assert_eq!(iris.code.get_bit(i), bit);
         }
     }
 
+    #[test]
+    fn bit_helpers() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let a = IrisCodeArray::random_rng(&mut rng);
+        let b = IrisCodeArray::random_rng(&mut rng);
+        assert_eq!(a.iter_bits().len(), IrisCodeArray::IRIS_CODE_SIZE);
+        assert_eq!(a.iter_bits().count(), IrisCodeArray::IRIS_CODE_SIZE);
+        assert_eq!(a.count_ones(), a.iter_bits().filter(|&bit| bit).count());
+        assert_eq!(a.set_bits().count(), a.count_ones());
+        assert!(a.set_bits().all(|i| a.get_bit(i)));
+
+        let and = a.and(&b);
+        let xor = a.xor(&b);
+        assert_eq!(and, a & b);
+        assert_eq!(xor, a ^ b);
+        for i in 0..IrisCodeArray::IRIS_CODE_SIZE {
+            assert_eq!(and.get_bit(i), a.get_bit(i) & b.get_bit(i));
+            assert_eq!(xor.get_bit(i), a.get_bit(i) ^ b.get_bit(i));
+        }
+        assert_eq!(a.xor(&a), IrisCodeArray::ZERO);
+        assert_eq!(a.and(&IrisCodeArray::ONES), a);
+        assert_eq!(
+            IrisCodeArray::ONES.count_ones(),
+            IrisCodeArray::IRIS_CODE_SIZE
+        );
+
+        let mut c = IrisCodeArray::ZERO;
+        let last = IrisCodeArray::IRIS_CODE_SIZE - 1;
+        c.set_bit(last, true);
+        assert!(c.get_bit(last));
+        assert_eq!(c.set_bits().collect::<Vec<_>>(), vec![last]);
+        c.set_bit(last, false);
+        assert_eq!(c, IrisCodeArray::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bit index 12800 out of range for 12800 bits")]
+    fn bit_index_is_checked() {
+        IrisCodeArray::ZERO.get_bit(IrisCodeArray::IRIS_CODE_SIZE);
+    }
+
     #[test]
     fn decode_from_string() {
         let (code_str, rotations) =
@@ -273,6 +557,217 @@ mod tests {
         assert_eq!(code_str, code.to_base64().unwrap());
     }
 
+    #[test]
+    fn open_iris_template_round_trip() {
+        let template: serde_json::Value =
+            serde_json::from_str(include_str!("../example-data/open_iris_template.json")).unwrap();
+        let code_b64 = template["iris_codes"].as_str().unwrap();
+        let mask_b64 = template["mask_codes"].as_str().unwrap();
+        let iris = IrisCode::from_open_iris_template(code_b64, mask_b64).unwrap();
+
+        // The fixture was packed from these (rows, columns, wavelets, bits)
+        // arrays with `np.packbits`.
+        for (i, (r, c, w, b)) in (0..16)
+            .flat_map(|r| (0..200).map(move |c| (r, c)))
+            .flat_map(|(r, c)| (0..4).map(move |k| (r, c, k / 2, k % 2)))
+            .enumerate()
+        {
+            assert_eq!(iris.code.get_bit(i), (3 * r + 5 * c + 2 * w + b) % 7 == 0);
+            let masked = (r == 1 && c < 8) || (w == 1 && c % 50 == 0);
+            assert_eq!(iris.mask.get_bit(i), !masked, "Mask bit {}", i);
+        }
+
+        let (code, mask) = iris.to_open_iris_template().unwrap();
+        assert_eq!(code, code_b64);
+        assert_eq!(mask, mask_b64);
+
+        let short = BASE64_STANDARD.encode([0u8; IrisCodeArray::IRIS_CODE_SIZE_BYTES - 8]);
+        let err = IrisCode::from_open_iris_template(code_b64, &short).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Invalid mask code: Expected 1600 bytes of (16, 200, 2, 2) bits, got 1592"
+        );
+        let long = BASE64_STANDARD.encode([0u8; IrisCodeArray::IRIS_CODE_SIZE_BYTES + 1]);
+        assert!(IrisCode::from_open_iris_template(&long, mask_b64).is_err());
+        assert!(IrisCode::from_open_iris_template("not base64!", mask_b64).is_err());
+    }
+
+    #[test]
+    fn mirrored_reverses_columns() {
+        let mut rng = rand::thread_rng();
+        let code = IrisCodeArray::random_rng(&mut rng);
+        let mirrored = code.mirrored();
+        assert_ne!(code, mirrored);
+        assert_eq!(mirrored.mirrored(), code);
+        assert_eq!(mirrored.count_ones(), code.count_ones());
+
+        let mut single = IrisCodeArray::ZERO;
+        // row 3, column 5, wavelet 1, bit 0
+        single.set_bit(3 * 800 + 5 * 4 + 2, true);
+        let mut expected = IrisCodeArray::ZERO;
+        expected.set_bit(3 * 800 + 194 * 4 + 2, true);
+        assert_eq!(single.mirrored(), expected);
+    }
+
+    #[test]
+    fn rotations_match_test_data() {
+        let (code_str, rotations) =
+            parse_test_data(include_str!("../example-data/all_rotations.txt")).unwrap();
+        let code = IrisCodeArray::from_base64(code_str).unwrap();
+        let all_rotations = code.all_rotations().collect::<Vec<_>>();
+        assert_eq!(all_rotations.len(), IrisCodeArray::ROTATIONS);
+        for (rotated, k) in all_rotations.iter().zip(-15..=15) {
+            let bits = rotated
+                .iter_bits()
+                .map(|bit| if bit { '1' } else { '0' })
+                .collect::<String>();
+            assert_eq!(bits, *rotations.get(&k).unwrap(), "Rotation {}", k);
+        }
+        assert_eq!(all_rotations[15], code);
+    }
+
+    #[test]
+    fn rotations_invert_and_commute_with_masking() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let iris = IrisCode::random_rng(&mut rng);
+        for k in [0, 1, 4, 15, 199, 200, 457] {
+            assert_eq!(iris.code.rotate_right(k).rotate_left(k), iris.code);
+            assert_eq!(iris.code.rotate_left(k).rotate_right(k), iris.code);
+            assert_eq!(
+                (iris.code & iris.mask).rotate_right(k),
+                iris.code.rotate_right(k) & iris.mask.rotate_right(k)
+            );
+            assert_eq!(
+                (iris.code & iris.mask).rotate_left(k),
+                iris.code.rotate_left(k) & iris.mask.rotate_left(k)
+            );
+        }
+        assert_eq!(iris.code.rotate_right(200), iris.code);
+        assert_eq!(iris.code.rotate_left(3), iris.code.rotate_right(197));
+
+        // Rotations stay within their row.
+        let mut single = IrisCodeArray::ZERO;
+        // row 2, column 198, wavelet 0, bit 1
+        single.set_bit(2 * 800 + 198 * 4 + 1, true);
+        let mut expected = IrisCodeArray::ZERO;
+        expected.set_bit(2 * 800 + 3 * 4 + 1, true);
+        assert_eq!(single.rotate_right(5), expected);
+    }
+
+    #[test]
+    fn morph_sequence_degrades_stepwise() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let base = IrisCode::random_rng(&mut rng);
+        let unmasked = base.mask.count_ones() as f64;
+        let sequence = base.morph_sequence(8, 0.5, &mut rng);
+        assert_eq!(sequence.len(), 9);
+        assert_eq!(sequence[0], base);
+        for (i, step) in sequence.iter().enumerate() {
+            assert_eq!(step.mask, base.mask);
+            let expected = i as f64 * 0.5 / 8.0;
+            assert!((base.get_distance(step) - expected).abs() <= 0.5 / unmasked);
+            if i > 0 {
+                // The bits flipped by the previous step stay flipped.
+                let previous = sequence[i - 1].code ^ base.code;
+                assert_eq!((step.code ^ base.code) & previous, previous);
+            }
+        }
+        assert!(sequence[2].is_close(&base));
+        assert!(!sequence[8].is_close(&base));
+    }
+
+    #[test]
+    fn morph_sequence_edge_cases() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut base = IrisCode::random_rng(&mut rng);
+        // Beyond what the mask allows, the distance is clamped.
+        let sequence = base.morph_sequence(2, 1.5, &mut rng);
+        assert_eq!(base.get_distance(&sequence[2]), 1.0);
+        assert_eq!(base.get_distance(&sequence[1]), 0.5);
+        assert_eq!(base.morph_sequence(0, 0.5, &mut rng), vec![base.clone()]);
+
+        // With three unmasked bits, the distances are rounded to thirds.
+        base.mask = IrisCodeArray::ZERO;
+        for i in [0, 1, 2] {
+            base.mask.set_bit(i, true);
+        }
+        let distances = base
+            .morph_sequence(4, 1.0, &mut rng)
+            .iter()
+            .map(|step| base.get_distance(step))
+            .collect::<Vec<_>>();
+        assert_eq!(distances, vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 1.0]);
+
+        base.mask = IrisCodeArray::ZERO;
+        let sequence = base.morph_sequence(3, 0.5, &mut rng);
+        assert!(sequence.iter().all(|step| step == &base));
+    }
+
+    #[test]
+    fn masked_distance_counts_common_unmasked_bits() {
+        let mut a = IrisCode {
+            code: IrisCodeArray::ZERO,
+            mask: IrisCodeArray::ZERO,
+        };
+        let mut b = a.clone();
+        assert_eq!(a.masked_distance(&b), None);
+        assert!(!a.is_match(&b));
+        assert!(a.get_distance(&b).is_nan());
+
+        // Bits 0..8 are unmasked in both codes, and differ in 0..3 and 8.
+        for i in 0..10 {
+            a.mask.set_bit(i, true);
+        }
+        for i in 0..8 {
+            b.mask.set_bit(i, true);
+        }
+        for i in [0, 1, 2, 8] {
+            b.code.set_bit(i, true);
+        }
+        assert_eq!(a.masked_distance(&b), Some((3, 8, 0.375)));
+        assert_eq!(b.masked_distance(&a), a.masked_distance(&b));
+        // The threshold itself is not a match.
+        assert!(!a.is_match(&b));
+        b.code.set_bit(2, false);
+        assert!(a.is_match(&b));
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let iris = IrisCode::random_rng(&mut rng);
+        let (_, mask_len, distance) = iris.masked_distance(&iris).unwrap();
+        assert_eq!(mask_len as usize, iris.mask.count_ones());
+        assert_eq!(distance, 0.0);
+        assert!(iris.is_match(&iris.get_similar_iris(&mut rng)));
+        assert!(!iris.is_match(&IrisCode::random_rng(&mut rng)));
+    }
+
+    #[test]
+    fn with_noise_respects_mask() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let base = IrisCode::random_rng(&mut rng);
+        let (noisy, flips) = base.with_noise(0.1, 0.05, &mut rng);
+        // Only unmasked bits are flipped, and masks only lose bits.
+        let flipped = noisy.code ^ base.code;
+        assert_eq!(flipped & noisy.mask, flipped);
+        assert_eq!(noisy.mask & base.mask, noisy.mask);
+        let dropped = base.mask.count_ones() - noisy.mask.count_ones();
+        assert!(dropped > 0 && dropped % 2 == 0);
+        assert_eq!(
+            base.masked_distance(&noisy),
+            Some((
+                flips as u32,
+                noisy.mask.count_ones() as u32,
+                flips as f64 / noisy.mask.count_ones() as f64
+            ))
+        );
+        assert!((base.get_distance(&noisy) - 0.1).abs() < 0.01);
+        assert!(base.is_match(&noisy));
+
+        assert_eq!(base.with_noise(0.0, f64::NAN, &mut rng), (base.clone(), 0));
+        let (noisy, flips) = base.with_noise(2.0, 0.0, &mut rng);
+        assert_eq!(flips, base.mask.count_ones());
+        assert_eq!(base.get_distance(&noisy), 1.0);
+    }
+
     pub fn parse_test_data(s: &str) -> eyre::Result<(&str, HashMap<i32, String>)> {
         let lines = s.lines();
         let mut lines = lines.map(|s| s.trim()).filter(|s| !s.is_empty());
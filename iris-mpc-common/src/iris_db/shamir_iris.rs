@@ -44,7 +44,9 @@ impl ShamirIris {
             ShamirIris::default(),
             ShamirIris::default(),
         ];
-        for (bitindex, (c_bit, m_bit)) in iris.code.bits().zip(iris.mask.bits()).enumerate() {
+        for (bitindex, (c_bit, m_bit)) in
+            iris.code.iter_bits().zip(iris.mask.iter_bits()).enumerate()
+        {
             let (code_shares, mask_shares) = Self::share_bit(c_bit, m_bit, rng);
             for (res, (code, mask)) in result
                 .iter_mut()
@@ -1,9 +1,21 @@
 use super::id::PartyID;
 use rand::Rng;
+use rayon::prelude::*;
 
 pub const P: u16 = ((1u32 << 16) - 17) as u16;
 pub const P32: u32 = P as u32;
 
+/// Share vectors at least this long are converted on the rayon pool.
+const PAR_CONVERSION_THRESHOLD: usize = 1 << 14;
+
+fn map_shares(len: usize, f: impl Fn(usize) -> u16 + Sync + Send) -> Vec<u16> {
+    if len >= PAR_CONVERSION_THRESHOLD {
+        (0..len).into_par_iter().map(f).collect()
+    } else {
+        (0..len).map(f).collect()
+    }
+}
+
 pub struct Shamir {}
 
 impl Shamir {
@@ -76,6 +88,61 @@ impl Shamir {
         }
         ((num * Self::mod_inverse(den as u16) as u32) % P32) as u16
     }
+
+    /// Converts this party's degree-1 Shamir shares into its half of a
+    /// replicated sharing over `P`, in the `(a, b)` layout of the GPU
+    /// `ChunkShare`:
+    ///
+    /// - `a` is the party's additive share `x_i = λ_i * s_i + z_i`, where `λ_i`
+    ///   is [`Shamir::my_lagrange_coeff_d2`] and `z_i` its entry of a
+    ///   zero-sharing (`z_0 + z_1 + z_2 = 0 mod P`, e.g. `r_i - r_{i-1}` from
+    ///   the PRF keys shared with the neighbours).
+    /// - `b` is `x_{i-1}`, the `a` of `party.prev_id()`.
+    ///
+    /// `a` must be sent to `party.next_id()`, and `prev_a` is what was
+    /// received from `party.prev_id()`; the zero-sharing is what keeps `a`
+    /// from revealing the Shamir share to the next party.
+    pub fn shamir_to_replicated(
+        party: PartyID,
+        shares: &[u16],
+        zero_shares: &[u16],
+        prev_a: Vec<u16>,
+    ) -> (Vec<u16>, Vec<u16>) {
+        assert_eq!(prev_a.len(), shares.len());
+        (Self::shamir_to_additive(party, shares, zero_shares), prev_a)
+    }
+
+    /// The local half of [`Shamir::shamir_to_replicated`]: this party's
+    /// additive shares `λ_i * s_i + z_i mod P`.
+    pub fn shamir_to_additive(party: PartyID, shares: &[u16], zero_shares: &[u16]) -> Vec<u16> {
+        assert_eq!(shares.len(), zero_shares.len());
+        let lagrange = Self::my_lagrange_coeff_d2(party) as u32;
+        map_shares(shares.len(), |i| {
+            ((shares[i] as u32 * lagrange % P32 + zero_shares[i] as u32) % P32) as u16
+        })
+    }
+
+    /// The inverse of [`Shamir::shamir_to_replicated`], used in tests: turns
+    /// the replicated `(a, b) = (x_i, x_{i-1})` shares back into degree-1
+    /// Shamir shares without communication. The shared polynomial is `f(X) =
+    /// sum_j x_j * (1 - X / e_j)`, where `e_j` is the evaluation point of the
+    /// party lacking `x_j`, so every party can evaluate it at its own point.
+    /// It is a fresh sharing of the same secret, not the original one.
+    pub fn replicated_to_shamir(party: PartyID, a: &[u16], b: &[u16]) -> Vec<u16> {
+        assert_eq!(a.len(), b.len());
+        let point = usize::from(party) as u32 + 1;
+        // x_j is missing at party j - 1, whose evaluation point is j (or 3 for
+        // j = 0).
+        let weight = |j: PartyID| {
+            let missing_at = usize::from(j.prev_id()) as u32 + 1;
+            let quotient = point * Self::mod_inverse(missing_at as u16) as u32 % P32;
+            (1 + P32 - quotient) % P32
+        };
+        let (weight_a, weight_b) = (weight(party), weight(party.prev_id()));
+        map_shares(a.len(), |i| {
+            ((a[i] as u32 * weight_a % P32 + b[i] as u32 * weight_b % P32) % P32) as u16
+        })
+    }
 }
 
 #[cfg(test)]
@@ -153,4 +220,100 @@ mod shamir_test {
             assert_eq!(mul, reconstructed);
         }
     }
+
+    fn zero_sharing<R: Rng>(len: usize, rng: &mut R) -> [Vec<u16>; 3] {
+        let z0: Vec<u16> = (0..len).map(|_| Shamir::random_fp(rng)).collect();
+        let z1: Vec<u16> = (0..len).map(|_| Shamir::random_fp(rng)).collect();
+        let z2 = z0
+            .iter()
+            .zip(z1.iter())
+            .map(|(&a, &b)| ((2 * P32 - a as u32 - b as u32) % P32) as u16)
+            .collect();
+        [z0, z1, z2]
+    }
+
+    fn reconstruct_d1(first: (PartyID, &[u16]), second: (PartyID, &[u16])) -> Vec<u16> {
+        let coeffs = [
+            Shamir::my_lagrange_coeff_d1(first.0, second.0) as u32,
+            Shamir::my_lagrange_coeff_d1(second.0, first.0) as u32,
+        ];
+        first
+            .1
+            .iter()
+            .zip(second.1.iter())
+            .map(|(&x, &y)| {
+                ((x as u32 * coeffs[0] % P32 + y as u32 * coeffs[1] % P32) % P32) as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replicated_conversion() {
+        let mut rng = rand::thread_rng();
+        let parties = [PartyID::ID0, PartyID::ID1, PartyID::ID2];
+        // Both sides of the rayon threshold.
+        for len in [1, 100, PAR_CONVERSION_THRESHOLD + 3] {
+            for _ in 0..TESTRUNS {
+                let secrets: Vec<u16> = (0..len).map(|_| Shamir::random_fp(&mut rng)).collect();
+                let mut shamir = [vec![0; len], vec![0; len], vec![0; len]];
+                for (i, &secret) in secrets.iter().enumerate() {
+                    let shares = Shamir::share_d1(secret, &mut rng);
+                    for (party_shares, share) in shamir.iter_mut().zip(shares) {
+                        party_shares[i] = share;
+                    }
+                }
+                assert_eq!(
+                    reconstruct_d1((PartyID::ID0, &shamir[0]), (PartyID::ID2, &shamir[2])),
+                    secrets
+                );
+
+                let zeros = zero_sharing(len, &mut rng);
+                let additive: Vec<Vec<u16>> = parties
+                    .iter()
+                    .map(|&p| {
+                        Shamir::shamir_to_additive(p, &shamir[p as usize], &zeros[p as usize])
+                    })
+                    .collect();
+                let replicated: Vec<(Vec<u16>, Vec<u16>)> = parties
+                    .iter()
+                    .map(|&p| {
+                        Shamir::shamir_to_replicated(
+                            p,
+                            &shamir[p as usize],
+                            &zeros[p as usize],
+                            additive[p.prev_id() as usize].clone(),
+                        )
+                    })
+                    .collect();
+
+                for p in parties {
+                    let (a, b) = &replicated[p as usize];
+                    assert_eq!(b, &replicated[p.prev_id() as usize].0);
+                    let sums: Vec<u16> = (0..len)
+                        .map(|i| {
+                            ((a[i] as u32
+                                + b[i] as u32
+                                + replicated[p.next_id() as usize].0[i] as u32)
+                                % P32) as u16
+                        })
+                        .collect();
+                    assert_eq!(sums, secrets);
+                }
+
+                let back: Vec<Vec<u16>> = parties
+                    .iter()
+                    .map(|&p| {
+                        let (a, b) = &replicated[p as usize];
+                        Shamir::replicated_to_shamir(p, a, b)
+                    })
+                    .collect();
+                for (x, y) in [(0, 1), (0, 2), (1, 2)] {
+                    assert_eq!(
+                        reconstruct_d1((parties[x], &back[x]), (parties[y], &back[y])),
+                        secrets
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,149 @@
+//! The data types used by the Python bindings and other embedders. CI runs
+//! this test with `--no-default-features`, so the types stay usable without
+//! the `aws` feature.
+
+mod tests {
+    use iris_mpc_common::{
+        galois_engine::degree4::{GaloisRingIrisCodeShare, ShareKind},
+        helpers::{
+            key_pair::{SharesEncryptionKeyPair, SharesEncryptionKeyPairs},
+            key_provider::KeyPairProvider,
+            smpc_request::{
+                iris_to_encrypted_shares, IrisCodesJSON, SharesS3Object, SupportedIrisVersion,
+                UniquenessRequest,
+            },
+        },
+        iris_db::iris::{IrisCode, IrisCodeArray},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_iris_code_base64_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let iris = IrisCode::random_rng(&mut rng);
+
+        let code = IrisCodeArray::from_base64(&iris.code.to_base64().unwrap()).unwrap();
+        let mask = IrisCodeArray::from_base64(&iris.mask.to_base64().unwrap()).unwrap();
+        let decoded = IrisCode { code, mask };
+        assert_eq!(decoded.get_distance(&iris), 0.0);
+        assert!(iris.is_close(&iris.get_similar_iris(&mut rng)));
+    }
+
+    #[test]
+    fn test_iris_code_shares_without_aws() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let iris = IrisCode::random_rng(&mut rng);
+
+        let shares = GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng);
+        let code = GaloisRingIrisCodeShare::reconstruct(&shares).unwrap();
+        assert_eq!(code, iris.code & iris.mask);
+        let masks = GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, &mut rng);
+
+        let json = IrisCodesJSON {
+            iris_version:           "1.0".to_string(),
+            iris_shares_version:    "1.0".to_string(),
+            left_iris_code_shares:  shares[0].to_base64(ShareKind::Code),
+            right_iris_code_shares: shares[1].to_base64(ShareKind::Code),
+            left_mask_code_shares:  masks[0].to_base64(ShareKind::Mask),
+            right_mask_code_shares: masks[1].to_base64(ShareKind::Mask),
+        };
+        let parsed: IrisCodesJSON =
+            serde_json::from_str(&serde_json::to_string(&json).unwrap()).unwrap();
+        assert_eq!(parsed, json);
+    }
+
+    #[tokio::test]
+    async fn test_iris_to_encrypted_shares() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let left = IrisCode::random_rng(&mut rng);
+        let right = IrisCode::random_rng(&mut rng);
+        let key_pairs: [SharesEncryptionKeyPair; 3] =
+            std::array::from_fn(|_| SharesEncryptionKeyPair::generate());
+
+        let (object, hashes) = iris_to_encrypted_shares(
+            &left,
+            &right,
+            key_pairs.each_ref().map(|key_pair| key_pair.public_key()),
+            &mut rng,
+        );
+        let request = UniquenessRequest {
+            batch_size:              None,
+            signup_id:               "signup".to_string(),
+            s3_key:                  "key".to_string(),
+            iris_shares:             None,
+            iris_shares_file_hashes: hashes,
+            mirrored_check:          None,
+            rotation_window:         None,
+            or_rule_serial_ids:      None,
+        };
+
+        let mut codes = Vec::new();
+        let mut masks = Vec::new();
+        for (party_id, key_pair) in key_pairs.into_iter().enumerate() {
+            let key_pairs = KeyPairProvider::fixed(SharesEncryptionKeyPairs {
+                current_key_pair:   key_pair,
+                previous_key_pairs: vec![],
+                generation:         None,
+            });
+            let share = request
+                .decrypt_iris_share(
+                    object.get(party_id).unwrap().clone(),
+                    &key_pairs,
+                    &SupportedIrisVersion::ALL,
+                )
+                .await
+                .unwrap();
+            request
+                .validate_iris_share(party_id, share.clone())
+                .unwrap();
+
+            let decode = |s: &str, kind| {
+                GaloisRingIrisCodeShare::decode_validated(s, kind, party_id).unwrap()
+            };
+            codes.push([
+                decode(&share.left_iris_code_shares, ShareKind::Code),
+                decode(&share.right_iris_code_shares, ShareKind::Code),
+            ]);
+            masks.push([
+                decode(&share.left_mask_code_shares, ShareKind::Mask),
+                decode(&share.right_mask_code_shares, ShareKind::Mask),
+            ]);
+        }
+
+        for (eye, iris) in [&left, &right].into_iter().enumerate() {
+            let code: [GaloisRingIrisCodeShare; 3] = std::array::from_fn(|i| codes[i][eye].clone());
+            let mask: [GaloisRingIrisCodeShare; 3] = std::array::from_fn(|i| masks[i][eye].clone());
+            assert_eq!(
+                GaloisRingIrisCodeShare::reconstruct(&code).unwrap(),
+                iris.code & iris.mask
+            );
+            assert_eq!(
+                GaloisRingIrisCodeShare::reconstruct_mask(&mask).unwrap(),
+                iris.mask
+            );
+        }
+    }
+
+    #[test]
+    fn test_shares_s3_object_formats() {
+        let legacy = r#"{"iris_share_0":"a","iris_share_1":"b","iris_share_2":"c"}"#;
+        let object: SharesS3Object = serde_json::from_str(legacy).unwrap();
+        assert_eq!(object.party_count(), 3);
+        assert_eq!(object.get(1), Some(&"b".to_string()));
+        assert_eq!(object.get(3), None);
+        // Three parties keep the field names of the uploaders.
+        assert_eq!(serde_json::to_string(&object).unwrap(), legacy);
+        let by_index: SharesS3Object =
+            serde_json::from_str(r#"{"0":"a","1":"b","2":"c","version":1}"#).unwrap();
+        assert_eq!(by_index, object);
+
+        let object = SharesS3Object::from_shares((0..4).map(|i| (i, i.to_string())));
+        let json = serde_json::to_string(&object).unwrap();
+        assert_eq!(json, r#"{"0":"0","1":"1","2":"2","3":"3"}"#);
+        let parsed: SharesS3Object = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.party_count(), 4);
+        assert_eq!(parsed, object);
+
+        assert!(serde_json::from_str::<SharesS3Object>(r#"{"0":"a","iris_share_0":"b"}"#).is_err());
+    }
+}
@@ -1,5 +1,8 @@
 mod tests {
-    use iris_mpc_common::helpers::sha256::calculate_sha256;
+    use iris_mpc_common::helpers::sha256::{
+        calculate_sha256, digest_eq_hex, sha256_digest, Sha256Stream,
+    };
+    use std::io::Write;
 
     #[test]
     fn test_calculate_sha256() {
@@ -47,4 +50,46 @@ mod tests {
             "SHA-256 hashes of different data should not be equal"
         );
     }
+
+    #[test]
+    fn test_digest_eq_hex() {
+        let digest = sha256_digest("Hello, world!");
+        let hash = calculate_sha256("Hello, world!");
+        assert!(digest_eq_hex(&digest, &hash));
+        assert!(digest_eq_hex(&digest, &hash.to_uppercase()));
+        assert!(!digest_eq_hex(&digest, &calculate_sha256("Hello, world?")));
+        // Truncated and malformed hashes never match.
+        assert!(!digest_eq_hex(&digest, &hash[..62]));
+        assert!(!digest_eq_hex(&digest, "incorrect_hash_value"));
+    }
+
+    #[test]
+    fn test_sha256_stream() {
+        let mut stream = Sha256Stream::new();
+        for chunk in ["Hello", ", ", "world", "!"] {
+            stream.update(chunk);
+        }
+        assert_eq!(
+            stream.finalize_hex(),
+            "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3"
+        );
+        assert_eq!(
+            Sha256Stream::new().finalize_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        // Chunks that do and do not align with the 64 byte blocks.
+        let data = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for chunk_size in [1, 7, 64, 1000, data.len()] {
+            let mut stream = Sha256Stream::new();
+            for chunk in data.chunks(chunk_size) {
+                stream.update(chunk);
+            }
+            assert_eq!(stream.finalize(), sha256_digest(&data), "{}", chunk_size);
+        }
+
+        let mut writer = Sha256Stream::new();
+        write!(writer, "Hello, world!").unwrap();
+        assert_eq!(writer.finalize_hex(), calculate_sha256("Hello, world!"));
+    }
 }
@@ -1,15 +1,50 @@
+#![cfg(feature = "aws")]
+
 mod tests {
+    use async_trait::async_trait;
+    use aws_config::retry::RetryConfig;
     use aws_credential_types::{provider::SharedCredentialsProvider, Credentials};
-    use aws_sdk_s3::Client as S3Client;
+    use aws_sdk_s3::{
+        config::{BehaviorVersion, Region},
+        Client as S3Client,
+    };
+    use aws_sdk_sns::{primitives::Blob, types::MessageAttributeValue};
     use base64::{engine::general_purpose::STANDARD, Engine};
-    use iris_mpc_common::helpers::{
-        key_pair::{SharesDecodingError, SharesEncryptionKeyPairs},
-        sha256::calculate_sha256,
-        smpc_request::{IrisCodesJSON, UniquenessRequest},
+    use iris_mpc_common::{
+        errors::{ErrorCode, HasErrorCode},
+        helpers::{
+            intake::BatchRequest,
+            key_pair::{
+                seal_share, PreviousKeyPair, PublicKeySet, SharesDecodingError,
+                SharesEncryptionKeyPair, SharesEncryptionKeyPairs,
+            },
+            key_provider::{KeyPairProvider, KeyPairSource, KeyRefreshConfig},
+            sha256::calculate_sha256,
+            share_download::{DownloadBackoff, DownloadRetryConfig, ShareDownloadConfig},
+            smpc_request::{
+                parse_incoming_body, IdentityDeletionBatchError, IdentityDeletionBatchRequest,
+                IrisCodesJSON, ParsedRequest, ReAuthRequest, ReceiveRequestError, RequestKind,
+                ResetCheckRequest, ResetUpdateRequest, SQSMessage, ShareSource, SharesS3Object,
+                SupportedIrisVersion, UniquenessRequest, MAX_IDENTITY_DELETION_BATCH_SIZE,
+                REAUTH_MESSAGE_TYPE, REQUEST_SCHEMA_VERSION, SMPC_SCHEMA_VERSION_ATTRIBUTE,
+                UNIQUENESS_MESSAGE_TYPE,
+            },
+            smpc_response::{create_message_type_attribute_map, SMPC_MESSAGE_TYPE_ATTRIBUTE},
+        },
+        iris_db::iris::IrisCode,
     };
+    use rand::{rngs::StdRng, SeedableRng};
     use serde_json::json;
     use sodiumoxide::crypto::{box_::PublicKey, sealedbox};
-    use std::sync::Arc;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
     use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
 
     const PREVIOUS_PUBLIC_KEY: &str = "1UY8lKlS7aVj5ZnorSfLIHlG3jg+L4ToVi4K+mLKqFQ=";
@@ -40,12 +75,39 @@ mod tests {
         }
     }
 
+    /// A share file of `version` whose shares have the length of its
+    /// encoding.
+    fn sized_iris_codes_json(version: &str, share_len: usize) -> IrisCodesJSON {
+        let share = |fill: u8| STANDARD.encode(vec![fill; share_len]);
+        IrisCodesJSON {
+            iris_version:           version.to_string(),
+            iris_shares_version:    "1.3".to_string(),
+            left_iris_code_shares:  share(0),
+            right_iris_code_shares: share(1),
+            left_mask_code_shares:  share(2),
+            right_mask_code_shares: share(3),
+        }
+    }
+
+    fn current_public_key() -> PublicKey {
+        PublicKey::from_slice(&STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap()).unwrap()
+    }
+
+    fn seal_iris_codes_json(iris_codes_json: &IrisCodesJSON, public_key: &PublicKey) -> String {
+        let json_string = serde_json::to_string(iris_codes_json).unwrap();
+        STANDARD.encode(sealedbox::seal(json_string.as_bytes(), public_key))
+    }
+
     fn get_mock_smpc_request_with_hashes(hashes: [String; 3]) -> UniquenessRequest {
         UniquenessRequest {
             batch_size:              Some(1),
             signup_id:               "signup_mock".to_string(),
             s3_key:                  "mock".to_string(),
+            iris_shares:             None,
             iris_shares_file_hashes: hashes,
+            mirrored_check:          None,
+            rotation_window:         None,
+            or_rule_serial_ids:      None,
         }
     }
 
@@ -54,11 +116,15 @@ mod tests {
             batch_size:              None,
             signup_id:               "test_signup_id".to_string(),
             s3_key:                  "package".to_string(),
+            iris_shares:             None,
             iris_shares_file_hashes: [
                 "hash_0".to_string(),
                 "hash_1".to_string(),
                 "hash_2".to_string(),
             ],
+            mirrored_check:          None,
+            rotation_window:         None,
+            or_rule_serial_ids:      None,
         }
     }
 
@@ -105,32 +171,164 @@ mod tests {
             batch_size:              None,
             signup_id:               "test_signup_id".to_string(),
             s3_key:                  key.to_string(),
+            iris_shares:             None,
             iris_shares_file_hashes: [
                 "hash_0".to_string(),
                 "hash_1".to_string(),
                 "hash_2".to_string(),
             ],
+            mirrored_check:          None,
+            rotation_window:         None,
+            or_rule_serial_ids:      None,
         };
 
         let result = smpc_request
-            .get_iris_data_by_party_id(0, &bucket_name.to_string(), &s3_client)
+            .get_iris_data_by_party_id(
+                0,
+                &bucket_name.to_string(),
+                &s3_client,
+                &ShareDownloadConfig::default(),
+            )
             .await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "share_0_data".to_string());
     }
 
+    async fn mock_s3_client(body: String) -> (MockServer, Arc<S3Client>) {
+        mock_s3_client_with(
+            ResponseTemplate::new(200)
+                .insert_header("Content-Type", "application/octet-stream")
+                .set_body_raw(body, "application/octet-stream"),
+        )
+        .await
+    }
+
+    async fn mock_s3_client_with(response: ResponseTemplate) -> (MockServer, Arc<S3Client>) {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(response)
+            .mount(&mock_server)
+            .await;
+
+        let credentials =
+            Credentials::new("test-access-key", "test-secret-key", None, None, "test");
+        let config = aws_config::from_env()
+            .region("us-west-2")
+            .endpoint_url(mock_server.uri())
+            .credentials_provider(SharedCredentialsProvider::new(credentials))
+            .load()
+            .await;
+        // Only the retries of the download are counted, not those of the SDK.
+        let s3_config = aws_sdk_s3::config::Builder::from(&config)
+            .endpoint_url(mock_server.uri())
+            .force_path_style(true)
+            .retry_config(RetryConfig::disabled())
+            .build();
+        (mock_server, Arc::new(S3Client::from_conf(s3_config)))
+    }
+
+    /// An S3 client for the downloads from presigned URLs, which never use it.
+    fn unused_s3_client() -> Arc<S3Client> {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-west-2"))
+            .build();
+        Arc::new(S3Client::from_conf(config))
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_iris_shares_of_absent_party() {
+        let response_body = json!({
+            "iris_share_0": "share_0_data",
+            "iris_share_1": "share_1_data",
+            "iris_share_2": "share_2_data"
+        });
+        let (_mock_server, s3_client) = mock_s3_client(response_body.to_string()).await;
+
+        let result = get_mock_request()
+            .get_iris_data_by_party_id(
+                3,
+                &"bobTheBucket".to_string(),
+                &s3_client,
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::PartyShareNotFound {
+                party_id:    3,
+                party_count: 3,
+            })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_and_decrypt() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let public_key =
+            PublicKey::from_slice(&STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap())
+                .unwrap();
+        let shares = IrisCodesJSON::share_irises(
+            &IrisCode::random_rng(&mut rng),
+            &IrisCode::random_rng(&mut rng),
+            &mut rng,
+        );
+        let (object, hashes) = SharesS3Object::seal(&shares, [&public_key; 3]);
+        let (_mock_server, s3_client) =
+            mock_s3_client(serde_json::to_string(&object).unwrap()).await;
+
+        let smpc_request = get_mock_smpc_request_with_hashes(hashes);
+        let key_pairs = KeyPairProvider::fixed(get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        ));
+        let bucket_name = "bobTheBucket".to_string();
+        for party_id in 0..3 {
+            let result = smpc_request
+                .fetch_and_decrypt(
+                    party_id,
+                    &bucket_name,
+                    &s3_client,
+                    &ShareDownloadConfig::default(),
+                    &key_pairs,
+                    &SupportedIrisVersion::ALL,
+                )
+                .await;
+            // Both eyes come back as they were shared.
+            assert_eq!(result.unwrap(), shares[party_id]);
+        }
+
+        // The hash covers both eyes, a share with either eye of another party
+        // fails as a whole.
+        for right_eye in [false, true] {
+            let mut tampered = shares.clone();
+            if right_eye {
+                tampered[0].right_iris_code_shares = shares[1].right_iris_code_shares.clone();
+            } else {
+                tampered[0].left_iris_code_shares = shares[1].left_iris_code_shares.clone();
+            }
+            let (object, _) = SharesS3Object::seal(&tampered, [&public_key; 3]);
+            let (_mock_server, s3_client) =
+                mock_s3_client(serde_json::to_string(&object).unwrap()).await;
+            let result = smpc_request
+                .fetch_and_decrypt(
+                    0,
+                    &bucket_name,
+                    &s3_client,
+                    &ShareDownloadConfig::default(),
+                    &key_pairs,
+                    &SupportedIrisVersion::ALL,
+                )
+                .await;
+            assert!(matches!(result, Err(SharesDecodingError::HashMismatch(_))));
+        }
+    }
+
     #[tokio::test]
     async fn test_decrypt_iris_share_success() {
         // Mocked base64 encoded JSON string
-        let iris_codes_json = IrisCodesJSON {
-            iris_version:           "1.0".to_string(),
-            iris_shares_version:    "1.3".to_string(),
-            left_iris_code_shares:  "left_code".to_string(),
-            right_iris_code_shares: "right_code".to_string(),
-            left_mask_code_shares:  "left_mask".to_string(),
-            right_mask_code_shares: "right_mask".to_string(),
-        };
+        let iris_codes_json = sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len());
 
         let decoded_public_key = STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap();
         let shares_encryption_public_key = PublicKey::from_slice(&decoded_public_key).unwrap();
@@ -146,7 +344,13 @@ mod tests {
             CURRENT_PRIVATE_KEY.to_string(),
         );
 
-        let result = smpc_request.decrypt_iris_share(encoded_share, key_pair);
+        let result = smpc_request
+            .decrypt_iris_share(
+                encoded_share,
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), iris_codes_json);
@@ -155,7 +359,7 @@ mod tests {
     #[tokio::test]
     async fn test_decrypt_iris_share_using_previous_valid_key() {
         // Mocked base64 encoded JSON string
-        let iris_codes_json = mock_iris_codes_json();
+        let iris_codes_json = sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len());
 
         // Use previous public key to encrypt the shares
         let decoded_public_key = STANDARD.decode(PREVIOUS_PUBLIC_KEY.as_bytes()).unwrap();
@@ -174,12 +378,82 @@ mod tests {
 
         // Decrypt the share. It will succeed, by first attempting to use the current
         // private key (failing), and then the previous private key (succeeding)
-        let result = smpc_request.decrypt_iris_share(encoded_share, key_pair);
+        let result = smpc_request
+            .decrypt_iris_share(
+                encoded_share,
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), iris_codes_json);
     }
 
+    #[tokio::test]
+    async fn test_seal_share_round_trip() {
+        let iris_codes_json = sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len());
+        let key_pairs = KeyPairProvider::fixed(get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        ));
+
+        // Senders holding either the current or the previous key of the party.
+        for public_key in [CURRENT_PUBLIC_KEY, PREVIOUS_PUBLIC_KEY] {
+            let public_keys = PublicKeySet::from_b64_strings([public_key; 3]).unwrap();
+            let sealed = seal_share(&iris_codes_json, public_keys.get(0).unwrap());
+            let result = get_mock_request()
+                .decrypt_iris_share(sealed, &key_pairs, &SupportedIrisVersion::ALL)
+                .await;
+            assert_eq!(result.unwrap(), iris_codes_json);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_key_set() {
+        let shares = [
+            sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len()),
+            sized_iris_codes_json("1.0", SupportedIrisVersion::V1_0.share_len()),
+            sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len()),
+        ];
+        let key_pairs = KeyPairProvider::fixed(get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        ));
+        let public_keys = PublicKeySet::from_b64_strings([
+            CURRENT_PUBLIC_KEY,
+            PREVIOUS_PUBLIC_KEY,
+            CURRENT_PUBLIC_KEY,
+        ])
+        .unwrap();
+        assert!(public_keys.get(3).is_none());
+
+        let (sealed, hashes) = public_keys.seal(&shares);
+        let smpc_request = get_mock_smpc_request_with_hashes(hashes);
+        for (party_id, expected) in shares.into_iter().enumerate() {
+            let share = smpc_request
+                .decrypt_iris_share(
+                    sealed.get(party_id).unwrap().clone(),
+                    &key_pairs,
+                    &SupportedIrisVersion::ALL,
+                )
+                .await
+                .unwrap();
+            assert_eq!(share, expected);
+            assert!(smpc_request.validate_iris_share(party_id, share).is_ok());
+        }
+
+        assert!(matches!(
+            PublicKeySet::from_b64_strings([CURRENT_PUBLIC_KEY, "not base64", CURRENT_PUBLIC_KEY]),
+            Err(SharesDecodingError::DecodingError(_))
+        ));
+        let short_key = STANDARD.encode([0u8; 16]);
+        assert!(matches!(
+            PublicKeySet::from_b64_strings([CURRENT_PUBLIC_KEY, &short_key, CURRENT_PUBLIC_KEY]),
+            Err(SharesDecodingError::ParsingKeyError)
+        ));
+    }
+
     #[tokio::test]
     async fn test_decrypt_iris_share_non_existent_previous_private_key() {
         // Mocked base64 encoded JSON string
@@ -200,13 +474,317 @@ mod tests {
         // Decrypt the share. It will fail: it will attempt to decrypt using the current
         // key, but the share was encrypted using the current key. The previous
         // key does not exist, so it will return a sealed box open error
-        let result = smpc_request.decrypt_iris_share(encoded_share, key_pair);
+        let result = smpc_request
+            .decrypt_iris_share(
+                encoded_share,
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
         assert!(matches!(
             result,
             Err(SharesDecodingError::SealedBoxOpenError)
         ));
     }
 
+    #[tokio::test]
+    async fn test_decrypt_iris_share_legacy_version() {
+        // Shares without a header, one byte longer than the current ones.
+        let iris_codes_json = sized_iris_codes_json("1.0", SupportedIrisVersion::V1_0.share_len());
+        let key_pair = || {
+            get_key_pairs(
+                CURRENT_PRIVATE_KEY.to_string(),
+                PREVIOUS_PRIVATE_KEY.to_string(),
+            )
+        };
+        let smpc_request = get_mock_request();
+
+        let result = smpc_request
+            .decrypt_iris_share(
+                seal_iris_codes_json(&iris_codes_json, &current_public_key()),
+                &KeyPairProvider::fixed(key_pair()),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
+        assert_eq!(result.unwrap(), iris_codes_json);
+        assert_eq!(
+            iris_codes_json
+                .validate_version(&SupportedIrisVersion::ALL)
+                .unwrap(),
+            SupportedIrisVersion::V1_0
+        );
+
+        // Unless the version is left out of the allow-list.
+        let result = smpc_request
+            .decrypt_iris_share(
+                seal_iris_codes_json(&iris_codes_json, &current_public_key()),
+                &KeyPairProvider::fixed(key_pair()),
+                &[SupportedIrisVersion::V1_1],
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::UnsupportedVersion { got, supported })
+                if got == "1.0" && supported == [SupportedIrisVersion::V1_1]
+        ));
+    }
+
+    #[test]
+    fn test_validate_version_share_length() {
+        let current = SupportedIrisVersion::V1_1;
+        assert_ne!(current.share_len(), SupportedIrisVersion::V1_0.share_len());
+        assert_eq!(
+            sized_iris_codes_json("1.1", current.share_len())
+                .validate_version(&SupportedIrisVersion::ALL)
+                .unwrap(),
+            current
+        );
+
+        // Shares of the legacy length under the current version.
+        let mut iris_codes_json = sized_iris_codes_json("1.1", current.share_len());
+        iris_codes_json.right_mask_code_shares =
+            STANDARD.encode(vec![0; SupportedIrisVersion::V1_0.share_len()]);
+        assert!(matches!(
+            iris_codes_json.validate_version(&SupportedIrisVersion::ALL),
+            Err(SharesDecodingError::WrongShareLength { version, expected, got })
+                if version == current
+                    && expected == current.share_len()
+                    && got == SupportedIrisVersion::V1_0.share_len()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_iris_share_future_version() {
+        let iris_codes_json = sized_iris_codes_json("1.2", SupportedIrisVersion::V1_1.share_len());
+        let key_pair = get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        );
+        let smpc_request = get_mock_request();
+
+        let result = smpc_request
+            .decrypt_iris_share(
+                seal_iris_codes_json(&iris_codes_json, &current_public_key()),
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
+        let Err(error) = result else {
+            panic!("A future version should be rejected");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Unsupported iris version \"1.2\", supported are 1.0, 1.1"
+        );
+    }
+
+    /// A current key pair and `n` previous ones, with their public keys.
+    fn key_chain(n: usize) -> (SharesEncryptionKeyPairs, Vec<PublicKey>) {
+        let current_key_pair = SharesEncryptionKeyPair::generate();
+        let previous_key_pairs: Vec<_> = (0..n)
+            .map(|_| PreviousKeyPair {
+                key_pair:    SharesEncryptionKeyPair::generate(),
+                valid_until: None,
+            })
+            .collect();
+        let public_keys = std::iter::once(&current_key_pair)
+            .chain(previous_key_pairs.iter().map(|previous| &previous.key_pair))
+            .map(|key_pair| *key_pair.public_key())
+            .collect();
+        let key_pairs = SharesEncryptionKeyPairs {
+            current_key_pair,
+            previous_key_pairs,
+            generation: None,
+        };
+        (key_pairs, public_keys)
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_iris_share_with_third_oldest_key() {
+        let iris_codes_json = sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len());
+        // Four rotations, the share is sealed to the third oldest key, the
+        // current one before the last two rotations.
+        let (key_pairs, public_keys) = key_chain(4);
+        let sealed = seal_iris_codes_json(&iris_codes_json, &public_keys[2]);
+        let smpc_request = get_mock_request();
+
+        let result = smpc_request
+            .decrypt_iris_share(
+                sealed.clone(),
+                &KeyPairProvider::fixed(key_pairs.clone()),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
+        assert_eq!(result.unwrap(), iris_codes_json);
+        let (_, key_index) = key_pairs
+            .open_sealed_box(&STANDARD.decode(&sealed).unwrap(), 0)
+            .unwrap();
+        assert_eq!(key_index, 2);
+
+        // Not once the key has expired.
+        let mut expired = key_pairs.clone();
+        expired.previous_key_pairs[1].valid_until = Some(1_700_000_000);
+        assert!(expired
+            .open_sealed_box(&STANDARD.decode(&sealed).unwrap(), 1_700_000_000)
+            .is_ok());
+        let result = smpc_request
+            .decrypt_iris_share(
+                sealed,
+                &KeyPairProvider::fixed(expired),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::SealedBoxOpenError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_iris_share_all_keys_fail() {
+        let iris_codes_json = sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len());
+        let (key_pairs, _) = key_chain(3);
+        let other_key_pair = SharesEncryptionKeyPair::generate();
+        let sealed = seal_iris_codes_json(&iris_codes_json, other_key_pair.public_key());
+
+        let result = get_mock_request()
+            .decrypt_iris_share(
+                sealed,
+                &KeyPairProvider::fixed(key_pairs),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::SealedBoxOpenError)
+        ));
+    }
+
+    /// A secrets manager whose keys the test rotates.
+    struct RotatingKeySource(Arc<Mutex<SharesEncryptionKeyPairs>>);
+
+    #[async_trait]
+    impl KeyPairSource for RotatingKeySource {
+        async fn load(&self) -> Result<SharesEncryptionKeyPairs, SharesDecodingError> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    /// Rotates the keys of `secret`: the current key pair becomes the newest
+    /// previous one. Returns the public key of the new key pair.
+    fn rotate(secret: &Mutex<SharesEncryptionKeyPairs>) -> PublicKey {
+        let mut secret = secret.lock().unwrap();
+        let new_key_pair = SharesEncryptionKeyPair::generate();
+        let public_key = *new_key_pair.public_key();
+        let old_key_pair = std::mem::replace(&mut secret.current_key_pair, new_key_pair);
+        secret.previous_key_pairs.insert(0, PreviousKeyPair {
+            key_pair:    old_key_pair,
+            valid_until: None,
+        });
+        public_key
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_key_rotation_without_restart() {
+        let share = sized_iris_codes_json("1.1", SupportedIrisVersion::V1_1.share_len());
+        let (key_pairs, public_keys) = key_chain(0);
+        let secret = Arc::new(Mutex::new(key_pairs));
+        let config = KeyRefreshConfig {
+            interval_secs:         60,
+            on_decryption_failure: true,
+            min_interval_secs:     10,
+        };
+        let provider = Arc::new(
+            KeyPairProvider::new(RotatingKeySource(Arc::clone(&secret)), config)
+                .await
+                .unwrap(),
+        );
+        let decrypt = |sealed: &String| {
+            let provider = Arc::clone(&provider);
+            let sealed = sealed.clone();
+            async move {
+                get_mock_request()
+                    .decrypt_iris_share(sealed, &provider, &SupportedIrisVersion::ALL)
+                    .await
+            }
+        };
+        let first = seal_iris_codes_json(&share, &public_keys[0]);
+        assert_eq!(decrypt(&first).await.unwrap(), share);
+
+        // Right after a reload, a share sealed to an unknown key does not
+        // reload the keys again.
+        let second = seal_iris_codes_json(&share, &rotate(&secret));
+        assert!(matches!(
+            decrypt(&second).await,
+            Err(SharesDecodingError::SealedBoxOpenError)
+        ));
+
+        // Later it does, and shares sealed to the old key still open.
+        tokio::time::sleep(config.min_interval()).await;
+        assert_eq!(decrypt(&second).await.unwrap(), share);
+        assert_eq!(decrypt(&first).await.unwrap(), share);
+
+        // The periodic reload picks up a rotation on its own.
+        let refresh = tokio::spawn({
+            let provider = Arc::clone(&provider);
+            async move { provider.refresh_every(config.interval().unwrap()).await }
+        });
+        let third_public_key = rotate(&secret);
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        refresh.abort();
+        let key_pairs = provider.key_pairs();
+        assert_eq!(key_pairs.current_key_pair.public_key(), &third_public_key);
+        assert_eq!(key_pairs.previous_key_pairs.len(), 2);
+        for sealed in [first, second] {
+            assert_eq!(decrypt(&sealed).await.unwrap(), share);
+        }
+    }
+
+    #[test]
+    fn test_key_chain_json() {
+        let secret = json!({
+            "current": CURRENT_PRIVATE_KEY,
+            "previous": [
+                { "private_key": PREVIOUS_PRIVATE_KEY, "valid_until": 1_700_000_000 },
+                { "private_key": CURRENT_PRIVATE_KEY },
+            ],
+            "generation": 3,
+        })
+        .to_string();
+        let key_pairs = SharesEncryptionKeyPairs::from_key_chain_json(&secret).unwrap();
+        assert_eq!(
+            STANDARD.encode(key_pairs.current_key_pair.public_key()),
+            CURRENT_PUBLIC_KEY
+        );
+        assert_eq!(key_pairs.previous_key_pairs.len(), 2);
+        assert_eq!(
+            STANDARD.encode(key_pairs.previous_key_pairs[0].key_pair.public_key()),
+            PREVIOUS_PUBLIC_KEY
+        );
+        assert_eq!(
+            key_pairs.previous_key_pairs[0].valid_until,
+            Some(1_700_000_000)
+        );
+        assert_eq!(key_pairs.previous_key_pairs[1].valid_until, None);
+        assert_eq!(key_pairs.generation, Some(3));
+
+        // The legacy layout holds a single previous key without an expiry.
+        let legacy = get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        );
+        assert_eq!(legacy.previous_key_pairs.len(), 1);
+        assert_eq!(legacy.previous_key_pairs[0].valid_until, None);
+        assert_eq!(legacy.generation, None);
+        let without_previous = get_key_pairs(CURRENT_PRIVATE_KEY.to_string(), String::new());
+        assert!(without_previous.previous_key_pairs.is_empty());
+
+        assert!(matches!(
+            SharesEncryptionKeyPairs::from_key_chain_json(r#"{"previous": []}"#),
+            Err(SharesDecodingError::SerdeError(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_decrypt_iris_share_invalid_base64() {
         let invalid_base64 = "InvalidBase64String";
@@ -216,7 +794,13 @@ mod tests {
         );
         let smpc_request = get_mock_request();
 
-        let result = smpc_request.decrypt_iris_share(invalid_base64.to_string(), key_pair);
+        let result = smpc_request
+            .decrypt_iris_share(
+                invalid_base64.to_string(),
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
 
         assert!(matches!(
             result,
@@ -239,7 +823,13 @@ mod tests {
         );
         let smpc_request = get_mock_request();
 
-        let result = smpc_request.decrypt_iris_share(encoded_share, key_pair);
+        let result = smpc_request
+            .decrypt_iris_share(
+                encoded_share,
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
 
         assert!(matches!(
             result,
@@ -262,7 +852,13 @@ mod tests {
         );
         let smpc_request = get_mock_request();
 
-        let result = smpc_request.decrypt_iris_share(encoded_share, key_pair);
+        let result = smpc_request
+            .decrypt_iris_share(
+                encoded_share,
+                &KeyPairProvider::fixed(key_pair),
+                &SupportedIrisVersion::ALL,
+            )
+            .await;
 
         assert!(matches!(result, Err(SharesDecodingError::SerdeError(_))));
     }
@@ -279,11 +875,38 @@ mod tests {
             "dummy_hash_2".to_string(),
         ]);
 
-        let is_valid = smpc_request
-            .validate_iris_share(0, mock_iris_codes_json)
-            .unwrap();
+        assert!(
+            smpc_request
+                .validate_iris_share(0, mock_iris_codes_json)
+                .is_ok(),
+            "The iris share should be valid"
+        );
+    }
 
-        assert!(is_valid, "The iris share should be valid");
+    #[test]
+    fn test_canonical_json() {
+        let share = mock_iris_codes_json();
+        let json = share.canonical_json();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"IRIS_version":"1.0","IRIS_shares_version":"1.3","#,
+                r#""left_iris_code_shares":"bGVmdF9pcmlzX2NvZGVfbW9jaw==","#,
+                r#""right_iris_code_shares":"cmlnaHRfaXJpc19jb2RlX21vY2s=","#,
+                r#""left_mask_code_shares":"bGVmdF9pcmlzX21hc2tfbW9jaw==","#,
+                r#""right_mask_code_shares":"cmlnaHRfaXJpc19tYXNrX21vY2s="}"#,
+            )
+        );
+        // The uploads of the clients hash the serde serialization.
+        assert_eq!(json, serde_json::to_string(&share).unwrap());
+        assert_eq!(
+            calculate_sha256(&json),
+            "f39348eee50ddf587249b0c7c30eb952937e2e1aad0f63e93e0efe7e24c23371"
+        );
+        assert_eq!(
+            hex::encode(share.canonical_json_sha256()),
+            "f39348eee50ddf587249b0c7c30eb952937e2e1aad0f63e93e0efe7e24c23371"
+        );
     }
 
     #[tokio::test]
@@ -299,11 +922,774 @@ mod tests {
         ]);
 
         // Act
-        let is_valid = smpc_request
-            .validate_iris_share(0, mock_iris_codes_json)
-            .unwrap();
+        let result = smpc_request.validate_iris_share(0, mock_iris_codes_json.clone());
 
         // Assert
-        assert!(!is_valid, "The iris share should be invalid");
+        let Err(mismatch) = result else {
+            panic!("The iris share should be invalid");
+        };
+        assert_eq!(mismatch.party_id, 0);
+        assert_eq!(mismatch.expected, "incorrect_hash_value");
+        assert_eq!(
+            mismatch.computed,
+            calculate_sha256(serde_json::to_string(&mock_iris_codes_json).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_rotation_window() {
+        let mut smpc_request = get_mock_request();
+        assert_eq!(smpc_request.rotation_window(15), 15);
+
+        smpc_request.rotation_window = Some(4);
+        assert_eq!(smpc_request.rotation_window(15), 4);
+        assert_eq!(smpc_request.rotation_window(2), 2);
+
+        // Requests of older clients come without the hint.
+        let json = json!({
+            "batch_size": null,
+            "signup_id": "test_signup_id",
+            "s3_key": "package",
+            "iris_shares_file_hashes": ["hash_0", "hash_1", "hash_2"],
+            "mirrored_check": null
+        });
+        let smpc_request: UniquenessRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(smpc_request.rotation_window, None);
+    }
+
+    #[test]
+    fn test_or_rule_indices() {
+        let mut smpc_request = get_mock_request();
+        assert_eq!(smpc_request.or_rule_indices(), None);
+
+        smpc_request.or_rule_serial_ids = Some(vec![7, 3, 7, 1]);
+        assert_eq!(smpc_request.or_rule_indices(), Some(vec![0, 2, 6]));
+        smpc_request.or_rule_serial_ids = Some(vec![]);
+        assert_eq!(smpc_request.or_rule_indices(), Some(vec![]));
+
+        // Requests of older clients come without the field.
+        let json = json!({
+            "batch_size": null,
+            "signup_id": "test_signup_id",
+            "s3_key": "package",
+            "iris_shares_file_hashes": ["hash_0", "hash_1", "hash_2"],
+            "mirrored_check": null
+        });
+        let smpc_request: UniquenessRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(smpc_request.or_rule_serial_ids, None);
+    }
+
+    #[tokio::test]
+    async fn test_reauth_request() {
+        let mock_server = MockServer::start().await;
+        let response_body = json!({
+            "iris_share_0": "share_0_data",
+            "iris_share_1": "share_1_data",
+            "iris_share_2": "share_2_data"
+        });
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(response_body.to_string()))
+            .mount(&mock_server)
+            .await;
+
+        let json = json!({
+            "reauth_id": "test_reauth_id",
+            "s3_presigned_url": format!("{}/shares?X-Amz-Signature=mock", mock_server.uri()),
+            "iris_shares_file_hashes": ["hash_0", "hash_1", "hash_2"],
+            "target_serial_ids": [1, 42, 0],
+            "batch_size": null
+        });
+        let smpc_request: ReAuthRequest = serde_json::from_value(json).unwrap();
+        // Serial id 0 does not exist, it maps beyond any DB.
+        assert_eq!(smpc_request.target_indices(), vec![0, 41, u32::MAX]);
+
+        let result = smpc_request
+            .get_iris_data_by_party_id(
+                2,
+                &unused_s3_client(),
+                &reqwest::Client::new(),
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), "share_2_data".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_reauth_request_expired_url() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(403).set_body_string("Request has expired"))
+            .mount(&mock_server)
+            .await;
+
+        let smpc_request = ReAuthRequest {
+            reauth_id:               "test_reauth_id".to_string(),
+            s3_presigned_url:        Some(mock_server.uri()),
+            s3_bucket:               None,
+            s3_key:                  None,
+            iris_shares_file_hashes: [
+                "hash_0".to_string(),
+                "hash_1".to_string(),
+                "hash_2".to_string(),
+            ],
+            target_serial_ids:       vec![1],
+            batch_size:              None,
+        };
+        let result = smpc_request
+            .get_iris_data_by_party_id(
+                0,
+                &unused_s3_client(),
+                &reqwest::Client::new(),
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::ResponseContent { status, .. }) if status == 403
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reset_requests() {
+        let mock_server = MockServer::start().await;
+        let response_body = json!({
+            "iris_share_0": "share_0_data",
+            "iris_share_1": "share_1_data",
+            "iris_share_2": "share_2_data"
+        });
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(response_body.to_string()))
+            .mount(&mock_server)
+            .await;
+        let s3_presigned_url = format!("{}/shares?X-Amz-Signature=mock", mock_server.uri());
+
+        let reset_check: ResetCheckRequest = serde_json::from_value(json!({
+            "reset_id": "test_reset_id",
+            "s3_presigned_url": s3_presigned_url,
+            "iris_shares_file_hashes": ["hash_0", "hash_1", "hash_2"],
+        }))
+        .unwrap();
+        assert_eq!(reset_check.batch_size, None);
+        let result = reset_check
+            .get_iris_data_by_party_id(
+                1,
+                &unused_s3_client(),
+                &reqwest::Client::new(),
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), "share_1_data".to_string());
+
+        let reset_update: ResetUpdateRequest = serde_json::from_value(json!({
+            "serial_id": 42,
+            "s3_presigned_url": s3_presigned_url,
+            "iris_shares_file_hashes": ["hash_0", "hash_1", "hash_2"],
+        }))
+        .unwrap();
+        assert_eq!(reset_update.index(), 41);
+        let result = reset_update
+            .get_iris_data_by_party_id(
+                0,
+                &unused_s3_client(),
+                &reqwest::Client::new(),
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), "share_0_data".to_string());
+    }
+
+    #[tokio::test]
+    async fn test_share_source() {
+        let response_body = json!({
+            "iris_share_0": "share_0_data",
+            "iris_share_1": "share_1_data",
+            "iris_share_2": "share_2_data"
+        });
+        let (mock_server, s3_client) = mock_s3_client(response_body.to_string()).await;
+
+        let reset_check: ResetCheckRequest = serde_json::from_value(json!({
+            "reset_id": "test_reset_id",
+            "s3_bucket": "bobTheBucket",
+            "s3_key": "kateTheKey",
+            "iris_shares_file_hashes": ["hash_0", "hash_1", "hash_2"],
+        }))
+        .unwrap();
+        assert_eq!(reset_check.share_source().unwrap(), ShareSource::S3Object {
+            bucket: "bobTheBucket",
+            key:    "kateTheKey",
+        });
+        // Read from the bucket, not from a presigned URL.
+        let result = reset_check
+            .get_iris_data_by_party_id(
+                1,
+                &s3_client,
+                &reqwest::Client::new(),
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), "share_1_data".to_string());
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].url.path(), "/bobTheBucket/kateTheKey");
+
+        // Exactly one of a URL and a bucket with a key.
+        for (url, bucket, key) in [
+            (Some("url"), Some("bucket"), Some("key")),
+            (Some("url"), None, Some("key")),
+            (None, Some("bucket"), None),
+            (None, None, Some("key")),
+            (None, None, None),
+        ] {
+            assert!(matches!(
+                ShareSource::from_fields(url, bucket, key),
+                Err(SharesDecodingError::InvalidShareSource)
+            ));
+        }
+        let mut reauth = reauth_request(mock_server.uri());
+        reauth.s3_bucket = Some("bobTheBucket".to_string());
+        reauth.s3_key = Some("kateTheKey".to_string());
+        let result = reauth
+            .get_iris_data_by_party_id(
+                0,
+                &s3_client,
+                &reqwest::Client::new(),
+                &ShareDownloadConfig::default(),
+            )
+            .await;
+        assert_eq!(
+            result.unwrap_err().error_code(),
+            ErrorCode::InvalidShareSource
+        );
+        // Nothing is downloaded.
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_inline_uniqueness_request() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let public_key =
+            PublicKey::from_slice(&STANDARD.decode(CURRENT_PUBLIC_KEY.as_bytes()).unwrap())
+                .unwrap();
+        let shares = IrisCodesJSON::share_irises(
+            &IrisCode::random_rng(&mut rng),
+            &IrisCode::random_rng(&mut rng),
+            &mut rng,
+        );
+        let (object, hashes) = SharesS3Object::seal(&shares, [&public_key; 3]);
+        let key_pairs = KeyPairProvider::fixed(get_key_pairs(
+            CURRENT_PRIVATE_KEY.to_string(),
+            PREVIOUS_PRIVATE_KEY.to_string(),
+        ));
+        let bucket_name = "bobTheBucket".to_string();
+
+        // Both schemas parse, and the inline one carries no key.
+        let inline: UniquenessRequest = serde_json::from_value(json!({
+            "batch_size": null,
+            "signup_id": "test_signup_id",
+            "iris_shares": object,
+            "iris_shares_file_hashes": hashes,
+            "mirrored_check": null
+        }))
+        .unwrap();
+        assert_eq!(
+            inline.share_source(&bucket_name).unwrap(),
+            ShareSource::Inline(&object)
+        );
+        let json = serde_json::to_value(&inline).unwrap();
+        assert!(json.get("s3_key").is_none());
+        let by_key = get_mock_smpc_request_with_hashes(hashes.clone());
+        assert_eq!(
+            by_key.share_source(&bucket_name).unwrap(),
+            ShareSource::S3Object {
+                bucket: "bobTheBucket",
+                key:    "mock",
+            }
+        );
+        let json = serde_json::to_value(&by_key).unwrap();
+        assert!(json.get("iris_shares").is_none());
+
+        // The inline shares resolve like downloaded ones, without S3.
+        let request = BatchRequest::Uniqueness(inline.clone());
+        for party_id in 0..3 {
+            let share = request
+                .resolve_shares(
+                    party_id,
+                    &bucket_name,
+                    &unused_s3_client(),
+                    &reqwest::Client::new(),
+                    &ShareDownloadConfig::default(),
+                    &key_pairs,
+                    &SupportedIrisVersion::ALL,
+                )
+                .await;
+            assert_eq!(share.unwrap(), shares[party_id]);
+        }
+
+        // Exactly one of a key and the inline shares.
+        let mut both = inline.clone();
+        both.s3_key = "mock".to_string();
+        let mut neither = inline;
+        neither.iris_shares = None;
+        for request in [both, neither] {
+            assert!(matches!(
+                request.share_source(&bucket_name),
+                Err(SharesDecodingError::InvalidShareSource)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_reset_update_hash_mismatch() {
+        let mock_iris_codes_json = mock_iris_codes_json();
+        let mock_serialized_iris = serde_json::to_string(&mock_iris_codes_json).unwrap();
+        let mock_hash = calculate_sha256(mock_serialized_iris.into_bytes());
+
+        let mut reset_update = ResetUpdateRequest {
+            serial_id:               1,
+            s3_presigned_url:        Some("mock".to_string()),
+            s3_bucket:               None,
+            s3_key:                  None,
+            iris_shares_file_hashes: [
+                "dummy_hash_0".to_string(),
+                mock_hash,
+                "dummy_hash_2".to_string(),
+            ],
+        };
+        // The hash of another party does not validate the share.
+        assert!(reset_update
+            .validate_iris_share(0, mock_iris_codes_json.clone())
+            .is_err());
+        assert!(reset_update
+            .validate_iris_share(1, mock_iris_codes_json.clone())
+            .is_ok());
+        reset_update.iris_shares_file_hashes[1] = "incorrect_hash_value".to_string();
+        assert!(reset_update
+            .validate_iris_share(1, mock_iris_codes_json)
+            .is_err());
+    }
+
+    #[test]
+    fn test_identity_deletion_batch_request() {
+        let request =
+            IdentityDeletionBatchRequest::parse(r#"{"serial_ids": [7, 3, 7, 1]}"#).unwrap();
+        assert_eq!(request.serial_ids, vec![1, 3, 7]);
+
+        let serial_ids = (1..=MAX_IDENTITY_DELETION_BATCH_SIZE as u32).collect::<Vec<_>>();
+        let message = json!({ "serial_ids": serial_ids }).to_string();
+        assert!(IdentityDeletionBatchRequest::parse(&message).is_ok());
+
+        let serial_ids = (0..=MAX_IDENTITY_DELETION_BATCH_SIZE as u32).collect::<Vec<_>>();
+        let message = json!({ "serial_ids": serial_ids }).to_string();
+        assert!(matches!(
+            IdentityDeletionBatchRequest::parse(&message),
+            Err(IdentityDeletionBatchError::TooManySerialIds { count, max })
+                if count == MAX_IDENTITY_DELETION_BATCH_SIZE + 1
+                    && max == MAX_IDENTITY_DELETION_BATCH_SIZE
+        ));
+        assert!(matches!(
+            IdentityDeletionBatchRequest::parse(r#"{"serial_id": 1}"#),
+            Err(IdentityDeletionBatchError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_incoming_body() {
+        let request = json!({ "serial_id": 7 }).to_string();
+        let envelope = json!({
+            "Type": "Notification",
+            "MessageId": "message-id",
+            "SequenceNumber": "1",
+            "TopicArn": "arn:aws:sns:eu-north-1:000000000000:requests.fifo",
+            "Message": request,
+            "Timestamp": "2024-01-01T00:00:00.000Z",
+            "UnsubscribeURL": "",
+            "MessageAttributes": {
+                "message_type": { "Type": "String", "Value": "identity_deletion" }
+            }
+        })
+        .to_string();
+        let (envelope, message) = parse_incoming_body(&envelope).unwrap();
+        let envelope = envelope.unwrap();
+        assert_eq!(message, request);
+        assert_eq!(envelope.message_id, "message-id");
+        assert_eq!(
+            envelope.message_attributes["message_type"].string_value(),
+            Some("identity_deletion")
+        );
+
+        // Raw message delivery.
+        let (envelope, message) = parse_incoming_body(&request).unwrap();
+        assert!(envelope.is_none());
+        assert_eq!(message, request);
+
+        // A raw message is kept as an envelope with the SQS attributes.
+        let attributes = create_message_type_attribute_map("identity_deletion");
+        let raw = SQSMessage::from_raw("sqs-id".to_string(), request.clone(), attributes);
+        let (envelope, message) =
+            parse_incoming_body(&serde_json::to_string(&raw).unwrap()).unwrap();
+        let envelope = envelope.unwrap();
+        assert_eq!(message, request);
+        assert_eq!(envelope.message_id, "sqs-id");
+        assert_eq!(
+            envelope.message_attributes["message_type"].string_value(),
+            Some("identity_deletion")
+        );
+
+        assert!(matches!(
+            parse_incoming_body("not json"),
+            Err(ReceiveRequestError::JsonParseError { json_name, .. }) if json_name == "SQS body"
+        ));
+        // An envelope without its required fields is not taken for a raw message.
+        assert!(matches!(
+            parse_incoming_body(r#"{"Type": "Notification", "Message": "{}"}"#),
+            Err(ReceiveRequestError::JsonParseError { json_name, .. })
+                if json_name == "SNS envelope"
+        ));
+    }
+
+    fn request_payload(kind: RequestKind) -> serde_json::Value {
+        let hashes = ["hash_0", "hash_1", "hash_2"];
+        match kind {
+            RequestKind::Uniqueness => json!({
+                "batch_size": null,
+                "signup_id": "signup_id",
+                "s3_key": "s3_key",
+                "iris_shares_file_hashes": hashes,
+                "mirrored_check": null,
+            }),
+            RequestKind::ReAuth => json!({
+                "reauth_id": "reauth_id",
+                "s3_presigned_url": "url",
+                "iris_shares_file_hashes": hashes,
+                "target_serial_ids": [1],
+            }),
+            RequestKind::ResetCheck => json!({
+                "reset_id": "reset_id",
+                "s3_presigned_url": "url",
+                "iris_shares_file_hashes": hashes,
+            }),
+            RequestKind::ResetUpdate => json!({
+                "serial_id": 1,
+                "s3_presigned_url": "url",
+                "iris_shares_file_hashes": hashes,
+            }),
+            RequestKind::IdentityDeletion | RequestKind::IdentityRestore => {
+                json!({ "serial_id": 1 })
+            }
+            RequestKind::IdentityDeletionBatch => json!({ "serial_ids": [3, 1] }),
+            RequestKind::CircuitBreaker => json!({ "batch_size": 8 }),
+            RequestKind::ThresholdUpdate => json!({
+                "params": { "version": 1, "match_threshold_ratio": 0.3 },
+                "activation_batch": 10,
+                "signature": "",
+            }),
+        }
+    }
+
+    #[test]
+    fn test_request_kinds() {
+        for &kind in RequestKind::ALL {
+            assert_eq!(
+                RequestKind::from_message_type(kind.message_type()),
+                Some(kind)
+            );
+
+            let mut attributes = create_message_type_attribute_map(kind.message_type());
+            assert_eq!(RequestKind::from_attributes(&attributes).unwrap(), kind);
+            attributes.insert(
+                SMPC_SCHEMA_VERSION_ATTRIBUTE.to_string(),
+                string_attribute(REQUEST_SCHEMA_VERSION),
+            );
+            assert_eq!(RequestKind::from_attributes(&attributes).unwrap(), kind);
+
+            let request = ParsedRequest::parse(kind, &request_payload(kind).to_string()).unwrap();
+            assert_eq!(request.kind(), kind);
+            assert!(matches!(
+                ParsedRequest::parse(kind, "[]"),
+                Err(ReceiveRequestError::JsonParseError { .. })
+            ));
+        }
+        assert_eq!(
+            RequestKind::from_message_type(UNIQUENESS_MESSAGE_TYPE),
+            Some(RequestKind::Uniqueness)
+        );
+        let request = ParsedRequest::parse(
+            RequestKind::IdentityDeletionBatch,
+            r#"{"serial_ids": [3, 1, 3]}"#,
+        );
+        let Ok(ParsedRequest::IdentityDeletionBatch(request)) = request else {
+            panic!("expected an identity deletion batch");
+        };
+        assert_eq!(request.validate().unwrap().serial_ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_request_kind_errors() {
+        assert!(matches!(
+            RequestKind::from_attributes(&HashMap::new()),
+            Err(ReceiveRequestError::NoMessageTypeAttribute)
+        ));
+
+        let binary = MessageAttributeValue::builder()
+            .data_type("Binary")
+            .binary_value(Blob::new(UNIQUENESS_MESSAGE_TYPE))
+            .build()
+            .unwrap();
+        let attributes = HashMap::from([(SMPC_MESSAGE_TYPE_ATTRIBUTE.to_string(), binary)]);
+        assert!(matches!(
+            RequestKind::from_attributes(&attributes),
+            Err(ReceiveRequestError::NoStringMessageTypeAttribute)
+        ));
+
+        let attributes = create_message_type_attribute_map("unknown");
+        assert!(matches!(
+            RequestKind::from_attributes(&attributes),
+            Err(ReceiveRequestError::InvalidMessageType)
+        ));
+
+        let mut attributes = create_message_type_attribute_map(REAUTH_MESSAGE_TYPE);
+        attributes.insert(
+            SMPC_SCHEMA_VERSION_ATTRIBUTE.to_string(),
+            string_attribute("2"),
+        );
+        let error = RequestKind::from_attributes(&attributes).unwrap_err();
+        assert_eq!(error.error_code(), ErrorCode::UnsupportedSchemaVersion);
+        assert!(matches!(
+            error,
+            ReceiveRequestError::UnsupportedSchemaVersion {
+                kind: RequestKind::ReAuth,
+                version,
+            } if version == "2"
+        ));
+    }
+
+    fn string_attribute(value: &str) -> MessageAttributeValue {
+        MessageAttributeValue::builder()
+            .data_type("String")
+            .string_value(value)
+            .build()
+            .unwrap()
+    }
+
+    fn fast_retries() -> ShareDownloadConfig {
+        ShareDownloadConfig {
+            retry: DownloadRetryConfig {
+                max_attempts:     3,
+                base_delay_ms:    1,
+                max_delay_ms:     1,
+                backoff:          DownloadBackoff::Fixed,
+                total_timeout_ms: 0,
+            },
+            ..ShareDownloadConfig::default()
+        }
+    }
+
+    fn reauth_request(url: String) -> ReAuthRequest {
+        ReAuthRequest {
+            reauth_id:               "test_reauth_id".to_string(),
+            s3_presigned_url:        Some(url),
+            s3_bucket:               None,
+            s3_key:                  None,
+            iris_shares_file_hashes: [
+                "hash_0".to_string(),
+                "hash_1".to_string(),
+                "hash_2".to_string(),
+            ],
+            target_serial_ids:       vec![1],
+            batch_size:              None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_retries() {
+        let shares = json!({
+            "iris_share_0": "share_0_data",
+            "iris_share_1": "share_1_data",
+            "iris_share_2": "share_2_data"
+        });
+        for (status, attempts) in [(503u16, 3), (500, 3), (403, 1), (404, 1)] {
+            let mock_server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&mock_server)
+                .await;
+            let result = reauth_request(mock_server.uri())
+                .get_iris_data_by_party_id(
+                    0,
+                    &unused_s3_client(),
+                    &reqwest::Client::new(),
+                    &fast_retries(),
+                )
+                .await;
+            assert!(matches!(
+                result,
+                Err(SharesDecodingError::ResponseContent { status: s, .. }) if s == status
+            ));
+            let received = mock_server.received_requests().await.unwrap();
+            assert_eq!(received.len(), attempts, "status {}", status);
+        }
+
+        // A transient failure passes on the next attempt.
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(shares.to_string()))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&mock_server)
+            .await;
+        let result = reauth_request(mock_server.uri())
+            .get_iris_data_by_party_id(
+                1,
+                &unused_s3_client(),
+                &reqwest::Client::new(),
+                &fast_retries(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), "share_1_data");
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+
+        // The same holds for the downloads from S3.
+        for (status, attempts) in [(503u16, 3), (404, 1)] {
+            let (mock_server, s3_client) = mock_s3_client_with(ResponseTemplate::new(status)).await;
+            let result = get_mock_request()
+                .get_iris_data_by_party_id(
+                    0,
+                    &"bobTheBucket".to_string(),
+                    &s3_client,
+                    &fast_retries(),
+                )
+                .await;
+            assert!(matches!(
+                result,
+                Err(SharesDecodingError::S3ResponseContent { .. })
+            ));
+            let received = mock_server.received_requests().await.unwrap();
+            assert_eq!(received.len(), attempts, "status {}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+        let download = ShareDownloadConfig {
+            retry: DownloadRetryConfig {
+                max_attempts: 100,
+                base_delay_ms: 50,
+                max_delay_ms: 50,
+                total_timeout_ms: 100,
+                ..fast_retries().retry
+            },
+            ..ShareDownloadConfig::default()
+        };
+        let result = reauth_request(mock_server.uri())
+            .get_iris_data_by_party_id(0, &unused_s3_client(), &reqwest::Client::new(), &download)
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::DownloadTimeout(_))
+        ));
+    }
+
+    /// Serves every connection with `head`, then `body`, and then keeps the
+    /// connection open without sending anything.
+    async fn serve_raw(head: &'static str, body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let _ = socket.read(&mut request).await;
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    socket.write_all(&body).await.unwrap();
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                });
+            }
+        });
+        format!("http://{}/shares", address)
+    }
+
+    #[tokio::test]
+    async fn test_download_too_large() {
+        let download = ShareDownloadConfig {
+            max_response_bytes: 1000,
+            ..fast_retries()
+        };
+
+        // Announced by the Content-Length header.
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("x".repeat(1001)))
+            .mount(&mock_server)
+            .await;
+        let result = reauth_request(mock_server.uri())
+            .get_iris_data_by_party_id(
+                0,
+                &unused_s3_client(),
+                &download.http_client().unwrap(),
+                &download,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::ResponseTooLarge { max_bytes: 1000 })
+        ));
+        // A file that is too large is not downloaded again.
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+
+        // Streamed without a length.
+        let chunk = format!("258\r\n{}\r\n", "x".repeat(600));
+        let url = serve_raw(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            format!("{chunk}{chunk}0\r\n\r\n").into_bytes(),
+        )
+        .await;
+        let result = reauth_request(url)
+            .get_iris_data_by_party_id(
+                0,
+                &unused_s3_client(),
+                &download.http_client().unwrap(),
+                &download,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::ResponseTooLarge { max_bytes: 1000 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_download_read_timeout() {
+        let download = ShareDownloadConfig {
+            read_timeout_ms: 100,
+            retry: DownloadRetryConfig::no_retries(),
+            ..ShareDownloadConfig::default()
+        };
+        // The server stops after the headers.
+        let url = serve_raw("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n", vec![]).await;
+        let started = std::time::Instant::now();
+        let result = reauth_request(url)
+            .get_iris_data_by_party_id(
+                0,
+                &unused_s3_client(),
+                &download.http_client().unwrap(),
+                &download,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SharesDecodingError::RequestError(e)) if e.is_timeout()
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
     }
 }
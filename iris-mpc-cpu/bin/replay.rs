@@ -0,0 +1,57 @@
+use clap::Parser;
+use eyre::{bail, Context};
+use iris_mpc_common::helpers::replay::{decode_secret_key, ReplayBundle};
+use iris_mpc_cpu::replay::replay;
+use std::path::PathBuf;
+
+/// Replays the batches recorded by the three parties and reports the first
+/// decision that differs from the recording.
+#[derive(Parser)]
+struct Args {
+    /// Directories holding the recorded batches, a party's batches may be in
+    /// any of them.
+    #[clap(required = true)]
+    recording_dirs: Vec<PathBuf>,
+
+    /// File holding the base64 encoded secret debugging key.
+    #[clap(long)]
+    secret_key_file: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let args = Args::parse();
+    let secret_key = std::fs::read_to_string(&args.secret_key_file)
+        .wrap_err("Failed to read the secret debugging key")?;
+    let secret_key = decode_secret_key(&secret_key)?;
+
+    let mut bundles = vec![];
+    for party_id in 0..3 {
+        let Some(bundle) = args
+            .recording_dirs
+            .iter()
+            .find_map(|dir| ReplayBundle::load_dir(dir, party_id, &secret_key).ok())
+        else {
+            bail!("No readable recording of party {}", party_id);
+        };
+        bundles.push(bundle);
+    }
+
+    let report = replay(&bundles).await?;
+    match report.divergence {
+        None => println!("Replayed {} batches without divergence", report.batches),
+        Some(divergence) => {
+            println!(
+                "Batch {} diverges at party {}, decision {} (request {})",
+                divergence.batch_id,
+                divergence.party_id,
+                divergence.index,
+                divergence.request_id.as_deref().unwrap_or("-")
+            );
+            println!("  recorded: {:?}", divergence.recorded);
+            println!("  replayed: {:?}", divergence.replayed);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Runs the three parties of the matcher in a single process, using only
+//! `iris_mpc_cpu::prelude`.
+//!
+//! Each party builds its own HNSW graph over its shares of a random database,
+//! then all parties search for a noisy copy of an enrolled iris and for an
+//! unrelated one.
+
+use iris_mpc_cpu::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+use tokio::task::JoinSet;
+
+const DATABASE_SIZE: usize = 20;
+
+fn main() -> eyre::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    rt.block_on(async move {
+        let mut rng = StdRng::seed_from_u64(0);
+        let database: Vec<IrisCode> = (0..DATABASE_SIZE)
+            .map(|_| IrisCode::random_rng(&mut rng))
+            .collect();
+        let enrolled = database[DATABASE_SIZE / 2].get_similar_iris(&mut rng);
+        let unknown = IrisCode::random_rng(&mut rng);
+
+        // Every party only ever sees its own share of each iris.
+        let shared_database: Vec<Vec<GaloisRingSharedIris>> = database
+            .into_iter()
+            .map(|iris| generate_galois_iris_shares(&mut rng, iris))
+            .collect();
+        let shared_queries = [
+            generate_galois_iris_shares(&mut rng, enrolled),
+            generate_galois_iris_shares(&mut rng, unknown),
+        ];
+
+        let stores = setup_local_store_aby3_players(NetworkType::LocalChannel).await?;
+        let mut parties = JoinSet::new();
+        for mut store in stores {
+            let party = store.get_owner_index();
+            let entries: Vec<_> = shared_database
+                .iter()
+                .map(|shares| store.prepare_query(shares[party].clone()))
+                .collect();
+            let queries: Vec<_> = shared_queries
+                .iter()
+                .map(|shares| store.prepare_query(shares[party].clone()))
+                .collect();
+            let mut rng = StdRng::seed_from_u64(party as u64);
+            parties.spawn(async move {
+                let searcher = HawkSearcher::default();
+                let mut graph = GraphMem::new();
+                for entry in entries.iter() {
+                    searcher
+                        .insert(&mut store, &mut graph, entry, &mut rng)
+                        .await;
+                }
+                let mut results = vec![];
+                for query in queries.iter() {
+                    let neighbors = searcher.search(&mut store, &mut graph, query, 1).await;
+                    results.push(searcher.is_match(&mut store, &[neighbors]).await);
+                }
+                (party, results)
+            });
+        }
+
+        for (party, results) in parties.join_all().await {
+            println!(
+                "party {}: enrolled iris matched: {}, unknown iris matched: {}",
+                party, results[0], results[1]
+            );
+            assert_eq!(results, vec![true, false]);
+        }
+        Ok(())
+    })
+}
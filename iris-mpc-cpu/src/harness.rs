@@ -0,0 +1,3214 @@
+//! In-process three-party harness for end-to-end tests of the uniqueness
+//! pipeline.
+//!
+//! All three parties run in one process on top of the CPU protocol and the
+//! local channel network. Requests are delivered through channel-backed
+//! stand-ins for the SQS queues and results are collected from channel-backed
+//! stand-ins for the SNS topic, so tests can drive the request→match→result
+//! flow and assert on both the emitted results and the per-party databases.
+//! The parties receive, hold and settle the requests with the
+//! [`RequestIntake`] of the server.
+
+use crate::{
+    database_generators::{generate_galois_iris_shares, GaloisRingSharedIris},
+    execution::{
+        local::LocalRuntime,
+        session::{Session, SessionHandles},
+    },
+    protocol::{
+        binary::{and_many, open_bin},
+        ops::{
+            batch_signed_lift_vec, compare_threshold_and_open, compare_threshold_many,
+            galois_ring_pairwise_distance, galois_ring_to_rep3, open_u16, or_many, or_tree_many,
+            secure_popcount,
+        },
+    },
+    shares::{share::DistanceShare, vecshare::VecShare},
+};
+use aes_prng::AesRng;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::{bail, eyre};
+use iris_mpc_common::{
+    config::{Config, MatchPolicy, ResultMode},
+    errors::{ErrorCode, HasErrorCode},
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    helpers::{
+        audit::{AuditBatch, AuditDecision, AuditHash, AuditRecord},
+        backfill::{backfill_signup_id, BackfillEntry, BackfillResponse},
+        canary::CanaryTarget,
+        intake::{
+            publish_deletion_batch_not_agreed, publish_request_not_agreed, verify_threshold_update,
+            BatchRequest, PendingRequest, ReceivedRequest, ReceivedRequests, RequestIntake,
+        },
+        priority_lanes::RequestLane,
+        queue::{
+            ChannelRequestReceiver, ChannelRequestSender, ChannelResultPublisher,
+            PoisonMessagePolicy, PublishedMessage, ResultPublisher,
+        },
+        reference,
+        replay::{
+            RecordedBatch, RecordedRequest, RecordedSettings, RecordedShares, ReplayBundle,
+            BACKFILL_MESSAGE_TYPE,
+        },
+        share_audit::{
+            find_inconsistent, report_audit, ShareAuditChallenge, ShareAuditContribution,
+        },
+        smpc_request::{
+            IdentityDeletionBatchRequest, IdentityDeletionRequest, IdentityRestoreRequest,
+            SQSMessage, UniquenessRequest, IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            IDENTITY_DELETION_MESSAGE_TYPE, IDENTITY_RESTORE_MESSAGE_TYPE,
+            THRESHOLD_UPDATE_MESSAGE_TYPE, UNIQUENESS_MESSAGE_TYPE,
+        },
+        smpc_response::{
+            create_message_type_attribute_map, IdentityDeletionBatchResult, IdentityDeletionResult,
+            IdentityRestoreResult, UniquenessResult, SMPC_MESSAGE_TYPE_ATTRIBUTE,
+        },
+        soft_delete::SoftDeletions,
+        sync::{
+            agreed_deletion_batches, agreed_purges, batch_key, compose_batch, or_rule_key,
+            BatchCandidate, BatchDecision, BatchSyncState, MAX_DELETION_BATCHES,
+        },
+        threshold::{
+            check_agreement, MatchThreshold, ThresholdParams, ThresholdSchedule,
+            ThresholdSyncState, ThresholdUpdateRequest,
+        },
+    },
+    id::PartyID,
+    iris_db::iris::{IrisCode, IrisCodeArray},
+};
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tokio::task::{spawn_blocking, JoinSet};
+
+/// In-memory replacement for the S3 bucket holding the encrypted shares. Maps
+/// the `s3_key` of a request to the left and right shares of every party, or
+/// to `None` where the download fails.
+type ShareStore =
+    Arc<Mutex<HashMap<String, Vec<Option<(GaloisRingSharedIris, GaloisRingSharedIris)>>>>>;
+
+/// Number of backfill entries a party holds until their batch, the default of
+/// `backfill_queue_size` in the server config.
+const BACKFILL_QUEUE_SIZE: usize = 1024;
+
+/// A result published by one of the parties.
+#[derive(Debug, Clone)]
+pub enum ResultEvent {
+    Uniqueness(UniquenessResult),
+    IdentityDeletion(IdentityDeletionResult),
+    IdentityDeletionBatch(IdentityDeletionBatchResult),
+    IdentityRestore(IdentityRestoreResult),
+}
+
+impl ResultEvent {
+    pub fn from_published(published: &PublishedMessage) -> eyre::Result<Self> {
+        let message_type = published
+            .message_attributes
+            .get(SMPC_MESSAGE_TYPE_ATTRIBUTE)
+            .and_then(|attribute| attribute.string_value())
+            .ok_or_else(|| eyre!("Published message without message type"))?;
+        match message_type {
+            UNIQUENESS_MESSAGE_TYPE => Ok(ResultEvent::Uniqueness(serde_json::from_str(
+                &published.message,
+            )?)),
+            IDENTITY_DELETION_MESSAGE_TYPE => Ok(ResultEvent::IdentityDeletion(
+                serde_json::from_str(&published.message)?,
+            )),
+            IDENTITY_DELETION_BATCH_MESSAGE_TYPE => Ok(ResultEvent::IdentityDeletionBatch(
+                serde_json::from_str(&published.message)?,
+            )),
+            IDENTITY_RESTORE_MESSAGE_TYPE => Ok(ResultEvent::IdentityRestore(
+                serde_json::from_str(&published.message)?,
+            )),
+            other => bail!("Unexpected result message type: {}", other),
+        }
+    }
+
+    pub fn node_id(&self) -> usize {
+        match self {
+            ResultEvent::Uniqueness(result) => result.node_id,
+            ResultEvent::IdentityDeletion(result) => result.node_id,
+            ResultEvent::IdentityDeletionBatch(result) => result.node_id,
+            ResultEvent::IdentityRestore(result) => result.node_id,
+        }
+    }
+
+    /// Returns the event as JSON with the party specific `node_id` removed, so
+    /// that events of different parties can be compared.
+    fn without_node_id(&self) -> serde_json::Value {
+        let mut value = match self {
+            ResultEvent::Uniqueness(result) => serde_json::to_value(result),
+            ResultEvent::IdentityDeletion(result) => serde_json::to_value(result),
+            ResultEvent::IdentityDeletionBatch(result) => serde_json::to_value(result),
+            ResultEvent::IdentityRestore(result) => serde_json::to_value(result),
+        }
+        .expect("results serialize to JSON");
+        value
+            .as_object_mut()
+            .expect("results serialize to JSON objects")
+            .remove("node_id");
+        value
+    }
+}
+
+/// The iris database held by a single party.
+#[derive(Debug, Clone, Default)]
+pub struct PartyDb {
+    pub left:       Vec<GaloisRingSharedIris>,
+    pub right:      Vec<GaloisRingSharedIris>,
+    /// The signup id every serial id was assigned to.
+    pub signup_ids: Vec<String>,
+}
+
+impl PartyDb {
+    fn push(&mut self, signup_id: String, left: GaloisRingSharedIris, right: GaloisRingSharedIris) {
+        self.left.push(left);
+        self.right.push(right);
+        self.signup_ids.push(signup_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.left.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty()
+    }
+}
+
+/// Shares written over deleted entries, see `get_dummy_shares_for_deletion` in
+/// the GPU actor.
+pub fn dummy_shares_for_deletion(party_id: usize) -> GaloisRingSharedIris {
+    let mut rng = StdRng::seed_from_u64(0);
+    let dummy = IrisCode::default();
+    let code = GaloisRingIrisCodeShare::encode_iris_code(&dummy.code, &dummy.mask, &mut rng)
+        [party_id]
+        .clone();
+    let mask = GaloisRingTrimmedMaskCodeShare::from(
+        &GaloisRingIrisCodeShare::encode_mask_code(&dummy.mask, &mut rng)[party_id],
+    );
+    GaloisRingSharedIris { code, mask }
+}
+
+/// Without a dead-letter queue in process, poison messages are only deleted.
+fn poison_message_policy(config: &Config) -> PoisonMessagePolicy {
+    PoisonMessagePolicy {
+        max_attempts: config.poison_message_max_attempts,
+        dead_letter:  None,
+    }
+}
+
+/// Settings of the share audit, see [`TestHarness::enable_share_audit`].
+#[derive(Debug, Clone)]
+struct ShareAuditSettings {
+    seed:        [u8; 32],
+    sample_size: usize,
+    interval:    u64,
+}
+
+/// A request of the batch a party composed, see [`Party::process_batch`].
+struct BatchEntry {
+    pending: PendingRequest,
+    request: UniquenessRequest,
+    lane:    RequestLane,
+    /// The shares of the request, boxed to keep the batch future small.
+    shares:  Option<Box<(GaloisRingSharedIris, GaloisRingSharedIris)>>,
+    /// Why the request cannot be processed, at this or another party.
+    error:   Option<ErrorCode>,
+}
+
+/// A request the parties agreed on, as processed in a batch.
+struct AgreedRequest {
+    request_id:   String,
+    message_type: String,
+    message:      String,
+    shares:       Option<Box<(GaloisRingSharedIris, GaloisRingSharedIris)>>,
+    error:        Option<ErrorCode>,
+}
+
+impl AgreedRequest {
+    /// A control request, like a deletion, by its SNS message id.
+    fn control<T: Serialize>(
+        message_id: String,
+        message_type: &str,
+        request: &T,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
+            request_id:   message_id,
+            message_type: message_type.to_string(),
+            message:      serde_json::to_string(request)?,
+            shares:       None,
+            error:        None,
+        })
+    }
+
+    fn record(&self) -> RecordedRequest {
+        RecordedRequest {
+            request_id:   self.request_id.clone(),
+            message_type: self.message_type.clone(),
+            message:      Some(self.message.clone()),
+            shares:       self
+                .shares
+                .as_deref()
+                .map(|(left, right)| record_shares(left, right)),
+            error:        self.error,
+        }
+    }
+}
+
+fn record_shares(left: &GaloisRingSharedIris, right: &GaloisRingSharedIris) -> RecordedShares {
+    RecordedShares::new(&left.code, &left.mask, &right.code, &right.mask)
+}
+
+fn decode_recorded_shares(
+    shares: &RecordedShares,
+) -> eyre::Result<(GaloisRingSharedIris, GaloisRingSharedIris)> {
+    let ((left_code, left_mask), (right_code, right_mask)) = shares.decode()?;
+    Ok((
+        GaloisRingSharedIris {
+            code: left_code,
+            mask: left_mask,
+        },
+        GaloisRingSharedIris {
+            code: right_code,
+            mask: right_mask,
+        },
+    ))
+}
+
+/// The agreed requests, backfilled entries and purges of a recorded batch.
+type DecodedBatch = (
+    Vec<AgreedRequest>,
+    Vec<(String, GaloisRingSharedIris, GaloisRingSharedIris)>,
+    Vec<u32>,
+);
+
+fn decode_recorded_batch(recorded: &RecordedBatch) -> eyre::Result<DecodedBatch> {
+    let mut requests = vec![];
+    let mut backfilled = vec![];
+    for request in recorded.requests.iter() {
+        let shares = request
+            .shares
+            .as_ref()
+            .map(decode_recorded_shares)
+            .transpose()?;
+        if request.message_type == BACKFILL_MESSAGE_TYPE {
+            let (left, right) = shares.ok_or_else(|| eyre!("Backfilled entry without shares"))?;
+            backfilled.push((request.request_id.clone(), left, right));
+            continue;
+        }
+        requests.push(AgreedRequest {
+            request_id:   request.request_id.clone(),
+            message_type: request.message_type.clone(),
+            message:      request
+                .message
+                .clone()
+                .ok_or_else(|| eyre!("Recorded request without message"))?,
+            shares:       shares.map(Box::new),
+            error:        request.error,
+        });
+    }
+    Ok((requests, backfilled, recorded.purged_serial_ids.clone()))
+}
+
+struct PendingQuery {
+    signup_id:       String,
+    left:            GaloisRingSharedIris,
+    right:           GaloisRingSharedIris,
+    mirrored_check:  bool,
+    /// See `UniquenessRequest::rotation_window`.
+    rotation_window: usize,
+    /// See `UniquenessRequest::or_rule_indices`.
+    or_rule:         Option<Vec<u32>>,
+}
+
+impl PendingQuery {
+    /// The mirrored irises with swapped eyes, see `enable_mirrored_checks` in
+    /// the server config.
+    fn mirrored(&self) -> (GaloisRingSharedIris, GaloisRingSharedIris) {
+        let mirror = |iris: &GaloisRingSharedIris| GaloisRingSharedIris {
+            code: iris.code.mirrored(),
+            mask: iris.mask.mirrored(),
+        };
+        (mirror(&self.right), mirror(&self.left))
+    }
+}
+
+struct Party {
+    party_id: usize,
+    session: Session,
+    /// The request queue of the party, without a fixed lane.
+    queues: Vec<(ChannelRequestReceiver, Option<RequestLane>)>,
+    results: ChannelResultPublisher,
+    db: PartyDb,
+    /// The server config of the party. The harness settings change it, see
+    /// [`TestHarness::set_result_mode`] and the like.
+    config: Config,
+    /// The threshold the party started with, before any runtime change.
+    match_threshold: MatchThreshold,
+    /// Number of comparison bits opened so far.
+    opened_bits: usize,
+    threshold_schedule: ThresholdSchedule,
+    /// The threshold state the last batch was processed with.
+    threshold_state: ThresholdSyncState,
+    batch_counter: u64,
+    /// Backfilled entries that are not yet in a batch.
+    backfill: VecDeque<BackfillEntry>,
+    /// The requests received and not yet through their batch, see
+    /// [`RequestIntake`].
+    intake: RequestIntake,
+    /// Deletion batches not all parties held yet, offered again with the
+    /// next batch.
+    pending_deletion_batches: Vec<ReceivedRequest<IdentityDeletionBatchRequest>>,
+    /// The audit records of all batches, see
+    /// [`iris_mpc_common::helpers::audit`].
+    audit: Vec<AuditRecord>,
+    /// The recorded batches, if recording is enabled, see
+    /// [`iris_mpc_common::helpers::replay`].
+    recording: Option<ReplayBundle>,
+    /// The soft-deleted serial ids, whose shares are kept until they are
+    /// purged, see [`iris_mpc_common::helpers::soft_delete`].
+    soft_deletions: SoftDeletions,
+    /// The clock of the party in seconds, which decides when soft-deletes are
+    /// due. The parties' clocks can differ.
+    clock_secs: u64,
+}
+
+impl Party {
+    /// The party after a restart: the database, the threshold schedule and
+    /// the audit log are persisted, everything else starts over. The queue
+    /// stays, but the messages the party held are only received again once
+    /// they are visible, see [`TestHarness::restart_party`].
+    fn restart(self) -> Self {
+        Self {
+            opened_bits: 0,
+            backfill: VecDeque::new(),
+            intake: RequestIntake::new(&self.config, poison_message_policy(&self.config)),
+            pending_deletion_batches: vec![],
+            ..self
+        }
+    }
+
+    /// Runs one batch like the server: receives requests through the
+    /// [`RequestIntake`] until the batch is full or the queue is empty, agrees
+    /// on the batch with the other parties and settles its messages.
+    async fn process_batch(
+        &mut self,
+        batch_size: usize,
+        share_store: &ShareStore,
+    ) -> eyre::Result<()> {
+        let receivers = self
+            .queues
+            .iter()
+            .map(|(receiver, _)| receiver)
+            .collect::<Vec<_>>();
+        let mut received = ReceivedRequests::default();
+        self.intake.keep_alive(&receivers).await;
+        while self.intake.len() < batch_size
+            && self
+                .intake
+                .poll(
+                    &self.queues,
+                    &self.results,
+                    &self.config,
+                    &[],
+                    &mut received,
+                )
+                .await?
+        {}
+        if !received.reset_updates.is_empty() {
+            bail!("The harness does not run reset updates");
+        }
+        let entries = self.intake.compose(batch_size, &receivers).await?;
+
+        let mut batch = vec![];
+        for entry in entries {
+            let BatchRequest::Uniqueness(request) = &entry.item.request else {
+                bail!(
+                    "The harness only runs uniqueness requests, got {}",
+                    entry.item.request.message_type()
+                );
+            };
+            let shares = share_store
+                .lock()
+                .unwrap()
+                .get(&request.s3_key)
+                .and_then(|shares| shares[self.party_id].clone().map(Box::new));
+            batch.push(BatchEntry {
+                request: request.clone(),
+                pending: entry.item,
+                lane: entry.lane,
+                error: shares.is_none().then_some(ErrorCode::ShareDownloadFailed),
+                shares,
+            });
+        }
+
+        // The parties agree on the batch like the batch sync of the GPU actor:
+        // the batch is made of the requests all parties hold, in the order of
+        // their keys, and of the deletion batches all parties hold.
+        batch.sort_by_key(|entry| batch_key(&entry.request.signup_id));
+        self.pending_deletion_batches
+            .append(&mut received.deletion_batches);
+        let offered = self
+            .pending_deletion_batches
+            .len()
+            .min(MAX_DELETION_BATCHES);
+        let deletion_batches = self
+            .pending_deletion_batches
+            .drain(..offered)
+            .collect::<Vec<_>>();
+        let mut own = BatchSyncState::new(
+            batch
+                .iter()
+                .map(|entry| BatchCandidate {
+                    key:            batch_key(&entry.request.signup_id),
+                    mirrored_check: false,
+                    lane:           entry.lane as u8,
+                    error:          entry.error.map(ErrorCode::as_u16),
+                    shed:           false,
+                    or_rule:        entry
+                        .request
+                        .or_rule_indices()
+                        .map(|indices| or_rule_key(&indices)),
+                })
+                .collect(),
+        );
+        own.purge_due = self.soft_deletions.due(self.clock_secs);
+        own.deletion_batches = deletion_batches
+            .iter()
+            .map(|deletion_batch| batch_key(&deletion_batch.message_id))
+            .collect();
+        let states = self.exchange(&(self.party_id, own.clone())).await?;
+        let mut states = states.into_iter().collect::<BTreeMap<_, _>>();
+        states.insert(self.party_id, own.clone());
+        let states = states.into_values().collect::<Vec<_>>();
+        let purges = agreed_purges(&states);
+        let agreed_deletion_batches = agreed_deletion_batches(&states);
+        let (applied_deletion_batches, deferred_deletion_batches): (Vec<_>, Vec<_>) =
+            deletion_batches.into_iter().partition(|deletion_batch| {
+                agreed_deletion_batches.contains(&batch_key(&deletion_batch.message_id))
+            });
+        let applied_message_ids = applied_deletion_batches
+            .iter()
+            .map(|deletion_batch| deletion_batch.message_id.clone())
+            .collect::<Vec<_>>();
+
+        // Like in the server, the other control requests are applied by every
+        // party as it receives them.
+        let mut requests = vec![];
+        for deletion in received.deletions {
+            requests.push(AgreedRequest::control(
+                deletion.message_id,
+                IDENTITY_DELETION_MESSAGE_TYPE,
+                &deletion.request,
+            )?);
+        }
+        for deletion_batch in applied_deletion_batches {
+            requests.push(AgreedRequest::control(
+                deletion_batch.message_id,
+                IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+                &deletion_batch.request,
+            )?);
+        }
+        for restore in received.restores {
+            requests.push(AgreedRequest::control(
+                restore.message_id,
+                IDENTITY_RESTORE_MESSAGE_TYPE,
+                &restore.request,
+            )?);
+        }
+        for threshold_update in received.threshold_updates {
+            requests.push(AgreedRequest::control(
+                threshold_update.message_id,
+                THRESHOLD_UPDATE_MESSAGE_TYPE,
+                &threshold_update.request,
+            )?);
+        }
+
+        let decisions = compose_batch(&own, &states);
+        // The decisions on the requests all parties hold are the same
+        // everywhere, so the parties agree on whether to take backfill.
+        let take_backfill = !decisions
+            .iter()
+            .any(|decision| matches!(decision, BatchDecision::Process | BatchDecision::Fail(_)));
+        let mut committed = vec![];
+        let mut shed_request_ids = vec![];
+        let mut deferred_request_ids = vec![];
+        for (entry, decision) in batch.into_iter().zip(decisions) {
+            committed.push(entry.pending.committed());
+            let request_id = entry.request.signup_id.clone();
+            match decision {
+                BatchDecision::Process | BatchDecision::Fail(_) => {
+                    let error = match decision {
+                        BatchDecision::Fail(code) => {
+                            Some(ErrorCode::from_u16(code).unwrap_or(ErrorCode::Internal))
+                        }
+                        _ => entry.error,
+                    };
+                    requests.push(AgreedRequest {
+                        request_id,
+                        message_type: UNIQUENESS_MESSAGE_TYPE.to_string(),
+                        message: serde_json::to_string(&entry.request)?,
+                        shares: entry.shares,
+                        error,
+                    });
+                }
+                BatchDecision::Defer => deferred_request_ids.push(request_id),
+                BatchDecision::Shed => shed_request_ids.push(request_id),
+            }
+        }
+
+        // Like in the server, backfilled entries are only taken while no
+        // requests are in the batch.
+        let backfilled = if take_backfill {
+            self.take_backfill(batch_size).await?
+        } else {
+            vec![]
+        };
+
+        let recorded_requests = self.recording.is_some().then(|| {
+            requests
+                .iter()
+                .map(AgreedRequest::record)
+                .chain(
+                    backfilled
+                        .iter()
+                        .map(|(signup_id, left, right)| RecordedRequest {
+                            request_id:   signup_id.clone(),
+                            message_type: BACKFILL_MESSAGE_TYPE.to_string(),
+                            message:      None,
+                            shares:       Some(record_shares(left, right)),
+                            error:        None,
+                        }),
+                )
+                .collect::<Vec<_>>()
+        });
+        // Boxed to keep the batch future small, it holds the shares of a batch.
+        let batch = Box::pin(self.process_agreed(requests, backfilled, purges.clone())).await?;
+        if let (Some(recording), Some(requests)) = (self.recording.as_mut(), recorded_requests) {
+            recording.batches.push(RecordedBatch {
+                batch_id: batch.batch_id,
+                requests,
+                decisions: batch.decisions,
+                deleted_serial_ids: batch.deleted_serial_ids,
+                purged_serial_ids: purges,
+                db_digest_before: batch.db_digest_before,
+                db_digest_after: batch.db_digest_after,
+            });
+        }
+
+        let receivers = self
+            .queues
+            .iter()
+            .map(|(receiver, _)| receiver)
+            .collect::<Vec<_>>();
+        let settled = self
+            .intake
+            .settle(
+                &committed,
+                &shed_request_ids,
+                &deferred_request_ids,
+                &receivers,
+            )
+            .await?;
+        self.intake.complete(&committed, &receivers).await?;
+        let (deferred_deletion_batches, dropped_deletion_batches) =
+            self.intake.defer_deletion_batches(
+                applied_message_ids.iter().map(String::as_str),
+                deferred_deletion_batches,
+                |deletion_batch| deletion_batch.message_id.as_str(),
+            );
+        self.pending_deletion_batches
+            .splice(0..0, deferred_deletion_batches);
+        for deletion_batch in dropped_deletion_batches {
+            publish_deletion_batch_not_agreed(
+                &deletion_batch.message_id,
+                deletion_batch.request.serial_ids,
+                deletion_batch.trace_context.as_ref(),
+                &self.results,
+                &self.config,
+            )
+            .await?;
+        }
+        for request_id in settled.dropped_request_ids {
+            publish_request_not_agreed(request_id, &self.results, &self.config).await?;
+        }
+        Ok(())
+    }
+
+    /// Processes a recorded batch again, with the batch id of the recording.
+    /// The results are published like in [`Self::process_batch`].
+    async fn replay_batch(
+        &mut self,
+        batch_id: u64,
+        (requests, backfilled, purges): DecodedBatch,
+    ) -> eyre::Result<AuditBatch> {
+        self.batch_counter = batch_id;
+        Box::pin(self.process_agreed(requests, backfilled, purges)).await
+    }
+
+    /// Processes the requests and backfilled entries the parties agreed on,
+    /// purges the agreed soft-deletes and writes the audit record of the
+    /// batch.
+    async fn process_agreed(
+        &mut self,
+        requests: Vec<AgreedRequest>,
+        backfilled: Vec<(String, GaloisRingSharedIris, GaloisRingSharedIris)>,
+        purges: Vec<u32>,
+    ) -> eyre::Result<AuditBatch> {
+        let mut queries = vec![];
+        let mut deletions = vec![];
+        let mut deletion_batches = vec![];
+        let mut restores = vec![];
+        let mut failed = vec![];
+        let db_digest_before = self.db_digest();
+        for request in requests {
+            match request.message_type.as_str() {
+                IDENTITY_DELETION_MESSAGE_TYPE => {
+                    let deletion: IdentityDeletionRequest = serde_json::from_str(&request.message)?;
+                    deletions.push(deletion);
+                }
+                IDENTITY_DELETION_BATCH_MESSAGE_TYPE => {
+                    deletion_batches.push(IdentityDeletionBatchRequest::parse(&request.message));
+                }
+                IDENTITY_RESTORE_MESSAGE_TYPE => {
+                    let restore: IdentityRestoreRequest = serde_json::from_str(&request.message)?;
+                    restores.push(restore.serial_id);
+                }
+                UNIQUENESS_MESSAGE_TYPE => {
+                    let uniqueness: UniquenessRequest = serde_json::from_str(&request.message)?;
+                    match (request.shares, request.error) {
+                        (Some(shares), None) => {
+                            let (left, right) = *shares;
+                            queries.push(PendingQuery {
+                                signup_id: uniqueness.signup_id,
+                                left,
+                                right,
+                                mirrored_check: self.config.enable_mirrored_checks
+                                    && uniqueness.mirrored_check.unwrap_or(false),
+                                rotation_window: uniqueness
+                                    .rotation_window(self.config.max_rotation_window),
+                                or_rule: uniqueness.or_rule_indices(),
+                            });
+                        }
+                        (_, error) => failed
+                            .push((uniqueness.signup_id, error.unwrap_or(ErrorCode::Internal))),
+                    }
+                }
+                THRESHOLD_UPDATE_MESSAGE_TYPE => {
+                    let request: ThresholdUpdateRequest = serde_json::from_str(&request.message)?;
+                    let scheduled =
+                        verify_threshold_update(&self.config, &request).and_then(|update| {
+                            self.threshold_schedule.schedule(update, self.batch_counter)
+                        });
+                    if let Err(e) = scheduled {
+                        tracing::warn!(
+                            party_id = self.party_id,
+                            "Rejected threshold update: {}",
+                            e
+                        );
+                    }
+                }
+                other => bail!("Unexpected request message type: {}", other),
+            }
+        }
+
+        // Like in the GPU actor, the parameters switch between batches.
+        let batch_id = self.batch_counter;
+        self.threshold_state = self.threshold_schedule.sync_state(self.batch_counter);
+        let threshold = self.threshold_schedule.activate(self.batch_counter);
+        self.batch_counter += 1;
+
+        // Deletions are applied before the queries are matched, like in the GPU
+        // actor.
+        let mut deletion_results = vec![];
+        let mut deleted_serial_ids = vec![];
+        for deletion in deletions {
+            let serial_id = deletion.serial_id;
+            let index = (serial_id as usize)
+                .checked_sub(1)
+                .filter(|&index| index < self.db.len());
+            let success = index.is_some();
+            if let Some(index) = index {
+                // A soft-delete keeps the shares, the entry is tombstoned for
+                // matching until it is restored or purged.
+                match deletion.grace_period_secs {
+                    Some(grace_period_secs) => self
+                        .soft_deletions
+                        .soft_delete(serial_id, self.clock_secs + grace_period_secs),
+                    None => {
+                        self.purge(index);
+                        self.soft_deletions.purged(&[serial_id]);
+                    }
+                }
+                deleted_serial_ids.push(serial_id);
+            }
+            deletion_results.push(IdentityDeletionResult::new(
+                self.party_id,
+                serial_id,
+                success,
+            ));
+        }
+        // All parties agreed on the deletion batch as a whole, so they delete
+        // the same serial ids.
+        let mut deletion_batch_results = vec![];
+        for deletion_batch in deletion_batches {
+            let deletion_batch = match deletion_batch {
+                Ok(deletion_batch) => deletion_batch,
+                Err(e) => {
+                    deletion_batch_results.push(IdentityDeletionBatchResult::error(
+                        self.party_id,
+                        vec![],
+                        e.error_code(),
+                    ));
+                    continue;
+                }
+            };
+            let (found, not_found): (Vec<_>, Vec<_>) = deletion_batch
+                .serial_ids
+                .iter()
+                .copied()
+                .partition(|&serial_id| serial_id >= 1 && serial_id as usize <= self.db.len());
+            for &serial_id in found.iter() {
+                self.purge(serial_id as usize - 1);
+            }
+            self.soft_deletions.purged(&found);
+            deleted_serial_ids.extend(found.iter().copied());
+            deletion_batch_results.push(IdentityDeletionBatchResult::new(
+                self.party_id,
+                deletion_batch.serial_ids,
+                found,
+                not_found,
+            ));
+        }
+        let restore_results = restores
+            .into_iter()
+            .map(|serial_id| {
+                let success = self.soft_deletions.restore(serial_id);
+                IdentityRestoreResult::new(self.party_id, serial_id, success)
+            })
+            .collect::<Vec<_>>();
+        // The restores of this batch win over the purges agreed for it. The
+        // parties agreed on the restores, so they skip the same purges.
+        let purges = purges
+            .into_iter()
+            .filter(|&serial_id| self.soft_deletions.is_soft_deleted(serial_id))
+            .collect::<Vec<_>>();
+        for &serial_id in purges.iter() {
+            self.purge(serial_id as usize - 1);
+        }
+        self.soft_deletions.purged(&purges);
+
+        let mut uniqueness_results = vec![];
+        for (signup_id, error_code) in failed {
+            uniqueness_results.push(UniquenessResult::error(
+                self.party_id,
+                signup_id,
+                error_code,
+            ));
+        }
+        let mut decisions = vec![];
+        let mut insertions = vec![];
+        for (i, query) in queries.iter().enumerate() {
+            let db_len = self.db.len();
+            let to_serial_ids = |indices: &[usize]| {
+                indices
+                    .iter()
+                    .filter(|&&index| index < db_len)
+                    .map(|&index| index as u32 + 1)
+                    .collect::<Vec<_>>()
+            };
+            let to_batch_request_ids = |indices: &[usize]| {
+                indices
+                    .iter()
+                    .filter(|&&index| index >= db_len)
+                    .map(|&index| queries[index - db_len].signup_id.clone())
+                    .collect::<Vec<_>>()
+            };
+
+            let or_rule = self.or_rule_candidates(query);
+
+            let mut result = match self.config.result_mode {
+                ResultMode::FullOpen | ResultMode::WithDistances => {
+                    let matches = self.full_open_matches(&queries, i, &threshold).await?;
+                    let matched_serial_ids = to_serial_ids(&matches.both);
+                    let matched_batch_request_ids = to_batch_request_ids(&matches.both);
+                    // Matches of the OR-rule serial ids are reported, but do not
+                    // keep the query out of the database.
+                    let is_match = matches.both.iter().any(|index| !or_rule.contains(index));
+                    let mut result = UniquenessResult::new(
+                        self.party_id,
+                        None,
+                        is_match,
+                        query.signup_id.clone(),
+                        Some(matched_serial_ids.clone()),
+                        Some(to_serial_ids(&matches.left)),
+                        Some(to_serial_ids(&matches.right)),
+                        Some(matched_batch_request_ids),
+                    );
+                    result.matched_serial_ids_mirror =
+                        matches.mirrored.map(|mirrored| to_serial_ids(&mirrored));
+                    if self.config.result_mode == ResultMode::WithDistances {
+                        let distances = self.matched_distances(query, &matched_serial_ids).await?;
+                        result.set_matched_distances(distances);
+                    }
+                    result
+                }
+                ResultMode::CountOnly => {
+                    let (count, matches) = self
+                        .count_only_matches(&queries, i, &threshold, &or_rule)
+                        .await?;
+                    UniquenessResult::new(
+                        self.party_id,
+                        None,
+                        count > 0,
+                        query.signup_id.clone(),
+                        matches.as_deref().map(to_serial_ids),
+                        None,
+                        None,
+                        matches.as_deref().map(to_batch_request_ids),
+                    )
+                }
+            };
+            result.threshold_version = Some(threshold.version);
+            result.match_policy = Some(self.config.match_policy);
+
+            if !result.is_match {
+                insertions.push(i);
+                result.serial_id = Some((db_len + insertions.len()) as u32);
+            }
+            decisions.push(AuditDecision {
+                request_id:   query.signup_id.clone(),
+                request_hash: request_hash(&query.signup_id, &query.left, &query.right),
+                is_match:     result.is_match,
+                serial_id:    result.serial_id,
+            });
+            uniqueness_results.push(result);
+        }
+
+        for i in insertions {
+            let query = &queries[i];
+            self.db.push(
+                query.signup_id.clone(),
+                query.left.clone(),
+                query.right.clone(),
+            );
+        }
+
+        for (signup_id, left, right) in backfilled {
+            decisions.push(AuditDecision {
+                request_id:   signup_id.clone(),
+                request_hash: request_hash(&signup_id, &left, &right),
+                is_match:     false,
+                serial_id:    Some(self.db.len() as u32 + 1),
+            });
+            self.db.push(signup_id.clone(), left, right);
+            let mut result = UniquenessResult::new(
+                self.party_id,
+                Some(self.db.len() as u32),
+                false,
+                signup_id,
+                None,
+                None,
+                None,
+                None,
+            );
+            result.threshold_version = Some(threshold.version);
+            result.backfill = Some(true);
+            uniqueness_results.push(result);
+        }
+
+        let prev_hash = self
+            .audit
+            .last()
+            .map_or_else(AuditHash::zero, |record| record.hash.clone());
+        let batch = AuditBatch {
+            batch_id,
+            decisions,
+            deleted_serial_ids,
+            db_digest_before,
+            db_digest_after: self.db_digest(),
+        };
+        // The batch id stands in for the time.
+        let record = AuditRecord::new(
+            self.audit.len() as u64,
+            batch_id as i64,
+            self.party_id,
+            batch.clone(),
+            prev_hash,
+        );
+        self.audit.push(record);
+
+        for result in uniqueness_results {
+            self.results
+                .publish(
+                    serde_json::to_string(&result)?,
+                    create_message_type_attribute_map(UNIQUENESS_MESSAGE_TYPE),
+                )
+                .await?;
+        }
+        for result in deletion_results {
+            self.results
+                .publish(
+                    serde_json::to_string(&result)?,
+                    create_message_type_attribute_map(IDENTITY_DELETION_MESSAGE_TYPE),
+                )
+                .await?;
+        }
+        for result in deletion_batch_results {
+            self.results
+                .publish(
+                    serde_json::to_string(&result)?,
+                    create_message_type_attribute_map(IDENTITY_DELETION_BATCH_MESSAGE_TYPE),
+                )
+                .await?;
+        }
+        for result in restore_results {
+            self.results
+                .publish(
+                    serde_json::to_string(&result)?,
+                    create_message_type_attribute_map(IDENTITY_RESTORE_MESSAGE_TYPE),
+                )
+                .await?;
+        }
+        Ok(batch)
+    }
+
+    /// The candidates a match of the query against does not keep it out of
+    /// the database: its OR-rule serial ids, without the ones that are deleted
+    /// or beyond the database.
+    fn or_rule_candidates(&self, query: &PendingQuery) -> Vec<usize> {
+        let Some(or_rule) = &query.or_rule else {
+            return vec![];
+        };
+        let dummy = dummy_shares_for_deletion(self.party_id);
+        let (known, unknown): (Vec<_>, Vec<_>) = or_rule
+            .iter()
+            .map(|&index| index as usize)
+            .partition(|&index| {
+                index < self.db.len()
+                    && !self.soft_deletions.is_soft_deleted(index as u32 + 1)
+                    && self.db.left[index] != dummy
+            });
+        if !unknown.is_empty() {
+            tracing::warn!(
+                party_id = self.party_id,
+                "Ignoring OR-rule serial ids of {} that are deleted or unknown: {:?}",
+                query.signup_id,
+                unknown
+                    .iter()
+                    .map(|&index| (index as u32).wrapping_add(1))
+                    .collect::<Vec<_>>()
+            );
+        }
+        known
+    }
+
+    /// Overwrites the entry at `index` with the dummy shares of a deletion.
+    fn purge(&mut self, index: usize) {
+        let dummy = dummy_shares_for_deletion(self.party_id);
+        self.db.left[index] = dummy.clone();
+        self.db.right[index] = dummy;
+    }
+
+    /// Takes up to `batch_size` backfilled entries and agrees on them with the
+    /// other parties. An entry is kept only if all parties hold the same valid
+    /// entry at its position.
+    async fn take_backfill(
+        &mut self,
+        batch_size: usize,
+    ) -> eyre::Result<Vec<(String, GaloisRingSharedIris, GaloisRingSharedIris)>> {
+        let n_entries = batch_size.min(self.backfill.len());
+        let backfill = self.backfill.drain(..n_entries).collect::<Vec<_>>();
+        let party_id = self.party_id;
+        // Decoding takes a lot of stack in debug builds, the server decodes on
+        // blocking threads as well.
+        let entries = spawn_blocking(move || {
+            backfill
+                .into_iter()
+                .map(|entry| match entry.decode(party_id) {
+                    Ok(((left_code, left_mask), (right_code, right_mask))) => Some((
+                        entry.signup_id,
+                        GaloisRingSharedIris {
+                            code: left_code,
+                            mask: left_mask,
+                        },
+                        GaloisRingSharedIris {
+                            code: right_code,
+                            mask: right_mask,
+                        },
+                    )),
+                    Err(e) => {
+                        tracing::error!(
+                            party_id,
+                            "Failed to process backfill entry {}: {}",
+                            entry.signup_id,
+                            e
+                        );
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .await?;
+        let valid_ids = entries
+            .iter()
+            .map(|entry| entry.as_ref().map(|(signup_id, ..)| signup_id.clone()))
+            .collect::<Vec<_>>();
+
+        let others = self.exchange(&valid_ids).await?;
+
+        Ok(entries
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, _)| {
+                others
+                    .iter()
+                    .all(|ids| ids.get(i).is_some_and(|id| id == &valid_ids[i]))
+            })
+            .filter_map(|(_, entry)| entry)
+            .collect())
+    }
+
+    /// Sends `value` to the next and the previous party and returns theirs.
+    async fn exchange<T: Serialize + DeserializeOwned>(&self, value: &T) -> eyre::Result<Vec<T>> {
+        let payload = bincode::serialize(value)?;
+        let network = self.session.network().clone();
+        let sid = self.session.session_id();
+        let peers = [self.session.next_identity()?, self.session.prev_identity()?];
+        for peer in peers.iter() {
+            network.send(payload.clone(), peer, &sid).await?;
+        }
+        let mut others = vec![];
+        for peer in peers.iter() {
+            others.push(bincode::deserialize(&network.receive(peer, &sid).await?)?);
+        }
+        Ok(others)
+    }
+
+    /// Digest of the serial ids, the signup ids they were assigned to and
+    /// whether they were deleted or are soft-deleted. Unlike the shares, it is
+    /// the same at all parties.
+    fn db_digest(&self) -> AuditHash {
+        let dummy = dummy_shares_for_deletion(self.party_id);
+        let mut parts = vec![];
+        for (index, signup_id) in self.db.signup_ids.iter().enumerate() {
+            let state = if self.soft_deletions.is_soft_deleted(index as u32 + 1) {
+                2
+            } else {
+                (self.db.left[index] == dummy) as u8
+            };
+            parts.push((index as u64 + 1).to_le_bytes().to_vec());
+            parts.push(signup_id.as_bytes().to_vec());
+            parts.push(vec![state]);
+        }
+        AuditHash::of_parts(&parts)
+    }
+
+    /// Runs the share audit for the current batch counter, see
+    /// [`iris_mpc_common::helpers::share_audit`]. Returns the inconsistent
+    /// serial ids and the payload sent to the other parties.
+    async fn audit_shares(
+        &mut self,
+        seed: &[u8; 32],
+        sample_size: usize,
+    ) -> eyre::Result<(Vec<u32>, Vec<u8>)> {
+        let challenge = ShareAuditChallenge::new(seed, self.batch_counter);
+        let sample = challenge.sample(self.db.len(), sample_size);
+        let db = &self.db;
+        let prf = self.session.prf_as_mut();
+        let contribution = ShareAuditContribution::new(
+            &challenge,
+            PartyID::try_from(self.party_id)?,
+            sample.iter().map(|&serial_id| {
+                let index = serial_id as usize - 1;
+                let (left, right) = (&db.left[index], &db.right[index]);
+                (serial_id, vec![
+                    &left.code.coefs[..],
+                    &left.mask.coefs[..],
+                    &right.code.coefs[..],
+                    &right.mask.coefs[..],
+                ])
+            }),
+            || prf.gen_zero_share::<u16>().convert(),
+        );
+        let payload = bincode::serialize(&contribution)?;
+
+        let network = self.session.network().clone();
+        let sid = self.session.session_id();
+        let peers = [self.session.next_identity()?, self.session.prev_identity()?];
+        for peer in peers.iter() {
+            network.send(payload.clone(), peer, &sid).await?;
+        }
+        let mut contributions = vec![contribution];
+        for peer in peers.iter() {
+            contributions.push(bincode::deserialize(&network.receive(peer, &sid).await?)?);
+        }
+        let inconsistent = find_inconsistent(&contributions)?;
+        report_audit(sample.len(), &inconsistent);
+        Ok((inconsistent, payload))
+    }
+
+    /// Matches the i-th query against the database and the earlier queries of
+    /// the batch, opening every comparison bit.
+    async fn full_open_matches(
+        &mut self,
+        queries: &[PendingQuery],
+        i: usize,
+        threshold: &ThresholdParams,
+    ) -> eyre::Result<OpenedMatches> {
+        let query = &queries[i];
+        let tombstone = dummy_shares_for_deletion(self.party_id);
+        let left_candidates = candidates(
+            &self.db.left,
+            &self.soft_deletions,
+            &tombstone,
+            queries[..i].iter().map(|q| &q.left),
+        );
+        let right_candidates = candidates(
+            &self.db.right,
+            &self.soft_deletions,
+            &tombstone,
+            queries[..i].iter().map(|q| &q.right),
+        );
+        let session = &mut self.session;
+        let mut opened_bits = 0;
+
+        let left = matching_indices(
+            session,
+            &left_candidates,
+            &query.left,
+            query.rotation_window,
+            threshold,
+            &mut opened_bits,
+        )
+        .await?;
+        let right = matching_indices(
+            session,
+            &right_candidates,
+            &query.right,
+            query.rotation_window,
+            threshold,
+            &mut opened_bits,
+        )
+        .await?;
+        let policy = self.config.match_policy;
+        let combine = |left: &[usize], right: &[usize]| {
+            (0..left_candidates.len())
+                .filter(|index| policy.combine(left.contains(index), right.contains(index)))
+                .collect::<Vec<_>>()
+        };
+        let mut both = combine(&left, &right);
+
+        let mirrored = if query.mirrored_check {
+            let (mirrored_left, mirrored_right) = query.mirrored();
+            let left = matching_indices(
+                session,
+                &left_candidates,
+                &mirrored_left,
+                query.rotation_window,
+                threshold,
+                &mut opened_bits,
+            )
+            .await?;
+            let right = matching_indices(
+                session,
+                &right_candidates,
+                &mirrored_right,
+                query.rotation_window,
+                threshold,
+                &mut opened_bits,
+            )
+            .await?;
+            let mirrored = combine(&left, &right);
+            for &index in mirrored.iter() {
+                if !both.contains(&index) {
+                    both.push(index);
+                }
+            }
+            Some(mirrored)
+        } else {
+            None
+        };
+
+        self.opened_bits += opened_bits;
+        Ok(OpenedMatches {
+            left,
+            right,
+            both,
+            mirrored,
+        })
+    }
+
+    /// Opens the distances between the query and the matched database
+    /// entries, see [`ResultMode::WithDistances`]. The distance of an eye is
+    /// the smallest over the rotations and, with the mirrored check, over the
+    /// mirrored query.
+    async fn matched_distances(
+        &mut self,
+        query: &PendingQuery,
+        matched_serial_ids: &[u32],
+    ) -> eyre::Result<Vec<f64>> {
+        let mirrored = query.mirrored_check.then(|| query.mirrored());
+        let session = &mut self.session;
+        let mut distances = Vec::with_capacity(matched_serial_ids.len());
+        for &serial_id in matched_serial_ids {
+            let index = serial_id as usize - 1;
+            let (entry_left, entry_right) = (&self.db.left[index], &self.db.right[index]);
+            let mut queries = vec![(&query.left, &query.right)];
+            queries.extend(mirrored.as_ref().map(|(left, right)| (left, right)));
+            let mut distance: Option<f64> = None;
+            for (query_left, query_right) in queries {
+                let window = query.rotation_window;
+                let left = min_distance(session, entry_left, query_left, window).await?;
+                let right = min_distance(session, entry_right, query_right, window).await?;
+                let combined = match (left, right) {
+                    (Some(left), Some(right)) => {
+                        Some(self.config.match_policy.combine_distances(left, right))
+                    }
+                    (one, None) | (None, one) => one,
+                };
+                distance = match (distance, combined) {
+                    (Some(distance), Some(combined)) => Some(distance.min(combined)),
+                    (one, None) | (None, one) => one,
+                };
+            }
+            distances.push(distance.ok_or_else(|| {
+                eyre!("Matched entry {} without common unmasked bits", serial_id)
+            })?);
+        }
+        Ok(distances)
+    }
+
+    /// Matches the i-th query like [`Party::full_open_matches`], but only
+    /// opens the number of matching candidates, without the `or_rule` ones.
+    /// The matching candidates are opened as well if
+    /// `reveal_matched_serial_ids` is set.
+    async fn count_only_matches(
+        &mut self,
+        queries: &[PendingQuery],
+        i: usize,
+        threshold: &ThresholdParams,
+        or_rule: &[usize],
+    ) -> eyre::Result<(u16, Option<Vec<usize>>)> {
+        let query = &queries[i];
+        let tombstone = dummy_shares_for_deletion(self.party_id);
+        let left_candidates = candidates(
+            &self.db.left,
+            &self.soft_deletions,
+            &tombstone,
+            queries[..i].iter().map(|q| &q.left),
+        );
+        let right_candidates = candidates(
+            &self.db.right,
+            &self.soft_deletions,
+            &tombstone,
+            queries[..i].iter().map(|q| &q.right),
+        );
+        let n_candidates = left_candidates.len();
+        let session = &mut self.session;
+        if n_candidates == 0 {
+            return Ok((0, self.config.reveal_matched_serial_ids.then(Vec::new)));
+        }
+
+        let left = match_bits(
+            session,
+            &left_candidates,
+            &query.left,
+            query.rotation_window,
+            threshold,
+        )
+        .await?;
+        let right = match_bits(
+            session,
+            &right_candidates,
+            &query.right,
+            query.rotation_window,
+            threshold,
+        )
+        .await?;
+        let mut bits = combine_bits(session, self.config.match_policy, left, right).await?;
+        if query.mirrored_check {
+            let (mirrored_left, mirrored_right) = query.mirrored();
+            let left = match_bits(
+                session,
+                &left_candidates,
+                &mirrored_left,
+                query.rotation_window,
+                threshold,
+            )
+            .await?;
+            let right = match_bits(
+                session,
+                &right_candidates,
+                &mirrored_right,
+                query.rotation_window,
+                threshold,
+            )
+            .await?;
+            let mirrored = combine_bits(session, self.config.match_policy, left, right).await?;
+            bits = or_many(session, bits, mirrored).await?;
+        }
+
+        // The candidates are public, so the bits of the OR-rule ones are
+        // cleared at every party without interaction.
+        let mut counted = bits.clone();
+        for &index in or_rule {
+            let word = &mut counted.shares[index / 64];
+            *word = word.clone() & !(1u64 << (index % 64));
+        }
+        let count = secure_popcount(session, counted, n_candidates).await?;
+        let count = open_u16(session, count).await?;
+
+        let matches = if self.config.reveal_matched_serial_ids {
+            let mut bits = bits.convert_to_bits();
+            bits.truncate(n_candidates);
+            let mut matches = vec![];
+            for (index, bit) in bits.into_iter().enumerate() {
+                if open_bin(session, bit).await?.convert() {
+                    matches.push(index);
+                }
+            }
+            self.opened_bits += n_candidates;
+            Some(matches)
+        } else {
+            None
+        };
+        Ok((count, matches))
+    }
+}
+
+/// The candidates of a query for one eye: the database, with the dummy shares
+/// of a deletion in place of the soft-deleted entries, followed by the earlier
+/// queries of the batch.
+fn candidates<'a>(
+    db: &'a [GaloisRingSharedIris],
+    soft_deletions: &SoftDeletions,
+    tombstone: &'a GaloisRingSharedIris,
+    earlier: impl Iterator<Item = &'a GaloisRingSharedIris>,
+) -> Vec<&'a GaloisRingSharedIris> {
+    db.iter()
+        .enumerate()
+        .map(|(index, iris)| {
+            if soft_deletions.is_soft_deleted(index as u32 + 1) {
+                tombstone
+            } else {
+                iris
+            }
+        })
+        .chain(earlier)
+        .collect()
+}
+
+/// Combines the secret-shared match bits of the two eyes under `policy`.
+async fn combine_bits(
+    session: &mut Session,
+    policy: MatchPolicy,
+    left: VecShare<u64>,
+    right: VecShare<u64>,
+) -> eyre::Result<VecShare<u64>> {
+    match policy {
+        MatchPolicy::And => and_many(session, left.as_slice(), right.as_slice()).await,
+        MatchPolicy::Or => or_many(session, left, right).await,
+    }
+}
+
+/// Hash of a request as received by a party, see [`AuditDecision`].
+fn request_hash(
+    signup_id: &str,
+    left: &GaloisRingSharedIris,
+    right: &GaloisRingSharedIris,
+) -> AuditHash {
+    AuditHash::of_parts(&[
+        signup_id.as_bytes(),
+        bytemuck::cast_slice(&left.code.coefs[..]),
+        bytemuck::cast_slice(&left.mask.coefs[..]),
+        bytemuck::cast_slice(&right.code.coefs[..]),
+        bytemuck::cast_slice(&right.mask.coefs[..]),
+    ])
+}
+
+/// The opened matches of a query, as indices into the candidates.
+struct OpenedMatches {
+    left:     Vec<usize>,
+    right:    Vec<usize>,
+    /// Candidates matching under the match policy, directly or mirrored.
+    both:     Vec<usize>,
+    mirrored: Option<Vec<usize>>,
+}
+
+/// Preprocesses the rotations of the query within `rotation_window` for the
+/// comparison against the candidates.
+fn query_rotations(
+    query: &GaloisRingSharedIris,
+    rotation_window: usize,
+) -> Vec<GaloisRingSharedIris> {
+    query
+        .code
+        .rotations_within(rotation_window)
+        .into_iter()
+        .zip(query.mask.rotations_within(rotation_window))
+        .map(|(mut code, mut mask)| {
+            code.preprocess_iris_code_query_share();
+            mask.preprocess_mask_code_query_share();
+            GaloisRingSharedIris { code, mask }
+        })
+        .collect()
+}
+
+/// Number of candidates compared at once by [`match_bits`], which bounds the
+/// memory taken by the pairs of shares. A multiple of 64, so that the packed
+/// bits of consecutive chunks line up.
+const MATCH_CHUNK_SIZE: usize = 128;
+
+/// Returns the shared bits, packed into u64 words, of the candidates that
+/// match any rotation of the query within `rotation_window`. Nothing is
+/// opened.
+async fn match_bits(
+    session: &mut Session,
+    candidates: &[&GaloisRingSharedIris],
+    query: &GaloisRingSharedIris,
+    rotation_window: usize,
+    threshold: &ThresholdParams,
+) -> eyre::Result<VecShare<u64>> {
+    let rotations = query_rotations(query, rotation_window);
+    let mut bits = VecShare::with_capacity(candidates.len().div_ceil(64));
+    for chunk in candidates.chunks(MATCH_CHUNK_SIZE) {
+        bits.extend(match_bits_chunk(session, chunk, &rotations, threshold).await?);
+    }
+    Ok(bits)
+}
+
+async fn match_bits_chunk(
+    session: &mut Session,
+    candidates: &[&GaloisRingSharedIris],
+    rotations: &[GaloisRingSharedIris],
+    threshold: &ThresholdParams,
+) -> eyre::Result<VecShare<u64>> {
+    let n_candidates = candidates.len();
+    // Rotation major, so that the bits of a rotation are contiguous.
+    let pairs = rotations
+        .iter()
+        .flat_map(|rotation| {
+            candidates
+                .iter()
+                .map(|&candidate| (candidate.clone(), rotation.clone()))
+        })
+        .collect::<Vec<_>>();
+    let dots = galois_ring_pairwise_distance(session, &pairs).await?;
+    let dots = galois_ring_to_rep3(session, dots).await?;
+    let dots = batch_signed_lift_vec(session, dots).await?;
+    let distances = dots
+        .chunks(2)
+        .map(|dot| DistanceShare::new(dot[0].clone(), dot[1].clone()))
+        .collect();
+    let mut bits = compare_threshold_many(session, distances, threshold.match_threshold())
+        .await?
+        .convert_to_bits();
+    bits.truncate(pairs.len());
+    let per_rotation = bits
+        .inner()
+        .chunks(n_candidates)
+        .map(|chunk| VecShare::new_vec(chunk.to_vec()).pack::<u64>())
+        .collect();
+    or_tree_many(session, per_rotation).await
+}
+
+/// Returns the smallest distance between the candidate and any rotation of
+/// the query within `rotation_window`, or `None` if they have no common
+/// unmasked bits. The dot products of all rotations are opened.
+async fn min_distance(
+    session: &mut Session,
+    candidate: &GaloisRingSharedIris,
+    query: &GaloisRingSharedIris,
+    rotation_window: usize,
+) -> eyre::Result<Option<f64>> {
+    let pairs = query_rotations(query, rotation_window)
+        .into_iter()
+        .map(|rotation| (candidate.clone(), rotation))
+        .collect::<Vec<_>>();
+    let dots = galois_ring_pairwise_distance(session, &pairs).await?;
+    let dots = galois_ring_to_rep3(session, dots).await?;
+    let mut min: Option<f64> = None;
+    for dot in dots.chunks(2) {
+        let code_dot = open_u16(session, dot[0].clone()).await?;
+        let mask_dot = open_u16(session, dot[1].clone()).await?;
+        if let Some(distance) = reference::distance(code_dot, mask_dot) {
+            min = Some(min.map_or(distance, |min| min.min(distance)));
+        }
+    }
+    Ok(min)
+}
+
+/// Returns the indices of the candidates that match any rotation of the
+/// query within `rotation_window`.
+async fn matching_indices(
+    session: &mut Session,
+    candidates: &[&GaloisRingSharedIris],
+    query: &GaloisRingSharedIris,
+    rotation_window: usize,
+    threshold: &ThresholdParams,
+    opened_bits: &mut usize,
+) -> eyre::Result<Vec<usize>> {
+    let rotations = query_rotations(query, rotation_window);
+
+    let mut matches = vec![];
+    for (index, &candidate) in candidates.iter().enumerate() {
+        let pairs = rotations
+            .iter()
+            .map(|rotation| (candidate.clone(), rotation.clone()))
+            .collect::<Vec<_>>();
+        let dots = galois_ring_pairwise_distance(session, &pairs).await?;
+        let dots = galois_ring_to_rep3(session, dots).await?;
+        let dots = batch_signed_lift_vec(session, dots).await?;
+        // The opened bits are the same for all parties, so they all stop at
+        // the same rotation.
+        for dot in dots.chunks(2) {
+            let distance = DistanceShare::new(dot[0].clone(), dot[1].clone());
+            *opened_bits += 1;
+            if compare_threshold_and_open(session, distance, threshold.match_threshold()).await? {
+                matches.push(index);
+                break;
+            }
+        }
+    }
+    Ok(matches)
+}
+
+pub struct TestHarness {
+    parties:              Vec<Party>,
+    request_senders:      Vec<ChannelRequestSender>,
+    share_store:          ShareStore,
+    rng:                  AesRng,
+    n_requests:           u64,
+    share_audit:          Option<ShareAuditSettings>,
+    /// The outcome of the last periodic share audit.
+    last_share_audit:     Option<Vec<u32>>,
+    /// The payloads sent by the parties in all share audits.
+    share_audit_messages: Vec<Vec<u8>>,
+    /// All request messages sent so far, for redeliveries.
+    sent_requests:        Vec<String>,
+    /// The requests held back from every party while it is disconnected.
+    held_requests:        Vec<Option<Vec<String>>>,
+}
+
+impl TestHarness {
+    pub async fn new(seed: u64) -> eyre::Result<Self> {
+        let runtime = LocalRuntime::mock_setup_with_channel().await?;
+        let mut parties = vec![];
+        let mut request_senders = vec![];
+        for (party_id, identity) in runtime.identities.iter().enumerate() {
+            let (sender, requests) = ChannelRequestReceiver::new();
+            request_senders.push(sender);
+            let config: Config =
+                serde_json::from_value(serde_json::json!({ "party_id": party_id }))?;
+            parties.push(Party {
+                party_id,
+                session: runtime.sessions[identity].clone(),
+                queues: vec![(requests, None)],
+                results: ChannelResultPublisher::new(),
+                db: PartyDb::default(),
+                match_threshold: MatchThreshold::default(),
+                opened_bits: 0,
+                threshold_schedule: ThresholdSchedule::default(),
+                threshold_state: ThresholdSchedule::default().sync_state(0),
+                batch_counter: 0,
+                backfill: VecDeque::new(),
+                intake: RequestIntake::new(&config, poison_message_policy(&config)),
+                pending_deletion_batches: vec![],
+                audit: vec![],
+                recording: None,
+                soft_deletions: SoftDeletions::default(),
+                clock_secs: 0,
+                config,
+            });
+        }
+        Ok(Self {
+            parties,
+            request_senders,
+            share_store: Arc::new(Mutex::new(HashMap::new())),
+            rng: AesRng::seed_from_u64(seed),
+            n_requests: 0,
+            share_audit: None,
+            last_share_audit: None,
+            share_audit_messages: vec![],
+            sent_requests: vec![],
+            held_requests: vec![None; 3],
+        })
+    }
+
+    /// Enables the mirrored checks on all parties, like
+    /// `enable_mirrored_checks` in the server config.
+    pub fn enable_mirrored_checks(&mut self, enabled: bool) {
+        for party in self.parties.iter_mut() {
+            party.config.enable_mirrored_checks = enabled;
+        }
+    }
+
+    /// Sets the largest rotation window a request can ask for, like
+    /// `max_rotation_window` in the server config.
+    pub fn set_max_rotation_window(&mut self, window: usize) {
+        for party in self.parties.iter_mut() {
+            party.config.max_rotation_window = window;
+        }
+    }
+
+    /// Sets what the parties open of the comparison results, like
+    /// `result_mode` and `reveal_matched_serial_ids` in the server config.
+    pub fn set_result_mode(&mut self, result_mode: ResultMode, reveal_matched_serial_ids: bool) {
+        for party in self.parties.iter_mut() {
+            party.config.result_mode = result_mode;
+            party.config.reveal_matched_serial_ids = reveal_matched_serial_ids;
+        }
+    }
+
+    /// Sets how the matches of the two eyes combine, like `match_policy` in
+    /// the server config.
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) {
+        for party in self.parties.iter_mut() {
+            party.config.match_policy = policy;
+        }
+    }
+
+    /// Sets the threshold the parties start with, like `match_threshold_ratio`
+    /// in the server config. Drops the scheduled changes, so set it before
+    /// the first batch.
+    pub fn set_match_threshold(&mut self, threshold: MatchThreshold) {
+        for party in self.parties.iter_mut() {
+            party.match_threshold = threshold;
+            party.threshold_schedule = ThresholdSchedule::new(ThresholdParams::initial(threshold));
+            party.threshold_state = party.threshold_schedule.sync_state(party.batch_counter);
+        }
+    }
+
+    /// Sets the raw Ed25519 public key that threshold updates are checked
+    /// against, like `threshold_operator_public_key` in the server config.
+    pub fn set_threshold_operator_key(&mut self, key: Option<Vec<u8>>) {
+        for party in self.parties.iter_mut() {
+            party.config.threshold_operator_public_key =
+                key.as_ref().map(|key| STANDARD.encode(key));
+        }
+    }
+
+    /// Enables the share audit of `sample_size` serial ids with the shared
+    /// `seed`, like `share_audit_sample_size` in the server config. The audit
+    /// also runs after every `interval` batches, unless `interval` is 0.
+    pub fn enable_share_audit(&mut self, seed: [u8; 32], sample_size: usize, interval: u64) {
+        self.share_audit = Some(ShareAuditSettings {
+            seed,
+            sample_size,
+            interval,
+        });
+    }
+
+    /// Runs the share audit on all parties, like the server does at startup.
+    /// Returns the serial ids with inconsistent shares.
+    pub async fn audit_shares(&mut self) -> eyre::Result<Vec<u32>> {
+        let settings = self
+            .share_audit
+            .clone()
+            .ok_or_else(|| eyre!("The share audit is not enabled"))?;
+        let mut jobs = JoinSet::new();
+        for mut party in self.parties.drain(..) {
+            let settings = settings.clone();
+            jobs.spawn(async move {
+                let audit = party
+                    .audit_shares(&settings.seed, settings.sample_size)
+                    .await;
+                (party, audit)
+            });
+        }
+        let mut parties = vec![];
+        let mut audits = vec![];
+        while let Some(joined) = jobs.join_next().await {
+            let (party, audit) = joined?;
+            audits.push((party.party_id, audit));
+            parties.push(party);
+        }
+        parties.sort_by_key(|party| party.party_id);
+        self.parties = parties;
+
+        audits.sort_by_key(|(party_id, _)| *party_id);
+        let mut outcomes = vec![];
+        for (_, audit) in audits {
+            let (inconsistent, payload) = audit?;
+            self.share_audit_messages.push(payload);
+            outcomes.push(inconsistent);
+        }
+        if outcomes.iter().any(|outcome| outcome != &outcomes[0]) {
+            bail!("The parties disagree on the share audit: {:?}", outcomes);
+        }
+        Ok(outcomes.swap_remove(0))
+    }
+
+    /// The serial ids flagged by the last periodic share audit, if one ran.
+    pub fn last_share_audit(&self) -> Option<&[u32]> {
+        self.last_share_audit.as_deref()
+    }
+
+    /// Sends a threshold update to all parties.
+    pub fn update_threshold(&mut self, request: &ThresholdUpdateRequest) -> eyre::Result<()> {
+        self.send_request(
+            THRESHOLD_UPDATE_MESSAGE_TYPE,
+            serde_json::to_string(request)?,
+        )
+    }
+
+    /// Secret shares the given irises and sends a uniqueness request for them
+    /// to all parties.
+    pub fn enroll(&mut self, signup_id: &str, left: IrisCode, right: IrisCode) -> eyre::Result<()> {
+        self.send_uniqueness_request(signup_id, left, right, None, None, None)
+    }
+
+    /// Like [`TestHarness::enroll`], but asks for the mirrored check.
+    pub fn enroll_with_mirrored_check(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+    ) -> eyre::Result<()> {
+        self.send_uniqueness_request(signup_id, left, right, Some(true), None, None)
+    }
+
+    /// Like [`TestHarness::enroll`], but only asks for the rotations by at
+    /// most `rotation_window` columns either way.
+    pub fn enroll_with_rotation_window(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+        rotation_window: usize,
+    ) -> eyre::Result<()> {
+        self.send_uniqueness_request(signup_id, left, right, None, Some(rotation_window), None)
+    }
+
+    /// Like [`TestHarness::enroll`], but a match against the given serial ids
+    /// does not keep the irises out of the database.
+    pub fn enroll_with_or_rule(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+        or_rule_serial_ids: Vec<u32>,
+    ) -> eyre::Result<()> {
+        self.send_uniqueness_request(signup_id, left, right, None, None, Some(or_rule_serial_ids))
+    }
+
+    fn send_uniqueness_request(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+        mirrored_check: Option<bool>,
+        rotation_window: Option<usize>,
+        or_rule_serial_ids: Option<Vec<u32>>,
+    ) -> eyre::Result<()> {
+        let left = generate_galois_iris_shares(&mut self.rng, left);
+        let right = generate_galois_iris_shares(&mut self.rng, right);
+        let s3_key = format!("{}.json", signup_id);
+        self.share_store.lock().unwrap().insert(
+            s3_key.clone(),
+            left.into_iter().zip(right).map(Some).collect(),
+        );
+
+        let request = UniquenessRequest {
+            batch_size: None,
+            signup_id: signup_id.to_string(),
+            s3_key,
+            iris_shares: None,
+            iris_shares_file_hashes: Default::default(),
+            mirrored_check,
+            rotation_window,
+            or_rule_serial_ids,
+        };
+        self.send_request(UNIQUENESS_MESSAGE_TYPE, serde_json::to_string(&request)?)
+    }
+
+    /// Secret shares the given irises into one backfill entry per party, like
+    /// the share files read by the `backfill` tool.
+    pub fn backfill_entries(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+    ) -> Vec<BackfillEntry> {
+        let left = generate_galois_iris_shares(&mut self.rng, left);
+        let right = generate_galois_iris_shares(&mut self.rng, right);
+        left.into_iter()
+            .zip(right)
+            .map(|(left, right)| {
+                BackfillEntry::new(
+                    signup_id,
+                    &(left.code, left.mask),
+                    &(right.code, right.mask),
+                )
+            })
+            .collect()
+    }
+
+    /// Hands backfill entries to the given party, like the backfill endpoint of
+    /// the server. Only the accepted entries are queued, the rest has to be
+    /// sent again.
+    ///
+    /// Unlike the server, the harness enrolls backfilled entries without
+    /// matching them: the in-process protocol is too slow to deduplicate
+    /// thousands of identities, which are expected to be unique already.
+    pub fn backfill(&mut self, party_id: usize, entries: Vec<BackfillEntry>) -> BackfillResponse {
+        let queue = &mut self.parties[party_id].backfill;
+        let accepted = entries.len().min(BACKFILL_QUEUE_SIZE - queue.len());
+        queue.extend(entries.into_iter().take(accepted).map(|mut entry| {
+            entry.signup_id = backfill_signup_id(&entry.signup_id);
+            entry
+        }));
+        BackfillResponse { accepted }
+    }
+
+    /// Sends an identity deletion request for the given serial id to all
+    /// parties.
+    pub fn delete(&mut self, serial_id: u32) -> eyre::Result<()> {
+        let request = IdentityDeletionRequest {
+            serial_id,
+            grace_period_secs: None,
+        };
+        self.send_request(
+            IDENTITY_DELETION_MESSAGE_TYPE,
+            serde_json::to_string(&request)?,
+        )
+    }
+
+    /// Sends an identity deletion request with a grace period to all parties,
+    /// see [`iris_mpc_common::helpers::soft_delete`].
+    pub fn soft_delete(&mut self, serial_id: u32, grace_period_secs: u64) -> eyre::Result<()> {
+        let request = IdentityDeletionRequest {
+            serial_id,
+            grace_period_secs: Some(grace_period_secs),
+        };
+        self.send_request(
+            IDENTITY_DELETION_MESSAGE_TYPE,
+            serde_json::to_string(&request)?,
+        )
+    }
+
+    /// Sends an identity deletion batch with the given serial ids to all
+    /// parties.
+    pub fn delete_batch(&mut self, serial_ids: &[u32]) -> eyre::Result<()> {
+        let request = IdentityDeletionBatchRequest {
+            serial_ids: serial_ids.to_vec(),
+        };
+        self.send_request(
+            IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            serde_json::to_string(&request)?,
+        )
+    }
+
+    /// Sends an identity restore request for the given serial id to all
+    /// parties.
+    pub fn restore(&mut self, serial_id: u32) -> eyre::Result<()> {
+        let request = IdentityRestoreRequest { serial_id };
+        self.send_request(
+            IDENTITY_RESTORE_MESSAGE_TYPE,
+            serde_json::to_string(&request)?,
+        )
+    }
+
+    /// Advances the clock of the given party, which decides when its
+    /// soft-deletes are due.
+    pub fn advance_clock(&mut self, party_id: usize, secs: u64) {
+        self.parties[party_id].clock_secs += secs;
+    }
+
+    /// The number of soft-deletes the given party has not purged yet.
+    pub fn pending_soft_deletes(&self, party_id: usize) -> usize {
+        self.parties[party_id].soft_deletions.len()
+    }
+
+    fn send_request(&mut self, message_type: &str, message: String) -> eyre::Result<()> {
+        self.n_requests += 1;
+        let envelope = SQSMessage {
+            notification_type: "Notification".to_string(),
+            message_id: self.n_requests.to_string(),
+            sequence_number: self.n_requests.to_string(),
+            topic_arn: "local".to_string(),
+            message,
+            timestamp: String::new(),
+            unsubscribe_url: String::new(),
+            message_attributes: create_message_type_attribute_map(message_type),
+        };
+        let body = serde_json::to_string(&envelope)?;
+        for (sender, held) in self
+            .request_senders
+            .iter()
+            .zip(self.held_requests.iter_mut())
+        {
+            match held {
+                Some(held) => held.push(body.clone()),
+                None => sender.send(body.clone())?,
+            }
+        }
+        self.sent_requests.push(body);
+        Ok(())
+    }
+
+    /// Holds back the requests sent to the given party until it is
+    /// reconnected, as if its connection to the request queue dropped.
+    pub fn disconnect(&mut self, party_id: usize) {
+        self.held_requests[party_id].get_or_insert_with(Vec::new);
+    }
+
+    /// Delivers the requests held back since [`TestHarness::disconnect`].
+    pub fn reconnect(&mut self, party_id: usize) -> eyre::Result<()> {
+        for body in self.held_requests[party_id].take().unwrap_or_default() {
+            self.request_senders[party_id].send(body)?;
+        }
+        Ok(())
+    }
+
+    /// Delivers the requests held back since [`TestHarness::disconnect`] in
+    /// another order: `order` lists the indices of the held requests, in the
+    /// order they are delivered in. Requests not listed are lost.
+    pub fn reconnect_permuted(&mut self, party_id: usize, order: &[usize]) -> eyre::Result<()> {
+        let held = self.held_requests[party_id].take().unwrap_or_default();
+        for &index in order {
+            let body = held
+                .get(index)
+                .ok_or_else(|| eyre!("No held request at index {}", index))?;
+            self.request_senders[party_id].send(body.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Delivers the last `count` requests to all parties again, like SQS does
+    /// with at-least-once delivery. A party ignores the copy of a request it
+    /// still holds, but answers a copy that arrives after the batch of the
+    /// request was through, just like the server. Control requests are
+    /// applied again.
+    pub fn redeliver(&mut self, count: usize) -> eyre::Result<()> {
+        let start = self.sent_requests.len().saturating_sub(count);
+        for body in self.sent_requests[start..].iter() {
+            for sender in self.request_senders.iter() {
+                sender.send(body.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the download of the shares of the given request fail at the given
+    /// party.
+    pub fn fail_share_download(&mut self, party_id: usize, signup_id: &str) -> eyre::Result<()> {
+        let s3_key = format!("{}.json", signup_id);
+        let mut share_store = self.share_store.lock().unwrap();
+        let shares = share_store
+            .get_mut(&s3_key)
+            .ok_or_else(|| eyre!("No shares stored under {}", s3_key))?;
+        shares[party_id] = None;
+        Ok(())
+    }
+
+    /// Restarts the given party between two batches, see [`Party::restart`].
+    /// The requests it held for the next batch are delivered again, as if
+    /// their visibility timeout ran out.
+    pub fn restart_party(&mut self, party_id: usize) -> eyre::Result<()> {
+        let party = self.parties.remove(party_id);
+        for pending in party.intake.waiting() {
+            self.request_senders[party_id].send(pending.body.clone())?;
+        }
+        self.parties.insert(party_id, party.restart());
+        Ok(())
+    }
+
+    /// Runs one batch of at most `batch_size` requests on all three parties.
+    pub async fn process_batch(&mut self, batch_size: usize) -> eyre::Result<()> {
+        let mut jobs = JoinSet::new();
+        for mut party in self.parties.drain(..) {
+            let share_store = self.share_store.clone();
+            jobs.spawn(async move {
+                party.process_batch(batch_size, &share_store).await?;
+                Ok::<_, eyre::Report>(party)
+            });
+        }
+        let mut parties = vec![];
+        while let Some(party) = jobs.join_next().await {
+            parties.push(party??);
+        }
+        parties.sort_by_key(|party| party.party_id);
+        self.parties = parties;
+
+        // The GPU actor checks this before matching; in process, checking
+        // afterwards is enough to fail the test.
+        let states = self
+            .parties
+            .iter()
+            .map(|party| party.threshold_state.clone())
+            .collect::<Vec<_>>();
+        check_agreement(&states)?;
+
+        if let Some(settings) = &self.share_audit {
+            if settings.interval > 0 && self.parties[0].batch_counter % settings.interval == 0 {
+                self.last_share_audit = Some(self.audit_shares().await?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns and clears the results published by the given party.
+    pub fn drain_results(&self, party_id: usize) -> eyre::Result<Vec<ResultEvent>> {
+        self.parties[party_id]
+            .results
+            .drain()
+            .iter()
+            .map(ResultEvent::from_published)
+            .collect()
+    }
+
+    /// Returns and clears the results of all parties, checking that the
+    /// parties published the same results. Returns the results of party 0.
+    pub fn drain_agreed_results(&self) -> eyre::Result<Vec<ResultEvent>> {
+        let results = (0..self.parties.len())
+            .map(|party_id| self.drain_results(party_id))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        for (party_id, party_results) in results.iter().enumerate() {
+            if party_results.iter().any(|r| r.node_id() != party_id) {
+                bail!("Party {} published results with a wrong node id", party_id);
+            }
+            let lhs = party_results
+                .iter()
+                .map(ResultEvent::without_node_id)
+                .collect::<Vec<_>>();
+            let rhs = results[0]
+                .iter()
+                .map(ResultEvent::without_node_id)
+                .collect::<Vec<_>>();
+            if lhs != rhs {
+                bail!("Results of party {} differ from party 0", party_id);
+            }
+        }
+        Ok(results.into_iter().next().unwrap_or_default())
+    }
+
+    pub fn db(&self, party_id: usize) -> &PartyDb {
+        &self.parties[party_id].db
+    }
+
+    /// The digest of the database of the given party, which must be the same
+    /// at all parties.
+    pub fn db_digest(&self, party_id: usize) -> AuditHash {
+        self.parties[party_id].db_digest()
+    }
+
+    /// The audit records the given party wrote for every batch.
+    pub fn audit_records(&self, party_id: usize) -> &[AuditRecord] {
+        &self.parties[party_id].audit
+    }
+
+    /// Number of comparison bits the given party has opened so far.
+    pub fn opened_bits(&self, party_id: usize) -> usize {
+        self.parties[party_id].opened_bits
+    }
+
+    /// Records every following batch on all parties, like
+    /// `replay_recording_dir` in the server config. The settings are recorded
+    /// as they are now, so set them first.
+    pub fn enable_recording(&mut self) {
+        for party in self.parties.iter_mut() {
+            party.recording = Some(ReplayBundle::new(party.party_id, RecordedSettings {
+                enable_mirrored_checks:    party.config.enable_mirrored_checks,
+                result_mode:               party.config.result_mode,
+                reveal_matched_serial_ids: party.config.reveal_matched_serial_ids,
+                match_policy:              party.config.match_policy,
+                match_threshold:           party.match_threshold,
+                max_rotation_window:       Some(party.config.max_rotation_window),
+                threshold_operator_key:    party
+                    .config
+                    .threshold_operator_public_key
+                    .as_ref()
+                    .and_then(|key| STANDARD.decode(key).ok()),
+            }));
+        }
+    }
+
+    /// The batches the given party recorded since recording was enabled.
+    pub fn recording(&self, party_id: usize) -> Option<&ReplayBundle> {
+        self.parties[party_id].recording.as_ref()
+    }
+
+    /// Applies the recorded settings to all parties.
+    pub fn apply_recorded_settings(&mut self, settings: &RecordedSettings) {
+        self.enable_mirrored_checks(settings.enable_mirrored_checks);
+        self.set_result_mode(settings.result_mode, settings.reveal_matched_serial_ids);
+        self.set_match_policy(settings.match_policy);
+        self.set_match_threshold(settings.match_threshold);
+        self.set_max_rotation_window(
+            settings
+                .max_rotation_window
+                .unwrap_or(IrisCodeArray::MAX_ROTATION),
+        );
+        self.set_threshold_operator_key(settings.threshold_operator_key.clone());
+    }
+
+    /// Processes the recorded batches of the same batch id again, one per
+    /// party in the order of the party ids. Returns what every party would
+    /// have written to its audit log.
+    pub async fn replay_batch(
+        &mut self,
+        batches: &[&RecordedBatch],
+    ) -> eyre::Result<Vec<AuditBatch>> {
+        if batches.len() != self.parties.len() {
+            bail!("Expected a recorded batch of every party");
+        }
+        // All batches are decoded before the parties start, so that no party
+        // waits for another one that failed. Decoding takes a lot of stack in
+        // debug builds.
+        let mut decoded = vec![];
+        for batch in batches {
+            let batch = (*batch).clone();
+            decoded.push((
+                batch.batch_id,
+                spawn_blocking(move || decode_recorded_batch(&batch)).await??,
+            ));
+        }
+        let mut jobs = JoinSet::new();
+        for (mut party, (batch_id, batch)) in self.parties.drain(..).zip(decoded) {
+            jobs.spawn(async move {
+                let replayed = party.replay_batch(batch_id, batch).await;
+                (party, replayed)
+            });
+        }
+        let mut parties = vec![];
+        let mut replayed = vec![];
+        while let Some(joined) = jobs.join_next().await {
+            let (party, batch) = joined?;
+            replayed.push((party.party_id, batch));
+            parties.push(party);
+        }
+        parties.sort_by_key(|party| party.party_id);
+        self.parties = parties;
+        replayed.sort_by_key(|(party_id, _)| *party_id);
+        replayed.into_iter().map(|(_, batch)| batch).collect()
+    }
+}
+
+/// Runs every canary request in a batch of its own.
+#[async_trait]
+impl CanaryTarget for TestHarness {
+    async fn uniqueness(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+    ) -> eyre::Result<UniquenessResult> {
+        self.enroll(signup_id, left, right)?;
+        self.process_batch(1).await?;
+        self.drain_agreed_results()?
+            .into_iter()
+            .find_map(|event| match event {
+                ResultEvent::Uniqueness(result) if result.signup_id == signup_id => Some(result),
+                _ => None,
+            })
+            .ok_or_else(|| eyre!("No result for {}", signup_id))
+    }
+
+    async fn delete(&mut self, serial_id: u32) -> eyre::Result<IdentityDeletionResult> {
+        TestHarness::delete(self, serial_id)?;
+        self.process_batch(1).await?;
+        self.drain_agreed_results()?
+            .into_iter()
+            .find_map(|event| match event {
+                ResultEvent::IdentityDeletion(result) if result.serial_id == serial_id => {
+                    Some(result)
+                }
+                _ => None,
+            })
+            .ok_or_else(|| eyre!("No deletion result for serial id {}", serial_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iris_mpc_common::helpers::{
+        canary::{Canary, CanaryStep, CANARY_SEED},
+        smpc_request::MAX_IDENTITY_DELETION_BATCH_SIZE,
+        sync::MAX_BATCH_DEFERRALS,
+    };
+    use rand::Rng;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    fn uniqueness_results(events: Vec<ResultEvent>) -> Vec<UniquenessResult> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                ResultEvent::Uniqueness(result) => result,
+                other => panic!("Expected uniqueness result, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn random_iris_pair(rng: &mut impl Rng) -> (IrisCode, IrisCode) {
+        (IrisCode::random_rng(rng), IrisCode::random_rng(rng))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_enroll_and_duplicate() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut harness = TestHarness::new(0).await.unwrap();
+
+        let (alice_left, alice_right) = random_iris_pair(&mut rng);
+        let (bob_left, bob_right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", alice_left.clone(), alice_right.clone())
+            .unwrap();
+        harness.enroll("bob", bob_left, bob_right).unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| !r.is_match));
+        assert_eq!(results[0].serial_id, Some(1));
+        assert_eq!(results[1].serial_id, Some(2));
+        for party_id in 0..3 {
+            assert_eq!(harness.db(party_id).len(), 2);
+        }
+
+        // A noisy capture of the same person matches on both eyes.
+        harness
+            .enroll(
+                "alice-again",
+                alice_left.get_similar_iris(&mut rng),
+                alice_right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        // Only one matching eye is not a duplicate.
+        harness
+            .enroll("carol", alice_left, IrisCode::random_rng(&mut rng))
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_match);
+        assert_eq!(results[0].serial_id, None);
+        assert_eq!(results[0].matched_serial_ids, Some(vec![1]));
+        assert!(!results[1].is_match);
+        assert_eq!(results[1].serial_id, Some(3));
+        assert_eq!(results[1].matched_serial_ids_left, Some(vec![1]));
+        assert_eq!(results[1].matched_serial_ids_right, Some(vec![]));
+        for party_id in 0..3 {
+            assert_eq!(harness.db(party_id).len(), 3);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_match_policy_or() {
+        for result_mode in [ResultMode::FullOpen, ResultMode::CountOnly] {
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut harness = TestHarness::new(1).await.unwrap();
+            harness.set_result_mode(result_mode, true);
+            harness.set_match_policy(MatchPolicy::Or);
+
+            let (alice_left, alice_right) = random_iris_pair(&mut rng);
+            harness
+                .enroll("alice", alice_left, alice_right.clone())
+                .unwrap();
+            harness.process_batch(8).await.unwrap();
+
+            // One matching eye is enough under the OR policy.
+            harness
+                .enroll(
+                    "carol",
+                    IrisCode::random_rng(&mut rng),
+                    alice_right.get_similar_iris(&mut rng),
+                )
+                .unwrap();
+            let (dave_left, dave_right) = random_iris_pair(&mut rng);
+            harness.enroll("dave", dave_left, dave_right).unwrap();
+            harness.process_batch(8).await.unwrap();
+
+            let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+            assert_eq!(results.len(), 3);
+            let result = |signup_id: &str| {
+                results
+                    .iter()
+                    .find(|result| result.signup_id == signup_id)
+                    .unwrap()
+            };
+            let carol = result("carol");
+            assert!(carol.is_match);
+            assert_eq!(carol.matched_serial_ids, Some(vec![1]));
+            assert_eq!(carol.match_policy, Some(MatchPolicy::Or));
+            if result_mode == ResultMode::FullOpen {
+                assert_eq!(carol.matched_serial_ids_left, Some(vec![]));
+                assert_eq!(carol.matched_serial_ids_right, Some(vec![1]));
+            }
+            assert!(!result("dave").is_match);
+            assert_eq!(result("dave").serial_id, Some(2));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_result_mode_with_distances() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut harness = TestHarness::new(2).await.unwrap();
+        harness.set_result_mode(ResultMode::WithDistances, false);
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results[0].matched_distances, Some(vec![]));
+        assert_eq!(results[0].min_distance, None);
+
+        harness
+            .enroll("alice-again", left.clone(), right.clone())
+            .unwrap();
+        harness
+            .enroll(
+                "alice-similar",
+                left.get_similar_iris(&mut rng),
+                right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results[0].matched_serial_ids, Some(vec![1]));
+        assert_eq!(results[0].matched_distances, Some(vec![0.0]));
+        assert_eq!(results[0].min_distance, Some(0.0));
+        assert_eq!(results[1].matched_serial_ids, Some(vec![1]));
+        let distance = results[1].min_distance.unwrap();
+        assert!(distance > 0.0 && distance < 0.375);
+        assert_eq!(results[1].matched_distances, Some(vec![distance]));
+
+        // Without the mode, the results carry no distances.
+        harness.set_result_mode(ResultMode::FullOpen, false);
+        harness.enroll("alice-full-open", left, right).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(results[0].is_match);
+        assert_eq!(results[0].matched_distances, None);
+        assert_eq!(results[0].min_distance, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mirrored_check() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut harness = TestHarness::new(3).await.unwrap();
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        // A synthetic identity whose right eye is the mirror of the stored left
+        // eye, and the other way round.
+        let (mirrored_left, mirrored_right) = (right.mirrored(), left.mirrored());
+
+        // Without the config flag, the request flag is ignored.
+        harness
+            .enroll_with_mirrored_check("mallory", mirrored_left.clone(), mirrored_right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].serial_id, Some(2));
+        assert_eq!(results[0].matched_serial_ids_mirror, None);
+
+        harness.enable_mirrored_checks(true);
+        // Only flagged requests are checked.
+        harness
+            .enroll(
+                "mallory-again",
+                mirrored_left.clone(),
+                mirrored_right.clone(),
+            )
+            .unwrap();
+        harness
+            .enroll_with_mirrored_check("mallory-flagged", mirrored_left, mirrored_right)
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 2);
+        let result = |signup_id: &str| {
+            results
+                .iter()
+                .find(|result| result.signup_id == signup_id)
+                .unwrap()
+        };
+        // The unflagged request matches mallory, who was enrolled unchecked.
+        let unflagged = result("mallory-again");
+        assert!(unflagged.is_match);
+        assert_eq!(unflagged.matched_serial_ids, Some(vec![2]));
+        assert_eq!(unflagged.matched_serial_ids_mirror, None);
+        let flagged = result("mallory-flagged");
+        assert!(flagged.is_match);
+        assert_eq!(flagged.serial_id, None);
+        assert_eq!(flagged.matched_serial_ids, Some(vec![2, 1]));
+        assert_eq!(flagged.matched_serial_ids_mirror, Some(vec![1]));
+        for party_id in 0..3 {
+            assert_eq!(harness.db(party_id).len(), 2);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rotation_window() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut harness = TestHarness::new(5).await.unwrap();
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        // Alice's irises, taken with the head tilted by `by` columns.
+        let rotated = |by: isize| {
+            let rotate = |iris: &IrisCode| {
+                let rotate = |code: &IrisCodeArray| {
+                    if by < 0 {
+                        code.rotate_left(by.unsigned_abs())
+                    } else {
+                        code.rotate_right(by as usize)
+                    }
+                };
+                IrisCode {
+                    code: rotate(&iris.code),
+                    mask: rotate(&iris.mask),
+                }
+            };
+            (rotate(&left), rotate(&right))
+        };
+
+        // The best rotation is within the window.
+        let (near_left, near_right) = rotated(2);
+        harness
+            .enroll_with_rotation_window("near", near_left, near_right, 4)
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(results[0].is_match);
+        assert_eq!(results[0].matched_serial_ids, Some(vec![1]));
+
+        // The best rotation is beyond the window, so the probe is unique.
+        let (far_left, far_right) = rotated(8);
+        harness
+            .enroll_with_rotation_window("far", far_left, far_right, 4)
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].serial_id, Some(2));
+
+        // Without a hint, all rotations are tried.
+        let (far_left, far_right) = rotated(-8);
+        harness
+            .enroll("far-unhinted", far_left.clone(), far_right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(results[0].is_match);
+        assert_eq!(results[0].matched_serial_ids, Some(vec![1]));
+
+        // The hint is capped at the configured maximum, which is also the
+        // window of requests without a hint.
+        harness.set_max_rotation_window(4);
+        harness
+            .enroll_with_rotation_window("far-capped", far_left.clone(), far_right.clone(), 15)
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].serial_id, Some(3));
+        harness.enroll("far-default", far_left, far_right).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(results[0].is_match);
+        assert_eq!(results[0].matched_serial_ids, Some(vec![3]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_or_rule() {
+        for result_mode in [ResultMode::FullOpen, ResultMode::CountOnly] {
+            let mut rng = StdRng::seed_from_u64(6);
+            let mut harness = TestHarness::new(6).await.unwrap();
+            harness.set_result_mode(result_mode, true);
+
+            let (left, right) = random_iris_pair(&mut rng);
+            harness
+                .enroll("alice", left.clone(), right.clone())
+                .unwrap();
+            let (bob_left, bob_right) = random_iris_pair(&mut rng);
+            harness.enroll("bob", bob_left, bob_right).unwrap();
+            harness.process_batch(8).await.unwrap();
+            harness.drain_agreed_results().unwrap();
+
+            // Alice re-enrolls, her match is reported but does not block. The
+            // unknown serial id is ignored.
+            harness
+                .enroll_with_or_rule(
+                    "alice-again",
+                    left.get_similar_iris(&mut rng),
+                    right.get_similar_iris(&mut rng),
+                    vec![1, 99],
+                )
+                .unwrap();
+            harness.process_batch(8).await.unwrap();
+            let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+            assert!(!results[0].is_match);
+            assert_eq!(results[0].serial_id, Some(3));
+            assert_eq!(results[0].matched_serial_ids, Some(vec![1]));
+
+            // A match beyond the OR-rule serial ids still blocks.
+            harness
+                .enroll_with_or_rule(
+                    "alice-third",
+                    left.get_similar_iris(&mut rng),
+                    right.get_similar_iris(&mut rng),
+                    vec![1, 2],
+                )
+                .unwrap();
+            harness.process_batch(8).await.unwrap();
+            let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+            assert!(results[0].is_match);
+            assert_eq!(results[0].serial_id, None);
+            assert_eq!(results[0].matched_serial_ids, Some(vec![1, 3]));
+            for party_id in 0..3 {
+                assert_eq!(harness.db(party_id).len(), 3);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delete() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut harness = TestHarness::new(1).await.unwrap();
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        harness.delete(1).unwrap();
+        harness.delete(5).unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        let results = harness.drain_agreed_results().unwrap();
+        let mut deletions = results
+            .into_iter()
+            .map(|event| match event {
+                ResultEvent::IdentityDeletion(result) => (result.serial_id, result.success),
+                other => panic!("Expected deletion result, got {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        // The batch is in the order of the request keys.
+        deletions.sort();
+        assert_eq!(deletions, vec![(1, true), (5, false)]);
+        for party_id in 0..3 {
+            let db = harness.db(party_id);
+            assert_eq!(db.len(), 1);
+            assert_eq!(db.left[0], dummy_shares_for_deletion(party_id));
+            assert_eq!(db.right[0], dummy_shares_for_deletion(party_id));
+        }
+
+        // The deleted identity can enroll again.
+        harness.enroll("alice-again", left, right).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 1);
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].serial_id, Some(2));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delete_batch() {
+        let mut rng = StdRng::seed_from_u64(14);
+        let mut harness = TestHarness::new(1).await.unwrap();
+        let deletion_batch_results = |events: Vec<ResultEvent>| {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    ResultEvent::IdentityDeletionBatch(result) => result,
+                    other => panic!("Expected deletion batch result, got {:?}", other),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for signup_id in ["alice", "bob", "carol"] {
+            let (left, right) = random_iris_pair(&mut rng);
+            harness.enroll(signup_id, left, right).unwrap();
+        }
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        // A batch above the cap is rejected as a whole.
+        let serial_ids = (1..=MAX_IDENTITY_DELETION_BATCH_SIZE as u32 + 1).collect::<Vec<_>>();
+        harness.delete_batch(&serial_ids).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = deletion_batch_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].error_code,
+            Some(ErrorCode::TooManyDeletionSerialIds)
+        );
+        assert!(results[0].deleted_serial_ids.is_empty());
+
+        // No party deletes anything while one of them lacks the batch.
+        harness.disconnect(2);
+        harness.delete_batch(&[3, 1, 9, 3]).unwrap();
+        harness.process_batch(8).await.unwrap();
+        assert!(harness.drain_agreed_results().unwrap().is_empty());
+        for party_id in 0..3 {
+            let db = harness.db(party_id);
+            assert!((0..3).all(|i| db.left[i] != dummy_shares_for_deletion(party_id)));
+        }
+
+        // Once all parties hold it, they delete the same serial ids, with one
+        // result for the whole batch.
+        harness.reconnect(2).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = deletion_batch_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].serial_ids, vec![1, 3, 9]);
+        assert_eq!(results[0].deleted_serial_ids, vec![1, 3]);
+        assert_eq!(results[0].not_found_serial_ids, vec![9]);
+        assert_eq!(results[0].error, None);
+        for party_id in 0..3 {
+            let db = harness.db(party_id);
+            assert_eq!(db.left[0], dummy_shares_for_deletion(party_id));
+            assert_ne!(db.left[1], dummy_shares_for_deletion(party_id));
+            assert_eq!(db.right[2], dummy_shares_for_deletion(party_id));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_soft_delete_and_restore() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut harness = TestHarness::new(1).await.unwrap();
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        // The soft-deleted identity does not match during the grace period.
+        harness.soft_delete(1, 100).unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+        harness
+            .enroll("alice-again", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].serial_id, Some(2));
+
+        // After the restore it matches again.
+        harness.delete(2).unwrap();
+        harness.restore(1).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let restores = harness
+            .drain_agreed_results()
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event {
+                ResultEvent::IdentityRestore(result) => Some((result.serial_id, result.success)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(restores, vec![(1, true)]);
+        harness.enroll("alice-third", left, right).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(results[0].is_match);
+
+        // The purge waits until the grace period is over at all parties.
+        harness.soft_delete(1, 100).unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+        harness.advance_clock(0, 100);
+        harness.advance_clock(1, 100);
+        harness.process_batch(8).await.unwrap();
+        for party_id in 0..3 {
+            assert_eq!(harness.pending_soft_deletes(party_id), 1);
+            assert_ne!(
+                harness.db(party_id).left[0],
+                dummy_shares_for_deletion(party_id)
+            );
+        }
+        harness.advance_clock(2, 100);
+        harness.process_batch(8).await.unwrap();
+        for party_id in 0..3 {
+            assert_eq!(harness.pending_soft_deletes(party_id), 0);
+            assert_eq!(
+                harness.db(party_id).left[0],
+                dummy_shares_for_deletion(party_id)
+            );
+            assert_eq!(harness.db_digest(party_id), harness.db_digest(0));
+        }
+
+        // A purged identity cannot be restored.
+        harness.restore(1).unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = harness.drain_agreed_results().unwrap();
+        assert!(matches!(
+            &results[..],
+            [ResultEvent::IdentityRestore(result)] if !result.success
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch_boundary() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut harness = TestHarness::new(2).await.unwrap();
+
+        let (alice_left, alice_right) = random_iris_pair(&mut rng);
+        let (bob_left, bob_right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", alice_left.clone(), alice_right.clone())
+            .unwrap();
+        harness
+            .enroll("alice-again", alice_left, alice_right)
+            .unwrap();
+        harness
+            .enroll("bob", bob_left.clone(), bob_right.clone())
+            .unwrap();
+        harness.enroll("bob-again", bob_left, bob_right).unwrap();
+
+        // Duplicates within a batch are matched against the earlier request,
+        // in the order of the request keys.
+        harness.process_batch(2).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].signup_id, "alice-again");
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].serial_id, Some(1));
+        assert!(results[1].is_match);
+        assert_eq!(results[1].matched_serial_ids, Some(vec![]));
+        assert_eq!(
+            results[1].matched_batch_request_ids,
+            Some(vec!["alice-again".to_string()])
+        );
+
+        // The batch size cuts between bob and his duplicate, which is then
+        // matched against the database instead.
+        harness.process_batch(1).await.unwrap();
+        harness.process_batch(1).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].serial_id, Some(2));
+        assert!(results[1].is_match);
+        assert_eq!(results[1].matched_serial_ids, Some(vec![2]));
+        assert_eq!(results[1].matched_batch_request_ids, Some(vec![]));
+
+        // Nothing left to process.
+        harness.process_batch(2).await.unwrap();
+        assert!(harness.drain_agreed_results().unwrap().is_empty());
+        for party_id in 0..3 {
+            assert_eq!(harness.db(party_id).len(), 2);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batch_composition_ignores_arrival_order() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut harness = TestHarness::new(3).await.unwrap();
+        let signup_ids = |results: &[UniquenessResult]| {
+            let mut signup_ids = results
+                .iter()
+                .map(|result| result.signup_id.clone())
+                .collect::<Vec<_>>();
+            signup_ids.sort();
+            signup_ids
+        };
+
+        // Party 2 receives the first request last, so it is not in its batch.
+        harness.disconnect(2);
+        for signup_id in ["straggler", "a", "b", "c", "d"] {
+            let (left, right) = random_iris_pair(&mut rng);
+            harness.enroll(signup_id, left, right).unwrap();
+        }
+        harness.reconnect_permuted(2, &[1, 2, 3, 4, 0]).unwrap();
+
+        // The batch is the same at all parties, although party 2 took another
+        // set of requests off its queue.
+        harness.process_batch(4).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(signup_ids(&results), ["a", "b", "c"]);
+
+        // The deferred requests make it into the next batch.
+        harness.process_batch(4).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(signup_ids(&results), ["d", "straggler"]);
+        for party_id in 0..3 {
+            assert_eq!(harness.db(party_id).len(), 5);
+        }
+
+        // A request that one party never receives is dropped after the
+        // deferrals, with an error result from the parties that held it.
+        harness.disconnect(2);
+        let (left, right) = random_iris_pair(&mut rng);
+        harness.enroll("lost", left, right).unwrap();
+        harness.reconnect_permuted(2, &[]).unwrap();
+        for _ in 0..MAX_BATCH_DEFERRALS {
+            harness.process_batch(4).await.unwrap();
+            assert!(harness.drain_agreed_results().unwrap().is_empty());
+        }
+        harness.process_batch(4).await.unwrap();
+        for party_id in 0..2 {
+            let results = uniqueness_results(harness.drain_results(party_id).unwrap());
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].signup_id, "lost");
+            assert_eq!(results[0].error_code, Some(ErrorCode::RequestNotAgreed));
+        }
+        assert!(harness.drain_results(2).unwrap().is_empty());
+        harness.process_batch(4).await.unwrap();
+        assert!(harness.drain_agreed_results().unwrap().is_empty());
+    }
+
+    /// Sends the same synthetic batches to a harness and returns its results:
+    /// fresh identities, duplicates against the database and within a batch,
+    /// a single matching eye and a mirrored identity.
+    async fn run_synthetic_batches(harness: &mut TestHarness) -> Vec<UniquenessResult> {
+        let mut rng = StdRng::seed_from_u64(4);
+        harness.enable_mirrored_checks(true);
+        let (alice_left, alice_right) = random_iris_pair(&mut rng);
+        let (bob_left, bob_right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", alice_left.clone(), alice_right.clone())
+            .unwrap();
+        harness
+            .enroll("bob", bob_left.clone(), bob_right.clone())
+            .unwrap();
+        harness
+            .enroll(
+                "bob-again",
+                bob_left.get_similar_iris(&mut rng),
+                bob_right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        harness
+            .enroll(
+                "alice-again",
+                alice_left.get_similar_iris(&mut rng),
+                alice_right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness
+            .enroll("carol", alice_left.clone(), IrisCode::random_rng(&mut rng))
+            .unwrap();
+        harness
+            .enroll_with_mirrored_check("mallory", alice_right.mirrored(), alice_left.mirrored())
+            .unwrap();
+        let (dave_left, dave_right) = random_iris_pair(&mut rng);
+        harness.enroll("dave", dave_left, dave_right).unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        uniqueness_results(harness.drain_agreed_results().unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_count_only_mode() {
+        let mut full = TestHarness::new(4).await.unwrap();
+        let full_results = run_synthetic_batches(&mut full).await;
+
+        let mut count_only = TestHarness::new(4).await.unwrap();
+        count_only.set_result_mode(ResultMode::CountOnly, false);
+        let count_only_results = run_synthetic_batches(&mut count_only).await;
+
+        let decisions = |results: &[UniquenessResult]| {
+            results
+                .iter()
+                .map(|r| (r.signup_id.clone(), r.is_match, r.serial_id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(decisions(&count_only_results), decisions(&full_results));
+        assert_eq!(
+            full_results.iter().filter(|r| r.is_match).count(),
+            3,
+            "bob-again, alice-again and mallory are duplicates"
+        );
+        for r in count_only_results.iter() {
+            assert_eq!(r.matched_serial_ids, None);
+            assert_eq!(r.matched_serial_ids_left, None);
+            assert_eq!(r.matched_batch_request_ids, None);
+        }
+        for party_id in 0..3 {
+            assert!(full.opened_bits(party_id) > 0);
+            assert_eq!(count_only.opened_bits(party_id), 0);
+            assert_eq!(count_only.db(party_id).len(), full.db(party_id).len());
+        }
+
+        // With the policy flag, the matching entries are opened too.
+        let mut revealing = TestHarness::new(4).await.unwrap();
+        revealing.set_result_mode(ResultMode::CountOnly, true);
+        let revealing_results = run_synthetic_batches(&mut revealing).await;
+        assert_eq!(decisions(&revealing_results), decisions(&full_results));
+        for (lhs, rhs) in revealing_results.iter().zip(full_results.iter()) {
+            assert_eq!(lhs.matched_serial_ids, rhs.matched_serial_ids);
+            assert_eq!(lhs.matched_batch_request_ids, rhs.matched_batch_request_ids);
+        }
+        assert!(revealing.opened_bits(0) > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_threshold_update() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut harness = TestHarness::new(5).await.unwrap();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let operator_key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        harness.set_threshold_operator_key(Some(operator_key.public_key().as_ref().to_vec()));
+
+        // A strict threshold from batch 2 on, and an unsigned update for batch 1
+        // that must be ignored.
+        let strict = ThresholdParams {
+            version:               1,
+            match_threshold_ratio: 0.01,
+        };
+        harness
+            .update_threshold(&ThresholdUpdateRequest::new_signed(
+                strict,
+                2,
+                &operator_key,
+            ))
+            .unwrap();
+        let mut unsigned = ThresholdUpdateRequest::new_signed(
+            ThresholdParams {
+                version:               2,
+                match_threshold_ratio: 0.01,
+            },
+            1,
+            &operator_key,
+        );
+        unsigned.signature = String::new();
+        harness.update_threshold(&unsigned).unwrap();
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results[0].threshold_version, Some(0));
+
+        // The last batch before the activation still uses the old threshold.
+        harness
+            .enroll(
+                "alice-again",
+                left.get_similar_iris(&mut rng),
+                right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(results[0].is_match);
+        assert_eq!(results[0].threshold_version, Some(0));
+
+        harness
+            .enroll(
+                "alice-third",
+                left.get_similar_iris(&mut rng),
+                right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert!(!results[0].is_match);
+        assert_eq!(results[0].threshold_version, Some(1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_configured_match_threshold() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut harness = TestHarness::new(5).await.unwrap();
+        harness.set_match_threshold(MatchThreshold::new(0.01).unwrap());
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness
+            .enroll(
+                "alice-again",
+                left.get_similar_iris(&mut rng),
+                right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| !r.is_match));
+        assert!(results.iter().all(|r| r.threshold_version == Some(0)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_canary_detects_threshold_misconfiguration() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut harness = TestHarness::new(6).await.unwrap();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let operator_key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        harness.set_threshold_operator_key(Some(operator_key.public_key().as_ref().to_vec()));
+
+        let (left, right) = random_iris_pair(&mut rng);
+        harness.enroll("alice", left, right).unwrap();
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        let mut canary = Canary::new(CANARY_SEED, 2);
+        for _ in 0..2 {
+            canary.run_round(&mut harness).await.unwrap();
+        }
+        assert!(canary.pending_purge().is_empty());
+
+        // A threshold so strict that noisy captures no longer match, from the
+        // batch after the one receiving the update on.
+        harness
+            .update_threshold(&ThresholdUpdateRequest::new_signed(
+                ThresholdParams {
+                    version:               1,
+                    match_threshold_ratio: 0.01,
+                },
+                harness.parties[0].batch_counter + 1,
+                &operator_key,
+            ))
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+        let failure = canary.run_round(&mut harness).await.unwrap_err();
+        assert_eq!(failure.step(), CanaryStep::Probe, "{}", failure);
+        // The enrollment and the unmatched probe are left for the purge.
+        assert_eq!(canary.pending_purge().len(), 2);
+
+        // The next round purges them before it fails the same way. The real
+        // identity is untouched.
+        let failure = canary.run_round(&mut harness).await.unwrap_err();
+        assert_eq!(failure.step(), CanaryStep::Probe);
+        let purged = harness
+            .db(0)
+            .left
+            .iter()
+            .filter(|iris| **iris == dummy_shares_for_deletion(0))
+            .count();
+        assert_eq!(purged, 4);
+        assert_ne!(harness.db(0).left[0], dummy_shares_for_deletion(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_share_audit_flags_corrupted_row() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut harness = TestHarness::new(7).await.unwrap();
+        for i in 0..4 {
+            let (left, right) = random_iris_pair(&mut rng);
+            harness.enroll(&format!("user-{}", i), left, right).unwrap();
+        }
+        harness.process_batch(8).await.unwrap();
+        harness.drain_agreed_results().unwrap();
+
+        harness.enable_share_audit([3; 32], 16, 2);
+        assert_eq!(harness.audit_shares().await.unwrap(), Vec::<u32>::new());
+
+        // Storage corruption of a single row at a single party.
+        harness.parties[1].db.right[2].code.coefs[4321] ^= 0x10;
+        assert_eq!(harness.audit_shares().await.unwrap(), vec![3]);
+
+        // The periodic audit after the second batch finds it as well.
+        assert_eq!(harness.last_share_audit(), None);
+        harness.process_batch(8).await.unwrap();
+        assert_eq!(harness.parties[0].batch_counter, 2);
+        assert_eq!(harness.last_share_audit(), Some(&[3][..]));
+
+        // Only the blinded check values went over the wire, no share bytes.
+        assert_eq!(harness.share_audit_messages.len(), 9);
+        for party in harness.parties.iter() {
+            for iris in party.db.left.iter().chain(party.db.right.iter()) {
+                let code: &[u8] = bytemuck::cast_slice(&iris.code.coefs[..16]);
+                let mask: &[u8] = bytemuck::cast_slice(&iris.mask.coefs[..16]);
+                for message in harness.share_audit_messages.iter() {
+                    assert!(message.len() < 4 * 64);
+                    assert!(!message.windows(code.len()).any(|w| w == code));
+                    assert!(!message.windows(mask.len()).any(|w| w == mask));
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_backfill_then_probe() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut harness = TestHarness::new(8).await.unwrap();
+        // Opening every comparison against a thousand identities takes too long.
+        harness.set_result_mode(ResultMode::CountOnly, true);
+        let identities = (0..1000)
+            .map(|_| random_iris_pair(&mut rng))
+            .collect::<Vec<_>>();
+        let mut share_files = vec![vec![]; 3];
+        for (i, (left, right)) in identities.iter().enumerate() {
+            let entries = harness.backfill_entries(&i.to_string(), left.clone(), right.clone());
+            for (share_file, entry) in share_files.iter_mut().zip(entries) {
+                share_file.push(entry);
+            }
+        }
+        for (party_id, share_file) in share_files.iter().enumerate() {
+            assert_eq!(
+                harness.backfill(party_id, share_file.clone()).accepted,
+                1000
+            );
+        }
+        // The queue is bounded. The extra entries only reach party 0, so the
+        // parties do not agree on them.
+        let extra = share_files[0][..100].to_vec();
+        assert_eq!(harness.backfill(0, extra).accepted, 24);
+
+        for _ in 0..4 {
+            harness.process_batch(256).await.unwrap();
+        }
+        let results = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(results.len(), 1000);
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.signup_id, format!("backfill-{}", i));
+            assert_eq!(result.serial_id, Some(i as u32 + 1));
+            assert!(!result.is_match);
+            assert_eq!(result.backfill, Some(true));
+        }
+        for party_id in 0..3 {
+            assert_eq!(harness.db(party_id).len(), 1000);
+        }
+
+        for (signup_id, index) in [("probe-first", 0), ("probe-last", 999)] {
+            let (left, right) = &identities[index];
+            harness
+                .enroll(
+                    signup_id,
+                    left.get_similar_iris(&mut rng),
+                    right.get_similar_iris(&mut rng),
+                )
+                .unwrap();
+        }
+        harness.process_batch(2).await.unwrap();
+        let probes = uniqueness_results(harness.drain_agreed_results().unwrap());
+        assert_eq!(probes.len(), 2);
+        for (signup_id, serial_id) in [("probe-first", 1), ("probe-last", 1000)] {
+            let probe = probes
+                .iter()
+                .find(|probe| probe.signup_id == signup_id)
+                .unwrap();
+            assert!(probe.is_match);
+            assert_eq!(probe.matched_serial_ids, Some(vec![serial_id]));
+            assert_eq!(probe.backfill, None);
+        }
+    }
+}
@@ -13,6 +13,7 @@ use crate::{
         galois_ring_pairwise_distance, galois_ring_to_rep3,
     },
     py_bindings::{io::read_bin, plaintext_store::from_ndjson_file},
+    share_store::{ShareStore, VecShareStore},
     shares::{
         ring_impl::RingElement,
         share::{DistanceShare, Share},
@@ -24,7 +25,7 @@ use hawk_pack::{
     graph_store::{graph_mem::Layer, GraphMem},
     GraphStore, HawkSearcher, VectorStore,
 };
-use iris_mpc_common::iris_db::db::IrisDB;
+use iris_mpc_common::{helpers::threshold::MatchThreshold, iris_db::db::IrisDB};
 use rand::{CryptoRng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fmt::Debug, sync::Arc, vec};
@@ -64,11 +65,11 @@ pub struct Query {
 type QueryRef = Arc<Query>;
 
 #[derive(Default, Clone)]
-pub struct Aby3NgStorePlayer {
-    points: Vec<GaloisRingPoint>,
+pub struct Aby3NgStorePlayer<S: ShareStore = VecShareStore> {
+    points: S,
 }
 
-impl std::fmt::Debug for Aby3NgStorePlayer {
+impl<S: ShareStore> std::fmt::Debug for Aby3NgStorePlayer<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.points.fmt(f)
     }
@@ -76,7 +77,15 @@ impl std::fmt::Debug for Aby3NgStorePlayer {
 
 impl Aby3NgStorePlayer {
     pub fn new_with_shared_db(data: Vec<GaloisRingSharedIris>) -> Self {
-        Aby3NgStorePlayer { points: data }
+        Aby3NgStorePlayer {
+            points: data.into(),
+        }
+    }
+}
+
+impl<S: ShareStore> Aby3NgStorePlayer<S> {
+    pub fn with_store(points: S) -> Self {
+        Aby3NgStorePlayer { points }
     }
 
     pub fn prepare_query(&mut self, raw_query: GaloisRingSharedIris) -> QueryRef {
@@ -90,17 +99,32 @@ impl Aby3NgStorePlayer {
         })
     }
 
-    pub fn get_vector(&self, vector: &VectorId) -> &GaloisRingPoint {
-        &self.points[vector.id]
+    pub fn get_vector(&self, vector: &VectorId) -> GaloisRingPoint {
+        self.points
+            .get(vector.id.0 as usize)
+            .unwrap_or_else(|| panic!("Vector {:?} is not in the store", vector))
+    }
+
+    pub fn get_vectors(&self, vectors: &[VectorId]) -> Vec<GaloisRingPoint> {
+        let ids = vectors.iter().map(|v| v.id.0 as usize).collect::<Vec<_>>();
+        self.points
+            .get_batch(&ids)
+            .into_iter()
+            .zip(vectors)
+            .map(|(point, vector)| {
+                point.unwrap_or_else(|| panic!("Vector {:?} is not in the store", vector))
+            })
+            .collect()
     }
 }
 
-impl Aby3NgStorePlayer {
+impl<S: ShareStore> Aby3NgStorePlayer<S> {
     fn insert(&mut self, query: &QueryRef) -> VectorId {
         // The query is now accepted in the store.
-        self.points.push(query.query.clone());
-
-        let new_id = self.points.len() - 1;
+        let new_id = self
+            .points
+            .append(query.query.clone())
+            .expect("Failed to store the query");
         VectorId { id: new_id.into() }
     }
 }
@@ -148,13 +172,13 @@ pub async fn setup_local_aby3_players_with_preloaded_db<R: RngCore + CryptoRng>(
 }
 
 #[derive(Debug, Clone)]
-pub struct LocalNetAby3NgStoreProtocol {
+pub struct LocalNetAby3NgStoreProtocol<S: ShareStore = VecShareStore> {
     pub owner:   Identity,
-    pub storage: Aby3NgStorePlayer,
+    pub storage: Aby3NgStorePlayer<S>,
     pub runtime: LocalRuntime,
 }
 
-impl LocalNetAby3NgStoreProtocol {
+impl<S: ShareStore> LocalNetAby3NgStoreProtocol<S> {
     pub fn get_owner_session(&self) -> Session {
         self.runtime.sessions.get(&self.owner).unwrap().clone()
     }
@@ -178,20 +202,41 @@ impl LocalNetAby3NgStoreProtocol {
 pub async fn setup_local_store_aby3_players(
     network_t: NetworkType,
 ) -> eyre::Result<Vec<LocalNetAby3NgStoreProtocol>> {
-    let runtime = LocalRuntime::mock_setup(network_t).await?;
+    let stores = generate_local_identities()
+        .iter()
+        .map(|_| VecShareStore::default())
+        .collect();
+    setup_local_aby3_players_with_stores(stores, network_t).await
+}
+
+/// Sets up the players with the given share stores, one per identity of
+/// [`generate_local_identities`] and in the same order.
+pub async fn setup_local_aby3_players_with_stores<S: ShareStore>(
+    stores: Vec<S>,
+    network_t: NetworkType,
+) -> eyre::Result<Vec<LocalNetAby3NgStoreProtocol<S>>> {
     let players = generate_local_identities();
+    if stores.len() != players.len() {
+        return Err(eyre::eyre!(
+            "Expected {} share stores, got {}",
+            players.len(),
+            stores.len()
+        ));
+    }
+    let runtime = LocalRuntime::mock_setup(network_t).await?;
     let local_stores = players
         .into_iter()
-        .map(|identity| LocalNetAby3NgStoreProtocol {
+        .zip(stores)
+        .map(|(identity, store)| LocalNetAby3NgStoreProtocol {
             runtime: runtime.clone(),
-            storage: Aby3NgStorePlayer::default(),
+            storage: Aby3NgStorePlayer::with_store(store),
             owner:   identity,
         })
         .collect();
     Ok(local_stores)
 }
 
-impl LocalNetAby3NgStoreProtocol {
+impl<S: ShareStore> LocalNetAby3NgStoreProtocol<S> {
     pub fn prepare_query(&mut self, code: GaloisRingSharedIris) -> QueryRef {
         self.storage.prepare_query(code)
     }
@@ -231,7 +276,7 @@ impl LocalNetAby3NgStoreProtocol {
     }
 }
 
-impl VectorStore for LocalNetAby3NgStoreProtocol {
+impl<S: ShareStore> VectorStore for LocalNetAby3NgStoreProtocol<S> {
     type QueryRef = QueryRef; // Point ID, pending insertion.
     type VectorRef = VectorId; // Point ID, inserted.
     type DistanceRef = DistanceShare<u32>; // Distance represented as shares.
@@ -246,7 +291,7 @@ impl VectorStore for LocalNetAby3NgStoreProtocol {
         vector: &Self::VectorRef,
     ) -> Self::DistanceRef {
         let vector_point = self.storage.get_vector(vector);
-        let pairs = vec![(query.processed_query.clone(), vector_point.clone())];
+        let pairs = vec![(query.processed_query.clone(), vector_point)];
         let dist = self.eval_pairwise_distances(pairs).await;
         self.lift_distances(dist).await.unwrap()[0].clone()
     }
@@ -259,12 +304,11 @@ impl VectorStore for LocalNetAby3NgStoreProtocol {
         if vectors.is_empty() {
             return vec![];
         }
-        let pairs = vectors
-            .iter()
-            .map(|vector_id| {
-                let vector_point = self.storage.get_vector(vector_id);
-                (query.processed_query.clone(), vector_point.clone())
-            })
+        let pairs = self
+            .storage
+            .get_vectors(vectors)
+            .into_iter()
+            .map(|vector_point| (query.processed_query.clone(), vector_point))
             .collect::<Vec<_>>();
         let dist = self.eval_pairwise_distances(pairs).await;
         self.lift_distances(dist).await.unwrap()
@@ -272,9 +316,13 @@ impl VectorStore for LocalNetAby3NgStoreProtocol {
 
     async fn is_match(&mut self, distance: &Self::DistanceRef) -> bool {
         let mut player_session = self.get_owner_session();
-        compare_threshold_and_open(&mut player_session, distance.clone())
-            .await
-            .unwrap()
+        compare_threshold_and_open(
+            &mut player_session,
+            distance.clone(),
+            MatchThreshold::default(),
+        )
+        .await
+        .unwrap()
     }
 
     async fn less_than(
@@ -299,7 +347,7 @@ impl VectorStore for LocalNetAby3NgStoreProtocol {
     }
 }
 
-impl LocalNetAby3NgStoreProtocol {
+impl<S: ShareStore> LocalNetAby3NgStoreProtocol<S> {
     pub fn get_trivial_share(&self, distance: u16) -> Share<u32> {
         let player = self.get_owner_index();
         let distance_elem = RingElement(distance as u32);
@@ -315,14 +363,14 @@ impl LocalNetAby3NgStoreProtocol {
 
     async fn eval_distance_vectors(
         &mut self,
-        vector1: &<LocalNetAby3NgStoreProtocol as VectorStore>::VectorRef,
-        vector2: &<LocalNetAby3NgStoreProtocol as VectorStore>::VectorRef,
-    ) -> <LocalNetAby3NgStoreProtocol as VectorStore>::DistanceRef {
+        vector1: &<Self as VectorStore>::VectorRef,
+        vector2: &<Self as VectorStore>::VectorRef,
+    ) -> <Self as VectorStore>::DistanceRef {
         let point1 = self.storage.get_vector(vector1);
-        let mut point2 = self.storage.get_vector(vector2).clone();
+        let mut point2 = self.storage.get_vector(vector2);
         point2.code.preprocess_iris_code_query_share();
         point2.mask.preprocess_mask_code_query_share();
-        let pairs = vec![(point1.clone(), point2.clone())];
+        let pairs = vec![(point1, point2)];
         let dist = self.eval_pairwise_distances(pairs).await;
         self.lift_distances(dist).await.unwrap()[0].clone()
     }
@@ -331,7 +379,7 @@ impl LocalNetAby3NgStoreProtocol {
         &mut self,
         graph_store: &GraphMem<PlaintextStore>,
         recompute_distances: bool,
-    ) -> GraphMem<LocalNetAby3NgStoreProtocol> {
+    ) -> GraphMem<Self> {
         let ep = graph_store.get_entry_point().await;
         let new_ep = ep.map(|(vector_ref, layer_count)| (VectorId { id: vector_ref }, layer_count));
 
@@ -595,7 +643,7 @@ impl LocalNetAby3NgStoreProtocol {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::database_generators::generate_galois_iris_shares;
+    use crate::{database_generators::generate_galois_iris_shares, share_store::MmapShareStore};
     use aes_prng::AesRng;
     use hawk_pack::{graph_store::GraphMem, HawkSearcher};
     use itertools::Itertools;
@@ -640,7 +688,7 @@ mod tests {
                 // Search for the same codes and find matches.
                 let mut matching_results = vec![];
                 for v in inserted.into_iter() {
-                    let query = store.prepare_query(store.storage.get_vector(&v).clone());
+                    let query = store.prepare_query(store.storage.get_vector(&v));
                     let neighbors = db.search(&mut store, &mut aby3_graph, &query, 1).await;
                     tracing::debug!("Finished checking query");
                     matching_results.push(db.is_match(&mut store, &[neighbors]).await)
@@ -660,6 +708,77 @@ mod tests {
         }
     }
 
+    /// Inserts random irises into the graph, then searches for each of them
+    /// and returns the nearest neighbor and whether it matches.
+    async fn search_inserted<S: ShareStore>(stores: Vec<S>) -> Vec<Vec<(Option<VectorId>, bool)>> {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let database_size = 10;
+        let cleartext_database = IrisDB::new_random_rng(database_size, &mut rng).db;
+        let shared_irises: Vec<_> = cleartext_database
+            .iter()
+            .map(|iris| generate_galois_iris_shares(&mut rng, iris.clone()))
+            .collect();
+
+        let mut stores = setup_local_aby3_players_with_stores(stores, NetworkType::LocalChannel)
+            .await
+            .unwrap();
+
+        let mut jobs = JoinSet::new();
+        for store in stores.iter_mut() {
+            let player_index = store.get_owner_index();
+            let queries = (0..database_size)
+                .map(|id| store.prepare_query(shared_irises[id][player_index].clone()))
+                .collect::<Vec<_>>();
+            let mut store = store.clone();
+            let mut rng = rng.clone();
+            jobs.spawn(async move {
+                let mut graph = GraphMem::new();
+                let searcher = HawkSearcher::default();
+                let mut inserted = vec![];
+                for query in queries.iter() {
+                    inserted.push(
+                        searcher
+                            .insert(&mut store, &mut graph, query, &mut rng)
+                            .await,
+                    );
+                }
+                let mut results = vec![];
+                for v in inserted.into_iter() {
+                    let query = store.prepare_query(store.storage.get_vector(&v));
+                    let neighbors = searcher.search(&mut store, &mut graph, &query, 1).await;
+                    let nearest = neighbors.get_nearest().map(|(v, _)| *v);
+                    results.push((nearest, searcher.is_match(&mut store, &[neighbors]).await));
+                }
+                (player_index, results)
+            });
+        }
+        let mut results = jobs.join_all().await;
+        results.sort_by_key(|(player_index, _)| *player_index);
+        results.into_iter().map(|(_, results)| results).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_gr_hnsw_share_stores() {
+        let in_memory = search_inserted(vec![VecShareStore::default(); 3]).await;
+
+        let dirs = (0..3)
+            .map(|i| {
+                std::env::temp_dir().join(format!("hnsw-shares-{}-{}", i, uuid::Uuid::new_v4()))
+            })
+            .collect::<Vec<_>>();
+        let stores = dirs
+            .iter()
+            .map(|dir| MmapShareStore::open(dir).unwrap())
+            .collect();
+        let mmap = search_inserted(stores).await;
+        for dir in dirs {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        assert_eq!(in_memory, mmap);
+        assert!(in_memory.iter().flatten().all(|(_, is_match)| *is_match));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     #[traced_test]
     async fn test_gr_premade_hnsw() {
@@ -703,7 +822,7 @@ mod tests {
                 let hawk_searcher = hawk_searcher.clone();
                 let mut v = v.clone();
                 let mut g = g.clone();
-                let q = v.prepare_query(v.storage.get_vector(&i.into()).clone());
+                let q = v.prepare_query(v.storage.get_vector(&i.into()));
                 jobs.spawn(async move {
                     let secret_neighbors = hawk_searcher.search(&mut v, &mut g, &q, 1).await;
 
@@ -718,7 +837,7 @@ mod tests {
                 let mut v = v.clone();
                 let mut g = g.clone();
                 jobs.spawn(async move {
-                    let query = v.prepare_query(v.storage.get_vector(&i.into()).clone());
+                    let query = v.prepare_query(v.storage.get_vector(&i.into()));
                     let secret_neighbors = hawk_searcher.search(&mut v, &mut g, &query, 1).await;
 
                     hawk_searcher.is_match(&mut v, &[secret_neighbors]).await
@@ -848,7 +967,7 @@ mod tests {
                 let mut store = store.clone();
                 let mut graph = graph.clone();
                 let searcher = searcher.clone();
-                let q = store.prepare_query(store.storage.get_vector(&i.into()).clone());
+                let q = store.prepare_query(store.storage.get_vector(&i.into()));
                 jobs.spawn(async move {
                     let secret_neighbors = searcher.search(&mut store, &mut graph, &q, 1).await;
                     searcher.is_match(&mut store, &[secret_neighbors]).await
@@ -13,15 +13,14 @@ pub struct PlaintextIris(pub IrisCode);
 
 impl PlaintextIris {
     /// Return the fractional Hamming distance with another PlaintextIris,
-    /// represented as u16 numerator and denominator.
+    /// represented as u16 numerator and denominator, both zero if no bit is
+    /// unmasked in both codes.
     pub fn distance_fraction(&self, other: &Self) -> (u16, u16) {
-        let combined_mask = self.0.mask & other.0.mask;
-        let combined_mask_len = combined_mask.count_ones();
-
-        let combined_code = (self.0.code ^ other.0.code) & combined_mask;
-        let code_distance = combined_code.count_ones();
-
-        (code_distance as u16, combined_mask_len as u16)
+        self.0
+            .masked_distance(&other.0)
+            .map_or((0, 0), |(code_distance, combined_mask_len, _)| {
+                (code_distance as u16, combined_mask_len as u16)
+            })
     }
 
     /// Return the fractional Hamming distance with another PlaintextIris,
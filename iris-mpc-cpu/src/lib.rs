@@ -1,9 +1,33 @@
+//! Secure iris matching among three parties on the CPU.
+//!
+//! External integrators should only rely on [`prelude`]; the remaining
+//! modules are internal and hidden from the documentation.
+
+#[doc(hidden)]
 pub mod database_generators;
+#[doc(hidden)]
 pub mod execution;
+#[cfg(any(test, feature = "harness"))]
+#[doc(hidden)]
+pub mod harness;
+#[doc(hidden)]
 pub mod hawkers;
 pub(crate) mod network;
+pub mod prelude;
 #[rustfmt::skip]
 pub(crate) mod proto_generated;
+#[doc(hidden)]
 pub mod protocol;
+#[doc(hidden)]
 pub mod py_bindings;
+#[cfg(any(test, feature = "harness"))]
+#[doc(hidden)]
+pub mod replay;
+#[doc(hidden)]
+pub mod scoring;
+#[doc(hidden)]
+pub mod share_store;
 pub(crate) mod shares;
+#[cfg(any(test, feature = "harness"))]
+#[doc(hidden)]
+pub mod soak;
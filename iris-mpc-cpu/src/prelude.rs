@@ -0,0 +1,38 @@
+//! The public API of the CPU matching engine, for services that embed it
+//! without the rest of the pipeline.
+//!
+//! Everything re-exported here is kept stable: the construction of the local
+//! three-party runtime and its sessions, the shares the parties are fed with,
+//! the secret-shared vector store for the HNSW graph of `hawk_pack`, the
+//! scoring of explicit pairs, and the in-process transport for testing. The
+//! other modules of this crate are exposed for the binaries and tests of this
+//! workspace only and may change at any time.
+
+pub use crate::{
+    database_generators::{generate_galois_iris_shares, GaloisRingSharedIris},
+    execution::{
+        local::{generate_local_identities, LocalRuntime},
+        player::{Identity, Role},
+        session::{Session, SessionId},
+    },
+    hawkers::{
+        galois_store::{
+            setup_local_aby3_players_with_preloaded_db, setup_local_aby3_players_with_stores,
+            setup_local_store_aby3_players, Aby3NgStorePlayer, LocalNetAby3NgStoreProtocol, Query,
+            VectorId,
+        },
+        plaintext_store::{PlaintextStore, PointId},
+    },
+    network::{
+        local::{LocalNetworking, LocalNetworkingStore},
+        NetworkType, Networking,
+    },
+    scoring::{score_pairs, MAX_SCORE_PAIRS},
+    share_store::{MmapShareStore, ShareStore, VecShareStore},
+    shares::share::Share,
+};
+pub use hawk_pack::{graph_store::GraphMem, GraphStore, HawkSearcher, VectorStore};
+pub use iris_mpc_common::{
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    iris_db::iris::IrisCode,
+};
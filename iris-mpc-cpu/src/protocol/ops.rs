@@ -1,4 +1,6 @@
-use super::binary::{mul_lift_2k, single_extract_msb_u32};
+use super::binary::{
+    and_many, bit_inject_ot_2round, extract_msb_u32, mul_lift_2k, single_extract_msb_u32,
+};
 use crate::{
     database_generators::GaloisRingSharedIris,
     execution::session::{BootSession, Session, SessionHandles},
@@ -15,11 +17,11 @@ use crate::{
     },
 };
 use eyre::eyre;
+use iris_mpc_common::helpers::threshold::MatchThreshold;
+use num_traits::Zero;
 
-pub(crate) const MATCH_THRESHOLD_RATIO: f64 = iris_mpc_common::iris_db::iris::MATCH_THRESHOLD_RATIO;
-pub(crate) const B_BITS: u64 = 16;
-pub(crate) const B: u64 = 1 << B_BITS;
-pub(crate) const A: u64 = ((1. - 2. * MATCH_THRESHOLD_RATIO) * B as f64) as u64;
+pub(crate) const B_BITS: u64 = MatchThreshold::B_BITS;
+pub(crate) const B: u64 = MatchThreshold::B;
 
 /// Setup the PRF seeds in the replicated protocol.
 /// Each party sends to the next party a random seed.
@@ -55,21 +57,41 @@ pub async fn setup_replicated_prf(session: &BootSession, my_seed: PrfSeed) -> ey
 /// - Takes as input two code and mask dot products between two Irises: i, j.
 ///   i.e. code_dot = <i.code, j.code> and mask_dot = <i.mask, j.mask>.
 /// - Lifts the two dot products to the ring Z_{2^32}.
-/// - Multiplies with predefined threshold constants B = 2^16 and A = ((1. - 2.
-///   * MATCH_THRESHOLD_RATIO) * B as f64).
+/// - Multiplies with the threshold constants B = 2^16 and A, see
+///   [`MatchThreshold::a`].
 /// - Compares mask_dot * A < code_dot * B.
 pub async fn compare_threshold(
     session: &mut Session,
     code_dot: Share<u32>,
     mask_dot: Share<u32>,
+    threshold: MatchThreshold,
 ) -> eyre::Result<Share<Bit>> {
-    let mut x = mask_dot * A as u32;
+    let mut x = mask_dot * threshold.a() as u32;
     let y = code_dot * B as u32;
     x -= y;
 
     single_extract_msb_u32::<32>(session, x).await
 }
 
+/// The batched version of compare_threshold. Returns the comparison bits
+/// packed into u64 words, the i-th distance at bit i % 64 of word i / 64.
+pub async fn compare_threshold_many(
+    session: &mut Session,
+    distances: Vec<DistanceShare<u32>>,
+    threshold: MatchThreshold,
+) -> eyre::Result<VecShare<u64>> {
+    let a = threshold.a() as u32;
+    let diffs = distances
+        .into_iter()
+        .map(|distance| {
+            let mut x = distance.mask_dot * a;
+            x -= distance.code_dot * B as u32;
+            x
+        })
+        .collect();
+    extract_msb_u32::<32>(session, VecShare::new_vec(diffs)).await
+}
+
 /// The same as compare_threshold, but the input shares are 16-bit and lifted to
 /// 32-bit before threshold comparison.
 ///
@@ -78,11 +100,12 @@ pub async fn lift_and_compare_threshold(
     session: &mut Session,
     code_dot: Share<u16>,
     mask_dot: Share<u16>,
+    threshold: MatchThreshold,
 ) -> eyre::Result<Share<Bit>> {
     let y = mul_lift_2k::<B_BITS>(&code_dot);
     let mut x = lift::<{ B_BITS as usize }>(session, VecShare::new_vec(vec![mask_dot])).await?;
     let mut x = x.pop().expect("Expected a single element in the VecShare");
-    x *= A as u32;
+    x *= threshold.a() as u32;
     x -= y;
 
     single_extract_msb_u32::<32>(session, x).await
@@ -244,31 +267,184 @@ pub async fn galois_ring_to_rep3(
 /// entry. This is done in the following manner:
 /// - Compute the dot product between the two Irises.
 /// - Convert the partial Shamir share result to a replicated sharing and then
-/// - Compare the distance using the MATCH_THRESHOLD_RATIO from the
+/// - Compare the distance to the threshold with the
 ///   `lift_and_compare_threshold` function.
 pub async fn galois_ring_is_match(
     session: &mut Session,
     pairs: &[(GaloisRingSharedIris, GaloisRingSharedIris)],
+    threshold: MatchThreshold,
 ) -> eyre::Result<bool> {
     assert_eq!(pairs.len(), 1);
     let additive_dots = galois_ring_pairwise_distance(session, pairs).await?;
     let rep_dots = galois_ring_to_rep3(session, additive_dots).await?;
     // compute dots[0] - dots[1]
-    let bit = lift_and_compare_threshold(session, rep_dots[0].clone(), rep_dots[1].clone()).await?;
+    let bit =
+        lift_and_compare_threshold(session, rep_dots[0].clone(), rep_dots[1].clone(), threshold)
+            .await?;
     let opened = open_bin(session, bit).await?;
     Ok(opened.convert())
 }
 
+/// How a comparison of [`galois_ring_match_decision`] was decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchDecision {
+    Match,
+    NonMatch,
+    /// Fewer bits than the floor are unmasked in both irises, so the
+    /// comparison is a non-match whatever the distance.
+    MaskTooSmall,
+}
+
+impl MatchDecision {
+    pub fn is_match(&self) -> bool {
+        *self == MatchDecision::Match
+    }
+
+    /// The reason code reported for the decision.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            MatchDecision::Match => "match",
+            MatchDecision::NonMatch => "non_match",
+            MatchDecision::MaskTooSmall => "mask_too_small",
+        }
+    }
+}
+
+/// The outcome of [`galois_ring_match_decision`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchReport {
+    pub decision:  MatchDecision,
+    /// The number of bits unmasked in both irises, i.e. the opened mask dot
+    /// product.
+    pub mask_bits: u16,
+}
+
+/// Like [`galois_ring_is_match`], but also opens the mask dot product, and
+/// treats the comparison as a non-match if fewer than `min_mask_bits` bits
+/// are unmasked in both irises. Opening the mask dot product reveals the
+/// number of common unmasked bits to all parties.
+pub async fn galois_ring_match_decision(
+    session: &mut Session,
+    pairs: &[(GaloisRingSharedIris, GaloisRingSharedIris)],
+    threshold: MatchThreshold,
+    min_mask_bits: u16,
+) -> eyre::Result<MatchReport> {
+    assert_eq!(pairs.len(), 1);
+    let additive_dots = galois_ring_pairwise_distance(session, pairs).await?;
+    let rep_dots = galois_ring_to_rep3(session, additive_dots).await?;
+    let bit =
+        lift_and_compare_threshold(session, rep_dots[0].clone(), rep_dots[1].clone(), threshold)
+            .await?;
+    let is_match: bool = open_bin(session, bit).await?.convert();
+    let mask_bits = open_u16(session, rep_dots[1].clone()).await?;
+    let decision = if mask_bits < min_mask_bits {
+        MatchDecision::MaskTooSmall
+    } else if is_match {
+        MatchDecision::Match
+    } else {
+        MatchDecision::NonMatch
+    };
+    Ok(MatchReport {
+        decision,
+        mask_bits,
+    })
+}
+
 /// Compares the given distance to a threshold and reveal the result.
 pub async fn compare_threshold_and_open(
     session: &mut Session,
     distance: DistanceShare<u32>,
+    threshold: MatchThreshold,
 ) -> eyre::Result<bool> {
-    let bit = compare_threshold(session, distance.code_dot, distance.mask_dot).await?;
+    let bit = compare_threshold(session, distance.code_dot, distance.mask_dot, threshold).await?;
     let opened = open_bin(session, bit).await?;
     Ok(opened.convert())
 }
 
+/// Computes the OR of two packed bit vectors as a ^ b ^ (a & b).
+pub async fn or_many(
+    session: &mut Session,
+    a: VecShare<u64>,
+    b: VecShare<u64>,
+) -> eyre::Result<VecShare<u64>> {
+    let and = and_many(session, a.as_slice(), b.as_slice()).await?;
+    Ok(a ^ b ^ and)
+}
+
+/// Reduces the packed bit vectors to their elementwise OR, in a tree of
+/// depth log2(x.len()).
+pub async fn or_tree_many(
+    session: &mut Session,
+    mut x: Vec<VecShare<u64>>,
+) -> eyre::Result<VecShare<u64>> {
+    if x.is_empty() {
+        return Err(eyre!("Cannot compute the OR of zero vectors"));
+    }
+    while x.len() > 1 {
+        let odd = if x.len() % 2 == 1 { x.pop() } else { None };
+        let half = x.len() / 2;
+        let rhs = x.split_off(half);
+        let lhs = VecShare::flatten(x);
+        let rhs = VecShare::flatten(rhs);
+        let len = lhs.len();
+        let res = or_many(session, lhs, rhs).await?;
+        x = res
+            .inner()
+            .chunks(len / half)
+            .map(|chunk| VecShare::new_vec(chunk.to_vec()))
+            .collect();
+        x.extend(odd);
+    }
+    Ok(x.pop().unwrap())
+}
+
+/// Counts the set bits among the first `len` bits of the packed vector. The
+/// bits are injected into Z_{2^16} and summed up locally, so only the count
+/// is ever opened.
+pub async fn secure_popcount(
+    session: &mut Session,
+    bits: VecShare<u64>,
+    len: usize,
+) -> eyre::Result<Share<u16>> {
+    if len > u16::MAX as usize {
+        return Err(eyre!("Cannot count {} bits in a u16", len));
+    }
+    if len == 0 {
+        return Ok(Share::zero());
+    }
+    let mut bits = bits.convert_to_bits();
+    bits.truncate(len);
+    let injected = bit_inject_ot_2round(session, bits).await?;
+    Ok(injected.sum())
+}
+
+/// Opens a replicated share of a u16 to all parties.
+pub async fn open_u16(session: &mut Session, share: Share<u16>) -> eyre::Result<u16> {
+    // send to next_party
+    let next_party = session.next_identity()?;
+    let network = session.network().clone();
+    let sid = session.session_id();
+    network
+        .send(
+            NetworkValue::RingElement16(share.b).to_network(),
+            &next_party,
+            &sid,
+        )
+        .await?;
+
+    // receiving from previous party
+    let prev_party = session.prev_identity()?;
+    let c = {
+        let serialized_other_share = network.receive(&prev_party, &sid).await;
+        match NetworkValue::from_network(serialized_other_share) {
+            Ok(NetworkValue::RingElement16(message)) => Ok(message),
+            _ => Err(eyre!("Error in receiving in open_u16 operation")),
+        }
+    }?;
+
+    Ok((share.a + share.b + c).convert())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -283,7 +459,13 @@ mod tests {
         shares::{int_ring::IntRing2k, ring_impl::RingElement},
     };
     use aes_prng::AesRng;
-    use iris_mpc_common::iris_db::db::IrisDB;
+    use iris_mpc_common::{
+        helpers::{
+            conformance::{ConformanceFixtures, DotShares},
+            reference,
+        },
+        iris_db::db::IrisDB,
+    };
     use rand::{Rng, RngCore, SeedableRng};
     use rstest::rstest;
     use std::collections::HashMap;
@@ -583,4 +765,185 @@ mod tests {
         assert_eq!(output0.1[0], plain_d1 as u16);
         assert_eq!(output0.1[1], plain_d2);
     }
+
+    #[tokio::test]
+    async fn test_galois_ring_is_match_with_threshold() {
+        let runtime = LocalRuntime::mock_setup_with_channel().await.unwrap();
+        let mut rng = AesRng::seed_from_u64(0);
+
+        let iris = IrisDB::new_random_rng(1, &mut rng).db.pop().unwrap();
+        let (noisy, _) = iris.with_noise(0.3, 0.0, &mut rng);
+        let distance = iris.get_distance(&noisy);
+        let thresholds = [
+            MatchThreshold::new(distance - 0.02).unwrap(),
+            MatchThreshold::default(),
+            MatchThreshold::new(distance + 0.02).unwrap(),
+        ];
+        let (code_dot, mask_dot) = reference::dots(&iris, &noisy);
+
+        let first_entry = generate_galois_iris_shares(&mut rng, iris);
+        let second_entry = generate_galois_iris_shares(&mut rng, noisy);
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().cloned().enumerate() {
+            let mut player_session = runtime.sessions.get(&player).unwrap().clone();
+            let mut pair = (first_entry[index].clone(), second_entry[index].clone());
+            pair.1.code.preprocess_iris_code_query_share();
+            pair.1.mask.preprocess_mask_code_query_share();
+            jobs.spawn(async move {
+                let mut matches = vec![];
+                for threshold in thresholds {
+                    let pairs = [pair.clone()];
+                    matches.push(
+                        galois_ring_is_match(&mut player_session, &pairs, threshold)
+                            .await
+                            .unwrap(),
+                    );
+                }
+                matches
+            });
+        }
+        let expected = thresholds
+            .map(|t| reference::masked_match(code_dot, mask_dot, t))
+            .to_vec();
+        assert!(!expected[0] && expected[2]);
+        while let Some(matches) = jobs.join_next().await {
+            assert_eq!(matches.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_galois_ring_match_decision() {
+        let runtime = LocalRuntime::mock_setup_with_channel().await.unwrap();
+        let mut rng = AesRng::seed_from_u64(1);
+
+        let iris = IrisDB::new_random_rng(1, &mut rng).db.pop().unwrap();
+        let (noisy, _) = iris.with_noise(0.1, 0.0, &mut rng);
+        let (_, mask_bits) = iris.hamming_and_mask_bits(&noisy);
+        let mask_bits = mask_bits as u16;
+        let floors = [0, mask_bits, mask_bits + 1];
+
+        let first_entry = generate_galois_iris_shares(&mut rng, iris);
+        let second_entry = generate_galois_iris_shares(&mut rng, noisy);
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().cloned().enumerate() {
+            let mut player_session = runtime.sessions.get(&player).unwrap().clone();
+            let mut pair = (first_entry[index].clone(), second_entry[index].clone());
+            pair.1.code.preprocess_iris_code_query_share();
+            pair.1.mask.preprocess_mask_code_query_share();
+            jobs.spawn(async move {
+                let mut reports = vec![];
+                for floor in floors {
+                    let pairs = [pair.clone()];
+                    reports.push(
+                        galois_ring_match_decision(
+                            &mut player_session,
+                            &pairs,
+                            MatchThreshold::default(),
+                            floor,
+                        )
+                        .await
+                        .unwrap(),
+                    );
+                }
+                reports
+            });
+        }
+        let expected = [
+            MatchDecision::Match,
+            MatchDecision::Match,
+            MatchDecision::MaskTooSmall,
+        ]
+        .map(|decision| MatchReport {
+            decision,
+            mask_bits,
+        })
+        .to_vec();
+        while let Some(reports) = jobs.join_next().await {
+            let reports = reports.unwrap();
+            assert_eq!(reports, expected);
+            assert!(!reports[2].decision.is_match());
+            assert_eq!(reports[2].decision.reason(), "mask_too_small");
+        }
+    }
+
+    /// Opens packed binary shares to all parties.
+    async fn open_bin_many(session: &Session, x: VecShare<u64>) -> eyre::Result<Vec<u64>> {
+        let network = session.network();
+        let next_role = session.identity(&session.own_role()?.next(3))?;
+        let prev_role = session.identity(&session.own_role()?.prev(3))?;
+        let shares = x.inner();
+        let shares_b = shares.iter().map(|s| s.b).collect();
+        network
+            .send(
+                NetworkValue::VecRing64(shares_b).to_network(),
+                next_role,
+                &session.session_id(),
+            )
+            .await?;
+        let serialized_reply = network.receive(prev_role, &session.session_id()).await;
+        let shares_c = match NetworkValue::from_network(serialized_reply) {
+            Ok(NetworkValue::VecRing64(elements)) => elements,
+            _ => return Err(eyre!("Could not deserialize VecRing64")),
+        };
+        Ok(shares
+            .into_iter()
+            .zip(shares_c)
+            .map(|(s, c)| (s.a ^ s.b ^ c).convert())
+            .collect())
+    }
+
+    fn rep_shares(a: &[u16], b: &[u16]) -> Vec<Share<u16>> {
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| Share::new(RingElement(a), RingElement(b)))
+            .collect()
+    }
+
+    /// Runs the golden vectors shared with the GPU circuits, see
+    /// `iris-mpc-gpu/tests/conformance.rs`, through the lifting and the
+    /// threshold comparison. The lanes beyond the fixtures in the last packed
+    /// word are padding and must not match.
+    #[tokio::test]
+    async fn test_threshold_conformance() {
+        let fixtures = ConformanceFixtures::load().unwrap();
+        for set in fixtures.sets {
+            let runtime = LocalRuntime::mock_setup_with_channel().await.unwrap();
+            let mut jobs = JoinSet::new();
+            for (index, player) in runtime.identities.iter().cloned().enumerate() {
+                let mut session = runtime.sessions.get(&player).unwrap().clone();
+                let DotShares {
+                    code_a,
+                    code_b,
+                    mask_a,
+                    mask_b,
+                } = set.shares[index].clone();
+                let threshold = set.threshold;
+                jobs.spawn(async move {
+                    let len = code_a.len();
+                    let mut dots = rep_shares(&code_a, &code_b);
+                    dots.extend(rep_shares(&mask_a, &mask_b));
+                    let mut dots = batch_signed_lift_vec(&mut session, dots).await.unwrap();
+                    let mask_dots = dots.split_off(len);
+                    let distances = dots
+                        .into_iter()
+                        .zip(mask_dots)
+                        .map(|(code_dot, mask_dot)| DistanceShare::new(code_dot, mask_dot))
+                        .collect();
+                    let bits = compare_threshold_many(&mut session, distances, threshold)
+                        .await
+                        .unwrap();
+                    open_bin_many(&session, bits).await.unwrap()
+                });
+            }
+            while let Some(words) = jobs.join_next().await {
+                let decisions = words
+                    .unwrap()
+                    .iter()
+                    .flat_map(|word| (0..64).map(move |i| (word >> i) & 1 == 1))
+                    .collect::<Vec<_>>();
+                assert_eq!(decisions.len(), set.len().next_multiple_of(64));
+                set.check(&decisions).unwrap();
+            }
+        }
+    }
 }
@@ -0,0 +1,199 @@
+//! Replay of recorded production batches against the in-process pipeline.
+//!
+//! The parties record their batches with `replay_recording_dir` in the server
+//! config, see [`iris_mpc_common::helpers::replay`]. [`replay`] runs the
+//! recorded batches of all three parties through the [`TestHarness`] and
+//! stops at the first decision that differs from the recording.
+
+use crate::harness::TestHarness;
+use eyre::{bail, ensure};
+use iris_mpc_common::helpers::replay::{first_divergence, Divergence, ReplayBundle};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of batches replayed, including a diverging one.
+    pub batches:    usize,
+    pub divergence: Option<Divergence>,
+}
+
+/// Replays the bundles of the three parties, in batch order. The replay
+/// starts from an empty database, so the bundles must cover every batch since
+/// the database was empty.
+pub async fn replay(bundles: &[ReplayBundle]) -> eyre::Result<ReplayReport> {
+    ensure!(bundles.len() == 3, "Expected the bundles of three parties");
+    let mut bundles = bundles.iter().collect::<Vec<_>>();
+    bundles.sort_by_key(|bundle| bundle.party_id);
+    for (party_id, bundle) in bundles.iter().enumerate() {
+        ensure!(
+            bundle.party_id == party_id,
+            "Expected one bundle of every party, got party {}",
+            bundle.party_id
+        );
+        ensure!(
+            bundle.settings == bundles[0].settings,
+            "Party {} recorded with other settings",
+            party_id
+        );
+        ensure!(
+            bundle
+                .batches
+                .iter()
+                .map(|batch| batch.batch_id)
+                .eq(bundles[0].batches.iter().map(|batch| batch.batch_id)),
+            "Party {} recorded other batches",
+            party_id
+        );
+    }
+
+    let mut harness = TestHarness::new(0).await?;
+    harness.apply_recorded_settings(&bundles[0].settings);
+    for index in 0..bundles[0].batches.len() {
+        let recorded = bundles
+            .iter()
+            .map(|bundle| &bundle.batches[index])
+            .collect::<Vec<_>>();
+        for (party_id, batch) in recorded.iter().enumerate() {
+            if batch.db_digest_before != harness.db_digest(party_id) {
+                bail!(
+                    "The database of party {} differs from the recording before batch {}",
+                    party_id,
+                    batch.batch_id
+                );
+            }
+        }
+
+        let replayed = harness.replay_batch(&recorded).await?;
+        let divergence = recorded.iter().zip(replayed.iter()).enumerate().find_map(
+            |(party_id, (recorded, replayed))| {
+                first_divergence(party_id, recorded, &replayed.decisions)
+            },
+        );
+        if divergence.is_some() {
+            return Ok(ReplayReport {
+                batches: index + 1,
+                divergence,
+            });
+        }
+        for (party_id, (recorded, replayed)) in recorded.iter().zip(replayed.iter()).enumerate() {
+            if recorded.deleted_serial_ids != replayed.deleted_serial_ids
+                || recorded.db_digest_after != replayed.db_digest_after
+            {
+                bail!(
+                    "The database of party {} differs from the recording after batch {}",
+                    party_id,
+                    recorded.batch_id
+                );
+            }
+        }
+    }
+    Ok(ReplayReport {
+        batches:    bundles[0].batches.len(),
+        divergence: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iris_mpc_common::{
+        helpers::replay::{decode_public_key, decode_secret_key, generate_debug_keys},
+        iris_db::iris::IrisCode,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    async fn recorded_bundles() -> Vec<ReplayBundle> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut harness = TestHarness::new(0).await.unwrap();
+        harness.enable_mirrored_checks(true);
+        harness.enable_recording();
+
+        let left = IrisCode::random_rng(&mut rng);
+        let right = IrisCode::random_rng(&mut rng);
+        harness
+            .enroll("alice", left.clone(), right.clone())
+            .unwrap();
+        harness
+            .enroll(
+                "bob",
+                IrisCode::random_rng(&mut rng),
+                IrisCode::random_rng(&mut rng),
+            )
+            .unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        harness
+            .enroll(
+                "alice-again",
+                left.get_similar_iris(&mut rng),
+                right.get_similar_iris(&mut rng),
+            )
+            .unwrap();
+        harness.delete(2).unwrap();
+        harness
+            .enroll(
+                "carol",
+                IrisCode::random_rng(&mut rng),
+                IrisCode::random_rng(&mut rng),
+            )
+            .unwrap();
+        harness.fail_share_download(1, "carol").unwrap();
+        harness.process_batch(8).await.unwrap();
+
+        (0..3)
+            .map(|party_id| harness.recording(party_id).unwrap().clone())
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replay_matches_recording() {
+        let bundles = recorded_bundles().await;
+        assert_eq!(bundles[0].batches.len(), 2);
+        assert_eq!(bundles[0].batches[1].requests.len(), 3);
+
+        // The bundles survive sealing to the debugging key.
+        let (public, secret) = generate_debug_keys();
+        let (public, secret) = (
+            decode_public_key(&public).unwrap(),
+            decode_secret_key(&secret).unwrap(),
+        );
+        let bundles = bundles
+            .iter()
+            .map(|bundle| ReplayBundle::open(&bundle.seal(&public), &secret).unwrap())
+            .collect::<Vec<_>>();
+
+        let report = replay(&bundles).await.unwrap();
+        assert_eq!(report, ReplayReport {
+            batches:    2,
+            divergence: None,
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replay_pinpoints_mutated_decision() {
+        let mut bundles = recorded_bundles().await;
+        let decision = &mut bundles[2].batches[1].decisions[0];
+        assert_eq!(decision.request_id, "alice-again");
+        assert!(decision.is_match);
+        decision.is_match = false;
+
+        let report = replay(&bundles).await.unwrap();
+        assert_eq!(report.batches, 2);
+        let divergence = report.divergence.unwrap();
+        assert_eq!(divergence.batch_id, 1);
+        assert_eq!(divergence.party_id, 2);
+        assert_eq!(divergence.index, 0);
+        assert_eq!(divergence.request_id.as_deref(), Some("alice-again"));
+        assert!(divergence.replayed.unwrap().is_match);
+        assert!(!divergence.recorded.unwrap().is_match);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replay_rejects_partial_bundles() {
+        let mut bundles = recorded_bundles().await;
+        assert!(replay(&bundles[..2]).await.is_err());
+        for bundle in bundles.iter_mut() {
+            bundle.batches.remove(0);
+        }
+        assert!(replay(&bundles).await.is_err());
+    }
+}
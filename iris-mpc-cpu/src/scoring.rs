@@ -0,0 +1,154 @@
+//! Scoring of explicit pairs of shared irises, without thresholding.
+//!
+//! [`score_pairs`] returns the secret-shared code and mask dot products of the
+//! pairs it is given. Nothing is opened; the caller decides whether and when
+//! the scores are revealed.
+
+use crate::{
+    database_generators::GaloisRingSharedIris,
+    execution::session::Session,
+    protocol::ops::{batch_signed_lift_vec, galois_ring_pairwise_distance, galois_ring_to_rep3},
+    shares::share::Share,
+};
+use eyre::ensure;
+
+/// Maximum number of pairs per call of [`score_pairs`], which bounds the
+/// memory and the traffic of a single call.
+pub const MAX_SCORE_PAIRS: usize = 1024;
+
+/// Computes replicated shares of the masked dot products of the given
+/// (database iris, query iris) pairs. The query shares must be preprocessed
+/// with `preprocess_iris_code_query_share` and
+/// `preprocess_mask_code_query_share`, like all queries.
+///
+/// Returns two shares per pair, in the order of the pairs: the dot product of
+/// the codes over the common unmasked bits, then the number of common
+/// unmasked bits. Both are signed values lifted to 32 bits.
+pub async fn score_pairs(
+    session: &mut Session,
+    pairs: &[(GaloisRingSharedIris, GaloisRingSharedIris)],
+) -> eyre::Result<Vec<Share<u32>>> {
+    ensure!(
+        pairs.len() <= MAX_SCORE_PAIRS,
+        "{} pairs exceed the limit of {} per call",
+        pairs.len(),
+        MAX_SCORE_PAIRS
+    );
+    let dots = galois_ring_pairwise_distance(session, pairs).await?;
+    let dots = galois_ring_to_rep3(session, dots).await?;
+    batch_signed_lift_vec(session, dots).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database_generators::generate_galois_iris_shares,
+        execution::{local::LocalRuntime, session::SessionHandles},
+        network::value::NetworkValue,
+    };
+    use aes_prng::AesRng;
+    use iris_mpc_common::iris_db::iris::{IrisCode, IrisCodeArray};
+    use rand::SeedableRng;
+    use tokio::task::JoinSet;
+
+    async fn open_many(session: &Session, shares: Vec<Share<u32>>) -> eyre::Result<Vec<u32>> {
+        let network = session.network().clone();
+        let sid = session.session_id();
+        let message = shares.iter().map(|share| share.b).collect::<Vec<_>>();
+        network
+            .send(
+                NetworkValue::VecRing32(message).to_network(),
+                &session.next_identity()?,
+                &sid,
+            )
+            .await?;
+        let missing = match NetworkValue::from_network(
+            network.receive(&session.prev_identity()?, &sid).await,
+        )? {
+            NetworkValue::VecRing32(missing) => missing,
+            _ => eyre::bail!("Expected a VecRing32"),
+        };
+        Ok(shares
+            .into_iter()
+            .zip(missing)
+            .map(|(share, c)| {
+                let (a, b) = share.get_ab();
+                (a + b + c).convert()
+            })
+            .collect())
+    }
+
+    fn plaintext_dots(x: &IrisCode, y: &IrisCode) -> (u32, u32) {
+        let (distance, mask_len, _) = x.masked_distance(y).unwrap_or_default();
+        (mask_len.wrapping_sub(2 * distance), mask_len)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_score_pairs() {
+        let mut rng = AesRng::seed_from_u64(0);
+        let mut irises = (0..4)
+            .map(|_| IrisCode::random_rng(&mut rng))
+            .collect::<Vec<_>>();
+        irises[3].mask = IrisCodeArray::ZERO;
+        // Trimmed masks only hold masks whose bits come in pairs, which the
+        // noise of a similar iris does not keep.
+        let mut similar = irises[0].get_similar_iris(&mut rng);
+        similar.mask = irises[0].mask;
+        irises.push(similar);
+        // Includes pairs with the fully masked iris on either side.
+        let plain_pairs = [(0, 1), (0, 4), (2, 2), (3, 1), (1, 3), (3, 3)];
+
+        let shares = irises
+            .iter()
+            .map(|iris| generate_galois_iris_shares(&mut rng, iris.clone()))
+            .collect::<Vec<_>>();
+        let runtime = LocalRuntime::mock_setup_with_channel().await.unwrap();
+        let mut jobs = JoinSet::new();
+        for (index, player) in runtime.identities.iter().enumerate() {
+            let mut session = runtime.sessions.get(player).unwrap().clone();
+            let pairs = plain_pairs
+                .iter()
+                .map(|&(x, y)| {
+                    let mut query = shares[y][index].clone();
+                    query.code.preprocess_iris_code_query_share();
+                    query.mask.preprocess_mask_code_query_share();
+                    (shares[x][index].clone(), query)
+                })
+                .collect::<Vec<_>>();
+            jobs.spawn(async move {
+                let scores = score_pairs(&mut session, &pairs).await.unwrap();
+                open_many(&session, scores).await.unwrap()
+            });
+        }
+        let opened = jobs.join_all().await;
+        assert_eq!(opened[0], opened[1]);
+        assert_eq!(opened[0], opened[2]);
+
+        let expected = plain_pairs
+            .iter()
+            .flat_map(|&(x, y)| {
+                let (code_dot, mask_dot) = plaintext_dots(&irises[x], &irises[y]);
+                [code_dot, mask_dot]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(opened[0], expected);
+        // The dot products of the fully masked pairs are zero.
+        assert_eq!(opened[0][6..12], [0; 6]);
+    }
+
+    #[tokio::test]
+    async fn test_score_pairs_limit() {
+        let mut rng = AesRng::seed_from_u64(1);
+        let iris = IrisCode::random_rng(&mut rng);
+        let iris = generate_galois_iris_shares(&mut rng, iris);
+        let pairs = vec![(iris[0].clone(), iris[0].clone()); MAX_SCORE_PAIRS + 1];
+        let runtime = LocalRuntime::mock_setup_with_channel().await.unwrap();
+        let mut session = runtime
+            .sessions
+            .get(&runtime.identities[0])
+            .unwrap()
+            .clone();
+        assert!(score_pairs(&mut session, &pairs).await.is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use super::ShareStore;
+use crate::database_generators::GaloisRingSharedIris;
+use eyre::{ensure, Result, WrapErr};
+use iris_mpc_common::{
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
+};
+use memmap2::MmapMut;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+const DATA_FILE: &str = "shares.bin";
+const INDEX_FILE: &str = "shares.idx";
+
+const MAGIC: [u8; 8] = *b"IRISSHRS";
+const VERSION: u32 = 1;
+/// Magic, version, 4 reserved bytes and the number of irises.
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 8;
+/// The ids of the code and mask shares, followed by their coefficients.
+const RECORD_SIZE: usize = 16 + 2 * (IRIS_CODE_LENGTH + MASK_CODE_LENGTH);
+/// Set in the index entry of a tombstoned iris.
+const TOMBSTONE: u64 = 1 << 63;
+const MIN_CAPACITY: usize = 16;
+
+/// Keeps the irises in a memory-mapped file, so that the database does not
+/// have to fit into memory and survives restarts.
+///
+/// The store lives in a directory with two files: the records of the irises,
+/// and an index with the offset of the record of every serial id. Both files
+/// grow by doubling. The store assumes that no other process modifies the
+/// files while it is open.
+///
+/// Clones share the same files, an iris appended through one clone is
+/// visible through all of them.
+#[derive(Clone)]
+pub struct MmapShareStore {
+    dir:   PathBuf,
+    inner: Arc<RwLock<Inner>>,
+}
+
+struct Inner {
+    data_file:  File,
+    data:       MmapMut,
+    index_file: File,
+    index:      MmapMut,
+    len:        usize,
+    capacity:   usize,
+}
+
+impl MmapShareStore {
+    /// Opens the store in the directory, creating an empty one if there is
+    /// none yet.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create share store at {}", dir.display()))?;
+        let open = |name: &str| {
+            let path = dir.join(name);
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .wrap_err_with(|| format!("Failed to open {}", path.display()))
+        };
+        let data_file = open(DATA_FILE)?;
+        let index_file = open(INDEX_FILE)?;
+
+        let is_new = index_file.metadata()?.len() == 0;
+        if is_new {
+            data_file.set_len((MIN_CAPACITY * RECORD_SIZE) as u64)?;
+            index_file.set_len((HEADER_SIZE + MIN_CAPACITY * ENTRY_SIZE) as u64)?;
+        }
+        let data = map(&data_file)?;
+        let mut index = map(&index_file)?;
+        if is_new {
+            index[..8].copy_from_slice(&MAGIC);
+            index[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        }
+
+        ensure!(
+            index.len() >= HEADER_SIZE && index[..8] == MAGIC,
+            "{} is not a share store",
+            dir.display()
+        );
+        let version = u32::from_le_bytes(index[8..12].try_into().unwrap());
+        ensure!(
+            version == VERSION,
+            "Unsupported share store version {}",
+            version
+        );
+        let len = u64::from_le_bytes(index[16..24].try_into().unwrap()) as usize;
+        let capacity = ((index.len() - HEADER_SIZE) / ENTRY_SIZE).min(data.len() / RECORD_SIZE);
+        ensure!(
+            len <= capacity,
+            "Share store at {} is truncated: {} irises, room for {}",
+            dir.display(),
+            len,
+            capacity
+        );
+
+        Ok(Self {
+            dir,
+            inner: Arc::new(RwLock::new(Inner {
+                data_file,
+                data,
+                index_file,
+                index,
+                len,
+                capacity,
+            })),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the changes to disk.
+    pub fn flush(&self) -> Result<()> {
+        let inner = self.inner.read().unwrap();
+        inner.data.flush()?;
+        inner.index.flush()?;
+        Ok(())
+    }
+}
+
+fn map(file: &File) -> Result<MmapMut> {
+    // SAFETY: The files are only modified through this store.
+    Ok(unsafe { MmapMut::map_mut(file)? })
+}
+
+impl Inner {
+    fn entry(&self, id: usize) -> u64 {
+        let start = HEADER_SIZE + id * ENTRY_SIZE;
+        u64::from_le_bytes(self.index[start..start + ENTRY_SIZE].try_into().unwrap())
+    }
+
+    fn set_entry(&mut self, id: usize, entry: u64) {
+        let start = HEADER_SIZE + id * ENTRY_SIZE;
+        self.index[start..start + ENTRY_SIZE].copy_from_slice(&entry.to_le_bytes());
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+        self.index[16..24].copy_from_slice(&(len as u64).to_le_bytes());
+    }
+
+    fn get(&self, id: usize) -> Option<GaloisRingSharedIris> {
+        if id >= self.len {
+            return None;
+        }
+        let entry = self.entry(id);
+        if entry & TOMBSTONE != 0 {
+            return None;
+        }
+        let offset = entry as usize;
+        Some(decode_record(&self.data[offset..offset + RECORD_SIZE]))
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        let capacity = (self.capacity * 2).max(MIN_CAPACITY);
+        self.data_file.set_len((capacity * RECORD_SIZE) as u64)?;
+        self.index_file
+            .set_len((HEADER_SIZE + capacity * ENTRY_SIZE) as u64)?;
+        self.data = map(&self.data_file)?;
+        self.index = map(&self.index_file)?;
+        self.capacity = capacity;
+        Ok(())
+    }
+}
+
+fn encode_record(iris: &GaloisRingSharedIris, record: &mut [u8]) {
+    let (ids, coefs) = record.split_at_mut(16);
+    ids[..8].copy_from_slice(&(iris.code.id as u64).to_le_bytes());
+    ids[8..].copy_from_slice(&(iris.mask.id as u64).to_le_bytes());
+    for (bytes, coef) in coefs
+        .chunks_exact_mut(2)
+        .zip(iris.code.coefs.iter().chain(iris.mask.coefs.iter()))
+    {
+        bytes.copy_from_slice(&coef.to_le_bytes());
+    }
+}
+
+fn decode_record(record: &[u8]) -> GaloisRingSharedIris {
+    let (ids, coefs) = record.split_at(16);
+    let (code_coefs, mask_coefs) = coefs.split_at(2 * IRIS_CODE_LENGTH);
+    let mut code = GaloisRingIrisCodeShare {
+        id:    u64::from_le_bytes(ids[..8].try_into().unwrap()) as usize,
+        coefs: [0; IRIS_CODE_LENGTH],
+    };
+    let mut mask = GaloisRingTrimmedMaskCodeShare {
+        id:    u64::from_le_bytes(ids[8..].try_into().unwrap()) as usize,
+        coefs: [0; MASK_CODE_LENGTH],
+    };
+    for (coef, bytes) in code.coefs.iter_mut().zip(code_coefs.chunks_exact(2)) {
+        *coef = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    for (coef, bytes) in mask.coefs.iter_mut().zip(mask_coefs.chunks_exact(2)) {
+        *coef = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    GaloisRingSharedIris { code, mask }
+}
+
+impl fmt::Debug for MmapShareStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MmapShareStore")
+            .field("dir", &self.dir)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl ShareStore for MmapShareStore {
+    fn get(&self, id: usize) -> Option<GaloisRingSharedIris> {
+        self.inner.read().unwrap().get(id)
+    }
+
+    fn get_batch(&self, ids: &[usize]) -> Vec<Option<GaloisRingSharedIris>> {
+        let inner = self.inner.read().unwrap();
+        ids.iter().map(|id| inner.get(*id)).collect()
+    }
+
+    fn append(&mut self, iris: GaloisRingSharedIris) -> Result<usize> {
+        let mut inner = self.inner.write().unwrap();
+        if inner.len == inner.capacity {
+            inner.grow()?;
+        }
+        let id = inner.len;
+        let offset = id * RECORD_SIZE;
+        encode_record(&iris, &mut inner.data[offset..offset + RECORD_SIZE]);
+        inner.set_entry(id, offset as u64);
+        inner.set_len(id + 1);
+        Ok(id)
+    }
+
+    fn tombstone(&mut self, id: usize) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        ensure!(id < inner.len, "No iris with serial id {}", id);
+        let entry = inner.entry(id);
+        inner.set_entry(id, entry | TOMBSTONE);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().len
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (usize, GaloisRingSharedIris)> + '_> {
+        Box::new((0..self.len()).filter_map(move |id| self.get(id).map(|iris| (id, iris))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::share_store::tests::{random_shares, temp_store_dir};
+
+    #[test]
+    fn test_reopen() {
+        let dir = temp_store_dir();
+        // More irises than the initial capacity, so that the files grow.
+        let irises = random_shares(2 * MIN_CAPACITY + 1, 1);
+        {
+            let mut store = MmapShareStore::open(&dir).unwrap();
+            for iris in &irises {
+                store.append(iris.clone()).unwrap();
+            }
+            store.tombstone(7).unwrap();
+            store.flush().unwrap();
+        }
+
+        let mut store = MmapShareStore::open(&dir).unwrap();
+        assert_eq!(store.len(), irises.len());
+        assert_eq!(store.get(7), None);
+        for (id, iris) in store.iter() {
+            assert_eq!(iris, irises[id]);
+        }
+        assert_eq!(store.iter().count(), irises.len() - 1);
+        assert_eq!(store.append(irises[0].clone()).unwrap(), irises.len());
+        assert_eq!(store.get(irises.len()).as_ref(), Some(&irises[0]));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_clones_share_files() {
+        let dir = temp_store_dir();
+        let mut store = MmapShareStore::open(&dir).unwrap();
+        let clone = store.clone();
+        let iris = random_shares(1, 2).remove(0);
+        store.append(iris.clone()).unwrap();
+        assert_eq!(clone.get(0), Some(iris));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let dir = temp_store_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(INDEX_FILE), vec![0u8; HEADER_SIZE]).unwrap();
+        assert!(MmapShareStore::open(&dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Storage of the shared irises of one party.
+//!
+//! The secret-shared vector store of the HNSW graph reads its irises through
+//! the [`ShareStore`] trait, so that the irises do not have to be held in
+//! memory. An iris is addressed by its serial id, the position at which it
+//! was appended. Deleted irises are tombstoned and keep their serial id.
+
+mod mmap;
+
+use crate::database_generators::GaloisRingSharedIris;
+pub use mmap::MmapShareStore;
+use std::{collections::HashSet, fmt::Debug};
+
+pub trait ShareStore: Clone + Debug + Send + Sync + 'static {
+    /// Returns the iris with the serial id, or `None` if there is none or it
+    /// was tombstoned.
+    fn get(&self, id: usize) -> Option<GaloisRingSharedIris>;
+
+    /// Returns the irises with the serial ids, see [`Self::get`]. The HNSW
+    /// search fetches whole neighborhoods, so stores should override this if
+    /// they can fetch several irises at once more efficiently.
+    fn get_batch(&self, ids: &[usize]) -> Vec<Option<GaloisRingSharedIris>> {
+        ids.iter().map(|id| self.get(*id)).collect()
+    }
+
+    /// Appends an iris and returns its serial id.
+    fn append(&mut self, iris: GaloisRingSharedIris) -> eyre::Result<usize>;
+
+    fn tombstone(&mut self, id: usize) -> eyre::Result<()>;
+
+    /// The number of appended irises, including the tombstoned ones.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the irises that are not tombstoned, with their serial
+    /// ids.
+    fn iter(&self) -> Box<dyn Iterator<Item = (usize, GaloisRingSharedIris)> + '_>;
+}
+
+/// Holds the irises in memory.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct VecShareStore {
+    points:     Vec<GaloisRingSharedIris>,
+    tombstones: HashSet<usize>,
+}
+
+impl From<Vec<GaloisRingSharedIris>> for VecShareStore {
+    fn from(points: Vec<GaloisRingSharedIris>) -> Self {
+        Self {
+            points,
+            tombstones: HashSet::new(),
+        }
+    }
+}
+
+impl ShareStore for VecShareStore {
+    fn get(&self, id: usize) -> Option<GaloisRingSharedIris> {
+        if self.tombstones.contains(&id) {
+            return None;
+        }
+        self.points.get(id).cloned()
+    }
+
+    fn append(&mut self, iris: GaloisRingSharedIris) -> eyre::Result<usize> {
+        self.points.push(iris);
+        Ok(self.points.len() - 1)
+    }
+
+    fn tombstone(&mut self, id: usize) -> eyre::Result<()> {
+        if id >= self.points.len() {
+            return Err(eyre::eyre!("No iris with serial id {}", id));
+        }
+        self.tombstones.insert(id);
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (usize, GaloisRingSharedIris)> + '_> {
+        Box::new(
+            self.points
+                .iter()
+                .enumerate()
+                .filter(|(id, _)| !self.tombstones.contains(id))
+                .map(|(id, iris)| (id, iris.clone())),
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::database_generators::generate_galois_iris_shares;
+    use iris_mpc_common::iris_db::iris::IrisCode;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::path::PathBuf;
+
+    pub(crate) fn random_shares(n: usize, seed: u64) -> Vec<GaloisRingSharedIris> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n)
+            .map(|_| {
+                let iris = IrisCode::random_rng(&mut rng);
+                generate_galois_iris_shares(&mut rng, iris)[1].clone()
+            })
+            .collect()
+    }
+
+    /// A fresh directory for an [`MmapShareStore`].
+    pub(crate) fn temp_store_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("share-store-{}", uuid::Uuid::new_v4()))
+    }
+
+    /// The behavior every store must have.
+    pub(crate) fn check_store(store: &mut impl ShareStore) {
+        let irises = random_shares(5, 0);
+        assert!(store.is_empty());
+        for (i, iris) in irises.iter().enumerate() {
+            assert_eq!(store.append(iris.clone()).unwrap(), i);
+        }
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.get(3).as_ref(), Some(&irises[3]));
+        assert_eq!(store.get(5), None);
+
+        store.tombstone(1).unwrap();
+        assert!(store.tombstone(5).is_err());
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.get(1), None);
+        assert_eq!(store.get_batch(&[4, 1, 0, 7]), vec![
+            Some(irises[4].clone()),
+            None,
+            Some(irises[0].clone()),
+            None
+        ]);
+        assert_eq!(store.iter().map(|(id, _)| id).collect::<Vec<_>>(), vec![
+            0, 2, 3, 4
+        ]);
+        assert!(store.iter().all(|(id, iris)| iris == irises[id]));
+    }
+
+    #[test]
+    fn test_vec_store() {
+        check_store(&mut VecShareStore::default());
+    }
+
+    #[test]
+    fn test_mmap_store() {
+        let dir = temp_store_dir();
+        check_store(&mut MmapShareStore::open(&dir).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+//! Long runs of generated traffic through the [`TestHarness`], with faults
+//! injected at scripted batches.
+//!
+//! A [`SoakSchedule`] is described in TOML:
+//!
+//! ```toml
+//! seed = 42
+//! batches = 1000
+//! requests_per_batch = 2
+//! duplicate_rate = 0.1
+//! deletion_rate = 0.05
+//!
+//! [[faults]]
+//! batch = 137
+//! kind = "network_drop"
+//! party = 1
+//!
+//! [[faults]]
+//! batch = 900
+//! kind = "sqs_redelivery"
+//! count = 2
+//! ```
+//!
+//! After the run, [`run_soak`] checks that the parties hold the same database,
+//! that every request was answered exactly once, that the serial ids are dense
+//! and match the results, and that the audit chains are intact. A failure
+//! carries the seed and the schedule to reproduce it.
+
+use crate::harness::{ResultEvent, TestHarness};
+use eyre::{bail, ensure, WrapErr};
+use iris_mpc_common::{config::ResultMode, helpers::audit::verify_chain, iris_db::iris::IrisCode};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Batches run after the schedule to deliver held back and requeued requests.
+const DRAIN_BATCHES: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SoakSchedule {
+    pub seed:               u64,
+    pub batches:            u64,
+    #[serde(default = "default_requests_per_batch")]
+    pub requests_per_batch: usize,
+    /// Probability that a request enrolls an identity sent before again.
+    #[serde(default)]
+    pub duplicate_rate:     f64,
+    /// Probability that a request deletes an assigned serial id.
+    #[serde(default)]
+    pub deletion_rate:      f64,
+    #[serde(default)]
+    pub faults:             Vec<ScheduledFault>,
+}
+
+fn default_requests_per_batch() -> usize {
+    2
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledFault {
+    pub batch: u64,
+    #[serde(flatten)]
+    pub fault: Fault,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fault {
+    /// The party receives the requests of the batch only with the next one,
+    /// see [`TestHarness::disconnect`]. The batch carries no single deletions:
+    /// every party applies those as it receives them, so the parties would
+    /// match the batch against different databases.
+    NetworkDrop { party: usize },
+    /// The last `count` requests are delivered again before the batch, while
+    /// the parties still hold them, see [`TestHarness::redeliver`].
+    SqsRedelivery { count: usize },
+    /// The party restarts before the batch, see [`TestHarness::restart_party`].
+    PartyRestart { party: usize },
+    /// The party fails to download the shares of the first enrollment of the
+    /// batch, see [`TestHarness::fail_share_download`].
+    S3Failure { party: usize },
+}
+
+impl SoakSchedule {
+    pub fn from_toml(s: &str) -> eyre::Result<Self> {
+        let schedule: Self = toml::from_str(s)?;
+        for fault in schedule.faults.iter() {
+            match fault.fault {
+                Fault::NetworkDrop { party }
+                | Fault::PartyRestart { party }
+                | Fault::S3Failure { party } => {
+                    ensure!(
+                        party < 3,
+                        "Invalid party {} at batch {}",
+                        party,
+                        fault.batch
+                    )
+                }
+                Fault::SqsRedelivery { .. } => {}
+            }
+        }
+        Ok(schedule)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("schedules serialize to TOML")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SoakReport {
+    pub enrollments: usize,
+    pub deletions:   usize,
+    pub matches:     usize,
+    pub errors:      usize,
+    pub db_size:     usize,
+}
+
+/// Runs the schedule and checks the invariants afterwards.
+pub async fn run_soak(schedule: &SoakSchedule) -> eyre::Result<SoakReport> {
+    run(schedule).await.wrap_err_with(|| {
+        format!(
+            "Soak run failed, reproduce with seed {} and schedule:\n{}",
+            schedule.seed,
+            schedule.to_toml()
+        )
+    })
+}
+
+async fn run(schedule: &SoakSchedule) -> eyre::Result<SoakReport> {
+    let mut rng = StdRng::seed_from_u64(schedule.seed);
+    let mut harness = TestHarness::new(schedule.seed).await?;
+    // Opening every comparison bit of thousands of batches takes too long.
+    harness.set_result_mode(ResultMode::CountOnly, false);
+    // Leaves room for requests that were held back or requeued.
+    let batch_size = 4 * schedule.requests_per_batch.max(1);
+
+    let mut identities: Vec<(IrisCode, IrisCode)> = vec![];
+    let mut answers: HashMap<String, usize> = HashMap::new();
+    // The serial id of every deletion sent, in the order of all requests.
+    let mut sent: Vec<Option<u32>> = vec![];
+    let mut sent_deletions: HashMap<u32, usize> = HashMap::new();
+    let mut deletion_results: HashMap<u32, usize> = HashMap::new();
+    let mut serial_ids: HashMap<u32, String> = HashMap::new();
+    let mut report = SoakReport::default();
+
+    for batch in 0..schedule.batches + DRAIN_BATCHES {
+        let faults = schedule
+            .faults
+            .iter()
+            .filter(|fault| fault.batch == batch)
+            .map(|fault| &fault.fault)
+            .collect::<Vec<_>>();
+        for fault in faults.iter() {
+            match fault {
+                Fault::NetworkDrop { party } => harness.disconnect(*party),
+                Fault::PartyRestart { party } => harness.restart_party(*party)?,
+                Fault::SqsRedelivery { .. } | Fault::S3Failure { .. } => {}
+            }
+        }
+
+        let network_drop = faults
+            .iter()
+            .any(|fault| matches!(fault, Fault::NetworkDrop { .. }));
+        let mut enrolled = vec![];
+        for _ in 0..schedule.requests_per_batch * (batch < schedule.batches) as usize {
+            if !network_drop && !serial_ids.is_empty() && rng.gen_bool(schedule.deletion_rate) {
+                let serial_id = rng.gen_range(1..=serial_ids.len() as u32);
+                harness.delete(serial_id)?;
+                sent.push(Some(serial_id));
+                *sent_deletions.entry(serial_id).or_default() += 1;
+                report.deletions += 1;
+                continue;
+            }
+            let (left, right) = if !identities.is_empty() && rng.gen_bool(schedule.duplicate_rate) {
+                identities[rng.gen_range(0..identities.len())].clone()
+            } else {
+                (
+                    IrisCode::random_rng(&mut rng),
+                    IrisCode::random_rng(&mut rng),
+                )
+            };
+            let signup_id = format!("soak-{}", answers.len());
+            harness.enroll(&signup_id, left.clone(), right.clone())?;
+            sent.push(None);
+            identities.push((left, right));
+            answers.insert(signup_id.clone(), 0);
+            enrolled.push(signup_id);
+            report.enrollments += 1;
+        }
+
+        for fault in faults.iter() {
+            match (fault, enrolled.first()) {
+                (Fault::S3Failure { party }, Some(signup_id)) => {
+                    harness.fail_share_download(*party, signup_id)?;
+                }
+                (Fault::SqsRedelivery { count }, _) => {
+                    harness.redeliver(*count)?;
+                    // A deletion is applied and answered once per delivery.
+                    let start = sent.len().saturating_sub(*count);
+                    for serial_id in sent[start..].iter().flatten() {
+                        *sent_deletions.entry(*serial_id).or_default() += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        harness
+            .process_batch(batch_size)
+            .await
+            .wrap_err_with(|| format!("Batch {} failed", batch))?;
+        let results = harness
+            .drain_agreed_results()
+            .wrap_err_with(|| format!("Parties disagree on the results of batch {}", batch))?;
+        for result in results {
+            match result {
+                ResultEvent::Uniqueness(result) => {
+                    let Some(count) = answers.get_mut(&result.signup_id) else {
+                        bail!("Result for unknown request {}", result.signup_id);
+                    };
+                    *count += 1;
+                    if result.error == Some(true) {
+                        report.errors += 1;
+                    } else if result.is_match {
+                        report.matches += 1;
+                    }
+                    if let Some(serial_id) = result.serial_id {
+                        if let Some(other) = serial_ids.insert(serial_id, result.signup_id) {
+                            bail!(
+                                "Serial id {} was assigned twice, first to {}",
+                                serial_id,
+                                other
+                            );
+                        }
+                    }
+                }
+                ResultEvent::IdentityDeletion(result) => {
+                    *deletion_results.entry(result.serial_id).or_default() += 1;
+                }
+                ResultEvent::IdentityRestore(_) => {}
+            }
+        }
+
+        for fault in faults.iter() {
+            match fault {
+                Fault::NetworkDrop { party } => harness.reconnect(*party)?,
+                Fault::SqsRedelivery { .. }
+                | Fault::PartyRestart { .. }
+                | Fault::S3Failure { .. } => {}
+            }
+        }
+    }
+
+    // Every request was answered exactly once.
+    for (signup_id, count) in answers.iter() {
+        ensure!(
+            *count == 1,
+            "Request {} was answered {} times",
+            signup_id,
+            count
+        );
+    }
+    ensure!(
+        sent_deletions == deletion_results,
+        "Deletions were answered {:?} times, expected {:?}",
+        deletion_results,
+        sent_deletions
+    );
+
+    // All parties hold the same database.
+    let db_size = harness.db(0).len();
+    let digest = harness.db_digest(0);
+    for party_id in 1..3 {
+        ensure!(
+            harness.db(party_id).len() == db_size,
+            "Database sizes differ"
+        );
+        ensure!(
+            harness.db_digest(party_id) == digest,
+            "Database digests of party {} and party 0 differ",
+            party_id
+        );
+    }
+
+    // The serial ids are dense and assigned as announced.
+    ensure!(
+        serial_ids.len() == db_size,
+        "{} serial ids were assigned for {} database entries",
+        serial_ids.len(),
+        db_size
+    );
+    for (index, signup_id) in harness.db(0).signup_ids.iter().enumerate() {
+        let serial_id = index as u32 + 1;
+        ensure!(
+            serial_ids.get(&serial_id) == Some(signup_id),
+            "Serial id {} holds {}, but was assigned to {:?}",
+            serial_id,
+            signup_id,
+            serial_ids.get(&serial_id)
+        );
+    }
+
+    // The audit chains are intact and record the same decisions.
+    for party_id in 0..3 {
+        let records = harness.audit_records(party_id);
+        let checked = verify_chain(records, i64::MIN, i64::MAX)?;
+        ensure!(
+            checked == records.len(),
+            "Only {} of {} audit records of party {} were checked",
+            checked,
+            records.len(),
+            party_id
+        );
+        let decisions = |party_id| {
+            harness
+                .audit_records(party_id)
+                .iter()
+                .map(|record| {
+                    let decisions = record
+                        .decisions
+                        .iter()
+                        .map(|d| (d.request_id.clone(), d.is_match, d.serial_id))
+                        .collect::<Vec<_>>();
+                    (
+                        decisions,
+                        record.deleted_serial_ids.clone(),
+                        record.db_digest_after.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        ensure!(
+            decisions(party_id) == decisions(0),
+            "Audit records of party {} and party 0 differ",
+            party_id
+        );
+    }
+
+    report.db_size = db_size;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = SoakSchedule::from_toml(include_str!("../soak/ci.toml")).unwrap();
+        assert_eq!(
+            SoakSchedule::from_toml(&schedule.to_toml()).unwrap(),
+            schedule
+        );
+        assert!(schedule.faults.contains(&ScheduledFault {
+            batch: 3,
+            fault: Fault::NetworkDrop { party: 1 },
+        }));
+        assert!(SoakSchedule::from_toml(include_str!("../soak/nightly.toml")).is_ok());
+
+        let invalid =
+            "seed = 1\nbatches = 2\n[[faults]]\nbatch = 1\nkind = \"party_restart\"\nparty = 3\n";
+        assert!(SoakSchedule::from_toml(invalid).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_soak_ci() {
+        let schedule = SoakSchedule::from_toml(include_str!("../soak/ci.toml")).unwrap();
+        let report = run_soak(&schedule).await.unwrap();
+        assert!(report.matches > 0);
+        assert!(report.errors > 0);
+        assert!(report.deletions > 0);
+    }
+
+    /// Runs nightly, see `.github/workflows/soak-nightly.yaml`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_soak_nightly() {
+        let schedule = SoakSchedule::from_toml(include_str!("../soak/nightly.toml")).unwrap();
+        run_soak(&schedule).await.unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Guards the public API in `iris_mpc_cpu::prelude`. Integrators build against
+//! these items only, so this test must keep compiling unchanged: a failure
+//! here means a breaking change of the facade.
+
+use iris_mpc_cpu::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+
+fn assert_vector_store<V: VectorStore>() {}
+fn assert_graph_store<G: GraphStore<V>, V: VectorStore>() {}
+fn assert_networking<N: Networking>() {}
+
+#[test]
+fn test_prelude_signatures() {
+    let _: fn(&mut StdRng, IrisCode) -> Vec<GaloisRingSharedIris> =
+        generate_galois_iris_shares::<StdRng>;
+    let _: fn() -> Vec<Identity> = generate_local_identities;
+    let _: fn(Vec<GaloisRingSharedIris>) -> Aby3NgStorePlayer =
+        Aby3NgStorePlayer::new_with_shared_db;
+    let _: fn(&LocalNetAby3NgStoreProtocol) -> usize = LocalNetAby3NgStoreProtocol::get_owner_index;
+    let _: fn(&LocalNetAby3NgStoreProtocol) -> Session =
+        LocalNetAby3NgStoreProtocol::get_owner_session;
+    let _: VectorId = VectorId::from(0_usize);
+    let _: VectorId = VectorId::from(PointId(0));
+    let _: fn(usize) -> Role = Role::new;
+    let _: SessionId = SessionId::from(0_u64);
+    let _: Option<(
+        &Query,
+        &LocalNetworkingStore,
+        &GaloisRingTrimmedMaskCodeShare,
+    )> = None;
+    let _: usize = MAX_SCORE_PAIRS;
+    let _: Option<Vec<Share<u32>>> = None;
+    let _ = score_pairs;
+
+    assert_vector_store::<LocalNetAby3NgStoreProtocol>();
+    assert_vector_store::<PlaintextStore>();
+    assert_graph_store::<GraphMem<LocalNetAby3NgStoreProtocol>, LocalNetAby3NgStoreProtocol>();
+    assert_networking::<LocalNetworking>();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_prelude_local_setup() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let stores = setup_local_store_aby3_players(NetworkType::LocalChannel)
+        .await
+        .unwrap();
+    assert_eq!(stores.len(), 3);
+
+    let mut owners: Vec<usize> = stores.iter().map(|s| s.get_owner_index()).collect();
+    owners.sort();
+    assert_eq!(owners, vec![0, 1, 2]);
+
+    let shares = generate_galois_iris_shares(&mut rng, IrisCode::random_rng(&mut rng));
+    assert_eq!(shares.len(), 3);
+    let GaloisRingSharedIris { code, mask } = shares[0].clone();
+    let _: (GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare) = (code, mask);
+
+    let LocalNetAby3NgStoreProtocol {
+        owner,
+        storage: _,
+        runtime,
+    } = stores[0].clone();
+    let _: LocalRuntime = runtime;
+    assert!(generate_local_identities().contains(&owner));
+
+    let mut plain = PlaintextStore::default();
+    let _ = plain.prepare_query(IrisCode::random_rng(&mut rng));
+    let _ = HawkSearcher::default();
+}
@@ -1,7 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
-use iris_mpc_common::{shamir::P, IRIS_CODE_LENGTH};
+use iris_mpc_common::{shamir::P, IRIS_CODE_LENGTH, MASK_CODE_LENGTH};
 use iris_mpc_gpu::{
-    dot::share_db::{preprocess_query, ShareDB},
+    dot::{
+        share_db::{preprocess_query, ShareDB},
+        ROTATIONS,
+    },
     helpers::device_manager::DeviceManager,
 };
 use rand::{rngs::StdRng, Rng, SeedableRng};
@@ -64,5 +67,52 @@ fn bench_memcpy(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_memcpy);
+fn bench_mask_transfer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bench_mask_transfer");
+    let batch_size = QUERY_SIZE / ROTATIONS;
+
+    let device_manager = Arc::new(DeviceManager::init());
+    let engine = ShareDB::init(
+        0,
+        device_manager.clone(),
+        1,
+        QUERY_SIZE,
+        MASK_CODE_LENGTH,
+        ([0u32; 8], [0u32; 8]),
+        vec![],
+    );
+    let streams = device_manager.fork_streams();
+    let rotated = preprocess_query(&random_vec(QUERY_SIZE, MASK_CODE_LENGTH, P as u32));
+    let unrotated = preprocess_query(&random_vec(batch_size, MASK_CODE_LENGTH, P as u32));
+
+    let transfer_size = |query: &[Vec<u8>]| query.iter().map(Vec::len).sum::<usize>();
+    println!(
+        "htod mask transfer per batch of {} queries: {} bytes with host rotations, {} bytes with \
+         device rotations",
+        batch_size,
+        transfer_size(&rotated),
+        transfer_size(&unrotated)
+    );
+    group.sample_size(10);
+
+    group.bench_function(format!("host rotations {}", batch_size), |b| {
+        b.iter(|| {
+            let _query = device_manager
+                .htod_transfer_query(&rotated, &streams, batch_size, MASK_CODE_LENGTH)
+                .unwrap();
+            device_manager.await_streams(&streams);
+        });
+    });
+    group.bench_function(format!("device rotations {}", batch_size), |b| {
+        b.iter(|| {
+            let query = device_manager
+                .htod_transfer_unrotated_query(&unrotated, &streams, batch_size, MASK_CODE_LENGTH)
+                .unwrap();
+            let _query = engine.rotate_queries(&query, batch_size, &streams);
+            device_manager.await_streams(&streams);
+        });
+    });
+}
+
+criterion_group!(benches, bench_memcpy, bench_mask_transfer);
 criterion_main!(benches);
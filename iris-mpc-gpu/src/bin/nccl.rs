@@ -6,13 +6,13 @@
 //! --release --bin nccl 0 Node: NCCL_DEBUG=INFO cargo run --release --bin nccl
 //! {1,2} HOST_IP:3000
 
-use axum::{extract::Path, routing::get, Router};
+use axum::{routing::get, Router};
 use cudarc::{
     driver::{CudaDevice, CudaSlice},
     nccl::{Comm, Id},
 };
-use iris_mpc_gpu::helpers::id_wrapper::IdWrapper;
-use std::{env, str::FromStr, sync::LazyLock, time::Instant};
+use iris_mpc_gpu::helpers::id_wrapper::{fetch_id, http_root};
+use std::{env, sync::LazyLock, time::Instant};
 
 static COMM_ID: LazyLock<Vec<Id>> = LazyLock::new(|| {
     (0..CudaDevice::count().unwrap())
@@ -22,11 +22,6 @@ static COMM_ID: LazyLock<Vec<Id>> = LazyLock::new(|| {
 
 const DUMMY_DATA_LEN: usize = 5 * (1 << 30);
 
-async fn root(Path(device_id): Path<String>) -> String {
-    let device_id: usize = device_id.parse().unwrap();
-    IdWrapper(COMM_ID[device_id]).to_string()
-}
-
 #[tokio::main(flavor = "multi_thread", worker_threads = 12)]
 async fn main() -> eyre::Result<()> {
     let args = env::args().collect::<Vec<_>>();
@@ -38,7 +33,8 @@ async fn main() -> eyre::Result<()> {
     if party_id == 0 {
         server_join_handle = Some(tokio::spawn(async move {
             println!("starting server...");
-            let app = Router::new().route("/:device_id", get(root));
+            let app =
+                Router::new().route("/:device_id", get(|path| http_root(COMM_ID.clone(), path)));
             let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
             axum::serve(listener, app).await.unwrap();
         }));
@@ -55,8 +51,8 @@ async fn main() -> eyre::Result<()> {
         let id = if party_id == 0 {
             COMM_ID[i]
         } else {
-            let res = reqwest::blocking::get(format!("http://{}/{}", args[2], i)).unwrap();
-            IdWrapper::from_str(&res.text().unwrap()).unwrap().0
+            // The blocking client must not run on the async workers.
+            tokio::task::block_in_place(|| fetch_id(&args[2], i))?
         };
 
         // This call to CudaDevice::new is only used in context of a benchmark - not
@@ -1,12 +1,14 @@
 use super::ROTATIONS;
 use crate::helpers::{
-    device_manager::DeviceManager, launch_config_from_elements_and_threads,
-    DEFAULT_LAUNCH_CONFIG_THREADS,
+    chunked_copy::{dtoh_on_stream_sync_chunked, ChunkedTransfer},
+    device_manager::DeviceManager,
+    launch_config_from_elements_and_threads, DEFAULT_LAUNCH_CONFIG_THREADS,
 };
 use cudarc::{
     driver::{CudaFunction, CudaSlice, CudaStream, CudaView, LaunchAsync},
-    nvrtc::compile_ptx,
+    nvrtc::{compile_ptx, compile_ptx_with_opts, CompileOptions},
 };
+use iris_mpc_common::config::MatchPolicy;
 use std::{cmp::min, sync::Arc};
 
 const PTX_SRC: &str = include_str!("kernel.cu");
@@ -31,6 +33,7 @@ pub struct DistanceComparator {
     pub match_counters_right:    Vec<CudaSlice<u32>>,
     pub partial_results_left:    Vec<CudaSlice<u32>>,
     pub partial_results_right:   Vec<CudaSlice<u32>>,
+    pub chunked_transfer:        ChunkedTransfer,
 }
 
 impl DistanceComparator {
@@ -110,6 +113,36 @@ impl DistanceComparator {
             all_matches,
             partial_results_left,
             partial_results_right,
+            chunked_transfer: ChunkedTransfer::default(),
+        }
+    }
+
+    /// Replaces the merge kernels with ones that combine the matches of the
+    /// two eyes with `policy`. The kernels of [`Self::init`] use
+    /// [`MatchPolicy::And`].
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) {
+        let options = match policy {
+            MatchPolicy::And => vec![],
+            MatchPolicy::Or => vec!["-DMATCH_POLICY_OR".to_string()],
+        };
+        let ptx = compile_ptx_with_opts(PTX_SRC, CompileOptions {
+            options,
+            ..Default::default()
+        })
+        .unwrap();
+        let module = format!("merge_{:?}", policy);
+        for i in 0..self.device_manager.device_count() {
+            let device = self.device_manager.device(i);
+            device
+                .load_ptx(ptx.clone(), &module, &[
+                    MERGE_DB_RESULTS_FUNCTION,
+                    MERGE_BATCH_RESULTS_FUNCTION,
+                ])
+                .unwrap();
+            self.merge_db_kernels[i] = device.get_func(&module, MERGE_DB_RESULTS_FUNCTION).unwrap();
+            self.merge_batch_kernels[i] = device
+                .get_func(&module, MERGE_BATCH_RESULTS_FUNCTION)
+                .unwrap();
         }
     }
 
@@ -248,46 +281,44 @@ impl DistanceComparator {
         }
     }
 
-    pub fn fetch_final_results(&self, final_results_ptrs: &[CudaSlice<u32>]) -> Vec<Vec<u32>> {
-        let mut results = vec![];
-        for i in 0..self.device_manager.device_count() {
-            results.push(
-                self.device_manager
-                    .device(i)
-                    .dtoh_sync_copy(&final_results_ptrs[i])
-                    .unwrap(),
-            );
-        }
-        results
+    /// Copies the result buffers of all devices to the host, in chunks.
+    fn fetch_chunked(&self, buffers: &[CudaSlice<u32>], streams: &[CudaStream]) -> Vec<Vec<u32>> {
+        (0..self.device_manager.device_count())
+            .map(|i| {
+                dtoh_on_stream_sync_chunked(
+                    &buffers[i],
+                    &self.device_manager.device(i),
+                    &streams[i],
+                    &self.chunked_transfer,
+                )
+                .unwrap()
+            })
+            .collect()
     }
 
-    pub fn fetch_match_counters(&self, counters: &[CudaSlice<u32>]) -> Vec<Vec<u32>> {
-        let mut results = vec![];
-        for i in 0..self.device_manager.device_count() {
-            results.push(
-                self.device_manager
-                    .device(i)
-                    .dtoh_sync_copy(&counters[i])
-                    .unwrap(),
-            );
-        }
-        results
+    pub fn fetch_final_results(
+        &self,
+        final_results_ptrs: &[CudaSlice<u32>],
+        streams: &[CudaStream],
+    ) -> Vec<Vec<u32>> {
+        self.fetch_chunked(final_results_ptrs, streams)
+    }
+
+    pub fn fetch_match_counters(
+        &self,
+        counters: &[CudaSlice<u32>],
+        streams: &[CudaStream],
+    ) -> Vec<Vec<u32>> {
+        self.fetch_chunked(counters, streams)
     }
 
     pub fn fetch_all_match_ids(
         &self,
         match_counters: &[Vec<u32>],
         matches: &[CudaSlice<u32>],
+        streams: &[CudaStream],
     ) -> Vec<Vec<u32>> {
-        let mut results = vec![];
-        for i in 0..self.device_manager.device_count() {
-            results.push(
-                self.device_manager
-                    .device(i)
-                    .dtoh_sync_copy(&matches[i])
-                    .unwrap(),
-            );
-        }
+        let results = self.fetch_chunked(matches, streams);
 
         let batch_match_idx: u32 = u32::MAX - (self.query_length / ROTATIONS) as u32; // batch matches have an index of u32::MAX - index
         let mut matches_per_query = vec![vec![]; match_counters[0].len()];
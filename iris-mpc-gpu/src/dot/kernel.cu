@@ -4,6 +4,14 @@
 #define U8 unsigned char
 #define MAX_MATCHES_LEN 256
 
+// How the matches of the two eyes combine, see `MatchPolicy`. The merge
+// kernels are compiled with MATCH_POLICY_OR defined for the OR policy.
+#ifdef MATCH_POLICY_OR
+#define EYES_MATCH(left, right) ((left) || (right))
+#else
+#define EYES_MATCH(left, right) ((left) && (right))
+#endif
+
 extern "C" __global__ void xor_assign_u8(U8 *lhs, U8 *rhs, size_t n)
 {
     size_t i = blockIdx.x * blockDim.x + threadIdx.x;
@@ -13,6 +21,39 @@ extern "C" __global__ void xor_assign_u8(U8 *lhs, U8 *rhs, size_t n)
     }
 }
 
+// Expands every un-rotated query into its ALL_ROTATIONS rotations, in the order
+// of `all_rotations` on the host. A rotation shifts each row of rowLength bytes
+// by whole Galois ring elements of elementSize bytes.
+extern "C" __global__ void rotate_query_u8(U8 *src, U8 *dst, size_t codeLength, size_t rowLength, size_t elementSize, size_t numElements)
+{
+    size_t idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numElements)
+    {
+        size_t query = idx / (codeLength * ALL_ROTATIONS);
+        size_t rotation = (idx / codeLength) % ALL_ROTATIONS;
+        size_t i = idx % codeLength;
+        size_t rowStart = i - i % rowLength;
+        long long shift = ((long long)rotation - ROTATIONS) * (long long)elementSize;
+        long long col = ((long long)(i % rowLength) - shift) % (long long)rowLength;
+        if (col < 0)
+        {
+            col += rowLength;
+        }
+        dst[idx] = src[query * codeLength + rowStart + col];
+    }
+}
+
+// Copies the reduced dot products at the given flat indices into the results,
+// see `ShareDB::gather_pairs`.
+extern "C" __global__ void gather_u16(unsigned short *src, unsigned int *indices, unsigned short *dst, size_t n)
+{
+    size_t i = blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n)
+    {
+        dst[i] = src[indices[i]];
+    }
+}
+
 extern "C" __global__ void matmul_correct_and_reduce(int *c, unsigned short *output, int *a0Sums, int *a1Sums, int *b0Sums, int *b1Sums, size_t dbLength, size_t numElements, size_t offset, unsigned short multiplier, unsigned short *rngMasks0, unsigned short *rngMasks1)
 {
     size_t idx = blockIdx.x * blockDim.x + threadIdx.x;
@@ -67,7 +108,7 @@ extern "C" __global__ void mergeDbResults(unsigned long long *matchResultsLeft,
             if (queryIdx >= queryLength || dbIdx >= dbLength)
                 continue;
 
-            // Check for partial results (only used for debugging)
+            // Record the matches of each eye on its own
             if (matchLeft)
             {
                 unsigned int queryMatchCounter = atomicAdd(&matchCounterLeft[queryIdx], 1);
@@ -81,8 +122,7 @@ extern "C" __global__ void mergeDbResults(unsigned long long *matchResultsLeft,
                     partialResultsRight[MAX_MATCHES_LEN * queryIdx + queryMatchCounter] = dbIdx;
             }
 
-            // Current *AND* policy: only match, if both eyes match
-            if (matchLeft && matchRight)
+            if (EYES_MATCH(matchLeft, matchRight))
             {
                 atomicMin(&finalResults[queryIdx], dbIdx);
                 unsigned int queryMatchCounter = atomicAdd(&matchCounter[queryIdx], 1);
@@ -118,8 +158,7 @@ extern "C" __global__ void mergeBatchResults(unsigned long long *matchResultsSel
             bool matchLeft = (matchResultsSelfLeft[idx] & (1ULL << i));
             bool matchRight = (matchResultsSelfRight[idx] & (1ULL << i));
 
-            // Current *AND* policy: only match if both eyes match
-            if (matchLeft && matchRight)
+            if (EYES_MATCH(matchLeft, matchRight))
             {
                 atomicMin(&finalResults[queryIdx], UINT_MAX - 1);
                 unsigned int queryMatchCounter = atomicAdd(&matchCounter[queryIdx], 1);
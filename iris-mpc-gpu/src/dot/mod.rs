@@ -1,6 +1,12 @@
 pub mod distance_comparator;
 pub mod share_db;
+pub mod snapshot;
 
 pub const IRIS_CODE_LENGTH: usize = 12_800;
 pub const MASK_CODE_LENGTH: usize = 6_400;
 pub const ROTATIONS: usize = 31;
+/// Coefficients of one Galois ring element in a shared code.
+pub const GALOIS_ELEMENT_SIZE: usize = 4;
+/// Coefficients of one row of a shared code. The rotations of a query shift
+/// each row by whole elements.
+pub const CODE_ROW_LENGTH: usize = 200 * GALOIS_ELEMENT_SIZE;
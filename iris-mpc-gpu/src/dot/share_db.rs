@@ -1,5 +1,16 @@
+use super::{
+    snapshot::{
+        read_header, read_section, write_header, write_section, SnapshotDigest, SnapshotError,
+        SnapshotHeader, SNAPSHOT_VERSION,
+    },
+    CODE_ROW_LENGTH, GALOIS_ELEMENT_SIZE, ROTATIONS,
+};
 use crate::{
     helpers::{
+        chunked_copy::{
+            dtoh_from_on_stream_sync_chunked, htod_into_on_stream_sync_chunked, ChunkedTransfer,
+            ChunkedTransferError,
+        },
         comm::NcclComm,
         device_manager::DeviceManager,
         launch_config_from_elements_and_threads,
@@ -20,27 +31,45 @@ use cudarc::{
         CudaBlas,
     },
     driver::{
-        result::{self, malloc_async},
+        result::malloc_async,
         sys::{CUdeviceptr, CU_MEMHOSTALLOC_PORTABLE},
         CudaFunction, CudaSlice, CudaStream, CudaView, DevicePtr, DeviceSlice, LaunchAsync,
     },
     nccl,
     nvrtc::compile_ptx,
 };
+use iris_mpc_common::id::PartyID;
 use itertools::{izip, Itertools};
 use memmap2::MmapMut;
 use rayon::prelude::*;
 use std::{
     ffi::{c_void, CStr},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read},
     mem::{self, forget},
+    path::Path,
     sync::Arc,
 };
+use thiserror::Error;
 
 const PTX_SRC: &str = include_str!("kernel.cu");
 const REDUCE_FUNCTION_NAME: &str = "matmul_correct_and_reduce";
 const XOR_ASSIGN_U8_NAME: &str = "xor_assign_u8";
+const ROTATE_QUERY_U8_NAME: &str = "rotate_query_u8";
+const GATHER_U16_NAME: &str = "gather_u16";
 const LIMBS: usize = 2;
 
+/// Maximum number of pairs per call of [`ShareDB::gather_pairs`].
+pub const MAX_SCORE_PAIRS: usize = 1 << 20;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PairScoringError {
+    #[error("{got} pairs exceed the limit of {max} per call")]
+    TooManyPairs { got: usize, max: usize },
+    #[error("Pair of query {query} and db entry {db} is out of range")]
+    OutOfRange { query: usize, db: usize },
+}
+
 pub fn preprocess_query(query: &[u16]) -> Vec<Vec<u8>> {
     let mut result = vec![];
     for _ in 0..LIMBS {
@@ -128,6 +157,8 @@ pub struct ShareDB {
     device_manager:        Arc<DeviceManager>,
     kernels:               Vec<CudaFunction>,
     xor_assign_u8_kernels: Vec<CudaFunction>,
+    rotate_query_kernels:  Vec<CudaFunction>,
+    gather_kernels:        Vec<CudaFunction>,
     rngs:                  Vec<(ChaChaCudaRng, ChaChaCudaRng)>,
     comms:                 Vec<Arc<NcclComm>>,
     ones:                  Vec<CudaSlice<u8>>,
@@ -135,6 +166,7 @@ pub struct ShareDB {
     pub results:           Vec<CudaSlice<u8>>,
     pub results_peer:      Vec<CudaSlice<u8>>,
     code_length:           usize,
+    chunked_transfer:      ChunkedTransfer,
 }
 
 impl ShareDB {
@@ -175,6 +207,25 @@ impl ShareDB {
             })
             .collect_vec();
 
+        let rotate_query_kernels = (0..n_devices)
+            .map(|i| {
+                let dev = device_manager.device(i);
+                dev.load_ptx(ptx.clone(), ROTATE_QUERY_U8_NAME, &[ROTATE_QUERY_U8_NAME])
+                    .unwrap();
+                dev.get_func(ROTATE_QUERY_U8_NAME, ROTATE_QUERY_U8_NAME)
+                    .unwrap()
+            })
+            .collect_vec();
+
+        let gather_kernels = (0..n_devices)
+            .map(|i| {
+                let dev = device_manager.device(i);
+                dev.load_ptx(ptx.clone(), GATHER_U16_NAME, &[GATHER_U16_NAME])
+                    .unwrap();
+                dev.get_func(GATHER_U16_NAME, GATHER_U16_NAME).unwrap()
+            })
+            .collect_vec();
+
         let ones = vec![1u8; code_length];
         let ones = (0..n_devices)
             .map(|idx| device_manager.device(idx).htod_sync_copy(&ones).unwrap())
@@ -226,6 +277,8 @@ impl ShareDB {
             device_manager,
             kernels,
             xor_assign_u8_kernels,
+            rotate_query_kernels,
+            gather_kernels,
             rngs,
             is_remote: !comms.is_empty(),
             comms,
@@ -234,9 +287,22 @@ impl ShareDB {
             results,
             results_peer,
             code_length,
+            chunked_transfer: ChunkedTransfer::default(),
         }
     }
 
+    /// Sets how the DB is uploaded to the devices in [`Self::preprocess_db`].
+    pub fn set_chunked_transfer(&mut self, transfer: ChunkedTransfer) {
+        self.chunked_transfer = transfer;
+    }
+
+    /// Swaps in the communicators rebuilt after a failed collective, see
+    /// [`DeviceManager::reinit`].
+    pub fn replace_comms(&mut self, comms: Vec<Arc<NcclComm>>) {
+        assert_eq!(comms.len(), self.comms.len());
+        self.comms = comms;
+    }
+
     pub fn alloc_db(&self, max_db_length: usize) -> SlicedProcessedDatabase {
         let max_size = max_db_length / self.device_manager.device_count();
         let (db0_sums, (db1_sums, (db0, db1))) = self
@@ -339,14 +405,32 @@ impl ShareDB {
         };
     }
 
-    pub fn preprocess_db(&self, db: &mut SlicedProcessedDatabase, db_lens: &[usize]) {
+    pub fn preprocess_db(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        db_lens: &[usize],
+    ) -> Result<(), ChunkedTransferError> {
+        let from_lens = vec![0; self.device_manager.device_count()];
+        self.append_db(db, &from_lens, db_lens)
+    }
+
+    /// Preprocesses only the entries loaded since the DB had `from_lens`
+    /// entries per device, e.g. the entries added to the store after a
+    /// snapshot was taken.
+    pub fn append_db(
+        &self,
+        db: &mut SlicedProcessedDatabase,
+        from_lens: &[usize],
+        to_lens: &[usize],
+    ) -> Result<(), ChunkedTransferError> {
         let code_len = self.code_length;
         for device_index in 0..self.device_manager.device_count() {
+            let from = from_lens[device_index];
             for (limbs, sum_slices) in [
                 (&db.code_gr.limb_0, &mut db.code_sums_gr.limb_0),
                 (&db.code_gr.limb_1, &mut db.code_sums_gr.limb_1),
             ] {
-                let sums = (0..db_lens[device_index])
+                let sums = (from..to_lens[device_index])
                     .into_par_iter()
                     .map(|idx| {
                         let slice: &[i8] = unsafe {
@@ -359,16 +443,169 @@ impl ShareDB {
                     })
                     .collect::<Vec<_>>();
 
-                self.device_manager
-                    .device(device_index)
-                    .bind_to_thread()
-                    .unwrap();
+                let sum_slice = &sum_slices[device_index];
                 unsafe {
-                    result::memcpy_htod_sync(sum_slices[device_index].cu_device_ptr, &sums)
-                        .unwrap();
+                    htod_into_on_stream_sync_chunked(
+                        &sums,
+                        sum_slice.cu_device_ptr + (from * mem::size_of::<u32>()) as u64,
+                        &self.device_manager.device(device_index),
+                        sum_slice.stream,
+                        &self.chunked_transfer,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads only the header of the snapshot at `path`, e.g. to look up the
+    /// expected digest in the store before [`Self::restore_from`].
+    pub fn read_snapshot_header(path: impl AsRef<Path>) -> Result<SnapshotHeader, SnapshotError> {
+        read_header(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the entries of `db` to a snapshot file at `path`, see
+    /// [`super::snapshot`]. The sums are copied back from the devices.
+    pub fn snapshot_to(
+        &self,
+        path: impl AsRef<Path>,
+        db: &SlicedProcessedDatabase,
+        db_sizes: &[usize],
+        digest: SnapshotDigest,
+        streams: &[CudaStream],
+    ) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        // A crash while writing must not leave a truncated snapshot behind.
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write_header(&mut writer, &SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            code_length: self.code_length,
+            db_sizes: db_sizes.to_vec(),
+            digest,
+        })?;
+
+        for device_index in 0..self.device_manager.device_count() {
+            let db_size = db_sizes[device_index];
+            for limbs in [&db.code_gr.limb_0, &db.code_gr.limb_1] {
+                let codes = unsafe {
+                    std::slice::from_raw_parts(
+                        limbs[device_index] as *const u8,
+                        db_size * self.code_length,
+                    )
+                };
+                write_section(&mut writer, codes)?;
+            }
+            for sum_slices in [&db.code_sums_gr.limb_0, &db.code_sums_gr.limb_1] {
+                let sums: Vec<u32> = unsafe {
+                    dtoh_from_on_stream_sync_chunked(
+                        sum_slices[device_index].cu_device_ptr,
+                        db_size,
+                        &self.device_manager.device(device_index),
+                        streams[device_index].stream,
+                        &self.chunked_transfer,
+                    )?
+                };
+                write_section(&mut writer, bytemuck::cast_slice(&sums))?;
+            }
+        }
+
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Loads a snapshot written by [`Self::snapshot_to`] into `db` and returns
+    /// the number of entries on each device.
+    ///
+    /// The snapshot is refused if its digest differs from `expected`, the
+    /// digest the store reports for the same entries, or if any chunk fails its
+    /// checksum. Nothing is uploaded to the devices then, but the host buffers
+    /// may be partially overwritten, so the DB has to be loaded from scratch.
+    pub fn restore_from(
+        &self,
+        path: impl AsRef<Path>,
+        db: &mut SlicedProcessedDatabase,
+        max_db_length: usize,
+        expected: &SnapshotDigest,
+        streams: &[CudaStream],
+    ) -> Result<Vec<usize>, SnapshotError> {
+        let n_devices = self.device_manager.device_count();
+        let max_size = max_db_length / n_devices;
+        let mut reader = BufReader::new(File::open(path)?);
+        let header = read_header(&mut reader)?;
+        if header.code_length != self.code_length {
+            return Err(SnapshotError::LayoutMismatch(format!(
+                "code length {}, expected {}",
+                header.code_length, self.code_length
+            )));
+        }
+        if header.db_sizes.len() != n_devices || header.db_sizes.iter().any(|&size| size > max_size)
+        {
+            return Err(SnapshotError::LayoutMismatch(format!(
+                "DB sizes {:?} for {} devices of {} entries",
+                header.db_sizes, n_devices, max_size
+            )));
+        }
+        if header.digest != *expected {
+            return Err(SnapshotError::Stale {
+                snapshot: header.digest,
+                expected: *expected,
+            });
+        }
+
+        let mut all_sums = vec![];
+        for device_index in 0..n_devices {
+            let db_size = header.db_sizes[device_index];
+            for (limb, limbs) in [&db.code_gr.limb_0, &db.code_gr.limb_1]
+                .into_iter()
+                .enumerate()
+            {
+                let codes = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        limbs[device_index] as *mut u8,
+                        db_size * self.code_length,
+                    )
+                };
+                read_section(
+                    &mut reader,
+                    codes,
+                    &format!("device {} code limb {}", device_index, limb),
+                )?;
+            }
+            for limb in 0..2 {
+                let mut sums = vec![0u32; db_size];
+                read_section(
+                    &mut reader,
+                    bytemuck::cast_slice_mut(&mut sums),
+                    &format!("device {} sums limb {}", device_index, limb),
+                )?;
+                all_sums.push(sums);
+            }
+        }
+        if reader.read(&mut [0u8])? != 0 {
+            return Err(SnapshotError::Format("trailing data".to_string()));
+        }
+
+        let mut all_sums = all_sums.into_iter();
+        for device_index in 0..n_devices {
+            for sum_slices in [&db.code_sums_gr.limb_0, &db.code_sums_gr.limb_1] {
+                let sums = all_sums.next().unwrap();
+                unsafe {
+                    htod_into_on_stream_sync_chunked(
+                        &sums,
+                        sum_slices[device_index].cu_device_ptr,
+                        &self.device_manager.device(device_index),
+                        streams[device_index].stream,
+                        &self.chunked_transfer,
+                    )?;
                 }
             }
         }
+        Ok(header.db_sizes)
     }
 
     #[allow(clippy::type_complexity)]
@@ -392,7 +629,7 @@ impl ShareDB {
             }
         }
 
-        self.preprocess_db(db, &db_lens);
+        self.preprocess_db(db, &db_lens).unwrap();
 
         db_lens
     }
@@ -478,6 +715,65 @@ impl ShareDB {
         }
     }
 
+    /// Materializes the [`ROTATIONS`] rotations of `n_queries` preprocessed
+    /// queries that were uploaded without their rotations. The output has the
+    /// layout of queries whose rotations were preprocessed on the host, so it
+    /// can be used in place of them.
+    pub fn rotate_queries(
+        &self,
+        queries: &CudaVec2DSlicerU8,
+        n_queries: usize,
+        streams: &[CudaStream],
+    ) -> CudaVec2DSlicerU8 {
+        let rotated_len = n_queries * ROTATIONS * self.code_length;
+        let mut rotated0 = vec![];
+        let mut rotated1 = vec![];
+
+        for idx in 0..self.device_manager.device_count() {
+            let device = self.device_manager.device(idx);
+            device.bind_to_thread().unwrap();
+
+            let cfg = launch_config_from_elements_and_threads(
+                rotated_len as u32,
+                DEFAULT_LAUNCH_CONFIG_THREADS,
+                &device,
+            );
+            for (src, dst) in [
+                (&queries.limb_0[idx], &mut rotated0),
+                (&queries.limb_1[idx], &mut rotated1),
+            ] {
+                let rotated = unsafe { malloc_async(streams[idx].stream, rotated_len).unwrap() };
+                unsafe {
+                    self.rotate_query_kernels[idx]
+                        .clone()
+                        .launch_on_stream(
+                            &streams[idx],
+                            cfg,
+                            (
+                                *src.device_ptr(),
+                                rotated,
+                                self.code_length as u64,
+                                CODE_ROW_LENGTH as u64,
+                                GALOIS_ELEMENT_SIZE as u64,
+                                rotated_len as u64,
+                            ),
+                        )
+                        .unwrap();
+                }
+                dst.push(StreamAwareCudaSlice::<u8>::upgrade_ptr_stream(
+                    rotated,
+                    streams[idx].stream,
+                    rotated_len,
+                ));
+            }
+        }
+
+        CudaVec2DSlicer {
+            limb_0: rotated0,
+            limb_1: rotated1,
+        }
+    }
+
     pub fn alloc_db_chunk_buffer(&self, max_chunk_size: usize) -> DBChunkBuffers {
         let mut limb_0 = vec![];
         let mut limb_1 = vec![];
@@ -637,6 +933,89 @@ impl ShareDB {
         self.dot_reduce_and_multiply(query_sums, db_sums, chunk_sizes, offset, streams, 1);
     }
 
+    /// Returns the reduced dot products of the given (query index, db index)
+    /// pairs only, instead of fetching the full matrix. The db index is the
+    /// position in the whole DB, which is sharded across the devices like in
+    /// [`Self::load_full_db`]. Must be called after [`Self::dot_reduce`] on
+    /// the whole DB, with `db_sizes` as returned by [`Self::load_full_db`].
+    pub fn gather_pairs(
+        &self,
+        pairs: &[(usize, usize)],
+        db_sizes: &[usize],
+        streams: &[CudaStream],
+    ) -> Result<Vec<u16>, PairScoringError> {
+        if pairs.len() > MAX_SCORE_PAIRS {
+            return Err(PairScoringError::TooManyPairs {
+                got: pairs.len(),
+                max: MAX_SCORE_PAIRS,
+            });
+        }
+
+        // Flat indices into the results of every device, with the position of
+        // the pair they belong to.
+        let n_devices = self.device_manager.device_count();
+        let mut indices = vec![vec![]; n_devices];
+        let mut positions = vec![vec![]; n_devices];
+        for (position, &(query, db)) in pairs.iter().enumerate() {
+            let (device_index, local) = (db % n_devices, db / n_devices);
+            if query >= self.query_length || local >= db_sizes[device_index] {
+                return Err(PairScoringError::OutOfRange { query, db });
+            }
+            indices[device_index].push((query * db_sizes[device_index] + local) as u32);
+            positions[device_index].push(position);
+        }
+
+        let mut outputs = vec![];
+        for (idx, indices) in indices.iter().enumerate() {
+            if indices.is_empty() {
+                outputs.push(None);
+                continue;
+            }
+            let device = self.device_manager.device(idx);
+            device.bind_to_thread().unwrap();
+            let indices_gpu = device.htod_sync_copy(indices).unwrap();
+            let mut output = unsafe { device.alloc::<u16>(indices.len()).unwrap() };
+            let cfg = launch_config_from_elements_and_threads(
+                indices.len() as u32,
+                DEFAULT_LAUNCH_CONFIG_THREADS,
+                &self.device_manager.devices()[idx],
+            );
+            unsafe {
+                self.gather_kernels[idx]
+                    .clone()
+                    .launch_on_stream(
+                        &streams[idx],
+                        cfg,
+                        (
+                            *self.results[idx].device_ptr(),
+                            &indices_gpu,
+                            &mut output,
+                            indices.len(),
+                        ),
+                    )
+                    .unwrap();
+            }
+            outputs.push(Some((indices_gpu, output)));
+        }
+        self.device_manager.await_streams(streams);
+
+        let mut scores = vec![0u16; pairs.len()];
+        for (idx, output) in outputs.into_iter().enumerate() {
+            let Some((_, output)) = output else {
+                continue;
+            };
+            let output = self
+                .device_manager
+                .device(idx)
+                .dtoh_sync_copy(&output)
+                .unwrap();
+            for (&position, score) in positions[idx].iter().zip(output) {
+                scores[position] = score;
+            }
+        }
+        Ok(scores)
+    }
+
     fn single_xor_assign_u8(
         &self,
         x1: &mut CudaView<u8>,
@@ -735,8 +1114,9 @@ impl ShareDB {
     }
 
     pub fn reshare_results(&mut self, db_sizes: &[usize], streams: &[CudaStream]) {
-        let next_peer = (self.peer_id + 1) % 3;
-        let prev_peer = (self.peer_id + 2) % 3;
+        let party_id = PartyID::try_from(self.peer_id).expect("party id is 0, 1 or 2");
+        let next_peer = usize::from(party_id.next_id());
+        let prev_peer = usize::from(party_id.prev_id());
 
         let send_bufs = (0..self.device_manager.device_count())
             .map(|idx| {
@@ -805,15 +1185,24 @@ impl ShareDB {
 #[cfg(test)]
 #[cfg(feature = "gpu_dependent")]
 mod tests {
-    use super::{preprocess_query, ShareDB};
+    use super::{
+        preprocess_query, PairScoringError, ShareDB, SlicedProcessedDatabase, MAX_SCORE_PAIRS,
+    };
     use crate::{
-        dot::{IRIS_CODE_LENGTH, MASK_CODE_LENGTH},
-        helpers::device_manager::DeviceManager,
+        dot::{
+            snapshot::{SnapshotDigest, SnapshotError},
+            IRIS_CODE_LENGTH, MASK_CODE_LENGTH, ROTATIONS,
+        },
+        helpers::{chunked_copy::dtoh_from_on_stream_sync_chunked, device_manager::DeviceManager},
     };
+    use cudarc::driver::CudaStream;
     use float_eq::assert_float_eq;
     use iris_mpc_common::{
         galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
-        iris_db::db::IrisDB,
+        iris_db::{
+            db::IrisDB,
+            iris::{IrisCode, IrisCodeArray},
+        },
     };
     use itertools::Itertools;
     use ndarray::Array2;
@@ -1196,4 +1585,450 @@ mod tests {
             assert_float_eq!(dists[i], reference_dists[i * n_devices], abs <= 1e-6);
         }
     }
+
+    /// Gathers the code and mask dot products of random pairs, including pairs
+    /// with a fully masked iris, and checks them against plaintext.
+    #[test]
+    fn check_gather_pairs() {
+        const MASKED: usize = 5;
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let mut db = IrisDB::new_random_par(DB_SIZE, &mut rng);
+        db.db[MASKED].mask = IrisCodeArray::ZERO;
+        let mut pairs = (0..64)
+            .map(|_| (rng.gen_range(0..QUERY_SIZE), rng.gen_range(0..DB_SIZE)))
+            .collect::<Vec<_>>();
+        pairs.extend([(MASKED, 0), (0, MASKED), (MASKED, MASKED)]);
+
+        let mut code_scores = vec![0u16; pairs.len()];
+        let mut mask_scores = vec![0u16; pairs.len()];
+        for party_id in 0..3 {
+            let share = |iris: &IrisCode, mask_only: bool, query: bool| {
+                let mut rng = StdRng::seed_from_u64(RNG_SEED);
+                let mut shares = if mask_only {
+                    GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, &mut rng)
+                } else {
+                    GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)
+                };
+                if query {
+                    shares[party_id].preprocess_iris_code_query_share();
+                }
+                if mask_only {
+                    let mask: GaloisRingTrimmedMaskCodeShare = shares[party_id].clone().into();
+                    mask.coefs.to_vec()
+                } else {
+                    shares[party_id].coefs.to_vec()
+                }
+            };
+
+            let device_manager = Arc::new(DeviceManager::init());
+            let streams = device_manager.fork_streams();
+            let blass = device_manager.create_cublas(&streams);
+            for (mask_only, code_length, multiplier, scores) in [
+                (false, IRIS_CODE_LENGTH, 1, &mut code_scores),
+                (true, MASK_CODE_LENGTH, 2, &mut mask_scores),
+            ] {
+                let entries = db
+                    .db
+                    .iter()
+                    .flat_map(|iris| share(iris, mask_only, false))
+                    .collect::<Vec<_>>();
+                let queries = db.db[0..QUERY_SIZE]
+                    .iter()
+                    .flat_map(|iris| share(iris, mask_only, true))
+                    .collect::<Vec<_>>();
+
+                let mut engine = ShareDB::init(
+                    party_id,
+                    device_manager.clone(),
+                    DB_SIZE,
+                    QUERY_SIZE,
+                    code_length,
+                    ([0u32; 8], [0u32; 8]),
+                    vec![],
+                );
+                let query = device_manager
+                    .htod_transfer_query(
+                        &preprocess_query(&queries),
+                        &streams,
+                        QUERY_SIZE,
+                        code_length,
+                    )
+                    .unwrap();
+                let query_sums = engine.query_sums(&query, &streams, &blass);
+                let mut db_slices = engine.alloc_db(DB_SIZE);
+                engine.register_host_memory(&db_slices, DB_SIZE);
+                let db_sizes = engine.load_full_db(&mut db_slices, &entries);
+                engine.dot(&query, &db_slices.code_gr, &db_sizes, 0, &streams, &blass);
+                engine.dot_reduce_and_multiply(
+                    &query_sums,
+                    &db_slices.code_sums_gr,
+                    &db_sizes,
+                    0,
+                    &streams,
+                    multiplier,
+                );
+                device_manager.await_streams(&streams);
+
+                let gathered = engine.gather_pairs(&pairs, &db_sizes, &streams).unwrap();
+                for (score, share) in scores.iter_mut().zip(gathered) {
+                    *score = score.wrapping_add(share);
+                }
+
+                let too_many = vec![(0, 0); MAX_SCORE_PAIRS + 1];
+                assert_eq!(
+                    engine.gather_pairs(&too_many, &db_sizes, &streams),
+                    Err(PairScoringError::TooManyPairs {
+                        got: MAX_SCORE_PAIRS + 1,
+                        max: MAX_SCORE_PAIRS,
+                    })
+                );
+                assert_eq!(
+                    engine.gather_pairs(&[(QUERY_SIZE, 0)], &db_sizes, &streams),
+                    Err(PairScoringError::OutOfRange {
+                        query: QUERY_SIZE,
+                        db:    0,
+                    })
+                );
+            }
+        }
+
+        for (i, &(query, entry)) in pairs.iter().enumerate() {
+            let (query, entry) = (&db.db[query], &db.db[entry]);
+            let (distance, mask_len, _) = query.masked_distance(entry).unwrap_or_default();
+            let (distance, mask_len) = (distance as u16, mask_len as u16);
+            assert_eq!(code_scores[i], mask_len.wrapping_sub(2 * distance));
+            assert_eq!(mask_scores[i], mask_len);
+        }
+        assert_eq!(code_scores[pairs.len() - 1], 0);
+        assert_eq!(mask_scores[pairs.len() - 1], 0);
+    }
+
+    /// The host codes and the device sums of all entries, per device.
+    fn db_contents(
+        engine: &ShareDB,
+        db: &SlicedProcessedDatabase,
+        db_sizes: &[usize],
+        streams: &[CudaStream],
+    ) -> Vec<(Vec<u8>, Vec<u32>)> {
+        let mut contents = vec![];
+        for (device_index, &db_size) in db_sizes.iter().enumerate() {
+            for (codes, sums) in [
+                (&db.code_gr.limb_0, &db.code_sums_gr.limb_0),
+                (&db.code_gr.limb_1, &db.code_sums_gr.limb_1),
+            ] {
+                let codes = unsafe {
+                    std::slice::from_raw_parts(
+                        codes[device_index] as *const u8,
+                        db_size * engine.code_length,
+                    )
+                };
+                let sums = unsafe {
+                    dtoh_from_on_stream_sync_chunked(
+                        sums[device_index].cu_device_ptr,
+                        db_size,
+                        &engine.device_manager.device(device_index),
+                        streams[device_index].stream,
+                        &engine.chunked_transfer,
+                    )
+                    .unwrap()
+                };
+                contents.push((codes.to_vec(), sums));
+            }
+        }
+        contents
+    }
+
+    /// Compares the sums computed on the host by `preprocess_for_query` with
+    /// the sums of [`ShareDB::query_sums`], and checks that uploading them
+    /// yields the same device buffers.
+    #[test]
+    fn check_host_query_sums() {
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let device_manager = Arc::new(DeviceManager::init());
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let irises = (0..QUERY_SIZE)
+            .map(|_| IrisCode::random_rng(&mut rng))
+            .collect::<Vec<_>>();
+
+        for party_id in 0..3 {
+            let codes = irises
+                .iter()
+                .map(|iris| {
+                    let mut share =
+                        GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, &mut rng)
+                            [party_id]
+                            .clone();
+                    share.preprocess_iris_code_query_share();
+                    share
+                })
+                .collect::<Vec<_>>();
+            let masks = codes
+                .iter()
+                .map(GaloisRingTrimmedMaskCodeShare::from)
+                .collect::<Vec<_>>();
+
+            for (coefs, host, code_length) in [
+                (
+                    codes.iter().flat_map(|c| c.coefs).collect::<Vec<_>>(),
+                    codes.iter().map(|c| c.preprocess_for_query()).collect_vec(),
+                    IRIS_CODE_LENGTH,
+                ),
+                (
+                    masks.iter().flat_map(|m| m.coefs).collect::<Vec<_>>(),
+                    masks.iter().map(|m| m.preprocess_for_query()).collect_vec(),
+                    MASK_CODE_LENGTH,
+                ),
+            ] {
+                let limbs = preprocess_query(&coefs);
+                for (limb, limb_bytes) in limbs.iter().enumerate() {
+                    let host_bytes = host
+                        .iter()
+                        .flat_map(|p| p.limbs[limb].clone())
+                        .collect::<Vec<_>>();
+                    assert_eq!(&host_bytes, limb_bytes);
+                }
+                let host_sums = (0..2)
+                    .map(|limb| host.iter().map(|p| p.sums[limb]).collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+
+                let engine = ShareDB::init(
+                    party_id,
+                    device_manager.clone(),
+                    DB_SIZE,
+                    QUERY_SIZE,
+                    code_length,
+                    ([0u32; 8], [0u32; 8]),
+                    vec![],
+                );
+                let query = device_manager
+                    .htod_transfer_query(&limbs, &streams, QUERY_SIZE, code_length)
+                    .unwrap();
+                let device_sums = engine.query_sums(&query, &streams, &blass);
+                let uploaded = device_manager
+                    .htod_transfer_query_sums(&host_sums, &streams, QUERY_SIZE * ROTATIONS)
+                    .unwrap();
+                device_manager.await_streams(&streams);
+
+                for device_index in 0..device_manager.device_count() {
+                    for (limb, (computed, uploaded)) in [
+                        (&device_sums.limb_0, &uploaded.limb_0),
+                        (&device_sums.limb_1, &uploaded.limb_1),
+                    ]
+                    .into_iter()
+                    .enumerate()
+                    {
+                        for sums in [computed, uploaded] {
+                            let sums = unsafe {
+                                dtoh_from_on_stream_sync_chunked::<u32>(
+                                    sums[device_index].cu_device_ptr,
+                                    QUERY_SIZE,
+                                    &device_manager.device(device_index),
+                                    streams[device_index].stream,
+                                    &engine.chunked_transfer,
+                                )
+                                .unwrap()
+                            };
+                            assert_eq!(sums, host_sums[limb]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Snapshots a DB, then restores it into fresh buffers and appends the
+    /// entries added since. A corrupted or stale snapshot is refused.
+    #[test]
+    fn check_snapshot_restore() {
+        const SNAPSHOT_DB_SIZE: usize = 8 * 16;
+        const NEW_ENTRIES: usize = 8;
+        let db = random_vec(SNAPSHOT_DB_SIZE, WIDTH, u16::MAX as u32);
+        let device_manager = Arc::new(DeviceManager::init());
+        let n_devices = device_manager.device_count();
+        let streams = device_manager.fork_streams();
+        let engine = ShareDB::init(
+            0,
+            device_manager.clone(),
+            SNAPSHOT_DB_SIZE,
+            QUERY_SIZE,
+            IRIS_CODE_LENGTH,
+            ([0u32; 8], [0u32; 8]),
+            vec![],
+        );
+
+        let snapshot_len = SNAPSHOT_DB_SIZE - NEW_ENTRIES;
+        let mut db_slices = engine.alloc_db(SNAPSHOT_DB_SIZE);
+        let db_sizes = engine.load_full_db(&mut db_slices, &db[..snapshot_len * WIDTH]);
+        let digest = SnapshotDigest {
+            db_len:           snapshot_len,
+            last_modified_at: 1_700_000_000,
+        };
+        let dir = std::env::temp_dir().join(format!("share_db_snapshot_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.snapshot");
+        engine
+            .snapshot_to(&path, &db_slices, &db_sizes, digest, &streams)
+            .unwrap();
+
+        // A single flipped byte is caught by the checksums.
+        let mut corrupted = std::fs::read(&path).unwrap();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 1;
+        let corrupted_path = dir.join("corrupted.snapshot");
+        std::fs::write(&corrupted_path, corrupted).unwrap();
+        let mut restored = engine.alloc_db(SNAPSHOT_DB_SIZE);
+        assert!(matches!(
+            engine.restore_from(
+                &corrupted_path,
+                &mut restored,
+                SNAPSHOT_DB_SIZE,
+                &digest,
+                &streams
+            ),
+            Err(SnapshotError::Corrupted { .. })
+        ));
+
+        // The store changed entries covered by the snapshot.
+        let stale = SnapshotDigest {
+            last_modified_at: digest.last_modified_at + 1,
+            ..digest
+        };
+        assert!(matches!(
+            engine.restore_from(&path, &mut restored, SNAPSHOT_DB_SIZE, &stale, &streams),
+            Err(SnapshotError::Stale { .. })
+        ));
+
+        let restored_sizes = engine
+            .restore_from(&path, &mut restored, SNAPSHOT_DB_SIZE, &digest, &streams)
+            .unwrap();
+        assert_eq!(restored_sizes, db_sizes);
+        assert_eq!(
+            db_contents(&engine, &restored, &restored_sizes, &streams),
+            db_contents(&engine, &db_slices, &db_sizes, &streams)
+        );
+
+        // The entries added after the snapshot are appended on top of it.
+        let mut full = engine.alloc_db(SNAPSHOT_DB_SIZE);
+        let full_sizes = engine.load_full_db(&mut full, &db);
+        let mut appended_sizes = restored_sizes.clone();
+        for index in snapshot_len..SNAPSHOT_DB_SIZE {
+            ShareDB::load_single_record(
+                index,
+                &restored.code_gr,
+                &db[index * WIDTH..(index + 1) * WIDTH],
+                n_devices,
+                IRIS_CODE_LENGTH,
+            );
+            appended_sizes[index % n_devices] += 1;
+        }
+        engine
+            .append_db(&mut restored, &restored_sizes, &appended_sizes)
+            .unwrap();
+        assert_eq!(appended_sizes, full_sizes);
+        assert_eq!(
+            db_contents(&engine, &restored, &appended_sizes, &streams),
+            db_contents(&engine, &full, &full_sizes, &streams)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Rotating the query masks on the devices gives the same queries, and
+    /// thus the same mask dot products and match decisions, as rotating them
+    /// on the host.
+    #[test]
+    fn check_device_mask_rotations() {
+        const PROBES: usize = 4;
+        let party_id = 0;
+        let mut rng = StdRng::seed_from_u64(RNG_SEED);
+        let db = IrisDB::new_random_rng(DB_SIZE, &mut rng);
+        let masks: Vec<GaloisRingTrimmedMaskCodeShare> = db
+            .db
+            .iter()
+            .map(|iris| {
+                GaloisRingIrisCodeShare::encode_mask_code(&iris.mask, &mut rng)[party_id]
+                    .clone()
+                    .into()
+            })
+            .collect();
+        let masks_db = masks.iter().flat_map(|mask| mask.coefs).collect::<Vec<_>>();
+
+        // Enrolled masks, as is and rotated by a few columns.
+        let probes = [
+            masks[0].clone(),
+            masks[1].clone(),
+            masks[2].all_rotations()[ROTATIONS / 2 + 3].clone(),
+            masks[3].all_rotations()[ROTATIONS / 2 - 5].clone(),
+        ]
+        .map(|mut probe| {
+            probe.preprocess_mask_code_query_share();
+            probe
+        });
+        let host_rotated = preprocess_query(
+            &probes
+                .iter()
+                .flat_map(|probe| probe.all_rotations())
+                .flat_map(|probe| probe.coefs)
+                .collect::<Vec<_>>(),
+        );
+        let unrotated =
+            preprocess_query(&probes.iter().flat_map(|probe| probe.coefs).collect_vec());
+
+        let device_manager = Arc::new(DeviceManager::init());
+        let streams = device_manager.fork_streams();
+        let blass = device_manager.create_cublas(&streams);
+        let mut results = vec![];
+        for device_rotations in [false, true] {
+            // A fresh engine for each run, so that both draw the same randomness.
+            let mut engine = ShareDB::init(
+                party_id,
+                device_manager.clone(),
+                DB_SIZE,
+                PROBES * ROTATIONS,
+                MASK_CODE_LENGTH,
+                ([0u32; 8], [0u32; 8]),
+                vec![],
+            );
+            let mut db_slices = engine.alloc_db(DB_SIZE);
+            let db_sizes = engine.load_full_db(&mut db_slices, &masks_db);
+
+            let queries = if device_rotations {
+                let unrotated = device_manager
+                    .htod_transfer_unrotated_query(&unrotated, &streams, PROBES, MASK_CODE_LENGTH)
+                    .unwrap();
+                engine.rotate_queries(&unrotated, PROBES, &streams)
+            } else {
+                device_manager
+                    .htod_transfer_query(&host_rotated, &streams, PROBES, MASK_CODE_LENGTH)
+                    .unwrap()
+            };
+            let query_sums = engine.query_sums(&queries, &streams, &blass);
+            engine.dot(&queries, &db_slices.code_gr, &db_sizes, 0, &streams, &blass);
+            engine.dot_reduce(&query_sums, &db_slices.code_sums_gr, &db_sizes, 0, &streams);
+            device_manager.await_streams(&streams);
+
+            let mut queries_host = vec![];
+            for limb in [&queries.limb_0, &queries.limb_1] {
+                for (device_index, query) in limb.iter().enumerate() {
+                    queries_host.push(unsafe {
+                        dtoh_from_on_stream_sync_chunked::<u8>(
+                            query.cu_device_ptr,
+                            query.len,
+                            &device_manager.device(device_index),
+                            streams[device_index].stream,
+                            &engine.chunked_transfer,
+                        )
+                        .unwrap()
+                    });
+                }
+            }
+            let mut products = vec![0u16; db_sizes[0] * PROBES * ROTATIONS];
+            engine.fetch_results(&mut products, &db_sizes, 0);
+            results.push((queries_host, products));
+        }
+
+        assert_eq!(results[0].0, results[1].0);
+        assert_eq!(results[0].1, results[1].1);
+    }
 }
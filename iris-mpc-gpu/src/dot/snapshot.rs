@@ -0,0 +1,130 @@
+//! File format of the local DB snapshots, see
+//! [`super::share_db::ShareDB::snapshot_to`].
+//!
+//! A snapshot holds the packed code limbs and the sums of one
+//! [`super::share_db::SlicedProcessedDatabase`], so that a restart can skip
+//! reloading the DB from Postgres. The file starts with a magic, a checksummed
+//! [`SnapshotHeader`], and then for each device the two code limbs followed by
+//! the two sum limbs. Every section is written in chunks of at most
+//! [`SNAPSHOT_CHUNK_SIZE`] bytes, each followed by its SHA-256.
+
+use crate::helpers::chunked_copy::ChunkedTransferError;
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"IRISSNAP";
+pub const SNAPSHOT_VERSION: u32 = 1;
+pub const SNAPSHOT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const MAX_HEADER_LEN: u64 = 1024 * 1024;
+
+/// The state of the persistent store a snapshot was taken from: the number of
+/// stored entries and the latest modification time among them. If the store
+/// reports a different digest for the same entries, some of them were changed
+/// or rolled back since, and the snapshot is stale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotDigest {
+    pub db_len:           usize,
+    pub last_modified_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub version:     u32,
+    pub code_length: usize,
+    /// Number of entries on each device.
+    pub db_sizes:    Vec<usize>,
+    pub digest:      SnapshotDigest,
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Transfer(#[from] ChunkedTransferError),
+    #[error("not a DB snapshot or unsupported version: {0}")]
+    Format(String),
+    #[error("checksum mismatch in snapshot section {section}")]
+    Corrupted { section: String },
+    #[error("snapshot does not fit this DB: {0}")]
+    LayoutMismatch(String),
+    #[error("stale snapshot: taken at {snapshot:?}, the store reports {expected:?}")]
+    Stale {
+        snapshot: SnapshotDigest,
+        expected: SnapshotDigest,
+    },
+}
+
+pub(super) fn write_header(writer: &mut impl Write, header: &SnapshotHeader) -> io::Result<()> {
+    let header = bincode::serialize(header).map_err(io::Error::other)?;
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&(header.len() as u64).to_le_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(digest(&SHA256, &header).as_ref())
+}
+
+/// Reads and checks the header of a snapshot without reading the data.
+pub fn read_header(reader: &mut impl Read) -> Result<SnapshotHeader, SnapshotError> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::Format("bad magic".to_string()));
+    }
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_HEADER_LEN {
+        return Err(SnapshotError::Format(format!("header of {} bytes", len)));
+    }
+    let mut header = vec![0u8; len as usize];
+    reader.read_exact(&mut header)?;
+    read_checksum(reader, &header, "header")?;
+    let header: SnapshotHeader =
+        bincode::deserialize(&header).map_err(|e| SnapshotError::Format(e.to_string()))?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::Format(format!(
+            "version {}, expected {}",
+            header.version, SNAPSHOT_VERSION
+        )));
+    }
+    Ok(header)
+}
+
+/// Writes `data` in checksummed chunks.
+pub(super) fn write_section(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(SNAPSHOT_CHUNK_SIZE) {
+        writer.write_all(chunk)?;
+        writer.write_all(digest(&SHA256, chunk).as_ref())?;
+    }
+    Ok(())
+}
+
+/// Reads a section written by [`write_section`] into `data`, checking every
+/// chunk before it is copied.
+pub(super) fn read_section(
+    reader: &mut impl Read,
+    data: &mut [u8],
+    section: &str,
+) -> Result<(), SnapshotError> {
+    let mut buf = vec![0u8; SNAPSHOT_CHUNK_SIZE.min(data.len())];
+    for chunk in data.chunks_mut(SNAPSHOT_CHUNK_SIZE) {
+        let buf = &mut buf[..chunk.len()];
+        reader.read_exact(buf)?;
+        read_checksum(reader, buf, section)?;
+        chunk.copy_from_slice(buf);
+    }
+    Ok(())
+}
+
+fn read_checksum(reader: &mut impl Read, data: &[u8], section: &str) -> Result<(), SnapshotError> {
+    let mut checksum = [0u8; SHA256_OUTPUT_LEN];
+    reader.read_exact(&mut checksum)?;
+    if digest(&SHA256, data).as_ref() != checksum {
+        return Err(SnapshotError::Corrupted {
+            section: section.to_string(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,421 @@
+//! Host/device transfers split into chunks of a configurable size.
+//!
+//! Large buffers (the DB sums, the opened results of a full batch) are copied
+//! chunk by chunk on the given stream. An event is recorded and waited on
+//! after every chunk, which is also where a [`TransferCancellation`] is
+//! checked, so that a shutdown does not have to wait for a multi-GB copy to
+//! finish. Optionally, the chunks are staged through page-locked host buffers
+//! taken from a [`PinnedStagingPool`].
+
+use cudarc::driver::{
+    result::{self, event, memcpy_dtoh_async, memcpy_htod_async},
+    sys::{self, lib, CUdeviceptr, CUstream},
+    CudaDevice, CudaSlice, CudaStream, DevicePtr, DevicePtrMut, DeviceRepr, DriverError,
+};
+use std::{
+    ffi::c_void,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use thiserror::Error;
+
+pub const DEFAULT_CHUNK_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum ChunkedTransferError {
+    #[error(transparent)]
+    Driver(#[from] DriverError),
+    #[error("transfer cancelled after {copied} of {total} bytes")]
+    Cancelled { copied: usize, total: usize },
+}
+
+/// Shared flag to stop running transfers at the next chunk boundary.
+#[derive(Debug, Clone, Default)]
+pub struct TransferCancellation(Arc<AtomicBool>);
+
+impl TransferCancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+struct PinnedBuffer {
+    ptr: *mut c_void,
+}
+
+// SAFETY: the buffer is owned exclusively, either by the pool or by the single
+// transfer that leased it.
+unsafe impl Send for PinnedBuffer {}
+
+impl Drop for PinnedBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            lib().cuMemFreeHost(self.ptr).result().unwrap();
+        }
+    }
+}
+
+/// Page-locked host buffers of a fixed size, reused across transfers. The
+/// buffers are allocated as portable, so they can be used with any device.
+pub struct PinnedStagingPool {
+    buffer_size: usize,
+    buffers:     Mutex<Vec<PinnedBuffer>>,
+}
+
+impl PinnedStagingPool {
+    pub fn new(buffer_size: usize) -> Self {
+        assert!(buffer_size > 0, "Staging buffers must not be empty");
+        Self {
+            buffer_size,
+            buffers: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Number of buffers currently idle in the pool.
+    pub fn available(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    fn lease(&self) -> Result<PinnedLease<'_>, DriverError> {
+        let buffer = match self.buffers.lock().unwrap().pop() {
+            Some(buffer) => buffer,
+            None => {
+                let mut ptr = std::ptr::null_mut();
+                unsafe {
+                    lib()
+                        .cuMemHostAlloc(&mut ptr, self.buffer_size, sys::CU_MEMHOSTALLOC_PORTABLE)
+                        .result()?;
+                }
+                PinnedBuffer { ptr }
+            }
+        };
+        Ok(PinnedLease {
+            pool:   self,
+            buffer: Some(buffer),
+        })
+    }
+}
+
+/// A buffer taken from the pool, handed back when dropped.
+struct PinnedLease<'a> {
+    pool:   &'a PinnedStagingPool,
+    buffer: Option<PinnedBuffer>,
+}
+
+impl PinnedLease<'_> {
+    fn ptr(&self) -> *mut u8 {
+        self.buffer.as_ref().unwrap().ptr as *mut u8
+    }
+}
+
+impl Drop for PinnedLease<'_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.buffers.lock().unwrap().push(buffer);
+        }
+    }
+}
+
+/// Settings of chunked transfers.
+#[derive(Clone)]
+pub struct ChunkedTransfer {
+    chunk_size_bytes: usize,
+    staging:          Option<Arc<PinnedStagingPool>>,
+    cancellation:     TransferCancellation,
+}
+
+impl Default for ChunkedTransfer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHUNK_SIZE_BYTES)
+    }
+}
+
+impl ChunkedTransfer {
+    pub fn new(chunk_size_bytes: usize) -> Self {
+        assert!(chunk_size_bytes > 0, "Chunk size must not be zero");
+        Self {
+            chunk_size_bytes,
+            staging: None,
+            cancellation: TransferCancellation::new(),
+        }
+    }
+
+    /// Stages the chunks through the buffers of `pool`. The chunk size is
+    /// capped at the buffer size of the pool.
+    pub fn with_staging_pool(mut self, pool: Arc<PinnedStagingPool>) -> Self {
+        self.chunk_size_bytes = self.chunk_size_bytes.min(pool.buffer_size());
+        self.staging = Some(pool);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: TransferCancellation) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn chunk_size_bytes(&self) -> usize {
+        self.chunk_size_bytes
+    }
+
+    pub fn cancellation(&self) -> &TransferCancellation {
+        &self.cancellation
+    }
+
+    fn chunk_len<T>(&self) -> usize {
+        (self.chunk_size_bytes / mem::size_of::<T>().max(1)).max(1)
+    }
+
+    /// Runs `copy_chunk(offset, len, staging)` for every chunk of a buffer of
+    /// `len` elements, waiting for each chunk to land before checking for
+    /// cancellation and issuing the next one. `after_chunk` runs once the
+    /// chunk is complete, i.e. when it is safe to read the staging buffer.
+    fn for_each_chunk<T>(
+        &self,
+        len: usize,
+        stream: CUstream,
+        mut copy_chunk: impl FnMut(usize, usize, Option<*mut u8>) -> Result<(), DriverError>,
+        mut after_chunk: impl FnMut(usize, usize, Option<*mut u8>),
+    ) -> Result<(), ChunkedTransferError> {
+        let lease = self.staging.as_ref().map(|pool| pool.lease()).transpose()?;
+        let staging = lease.as_ref().map(|lease| lease.ptr());
+        let chunk_len = self.chunk_len::<T>();
+        let evt = event::create(sys::CUevent_flags::CU_EVENT_DISABLE_TIMING)?;
+
+        let res = (|| {
+            let mut offset = 0;
+            while offset < len {
+                if self.cancellation.is_cancelled() {
+                    return Err(ChunkedTransferError::Cancelled {
+                        copied: offset * mem::size_of::<T>(),
+                        total:  len * mem::size_of::<T>(),
+                    });
+                }
+                let n = chunk_len.min(len - offset);
+                copy_chunk(offset, n, staging)?;
+                unsafe {
+                    event::record(evt, stream)?;
+                    lib().cuEventSynchronize(evt).result()?;
+                }
+                after_chunk(offset, n, staging);
+                offset += n;
+            }
+            Ok(())
+        })();
+
+        unsafe {
+            event::destroy(evt)?;
+        }
+        res
+    }
+}
+
+/// Chunked variant of [`super::dtoh_on_stream_sync`].
+pub fn dtoh_on_stream_sync_chunked<T: DeviceRepr + Default + Clone, U: DevicePtr<T>>(
+    input: &U,
+    device: &Arc<CudaDevice>,
+    stream: &CudaStream,
+    transfer: &ChunkedTransfer,
+) -> Result<Vec<T>, ChunkedTransferError> {
+    // SAFETY: `input` is a device buffer of `input.len()` elements.
+    unsafe {
+        dtoh_from_on_stream_sync_chunked(
+            *input.device_ptr(),
+            input.len(),
+            device,
+            stream.stream,
+            transfer,
+        )
+    }
+}
+
+/// Chunked copy of `len` elements of device memory at `src` to the host.
+///
+/// # Safety
+/// `src` must point to device memory of at least `len` elements, which is not
+/// written to by anything else until this returns.
+pub unsafe fn dtoh_from_on_stream_sync_chunked<T: DeviceRepr + Default + Clone>(
+    src: CUdeviceptr,
+    len: usize,
+    device: &Arc<CudaDevice>,
+    stream: CUstream,
+    transfer: &ChunkedTransfer,
+) -> Result<Vec<T>, ChunkedTransferError> {
+    device.bind_to_thread()?;
+    let size = mem::size_of::<T>();
+    let mut buf = vec![T::default(); len];
+    let buf_ptr = buf.as_mut_ptr();
+
+    // SAFETY: every chunk is synchronized before `buf` (or the staging buffer) is
+    // touched again, so neither is written to by the device after this returns.
+    transfer.for_each_chunk::<T>(
+        len,
+        stream,
+        |offset, n, staging| unsafe {
+            let dst = match staging {
+                Some(staging) => std::slice::from_raw_parts_mut(staging as *mut T, n),
+                None => std::slice::from_raw_parts_mut(buf_ptr.add(offset), n),
+            };
+            memcpy_dtoh_async(dst, src + (offset * size) as u64, stream)
+        },
+        |offset, n, staging| unsafe {
+            if let Some(staging) = staging {
+                std::ptr::copy_nonoverlapping(staging as *const T, buf_ptr.add(offset), n);
+            }
+        },
+    )?;
+
+    Ok(buf)
+}
+
+/// Chunked copy of `input` into already allocated device memory.
+///
+/// # Safety
+/// `dst` must point to device memory of at least `input.len()` elements, which
+/// is not accessed by anything else until this returns.
+pub unsafe fn htod_into_on_stream_sync_chunked<T: DeviceRepr>(
+    input: &[T],
+    dst: CUdeviceptr,
+    device: &Arc<CudaDevice>,
+    stream: CUstream,
+    transfer: &ChunkedTransfer,
+) -> Result<(), ChunkedTransferError> {
+    device.bind_to_thread()?;
+    let size = mem::size_of::<T>();
+    transfer.for_each_chunk::<T>(
+        input.len(),
+        stream,
+        |offset, n, staging| {
+            let chunk = &input[offset..offset + n];
+            let dst = dst + (offset * size) as u64;
+            match staging {
+                Some(staging) => {
+                    std::ptr::copy_nonoverlapping(chunk.as_ptr(), staging as *mut T, n);
+                    memcpy_htod_async(
+                        dst,
+                        std::slice::from_raw_parts(staging as *const T, n),
+                        stream,
+                    )
+                }
+                None => memcpy_htod_async(dst, chunk, stream),
+            }
+        },
+        |_, _, _| {},
+    )
+}
+
+/// Chunked variant of [`super::htod_on_stream_sync`]. On cancellation, the
+/// partially filled device buffer is freed before returning.
+pub fn htod_on_stream_sync_chunked<T: DeviceRepr>(
+    input: &[T],
+    device: &Arc<CudaDevice>,
+    stream: &CudaStream,
+    transfer: &ChunkedTransfer,
+) -> Result<CudaSlice<T>, ChunkedTransferError> {
+    device.bind_to_thread()?;
+    // SAFETY: the uninitialized buffer is fully overwritten by the copy, or
+    // dropped if the copy does not complete.
+    unsafe {
+        let mut buf = device.alloc(input.len())?;
+        htod_into_on_stream_sync_chunked(
+            input,
+            *buf.device_ptr_mut(),
+            device,
+            stream.stream,
+            transfer,
+        )?;
+        Ok(buf)
+    }
+}
+
+/// Free and total memory of the device bound to the current thread.
+pub fn device_mem_info(device: &Arc<CudaDevice>) -> Result<(usize, usize), DriverError> {
+    device.bind_to_thread()?;
+    result::mem_get_info()
+}
+
+#[cfg(test)]
+#[cfg(feature = "gpu_dependent")]
+mod tests {
+    use super::*;
+    use crate::helpers::device_manager::DeviceManager;
+
+    const CHUNK_SIZE_BYTES: usize = 4096;
+
+    fn test_data(len: usize) -> Vec<u32> {
+        (0..len as u32)
+            .map(|x| x.wrapping_mul(0x9e3779b9))
+            .collect()
+    }
+
+    fn round_trip(transfer: &ChunkedTransfer) {
+        let device_manager = DeviceManager::init();
+        let device = device_manager.device(0);
+        let stream = device.fork_default_stream().unwrap();
+        let chunk_len = CHUNK_SIZE_BYTES / mem::size_of::<u32>();
+
+        for len in [
+            1,
+            chunk_len - 1,
+            chunk_len,
+            chunk_len + 1,
+            3 * chunk_len + 1,
+        ] {
+            let data = test_data(len);
+            let slice = htod_on_stream_sync_chunked(&data, &device, &stream, transfer).unwrap();
+            assert_eq!(device.dtoh_sync_copy(&slice).unwrap(), data);
+            let back = dtoh_on_stream_sync_chunked(&slice, &device, &stream, transfer).unwrap();
+            assert_eq!(back, data, "round trip of {} elements", len);
+        }
+    }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        round_trip(&ChunkedTransfer::new(CHUNK_SIZE_BYTES));
+    }
+
+    #[test]
+    fn test_chunked_round_trip_pinned_staging() {
+        let pool = Arc::new(PinnedStagingPool::new(CHUNK_SIZE_BYTES));
+        round_trip(&ChunkedTransfer::new(DEFAULT_CHUNK_SIZE_BYTES).with_staging_pool(pool.clone()));
+        // The staging buffer is handed back after every transfer.
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn test_cancelled_transfer_frees_device_memory() {
+        let device_manager = DeviceManager::init();
+        let device = device_manager.device(0);
+        let stream = device.fork_default_stream().unwrap();
+        let transfer = ChunkedTransfer::new(CHUNK_SIZE_BYTES);
+        let data = test_data(1 << 24);
+
+        device.synchronize().unwrap();
+        let (free_before, _) = device_mem_info(&device).unwrap();
+
+        transfer.cancellation().cancel();
+        let res = htod_on_stream_sync_chunked(&data, &device, &stream, &transfer);
+        assert!(matches!(
+            res,
+            Err(ChunkedTransferError::Cancelled { copied: 0, .. })
+        ));
+
+        device.synchronize().unwrap();
+        let (free_after, _) = device_mem_info(&device).unwrap();
+        assert_eq!(free_before, free_after);
+    }
+}
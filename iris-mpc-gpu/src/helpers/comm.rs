@@ -2,7 +2,20 @@ use cudarc::{
     driver::{CudaDevice, CudaSlice, CudaStream, CudaView, DevicePtr, DevicePtrMut, DeviceSlice},
     nccl::{result, sys, Id, NcclType},
 };
-use std::{mem::MaybeUninit, ptr, sync::Arc};
+use std::{
+    mem::MaybeUninit,
+    ptr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// `NCCL_CONFIG_INITIALIZER` of `nccl.h`, for the fields of NCCL 2.17.
+const NCCL_CONFIG_MAGIC: u32 = 0xcafebeef;
+const NCCL_CONFIG_VERSION: u32 = 21700;
+const NCCL_CONFIG_UNDEF_INT: i32 = i32::MIN;
+/// How long to wait before polling a nonblocking communicator again.
+const NCCL_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 pub struct NcclComm {
@@ -10,6 +23,9 @@ pub struct NcclComm {
     device:     Arc<CudaDevice>,
     rank:       usize,
     world_size: usize,
+    /// Set once the communicator is aborted, which frees it. Held while
+    /// polling the communicator, so that it is not freed meanwhile.
+    aborted:    Arc<Mutex<bool>>,
 }
 
 // creation methods
@@ -84,8 +100,59 @@ impl NcclComm {
             device,
             rank,
             world_size,
+            aborted: Arc::new(Mutex::new(false)),
         })
     }
+
+    /// Like [`Self::from_rank`], but returns as soon as the initialization is
+    /// started, without waiting for the other ranks to join. Until
+    /// [`Self::wait`] returns, the initialization may be aborted from another
+    /// thread, see [`Self::abort_handle`]. Operations on the communicator may
+    /// return [`result::NcclStatus::InProgress`], and must then be waited for
+    /// as well.
+    pub fn from_rank_nonblocking(
+        device: Arc<CudaDevice>,
+        rank: usize,
+        world_size: usize,
+        id: Id,
+    ) -> Result<Self, result::NcclError> {
+        let mut comm = MaybeUninit::uninit();
+
+        let id_low = sys::ncclUniqueId {
+            internal: *id.internal(),
+        };
+        let mut config: sys::ncclConfig_t = unsafe { MaybeUninit::zeroed().assume_init() };
+        config.size = size_of::<sys::ncclConfig_t>();
+        config.magic = NCCL_CONFIG_MAGIC;
+        config.version = NCCL_CONFIG_VERSION;
+        config.blocking = 0;
+        config.cgaClusterSize = NCCL_CONFIG_UNDEF_INT;
+        config.minCTAs = NCCL_CONFIG_UNDEF_INT;
+        config.maxCTAs = NCCL_CONFIG_UNDEF_INT;
+
+        let comm = unsafe {
+            sys::lib()
+                .ncclCommInitRankConfig(
+                    comm.as_mut_ptr(),
+                    world_size
+                        .try_into()
+                        .expect("World_size cannot be casted to i32"),
+                    id_low,
+                    rank.try_into().expect("Rank cannot be cast to i32"),
+                    &mut config,
+                )
+                .result()?;
+            comm.assume_init()
+        };
+        Ok(Self {
+            comm,
+            device,
+            rank,
+            world_size,
+            aborted: Arc::new(Mutex::new(false)),
+        })
+    }
+
     pub fn broadcast<S: DevicePtr<T>, R: DevicePtrMut<T>, T: NcclType>(
         &self,
         sendbuff: &Option<S>,
@@ -127,8 +194,79 @@ impl NcclComm {
     }
 }
 
+/// Aborts the communicator of a [`NcclComm`] from another thread, so that its
+/// pending operations return. The communicator must not be used afterwards.
+#[derive(Debug, Clone)]
+pub struct NcclAbortHandle {
+    comm:    sys::ncclComm_t,
+    aborted: Arc<Mutex<bool>>,
+}
+
+// The handle only passes the communicator to ncclCommAbort, which may be
+// called from any thread, and at most once per communicator.
+unsafe impl Send for NcclAbortHandle {}
+
+impl NcclAbortHandle {
+    /// Aborts the communicator, unless it already was.
+    pub fn abort(&self) -> Result<(), result::NcclError> {
+        let mut aborted = self.aborted.lock().unwrap();
+        if *aborted {
+            return Ok(());
+        }
+        *aborted = true;
+        unsafe { result::comm_abort(self.comm) }?;
+        Ok(())
+    }
+}
+
 // our comm methods
 impl NcclComm {
+    pub fn abort_handle(&self) -> NcclAbortHandle {
+        NcclAbortHandle {
+            comm:    self.comm,
+            aborted: self.aborted.clone(),
+        }
+    }
+
+    /// Aborts the communicator after a failed operation, see
+    /// [`NcclAbortHandle::abort`]. The communicator must not be used
+    /// afterwards, a new one is created with
+    /// [`DeviceManager::reinit`](super::device_manager::DeviceManager::reinit).
+    pub fn abort(&self) -> Result<(), result::NcclError> {
+        self.abort_handle().abort()
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        *self.aborted.lock().unwrap()
+    }
+
+    /// Waits until the pending initialization or operations of a nonblocking
+    /// communicator complete, see [`Self::from_rank_nonblocking`]. Returns
+    /// immediately for a blocking one. Fails once the communicator is aborted.
+    pub fn wait(&self) -> Result<(), result::NcclError> {
+        loop {
+            let state = {
+                let aborted = self.aborted.lock().unwrap();
+                if *aborted {
+                    // The communicator is freed, and can no longer be polled.
+                    return Err(result::NcclError(sys::ncclResult_t::ncclInvalidUsage));
+                }
+                let mut state = sys::ncclResult_t::ncclInProgress;
+                unsafe {
+                    sys::lib()
+                        .ncclCommGetAsyncError(self.comm, &mut state)
+                        .result()?;
+                }
+                state
+            };
+            if state != sys::ncclResult_t::ncclInProgress {
+                state.result()?;
+                return Ok(());
+            }
+            thread::sleep(NCCL_POLL_INTERVAL);
+        }
+    }
+
     pub fn send_u16(
         &self,
         send: &CudaSlice<u16>,
@@ -1,6 +1,7 @@
 use super::{
+    chunked_copy::device_mem_info,
     comm::NcclComm,
-    query_processor::{CudaVec2DSlicerU8, StreamAwareCudaSlice},
+    query_processor::{CudaVec2DSlicerU32, CudaVec2DSlicerU8, StreamAwareCudaSlice},
 };
 use crate::dot::ROTATIONS;
 use cudarc::{
@@ -15,11 +16,20 @@ use cudarc::{
     },
     nccl::Id,
 };
-use std::{sync::Arc, thread::sleep, time::Duration};
+use iris_mpc_common::helpers::memory_pressure::DeviceMemoryInfo;
+use std::{mem, sync::Arc, thread::sleep, time::Duration};
 
 pub const NCCL_START_WAIT_TIME: Duration = Duration::from_secs(5);
 pub const NCCL_START_RETRIES: usize = 5;
 
+/// The magic of the NCCL ids of the `generation`-th network, for
+/// [`DeviceManager::get_ids_from_magic`]. The network at startup is generation
+/// 0, every [`DeviceManager::reinit`] uses the next one, so that its ids
+/// differ from those of the failed communicators.
+pub fn network_magic(generation: u64) -> u64 {
+    generation << 32
+}
+
 #[derive(Debug, Clone)]
 pub struct DeviceManager {
     devices: Vec<Arc<CudaDevice>>,
@@ -48,6 +58,26 @@ impl DeviceManager {
         Self { devices }
     }
 
+    /// Uses only the devices with the given ordinals, in the given order. This
+    /// allows several parties to share one host, each with its own devices.
+    pub fn init_with_device_ids(device_ids: &[usize]) -> eyre::Result<Self> {
+        let n_devices = CudaDevice::count()? as usize;
+        let mut devices = vec![];
+        for (pos, &i) in device_ids.iter().enumerate() {
+            if i >= n_devices {
+                eyre::bail!("Device {} requested, but only {} present", i, n_devices);
+            }
+            if device_ids[..pos].contains(&i) {
+                eyre::bail!("Device {} requested twice", i);
+            }
+            devices.push(CudaDevice::new(i)?);
+        }
+
+        tracing::info!("Using devices {:?}", device_ids);
+
+        Ok(Self { devices })
+    }
+
     /// Splits the devices into n chunks, returning a device manager for each
     /// chunk.
     /// If too few devices are present, returns the original device manager.
@@ -138,10 +168,34 @@ impl DeviceManager {
         streams: &[CudaStream],
         batch_size: usize,
         code_size: usize,
+    ) -> eyre::Result<CudaVec2DSlicerU8> {
+        self.htod_transfer_query_of_size(
+            preprocessed_query,
+            streams,
+            batch_size * ROTATIONS * code_size,
+        )
+    }
+
+    /// Like [`Self::htod_transfer_query`], for queries without their
+    /// rotations, see [`crate::dot::share_db::ShareDB::rotate_queries`].
+    pub fn htod_transfer_unrotated_query(
+        &self,
+        preprocessed_query: &[Vec<u8>],
+        streams: &[CudaStream],
+        batch_size: usize,
+        code_size: usize,
+    ) -> eyre::Result<CudaVec2DSlicerU8> {
+        self.htod_transfer_query_of_size(preprocessed_query, streams, batch_size * code_size)
+    }
+
+    fn htod_transfer_query_of_size(
+        &self,
+        preprocessed_query: &[Vec<u8>],
+        streams: &[CudaStream],
+        query_size: usize,
     ) -> eyre::Result<CudaVec2DSlicerU8> {
         let mut slices0 = vec![];
         let mut slices1 = vec![];
-        let query_size = batch_size * ROTATIONS * code_size;
         for idx in 0..self.device_count() {
             let device = self.device(idx);
             device.bind_to_thread().unwrap();
@@ -187,6 +241,40 @@ impl DeviceManager {
         })
     }
 
+    /// Uploads the sums of both limbs of the queries, computed on the host
+    /// like [`crate::dot::share_db::ShareDB::query_sums`] computes them on the
+    /// devices. Every device gets room for `query_length` sums.
+    pub fn htod_transfer_query_sums(
+        &self,
+        sums: &[Vec<u32>],
+        streams: &[CudaStream],
+        query_length: usize,
+    ) -> eyre::Result<CudaVec2DSlicerU32> {
+        let mut slices = [vec![], vec![]];
+        for idx in 0..self.device_count() {
+            let device = self.device(idx);
+            device.bind_to_thread().unwrap();
+            for (limb, slices) in slices.iter_mut().enumerate() {
+                let len = query_length.max(sums[limb].len());
+                let ptr = unsafe {
+                    malloc_async(streams[idx].stream, len * mem::size_of::<u32>()).unwrap()
+                };
+                // Sums beyond the batch stay uninitialized, like the queries
+                // they belong to.
+                unsafe {
+                    memcpy_htod_async(ptr, &sums[limb], streams[idx].stream).unwrap();
+                }
+                slices.push(StreamAwareCudaSlice::<u32>::upgrade_ptr_stream(
+                    ptr,
+                    streams[idx].stream,
+                    len,
+                ));
+            }
+        }
+        let [limb_0, limb_1] = slices;
+        Ok(CudaVec2DSlicerU32 { limb_0, limb_1 })
+    }
+
     pub fn device(&self, index: usize) -> Arc<CudaDevice> {
         self.devices[index].clone()
     }
@@ -285,4 +373,30 @@ impl DeviceManager {
         }
         Ok(comms)
     }
+
+    /// Tears down the communicators after a failed collective and connects
+    /// new ones on the same devices, from `ids` of a new generation, see
+    /// [`network_magic`]. All parties must reinitialize together.
+    pub fn reinit(
+        &self,
+        peer_id: usize,
+        failed: &[Arc<NcclComm>],
+        ids: &[Id],
+    ) -> eyre::Result<Vec<Arc<NcclComm>>> {
+        for comm in failed {
+            if let Err(e) = comm.abort() {
+                tracing::warn!("Failed to abort NCCL communicator: {:?}", e);
+            }
+        }
+        self.instantiate_network_from_ids(peer_id, ids)
+    }
+}
+
+impl DeviceMemoryInfo for DeviceManager {
+    fn mem_info(&self) -> eyre::Result<Vec<(usize, usize)>> {
+        self.devices
+            .iter()
+            .map(|device| Ok(device_mem_info(device)?))
+            .collect()
+    }
 }
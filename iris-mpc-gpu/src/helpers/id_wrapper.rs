@@ -1,15 +1,46 @@
-use axum::extract::Path;
+use axum::{extract::Path, http::StatusCode};
 use cudarc::nccl::Id;
-use std::str::FromStr;
+use eyre::WrapErr;
+use std::{str::FromStr, thread, time::Duration};
+use thiserror::Error;
+
+/// Length of an NCCL id in bytes, twice that in hex.
+pub const ID_LEN: usize = 128;
+
+/// Attempts of [`fetch_id`], e.g. while the host is not serving yet or its
+/// response was cut off.
+pub const FETCH_ID_ATTEMPTS: usize = 10;
+const FETCH_ID_BASE_DELAY: Duration = Duration::from_millis(250);
+const FETCH_ID_MAX_DELAY: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Error, PartialEq)]
+pub enum IdParseError {
+    #[error("empty NCCL id")]
+    Empty,
+    #[error("NCCL id of {0} bytes, expected {ID_LEN}")]
+    WrongLength(usize),
+    #[error("NCCL id is not valid hex: {0}")]
+    InvalidHex(#[from] hex::FromHexError),
+}
 
 pub struct IdWrapper(pub Id);
 
 impl FromStr for IdWrapper {
-    type Err = hex::FromHexError;
+    type Err = IdParseError;
 
+    /// Parses the hex of [`Display`](std::fmt::Display), ignoring surrounding
+    /// whitespace.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut id: [std::ffi::c_char; 128] = [0; 128];
-        hex::decode_to_slice(s, bytemuck::cast_slice_mut(&mut id))?;
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(IdParseError::Empty);
+        }
+        let bytes = hex::decode(s)?;
+        if bytes.len() != ID_LEN {
+            return Err(IdParseError::WrongLength(bytes.len()));
+        }
+        let mut id: [std::ffi::c_char; ID_LEN] = [0; ID_LEN];
+        bytemuck::cast_slice_mut(&mut id).copy_from_slice(&bytes);
         Ok(IdWrapper(Id::uninit(id)))
     }
 }
@@ -29,7 +60,118 @@ impl std::fmt::Display for IdWrapper {
     }
 }
 
-pub async fn http_root(ids: Vec<Id>, Path(device_id): Path<String>) -> String {
-    let device_id: usize = device_id.parse().unwrap();
-    IdWrapper(ids[device_id]).to_string()
+/// Serves the id of a device. A malformed device id is a bad request, one
+/// without a device is not found.
+pub async fn http_root(
+    ids: Vec<Id>,
+    Path(device_id): Path<String>,
+) -> Result<String, (StatusCode, String)> {
+    let device_id: usize = device_id.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid device id {:?}", device_id),
+        )
+    })?;
+    let id = ids
+        .get(device_id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("No device {}", device_id)))?;
+    Ok(IdWrapper(*id).to_string())
+}
+
+/// Fetches the id of `device_id` from the [`http_root`] served at `host`.
+/// Failed requests and responses that do not parse are retried with
+/// exponential backoff, up to [`FETCH_ID_ATTEMPTS`] attempts.
+pub fn fetch_id(host: &str, device_id: usize) -> eyre::Result<Id> {
+    let url = format!("http://{}/{}", host, device_id);
+    let mut delay = FETCH_ID_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result = reqwest::blocking::get(&url)
+            .and_then(|res| res.error_for_status())
+            .and_then(|res| res.text())
+            .map_err(eyre::Report::from)
+            .and_then(|text| Ok(IdWrapper::from_str(&text)?.0));
+        match result {
+            Ok(id) => return Ok(id),
+            Err(e) if attempt < FETCH_ID_ATTEMPTS => {
+                tracing::warn!(
+                    "Failed to fetch NCCL id from {} (attempt {}): {:?}",
+                    url,
+                    attempt,
+                    e
+                );
+                thread::sleep(delay);
+                delay = (delay * 2).min(FETCH_ID_MAX_DELAY);
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to fetch NCCL id from {}", url))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some_id_hex() -> String {
+        (0..ID_LEN).map(|i| format!("{:02x}", i * 2)).collect()
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let hex = some_id_hex();
+        let id = IdWrapper::from_str(&hex).unwrap();
+        assert_eq!(id.to_string(), hex);
+        let parsed = IdWrapper::from_str(&id.to_string()).unwrap();
+        assert_eq!(parsed.0.internal(), id.0.internal());
+        // A trailing newline of an HTTP response.
+        let parsed = IdWrapper::from_str(&format!("{}\n", hex)).unwrap();
+        assert_eq!(parsed.to_string(), hex);
+    }
+
+    #[test]
+    fn test_malformed_ids() {
+        let hex = some_id_hex();
+        assert_eq!(IdWrapper::from_str("").err(), Some(IdParseError::Empty));
+        assert_eq!(IdWrapper::from_str(" \n").err(), Some(IdParseError::Empty));
+        // Truncated.
+        assert_eq!(
+            IdWrapper::from_str(&hex[..hex.len() - 2]).err(),
+            Some(IdParseError::WrongLength(ID_LEN - 1))
+        );
+        assert_eq!(
+            IdWrapper::from_str(&format!("{}00", hex)).err(),
+            Some(IdParseError::WrongLength(ID_LEN + 1))
+        );
+        assert!(matches!(
+            IdWrapper::from_str(&hex[..hex.len() - 1]),
+            Err(IdParseError::InvalidHex(_))
+        ));
+        assert!(matches!(
+            IdWrapper::from_str(&"zz".repeat(ID_LEN)),
+            Err(IdParseError::InvalidHex(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_root() {
+        let hex = some_id_hex();
+        let ids = vec![IdWrapper::from_str(&hex).unwrap().0];
+        assert_eq!(
+            http_root(ids.clone(), Path("0".to_string())).await.unwrap(),
+            hex
+        );
+        let (status, _) = http_root(ids.clone(), Path("x".to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = http_root(ids.clone(), Path("-1".to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = http_root(ids, Path("1".to_string())).await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
 }
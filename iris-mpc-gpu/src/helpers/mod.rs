@@ -7,6 +7,7 @@ use cudarc::driver::{
 };
 use std::sync::Arc;
 
+pub mod chunked_copy;
 pub mod comm;
 pub mod device_manager;
 pub mod id_wrapper;
@@ -148,6 +149,23 @@ pub fn dtoh_on_stream_sync<T: Default + Clone, U: DevicePtr<T>>(
     Ok(buf)
 }
 
+/// Like [`dtoh_on_stream_sync`], but copies into `output`, which has the length
+/// of `input`.
+pub fn dtoh_into_on_stream_sync<T, U: DevicePtr<T>>(
+    input: &U,
+    output: &mut [T],
+    device: &Arc<CudaDevice>,
+    stream: &CudaStream,
+) -> Result<(), DriverError> {
+    assert_eq!(input.len(), output.len());
+    device.bind_to_thread()?;
+    // SAFETY: `output` is borrowed until the stream is synchronized.
+    unsafe {
+        memcpy_dtoh_async(output, *input.device_ptr(), stream.stream)?;
+        stream::synchronize(stream.stream)
+    }
+}
+
 pub fn htod_on_stream_sync<T: DeviceRepr>(
     input: &[T],
     device: &Arc<CudaDevice>,
@@ -1,7 +1,7 @@
 use crate::{
     dot::{
         share_db::{DBChunkBuffers, ShareDB, SlicedProcessedDatabase},
-        IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
+        IRIS_CODE_LENGTH, MASK_CODE_LENGTH, ROTATIONS,
     },
     helpers::device_manager::DeviceManager,
 };
@@ -13,8 +13,26 @@ use cudarc::{
         CudaSlice, CudaStream, DevicePtr, DeviceSlice,
     },
 };
-use iris_mpc_common::galois_engine::CompactGaloisRingShares;
-use std::marker::{Send, Sync};
+use iris_mpc_common::{
+    galois_engine::CompactGaloisRingShares, helpers::memory_pressure::BatchCostModel,
+};
+use std::{
+    marker::{Send, Sync},
+    mem,
+};
+
+/// Estimated memory the queries of a batch take on every device. Every entry
+/// uploads the code and mask shares of both eyes in all rotations, for the
+/// comparison and for the insertion, each as two limbs, together with their
+/// sums.
+pub fn batch_cost_model() -> BatchCostModel {
+    let shares = 2 * 2 * (IRIS_CODE_LENGTH + MASK_CODE_LENGTH);
+    let sums = 2 * 2 * 2 * mem::size_of::<u32>();
+    BatchCostModel {
+        fixed_bytes:     0,
+        bytes_per_entry: 2 * ROTATIONS * (shares + sums),
+    }
+}
 
 pub struct StreamAwareCudaSlice<T> {
     pub cu_device_ptr: CUdeviceptr,
@@ -141,6 +159,103 @@ impl CompactQuery {
             )?,
         })
     }
+
+    /// Like [`Self::htod_transfer`], but only uploads the un-rotated masks and
+    /// lets the devices materialize their rotations. This cuts the transfer
+    /// of the masks by a factor of [`ROTATIONS`].
+    pub fn htod_transfer_rotating_masks(
+        &self,
+        device: &DeviceManager,
+        mask_engine: &ShareDB,
+        streams: &[CudaStream],
+        batch_size: usize,
+    ) -> eyre::Result<DeviceCompactQuery> {
+        let rotate_masks = |mask_query: &[Vec<u8>]| -> eyre::Result<CudaVec2DSlicerU8> {
+            let unrotated = device.htod_transfer_unrotated_query(
+                &unrotated_entries(mask_query, MASK_CODE_LENGTH),
+                streams,
+                batch_size,
+                MASK_CODE_LENGTH,
+            )?;
+            Ok(mask_engine.rotate_queries(&unrotated, batch_size, streams))
+        };
+        Ok(DeviceCompactQuery {
+            code_query:        device.htod_transfer_query(
+                &self.code_query,
+                streams,
+                batch_size,
+                IRIS_CODE_LENGTH,
+            )?,
+            mask_query:        rotate_masks(&self.mask_query)?,
+            code_query_insert: device.htod_transfer_query(
+                &self.code_query_insert,
+                streams,
+                batch_size,
+                IRIS_CODE_LENGTH,
+            )?,
+            mask_query_insert: rotate_masks(&self.mask_query_insert)?,
+        })
+    }
+}
+
+/// Picks the un-rotated entry out of the [`ROTATIONS`] preprocessed entries
+/// of each query.
+fn unrotated_entries(preprocessed_query: &[Vec<u8>], code_length: usize) -> Vec<Vec<u8>> {
+    preprocessed_query
+        .iter()
+        .map(|limb| {
+            limb.chunks(ROTATIONS * code_length)
+                .flat_map(|rotations| {
+                    rotations[ROTATIONS / 2 * code_length..(ROTATIONS / 2 + 1) * code_length]
+                        .iter()
+                        .copied()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The sums of the limbs of a [`CompactQuery`], computed on the host, see
+/// [`iris_mpc_common::galois_engine::degree4::GaloisRingIrisCodeShare::preprocess_for_query`].
+pub struct CompactQuerySums {
+    pub code_query:        Vec<Vec<u32>>,
+    pub mask_query:        Vec<Vec<u32>>,
+    pub code_query_insert: Vec<Vec<u32>>,
+    pub mask_query_insert: Vec<Vec<u32>>,
+}
+
+impl CompactQuerySums {
+    /// Uploads the sums in place of [`DeviceCompactQuery::query_sums`].
+    pub fn htod_transfer(
+        &self,
+        device: &DeviceManager,
+        streams: &[CudaStream],
+        batch_size: usize,
+    ) -> eyre::Result<DeviceCompactSums> {
+        let query_length = batch_size * ROTATIONS;
+        Ok(DeviceCompactSums {
+            code_query:        device.htod_transfer_query_sums(
+                &self.code_query,
+                streams,
+                query_length,
+            )?,
+            mask_query:        device.htod_transfer_query_sums(
+                &self.mask_query,
+                streams,
+                query_length,
+            )?,
+            code_query_insert: device.htod_transfer_query_sums(
+                &self.code_query_insert,
+                streams,
+                query_length,
+            )?,
+            mask_query_insert: device.htod_transfer_query_sums(
+                &self.mask_query_insert,
+                streams,
+                query_length,
+            )?,
+        })
+    }
 }
 
 pub struct DeviceCompactQuery {
@@ -1,16 +1,26 @@
-use super::{BatchQuery, Eye, ServerJob, ServerJobResult};
+use super::{
+    insertion::{
+        canonical_order, chain_digest, check_agreement, insertion_mapping, InsertionDigest,
+    },
+    sync_nccl::{sync_batch, sync_generation, sync_threshold},
+    AppliedDeletionBatch, BatchQuery, BatchQueryEntriesPreprocessed, Eye, ServerCommand, ServerJob,
+    ServerJobResult,
+};
 use crate::{
     dot::{
         distance_comparator::DistanceComparator,
         share_db::{preprocess_query, DBChunkBuffers, ShareDB, SlicedProcessedDatabase},
+        snapshot::{SnapshotDigest, SnapshotError, SnapshotHeader},
         IRIS_CODE_LENGTH, MASK_CODE_LENGTH, ROTATIONS,
     },
     helpers::{
         self,
-        comm::NcclComm,
-        device_manager::DeviceManager,
+        chunked_copy::{ChunkedTransfer, ChunkedTransferError},
+        comm::{NcclAbortHandle, NcclComm},
+        device_manager::{network_magic, DeviceManager},
         query_processor::{
-            CompactQuery, CudaVec2DSlicerRawPointer, DeviceCompactQuery, DeviceCompactSums,
+            CompactQuery, CompactQuerySums, CudaVec2DSlicerRawPointer, DeviceCompactQuery,
+            DeviceCompactSums,
         },
     },
     threshold_ring::protocol::{ChunkShare, Circuits},
@@ -26,14 +36,31 @@ use cudarc::{
 use eyre::eyre;
 use futures::{Future, FutureExt};
 use iris_mpc_common::{
+    config::MatchPolicy,
+    errors::ErrorCode,
     galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    helpers::{
+        latency_budget::BudgetPhase,
+        spans::Phase,
+        sync::{
+            agreed_deletion_batches, agreed_purges, batch_key, compose_batch, or_rule_key,
+            BatchCandidate, BatchDecision, BatchSyncState,
+        },
+        threshold::{self, MatchThreshold, ScheduledThreshold, ThresholdParams, ThresholdSchedule},
+    },
     iris_db::iris::IrisCode,
     IrisCodeDbSlice,
 };
 use itertools::Itertools;
 use rand::{rngs::StdRng, SeedableRng};
 use ring::hkdf::{Algorithm, Okm, Salt, HKDF_SHA256};
-use std::{collections::HashMap, mem, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, oneshot};
 
 macro_rules! record_stream_time {
@@ -50,7 +77,10 @@ macro_rules! record_stream_time {
 
 #[derive(Debug, Clone)]
 pub struct ServerActorHandle {
-    job_queue: mpsc::Sender<ServerJob>,
+    job_queue:     mpsc::Sender<ServerCommand>,
+    /// Of the current communicators of the actor, which swaps them on a
+    /// reinit.
+    abort_handles: Arc<Mutex<Vec<NcclAbortHandle>>>,
 }
 
 impl ServerActorHandle {
@@ -62,10 +92,53 @@ impl ServerActorHandle {
         let job = ServerJob {
             batch,
             return_channel: tx,
+            span: tracing::Span::current(),
         };
-        self.job_queue.send(job).await.unwrap();
+        self.job_queue
+            .send(ServerCommand::Batch(job))
+            .await
+            .unwrap();
         rx.map(|x| x.unwrap())
     }
+
+    /// Like [`Self::submit_batch_query`], but fails instead of panicking if the
+    /// actor gives up on the batch, e.g. after a failed collective.
+    pub async fn try_submit_batch_query(
+        &mut self,
+        batch: BatchQuery,
+    ) -> impl Future<Output = eyre::Result<ServerJobResult>> {
+        let (tx, rx) = oneshot::channel();
+        let job = ServerJob {
+            batch,
+            return_channel: tx,
+            span: tracing::Span::current(),
+        };
+        self.job_queue
+            .send(ServerCommand::Batch(job))
+            .await
+            .unwrap();
+        rx.map(|x| x.map_err(|_| eyre!("Server actor failed to process the batch")))
+    }
+
+    /// Aborts the communicators of the actor, so that a batch stuck in a
+    /// collective returns. They are rebuilt with [`Self::reinit_network`].
+    pub fn abort_network(&self) {
+        for abort_handle in self.abort_handles.lock().unwrap().iter() {
+            if let Err(e) = abort_handle.abort() {
+                tracing::error!("Failed to abort NCCL communicator: {:?}", e);
+            }
+        }
+    }
+
+    /// Rebuilds the communicators of the actor after a failed batch. Resolves
+    /// to the generation of the new communicators once all parties connected
+    /// and agreed on it.
+    pub async fn reinit_network(&mut self) -> impl Future<Output = eyre::Result<u64>> {
+        let (tx, rx) = oneshot::channel();
+        let command = ServerCommand::ReinitNetwork { return_channel: tx };
+        self.job_queue.send(command).await.unwrap();
+        rx.map(|x| x.unwrap_or_else(|_| Err(eyre!("Server actor stopped"))))
+    }
 }
 
 const DB_CHUNK_SIZE: usize = 1 << 15;
@@ -73,7 +146,7 @@ const KDF_SALT: &str = "111a1a93518f670e9bb0c2c68888e2beb9406d4c4ed571dc77b801e6
 const SUPERMATCH_THRESHOLD: usize = 4_000;
 
 pub struct ServerActor {
-    job_queue:              mpsc::Receiver<ServerJob>,
+    job_queue:              mpsc::Receiver<ServerCommand>,
     device_manager:         Arc<DeviceManager>,
     party_id:               usize,
     // engines
@@ -85,6 +158,7 @@ pub struct ServerActor {
     phase2_batch:           Circuits,
     distance_comparator:    DistanceComparator,
     comms:                  Vec<Arc<NcclComm>>,
+    abort_handles:          Arc<Mutex<Vec<NcclAbortHandle>>>,
     // DB slices
     left_code_db_slices:    SlicedProcessedDatabase,
     left_mask_db_slices:    SlicedProcessedDatabase,
@@ -110,9 +184,41 @@ pub struct ServerActor {
     dot_events:             Vec<Vec<CUevent>>,
     exchange_events:        Vec<Vec<CUevent>>,
     phase2_events:          Vec<Vec<CUevent>>,
+    /// Running digest of the serial ids assigned to new entries since startup,
+    /// compared across parties after every batch.
+    insertion_digest:       InsertionDigest,
+    /// The match threshold and its scheduled changes.
+    threshold_schedule:     ThresholdSchedule,
+    /// Number of batches processed since startup, the same at all parties.
+    batch_counter:          u64,
+    /// The generation of the communicators, see [`network_magic`].
+    network_generation:     u64,
+    /// Upload only the un-rotated query masks and rotate them on the devices.
+    device_mask_rotations:  bool,
+    /// Time spent opening the comparison results in the current batch.
+    open_time:              Duration,
+}
+
+pub(super) const NON_MATCH_ID: u32 = u32::MAX;
+
+/// The host state a batch changes before its last collective. It is taken
+/// before every batch and restored when the batch fails, so that the batch can
+/// run again after [`ServerActor::reinit_network`]. The database entries the
+/// failed batch already deleted, restored or reset are written again with the
+/// same shares, and its insertions beyond `current_db_sizes` are ignored.
+struct BatchCheckpoint {
+    threshold_schedule: ThresholdSchedule,
+    batch_counter:      u64,
+    current_db_sizes:   Vec<usize>,
+    insertion_digest:   InsertionDigest,
 }
 
-const NON_MATCH_ID: u32 = u32::MAX;
+/// The snapshot files of the left codes, left masks, right codes and right
+/// masks.
+fn snapshot_paths(dir: &Path) -> [PathBuf; 4] {
+    ["left_code", "left_mask", "right_code", "right_mask"]
+        .map(|name| dir.join(format!("{}.snapshot", name)))
+}
 
 impl ServerActor {
     #[allow(clippy::too_many_arguments)]
@@ -187,7 +293,11 @@ impl ServerActor {
             return_partial_results,
             disable_persistence,
         )?;
-        Ok((actor, ServerActorHandle { job_queue: tx }))
+        let handle = ServerActorHandle {
+            job_queue:     tx,
+            abort_handles: actor.abort_handles.clone(),
+        };
+        Ok((actor, handle))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -196,7 +306,7 @@ impl ServerActor {
         chacha_seeds: ([u32; 8], [u32; 8]),
         device_manager: Arc<DeviceManager>,
         comms: Vec<Arc<NcclComm>>,
-        job_queue: mpsc::Receiver<ServerJob>,
+        job_queue: mpsc::Receiver<ServerCommand>,
         max_db_size: usize,
         max_batch_size: usize,
         return_partial_results: bool,
@@ -295,6 +405,7 @@ impl ServerActor {
             next_chacha_seeds(chacha_seeds)?,
             device_manager.clone(),
             comms.clone(),
+            MatchThreshold::default(),
         );
 
         let phase2 = Circuits::new(
@@ -304,6 +415,7 @@ impl ServerActor {
             next_chacha_seeds(chacha_seeds)?,
             device_manager.clone(),
             comms.clone(),
+            MatchThreshold::default(),
         );
 
         let distance_comparator = DistanceComparator::init(n_queries, device_manager.clone());
@@ -342,6 +454,10 @@ impl ServerActor {
             dev.synchronize().unwrap();
         }
 
+        let abort_handles = Arc::new(Mutex::new(
+            comms.iter().map(|comm| comm.abort_handle()).collect(),
+        ));
+
         Ok(Self {
             party_id,
             job_queue,
@@ -354,6 +470,7 @@ impl ServerActor {
             batch_codes_engine,
             batch_masks_engine,
             comms,
+            abort_handles,
             left_code_db_slices,
             left_mask_db_slices,
             right_code_db_slices,
@@ -378,20 +495,109 @@ impl ServerActor {
             dot_events,
             exchange_events,
             phase2_events,
+            insertion_digest: [0; 32],
+            threshold_schedule: ThresholdSchedule::default(),
+            batch_counter: 0,
+            network_generation: 0,
+            device_mask_rotations: false,
+            open_time: Duration::ZERO,
         })
     }
 
     pub fn run(mut self) {
-        while let Some(job) = self.job_queue.blocking_recv() {
-            let ServerJob {
-                batch,
-                return_channel,
-            } = job;
-            let _ = self.process_batch_query(batch, return_channel);
+        while let Some(command) = self.job_queue.blocking_recv() {
+            match command {
+                ServerCommand::Batch(ServerJob {
+                    batch,
+                    return_channel,
+                    span,
+                }) => {
+                    // The return channel is dropped on an error, failing the
+                    // batch at the caller, which may run it again.
+                    let checkpoint = self.checkpoint();
+                    if let Err(e) =
+                        span.in_scope(|| self.process_batch_query(batch, return_channel))
+                    {
+                        tracing::error!("Failed to process batch: {:?}", e);
+                        self.rollback(checkpoint);
+                    }
+                }
+                ServerCommand::ReinitNetwork { return_channel } => {
+                    let _ = return_channel.send(self.reinit_network());
+                }
+            }
         }
         tracing::info!("Server Actor finished due to all job queues being closed");
     }
 
+    fn checkpoint(&self) -> BatchCheckpoint {
+        BatchCheckpoint {
+            threshold_schedule: self.threshold_schedule.clone(),
+            batch_counter:      self.batch_counter,
+            current_db_sizes:   self.current_db_sizes.clone(),
+            insertion_digest:   self.insertion_digest,
+        }
+    }
+
+    /// Undoes what a failed batch did up to its failure, see
+    /// [`BatchCheckpoint`].
+    fn rollback(&mut self, checkpoint: BatchCheckpoint) {
+        let params = checkpoint.threshold_schedule.active();
+        self.phase2.set_threshold(&params);
+        self.phase2_batch.set_threshold(&params);
+        self.threshold_schedule = checkpoint.threshold_schedule;
+        self.batch_counter = checkpoint.batch_counter;
+        self.current_db_sizes = checkpoint.current_db_sizes;
+        self.insertion_digest = checkpoint.insertion_digest;
+        self.reset_results();
+        tracing::info!("Rolled back to batch {}", self.batch_counter);
+    }
+
+    /// Tears down the communicators after a failed collective and rebuilds
+    /// them on the same devices, keeping the loaded database. The generation
+    /// of the new communicators follows from the number of processed batches,
+    /// which the parties agree on, and is compared across the parties once
+    /// they connected. Returns the generation.
+    fn reinit_network(&mut self) -> eyre::Result<u64> {
+        let generation = (self.batch_counter + 1).max(self.network_generation + 1);
+        tracing::info!("Reinitializing NCCL, generation {}", generation);
+        let ids = self
+            .device_manager
+            .get_ids_from_magic(network_magic(generation));
+        let comms = self
+            .device_manager
+            .reinit(self.party_id, &self.comms, &ids)?;
+        self.replace_comms(comms);
+
+        let all_generations = sync_generation(&self.comms[0], generation)?;
+        if all_generations.iter().any(|&g| g != generation) {
+            metrics::counter!("nccl.generation_mismatch").increment(1);
+            return Err(eyre!(
+                "Parties reinitialized NCCL with different generations: {:?}",
+                all_generations
+            ));
+        }
+        self.network_generation = generation;
+        tracing::info!("Reinitialized NCCL, generation {}", generation);
+        Ok(generation)
+    }
+
+    fn replace_comms(&mut self, comms: Vec<Arc<NcclComm>>) {
+        for engine in [
+            &mut self.codes_engine,
+            &mut self.masks_engine,
+            &mut self.batch_codes_engine,
+            &mut self.batch_masks_engine,
+        ] {
+            engine.replace_comms(comms.clone());
+        }
+        self.phase2.replace_comms(comms.clone());
+        self.phase2_batch.replace_comms(comms.clone());
+        *self.abort_handles.lock().unwrap() =
+            comms.iter().map(|comm| comm.abort_handle()).collect();
+        self.comms = comms;
+    }
+
     pub fn current_db_sizes(&self) -> Vec<usize> {
         self.current_db_sizes.clone()
     }
@@ -490,15 +696,164 @@ impl ServerActor {
         self.current_db_sizes[index % self.device_manager.device_count()] += 1;
     }
 
-    pub fn preprocess_db(&mut self) {
+    pub fn preprocess_db(&mut self) -> Result<(), ChunkedTransferError> {
         self.codes_engine
-            .preprocess_db(&mut self.left_code_db_slices, &self.current_db_sizes);
+            .preprocess_db(&mut self.left_code_db_slices, &self.current_db_sizes)?;
         self.masks_engine
-            .preprocess_db(&mut self.left_mask_db_slices, &self.current_db_sizes);
+            .preprocess_db(&mut self.left_mask_db_slices, &self.current_db_sizes)?;
         self.codes_engine
-            .preprocess_db(&mut self.right_code_db_slices, &self.current_db_sizes);
+            .preprocess_db(&mut self.right_code_db_slices, &self.current_db_sizes)?;
         self.masks_engine
-            .preprocess_db(&mut self.right_mask_db_slices, &self.current_db_sizes);
+            .preprocess_db(&mut self.right_mask_db_slices, &self.current_db_sizes)
+    }
+
+    /// Preprocesses the entries loaded since the DB had `from_sizes` entries
+    /// per device, see [`ShareDB::append_db`].
+    pub fn append_db(&mut self, from_sizes: &[usize]) -> Result<(), ChunkedTransferError> {
+        self.codes_engine.append_db(
+            &mut self.left_code_db_slices,
+            from_sizes,
+            &self.current_db_sizes,
+        )?;
+        self.masks_engine.append_db(
+            &mut self.left_mask_db_slices,
+            from_sizes,
+            &self.current_db_sizes,
+        )?;
+        self.codes_engine.append_db(
+            &mut self.right_code_db_slices,
+            from_sizes,
+            &self.current_db_sizes,
+        )?;
+        self.masks_engine.append_db(
+            &mut self.right_mask_db_slices,
+            from_sizes,
+            &self.current_db_sizes,
+        )
+    }
+
+    /// Writes the DB to one snapshot file per eye and share type in `dir`.
+    /// `digest` identifies the state of the store the DB was loaded from.
+    pub fn snapshot_to(&self, dir: &Path, digest: SnapshotDigest) -> Result<(), SnapshotError> {
+        std::fs::create_dir_all(dir)?;
+        let [left_code, left_mask, right_code, right_mask] = snapshot_paths(dir);
+        let streams = &self.streams[0];
+        self.codes_engine.snapshot_to(
+            left_code,
+            &self.left_code_db_slices,
+            &self.current_db_sizes,
+            digest,
+            streams,
+        )?;
+        self.masks_engine.snapshot_to(
+            left_mask,
+            &self.left_mask_db_slices,
+            &self.current_db_sizes,
+            digest,
+            streams,
+        )?;
+        self.codes_engine.snapshot_to(
+            right_code,
+            &self.right_code_db_slices,
+            &self.current_db_sizes,
+            digest,
+            streams,
+        )?;
+        self.masks_engine.snapshot_to(
+            right_mask,
+            &self.right_mask_db_slices,
+            &self.current_db_sizes,
+            digest,
+            streams,
+        )
+    }
+
+    /// Reads the header of the snapshot in `dir`, to look up the digest of the
+    /// store entries it covers.
+    pub fn read_snapshot_header(dir: &Path) -> Result<SnapshotHeader, SnapshotError> {
+        let [left_code, ..] = snapshot_paths(dir);
+        ShareDB::read_snapshot_header(left_code)
+    }
+
+    /// Restores the DB from the snapshot in `dir`, see
+    /// [`ShareDB::restore_from`]. On error, the DB has to be loaded from
+    /// scratch.
+    pub fn restore_from(
+        &mut self,
+        dir: &Path,
+        expected: &SnapshotDigest,
+    ) -> Result<(), SnapshotError> {
+        let [left_code, left_mask, right_code, right_mask] = snapshot_paths(dir);
+        let streams = &self.streams[0];
+        let all_sizes = [
+            self.codes_engine.restore_from(
+                left_code,
+                &mut self.left_code_db_slices,
+                self.max_db_size,
+                expected,
+                streams,
+            )?,
+            self.masks_engine.restore_from(
+                left_mask,
+                &mut self.left_mask_db_slices,
+                self.max_db_size,
+                expected,
+                streams,
+            )?,
+            self.codes_engine.restore_from(
+                right_code,
+                &mut self.right_code_db_slices,
+                self.max_db_size,
+                expected,
+                streams,
+            )?,
+            self.masks_engine.restore_from(
+                right_mask,
+                &mut self.right_mask_db_slices,
+                self.max_db_size,
+                expected,
+                streams,
+            )?,
+        ];
+        if all_sizes.iter().any(|sizes| sizes != &all_sizes[0]) {
+            return Err(SnapshotError::LayoutMismatch(format!(
+                "DB sizes differ between the snapshot files: {:?}",
+                all_sizes
+            )));
+        }
+        let [db_sizes, ..] = all_sizes;
+        self.current_db_sizes = db_sizes;
+        Ok(())
+    }
+
+    /// Sets how the DB is uploaded and the results are fetched from the
+    /// devices. Cancelling the transfer stops both at the next chunk.
+    pub fn set_chunked_transfer(&mut self, transfer: ChunkedTransfer) {
+        self.codes_engine.set_chunked_transfer(transfer.clone());
+        self.masks_engine.set_chunked_transfer(transfer.clone());
+        self.distance_comparator.chunked_transfer = transfer;
+    }
+
+    /// Enables the upload of only the un-rotated query masks, whose rotations
+    /// are then materialized on the devices. The results are the same either
+    /// way.
+    pub fn set_device_mask_rotations(&mut self, enabled: bool) {
+        self.device_mask_rotations = enabled;
+    }
+
+    /// Sets how the matches of the two eyes combine into a match of the
+    /// request. The matches of each eye are reported either way.
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) {
+        self.distance_comparator.set_match_policy(policy);
+    }
+
+    /// Sets the threshold to start with, before the first batch. Runtime
+    /// changes are scheduled on top of it.
+    pub fn set_match_threshold(&mut self, threshold: MatchThreshold) {
+        let params = ThresholdParams::initial(threshold);
+        self.threshold_schedule = ThresholdSchedule::new(params);
+        self.phase2.set_threshold(&params);
+        self.phase2_batch.set_threshold(&params);
     }
 
     pub fn register_host_memory(&self) {
@@ -546,26 +901,98 @@ impl ServerActor {
             "Query batch sizes mismatch"
         );
 
+        let threshold = self.update_threshold(&batch.threshold_updates)?;
+
         ///////////////////////////////////////////////////////////////////
         // PERFORM DELETIONS (IF ANY)
         ///////////////////////////////////////////////////////////////////
-        if !batch.deletion_requests_indices.is_empty() {
-            tracing::info!("Performing deletions");
-            // Prepare dummy deletion shares
-            let (dummy_queries, dummy_sums) = self.prepare_deletion_shares()?;
-
-            // Overwrite the in-memory db
-            for deletion_index in batch.deletion_requests_indices.clone() {
-                let device_index = deletion_index % self.device_manager.device_count() as u32;
-                let device_db_index = deletion_index / self.device_manager.device_count() as u32;
+        let (deleted_ids, unknown_deletion_ids) =
+            self.delete_entries(&batch.deletion_requests_indices)?;
+
+        ///////////////////////////////////////////////////////////////////
+        // PERFORM RESTORES (IF ANY)
+        ///////////////////////////////////////////////////////////////////
+        let mut restored_ids = vec![];
+        let mut unknown_restore_ids = vec![];
+        if !batch.restore_requests_indices.is_empty() {
+            tracing::info!("Performing restores");
+            for restore_index in batch.restore_requests_indices.clone() {
+                let device_index = restore_index % self.device_manager.device_count() as u32;
+                let device_db_index = restore_index / self.device_manager.device_count() as u32;
+                // Only entries that are still soft-deleted come with their shares.
+                let entry = batch
+                    .restored_entries
+                    .iter()
+                    .find(|entry| entry.index == restore_index);
+                let entry = match entry {
+                    Some(entry)
+                        if (device_db_index as usize)
+                            < self.current_db_sizes[device_index as usize] =>
+                    {
+                        entry
+                    }
+                    _ => {
+                        tracing::warn!(
+                            "Restore index {} is not soft-deleted on device {}",
+                            restore_index,
+                            device_index
+                        );
+                        metrics::counter!("identity_restore.unknown_serial_id").increment(1);
+                        unknown_restore_ids.push(restore_index);
+                        continue;
+                    }
+                };
+                let (left_queries, left_sums) =
+                    self.prepare_db_shares(&entry.left_code, &entry.left_mask)?;
+                let (right_queries, right_sums) =
+                    self.prepare_db_shares(&entry.right_code, &entry.right_mask)?;
+                restored_ids.push(restore_index);
+                self.device_manager
+                    .device(device_index as usize)
+                    .bind_to_thread()
+                    .unwrap();
+                write_db_at_index(
+                    &self.left_code_db_slices,
+                    &self.left_mask_db_slices,
+                    &self.right_code_db_slices,
+                    &self.right_mask_db_slices,
+                    &left_queries,
+                    &left_sums,
+                    &right_queries,
+                    &right_sums,
+                    0,
+                    device_db_index as usize,
+                    device_index as usize,
+                    &self.streams[0],
+                );
+            }
+        }
+
+        ///////////////////////////////////////////////////////////////////
+        // PERFORM RESET UPDATES (IF ANY)
+        ///////////////////////////////////////////////////////////////////
+        let mut reset_update_ids = vec![];
+        let mut unknown_reset_update_ids = vec![];
+        if !batch.reset_updates.is_empty() {
+            tracing::info!("Performing reset updates");
+            for entry in batch.reset_updates.iter() {
+                let device_index = entry.index % self.device_manager.device_count() as u32;
+                let device_db_index = entry.index / self.device_manager.device_count() as u32;
                 if device_db_index as usize >= self.current_db_sizes[device_index as usize] {
                     tracing::warn!(
-                        "Deletion index {} is out of bounds for device {}",
-                        deletion_index,
+                        "Reset update index {} is out of bounds for device {}",
+                        entry.index,
                         device_index
                     );
+                    metrics::counter!("reset_update.unknown_serial_id").increment(1);
+                    unknown_reset_update_ids.push(entry.index);
                     continue;
                 }
+                let (left_queries, left_sums) =
+                    self.prepare_db_shares(&entry.left_code, &entry.left_mask)?;
+                let (right_queries, right_sums) =
+                    self.prepare_db_shares(&entry.right_code, &entry.right_mask)?;
+                reset_update_ids.push(entry.index);
                 self.device_manager
                     .device(device_index as usize)
                     .bind_to_thread()
@@ -575,10 +1002,10 @@ impl ServerActor {
                     &self.left_mask_db_slices,
                     &self.right_code_db_slices,
                     &self.right_mask_db_slices,
-                    &dummy_queries,
-                    &dummy_sums,
-                    &dummy_queries,
-                    &dummy_sums,
+                    &left_queries,
+                    &left_sums,
+                    &right_queries,
+                    &right_sums,
                     0,
                     device_db_index as usize,
                     device_index as usize,
@@ -592,12 +1019,57 @@ impl ServerActor {
         ///////////////////////////////////////////////////////////////////
         let tmp_now = Instant::now();
         tracing::info!("Syncing batch entries");
-        let valid_entries = self.sync_batch_entries(&batch.valid_entries)?;
-        let valid_entry_idxs = valid_entries.iter().positions(|&x| x).collect::<Vec<_>>();
+        // Put the entries in an order that does not depend on the local arrival
+        // order. The entries all parties hold are then in the same order at
+        // every party.
+        batch.reorder(&canonical_order(&batch.request_ids, &batch.mirrored_checks));
+        let (decisions, purged_serial_ids, agreed_deletion_batches) =
+            self.sync_batch_entries(&batch)?;
+        // A mirrored check is requeued with the request it belongs to.
+        let request_ids_with = |decision: BatchDecision| {
+            decisions
+                .iter()
+                .positions(|&x| x == decision)
+                .filter(|&i| !batch.mirrored_checks.get(i).copied().unwrap_or(false))
+                .map(|i| batch.request_ids[i].clone())
+                .unique()
+                .collect::<Vec<_>>()
+        };
+        let requeued_request_ids = request_ids_with(BatchDecision::Shed);
+        let deferred_request_ids = request_ids_with(BatchDecision::Defer);
+        let valid_entry_idxs = decisions
+            .iter()
+            .positions(|&x| x == BatchDecision::Process)
+            .collect::<Vec<_>>();
         batch_size = valid_entry_idxs.len();
         batch.retain(&valid_entry_idxs);
         tracing::info!("Sync and filter done in {:?}", tmp_now.elapsed());
 
+        ///////////////////////////////////////////////////////////////////
+        // PERFORM DELETION BATCHES (IF ANY)
+        ///////////////////////////////////////////////////////////////////
+        let mut applied_deletion_batches = vec![];
+        let mut deferred_deletion_batches = vec![];
+        for deletion_batch in mem::take(&mut batch.deletion_batches) {
+            if !agreed_deletion_batches.contains(&batch_key(&deletion_batch.message_id)) {
+                deferred_deletion_batches.push(deletion_batch);
+                continue;
+            }
+            let indices = deletion_batch
+                .serial_ids
+                .iter()
+                .map(|serial_id| serial_id.wrapping_sub(1))
+                .collect::<Vec<_>>();
+            let (deleted_ids, unknown_ids) = self.delete_entries(&indices)?;
+            applied_deletion_batches.push(AppliedDeletionBatch {
+                batch: deletion_batch,
+                deleted_ids,
+                unknown_ids,
+            });
+        }
+        let gpu_start = Instant::now();
+        self.open_time = Duration::ZERO;
+
         ///////////////////////////////////////////////////////////////////
         // COMPARE LEFT EYE QUERIES
         ///////////////////////////////////////////////////////////////////
@@ -617,19 +1089,12 @@ impl ServerActor {
             events,
             "query_preprocess",
             {
-                // This needs to be max_batch_size, even though the query can be shorter to have
-                // enough padding for GEMM
-                let compact_device_queries_left = compact_query_left.htod_transfer(
-                    &self.device_manager,
-                    &self.streams[0],
-                    self.max_batch_size,
-                )?;
+                let compact_device_queries_left = self.htod_transfer_query(&compact_query_left)?;
 
-                let compact_device_sums_left = compact_device_queries_left.query_sums(
-                    &self.codes_engine,
-                    &self.masks_engine,
-                    &self.streams[0],
-                    &self.cublas_handles[0],
+                let compact_device_sums_left = self.query_sums(
+                    &compact_device_queries_left,
+                    &batch.query_left_preprocessed,
+                    &batch.db_left_preprocessed,
                 )?;
 
                 (compact_device_queries_left, compact_device_sums_left)
@@ -663,19 +1128,13 @@ impl ServerActor {
             events,
             "query_preprocess",
             {
-                // This needs to be MAX_BATCH_SIZE, even though the query can be shorter to have
-                // enough padding for GEMM
-                let compact_device_queries_right = compact_query_right.htod_transfer(
-                    &self.device_manager,
-                    &self.streams[0],
-                    self.max_batch_size,
-                )?;
+                let compact_device_queries_right =
+                    self.htod_transfer_query(&compact_query_right)?;
 
-                let compact_device_sums_right = compact_device_queries_right.query_sums(
-                    &self.codes_engine,
-                    &self.masks_engine,
-                    &self.streams[0],
-                    &self.cublas_handles[0],
+                let compact_device_sums_right = self.query_sums(
+                    &compact_device_queries_right,
+                    &batch.query_right_preprocessed,
+                    &batch.db_right_preprocessed,
                 )?;
 
                 (compact_device_queries_right, compact_device_sums_right)
@@ -728,7 +1187,17 @@ impl ServerActor {
         // Fetch the final results (blocking)
         let mut host_results = self
             .distance_comparator
-            .fetch_final_results(&self.final_results);
+            .fetch_final_results(&self.final_results, &self.streams[0]);
+
+        if let Some(deadline) = batch.deadline.as_mut() {
+            deadline.record(
+                BudgetPhase::Gpu,
+                gpu_start.elapsed().saturating_sub(self.open_time),
+            );
+            deadline.record(BudgetPhase::Open, self.open_time);
+            deadline.report_soft_breach(BudgetPhase::Gpu);
+            deadline.report_soft_breach(BudgetPhase::Open);
+        }
 
         // Truncate the results to the batch size
         host_results.iter_mut().for_each(|x| x.truncate(batch_size));
@@ -736,7 +1205,7 @@ impl ServerActor {
         // Fetch and truncate the match counters
         let match_counters_devices = self
             .distance_comparator
-            .fetch_match_counters(&self.distance_comparator.match_counters)
+            .fetch_match_counters(&self.distance_comparator.match_counters, &self.streams[0])
             .into_iter()
             .map(|x| x[..batch_size].to_vec())
             .collect::<Vec<_>>();
@@ -756,6 +1225,7 @@ impl ServerActor {
         let match_ids = self.distance_comparator.fetch_all_match_ids(
             &match_counters_devices,
             &self.distance_comparator.all_matches,
+            &self.streams[0],
         );
 
         // Check if there are more matches than we fetch
@@ -771,44 +1241,36 @@ impl ServerActor {
             }
         }
 
-        // Fetch the partial matches
-        let (
-            partial_match_ids_left,
-            partial_match_counters_left,
-            partial_match_ids_right,
-            partial_match_counters_right,
-        ) = if self.return_partial_results {
-            // Transfer the partial results to the host
-            let partial_match_counters_left = self
-                .distance_comparator
-                .fetch_match_counters(&self.distance_comparator.match_counters_left)
-                .into_iter()
-                .map(|x| x[..batch_size].to_vec())
-                .collect::<Vec<_>>();
-            let partial_match_counters_right = self
-                .distance_comparator
-                .fetch_match_counters(&self.distance_comparator.match_counters_right)
-                .into_iter()
-                .map(|x| x[..batch_size].to_vec())
-                .collect::<Vec<_>>();
-
-            let partial_results_left = self.distance_comparator.fetch_all_match_ids(
-                &partial_match_counters_left,
-                &self.distance_comparator.partial_results_left,
-            );
-            let partial_results_right = self.distance_comparator.fetch_all_match_ids(
-                &partial_match_counters_right,
-                &self.distance_comparator.partial_results_right,
-            );
-            (
-                partial_results_left,
-                partial_match_counters_left,
-                partial_results_right,
-                partial_match_counters_right,
+        // Fetch the matches of each eye on its own
+        let partial_match_counters_left = self
+            .distance_comparator
+            .fetch_match_counters(
+                &self.distance_comparator.match_counters_left,
+                &self.streams[0],
             )
-        } else {
-            (vec![], vec![], vec![], vec![])
-        };
+            .into_iter()
+            .map(|x| x[..batch_size].to_vec())
+            .collect::<Vec<_>>();
+        let partial_match_counters_right = self
+            .distance_comparator
+            .fetch_match_counters(
+                &self.distance_comparator.match_counters_right,
+                &self.streams[0],
+            )
+            .into_iter()
+            .map(|x| x[..batch_size].to_vec())
+            .collect::<Vec<_>>();
+
+        let partial_match_ids_left = self.distance_comparator.fetch_all_match_ids(
+            &partial_match_counters_left,
+            &self.distance_comparator.partial_results_left,
+            &self.streams[0],
+        );
+        let partial_match_ids_right = self.distance_comparator.fetch_all_match_ids(
+            &partial_match_counters_right,
+            &self.distance_comparator.partial_results_right,
+            &self.streams[0],
+        );
 
         let partial_match_counters_left = partial_match_counters_left.iter().fold(
             vec![0usize; batch_size],
@@ -835,6 +1297,38 @@ impl ServerActor {
         let mut merged_results =
             get_merged_results(&host_results, self.device_manager.device_count());
 
+        // A query that only matches its OR-rule serial ids counts as a
+        // non-match. A match within the batch always counts, and so do the
+        // matches beyond the fetched ones, which cannot be checked.
+        for idx in 0..batch_size {
+            let Some(or_rule) = batch.or_rule(idx) else {
+                continue;
+            };
+            if merged_results[idx] != NON_MATCH_ID
+                && !match_ids[idx].is_empty()
+                && match_counters[idx] == match_ids[idx].len()
+                && match_ids[idx].iter().all(|id| or_rule.contains(id))
+            {
+                tracing::info!(
+                    "Query {} only matches its OR-rule serial ids {:?}",
+                    idx,
+                    match_ids[idx]
+                );
+                metrics::counter!("batch.or_rule_override").increment(1);
+                merged_results[idx] = NON_MATCH_ID;
+            }
+        }
+
+        // Mirrored checks, reauth entries and reset checks are never inserted,
+        // and a match of a mirrored check also keeps the request it was derived
+        // from out of the database.
+        let is_mirrored_check =
+            |idx: usize| batch.mirrored_checks.get(idx).copied().unwrap_or(false);
+        let mirrored_matches = (0..batch_size)
+            .filter(|&idx| is_mirrored_check(idx) && merged_results[idx] != NON_MATCH_ID)
+            .map(|idx| batch.request_ids[idx].clone())
+            .collect::<HashSet<_>>();
+
         // List the indices of the queries that did not match.
         let insertion_list = merged_results
             .iter()
@@ -842,8 +1336,13 @@ impl ServerActor {
             .filter(|&(idx, &num)| {
                 num == NON_MATCH_ID
                     // Filter-out supermatchers on both sides (TODO: remove this in the future)
-                    && partial_match_counters_left[idx] <= SUPERMATCH_THRESHOLD
-                    && partial_match_counters_right[idx] <= SUPERMATCH_THRESHOLD
+                    && (!self.return_partial_results
+                        || (partial_match_counters_left[idx] <= SUPERMATCH_THRESHOLD
+                            && partial_match_counters_right[idx] <= SUPERMATCH_THRESHOLD))
+                    && !is_mirrored_check(idx)
+                    && !batch.is_reauth(idx)
+                    && !batch.is_reset_check(idx)
+                    && !mirrored_matches.contains(&batch.request_ids[idx])
             })
             .map(|(idx, _num)| idx)
             .collect::<Vec<_>>();
@@ -859,6 +1358,21 @@ impl ServerActor {
             batch_size,
         );
 
+        // Make sure all parties assigned the same serial ids before anything is
+        // written or published.
+        let db_digest_before = self.insertion_digest;
+        let insertion_digest = chain_digest(
+            &self.insertion_digest,
+            &insertion_mapping(&batch.request_ids, &merged_results, &matches),
+        );
+        let all_digests = self.sync_insertion_digests(&insertion_digest)?;
+        if let Err(divergence) = check_agreement(&all_digests) {
+            tracing::error!(party_id = self.party_id, "{}", divergence);
+            metrics::counter!("batch.insertion_divergence").increment(1);
+            return Err(divergence.into());
+        }
+        self.insertion_digest = insertion_digest;
+
         // Check for batch matches
         let matched_batch_request_ids = match_ids
             .iter()
@@ -946,8 +1460,28 @@ impl ServerActor {
                 partial_match_ids_right,
                 store_left: query_store_left,
                 store_right: query_store_right,
-                deleted_ids: batch.deletion_requests_indices,
+                deleted_ids,
+                unknown_deletion_ids,
+                restored_ids,
+                unknown_restore_ids,
+                reset_update_ids,
+                unknown_reset_update_ids,
+                purged_serial_ids,
+                applied_deletion_batches,
+                deferred_deletion_batches,
                 matched_batch_request_ids,
+                mirrored_checks: batch.mirrored_checks,
+                rotation_windows: batch.rotation_windows,
+                reauth_targets: batch.reauth_targets,
+                or_rule_indices: batch.or_rule_indices,
+                reset_checks: batch.reset_checks,
+                db_digest_before,
+                db_digest_after: self.insertion_digest,
+                threshold_version: threshold.version,
+                requeued_request_ids,
+                deferred_request_ids,
+                deadline: batch.deadline,
+                span: tracing::Span::current(),
             })
             .unwrap();
 
@@ -956,35 +1490,7 @@ impl ServerActor {
         self.device_manager.await_streams(&self.streams[1]);
 
         // Reset the results buffers for reuse
-        for dst in &[
-            &self.db_match_list_left,
-            &self.db_match_list_right,
-            &self.batch_match_list_left,
-            &self.batch_match_list_right,
-        ] {
-            reset_slice(self.device_manager.devices(), dst, 0, &self.streams[0]);
-        }
-
-        reset_slice(
-            self.device_manager.devices(),
-            &self.distance_comparator.match_counters,
-            0,
-            &self.streams[0],
-        );
-
-        reset_slice(
-            self.device_manager.devices(),
-            &self.distance_comparator.match_counters_left,
-            0,
-            &self.streams[0],
-        );
-
-        reset_slice(
-            self.device_manager.devices(),
-            &self.distance_comparator.match_counters_right,
-            0,
-            &self.streams[0],
-        );
+        self.reset_results();
 
         // ---- END RESULT PROCESSING ----
         log_timers(events);
@@ -1031,6 +1537,159 @@ impl ServerActor {
         Ok(())
     }
 
+    /// Runs dummy batches of the maximum shape through the dot products, the
+    /// threshold circuits and the opening, so that the lazily initialized
+    /// cuBLAS handles, kernels and NCCL channels are ready before the first
+    /// real batch. All parties have to call this in lockstep. The DB and the
+    /// insertion digest are left untouched, the results are discarded.
+    pub fn warmup(&mut self, rounds: usize) -> eyre::Result<()> {
+        if rounds == 0 {
+            return Ok(());
+        }
+        // Make sure the collectives of the warmup are paired up between the
+        // parties.
+        self.sync_barrier()?;
+
+        let (dummy_code_share, dummy_mask_share) = get_dummy_shares_for_deletion(self.party_id);
+        let preprocess = |shares: Vec<Vec<u16>>| {
+            preprocess_query(
+                &shares
+                    .into_iter()
+                    .cycle()
+                    .take(self.max_batch_size * ROTATIONS)
+                    .flatten()
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let code = preprocess(
+            dummy_code_share
+                .all_rotations()
+                .into_iter()
+                .map(|e| e.coefs.to_vec())
+                .collect(),
+        );
+        let mask = preprocess(
+            dummy_mask_share
+                .all_rotations()
+                .into_iter()
+                .map(|e| e.coefs.to_vec())
+                .collect(),
+        );
+        let compact_query = CompactQuery {
+            code_query:        code.clone(),
+            mask_query:        mask.clone(),
+            code_query_insert: code,
+            mask_query_insert: mask,
+        };
+
+        for round in 0..rounds {
+            let now = Instant::now();
+            let mut events: HashMap<&str, Vec<Vec<CUevent>>> = HashMap::new();
+            let compact_device_queries = self.htod_transfer_query(&compact_query)?;
+            let compact_device_sums = compact_device_queries.query_sums(
+                &self.codes_engine,
+                &self.masks_engine,
+                &self.streams[0],
+                &self.cublas_handles[0],
+            )?;
+            for eye in [Eye::Left, Eye::Right] {
+                self.compare_query_against_db_and_self(
+                    &compact_device_queries,
+                    &compact_device_sums,
+                    &mut events,
+                    eye,
+                );
+            }
+            self.distance_comparator.join_db_matches(
+                &self.db_match_list_left,
+                &self.db_match_list_right,
+                &self.final_results,
+                &self.current_db_sizes,
+                &self.streams[0],
+            );
+            self.distance_comparator.join_batch_matches(
+                &self.batch_match_list_left,
+                &self.batch_match_list_right,
+                &self.final_results,
+                &self.streams[0],
+            );
+            self.device_manager.await_streams(&self.streams[0]);
+            self.distance_comparator
+                .fetch_final_results(&self.final_results, &self.streams[0]);
+
+            self.device_manager.await_streams(&self.streams[0]);
+            self.device_manager.await_streams(&self.streams[1]);
+            self.reset_results();
+            for event in events.into_values().flatten() {
+                self.device_manager.destroy_events(event);
+            }
+            tracing::info!("Warmup round {} took {:?}", round, now.elapsed());
+        }
+        self.device_manager.await_streams(&self.streams[0]);
+        Ok(())
+    }
+
+    /// Clears the match lists and counters for the next batch.
+    fn reset_results(&self) {
+        for dst in &[
+            &self.db_match_list_left,
+            &self.db_match_list_right,
+            &self.batch_match_list_left,
+            &self.batch_match_list_right,
+        ] {
+            reset_slice(self.device_manager.devices(), dst, 0, &self.streams[0]);
+        }
+
+        for dst in &[
+            &self.distance_comparator.match_counters,
+            &self.distance_comparator.match_counters_left,
+            &self.distance_comparator.match_counters_right,
+        ] {
+            reset_slice(self.device_manager.devices(), dst, 0, &self.streams[0]);
+        }
+    }
+
+    fn htod_transfer_query(&self, query: &CompactQuery) -> eyre::Result<DeviceCompactQuery> {
+        // This needs to be max_batch_size, even though the query can be shorter to have
+        // enough padding for GEMM
+        if self.device_mask_rotations {
+            query.htod_transfer_rotating_masks(
+                &self.device_manager,
+                &self.masks_engine,
+                &self.streams[0],
+                self.max_batch_size,
+            )
+        } else {
+            query.htod_transfer(&self.device_manager, &self.streams[0], self.max_batch_size)
+        }
+    }
+
+    /// Uploads the sums of the limbs if the batch carries them, and computes
+    /// them on the devices otherwise.
+    fn query_sums(
+        &self,
+        compact_device_queries: &DeviceCompactQuery,
+        query: &BatchQueryEntriesPreprocessed,
+        db: &BatchQueryEntriesPreprocessed,
+    ) -> eyre::Result<DeviceCompactSums> {
+        if query.has_sums() && db.has_sums() {
+            CompactQuerySums {
+                code_query:        query.code_sums.clone(),
+                mask_query:        query.mask_sums.clone(),
+                code_query_insert: db.code_sums.clone(),
+                mask_query_insert: db.mask_sums.clone(),
+            }
+            .htod_transfer(&self.device_manager, &self.streams[0], self.max_batch_size)
+        } else {
+            compact_device_queries.query_sums(
+                &self.codes_engine,
+                &self.masks_engine,
+                &self.streams[0],
+                &self.cublas_handles[0],
+            )
+        }
+    }
+
     fn compare_query_against_db_and_self(
         &mut self,
         compact_device_queries: &DeviceCompactQuery,
@@ -1055,6 +1714,7 @@ impl ServerActor {
         // ---- START BATCH DEDUP ----
         tracing::info!(party_id = self.party_id, "Starting batch deduplication");
 
+        let dot_span = Phase::GpuDot.span().entered();
         record_stream_time!(&self.device_manager, batch_streams, events, "batch_dot", {
             tracing::info!(party_id = self.party_id, "batch_dot start");
 
@@ -1093,12 +1753,14 @@ impl ServerActor {
                 tracing::info!(party_id = self.party_id, "batch_reshare end");
             }
         );
+        drop(dot_span);
 
         let db_sizes_batch =
             vec![self.max_batch_size * ROTATIONS; self.device_manager.device_count()];
         let code_dots_batch = self.batch_codes_engine.result_chunk_shares(&db_sizes_batch);
         let mask_dots_batch = self.batch_masks_engine.result_chunk_shares(&db_sizes_batch);
 
+        let threshold_span = Phase::GpuThreshold.span().entered();
         record_stream_time!(
             &self.device_manager,
             batch_streams,
@@ -1106,18 +1768,23 @@ impl ServerActor {
             "batch_threshold",
             {
                 tracing::info!(party_id = self.party_id, "batch_threshold start");
-                self.phase2_batch.compare_threshold_masked_many(
-                    &code_dots_batch,
-                    &mask_dots_batch,
-                    batch_streams,
-                );
+                self.phase2_batch
+                    .compare_threshold_masked_many(
+                        &code_dots_batch,
+                        &mask_dots_batch,
+                        batch_streams,
+                    )
+                    .unwrap();
                 tracing::info!(party_id = self.party_id, "batch_threshold end");
             }
         );
+        drop(threshold_span);
 
         tracing::info!(party_id = self.party_id, "phase2_batch start");
 
-        let res = self.phase2_batch.take_result_buffer();
+        let open_span = Phase::Open.span().entered();
+        let open_start = Instant::now();
+        let res = self.phase2_batch.results().unwrap();
         let chunk_size = self.phase2_batch.chunk_size();
         open(
             &mut self.phase2_batch,
@@ -1132,7 +1799,11 @@ impl ServerActor {
             &vec![false; self.device_manager.device_count()],
             batch_streams,
         );
-        self.phase2_batch.return_result_buffer(res);
+        self.phase2_batch
+            .return_results(res, batch_streams)
+            .unwrap();
+        self.open_time += open_start.elapsed();
+        drop(open_span);
 
         tracing::info!(party_id = self.party_id, "Finished batch deduplication");
         // ---- END BATCH DEDUP ----
@@ -1235,6 +1906,7 @@ impl ServerActor {
                 .await_event(request_streams, &self.dot_events[db_chunk_idx % 2]);
 
             // ---- START PHASE 1 ----
+            let dot_span = Phase::GpuDot.span().entered();
             record_stream_time!(&self.device_manager, batch_streams, events, "db_dot", {
                 compact_device_queries.dot_products_against_db(
                     &mut self.codes_engine,
@@ -1286,6 +1958,7 @@ impl ServerActor {
                 }
             );
 
+            drop(dot_span);
             // ---- END PHASE 1 ----
 
             self.device_manager
@@ -1305,19 +1978,19 @@ impl ServerActor {
                 self.phase2
                     .set_chunk_size(max_chunk_size * self.max_batch_size * ROTATIONS / 64);
 
+                let threshold_span = Phase::GpuThreshold.span().entered();
                 record_stream_time!(
                     &self.device_manager,
                     request_streams,
                     events,
                     "db_threshold",
                     {
-                        self.phase2.compare_threshold_masked_many(
-                            &code_dots,
-                            &mask_dots,
-                            request_streams,
-                        );
+                        self.phase2
+                            .compare_threshold_masked_many(&code_dots, &mask_dots, request_streams)
+                            .unwrap();
                     }
                 );
+                drop(threshold_span);
                 // we can now record the exchange event since the phase 2 is no longer using the
                 // code_dots/mask_dots which are just reinterpretations of the exchange result
                 // buffers
@@ -1326,7 +1999,9 @@ impl ServerActor {
                     &self.exchange_events[(db_chunk_idx + 1) % 2],
                 );
 
-                let res = self.phase2.take_result_buffer();
+                let open_span = Phase::Open.span().entered();
+                let open_start = Instant::now();
+                let res = self.phase2.results().unwrap();
                 record_stream_time!(&self.device_manager, request_streams, events, "db_open", {
                     open(
                         &mut self.phase2,
@@ -1341,8 +2016,10 @@ impl ServerActor {
                         &ignore_device_results,
                         request_streams,
                     );
-                    self.phase2.return_result_buffer(res);
+                    self.phase2.return_results(res, request_streams).unwrap();
                 });
+                self.open_time += open_start.elapsed();
+                drop(open_span);
             }
             self.device_manager
                 .record_event(request_streams, &self.phase2_events[(db_chunk_idx + 1) % 2]);
@@ -1371,70 +2048,237 @@ impl ServerActor {
         }
     }
 
-    fn sync_batch_entries(&mut self, valid_entries: &[bool]) -> eyre::Result<Vec<bool>> {
+    /// Agrees on the batch composition across parties, see
+    /// [`compose_batch`]. An entry is kept only if all parties hold it, it is
+    /// valid at all of them and they assigned it to the same priority lane.
+    /// Missing lanes default to
+    /// [`RequestLane::Bulk`](iris_mpc_common::helpers::priority_lanes::RequestLane::Bulk).
+    ///
+    /// An entry that would be kept but is shed by any party is dropped too,
+    /// and all parties requeue it, like the entries beyond the smallest batch
+    /// size limit of the parties. An entry some party does not hold yet is
+    /// deferred to a later batch.
+    ///
+    /// Also returns the soft-deletes that are due at all parties, see
+    /// [`agreed_purges`], and the keys of the deletion batches all parties
+    /// hold, see [`agreed_deletion_batches`].
+    fn sync_batch_entries(
+        &mut self,
+        batch: &BatchQuery,
+    ) -> eyre::Result<(Vec<BatchDecision>, Vec<u32>, Vec<u64>)> {
         tracing::info!(
             party_id = self.party_id,
             "valid_entries {:?} ({})",
-            valid_entries,
-            valid_entries.len()
+            batch.valid_entries,
+            batch.valid_entries.len()
         );
-        tracing::info!(party_id = self.party_id, "sync_batch_entries start");
-        let mut buffer = self
-            .device_manager
-            .device(0)
-            .alloc_zeros(valid_entries.len() * self.comms[0].world_size())
-            .unwrap();
+        // The batch is in canonical order already.
+        let own = BatchSyncState {
+            candidates:       (0..batch.request_ids.len())
+                .map(|i| BatchCandidate {
+                    key:            batch_key(&batch.request_ids[i]),
+                    mirrored_check: batch.mirrored_checks.get(i).copied().unwrap_or(false),
+                    lane:           batch.request_lanes.get(i).copied().unwrap_or_default() as u8,
+                    error:          (!batch.valid_entries[i])
+                        .then_some(ErrorCode::FailedToProcessIrisShares.as_u16()),
+                    shed:           batch.shed_entries.get(i).copied().unwrap_or(false),
+                    or_rule:        batch.or_rule(i).map(or_rule_key),
+                })
+                .collect(),
+            batch_size_limit: batch.batch_size_limit,
+            purge_due:        batch.purge_due.clone(),
+            deletion_batches: batch
+                .deletion_batches
+                .iter()
+                .map(|deletion_batch| batch_key(&deletion_batch.message_id))
+                .collect(),
+        };
 
-        tracing::info!(party_id = self.party_id, "htod_copy start");
+        tracing::info!(party_id = self.party_id, "sync_batch_entries start");
+        let all_states = sync_batch(&self.comms[0], &own, self.max_batch_size)?;
+        tracing::info!(party_id = self.party_id, "sync_batch_entries end");
 
-        let buffer_self = self
-            .device_manager
-            .device(0)
-            .htod_copy(valid_entries.iter().map(|&x| x as u8).collect::<Vec<_>>())?;
+        let decisions = compose_batch(&own, &all_states);
+        for (i, decision) in decisions.iter().enumerate() {
+            match decision {
+                BatchDecision::Defer => {
+                    tracing::warn!(
+                        party_id = self.party_id,
+                        "Deferring batch entry {}, not all parties hold it",
+                        i,
+                    );
+                    metrics::counter!("batch.entry_deferred").increment(1);
+                }
+                BatchDecision::Fail(_) if batch.valid_entries[i] => {
+                    tracing::warn!(
+                        party_id = self.party_id,
+                        "Dropping batch entry {}, it is not valid at all parties",
+                        i,
+                    );
+                    metrics::counter!("batch.entry_dropped").increment(1);
+                }
+                _ => {}
+            }
+        }
+        Ok((
+            decisions,
+            agreed_purges(&all_states),
+            agreed_deletion_batches(&all_states),
+        ))
+    }
 
-        self.device_manager.device(0).synchronize()?;
+    /// Schedules the received threshold changes and switches to the parameters
+    /// due for the current batch, once all parties agree on them.
+    fn update_threshold(
+        &mut self,
+        updates: &[ScheduledThreshold],
+    ) -> eyre::Result<ThresholdParams> {
+        let batch = self.batch_counter;
+        for update in updates {
+            if let Err(e) = self.threshold_schedule.schedule(*update, batch) {
+                tracing::warn!(party_id = self.party_id, "Ignoring threshold update: {}", e);
+                metrics::counter!("threshold.update_rejected").increment(1);
+            }
+        }
 
-        tracing::info!(party_id = self.party_id, "all_gather start");
+        let all_states =
+            sync_threshold(&self.comms[0], &self.threshold_schedule.sync_state(batch))?;
+        let params = match threshold::check_agreement(&all_states) {
+            Ok(params) => params,
+            Err(divergence) => {
+                tracing::error!(party_id = self.party_id, "{}", divergence);
+                metrics::counter!("threshold.divergence").increment(1);
+                return Err(divergence.into());
+            }
+        };
+        if params != self.threshold_schedule.active() {
+            tracing::info!(
+                "Switching to threshold version {} (ratio {}) at batch {}",
+                params.version,
+                params.match_threshold_ratio,
+                batch
+            );
+        }
+        self.threshold_schedule.activate(batch);
+        self.phase2.set_threshold(&params);
+        self.phase2_batch.set_threshold(&params);
+
+        metrics::gauge!("batch.number").set(batch as f64);
+        metrics::gauge!("threshold.version").set(params.version as f64);
+        self.batch_counter += 1;
+        Ok(params)
+    }
 
+    /// Returns once all parties got here.
+    fn sync_barrier(&mut self) -> eyre::Result<()> {
+        let device = self.device_manager.device(0);
+        let mut buffer = device
+            .alloc_zeros::<u8>(self.comms[0].world_size())
+            .unwrap();
+        let buffer_self = device.htod_copy(vec![1u8])?;
         self.comms[0]
             .all_gather(&buffer_self, &mut buffer)
             .map_err(|e| eyre!(format!("{:?}", e)))?;
+        device.synchronize()?;
+        Ok(())
+    }
 
-        self.device_manager.device(0).synchronize()?;
-
-        tracing::info!(party_id = self.party_id, "dtoh_sync_copy start");
+    /// Exchanges the insertion digests of all parties.
+    fn sync_insertion_digests(
+        &mut self,
+        digest: &InsertionDigest,
+    ) -> eyre::Result<Vec<InsertionDigest>> {
+        let device = self.device_manager.device(0);
+        let mut buffer = device
+            .alloc_zeros(digest.len() * self.comms[0].world_size())
+            .unwrap();
+        let buffer_self = device.htod_copy(digest.to_vec())?;
+        device.synchronize()?;
 
-        let results = self.device_manager.device(0).dtoh_sync_copy(&buffer)?;
-        let results: Vec<_> = results
-            .chunks_exact(results.len() / self.comms[0].world_size())
-            .collect();
+        self.comms[0]
+            .all_gather(&buffer_self, &mut buffer)
+            .map_err(|e| eyre!(format!("{:?}", e)))?;
+        device.synchronize()?;
 
-        tracing::info!(party_id = self.party_id, "sync_batch_entries end");
+        let results = device.dtoh_sync_copy(&buffer)?;
+        Ok(results
+            .chunks_exact(digest.len())
+            .map(|x| x.try_into().unwrap())
+            .collect())
+    }
 
-        let mut valid_merged = vec![];
-        for i in 0..results[0].len() {
-            valid_merged.push(
-                [results[0][i], results[1][i], results[2][i]]
-                    .iter()
-                    .all(|&x| x == 1),
+    /// Overwrites the entries at the 0-indexed `indices` with the dummy shares
+    /// of a deletion. Returns the deleted indices and the ones beyond the
+    /// database, which are ignored.
+    fn delete_entries(&mut self, indices: &[u32]) -> eyre::Result<(Vec<u32>, Vec<u32>)> {
+        let mut deleted_ids = vec![];
+        let mut unknown_ids = vec![];
+        if indices.is_empty() {
+            return Ok((deleted_ids, unknown_ids));
+        }
+        tracing::info!("Performing deletions");
+        // Prepare dummy deletion shares
+        let (dummy_queries, dummy_sums) = self.prepare_deletion_shares()?;
+
+        // Overwrite the in-memory db
+        for &deletion_index in indices {
+            let device_index = deletion_index % self.device_manager.device_count() as u32;
+            let device_db_index = deletion_index / self.device_manager.device_count() as u32;
+            if device_db_index as usize >= self.current_db_sizes[device_index as usize] {
+                tracing::warn!(
+                    "Deletion index {} is out of bounds for device {}",
+                    deletion_index,
+                    device_index
+                );
+                metrics::counter!("identity_deletion.unknown_serial_id").increment(1);
+                unknown_ids.push(deletion_index);
+                continue;
+            }
+            deleted_ids.push(deletion_index);
+            self.device_manager
+                .device(device_index as usize)
+                .bind_to_thread()
+                .unwrap();
+            write_db_at_index(
+                &self.left_code_db_slices,
+                &self.left_mask_db_slices,
+                &self.right_code_db_slices,
+                &self.right_mask_db_slices,
+                &dummy_queries,
+                &dummy_sums,
+                &dummy_queries,
+                &dummy_sums,
+                0,
+                device_db_index as usize,
+                device_index as usize,
+                &self.streams[0],
             );
         }
-
-        Ok(valid_merged)
+        Ok((deleted_ids, unknown_ids))
     }
 
     fn prepare_deletion_shares(&self) -> eyre::Result<(DeviceCompactQuery, DeviceCompactSums)> {
         let (dummy_code_share, dummy_mask_share) = get_dummy_shares_for_deletion(self.party_id);
+        self.prepare_db_shares(&dummy_code_share, &dummy_mask_share)
+    }
+
+    /// Transfers the shares of an eye to the devices, in the form they are
+    /// written into the in-memory db with.
+    fn prepare_db_shares(
+        &self,
+        code_share: &GaloisRingIrisCodeShare,
+        mask_share: &GaloisRingTrimmedMaskCodeShare,
+    ) -> eyre::Result<(DeviceCompactQuery, DeviceCompactSums)> {
         let compact_query = {
             let code = preprocess_query(
-                &dummy_code_share
+                &code_share
                     .all_rotations()
                     .into_iter()
                     .flat_map(|e| e.coefs)
                     .collect::<Vec<_>>(),
             );
             let mask = preprocess_query(
-                &dummy_mask_share
+                &mask_share
                     .all_rotations()
                     .into_iter()
                     .flat_map(|e| e.coefs)
@@ -1448,11 +2292,7 @@ impl ServerActor {
             }
         };
 
-        let compact_device_queries = compact_query.htod_transfer(
-            &self.device_manager,
-            &self.streams[0],
-            self.max_batch_size,
-        )?;
+        let compact_device_queries = self.htod_transfer_query(&compact_query)?;
 
         let compact_device_sums = compact_device_queries.query_sums(
             &self.codes_engine,
@@ -1577,7 +2417,7 @@ fn get_merged_results(host_results: &[Vec<u32>], n_devices: usize) -> Vec<u32> {
     results
 }
 
-fn distribute_insertions(results: &[usize], db_sizes: &[usize]) -> Vec<Vec<usize>> {
+pub(super) fn distribute_insertions(results: &[usize], db_sizes: &[usize]) -> Vec<Vec<usize>> {
     let mut ret = vec![vec![]; db_sizes.len()];
     let start = db_sizes
         .iter()
@@ -1612,7 +2452,7 @@ fn reset_slice<T>(
     }
 }
 
-fn calculate_insertion_indices(
+pub(super) fn calculate_insertion_indices(
     merged_results: &mut [u32],
     insertion_list: &[Vec<usize>],
     db_sizes: &[usize],
@@ -0,0 +1,185 @@
+//! Agreement of the parties on where the new entries of a batch are inserted.
+//!
+//! The entries of a batch are put in a canonical order derived from their
+//! request ids before the batch is synced, so that the arrival order at each
+//! party does not matter. After the insertion indices are assigned, each
+//! party folds the (request id -> serial id) mapping into a running digest,
+//! and the digests are compared before any result is published.
+
+use iris_mpc_common::helpers::sync::batch_key;
+use ring::digest::{Context, SHA256};
+use std::fmt;
+
+pub(crate) type InsertionDigest = [u8; 32];
+
+/// Returns the canonical order of the batch entries: by [`batch_key`], with a
+/// mirrored check following the request it was derived from. Ties keep the
+/// local order.
+pub(crate) fn canonical_order(request_ids: &[String], mirrored_checks: &[bool]) -> Vec<usize> {
+    let mut order = (0..request_ids.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| {
+        (
+            batch_key(&request_ids[i]),
+            &request_ids[i],
+            mirrored_checks.get(i).copied().unwrap_or(false),
+        )
+    });
+    order
+}
+
+/// The serial ids assigned to the inserted entries, ordered by serial id.
+pub(crate) fn insertion_mapping(
+    request_ids: &[String],
+    merged_results: &[u32],
+    matches: &[bool],
+) -> Vec<(String, u32)> {
+    let mut mapping = matches
+        .iter()
+        .enumerate()
+        .filter(|&(_, &matched)| !matched)
+        .map(|(i, _)| (request_ids[i].clone(), merged_results[i]))
+        .collect::<Vec<_>>();
+    mapping.sort_by_key(|(_, serial_id)| *serial_id);
+    mapping
+}
+
+/// Folds the mapping of a batch into the digest of all insertions so far.
+pub(crate) fn chain_digest(prev: &InsertionDigest, mapping: &[(String, u32)]) -> InsertionDigest {
+    let mut ctx = Context::new(&SHA256);
+    ctx.update(prev);
+    for (request_id, serial_id) in mapping {
+        ctx.update(&(request_id.len() as u64).to_le_bytes());
+        ctx.update(request_id.as_bytes());
+        ctx.update(&serial_id.to_le_bytes());
+    }
+    ctx.finish().as_ref().try_into().unwrap()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertionDivergence {
+    pub digests: Vec<InsertionDigest>,
+}
+
+impl fmt::Display for InsertionDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "parties diverged on the inserted serial ids: [")?;
+        for (i, digest) in self.digests.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", hex::encode(digest))?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl std::error::Error for InsertionDivergence {}
+
+/// Checks that the insertion digests of all parties agree.
+pub(crate) fn check_agreement(digests: &[InsertionDigest]) -> Result<(), InsertionDivergence> {
+    if digests.windows(2).all(|w| w[0] == w[1]) {
+        Ok(())
+    } else {
+        Err(InsertionDivergence {
+            digests: digests.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::actor::{calculate_insertion_indices, distribute_insertions, NON_MATCH_ID};
+
+    const DB_SIZES: [usize; 2] = [5, 4];
+
+    /// Runs the insertion index assignment of a party on a batch in which the
+    /// requests in `unique` did not match, returning the digest of the batch.
+    fn assign(request_ids: &[String], unique: &[&str], canonical: bool) -> InsertionDigest {
+        let mut request_ids = request_ids.to_vec();
+        if canonical {
+            let order = canonical_order(&request_ids, &[]);
+            request_ids = order.iter().map(|&i| request_ids[i].clone()).collect();
+        }
+        let mut merged_results = request_ids
+            .iter()
+            .map(|id| {
+                if unique.contains(&id.as_str()) {
+                    NON_MATCH_ID
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
+        let insertion_list = merged_results
+            .iter()
+            .enumerate()
+            .filter(|&(_, &r)| r == NON_MATCH_ID)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let insertion_list = distribute_insertions(&insertion_list, &DB_SIZES);
+        let matches = calculate_insertion_indices(
+            &mut merged_results,
+            &insertion_list,
+            &DB_SIZES,
+            request_ids.len(),
+        );
+        let mapping = insertion_mapping(&request_ids, &merged_results, &matches);
+        chain_digest(&[0; 32], &mapping)
+    }
+
+    fn requests() -> Vec<String> {
+        (0..6).map(|i| format!("request-{}", i)).collect()
+    }
+
+    #[test]
+    fn test_canonical_order_ignores_arrival_order() {
+        let requests = requests();
+        let mut shuffled = requests.clone();
+        shuffled.reverse();
+        shuffled.swap(1, 4);
+
+        let unique = ["request-1", "request-2", "request-5"];
+        let digests = [
+            assign(&requests, &unique, true),
+            assign(&requests, &unique, true),
+            assign(&shuffled, &unique, true),
+        ];
+        assert_eq!(check_agreement(&digests), Ok(()));
+    }
+
+    #[test]
+    fn test_local_order_divergence_is_detected() {
+        let requests = requests();
+        let mut shuffled = requests.clone();
+        shuffled.reverse();
+
+        let unique = ["request-1", "request-2", "request-5"];
+        let digests = [
+            assign(&requests, &unique, false),
+            assign(&requests, &unique, false),
+            assign(&shuffled, &unique, false),
+        ];
+        let err = check_agreement(&digests).unwrap_err();
+        assert_eq!(err.digests, digests.to_vec());
+    }
+
+    #[test]
+    fn test_mirrored_check_follows_its_request() {
+        let request_ids = ["b", "a", "b", "a"].map(String::from);
+        let mirrored_checks = [false, false, true, true];
+        let order = canonical_order(&request_ids, &mirrored_checks);
+        for pair in order.chunks(2) {
+            assert_eq!(request_ids[pair[0]], request_ids[pair[1]]);
+            assert!(!mirrored_checks[pair[0]] && mirrored_checks[pair[1]]);
+        }
+    }
+
+    #[test]
+    fn test_digest_chains_batches() {
+        let mapping = vec![("a".to_string(), 9)];
+        let first = chain_digest(&[0; 32], &mapping);
+        assert_ne!(chain_digest(&first, &mapping), first);
+        assert_ne!(chain_digest(&[0; 32], &[("a".to_string(), 10)]), first);
+    }
+}
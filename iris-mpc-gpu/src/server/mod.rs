@@ -1,10 +1,17 @@
 mod actor;
+mod insertion;
+pub mod seed_exchange;
 pub mod sync_nccl;
 
 use crate::dot::{share_db::preprocess_query, IRIS_CODE_LENGTH, MASK_CODE_LENGTH, ROTATIONS};
 pub use actor::{get_dummy_shares_for_deletion, ServerActor, ServerActorHandle};
-use iris_mpc_common::galois_engine::degree4::{
-    GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare,
+pub use insertion::InsertionDivergence;
+use iris_mpc_common::{
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    helpers::{
+        latency_budget::BatchDeadline, priority_lanes::RequestLane, threshold::ScheduledThreshold,
+    },
+    iris_db::iris::IrisCodeArray,
 };
 use std::collections::HashSet;
 use tokio::sync::oneshot;
@@ -17,8 +24,14 @@ pub struct BatchQueryEntries {
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BatchQueryEntriesPreprocessed {
-    pub code: Vec<Vec<u8>>,
-    pub mask: Vec<Vec<u8>>,
+    pub code:      Vec<Vec<u8>>,
+    pub mask:      Vec<Vec<u8>>,
+    /// The sums of both limbs of every code entry, see
+    /// [`GaloisRingIrisCodeShare::preprocess_for_query`]. Empty if the sums
+    /// are left to `ShareDB::query_sums`.
+    pub code_sums: Vec<Vec<u32>>,
+    /// Like `code_sums`, for the masks.
+    pub mask_sums: Vec<Vec<u32>>,
 }
 
 impl From<BatchQueryEntries> for BatchQueryEntriesPreprocessed {
@@ -34,11 +47,41 @@ impl From<BatchQueryEntries> for BatchQueryEntriesPreprocessed {
         Self {
             code: preprocess_query(code_coefs),
             mask: preprocess_query(mask_coefs),
+            ..Default::default()
         }
     }
 }
 
 impl BatchQueryEntriesPreprocessed {
+    /// Like the conversion from [`BatchQueryEntries`], but also computes the
+    /// sums of the limbs on the host, so the actor does not compute them on
+    /// the devices.
+    pub fn with_sums(value: BatchQueryEntries) -> Self {
+        assert_eq!(value.code.len(), value.mask.len());
+        let mut result = Self {
+            code:      vec![vec![]; 2],
+            mask:      vec![vec![]; 2],
+            code_sums: vec![vec![]; 2],
+            mask_sums: vec![vec![]; 2],
+        };
+        let code = value.code.iter().map(|e| e.preprocess_for_query());
+        let mask = value.mask.iter().map(|e| e.preprocess_for_query());
+        for (code, mask) in code.zip(mask) {
+            for i in 0..2 {
+                result.code[i].extend_from_slice(&code.limbs[i]);
+                result.mask[i].extend_from_slice(&mask.limbs[i]);
+                result.code_sums[i].push(code.sums[i]);
+                result.mask_sums[i].push(mask.sums[i]);
+            }
+        }
+        result
+    }
+
+    /// Whether the sums of the limbs were computed on the host.
+    pub fn has_sums(&self) -> bool {
+        !self.code_sums.is_empty() && !self.mask_sums.is_empty()
+    }
+
     pub fn len(&self) -> usize {
         assert_eq!(self.code.len(), self.mask.len());
         self.code.iter().zip(self.mask.iter()).for_each(|(c, m)| {
@@ -63,6 +106,40 @@ pub struct BatchMetadata {
     pub span_id:  String,
 }
 
+/// The shares written over an entry of the in-memory db: the shares of a
+/// soft-deleted entry that is restored, as kept in the store, see
+/// [`iris_mpc_common::helpers::soft_delete`], or the new shares of a reset
+/// update.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RestoredEntry {
+    pub index:      u32, // 0-indexed
+    pub left_code:  GaloisRingIrisCodeShare,
+    pub left_mask:  GaloisRingTrimmedMaskCodeShare,
+    pub right_code: GaloisRingIrisCodeShare,
+    pub right_mask: GaloisRingTrimmedMaskCodeShare,
+}
+
+/// An identity deletion batch. It is applied with the first batch all
+/// parties hold it in, see
+/// [`agreed_deletion_batches`](iris_mpc_common::helpers::sync::agreed_deletion_batches).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeletionBatch {
+    /// The SNS message id of the request, the same at every party.
+    pub message_id: String,
+    /// The serial ids of the request, sorted and deduplicated.
+    pub serial_ids: Vec<u32>,
+    pub metadata:   BatchMetadata,
+}
+
+/// A deletion batch applied by all parties.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AppliedDeletionBatch {
+    pub batch:       DeletionBatch,
+    /// 0-indexed, like [`ServerJobResult::deleted_ids`].
+    pub deleted_ids: Vec<u32>,
+    pub unknown_ids: Vec<u32>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct BatchQuery {
     pub request_ids:                Vec<String>,
@@ -79,7 +156,52 @@ pub struct BatchQuery {
     pub db_right_preprocessed:      BatchQueryEntriesPreprocessed,
     pub deletion_requests_indices:  Vec<u32>, // 0-indexed indicies in of entries to be deleted
     pub deletion_requests_metadata: Vec<BatchMetadata>,
+    /// Purge times of the deletion requests with a grace period, in seconds
+    /// since the epoch. The entry is tombstoned for matching either way.
+    pub deletion_requests_purge_at: Vec<Option<u64>>,
+    /// The identity deletion batches this party holds, applied after the
+    /// batch sync if all parties hold them.
+    pub deletion_batches:           Vec<DeletionBatch>,
+    pub restore_requests_indices:   Vec<u32>, // 0-indexed
+    pub restore_requests_metadata:  Vec<BatchMetadata>,
+    /// The restore requests of entries that are still soft-deleted, with
+    /// their shares. They are written back into the in-memory db.
+    pub restored_entries:           Vec<RestoredEntry>,
+    /// The new shares of the reset updates, written over their entries after
+    /// the restores.
+    pub reset_updates:              Vec<RestoredEntry>,
+    pub reset_updates_metadata:     Vec<BatchMetadata>,
+    /// The serial ids whose soft-delete is due by the clock of this party.
+    /// Only the ones due at all parties are purged.
+    pub purge_due:                  Vec<u32>,
     pub valid_entries:              Vec<bool>,
+    pub request_lanes:              Vec<RequestLane>,
+    /// Marks the entries holding the mirrored check of the earlier entry with
+    /// the same request id. These entries are only compared, never inserted.
+    pub mirrored_checks:            Vec<bool>,
+    /// The rotation window of every entry, see [`Self::rotation_window`].
+    pub rotation_windows:           Vec<usize>,
+    /// The 0-indexed serial ids a reauth entry is decided against, `None`
+    /// for the other entries. Like mirrored checks, reauth entries are only
+    /// compared, never inserted.
+    pub reauth_targets:             Vec<Option<Vec<u32>>>,
+    /// The OR-rule serial ids of every entry, see [`Self::or_rule`]. Unknown
+    /// and deleted ones are left out.
+    pub or_rule_indices:            Vec<Option<Vec<u32>>>,
+    /// Marks the entries of reset checks, which are only compared, never
+    /// inserted.
+    pub reset_checks:               Vec<bool>,
+    /// Verified threshold changes received with this batch. They are
+    /// scheduled before the batch is processed.
+    pub threshold_updates:          Vec<ScheduledThreshold>,
+    /// Marks the entries this party wants to shed because the batch cannot
+    /// make its latency budget. Any party can shed an entry.
+    pub shed_entries:               Vec<bool>,
+    /// The latency budget of the batch, with the timings so far.
+    pub deadline:                   Option<BatchDeadline>,
+    /// The batch size this party is limited to by its free device memory, see
+    /// [`iris_mpc_common::helpers::memory_pressure`].
+    pub batch_size_limit:           Option<usize>,
 }
 
 macro_rules! filter_by_indices {
@@ -115,6 +237,18 @@ macro_rules! filter_by_indices_with_rotations_and_code_length {
     };
 }
 
+macro_rules! reorder_by_indices {
+    ($data:expr, $order:expr, $chunk_len:expr) => {
+        if !$data.is_empty() {
+            assert_eq!($data.len(), $order.len() * $chunk_len);
+            $data = $order
+                .iter()
+                .flat_map(|&i| $data[i * $chunk_len..(i + 1) * $chunk_len].iter().cloned())
+                .collect();
+        }
+    };
+}
+
 impl BatchQuery {
     pub fn retain(&mut self, indices: &[usize]) {
         let indices_set: HashSet<usize> = indices.iter().cloned().collect();
@@ -137,6 +271,82 @@ impl BatchQuery {
         Self::filter_preprocessed_entry(&mut self.query_right_preprocessed, &indices_set);
         Self::filter_preprocessed_entry(&mut self.db_right_preprocessed, &indices_set);
         filter_by_indices!(self.valid_entries, indices_set);
+        filter_by_indices!(self.request_lanes, indices_set);
+        filter_by_indices!(self.mirrored_checks, indices_set);
+        filter_by_indices!(self.rotation_windows, indices_set);
+        filter_by_indices!(self.reauth_targets, indices_set);
+        filter_by_indices!(self.or_rule_indices, indices_set);
+        filter_by_indices!(self.reset_checks, indices_set);
+        filter_by_indices!(self.shed_entries, indices_set);
+    }
+
+    /// Puts the entries in the given order, `order[i]` being the current
+    /// index of the entry that ends up at index `i`.
+    pub fn reorder(&mut self, order: &[usize]) {
+        reorder_by_indices!(self.request_ids, order, 1);
+        reorder_by_indices!(self.metadata, order, 1);
+        reorder_by_indices!(self.store_left.code, order, 1);
+        reorder_by_indices!(self.store_left.mask, order, 1);
+        reorder_by_indices!(self.store_right.code, order, 1);
+        reorder_by_indices!(self.store_right.mask, order, 1);
+        reorder_by_indices!(self.query_left.code, order, ROTATIONS);
+        reorder_by_indices!(self.query_left.mask, order, ROTATIONS);
+        reorder_by_indices!(self.db_left.code, order, ROTATIONS);
+        reorder_by_indices!(self.db_left.mask, order, ROTATIONS);
+        reorder_by_indices!(self.query_right.code, order, ROTATIONS);
+        reorder_by_indices!(self.query_right.mask, order, ROTATIONS);
+        reorder_by_indices!(self.db_right.code, order, ROTATIONS);
+        reorder_by_indices!(self.db_right.mask, order, ROTATIONS);
+        for entry in [
+            &mut self.query_left_preprocessed,
+            &mut self.db_left_preprocessed,
+            &mut self.query_right_preprocessed,
+            &mut self.db_right_preprocessed,
+        ] {
+            for i in 0..2 {
+                reorder_by_indices!(entry.code[i], order, IRIS_CODE_LENGTH * ROTATIONS);
+                reorder_by_indices!(entry.mask[i], order, MASK_CODE_LENGTH * ROTATIONS);
+            }
+            for sums in entry.code_sums.iter_mut().chain(entry.mask_sums.iter_mut()) {
+                reorder_by_indices!(*sums, order, ROTATIONS);
+            }
+        }
+        reorder_by_indices!(self.valid_entries, order, 1);
+        reorder_by_indices!(self.request_lanes, order, 1);
+        reorder_by_indices!(self.mirrored_checks, order, 1);
+        reorder_by_indices!(self.rotation_windows, order, 1);
+        reorder_by_indices!(self.reauth_targets, order, 1);
+        reorder_by_indices!(self.or_rule_indices, order, 1);
+        reorder_by_indices!(self.reset_checks, order, 1);
+        reorder_by_indices!(self.shed_entries, order, 1);
+    }
+
+    /// The largest rotation, in columns either way, that the query of entry
+    /// `i` is compared with. Its query lanes of the larger rotations hold
+    /// shares of zero, see
+    /// [`GaloisRingIrisCodeShare::padded_rotations`], so they never match.
+    /// All rotations if the batch has no windows.
+    pub fn rotation_window(&self, i: usize) -> usize {
+        self.rotation_windows
+            .get(i)
+            .copied()
+            .unwrap_or(IrisCodeArray::MAX_ROTATION)
+    }
+
+    /// Whether entry `i` is a reauth entry, see [`Self::reauth_targets`].
+    pub fn is_reauth(&self, i: usize) -> bool {
+        matches!(self.reauth_targets.get(i), Some(Some(_)))
+    }
+
+    /// The serial ids, 0-indexed, a match of entry `i` against does not keep it
+    /// out of the database. Its matches against them are still reported.
+    pub fn or_rule(&self, i: usize) -> Option<&[u32]> {
+        self.or_rule_indices.get(i)?.as_deref()
+    }
+
+    /// Whether entry `i` is a reset check, see [`Self::reset_checks`].
+    pub fn is_reset_check(&self, i: usize) -> bool {
+        self.reset_checks.get(i).copied().unwrap_or(false)
     }
 
     fn filter_preprocessed_entry(
@@ -155,28 +365,89 @@ impl BatchQuery {
                 MASK_CODE_LENGTH
             );
         }
+        for sums in entry.code_sums.iter_mut().chain(entry.mask_sums.iter_mut()) {
+            filter_by_indices_with_rotations!(*sums, indices);
+        }
     }
 }
 
+/// A command to the [`ServerActor`].
+#[derive(Debug)]
+pub enum ServerCommand {
+    Batch(ServerJob),
+    /// Rebuilds the communicators after a failed batch, see
+    /// [`ServerActorHandle::reinit_network`].
+    ReinitNetwork {
+        return_channel: oneshot::Sender<eyre::Result<u64>>,
+    },
+}
+
 #[derive(Debug)]
 pub struct ServerJob {
     batch:          BatchQuery,
     return_channel: oneshot::Sender<ServerJobResult>,
+    /// Span of the batch, the GPU phases are recorded as its children.
+    span:           tracing::Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerJobResult {
-    pub merged_results:            Vec<u32>,
-    pub request_ids:               Vec<String>,
-    pub metadata:                  Vec<BatchMetadata>,
-    pub matches:                   Vec<bool>,
-    pub match_ids:                 Vec<Vec<u32>>,
-    pub partial_match_ids_left:    Vec<Vec<u32>>,
-    pub partial_match_ids_right:   Vec<Vec<u32>>,
-    pub store_left:                BatchQueryEntries,
-    pub store_right:               BatchQueryEntries,
-    pub deleted_ids:               Vec<u32>,
+    pub merged_results: Vec<u32>,
+    pub request_ids: Vec<String>,
+    pub metadata: Vec<BatchMetadata>,
+    pub matches: Vec<bool>,
+    pub match_ids: Vec<Vec<u32>>,
+    /// Matches of the left and right eye alone, per query, regardless of the
+    /// match policy.
+    pub partial_match_ids_left: Vec<Vec<u32>>,
+    pub partial_match_ids_right: Vec<Vec<u32>>,
+    pub store_left: BatchQueryEntries,
+    pub store_right: BatchQueryEntries,
+    pub deleted_ids: Vec<u32>,
+    /// Deletion requests for entries beyond the DB, which were ignored.
+    pub unknown_deletion_ids: Vec<u32>,
+    /// Entries restored from a soft-delete, and restore requests for entries
+    /// that were not soft-deleted, which were ignored.
+    pub restored_ids: Vec<u32>,
+    pub unknown_restore_ids: Vec<u32>,
+    /// Entries whose shares were replaced by a reset update, and reset
+    /// updates for entries beyond the DB, which were ignored.
+    pub reset_update_ids: Vec<u32>,
+    pub unknown_reset_update_ids: Vec<u32>,
+    /// Serial ids of the soft-deletes the parties agreed to purge with this
+    /// batch. Their entries are tombstoned already.
+    pub purged_serial_ids: Vec<u32>,
+    /// The deletion batches all parties held, and the ones that are offered
+    /// again with the next batch.
+    pub applied_deletion_batches: Vec<AppliedDeletionBatch>,
+    pub deferred_deletion_batches: Vec<DeletionBatch>,
     pub matched_batch_request_ids: Vec<Vec<String>>,
+    pub mirrored_checks: Vec<bool>,
+    /// See [`BatchQuery::rotation_window`].
+    pub rotation_windows: Vec<usize>,
+    /// See [`BatchQuery::reauth_targets`]. The decision of a reauth entry is
+    /// whether its `match_ids` hold one of its targets.
+    pub reauth_targets: Vec<Option<Vec<u32>>>,
+    /// See [`BatchQuery::or_rule`].
+    pub or_rule_indices: Vec<Option<Vec<u32>>>,
+    /// See [`BatchQuery::reset_checks`].
+    pub reset_checks: Vec<bool>,
+    /// Digest of the serial ids assigned since startup, before and after this
+    /// batch.
+    pub db_digest_before: [u8; 32],
+    pub db_digest_after: [u8; 32],
+    /// Version of the threshold parameters the batch was matched with.
+    pub threshold_version: u32,
+    /// Requests that were shed at some party to keep the latency budget. They
+    /// were not processed and have to be requeued.
+    pub requeued_request_ids: Vec<String>,
+    /// Requests that not all parties held yet. They were not processed and
+    /// are requeued, up to
+    /// [`MAX_BATCH_DEFERRALS`](iris_mpc_common::helpers::sync::MAX_BATCH_DEFERRALS)
+    /// times.
+    pub deferred_request_ids: Vec<String>,
+    pub deadline: Option<BatchDeadline>,
+    pub span: tracing::Span,
 }
 
 enum Eye {
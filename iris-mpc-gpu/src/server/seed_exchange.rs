@@ -0,0 +1,522 @@
+//! Establish the seeds of the correlated randomness between parties.
+//!
+//! Every party shares one ChaCha seed with the next party and one with the
+//! previous party. At startup, each party generates an ephemeral X25519 key
+//! pair and proposes a session epoch, and all parties exchange their public
+//! keys and epochs over NCCL. The seed of an adjacent pair is then derived via
+//! HKDF from the ephemeral shared secret and the long-term shared secret of the
+//! pair (from KMS), which authenticates the exchange, bound to the session
+//! epoch and both public keys. Every startup thus derives fresh seeds.
+//!
+//! Before the seeds are used, the parties exchange a key confirmation tag per
+//! pair, derived from the same keys over the epoch and both public keys. A
+//! neighbour with another static secret, or that saw other messages, sends a
+//! different tag and the exchange fails instead of desynchronizing the
+//! correlated randomness.
+
+use crate::helpers::comm::NcclComm;
+use cudarc::driver::DeviceSlice;
+use eyre::{eyre, Result};
+use iris_mpc_common::id::PartyID;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use sodiumoxide::crypto::{
+    box_::{self, PublicKey, PUBLICKEYBYTES},
+    scalarmult::curve25519::{scalarmult, GroupElement, Scalar},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// The seeds of a party: shared with the next party and with the previous
+/// party.
+pub type ChachaSeeds = ([u32; 8], [u32; 8]);
+
+const N_PARTIES: usize = 3;
+const SEED_KDF_SALT: &[u8] = b"iris-mpc/nccl-chacha-seeds/v1";
+const CONFIRMATION_KDF_INFO: &[u8] = b"key-confirmation";
+/// Proposed epoch followed by the public key.
+const MESSAGE_LEN: usize = size_of::<u64>() + PUBLICKEYBYTES;
+const TAG_LEN: usize = 32;
+/// The tag of the pair with the next party followed by the one with the
+/// previous party.
+const CONFIRMATION_LEN: usize = 2 * TAG_LEN;
+
+/// The seeds the parties used before the key exchange, derived from the party
+/// ids only. Anyone can compute them, so they must only be used in tests.
+pub fn insecure_deterministic_seeds(party_id: usize) -> ChachaSeeds {
+    let (next, prev) = PartyID::try_from(party_id)
+        .expect("party id is 0, 1 or 2")
+        .seed_ids();
+    ([next; 8], [prev; 8])
+}
+
+/// The X25519 key pair of one seed establishment. It is never cloned or
+/// persisted, and its secret key is zeroized on drop.
+struct EphemeralKeyPair {
+    public_key: PublicKey,
+    secret_key: [u8; 32],
+}
+
+impl EphemeralKeyPair {
+    fn generate() -> Self {
+        let (public_key, secret_key) = box_::gen_keypair();
+        Self {
+            public_key,
+            secret_key: secret_key.0,
+        }
+    }
+
+    fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Computes the raw shared secret with the public key of another party.
+    /// Fails if the other key is a low order point.
+    fn diffie_hellman(&self, other: &PublicKey) -> Result<Zeroizing<[u8; 32]>> {
+        let shared = scalarmult(&Scalar(self.secret_key), &GroupElement(other.0))
+            .map_err(|_| eyre!("Seed exchange public key is a low order point"))?;
+        Ok(Zeroizing::new(shared.0))
+    }
+}
+
+impl Drop for EphemeralKeyPair {
+    fn drop(&mut self) {
+        self.secret_key.zeroize();
+    }
+}
+
+/// The local side of one seed establishment.
+pub struct SeedExchange {
+    party_id: PartyID,
+    key_pair: EphemeralKeyPair,
+    epoch:    u64,
+}
+
+impl SeedExchange {
+    /// Starts an exchange with a fresh ephemeral key pair. The session epoch
+    /// is the largest epoch proposed by any party.
+    pub fn new(party_id: usize, proposed_epoch: u64) -> Self {
+        Self {
+            party_id: PartyID::try_from(party_id).expect("party id is 0, 1 or 2"),
+            key_pair: EphemeralKeyPair::generate(),
+            epoch:    proposed_epoch,
+        }
+    }
+
+    /// The message to send to all other parties.
+    pub fn message(&self) -> [u8; MESSAGE_LEN] {
+        let mut message = [0u8; MESSAGE_LEN];
+        message[..size_of::<u64>()].copy_from_slice(&self.epoch.to_le_bytes());
+        message[size_of::<u64>()..].copy_from_slice(&self.key_pair.public_key().0);
+        message
+    }
+
+    /// Derives the seeds from the messages of all parties, ordered by party
+    /// id. `static_secrets` are the long-term secrets shared with the next and
+    /// the previous party. The seeds are only used once the neighbours
+    /// confirmed them, see [`DerivedSeeds::confirm`].
+    pub fn derive(
+        &self,
+        all_messages: &[u8],
+        static_secrets: &([u8; 32], [u8; 32]),
+    ) -> Result<DerivedSeeds> {
+        if all_messages.len() != N_PARTIES * MESSAGE_LEN {
+            return Err(eyre!(
+                "Expected {} bytes of seed exchange messages, got {}",
+                N_PARTIES * MESSAGE_LEN,
+                all_messages.len()
+            ));
+        }
+        let messages = all_messages.chunks(MESSAGE_LEN).collect::<Vec<_>>();
+        if messages[usize::from(self.party_id)] != self.message() {
+            return Err(eyre!("Own seed exchange message was altered"));
+        }
+        let epoch = messages
+            .iter()
+            .map(|m| u64::from_le_bytes(m[..size_of::<u64>()].try_into().unwrap()))
+            .max()
+            .unwrap();
+        let public_key =
+            |i: usize| PublicKey::from_slice(&messages[i][size_of::<u64>()..]).unwrap();
+
+        let next_id = self.party_id.next_id().into();
+        let prev_id = self.party_id.prev_id().into();
+        let (next_seed, next_tag) = self.derive_pair_seed(
+            epoch,
+            &static_secrets.0,
+            self.key_pair.public_key(),
+            &public_key(next_id),
+        )?;
+        let (prev_seed, prev_tag) = self.derive_pair_seed(
+            epoch,
+            &static_secrets.1,
+            &public_key(prev_id),
+            self.key_pair.public_key(),
+        )?;
+        Ok(DerivedSeeds {
+            party_id: self.party_id,
+            seeds:    (next_seed, prev_seed),
+            tags:     (next_tag, prev_tag),
+        })
+    }
+
+    /// Derives the seed of the pair from the party `first` to the next party
+    /// `second`, and the tag that confirms it. Both parties of the pair call
+    /// this with the same arguments up to their own secret key.
+    fn derive_pair_seed(
+        &self,
+        epoch: u64,
+        static_secret: &[u8; 32],
+        first: &PublicKey,
+        second: &PublicKey,
+    ) -> Result<([u32; 8], [u8; TAG_LEN])> {
+        let other = if first == self.key_pair.public_key() {
+            second
+        } else {
+            first
+        };
+        let shared_secret = self.key_pair.diffie_hellman(other)?;
+
+        let mut salt = SEED_KDF_SALT.to_vec();
+        salt.extend_from_slice(&epoch.to_be_bytes());
+        let input_key =
+            Zeroizing::new([shared_secret.as_slice(), static_secret.as_slice()].concat());
+        let pseudo_rand_key = Salt::new(HKDF_SHA256, &salt).extract(&input_key);
+        let context = [first.0.as_slice(), second.0.as_slice()];
+        let mut seed = [0u32; 8];
+        pseudo_rand_key
+            .expand(&context, HKDF_SHA256)
+            .and_then(|okm| okm.fill(bytemuck::cast_slice_mut(&mut seed)))
+            .map_err(|_| eyre!("Failed to derive seed"))?;
+
+        let epoch = epoch.to_be_bytes();
+        let context = [
+            CONFIRMATION_KDF_INFO,
+            epoch.as_slice(),
+            first.0.as_slice(),
+            second.0.as_slice(),
+        ];
+        let mut tag = [0u8; TAG_LEN];
+        pseudo_rand_key
+            .expand(&context, HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut tag))
+            .map_err(|_| eyre!("Failed to derive key confirmation tag"))?;
+        Ok((seed, tag))
+    }
+}
+
+/// The seeds of a party that its neighbours have yet to confirm.
+pub struct DerivedSeeds {
+    party_id: PartyID,
+    seeds:    ChachaSeeds,
+    /// The tags of the pair with the next and with the previous party.
+    tags:     ([u8; TAG_LEN], [u8; TAG_LEN]),
+}
+
+impl DerivedSeeds {
+    /// The key confirmation message to send to all other parties.
+    pub fn confirmation(&self) -> [u8; CONFIRMATION_LEN] {
+        let mut confirmation = [0u8; CONFIRMATION_LEN];
+        confirmation[..TAG_LEN].copy_from_slice(&self.tags.0);
+        confirmation[TAG_LEN..].copy_from_slice(&self.tags.1);
+        confirmation
+    }
+
+    /// Checks the key confirmation messages of all parties, ordered by party
+    /// id: the next party must have derived the tag of our next seed as the
+    /// one of its previous seed, and vice versa. Fails naming the neighbour
+    /// otherwise.
+    pub fn confirm(self, all_confirmations: &[u8]) -> Result<ChachaSeeds> {
+        if all_confirmations.len() != N_PARTIES * CONFIRMATION_LEN {
+            return Err(eyre!(
+                "Expected {} bytes of key confirmation messages, got {}",
+                N_PARTIES * CONFIRMATION_LEN,
+                all_confirmations.len()
+            ));
+        }
+        let confirmations = all_confirmations
+            .chunks(CONFIRMATION_LEN)
+            .collect::<Vec<_>>();
+        let next_id = self.party_id.next_id();
+        let prev_id = self.party_id.prev_id();
+        if confirmations[usize::from(next_id)][TAG_LEN..] != self.tags.0 {
+            return Err(eyre!(
+                "Key confirmation with the next party {} failed",
+                next_id
+            ));
+        }
+        if confirmations[usize::from(prev_id)][..TAG_LEN] != self.tags.1 {
+            return Err(eyre!(
+                "Key confirmation with the previous party {} failed",
+                prev_id
+            ));
+        }
+        Ok(self.seeds)
+    }
+}
+
+/// Runs the seed establishment with the other parties over `comm`, see the
+/// module docs. `static_secrets` are the long-term secrets shared with the
+/// next and the previous party.
+pub fn exchange_seeds(
+    comm: &NcclComm,
+    party_id: usize,
+    proposed_epoch: u64,
+    static_secrets: &([u8; 32], [u8; 32]),
+) -> Result<ChachaSeeds> {
+    if comm.world_size() != N_PARTIES {
+        return Err(eyre!(
+            "Seed exchange needs {} parties, got {}",
+            N_PARTIES,
+            comm.world_size()
+        ));
+    }
+    let exchange = SeedExchange::new(party_id, proposed_epoch);
+
+    let message_dev = comm
+        .device()
+        .htod_copy(exchange.message().to_vec())
+        .unwrap();
+    let mut all_messages_dev = comm
+        .device()
+        .alloc_zeros(message_dev.len() * comm.world_size())
+        .unwrap();
+    comm.all_gather(&message_dev, &mut all_messages_dev)
+        .map_err(|e| eyre!("{:?}", e.0))?;
+    let all_messages = comm.device().dtoh_sync_copy(&all_messages_dev).unwrap();
+    let derived = exchange.derive(&all_messages, static_secrets)?;
+
+    let confirmation_dev = comm
+        .device()
+        .htod_copy(derived.confirmation().to_vec())
+        .unwrap();
+    let mut all_confirmations_dev = comm
+        .device()
+        .alloc_zeros(confirmation_dev.len() * comm.world_size())
+        .unwrap();
+    comm.all_gather(&confirmation_dev, &mut all_confirmations_dev)
+        .map_err(|e| eyre!("{:?}", e.0))?;
+    let all_confirmations = comm
+        .device()
+        .dtoh_sync_copy(&all_confirmations_dev)
+        .unwrap();
+
+    derived.confirm(&all_confirmations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the exchange between three local parties. `static_secrets[i]` are
+    /// the secrets party i holds for its pair with party i + 1 and with party
+    /// i - 1.
+    fn try_exchange(
+        epochs: [u64; 3],
+        static_secrets: [([u8; 32], [u8; 32]); 3],
+    ) -> Vec<Result<ChachaSeeds>> {
+        let parties = (0..N_PARTIES)
+            .map(|i| SeedExchange::new(i, epochs[i]))
+            .collect::<Vec<_>>();
+        let all_messages = parties.iter().flat_map(|p| p.message()).collect::<Vec<_>>();
+        let derived = (0..N_PARTIES)
+            .map(|i| {
+                parties[i]
+                    .derive(&all_messages, &static_secrets[i])
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let all_confirmations = derived
+            .iter()
+            .flat_map(|d| d.confirmation())
+            .collect::<Vec<_>>();
+        derived
+            .into_iter()
+            .map(|d| d.confirm(&all_confirmations))
+            .collect()
+    }
+
+    /// Runs the exchange with consistent static secrets, `static_secrets[i]`
+    /// is shared between party i and party i + 1.
+    fn run_exchange(epochs: [u64; 3], static_secrets: [[u8; 32]; 3]) -> Vec<ChachaSeeds> {
+        let secrets = std::array::from_fn(|i| (static_secrets[i], static_secrets[(i + 2) % 3]));
+        try_exchange(epochs, secrets)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    fn assert_pairs_agree(seeds: &[ChachaSeeds]) {
+        for i in 0..N_PARTIES {
+            assert_eq!(seeds[i].0, seeds[(i + 1) % 3].1);
+            assert_ne!(seeds[i].0, seeds[i].1);
+        }
+    }
+
+    #[test]
+    fn test_pairs_derive_same_seed() {
+        let seeds = run_exchange([1, 2, 3], [[1; 32], [2; 32], [3; 32]]);
+        assert_pairs_agree(&seeds);
+        assert_pairs_agree(&(0..3).map(insecure_deterministic_seeds).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_startups_derive_different_seeds() {
+        let secrets = [[1; 32], [2; 32], [3; 32]];
+        let first = run_exchange([1, 1, 1], secrets);
+        let second = run_exchange([1, 1, 1], secrets);
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_ne!(a.0, b.0);
+            assert_ne!(a.1, b.1);
+        }
+    }
+
+    #[test]
+    fn test_open_with_derived_seeds() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // Zero sharing as in the correlated randomness: every party XORs the
+        // streams of its two seeds, which cancel out across the parties.
+        let seeds = run_exchange([5, 7, 6], [[9; 32], [8; 32], [7; 32]]);
+        let stream = |seed: [u32; 8]| {
+            let mut rng = StdRng::from_seed(bytemuck::cast(seed));
+            (0..64).map(|_| rng.gen::<u64>()).collect::<Vec<_>>()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let secret = (0..64).map(|_| rng.gen::<u64>()).collect::<Vec<_>>();
+
+        // Party 0 masks the secret, the others only their randomness.
+        let shares = seeds
+            .iter()
+            .enumerate()
+            .map(|(i, (next, prev))| {
+                let (a, b) = (stream(*next), stream(*prev));
+                (0..64)
+                    .map(|j| a[j] ^ b[j] ^ if i == 0 { secret[j] } else { 0 })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let opened = (0..64)
+            .map(|j| shares[0][j] ^ shares[1][j] ^ shares[2][j])
+            .collect::<Vec<_>>();
+        assert_eq!(opened, secret);
+        assert_ne!(shares[0], secret);
+    }
+
+    #[test]
+    fn test_ephemeral_diffie_hellman() {
+        let (a, b) = (EphemeralKeyPair::generate(), EphemeralKeyPair::generate());
+        assert_eq!(
+            *a.diffie_hellman(b.public_key()).unwrap(),
+            *b.diffie_hellman(a.public_key()).unwrap()
+        );
+        assert_ne!(a.public_key(), b.public_key());
+        // The identity is a low order point.
+        assert!(a.diffie_hellman(&PublicKey([0; PUBLICKEYBYTES])).is_err());
+    }
+
+    #[test]
+    fn test_epoch_and_static_secret_bind_seeds() {
+        let parties = (0..N_PARTIES)
+            .map(|i| SeedExchange::new(i, 1))
+            .collect::<Vec<_>>();
+        let (first, second) = (
+            parties[0].key_pair.public_key(),
+            parties[1].key_pair.public_key(),
+        );
+        let seed = |epoch, secret| {
+            parties[0]
+                .derive_pair_seed(epoch, &secret, first, second)
+                .unwrap()
+                .0
+        };
+        assert_eq!(seed(1, [1; 32]), seed(1, [1; 32]));
+        assert_ne!(seed(1, [1; 32]), seed(2, [1; 32]));
+        assert_ne!(seed(1, [1; 32]), seed(1, [2; 32]));
+
+        // Messages of the wrong size or with an altered own message fail.
+        let messages = parties.iter().flat_map(|p| p.message()).collect::<Vec<_>>();
+        let secrets = ([1; 32], [3; 32]);
+        assert!(parties[0].derive(&messages, &secrets).is_ok());
+        assert!(parties[0]
+            .derive(&messages[..MESSAGE_LEN], &secrets)
+            .is_err());
+        let mut altered = messages.clone();
+        altered[0] ^= 1;
+        assert!(parties[0].derive(&altered, &secrets).is_err());
+    }
+
+    #[test]
+    fn test_mismatched_static_secrets_fail_confirmation() {
+        // Party 1 holds another secret for its pair with party 0.
+        let results = try_exchange([1, 1, 1], [
+            ([1; 32], [3; 32]),
+            ([2; 32], [4; 32]),
+            ([3; 32], [2; 32]),
+        ]);
+        let error = |i: usize| results[i].as_ref().unwrap_err().to_string();
+        assert!(error(0).contains("next party"));
+        assert!(error(1).contains("previous party"));
+        // The pairs of party 2 are unaffected.
+        assert!(results[2].is_ok());
+
+        // Confirmations of the wrong size fail.
+        let parties = (0..N_PARTIES)
+            .map(|i| SeedExchange::new(i, 1))
+            .collect::<Vec<_>>();
+        let messages = parties.iter().flat_map(|p| p.message()).collect::<Vec<_>>();
+        let derived = parties[0].derive(&messages, &([1; 32], [3; 32])).unwrap();
+        let confirmation = derived.confirmation();
+        assert!(derived.confirm(&confirmation).is_err());
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "gpu_dependent")]
+mod gpu_tests {
+    use super::*;
+    use crate::{helpers::dtoh_on_stream_sync, rng::chacha_corr::ChaChaCudaCorrRng};
+    use cudarc::{driver::CudaDevice, nccl::Id};
+    use itertools::izip;
+    use tokio::task::JoinSet;
+
+    #[tokio::test]
+    async fn test_exchange_seeds() -> Result<()> {
+        if CudaDevice::count()? < N_PARTIES as i32 {
+            return Ok(());
+        }
+        let net_id = Id::new().unwrap();
+        let static_secrets = [[1u8; 32], [2u8; 32], [3u8; 32]];
+
+        let mut tasks = JoinSet::new();
+        for i in 0..N_PARTIES {
+            let secrets = (static_secrets[i], static_secrets[(i + 2) % 3]);
+            tasks.spawn_blocking(move || {
+                let device = CudaDevice::new(i).unwrap();
+                let comm = NcclComm::from_rank(device.clone(), i, N_PARTIES, net_id).unwrap();
+                let seeds = exchange_seeds(&comm, i, i as u64, &secrets).unwrap();
+
+                let stream = device.fork_default_stream().unwrap();
+                let mut rng = ChaChaCudaCorrRng::init(device.clone(), seeds.0, seeds.1);
+                let mut buf = device.alloc_zeros(1024 * 1024).unwrap();
+                rng.fill_rng_into(&mut buf.slice_mut(..), &stream);
+                (
+                    i,
+                    seeds,
+                    dtoh_on_stream_sync(&buf, &device, &stream).unwrap(),
+                )
+            });
+        }
+
+        let mut results = vec![];
+        while let Some(result) = tasks.join_next().await {
+            results.push(result?);
+        }
+        results.sort_by_key(|(i, ..)| *i);
+        for i in 0..N_PARTIES {
+            assert_eq!(results[i].1 .0, results[(i + 1) % 3].1 .1);
+        }
+        for (a, b, c) in izip!(&results[0].2, &results[1].2, &results[2].2) {
+            assert_eq!(a ^ b ^ c, 0);
+        }
+        Ok(())
+    }
+}
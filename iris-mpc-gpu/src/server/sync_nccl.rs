@@ -1,79 +1,379 @@
 //! Exchange the SyncState between parties using NCCL.
 
 use crate::helpers::comm::NcclComm;
-use cudarc::driver::DeviceSlice;
+use cudarc::{
+    driver::{CudaDevice, DeviceSlice},
+    nccl::{result::NcclStatus, Id},
+};
 use eyre::{eyre, Result};
-use iris_mpc_common::helpers::sync::{SyncResult, SyncState};
+use iris_mpc_common::helpers::{
+    share_audit::{ShareAuditContribution, SHARE_AUDIT_CHECKS},
+    soft_delete::MAX_PURGES_PER_BATCH,
+    state_exchange::{StateExchange, MAX_SYNC_STATE_LEN},
+    sync::{BatchSyncState, SyncResult, SyncState, MAX_DELETION_BATCHES},
+    threshold::{ThresholdSyncState, MAX_PENDING_THRESHOLDS},
+};
+use rand::Rng;
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
 
-pub fn sync(comm: &NcclComm, state: &SyncState) -> Result<SyncResult> {
-    let state_dev = comm.device().htod_copy(serialize(state)?).unwrap();
-    let mut all_states_dev = comm
-        .device()
-        .alloc_zeros(state_dev.len() * comm.world_size())
-        .unwrap();
+/// The other parties did not complete the state sync in time, e.g. because
+/// one of them never came up. The communicator is aborted.
+#[derive(Debug, Error)]
+#[error("State sync of party {party_id} timed out after {elapsed:?}")]
+pub struct SyncTimeout {
+    pub party_id: usize,
+    pub elapsed:  Duration,
+}
+
+/// The magic of the [`DeviceManager::get_ids_from_magic`] id of the
+/// communicator of [`NcclStateExchange`]. Its low half is no device index, so
+/// it differs from the [`network_magic`] of every generation.
+///
+/// [`DeviceManager::get_ids_from_magic`]: crate::helpers::device_manager::DeviceManager::get_ids_from_magic
+/// [`network_magic`]: crate::helpers::device_manager::network_magic
+pub const STATE_SYNC_MAGIC: u64 = u32::MAX as u64;
+
+/// [`StateExchange`] over NCCL, on a communicator of its own, giving up after
+/// `timeout`. Both the initialization of the communicator and the exchange
+/// are watched: once the timeout passes, a watchdog thread aborts the
+/// communicator, so that the pending initialization or collective returns,
+/// and the exchange fails with [`SyncTimeout`].
+pub struct NcclStateExchange {
+    device:     Arc<CudaDevice>,
+    rank:       usize,
+    world_size: usize,
+    id:         Id,
+    timeout:    Duration,
+}
+
+impl NcclStateExchange {
+    pub fn new(
+        device: Arc<CudaDevice>,
+        rank: usize,
+        world_size: usize,
+        id: Id,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            device,
+            rank,
+            world_size,
+            id,
+            timeout,
+        }
+    }
+}
 
-    comm.all_gather(&state_dev, &mut all_states_dev)
+impl StateExchange for NcclStateExchange {
+    fn exchange(&self, state: &SyncState) -> Result<Vec<SyncState>> {
+        let started = Instant::now();
+        self.device.bind_to_thread()?;
+        let comm = NcclComm::from_rank_nonblocking(
+            self.device.clone(),
+            self.rank,
+            self.world_size,
+            self.id,
+        )
         .map_err(|e| eyre!("{:?}", e.0))?;
 
-    let all_states_ser = comm.device().dtoh_sync_copy(&all_states_dev).unwrap();
-    let all_states = deserialize_all(&all_states_ser)?;
-    Ok(SyncResult::new(state.clone(), all_states))
+        let abort_handle = comm.abort_handle();
+        let timeout = self.timeout;
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let watchdog = thread::spawn(move || match done_rx.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = abort_handle.abort() {
+                    tracing::error!("Failed to abort the NCCL communicator: {:?}", e);
+                }
+                true
+            }
+            _ => false,
+        });
+
+        let result = comm
+            .wait()
+            .map_err(|e| eyre!("{:?}", e.0))
+            .and_then(|()| exchange(&comm, state));
+        drop(done_tx);
+        if watchdog.join().expect("the watchdog does not panic") {
+            return Err(SyncTimeout {
+                party_id: self.rank,
+                elapsed:  started.elapsed(),
+            }
+            .into());
+        }
+        if let Err(e) = comm.abort() {
+            tracing::warn!("Failed to tear down the state sync communicator: {:?}", e);
+        }
+        result
+    }
+}
+
+pub fn sync(comm: &NcclComm, state: &SyncState) -> Result<SyncResult> {
+    Ok(SyncResult::new(state.clone(), exchange(comm, state)?))
 }
 
-// Change these parameters together - see unittests below.
-/// The fixed serialization size of SyncState.
-pub const MAX_REQUESTS: usize = 256 * 2;
-const MAX_REQUEST_ID_LEN: usize = 36; // uuidv4 string
-const SERIAL_SIZE: usize =
-    MAX_REQUESTS * (size_of::<usize>() + MAX_REQUEST_ID_LEN) + 2 * size_of::<usize>();
+fn exchange(comm: &NcclComm, state: &SyncState) -> Result<Vec<SyncState>> {
+    all_gather_dynamic(comm, serialize(state)?)?
+        .iter()
+        .map(|state_ser| deserialize(state_ser))
+        .collect()
+}
 
-/// Serialize the state to a fixed-size buffer suitable for all_gather.
-fn serialize(state: &SyncState) -> Result<Vec<u8>> {
+/// Exchanges the threshold state before a batch, returning the states of all
+/// parties.
+pub fn sync_threshold(
+    comm: &NcclComm,
+    state: &ThresholdSyncState,
+) -> Result<Vec<ThresholdSyncState>> {
     let mut state_ser = bincode::serialize(state)?;
-    if state_ser.len() > SERIAL_SIZE {
-        return Err(eyre!("State too large to serialize"));
+    if state_ser.len() > THRESHOLD_SYNC_LEN {
+        return Err(eyre!("Threshold state too large to serialize"));
     }
-    state_ser.extend(std::iter::repeat(0).take(SERIAL_SIZE - state_ser.len()));
-    Ok(state_ser)
+    state_ser.resize(THRESHOLD_SYNC_LEN, 0);
+    all_gather_fixed(comm, state_ser)?
+        .chunks(THRESHOLD_SYNC_LEN)
+        .map(|s| Ok(bincode::deserialize(s)?))
+        .collect()
 }
 
-/// Deserialize the state from a fixed-size buffer.
-fn deserialize(state_ser: &[u8]) -> Result<SyncState> {
-    Ok(bincode::deserialize(state_ser)?)
+/// Exchanges the generation of the communicators after a reinit, returning
+/// the generations of all parties.
+pub fn sync_generation(comm: &NcclComm, generation: u64) -> Result<Vec<u64>> {
+    Ok(all_gather_fixed(comm, generation.to_le_bytes().to_vec())?
+        .chunks(size_of::<u64>())
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
 }
 
-/// Deserialize all states concatenated in a buffer (the output of all_gather).
-fn deserialize_all(state_ser: &[u8]) -> Result<Vec<SyncState>> {
-    state_ser.chunks(SERIAL_SIZE).map(deserialize).collect()
+/// Exchanges the candidates for the next batch, holding up to `max_candidates`
+/// entries, returning the states of all parties.
+pub fn sync_batch(
+    comm: &NcclComm,
+    state: &BatchSyncState,
+    max_candidates: usize,
+) -> Result<Vec<BatchSyncState>> {
+    let len = batch_sync_len(max_candidates);
+    let mut state_ser = bincode::serialize(state)?;
+    if state_ser.len() > len {
+        return Err(eyre!("Batch candidates too large to serialize"));
+    }
+    state_ser.resize(len, 0);
+    all_gather_fixed(comm, state_ser)?
+        .chunks(len)
+        .map(|s| Ok(bincode::deserialize(s)?))
+        .collect()
+}
+
+/// Exchanges a random contribution to the seed of the share audit, returning
+/// the contributions of all parties.
+pub fn sync_share_audit_seed(comm: &NcclComm) -> Result<Vec<[u8; 32]>> {
+    let contribution = rand::thread_rng().gen::<[u8; 32]>();
+    Ok(all_gather_fixed(comm, contribution.to_vec())?
+        .chunks(32)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect())
+}
+
+/// Exchanges the share audit contributions of up to `max_serial_ids` serial
+/// ids, returning the contributions of all parties.
+pub fn sync_share_audit(
+    comm: &NcclComm,
+    contribution: &ShareAuditContribution,
+    max_serial_ids: usize,
+) -> Result<Vec<ShareAuditContribution>> {
+    let len = share_audit_len(max_serial_ids);
+    let mut contribution_ser = bincode::serialize(contribution)?;
+    if contribution_ser.len() > len {
+        return Err(eyre!("Share audit contribution too large to serialize"));
+    }
+    contribution_ser.resize(len, 0);
+    all_gather_fixed(comm, contribution_ser)?
+        .chunks(len)
+        .map(|s| Ok(bincode::deserialize(s)?))
+        .collect()
+}
+
+/// Gathers a buffer of the same size from every party.
+fn all_gather_fixed(comm: &NcclComm, buffer: Vec<u8>) -> Result<Vec<u8>> {
+    let buffer_dev = comm.device().htod_copy(buffer)?;
+    let mut all_dev = comm
+        .device()
+        .alloc_zeros(buffer_dev.len() * comm.world_size())?;
+    let status = comm
+        .all_gather(&buffer_dev, &mut all_dev)
+        .map_err(|e| eyre!("{:?}", e.0))?;
+    if status == NcclStatus::InProgress {
+        comm.wait().map_err(|e| eyre!("{:?}", e.0))?;
+    }
+    // Fails rather than panics once the communicator is aborted.
+    comm.device()
+        .dtoh_sync_copy(&all_dev)
+        .map_err(|e| eyre!("{:?}", e))
+}
+
+/// Gathers a buffer of any length from every party: first the lengths, then
+/// the buffers padded to the longest one. The lengths are checked against
+/// [`MAX_SYNC_STATE_LEN`] once gathered, so that all parties fail together.
+fn all_gather_dynamic(comm: &NcclComm, buffer: Vec<u8>) -> Result<Vec<Vec<u8>>> {
+    let all_lens = decode_lens(&all_gather_fixed(comm, encode_len(buffer.len()))?)?;
+    let all_buffers = all_gather_fixed(comm, pad(buffer, padded_len(&all_lens)))?;
+    Ok(split_padded(&all_buffers, &all_lens))
+}
+
+fn encode_len(len: usize) -> Vec<u8> {
+    (len as u64).to_le_bytes().to_vec()
+}
+
+/// Decodes the gathered lengths, failing if any exceeds
+/// [`MAX_SYNC_STATE_LEN`].
+fn decode_lens(all_lens: &[u8]) -> Result<Vec<usize>> {
+    all_lens
+        .chunks_exact(size_of::<u64>())
+        .map(|len| {
+            let len = u64::from_le_bytes(len.try_into().unwrap());
+            if len > MAX_SYNC_STATE_LEN as u64 {
+                return Err(eyre!(
+                    "State of {} bytes exceeds the limit of {} bytes",
+                    len,
+                    MAX_SYNC_STATE_LEN
+                ));
+            }
+            Ok(len as usize)
+        })
+        .collect()
+}
+
+/// The length every buffer is padded to, at least one byte.
+fn padded_len(all_lens: &[usize]) -> usize {
+    all_lens.iter().copied().max().unwrap_or(0).max(1)
+}
+
+fn pad(mut buffer: Vec<u8>, len: usize) -> Vec<u8> {
+    buffer.resize(len, 0);
+    buffer
+}
+
+/// Splits the gathered padded buffers, dropping the padding.
+fn split_padded(all_buffers: &[u8], all_lens: &[usize]) -> Vec<Vec<u8>> {
+    all_buffers
+        .chunks(padded_len(all_lens))
+        .zip(all_lens)
+        .map(|(buffer, &len)| buffer[..len].to_vec())
+        .collect()
+}
+
+/// Party id, and the length prefixed serial ids and check values.
+fn share_audit_len(max_serial_ids: usize) -> usize {
+    size_of::<usize>()
+        + 2 * size_of::<u64>()
+        + max_serial_ids * (size_of::<u32>() + SHARE_AUDIT_CHECKS * size_of::<u16>())
+}
+
+/// The length prefixed candidates: key, mirrored flag, lane, optional error
+/// code, shed flag and optional OR-rule key, followed by the optional batch
+/// size limit, the length prefixed serial ids due for purging and the length
+/// prefixed keys of the deletion batches.
+fn batch_sync_len(max_candidates: usize) -> usize {
+    size_of::<usize>()
+        + max_candidates
+            * (size_of::<u64>()
+                + 2 * size_of::<bool>()
+                + size_of::<u8>()
+                + 1
+                + size_of::<u16>()
+                + 1
+                + size_of::<u64>())
+        + 1
+        + size_of::<usize>()
+        + size_of::<usize>()
+        + MAX_PURGES_PER_BATCH * size_of::<u32>()
+        + size_of::<usize>()
+        + MAX_DELETION_BATCHES * size_of::<u64>()
+}
+
+/// Batch number, active params, and the length prefixed pending changes.
+const THRESHOLD_SYNC_LEN: usize = size_of::<u64>()
+    + THRESHOLD_PARAMS_LEN
+    + size_of::<usize>()
+    + MAX_PENDING_THRESHOLDS * (size_of::<u64>() + THRESHOLD_PARAMS_LEN);
+const THRESHOLD_PARAMS_LEN: usize = size_of::<u32>() + size_of::<f64>();
+
+fn serialize(state: &SyncState) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(state)?)
+}
+
+fn deserialize(state_ser: &[u8]) -> Result<SyncState> {
+    Ok(bincode::deserialize(state_ser)?)
 }
 
 #[cfg(test)]
 #[cfg(feature = "gpu_dependent")]
 mod tests {
     use super::*;
-    use cudarc::{driver::CudaDevice, nccl::Id};
     use eyre::Result;
+    use iris_mpc_common::{
+        config::{CommonConfig, MatchPolicy, ResultMode},
+        helpers::{
+            sync::BatchCandidate,
+            threshold::{ScheduledThreshold, ThresholdParams},
+        },
+    };
     use tokio::task::JoinSet;
 
     #[test]
     fn test_serialize() -> Result<()> {
-        // My state.
-        let state = SyncState {
+        // Far more deletions than fit the former fixed size of about 22 KB.
+        let large_state = SyncState {
             db_len:              123,
-            deleted_request_ids: vec!["A".repeat(MAX_REQUEST_ID_LEN); MAX_REQUESTS],
+            deleted_request_ids: vec!["A".repeat(36); 100_000],
+            config_fingerprint:  "F".repeat(64),
+            common_config:       "C".repeat(1024),
+            threshold:           full_threshold_state(),
+            key_generation:      Some(u64::MAX),
         };
-        let state_ser = serialize(&state)?;
-        assert_eq!(state_ser.len(), SERIAL_SIZE);
-        // Concatenation of states from 3 parties.
-        let all_states_ser = vec![state_ser.clone(); 3].concat();
-        let all_states = deserialize_all(&all_states_ser)?;
-
-        for s in all_states.iter() {
-            assert_eq!(s, &state);
+        let states = [large_state.clone(), some_state(), large_state];
+        let states_ser = states.iter().map(serialize).collect::<Result<Vec<_>>>()?;
+        assert!(states_ser[0].len() > 100_000 * 36);
+
+        // The exchange of all_gather_dynamic, between 3 parties.
+        let all_lens = decode_lens(
+            &states_ser
+                .iter()
+                .flat_map(|state_ser| encode_len(state_ser.len()))
+                .collect::<Vec<_>>(),
+        )?;
+        let all_states_ser = states_ser
+            .into_iter()
+            .flat_map(|state_ser| pad(state_ser, padded_len(&all_lens)))
+            .collect::<Vec<_>>();
+        let all_states = split_padded(&all_states_ser, &all_lens);
+
+        assert_eq!(all_states.len(), 3);
+        for (state_ser, state) in all_states.iter().zip(&states) {
+            assert_eq!(&deserialize(state_ser)?, state);
         }
         Ok(())
     }
 
+    #[test]
+    fn test_state_len_limit() {
+        let all_lens = [encode_len(10), encode_len(MAX_SYNC_STATE_LEN + 1)].concat();
+        assert!(decode_lens(&all_lens).is_err());
+        let all_lens = [encode_len(10), encode_len(MAX_SYNC_STATE_LEN)].concat();
+        assert_eq!(decode_lens(&all_lens).unwrap(), vec![
+            10,
+            MAX_SYNC_STATE_LEN
+        ]);
+    }
+
     #[tokio::test]
     async fn test_sync() -> Result<()> {
         let n_parties = 3.min(CudaDevice::count()? as usize);
@@ -109,10 +409,7 @@ mod tests {
             let my_state = if i == 0 {
                 some_state()
             } else {
-                SyncState {
-                    db_len:              12, // late
-                    deleted_request_ids: vec![],
-                }
+                SyncState::new(12 /* late */, vec![], &some_config())
             };
             move || {
                 let device = CudaDevice::new(i).unwrap();
@@ -132,10 +429,158 @@ mod tests {
         Ok(())
     }
 
-    fn some_state() -> SyncState {
-        SyncState {
-            db_len:              123,
-            deleted_request_ids: vec!["abc".to_string(), "def".to_string()],
+    #[tokio::test]
+    async fn test_sync_timeout() -> Result<()> {
+        let n_parties = 3.min(CudaDevice::count()? as usize);
+        if n_parties < 2 {
+            return Ok(());
+        }
+        let net_id = Id::new().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // The last party never joins, so the others time out while
+        // initializing the communicator.
+        let sync_task = |i| {
+            let my_state = some_state();
+            move || {
+                let device = CudaDevice::new(i).unwrap();
+                let started = Instant::now();
+                let result =
+                    NcclStateExchange::new(device, i, n_parties, net_id, timeout).sync(&my_state);
+                (result, started.elapsed())
+            }
+        };
+
+        let mut tasks = JoinSet::new();
+        for i in 0..n_parties - 1 {
+            tasks.spawn_blocking(sync_task(i));
+        }
+
+        let mut timed_out = 0;
+        while let Some(result) = tasks.join_next().await {
+            let (result, elapsed) = result?;
+            let error = result.unwrap_err();
+            let sync_timeout = error.downcast_ref::<SyncTimeout>().unwrap();
+            assert!(sync_timeout.party_id < n_parties - 1);
+            assert!(sync_timeout.elapsed >= timeout);
+            assert!(elapsed < timeout * 2);
+            timed_out += 1;
+        }
+        assert_eq!(timed_out, n_parties - 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_state_exchange() -> Result<()> {
+        let n_parties = 3.min(CudaDevice::count()? as usize);
+        let net_id = Id::new().unwrap();
+
+        let mut tasks = JoinSet::new();
+        for i in 0..n_parties {
+            tasks.spawn_blocking(move || {
+                let device = CudaDevice::new(i).unwrap();
+                NcclStateExchange::new(device, i, n_parties, net_id, Duration::from_secs(60))
+                    .sync(&some_state())
+                    .unwrap()
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            assert_eq!(result?.must_rollback_storage(), None);
         }
+        Ok(())
+    }
+
+    #[test]
+    fn test_threshold_state_fits() -> Result<()> {
+        let state = full_threshold_state();
+        let state_ser = bincode::serialize(&state)?;
+        assert_eq!(state_ser.len(), THRESHOLD_SYNC_LEN);
+        assert_eq!(
+            bincode::deserialize::<ThresholdSyncState>(&state_ser)?,
+            state
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_share_audit_fits() -> Result<()> {
+        let contribution = ShareAuditContribution {
+            party_id:   2,
+            serial_ids: vec![1, 5, 9],
+            values:     vec![[u16::MAX; SHARE_AUDIT_CHECKS]; 3],
+        };
+        let contribution_ser = bincode::serialize(&contribution)?;
+        assert_eq!(contribution_ser.len(), share_audit_len(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_sync_fits() -> Result<()> {
+        let candidate = BatchCandidate {
+            key:            u64::MAX,
+            mirrored_check: true,
+            lane:           u8::MAX,
+            error:          Some(u16::MAX),
+            shed:           true,
+            or_rule:        Some(u64::MAX),
+        };
+        let mut state = BatchSyncState::new(vec![candidate; 3]);
+        state.batch_size_limit = Some(usize::MAX);
+        state.purge_due = vec![u32::MAX; MAX_PURGES_PER_BATCH];
+        state.deletion_batches = vec![u64::MAX; MAX_DELETION_BATCHES];
+        let state_ser = bincode::serialize(&state)?;
+        assert_eq!(state_ser.len(), batch_sync_len(3));
+        assert_eq!(bincode::deserialize::<BatchSyncState>(&state_ser)?, state);
+        Ok(())
+    }
+
+    fn some_config() -> CommonConfig {
+        CommonConfig {
+            match_threshold_ratio:     0.375,
+            iris_code_length:          12800,
+            mask_code_length:          12800,
+            max_batch_size:            64,
+            max_db_size:               1000,
+            interactive_min_share:     0.5,
+            return_partial_results:    false,
+            enable_mirrored_checks:    false,
+            max_rotation_window:       15,
+            disable_persistence:       false,
+            replay_window_secs:        86400,
+            result_mode:               ResultMode::FullOpen,
+            reveal_matched_serial_ids: false,
+            match_policy:              MatchPolicy::And,
+            warmup_rounds:             1,
+            share_audit_sample_size:   64,
+            schema_version:            1,
+            device_count:              8,
+        }
+    }
+
+    fn full_threshold_state() -> ThresholdSyncState {
+        let params = ThresholdParams {
+            version:               u32::MAX,
+            match_threshold_ratio: 0.375,
+        };
+        ThresholdSyncState {
+            batch:   u64::MAX,
+            active:  params,
+            pending: vec![
+                ScheduledThreshold {
+                    activation_batch: u64::MAX,
+                    params,
+                };
+                MAX_PENDING_THRESHOLDS
+            ],
+        }
+    }
+
+    fn some_state() -> SyncState {
+        SyncState::new(
+            123,
+            vec!["abc".to_string(), "def".to_string()],
+            &some_config(),
+        )
     }
 }
@@ -3,10 +3,7 @@
 #define U64 unsigned long long
 #define TYPE U64
 
-#define MATCH_THRESHOLD_RATIO 0.375
 #define B_BITS 16
-#define B (1ULL << B_BITS)
-#define A ((U64)((1. - 2. * MATCH_THRESHOLD_RATIO) * (double)B))
 
 ////////////////////////////////////////////////////////////////////////////////
 // Basic Blocks (not parallelized)
@@ -164,14 +161,15 @@ __device__ void u32_transpose_pack_u64(U64 *out_a, U64 *out_b, U32 *in_a,
   }
 }
 
+// a = (1 - 2 * MATCH_THRESHOLD_RATIO) * 2^B_BITS, set from the host
 __device__ void lift_mul_sub(U32 *mask, U16 *mask_corr1, U16 *mask_corr2,
-                             U16 *code) {
+                             U16 *code, U32 a) {
   *mask -= (U32)(*mask_corr1) << 16;
   *mask -= (U32)(*mask_corr2) << 17;
 
   U32 lifted;
   mul_lift_b(&lifted, code);
-  *mask *= A;
+  *mask *= a;
   *mask -= lifted;
 }
 
@@ -323,11 +321,14 @@ extern "C" __global__ void lift_split(U16 *in_a, U16 *in_b, U32 *lifted_a,
 extern "C" __global__ void shared_lift_mul_sub(U32 *mask_a, U32 *mask_b,
                                                U16 *mask_corr_a,
                                                U16 *mask_corr_b, U16 *code_a,
-                                               U16 *code_b, int id, size_t n) {
+                                               U16 *code_b, U32 a, int id,
+                                               size_t n) {
   size_t i = blockIdx.x * blockDim.x + threadIdx.x;
   if (i < n) {
-    lift_mul_sub(&mask_a[i], &mask_corr_a[i], &mask_corr_a[i + n], &code_a[i]);
-    lift_mul_sub(&mask_b[i], &mask_corr_b[i], &mask_corr_b[i + n], &code_b[i]);
+    lift_mul_sub(&mask_a[i], &mask_corr_a[i], &mask_corr_a[i + n], &code_a[i],
+                 a);
+    lift_mul_sub(&mask_b[i], &mask_corr_b[i], &mask_corr_b[i + n], &code_b[i],
+                 a);
     switch (id) {
     case 0:
       mask_a[i] -= 1; // Transforms the <= into <
@@ -425,3 +426,22 @@ extern "C" __global__ void collapse_u64_helper(U64 *inout_a, U64 *in_b,
     *inout_a = res_a & mask;
   }
 }
+
+extern "C" __global__ void popcount_ranges(U16 *out_a, U16 *out_b, U16 *in_a,
+                                           U16 *in_b, size_t range_len,
+                                           size_t valid_len, size_t n) {
+  // in holds the injected bits, out one sum per range of range_len bits of
+  // which the first valid_len are counted. Sums are local since the sharing
+  // is additive.
+  size_t i = blockIdx.x * blockDim.x + threadIdx.x;
+  if (i < n) {
+    U16 sum_a = 0;
+    U16 sum_b = 0;
+    for (size_t j = 0; j < valid_len; j++) {
+      sum_a += in_a[i * range_len + j];
+      sum_b += in_b[i * range_len + j];
+    }
+    out_a[i] = sum_a;
+    out_b[i] = sum_b;
+  }
+}
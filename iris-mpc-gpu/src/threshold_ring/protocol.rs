@@ -1,23 +1,34 @@
 use crate::{
     helpers::{
-        comm::NcclComm, device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync,
-        launch_config_from_elements_and_threads, DEFAULT_LAUNCH_CONFIG_THREADS,
+        comm::NcclComm, device_manager::DeviceManager, dtoh_into_on_stream_sync,
+        dtoh_on_stream_sync, htod_on_stream_sync, launch_config_from_elements_and_threads,
+        DEFAULT_LAUNCH_CONFIG_THREADS,
     },
     rng::chacha_corr::ChaChaCudaCorrRng,
     threshold_ring::cuda::PTX_SRC,
 };
 use cudarc::{
     driver::{
-        result::stream, CudaDevice, CudaFunction, CudaSlice, CudaStream, CudaView, CudaViewMut,
-        DeviceSlice, LaunchAsync,
+        result::{event, stream},
+        sys::{CUevent, CUevent_flags, CUevent_wait_flags},
+        CudaDevice, CudaFunction, CudaSlice, CudaStream, CudaView, CudaViewMut, DeviceSlice,
+        LaunchAsync,
     },
     nccl::result,
     nvrtc::{self, Ptx},
 };
+use iris_mpc_common::{
+    helpers::threshold::{MatchThreshold, ThresholdParams},
+    id::PartyID,
+};
 use itertools::{izip, Itertools};
-use std::{ops::Range, sync::Arc};
+use std::{
+    ops::{Deref, DerefMut, Range},
+    sync::Arc,
+};
+use thiserror::Error;
 
-pub(crate) const B_BITS: usize = 16;
+pub(crate) const B_BITS: usize = MatchThreshold::B_BITS as usize;
 const SHARE_RING_BITSIZE: usize = 16;
 
 pub struct ChunkShare<T> {
@@ -139,6 +150,7 @@ struct Kernels {
     pub(crate) ot_helper:             CudaFunction,
     pub(crate) assign:                CudaFunction,
     pub(crate) collapse_u64_helper:   CudaFunction,
+    pub(crate) popcount_ranges:       CudaFunction,
 }
 
 impl Kernels {
@@ -162,6 +174,7 @@ impl Kernels {
             "packed_ot_helper",
             "shared_assign",
             "collapse_u64_helper",
+            "popcount_ranges",
         ])
         .unwrap();
         let and = dev.get_func(Self::MOD_NAME, "shared_and_pre").unwrap();
@@ -186,6 +199,7 @@ impl Kernels {
         let ot_helper = dev.get_func(Self::MOD_NAME, "packed_ot_helper").unwrap();
         let assign = dev.get_func(Self::MOD_NAME, "shared_assign").unwrap();
         let collapse_u64_helper = dev.get_func(Self::MOD_NAME, "collapse_u64_helper").unwrap();
+        let popcount_ranges = dev.get_func(Self::MOD_NAME, "popcount_ranges").unwrap();
 
         Kernels {
             and,
@@ -204,6 +218,7 @@ impl Kernels {
             ot_helper,
             assign,
             collapse_u64_helper,
+            popcount_ranges,
         }
     }
 }
@@ -331,17 +346,101 @@ impl Buffers {
     }
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ResultBufferError {
+    #[error("the result buffer has already been taken")]
+    AlreadyTaken,
+    #[error("the result buffer of the previous comparison has not been returned")]
+    NotReturned,
+    #[error("the returned result buffer has shape {got:?} instead of {expected:?}")]
+    WrongShape {
+        expected: Vec<(usize, usize)>,
+        got:      Vec<(usize, usize)>,
+    },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StreamedOpenError {
+    #[error("the chunk size must not be 0")]
+    ZeroChunk,
+    #[error(
+        "party {peer} opens with chunk size, elements and devices {peer_params:?} instead of \
+         {own:?}"
+    )]
+    ParamsMismatch {
+        peer:        usize,
+        own:         Vec<u64>,
+        peer_params: Vec<u64>,
+    },
+}
+
+/// Where a chunk opened by [`Circuits::open_results_streamed`] belongs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkMeta {
+    /// Position of the chunk in the order the chunks were opened.
+    pub index:  usize,
+    pub device: usize,
+    /// Offset of the chunk in the result of the device, in u64 elements.
+    pub offset: usize,
+}
+
+/// The result buffer of [`Circuits`], taken with [`Circuits::results`]. It has
+/// to be handed back with [`Circuits::return_results`] or
+/// [`Circuits::discard_results`] before the next comparison can run.
+#[must_use = "the result buffer has to be returned to the circuits"]
+pub struct ResultGuard {
+    buffer: Vec<ChunkShare<u64>>,
+    shape:  Vec<(usize, usize)>,
+}
+
+impl ResultGuard {
+    fn shape_of(buffer: &[ChunkShare<u64>]) -> Vec<(usize, usize)> {
+        buffer.iter().map(|x| (x.a.len(), x.b.len())).collect()
+    }
+
+    fn into_checked_buffer(self) -> Result<Vec<ChunkShare<u64>>, ResultBufferError> {
+        let got = Self::shape_of(&self.buffer);
+        if got != self.shape {
+            return Err(ResultBufferError::WrongShape {
+                expected: self.shape,
+                got,
+            });
+        }
+        Ok(self.buffer)
+    }
+}
+
+impl Deref for ResultGuard {
+    type Target = Vec<ChunkShare<u64>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl DerefMut for ResultGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+
 pub struct Circuits {
-    peer_id:    usize,
-    next_id:    usize,
-    prev_id:    usize,
-    chunk_size: usize,
-    n_devices:  usize,
-    devs:       Vec<Arc<CudaDevice>>,
-    comms:      Vec<Arc<NcclComm>>,
-    kernels:    Vec<Kernels>,
-    buffers:    Buffers,
-    rngs:       Vec<ChaChaCudaCorrRng>,
+    peer_id:          usize,
+    next_id:          usize,
+    prev_id:          usize,
+    chunk_size:       usize,
+    n_devices:        usize,
+    devs:             Vec<Arc<CudaDevice>>,
+    comms:            Vec<Arc<NcclComm>>,
+    kernels:          Vec<Kernels>,
+    buffers:          Buffers,
+    rngs:             Vec<ChaChaCudaCorrRng>,
+    // Recorded when the results are returned, the next comparison waits on them
+    // before overwriting the result buffer.
+    results_events:   Vec<CUevent>,
+    results_returned: bool,
+    // The factor of the mask dot product in the threshold comparison.
+    threshold_a:      u32,
 }
 
 impl Circuits {
@@ -368,7 +467,9 @@ impl Circuits {
         chacha_seeds: ([u32; 8], [u32; 8]),
         device_manager: Arc<DeviceManager>,
         comms: Vec<Arc<NcclComm>>,
+        threshold: MatchThreshold,
     ) -> Self {
+        let party_id = PartyID::try_from(peer_id).expect("party id is 0, 1 or 2");
         // For the transpose, inputs should be multiple of 64 bits
         assert!(input_size % 64 == 0);
         // Chunk size is the number of u64 elements per bit in the binary circuits
@@ -393,10 +494,18 @@ impl Circuits {
 
         let buffers = Buffers::new(&devs, alloc_size);
 
+        let results_events = devs
+            .iter()
+            .map(|dev| {
+                dev.bind_to_thread().unwrap();
+                event::create(CUevent_flags::CU_EVENT_DISABLE_TIMING).unwrap()
+            })
+            .collect();
+
         Circuits {
             peer_id,
-            next_id: (peer_id + 1) % 3,
-            prev_id: (peer_id + 2) % 3,
+            next_id: party_id.next_id().into(),
+            prev_id: party_id.prev_id().into(),
             chunk_size,
             n_devices,
             devs,
@@ -404,9 +513,18 @@ impl Circuits {
             kernels,
             buffers,
             rngs,
+            results_events,
+            results_returned: false,
+            threshold_a: threshold.a() as u32,
         }
     }
 
+    /// Sets the threshold for the following comparisons. Callers have to make
+    /// sure that all parties switch at the same comparison.
+    pub fn set_threshold(&mut self, params: &ThresholdParams) {
+        self.threshold_a = params.a() as u32;
+    }
+
     // TODO: have different chunk sizes for each gpu
     pub fn set_chunk_size(&mut self, chunk_size: usize) {
         assert!(chunk_size <= self.buffers.chunk_size);
@@ -418,12 +536,185 @@ impl Circuits {
         self.chunk_size
     }
 
-    pub fn take_result_buffer(&mut self) -> Vec<ChunkShare<u64>> {
-        Buffers::take_buffer(&mut self.buffers.lifted_shares_split1_result)
+    /// Takes the result buffer of the last comparison. The result is in the
+    /// first bit.
+    pub fn results(&mut self) -> Result<ResultGuard, ResultBufferError> {
+        let buffer = self
+            .buffers
+            .lifted_shares_split1_result
+            .take()
+            .ok_or(ResultBufferError::AlreadyTaken)?;
+        Ok(ResultGuard {
+            shape: ResultGuard::shape_of(&buffer),
+            buffer,
+        })
+    }
+
+    /// Hands back the result buffer once all work reading it has been issued on
+    /// `streams`. The next comparison waits for that work before overwriting
+    /// the buffer.
+    pub fn return_results(
+        &mut self,
+        results: ResultGuard,
+        streams: &[CudaStream],
+    ) -> Result<(), ResultBufferError> {
+        let buffer = results.into_checked_buffer()?;
+        for (dev, stream, evt) in izip!(&self.devs, streams, &self.results_events) {
+            dev.bind_to_thread().unwrap();
+            unsafe { event::record(*evt, stream.stream).unwrap() };
+        }
+        self.results_returned = true;
+        Buffers::return_buffer(&mut self.buffers.lifted_shares_split1_result, buffer);
+        Ok(())
     }
 
-    pub fn return_result_buffer(&mut self, src: Vec<ChunkShare<u64>>) {
-        Buffers::return_buffer(&mut self.buffers.lifted_shares_split1_result, src);
+    /// Hands back a result buffer that has not been used on the device.
+    pub fn discard_results(&mut self, results: ResultGuard) -> Result<(), ResultBufferError> {
+        let buffer = results.into_checked_buffer()?;
+        Buffers::return_buffer(&mut self.buffers.lifted_shares_split1_result, buffer);
+        Ok(())
+    }
+
+    /// Makes sure the result buffer can be overwritten on `streams`.
+    fn claim_results(&mut self, streams: &[CudaStream]) -> Result<(), ResultBufferError> {
+        if self.buffers.lifted_shares_split1_result.is_none() {
+            return Err(ResultBufferError::NotReturned);
+        }
+        if self.results_returned {
+            for (dev, stream, evt) in izip!(&self.devs, streams, &self.results_events) {
+                dev.bind_to_thread().unwrap();
+                unsafe {
+                    stream::wait_event(
+                        stream.stream,
+                        *evt,
+                        CUevent_wait_flags::CU_EVENT_WAIT_DEFAULT,
+                    )
+                    .unwrap();
+                }
+            }
+            self.results_returned = false;
+        }
+        Ok(())
+    }
+
+    /// Opens the result of the last comparison, which is in the first bit of
+    /// `res`, in chunks of at most `chunk_elems` u64 elements per device. Each
+    /// opened chunk is handed to `sink` and then overwritten, so the memory
+    /// used on the host and the devices is bounded by the chunk size, not by
+    /// the size of the database.
+    ///
+    /// The chunks are exchanged one at a time, all devices at once, in the
+    /// same order at all parties. The parties check beforehand that they use
+    /// the same chunking, so that no chunk is ever matched with another one.
+    pub fn open_results_streamed(
+        &mut self,
+        res: &[ChunkShare<u64>],
+        chunk_elems: usize,
+        streams: &[CudaStream],
+        mut sink: impl FnMut(ChunkMeta, &[u64]),
+    ) -> Result<(), StreamedOpenError> {
+        if chunk_elems == 0 {
+            return Err(StreamedOpenError::ZeroChunk);
+        }
+        assert_eq!(res.len(), self.n_devices);
+        assert!(res.iter().all(|res| res.len() >= self.chunk_size));
+        self.sync_stream_params(chunk_elems, streams)?;
+
+        let len = self.chunk_size;
+        let chunk_elems = chunk_elems.min(len);
+        let staging = self
+            .devs
+            .iter()
+            .map(|dev| unsafe { dev.alloc::<u64>(chunk_elems) }.unwrap())
+            .collect_vec();
+        let mut host_a = vec![0u64; chunk_elems];
+        let mut host_b = vec![0u64; chunk_elems];
+        let mut host_c = vec![0u64; chunk_elems];
+
+        let mut index = 0;
+        for offset in (0..len).step_by(chunk_elems) {
+            let end = (offset + chunk_elems).min(len);
+            let n = end - offset;
+
+            result::group_start().unwrap();
+            for (idx, res) in res.iter().enumerate() {
+                self.comms[idx]
+                    .send_view(&res.b.slice(offset..end), self.next_id, &streams[idx])
+                    .unwrap();
+            }
+            for (idx, staging) in staging.iter().enumerate() {
+                let mut rcv = staging.slice(0..n);
+                self.comms[idx]
+                    .receive_view(&mut rcv, self.prev_id, &streams[idx])
+                    .unwrap();
+            }
+            result::group_end().unwrap();
+
+            for (idx, (dev, res, staging)) in izip!(&self.devs, res, &staging).enumerate() {
+                let stream = &streams[idx];
+                dtoh_into_on_stream_sync(&res.a.slice(offset..end), &mut host_a[..n], dev, stream)
+                    .unwrap();
+                dtoh_into_on_stream_sync(&res.b.slice(offset..end), &mut host_b[..n], dev, stream)
+                    .unwrap();
+                dtoh_into_on_stream_sync(&staging.slice(0..n), &mut host_c[..n], dev, stream)
+                    .unwrap();
+                for (a, b, c) in izip!(&mut host_a[..n], &host_b[..n], &host_c[..n]) {
+                    *a ^= b ^ c;
+                }
+                sink(
+                    ChunkMeta {
+                        index,
+                        device: idx,
+                        offset,
+                    },
+                    &host_a[..n],
+                );
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Exchanges the chunking of [`Self::open_results_streamed`] with both
+    /// other parties, so that all of them see every mismatch and none starts
+    /// exchanging chunks.
+    fn sync_stream_params(
+        &mut self,
+        chunk_elems: usize,
+        streams: &[CudaStream],
+    ) -> Result<(), StreamedOpenError> {
+        let own = [
+            chunk_elems as u64,
+            self.chunk_size as u64,
+            self.n_devices as u64,
+        ];
+        let dev = &self.devs[0];
+        let send = htod_on_stream_sync(&own, dev, &streams[0]).unwrap();
+        let mut from_next = unsafe { dev.alloc::<u64>(own.len()) }.unwrap();
+        let mut from_prev = unsafe { dev.alloc::<u64>(own.len()) }.unwrap();
+        result::group_start().unwrap();
+        for peer in [self.next_id, self.prev_id] {
+            self.comms[0].send(&send, peer, &streams[0]).unwrap();
+        }
+        self.comms[0]
+            .receive(&mut from_next, self.next_id, &streams[0])
+            .unwrap();
+        self.comms[0]
+            .receive(&mut from_prev, self.prev_id, &streams[0])
+            .unwrap();
+        result::group_end().unwrap();
+
+        for (peer, params) in [(self.next_id, from_next), (self.prev_id, from_prev)] {
+            let params = dtoh_on_stream_sync(&params, dev, &streams[0]).unwrap();
+            if params != own {
+                return Err(StreamedOpenError::ParamsMismatch {
+                    peer,
+                    own: own.to_vec(),
+                    peer_params: params,
+                });
+            }
+        }
+        Ok(())
     }
 
     pub fn next_id(&self) -> usize {
@@ -441,6 +732,13 @@ impl Circuits {
         &self.comms
     }
 
+    /// Swaps in the communicators rebuilt after a failed collective, see
+    /// [`DeviceManager::reinit`].
+    pub fn replace_comms(&mut self, comms: Vec<Arc<NcclComm>>) {
+        assert_eq!(comms.len(), self.n_devices);
+        self.comms = comms;
+    }
+
     // Fill randomness using the correlated RNG
     fn fill_rand_u64(&mut self, rand: &mut CudaSlice<u64>, idx: usize, streams: &[CudaStream]) {
         let rng = &mut self.rngs[idx];
@@ -1466,6 +1764,7 @@ impl Circuits {
                             &mc.b,
                             &c.a,
                             &c.b,
+                            self.threshold_a,
                             self.peer_id as u32,
                             self.chunk_size * 64,
                         ),
@@ -1620,6 +1919,7 @@ impl Circuits {
     }
 
     pub fn extract_msb(&mut self, x: &mut [ChunkShareView<u32>], streams: &[CudaStream]) {
+        self.claim_results(streams).unwrap();
         let x1_ = Buffers::take_buffer(&mut self.buffers.lifted_shares_split1_result);
         let x2_ = Buffers::take_buffer(&mut self.buffers.lifted_shares_split2);
         let x3_ = Buffers::take_buffer(&mut self.buffers.lifted_shares_split3);
@@ -1893,6 +2193,68 @@ impl Circuits {
         // Result is in the first bit of the first GPU
     }
 
+    /// Counts the set result bits of every query without opening them. The
+    /// bits of query `i` are `i * bits_per_query..(i + 1) * bits_per_query` of
+    /// the result buffer (the layout of `openResults`), of which the first
+    /// `valid_bits` are counted. `counts` receives a share over Z_{2^16} of
+    /// the count of each query, per device.
+    pub fn popcount_per_query(
+        &mut self,
+        bits_per_query: usize,
+        valid_bits: usize,
+        counts: &mut [ChunkShare<u16>],
+        streams: &[CudaStream],
+    ) -> Result<(), ResultBufferError> {
+        assert_eq!(self.n_devices, counts.len());
+        assert!(valid_bits <= bits_per_query);
+        assert!(valid_bits <= u16::MAX as usize);
+        let n_queries = self.chunk_size * 64 / bits_per_query;
+
+        let result = self.results()?;
+        // The bit injection works on twice the chunk size, the upper half is
+        // not counted.
+        let bits = result
+            .iter()
+            .map(|r| r.get_offset(0, 2 * self.chunk_size))
+            .collect::<Vec<_>>();
+        let injected_ = Buffers::take_buffer(&mut self.buffers.lifting_corrections);
+        let mut injected = Buffers::get_buffer_chunk(&injected_, 128 * self.chunk_size);
+        self.bit_inject_ot(&bits, &mut injected, streams);
+
+        for (idx, (injected, count)) in izip!(&injected, counts.iter()).enumerate() {
+            assert!(count.len() >= n_queries);
+            let cfg = launch_config_from_elements_and_threads(
+                n_queries as u32,
+                DEFAULT_LAUNCH_CONFIG_THREADS,
+                &self.devs[idx],
+            );
+            unsafe {
+                self.kernels[idx]
+                    .popcount_ranges
+                    .clone()
+                    .launch_on_stream(
+                        &streams[idx],
+                        cfg,
+                        (
+                            &count.a,
+                            &count.b,
+                            &injected.a,
+                            &injected.b,
+                            bits_per_query,
+                            valid_bits,
+                            n_queries,
+                        ),
+                    )
+                    .unwrap();
+            }
+        }
+
+        Buffers::return_buffer(&mut self.buffers.lifting_corrections, injected_);
+        self.return_results(result, streams)?;
+        self.buffers.check_buffers();
+        Ok(())
+    }
+
     // input should be of size: n_devices * input_size
     // Result is in the first bit of the result buffer
     pub fn compare_threshold_masked_many(
@@ -1900,7 +2262,8 @@ impl Circuits {
         code_dots: &[ChunkShareView<u16>],
         mask_dots: &[ChunkShareView<u16>],
         streams: &[CudaStream],
-    ) {
+    ) -> Result<(), ResultBufferError> {
+        self.claim_results(streams)?;
         assert_eq!(self.n_devices, code_dots.len());
         assert_eq!(self.n_devices, mask_dots.len());
         for chunk in code_dots.iter().chain(mask_dots.iter()) {
@@ -1921,6 +2284,7 @@ impl Circuits {
         self.buffers.check_buffers();
 
         // Result is in the first bit of the result buffer
+        Ok(())
     }
 
     // input should be of size: n_devices * input_size
@@ -1930,15 +2294,25 @@ impl Circuits {
         code_dots: &[ChunkShareView<u16>],
         mask_dots: &[ChunkShareView<u16>],
         streams: &[CudaStream],
-    ) {
-        self.compare_threshold_masked_many(code_dots, mask_dots, streams);
-        let mut result = self.take_result_buffer();
+    ) -> Result<(), ResultBufferError> {
+        self.compare_threshold_masked_many(code_dots, mask_dots, streams)?;
+        let mut result = self.results()?;
         self.or_reduce_result(&mut result, streams);
         // Result is in the first bit of the first GPU
 
-        self.return_result_buffer(result);
+        self.return_results(result, streams)?;
         self.buffers.check_buffers();
 
         // Result is in the lowest bit of the result buffer on the first gpu
+        Ok(())
+    }
+}
+
+impl Drop for Circuits {
+    fn drop(&mut self) {
+        for (dev, evt) in izip!(&self.devs, &self.results_events) {
+            dev.bind_to_thread().unwrap();
+            unsafe { event::destroy(*evt).unwrap() };
+        }
     }
 }
@@ -0,0 +1,237 @@
+#[cfg(feature = "gpu_dependent")]
+mod batch_retry_test {
+    use cudarc::nccl::Id;
+    use eyre::Result;
+    use iris_mpc_common::{
+        galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+        helpers::threshold::{ScheduledThreshold, ThresholdParams},
+        iris_db::{db::IrisDB, iris::IrisCode},
+    };
+    use iris_mpc_gpu::{
+        helpers::device_manager::DeviceManager,
+        server::{BatchQuery, BatchQueryEntriesPreprocessed, ServerActor, ServerActorHandle},
+    };
+    use itertools::Itertools;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{env, sync::Arc};
+    use tokio::{sync::oneshot, task::JoinHandle};
+    use uuid::Uuid;
+
+    const DB_SIZE: usize = 8 * 100;
+    /// Room for fewer insertions than the failing batch makes.
+    const DB_BUFFER: usize = 8;
+    const DB_RNG_SEED: u64 = 0xdeadbeef;
+    const MAX_BATCH_SIZE: usize = 64;
+
+    fn generate_db(party_id: usize) -> (Vec<u16>, Vec<u16>) {
+        let db = IrisDB::new_random_par(DB_SIZE, &mut StdRng::seed_from_u64(DB_RNG_SEED));
+        let codes = db
+            .db
+            .iter()
+            .flat_map(|iris| {
+                GaloisRingIrisCodeShare::encode_iris_code(
+                    &iris.code,
+                    &iris.mask,
+                    &mut StdRng::seed_from_u64(DB_RNG_SEED),
+                )[party_id]
+                    .coefs
+            })
+            .collect();
+        let masks = db
+            .db
+            .iter()
+            .flat_map(|iris| {
+                let mask: GaloisRingTrimmedMaskCodeShare =
+                    GaloisRingIrisCodeShare::encode_mask_code(
+                        &iris.mask,
+                        &mut StdRng::seed_from_u64(DB_RNG_SEED),
+                    )[party_id]
+                        .clone()
+                        .into();
+                mask.coefs
+            })
+            .collect();
+        (codes, masks)
+    }
+
+    fn start_actor(
+        party_id: usize,
+        device_manager: Arc<DeviceManager>,
+        ids: Vec<Id>,
+    ) -> (JoinHandle<()>, oneshot::Receiver<ServerActorHandle>) {
+        let (tx, rx) = oneshot::channel();
+        let task = tokio::task::spawn_blocking(move || {
+            let db = generate_db(party_id);
+            let comms = device_manager
+                .instantiate_network_from_ids(party_id, &ids)
+                .unwrap();
+            let seeds = ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]);
+            let (mut actor, handle) = ServerActor::new_with_device_manager_and_comms(
+                party_id,
+                seeds,
+                device_manager,
+                comms,
+                8,
+                DB_SIZE + DB_BUFFER,
+                MAX_BATCH_SIZE,
+                true,
+                false,
+            )
+            .unwrap();
+            actor.load_full_db(&(&db.0, &db.1), &(&db.0, &db.1), DB_SIZE);
+            actor.register_host_memory();
+            tx.send(handle).unwrap();
+            actor.run();
+        });
+        (task, rx)
+    }
+
+    /// The batches of the three parties, for fresh irises that match nothing,
+    /// together with `deletions` and `threshold_updates`.
+    fn batches(
+        n_irises: usize,
+        deletions: &[u32],
+        threshold_updates: &[ScheduledThreshold],
+        rng: &mut StdRng,
+    ) -> Vec<BatchQuery> {
+        let mut batches = vec![
+            BatchQuery::default(),
+            BatchQuery::default(),
+            BatchQuery::default(),
+        ];
+        for _ in 0..n_irises {
+            let request_id = Uuid::new_v4().to_string();
+            let template = IrisCode::random_rng(rng);
+            let mut codes =
+                GaloisRingIrisCodeShare::encode_iris_code(&template.code, &template.mask, rng);
+            let mut masks: Vec<GaloisRingTrimmedMaskCodeShare> =
+                GaloisRingIrisCodeShare::encode_mask_code(&template.mask, rng)
+                    .iter()
+                    .map(|mask| mask.clone().into())
+                    .collect();
+            for (i, batch) in batches.iter_mut().enumerate() {
+                batch.metadata.push(Default::default());
+                batch.valid_entries.push(true);
+                batch.request_ids.push(request_id.clone());
+                batch.store_left.code.push(codes[i].clone());
+                batch.store_left.mask.push(masks[i].clone());
+                batch.db_left.code.extend(codes[i].all_rotations());
+                batch.db_left.mask.extend(masks[i].all_rotations());
+                GaloisRingIrisCodeShare::preprocess_iris_code_query_share(&mut codes[i]);
+                GaloisRingTrimmedMaskCodeShare::preprocess_mask_code_query_share(&mut masks[i]);
+                batch.query_left.code.extend(codes[i].all_rotations());
+                batch.query_left.mask.extend(masks[i].all_rotations());
+            }
+        }
+        for batch in batches.iter_mut() {
+            batch.deletion_requests_indices = deletions.to_vec();
+            batch.threshold_updates = threshold_updates.to_vec();
+            batch.db_right = batch.db_left.clone();
+            batch.query_right = batch.query_left.clone();
+            batch.store_right = batch.store_left.clone();
+            batch.query_left_preprocessed =
+                BatchQueryEntriesPreprocessed::from(batch.query_left.clone());
+            batch.query_right_preprocessed =
+                BatchQueryEntriesPreprocessed::from(batch.query_right.clone());
+            batch.db_left_preprocessed = BatchQueryEntriesPreprocessed::from(batch.db_left.clone());
+            batch.db_right_preprocessed =
+                BatchQueryEntriesPreprocessed::from(batch.db_right.clone());
+        }
+        batches
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_failure_midway() -> Result<()> {
+        env::set_var("NCCL_P2P_LEVEL", "LOC");
+        env::set_var("NCCL_NET", "Socket");
+
+        let device_managers = DeviceManager::init()
+            .split_into_n_chunks(3)
+            .expect("have at least 3 devices")
+            .into_iter()
+            .map(Arc::new)
+            .collect_vec();
+        let n_devices = device_managers[0].device_count();
+        let ids = (0..n_devices).map(|_| Id::new().unwrap()).collect_vec();
+        let mut tasks = vec![];
+        let mut handles = vec![];
+        for (party_id, device_manager) in device_managers.into_iter().enumerate() {
+            let (task, handle) = start_actor(party_id, device_manager, ids.clone());
+            tasks.push(task);
+            handles.push(handle);
+        }
+        let mut handles = futures::future::try_join_all(handles).await?;
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let deletions = [3, 17];
+        let update = ScheduledThreshold {
+            activation_batch: 1,
+            params:           ThresholdParams {
+                version: 1,
+                ..Default::default()
+            },
+        };
+
+        // More insertions than the database has room for. The parties fail
+        // after they scheduled the threshold update, bumped the batch number,
+        // applied the deletions and synced the insertion digest.
+        let mut results = vec![];
+        for (handle, batch) in
+            handles
+                .iter_mut()
+                .zip(batches(2 * DB_BUFFER, &deletions, &[update], &mut rng))
+        {
+            results.push(handle.try_submit_batch_query(batch).await);
+        }
+        for result in futures::future::join_all(results).await {
+            assert!(result.is_err());
+        }
+
+        // As the server does after a failed batch.
+        let mut reinits = vec![];
+        for handle in handles.iter_mut() {
+            reinits.push(handle.reinit_network().await);
+        }
+        for generation in futures::future::try_join_all(reinits).await? {
+            assert_eq!(generation, 1);
+        }
+
+        // The batch runs again as if the failed attempt never happened: it is
+        // batch 0, before the threshold update activates, and the deletions
+        // find the entries they delete.
+        let mut results = vec![];
+        for (handle, batch) in
+            handles
+                .iter_mut()
+                .zip(batches(DB_BUFFER / 2, &deletions, &[update], &mut rng))
+        {
+            results.push(handle.try_submit_batch_query(batch).await);
+        }
+        let results = futures::future::try_join_all(results).await?;
+        for result in &results {
+            assert_eq!(result.threshold_version, 0);
+            assert_eq!(result.deleted_ids, deletions);
+            assert!(result.unknown_deletion_ids.is_empty());
+            assert_eq!(result.db_digest_before, [0; 32]);
+            assert_eq!(result.matches, vec![false; DB_BUFFER / 2]);
+            assert_eq!(result.merged_results, results[0].merged_results);
+            assert_eq!(result.db_digest_after, results[0].db_digest_after);
+        }
+
+        // The next batch is batch 1.
+        let mut next_results = vec![];
+        for (handle, batch) in handles.iter_mut().zip(batches(1, &[], &[], &mut rng)) {
+            next_results.push(handle.try_submit_batch_query(batch).await);
+        }
+        for result in futures::future::try_join_all(next_results).await? {
+            assert_eq!(result.threshold_version, 1);
+            assert_eq!(result.db_digest_before, results[0].db_digest_after);
+        }
+
+        drop(handles);
+        for task in tasks {
+            task.await?;
+        }
+        Ok(())
+    }
+}
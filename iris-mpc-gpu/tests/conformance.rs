@@ -0,0 +1,194 @@
+#[cfg(feature = "gpu_dependent")]
+mod conformance_test {
+    use cudarc::{
+        driver::{CudaDevice, CudaStream},
+        nccl::Id,
+    };
+    use iris_mpc_common::helpers::conformance::{ConformanceFixtures, ConformanceSet};
+    use iris_mpc_gpu::{
+        helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
+        server::seed_exchange,
+        threshold_ring::protocol::{ChunkShare, Circuits},
+    };
+    use itertools::{izip, Itertools};
+    use std::{env, sync::Arc, thread};
+
+    /// Inputs per device are a multiple of this, see the threshold test.
+    const INPUT_ALIGNMENT: usize = 2048;
+
+    /// Uploads the shares, padded with zero shares to `inputs_per_gpu` per
+    /// device.
+    fn to_gpu(
+        a: &[u16],
+        b: &[u16],
+        inputs_per_gpu: usize,
+        devices: &[Arc<CudaDevice>],
+        streams: &[CudaStream],
+    ) -> Vec<ChunkShare<u16>> {
+        let padded = |x: &[u16]| {
+            let mut x = x.to_vec();
+            x.resize(inputs_per_gpu * devices.len(), 0);
+            x
+        };
+        let (a, b) = (padded(a), padded(b));
+
+        izip!(
+            devices,
+            streams,
+            a.chunks(inputs_per_gpu),
+            b.chunks(inputs_per_gpu)
+        )
+        .map(|(dev, stream, a, b)| {
+            let a = htod_on_stream_sync(a, dev, stream).unwrap();
+            let b = htod_on_stream_sync(b, dev, stream).unwrap();
+            ChunkShare::new(a, b)
+        })
+        .collect()
+    }
+
+    fn open(
+        party: &mut Circuits,
+        x: &[ChunkShare<u64>],
+        chunk_size: usize,
+        streams: &[CudaStream],
+    ) -> Vec<u64> {
+        let n_devices = x.len();
+        let mut a = Vec::with_capacity(n_devices);
+        let mut b = Vec::with_capacity(n_devices);
+        let mut c = Vec::with_capacity(n_devices);
+
+        cudarc::nccl::result::group_start().unwrap();
+        for (idx, res) in x.iter().enumerate() {
+            // Result is in bit 0
+            let res = res.get_offset(0, chunk_size);
+            party.comms()[idx]
+                .send_view(&res.b, party.next_id(), &streams[idx])
+                .unwrap();
+            a.push(res.a);
+            b.push(res.b);
+        }
+        for (idx, res) in x.iter().enumerate() {
+            let mut res = res.get_offset(1, chunk_size);
+            party.comms()[idx]
+                .receive_view(&mut res.a, party.prev_id(), &streams[idx])
+                .unwrap();
+            c.push(res.a);
+        }
+        cudarc::nccl::result::group_end().unwrap();
+
+        let mut result = Vec::with_capacity(n_devices * chunk_size);
+        let devices = party.get_devices();
+        for (dev, stream, a, b, c) in izip!(devices, streams, a, b, c) {
+            let mut a = dtoh_on_stream_sync(&a, &dev, stream).unwrap();
+            let b = dtoh_on_stream_sync(&b, &dev, stream).unwrap();
+            let c = dtoh_on_stream_sync(&c, &dev, stream).unwrap();
+            for (a, b, c) in izip!(a.iter_mut(), b, c) {
+                *a ^= b ^ c;
+            }
+            result.extend(a);
+        }
+        result
+    }
+
+    /// Compares the lanes of the set as the given party and returns the opened
+    /// decisions, including the padding lanes.
+    fn run_set(
+        party_id: usize,
+        set: &ConformanceSet,
+        device_manager: Arc<DeviceManager>,
+        ids: &[Id],
+    ) -> Vec<bool> {
+        let n_devices = device_manager.device_count();
+        let inputs_per_gpu = set.len().div_ceil(n_devices * INPUT_ALIGNMENT) * INPUT_ALIGNMENT;
+        let chunk_size = inputs_per_gpu / 64;
+
+        let comms = device_manager
+            .instantiate_network_from_ids(party_id, ids)
+            .unwrap();
+        let mut party = Circuits::new(
+            party_id,
+            inputs_per_gpu,
+            chunk_size,
+            seed_exchange::insecure_deterministic_seeds(party_id),
+            device_manager,
+            comms,
+            set.threshold,
+        );
+        let devices = party.get_devices();
+        let streams = devices
+            .iter()
+            .map(|dev| dev.fork_default_stream().unwrap())
+            .collect_vec();
+
+        let shares = &set.shares[party_id];
+        let code = to_gpu(
+            &shares.code_a,
+            &shares.code_b,
+            inputs_per_gpu,
+            &devices,
+            &streams,
+        );
+        let mask = to_gpu(
+            &shares.mask_a,
+            &shares.mask_b,
+            inputs_per_gpu,
+            &devices,
+            &streams,
+        );
+        let code = code.iter().map(|x| x.as_view()).collect_vec();
+        let mask = mask.iter().map(|x| x.as_view()).collect_vec();
+
+        party
+            .compare_threshold_masked_many(&code, &mask, &streams)
+            .unwrap();
+        party.synchronize_streams(&streams);
+        let res = party.results().unwrap();
+        let words = open(&mut party, &res, chunk_size, &streams);
+        party.return_results(res, &streams).unwrap();
+
+        // The words of a device cover exactly its inputs, so the lanes follow
+        // each other across devices.
+        words
+            .iter()
+            .flat_map(|word| (0..64).map(move |i| (word >> i) & 1 == 1))
+            .collect()
+    }
+
+    /// Runs the golden vectors shared with the CPU protocol, see
+    /// `test_threshold_conformance` in `iris-mpc-cpu`, with the three parties
+    /// on disjoint devices of this host.
+    #[test]
+    fn test_threshold_conformance() {
+        env::set_var("NCCL_P2P_LEVEL", "LOC");
+        env::set_var("NCCL_NET", "Socket");
+
+        let fixtures = ConformanceFixtures::load().unwrap();
+        let device_managers = DeviceManager::init()
+            .split_into_n_chunks(3)
+            .expect("have at least 3 devices")
+            .into_iter()
+            .map(Arc::new)
+            .collect_vec();
+        let n_devices = device_managers[0].device_count();
+
+        for set in fixtures.sets {
+            let set = Arc::new(set);
+            let ids = (0..n_devices).map(|_| Id::new().unwrap()).collect_vec();
+            let parties = device_managers
+                .iter()
+                .enumerate()
+                .map(|(party_id, device_manager)| {
+                    let (set, device_manager, ids) =
+                        (set.clone(), device_manager.clone(), ids.clone());
+                    thread::spawn(move || run_set(party_id, &set, device_manager, &ids))
+                })
+                .collect_vec();
+            for (party_id, party) in parties.into_iter().enumerate() {
+                let decisions = party.join().unwrap();
+                if let Err(e) = set.check(&decisions) {
+                    panic!("party {}: {}", party_id, e);
+                }
+            }
+        }
+    }
+}
@@ -14,7 +14,12 @@ mod e2e_test {
         server::{BatchQuery, BatchQueryEntriesPreprocessed, ServerActor, ServerJobResult},
     };
     use rand::{rngs::StdRng, Rng, SeedableRng};
-    use std::{collections::HashMap, env, sync::Arc};
+    use std::{
+        collections::HashMap,
+        env,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
     use tokio::sync::oneshot;
     use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
     use uuid::Uuid;
@@ -26,6 +31,10 @@ mod e2e_test {
     const NUM_BATCHES: usize = 10;
     const MAX_BATCH_SIZE: usize = 64;
     const MAX_DELETIONS_PER_BATCH: usize = 10;
+    const WARMUP_ROUNDS: usize = 2;
+    /// How much slower the first batch after the warmup may be than the
+    /// median batch.
+    const WARMUP_LATENCY_TOLERANCE: f64 = 2.0;
     const THRESHOLD_ABSOLUTE: usize = 4800; // 0.375 * 12800
 
     fn generate_db(party_id: usize) -> Result<(Vec<u16>, Vec<u16>)> {
@@ -133,6 +142,10 @@ mod e2e_test {
                 Ok((mut actor, handle)) => {
                     actor.load_full_db(&(&db0.0, &db0.1), &(&db0.0, &db0.1), DB_SIZE);
                     actor.register_host_memory();
+                    let db_sizes = actor.current_db_sizes();
+                    actor.warmup(WARMUP_ROUNDS).unwrap();
+                    assert_eq!(actor.current_db_sizes(), db_sizes);
+                    // The handle stands in for the readiness of the server.
                     tx0.send(Ok(handle)).unwrap();
                     actor
                 }
@@ -161,6 +174,10 @@ mod e2e_test {
                 Ok((mut actor, handle)) => {
                     actor.load_full_db(&(&db1.0, &db1.1), &(&db1.0, &db1.1), DB_SIZE);
                     actor.register_host_memory();
+                    let db_sizes = actor.current_db_sizes();
+                    actor.warmup(WARMUP_ROUNDS).unwrap();
+                    assert_eq!(actor.current_db_sizes(), db_sizes);
+                    // The handle stands in for the readiness of the server.
                     tx1.send(Ok(handle)).unwrap();
                     actor
                 }
@@ -189,6 +206,10 @@ mod e2e_test {
                 Ok((mut actor, handle)) => {
                     actor.load_full_db(&(&db2.0, &db2.1), &(&db2.0, &db2.1), DB_SIZE);
                     actor.register_host_memory();
+                    let db_sizes = actor.current_db_sizes();
+                    actor.warmup(WARMUP_ROUNDS).unwrap();
+                    assert_eq!(actor.current_db_sizes(), db_sizes);
+                    // The handle stands in for the readiness of the server.
                     tx2.send(Ok(handle)).unwrap();
                     actor
                 }
@@ -221,6 +242,7 @@ mod e2e_test {
         let mut deleted_indices: HashSet<u32> = HashSet::new();
         let mut disallowed_queries = Vec::new();
 
+        let mut batch_durations: Vec<Duration> = vec![];
         for _ in 0..NUM_BATCHES {
             let mut batch0 = BatchQuery::default();
             let mut batch1 = BatchQuery::default();
@@ -459,6 +481,7 @@ mod e2e_test {
             preprocess_batch(&mut batch2)?;
 
             // send batches to servers
+            let now = Instant::now();
             let res0_fut = handle0.submit_batch_query(batch0).await;
             let res1_fut = handle1.submit_batch_query(batch1).await;
             let res2_fut = handle2.submit_batch_query(batch2).await;
@@ -466,6 +489,7 @@ mod e2e_test {
             let res0 = res0_fut.await;
             let res1 = res1_fut.await;
             let res2 = res2_fut.await;
+            batch_durations.push(now.elapsed());
 
             // go over results and check if correct
             for res in [res0, res1, res2].iter() {
@@ -520,6 +544,17 @@ mod e2e_test {
             }
         }
 
+        // Thanks to the warmup, the first batch is not slower than the rest.
+        let mut sorted_durations = batch_durations[1..].to_vec();
+        sorted_durations.sort();
+        let median = sorted_durations[sorted_durations.len() / 2];
+        assert!(
+            batch_durations[0].as_secs_f64() <= median.as_secs_f64() * WARMUP_LATENCY_TOLERANCE,
+            "first batch took {:?}, median batch {:?}",
+            batch_durations[0],
+            median
+        );
+
         drop(handle0);
         drop(handle1);
         drop(handle2);
@@ -2,9 +2,10 @@
 mod extract_msb_mod_test {
 
     use cudarc::driver::{CudaDevice, CudaStream};
-    use iris_mpc_common::iris_db::iris::IrisCodeArray;
+    use iris_mpc_common::{helpers::threshold::MatchThreshold, iris_db::iris::IrisCodeArray};
     use iris_mpc_gpu::{
         helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
+        server::seed_exchange,
         threshold_ring::protocol::{ChunkShare, ChunkShareView, Circuits},
     };
     use itertools::izip;
@@ -187,9 +188,10 @@ mod extract_msb_mod_test {
             party_id,
             INPUTS_PER_GPU_SIZE,
             INPUTS_PER_GPU_SIZE / 64,
-            ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]),
+            seed_exchange::insecure_deterministic_seeds(party_id),
             device_manager.clone(),
             comms,
+            MatchThreshold::default(),
         );
         let devices = party.get_devices();
         let streams = devices
@@ -216,11 +218,11 @@ mod extract_msb_mod_test {
             party.extract_msb(&mut x, &streams);
             println!("extract time: {:?}", now.elapsed());
 
-            let res = party.take_result_buffer();
+            let res = party.results().unwrap();
             let now = Instant::now();
             let result = open(&mut party, &res, &streams);
             party.synchronize_streams(&streams);
-            party.return_result_buffer(res);
+            party.return_results(res, &streams).unwrap();
             println!("Open and transfer to CPU time: {:?}", now.elapsed());
 
             let mut correct = true;
@@ -1,9 +1,10 @@
 #[cfg(feature = "gpu_dependent")]
 mod lift_test {
     use cudarc::driver::{CudaDevice, CudaStream};
-    use iris_mpc_common::iris_db::iris::IrisCodeArray;
+    use iris_mpc_common::{helpers::threshold::MatchThreshold, iris_db::iris::IrisCodeArray};
     use iris_mpc_gpu::{
         helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
+        server::seed_exchange,
         threshold_ring::protocol::{ChunkShare, ChunkShareView, Circuits},
     };
     use itertools::izip;
@@ -197,9 +198,10 @@ mod lift_test {
             party_id,
             INPUTS_PER_GPU_SIZE,
             INPUTS_PER_GPU_SIZE / 64,
-            ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]),
+            seed_exchange::insecure_deterministic_seeds(party_id),
             device_manager.clone(),
             comms,
+            MatchThreshold::default(),
         );
         let devices = party.get_devices();
         let streams = devices
@@ -0,0 +1,78 @@
+#[cfg(feature = "gpu_dependent")]
+mod nccl_reinit_test {
+    use cudarc::nccl::Id;
+    use iris_mpc_gpu::helpers::{comm::NcclComm, device_manager::DeviceManager};
+    use itertools::Itertools;
+    use std::{env, sync::Arc, thread};
+
+    /// Gathers the party id of every party over the communicators.
+    fn gather_party_ids(comms: &[Arc<NcclComm>], party_id: usize) -> Vec<Vec<u8>> {
+        comms
+            .iter()
+            .map(|comm| {
+                let device = comm.device();
+                device.bind_to_thread().unwrap();
+                let send = device.htod_copy(vec![party_id as u8]).unwrap();
+                let mut receive = device.alloc_zeros::<u8>(comm.world_size()).unwrap();
+                comm.all_gather(&send, &mut receive).unwrap();
+                device.dtoh_sync_copy(&receive).unwrap()
+            })
+            .collect()
+    }
+
+    fn run_party(
+        party_id: usize,
+        device_manager: Arc<DeviceManager>,
+        ids: &[Id],
+        reinit_ids: &[Id],
+    ) {
+        let n_devices = device_manager.device_count();
+        let comms = device_manager
+            .instantiate_network_from_ids(party_id, ids)
+            .unwrap();
+        assert_eq!(gather_party_ids(&comms, party_id), vec![
+            vec![0, 1, 2];
+            n_devices
+        ]);
+
+        // A failed collective leaves the communicator to be aborted.
+        comms[0].abort().unwrap();
+        assert!(comms[0].is_aborted());
+
+        let comms = device_manager.reinit(party_id, &comms, reinit_ids).unwrap();
+        assert!(comms.iter().all(|comm| !comm.is_aborted()));
+        assert_eq!(gather_party_ids(&comms, party_id), vec![
+            vec![0, 1, 2];
+            n_devices
+        ]);
+    }
+
+    #[test]
+    fn test_abort_and_reinit() {
+        env::set_var("NCCL_P2P_LEVEL", "LOC");
+        env::set_var("NCCL_NET", "Socket");
+
+        let device_managers = DeviceManager::init()
+            .split_into_n_chunks(3)
+            .expect("have at least 3 devices")
+            .into_iter()
+            .map(Arc::new)
+            .collect_vec();
+        let n_devices = device_managers[0].device_count();
+        let ids = (0..n_devices).map(|_| Id::new().unwrap()).collect_vec();
+        let reinit_ids = (0..n_devices).map(|_| Id::new().unwrap()).collect_vec();
+
+        let parties = device_managers
+            .iter()
+            .enumerate()
+            .map(|(party_id, device_manager)| {
+                let (device_manager, ids, reinit_ids) =
+                    (device_manager.clone(), ids.clone(), reinit_ids.clone());
+                thread::spawn(move || run_party(party_id, device_manager, &ids, &reinit_ids))
+            })
+            .collect_vec();
+        for party in parties {
+            party.join().unwrap();
+        }
+    }
+}
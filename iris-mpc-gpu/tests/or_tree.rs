@@ -1,8 +1,10 @@
 #[cfg(feature = "gpu_dependent")]
 mod or_tree_test {
     use cudarc::driver::{CudaDevice, CudaStream};
+    use iris_mpc_common::helpers::threshold::MatchThreshold;
     use iris_mpc_gpu::{
         helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
+        server::seed_exchange,
         threshold_ring::protocol::{ChunkShare, Circuits},
     };
     use itertools::izip;
@@ -113,9 +115,10 @@ mod or_tree_test {
             party_id,
             INPUTS_PER_GPU_SIZE,
             INPUTS_PER_GPU_SIZE / 64,
-            ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]),
+            seed_exchange::insecure_deterministic_seeds(party_id),
             device_manager.clone(),
             comms,
+            MatchThreshold::default(),
         );
         let devices = party.get_devices();
         let streams = devices
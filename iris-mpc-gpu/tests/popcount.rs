@@ -0,0 +1,169 @@
+#[cfg(feature = "gpu_dependent")]
+mod popcount_test {
+    use cudarc::driver::{CudaDevice, CudaStream, DeviceSlice};
+    use iris_mpc_common::helpers::threshold::MatchThreshold;
+    use iris_mpc_gpu::{
+        helpers::{device_manager::DeviceManager, dtoh_on_stream_sync},
+        server::seed_exchange,
+        threshold_ring::protocol::{ChunkShare, Circuits},
+    };
+    use itertools::izip;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{env, sync::Arc};
+
+    const INPUTS_PER_GPU_SIZE: usize = 2048 * 2;
+    const BITS_PER_QUERY: usize = 3 * 64;
+    const VALID_BITS: usize = 150;
+
+    fn sample_bits<R: Rng>(size: usize, rng: &mut R) -> Vec<u64> {
+        (0..size / 64).map(|_| rng.gen()).collect::<Vec<_>>()
+    }
+
+    fn rep_share<R: Rng>(value: u64, id: usize, rng: &mut R) -> (u64, u64) {
+        let a = rng.next_u64();
+        let b = rng.next_u64();
+        let c = value ^ a ^ b;
+
+        match id {
+            0 => (a, c),
+            1 => (b, a),
+            2 => (c, b),
+            _ => unreachable!(),
+        }
+    }
+
+    fn rep_share_vec<R: Rng>(value: &[u64], id: usize, rng: &mut R) -> (Vec<u64>, Vec<u64>) {
+        let mut a = Vec::with_capacity(value.len());
+        let mut b = Vec::with_capacity(value.len());
+        for v in value.iter() {
+            let (a_, b_) = rep_share(*v, id, rng);
+            a.push(a_);
+            b.push(b_);
+        }
+        (a, b)
+    }
+
+    /// The counts of every query on every device, in device order.
+    fn real_result(input: &[u64]) -> Vec<u16> {
+        let n_queries = INPUTS_PER_GPU_SIZE / BITS_PER_QUERY;
+        let mut res = vec![];
+        for device_bits in input.chunks(INPUTS_PER_GPU_SIZE / 64) {
+            for query in 0..n_queries {
+                let count = (query * BITS_PER_QUERY..query * BITS_PER_QUERY + VALID_BITS)
+                    .filter(|&i| (device_bits[i / 64] >> (i % 64)) & 1 == 1)
+                    .count();
+                res.push(count as u16);
+            }
+        }
+        res
+    }
+
+    fn alloc_counts(size: usize, devices: &[Arc<CudaDevice>]) -> Vec<ChunkShare<u16>> {
+        devices
+            .iter()
+            .map(|dev| {
+                let a = dev.alloc_zeros(size).unwrap();
+                let b = dev.alloc_zeros(size).unwrap();
+                ChunkShare::new(a, b)
+            })
+            .collect()
+    }
+
+    fn open(party: &mut Circuits, x: &mut [ChunkShare<u16>], streams: &[CudaStream]) -> Vec<u16> {
+        let mut a = Vec::with_capacity(x.len());
+        let mut b = Vec::with_capacity(x.len());
+        let mut c = Vec::with_capacity(x.len());
+
+        let devices = party.get_devices();
+        for (idx, res) in x.iter().enumerate() {
+            a.push(dtoh_on_stream_sync(&res.a, &devices[idx], &streams[idx]).unwrap());
+            b.push(dtoh_on_stream_sync(&res.b, &devices[idx], &streams[idx]).unwrap());
+        }
+        cudarc::nccl::result::group_start().unwrap();
+        for (idx, res) in x.iter().enumerate() {
+            party.comms()[idx]
+                .send_view_u16(&res.b.slice(..), party.next_id(), &streams[idx])
+                .unwrap();
+        }
+        for (idx, res) in x.iter_mut().enumerate() {
+            party.comms()[idx]
+                .receive_view_u16(&mut res.a.slice(..), party.prev_id(), &streams[idx])
+                .unwrap();
+        }
+        cudarc::nccl::result::group_end().unwrap();
+        for (idx, res) in x.iter().enumerate() {
+            c.push(dtoh_on_stream_sync(&res.a, &devices[idx], &streams[idx]).unwrap())
+        }
+
+        let mut result = vec![];
+        for (mut a, b, c) in izip!(a, b, c) {
+            for (a, b, c) in izip!(a.iter_mut(), b, c) {
+                *a += b + c;
+            }
+            result.extend(a);
+        }
+        result
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn test_popcount_per_query() -> eyre::Result<()> {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        let party_id: usize = env::var("SMPC__PARTY_ID")
+            .expect("SMPC__PARTY_ID environment variable not set")
+            .parse()
+            .expect("SMPC__PARTY_ID must be a valid usize");
+        let n_devices = CudaDevice::count()? as usize;
+        let n_queries = INPUTS_PER_GPU_SIZE / BITS_PER_QUERY;
+
+        let input_bits = sample_bits(INPUTS_PER_GPU_SIZE * n_devices, &mut rng);
+        let (input_bits_a, input_bits_b) = rep_share_vec(&input_bits, party_id, &mut rng);
+        let real_result = real_result(&input_bits);
+
+        let device_manager = Arc::new(DeviceManager::init());
+        let ids = device_manager.get_ids_from_magic(0);
+        let comms = device_manager.instantiate_network_from_ids(party_id, &ids)?;
+        let mut party = Circuits::new(
+            party_id,
+            INPUTS_PER_GPU_SIZE,
+            INPUTS_PER_GPU_SIZE / 64,
+            seed_exchange::insecure_deterministic_seeds(party_id),
+            device_manager.clone(),
+            comms,
+            MatchThreshold::default(),
+        );
+        let devices = party.get_devices();
+        let streams = devices
+            .iter()
+            .map(|dev| dev.fork_default_stream().unwrap())
+            .collect::<Vec<_>>();
+
+        // Put the shared bits where a comparison would leave its results.
+        let mut results = party.results().unwrap();
+        for (dev, res, a, b) in izip!(
+            &devices,
+            results.iter_mut(),
+            input_bits_a.chunks(INPUTS_PER_GPU_SIZE / 64),
+            input_bits_b.chunks(INPUTS_PER_GPU_SIZE / 64)
+        ) {
+            let mut a = a.to_vec();
+            let mut b = b.to_vec();
+            a.resize(res.a.len(), 0);
+            b.resize(res.b.len(), 0);
+            dev.htod_sync_copy_into(&a, &mut res.a)?;
+            dev.htod_sync_copy_into(&b, &mut res.b)?;
+        }
+        party.return_results(results, &streams).unwrap();
+
+        let mut counts = alloc_counts(n_queries, &devices);
+        party
+            .popcount_per_query(BITS_PER_QUERY, VALID_BITS, &mut counts, &streams)
+            .unwrap();
+        let result = open(&mut party, &mut counts, &streams);
+        party.synchronize_streams(&streams);
+
+        assert_eq!(result, real_result);
+        Ok(())
+    }
+}
@@ -1,25 +1,34 @@
 #[cfg(feature = "gpu_dependent")]
 mod threshold_test {
     use cudarc::driver::{CudaDevice, CudaStream};
-    use iris_mpc_common::iris_db::iris::{IrisCodeArray, MATCH_THRESHOLD_RATIO};
+    use iris_mpc_common::{
+        helpers::{reference, threshold::MatchThreshold},
+        iris_db::iris::IrisCodeArray,
+    };
     use iris_mpc_gpu::{
         helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
-        threshold_ring::protocol::{ChunkShare, Circuits},
+        server::seed_exchange,
+        threshold_ring::protocol::{
+            ChunkMeta, ChunkShare, Circuits, ResultBufferError, StreamedOpenError,
+        },
     };
     use itertools::izip;
     use rand::{rngs::StdRng, Rng, SeedableRng};
     use static_assertions::const_assert;
-    use std::{env, sync::Arc};
+    use std::{env, net::TcpListener, process::Command, sync::Arc};
     use tokio::time::Instant;
 
     // ceil(930 * 125_000 / 2048) * 2048
     // const INPUTS_PER_GPU_SIZE: usize = 116_250_624;
     const INPUTS_PER_GPU_SIZE: usize = 12_507_136;
-    const CHUNK_SIZE: usize = INPUTS_PER_GPU_SIZE / 64;
+    /// Input size of the three-party test on a single host.
+    const LOCAL_INPUTS_PER_GPU_SIZE: usize = 2048 * 64;
 
-    const B_BITS: u64 = 16;
-    pub(crate) const B: u64 = 1 << B_BITS;
-    pub(crate) const A: u64 = ((1. - 2. * MATCH_THRESHOLD_RATIO) * B as f64) as u64;
+    /// Minimum number of devices for the three-party test on a single host,
+    /// one per party.
+    const LOCAL_MIN_DEVICES: usize = 3;
+    const LOCAL_PARTY_ID_ENV: &str = "THREE_PARTY_LOCAL_PARTY_ID";
+    const LOCAL_DEVICES_ENV: &str = "THREE_PARTY_LOCAL_DEVICES";
 
     fn sample_code_dots<R: Rng>(size: usize, rng: &mut R) -> Vec<u16> {
         (0..size)
@@ -67,6 +76,7 @@ mod threshold_test {
     fn to_gpu(
         a: &[u16],
         b: &[u16],
+        inputs_per_gpu: usize,
         devices: &[Arc<CudaDevice>],
         streams: &[CudaStream],
     ) -> Vec<ChunkShare<u16>> {
@@ -77,8 +87,8 @@ mod threshold_test {
         for (dev, stream, a, b) in izip!(
             devices,
             streams,
-            a.chunks(INPUTS_PER_GPU_SIZE),
-            b.chunks(INPUTS_PER_GPU_SIZE)
+            a.chunks(inputs_per_gpu),
+            b.chunks(inputs_per_gpu)
         ) {
             let a_ = htod_on_stream_sync(a, dev, stream).unwrap();
             let b_ = htod_on_stream_sync(b, dev, stream).unwrap();
@@ -88,34 +98,12 @@ mod threshold_test {
         result
     }
 
-    fn pack_with_device_padding(bits: Vec<bool>) -> Vec<u64> {
-        assert!(bits.len() % INPUTS_PER_GPU_SIZE == 0);
-        let mut res = vec![];
-        for devices in bits.chunks_exact(INPUTS_PER_GPU_SIZE) {
-            for bits in devices.chunks(64) {
-                let mut r = 0;
-                for (i, bit) in bits.iter().enumerate() {
-                    r |= u64::from(*bit) << i;
-                }
-                res.push(r);
-            }
-        }
-        res
-    }
-
-    fn real_result_msb(code_input: Vec<u16>, mask_input: Vec<u16>) -> Vec<u64> {
-        assert_eq!(code_input.len(), mask_input.len());
-        let mod_ = 1u64 << (16 + B_BITS);
-        let mut res = Vec::with_capacity(code_input.len());
-        for (c, m) in code_input.into_iter().zip(mask_input) {
-            let r = ((m as u64) * A - ((c as u64) << B_BITS)) % mod_;
-            let msb = r >> (B_BITS + 16 - 1) & 1 == 1;
-            res.push(msb)
-        }
-        pack_with_device_padding(res)
-    }
-
-    fn open(party: &mut Circuits, x: &[ChunkShare<u64>], streams: &[CudaStream]) -> Vec<u64> {
+    fn open(
+        party: &mut Circuits,
+        x: &[ChunkShare<u64>],
+        chunk_size: usize,
+        streams: &[CudaStream],
+    ) -> Vec<u64> {
         let n_devices = x.len();
         let mut a = Vec::with_capacity(n_devices);
         let mut b = Vec::with_capacity(n_devices);
@@ -124,7 +112,7 @@ mod threshold_test {
         cudarc::nccl::result::group_start().unwrap();
         for (idx, res) in x.iter().enumerate() {
             // Result is in bit 0
-            let res = res.get_offset(0, CHUNK_SIZE);
+            let res = res.get_offset(0, chunk_size);
             party.comms()[idx]
                 .send_view(&res.b, party.next_id(), &streams[idx])
                 .unwrap();
@@ -132,7 +120,7 @@ mod threshold_test {
             b.push(res.b);
         }
         for (idx, res) in x.iter().enumerate() {
-            let mut res = res.get_offset(1, CHUNK_SIZE);
+            let mut res = res.get_offset(1, chunk_size);
             party.comms()[idx]
                 .receive_view(&mut res.a, party.prev_id(), &streams[idx])
                 .unwrap();
@@ -140,7 +128,7 @@ mod threshold_test {
         }
         cudarc::nccl::result::group_end().unwrap();
 
-        let mut result = Vec::with_capacity(n_devices * CHUNK_SIZE);
+        let mut result = Vec::with_capacity(n_devices * chunk_size);
         let devices = party.get_devices();
         for (dev, stream, a, b, c) in izip!(devices, streams, a, b, c) {
             let mut a = dtoh_on_stream_sync(&a, &dev, stream).unwrap();
@@ -151,48 +139,83 @@ mod threshold_test {
             }
             result.extend(a);
         }
-        assert_eq!(result.len(), n_devices * CHUNK_SIZE);
+        assert_eq!(result.len(), n_devices * chunk_size);
         result
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    #[ignore]
-    async fn test_threshold() -> eyre::Result<()> {
+    /// Like [`open`], through [`Circuits::open_results_streamed`]. Checks that
+    /// the chunks arrive in order and reassembles them.
+    fn open_streamed(
+        party: &mut Circuits,
+        x: &[ChunkShare<u64>],
+        chunk_size: usize,
+        chunk_elems: usize,
+        streams: &[CudaStream],
+    ) -> Vec<u64> {
+        let n_devices = x.len();
+        let mut result = vec![0u64; n_devices * chunk_size];
+        let mut expected = ChunkMeta {
+            index:  0,
+            device: 0,
+            offset: 0,
+        };
+        party
+            .open_results_streamed(x, chunk_elems, streams, |meta, chunk| {
+                assert_eq!(meta, expected);
+                assert_eq!(chunk.len(), chunk_elems.min(chunk_size - meta.offset));
+                let start = meta.device * chunk_size + meta.offset;
+                result[start..start + chunk.len()].copy_from_slice(chunk);
+                expected.index += 1;
+                expected.device = (meta.device + 1) % n_devices;
+                if expected.device == 0 {
+                    expected.offset += chunk_elems;
+                }
+            })
+            .unwrap();
+        assert_eq!(expected.index, n_devices * chunk_size.div_ceil(chunk_elems));
+        result
+    }
+
+    /// Runs `rounds` threshold comparisons of `inputs_per_gpu` elements per
+    /// device as the given party and returns whether all results were
+    /// correct.
+    fn run_threshold(
+        party_id: usize,
+        device_manager: Arc<DeviceManager>,
+        inputs_per_gpu: usize,
+        rounds: usize,
+    ) -> eyre::Result<bool> {
         use itertools::Itertools;
 
-        const_assert!(
-            INPUTS_PER_GPU_SIZE % (2048) == 0,
-            // Mod 16 for randomness, mod 64 for chunk size
-        );
-        // TODO
+        assert_eq!(inputs_per_gpu % 2048, 0);
         let mut rng = StdRng::seed_from_u64(42);
-
-        let party_id: usize = env::var("SMPC__PARTY_ID")
-            .expect("SMPC__PARTY_ID environment variable not set")
-            .parse()
-            .expect("SMPC__PARTY_ID must be a valid usize");
-        let n_devices = CudaDevice::count()? as usize;
+        let n_devices = device_manager.device_count();
 
         // Get inputs
-        let code_dots = sample_code_dots(INPUTS_PER_GPU_SIZE * n_devices, &mut rng);
-        let mask_dots = sample_mask_dots(INPUTS_PER_GPU_SIZE * n_devices, &mut rng);
+        let code_dots = sample_code_dots(inputs_per_gpu * n_devices, &mut rng);
+        let mask_dots = sample_mask_dots(inputs_per_gpu * n_devices, &mut rng);
 
         let (code_share_a, code_share_b) = rep_share_vec(&code_dots, party_id, &mut rng);
         let (mask_share_a, mask_share_b) = rep_share_vec(&mask_dots, party_id, &mut rng);
-        let real_result = real_result_msb(code_dots, mask_dots);
+        let real_result = reference::masked_match_packed(
+            &code_dots,
+            &mask_dots,
+            MatchThreshold::default(),
+            inputs_per_gpu,
+        );
         println!("Random shared inputs generated!");
 
         // Get Circuit Party
-        let device_manager = Arc::new(DeviceManager::init());
         let ids = device_manager.get_ids_from_magic(0);
         let comms = device_manager.instantiate_network_from_ids(party_id, &ids)?;
         let mut party = Circuits::new(
             party_id,
-            INPUTS_PER_GPU_SIZE,
-            INPUTS_PER_GPU_SIZE / 64,
-            ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]),
+            inputs_per_gpu,
+            inputs_per_gpu / 64,
+            seed_exchange::insecure_deterministic_seeds(party_id),
             device_manager.clone(),
             comms,
+            MatchThreshold::default(),
         );
         let devices = party.get_devices();
         let streams = devices
@@ -201,25 +224,49 @@ mod threshold_test {
             .collect::<Vec<_>>();
 
         // Import to GPU
-        let code_gpu = to_gpu(&code_share_a, &code_share_b, &devices, &streams);
-        let mask_gpu = to_gpu(&mask_share_a, &mask_share_b, &devices, &streams);
+        let code_gpu = to_gpu(
+            &code_share_a,
+            &code_share_b,
+            inputs_per_gpu,
+            &devices,
+            &streams,
+        );
+        let mask_gpu = to_gpu(
+            &mask_share_a,
+            &mask_share_b,
+            inputs_per_gpu,
+            &devices,
+            &streams,
+        );
         println!("Data is on GPUs!");
         println!("Starting tests...");
 
-        for _ in 0..10 {
+        let mut all_correct = true;
+        for _ in 0..rounds {
             let code_gpu = code_gpu.iter().map(|x| x.as_view()).collect_vec();
             let mask_gpu = mask_gpu.iter().map(|x| x.as_view()).collect_vec();
 
             let now = Instant::now();
-            party.compare_threshold_masked_many(&code_gpu, &mask_gpu, &streams);
+            party
+                .compare_threshold_masked_many(&code_gpu, &mask_gpu, &streams)
+                .unwrap();
             party.synchronize_streams(&streams);
             println!("compute time: {:?}", now.elapsed());
 
-            let res = party.take_result_buffer();
+            let res = party.results().unwrap();
             let now = Instant::now();
-            let result = open(&mut party, &res, &streams);
-            party.synchronize_streams(&streams);
-            party.return_result_buffer(res);
+            let result = open(&mut party, &res, inputs_per_gpu / 64, &streams);
+            // The streamed open yields the same, also with chunks that do not
+            // divide the result evenly.
+            let chunk_size = inputs_per_gpu / 64;
+            let streamed_matches = [chunk_size, chunk_size / 4, chunk_size / 3 + 1]
+                .into_iter()
+                .all(|chunk_elems| {
+                    open_streamed(&mut party, &res, chunk_size, chunk_elems, &streams) == result
+                });
+            // No need to synchronize, the next comparison waits for the open to finish
+            // reading the results.
+            party.return_results(res, &streams).unwrap();
             println!("Open and transfer to CPU time: {:?}", now.elapsed());
 
             let mut correct = true;
@@ -230,11 +277,168 @@ mod threshold_test {
                     break;
                 }
             }
+            if !streamed_matches {
+                correct = false;
+                println!("Test failed: the streamed open differs from the one-shot open");
+            }
             if correct {
                 println!("Test passed!");
             }
+            all_correct &= correct;
         }
 
+        Ok(all_correct)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn test_threshold() -> eyre::Result<()> {
+        const_assert!(
+            INPUTS_PER_GPU_SIZE % (2048) == 0,
+            // Mod 16 for randomness, mod 64 for chunk size
+        );
+
+        let party_id: usize = env::var("SMPC__PARTY_ID")
+            .expect("SMPC__PARTY_ID environment variable not set")
+            .parse()
+            .expect("SMPC__PARTY_ID must be a valid usize");
+
+        let device_manager = Arc::new(DeviceManager::init());
+        run_threshold(party_id, device_manager, INPUTS_PER_GPU_SIZE, 10)?;
+        Ok(())
+    }
+
+    /// Runs the threshold test with all three parties on one host. The test
+    /// spawns itself three times, once per party, with a disjoint subset of
+    /// the devices each, and NCCL bootstraps over localhost. Needs at least
+    /// [`LOCAL_MIN_DEVICES`] devices and is skipped below that.
+    ///
+    /// `cargo test -p iris-mpc-gpu --features gpu_dependent -- --ignored
+    /// three_party_local`
+    #[test]
+    #[ignore]
+    fn three_party_local() -> eyre::Result<()> {
+        if let Ok(party_id) = env::var(LOCAL_PARTY_ID_ENV) {
+            // Spawned by the parent test as one of the parties.
+            let party_id: usize = party_id.parse()?;
+            let device_ids = env::var(LOCAL_DEVICES_ENV)?
+                .split(',')
+                .map(|id| id.parse())
+                .collect::<Result<Vec<usize>, _>>()?;
+            let device_manager = Arc::new(DeviceManager::init_with_device_ids(&device_ids)?);
+            let correct = run_threshold(party_id, device_manager, LOCAL_INPUTS_PER_GPU_SIZE, 2)?;
+            assert!(correct, "party {} opened wrong results", party_id);
+            return Ok(());
+        }
+
+        let n_devices = CudaDevice::count()? as usize;
+        if n_devices < LOCAL_MIN_DEVICES {
+            println!(
+                "Skipping three_party_local: {} devices present, {} needed",
+                n_devices, LOCAL_MIN_DEVICES
+            );
+            return Ok(());
+        }
+        let devices_per_party = n_devices / 3;
+
+        // Party 0 hosts the NCCL bootstrap on a free local port.
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let exe = env::current_exe()?;
+        let mut children = vec![];
+        for party_id in 0..3 {
+            let device_ids = (party_id * devices_per_party..(party_id + 1) * devices_per_party)
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            let child = Command::new(&exe)
+                .args([
+                    "--ignored",
+                    "--exact",
+                    "threshold_test::three_party_local",
+                    "--nocapture",
+                ])
+                .env(LOCAL_PARTY_ID_ENV, party_id.to_string())
+                .env(LOCAL_DEVICES_ENV, device_ids)
+                .env("NCCL_COMM_ID", format!("127.0.0.1:{}", port))
+                .spawn()?;
+            children.push(child);
+        }
+
+        let mut failed = vec![];
+        for (party_id, mut child) in children.into_iter().enumerate() {
+            if !child.wait()?.success() {
+                failed.push(party_id);
+            }
+        }
+        assert!(failed.is_empty(), "parties {:?} failed", failed);
         Ok(())
     }
+
+    /// A single party without network, enough to exercise the result buffer.
+    fn local_party() -> (Circuits, Vec<CudaStream>) {
+        let device_manager = Arc::new(DeviceManager::init());
+        let party = Circuits::new(
+            0,
+            64 * 64,
+            64,
+            ([0u32; 8], [2u32; 8]),
+            device_manager,
+            vec![],
+            MatchThreshold::default(),
+        );
+        let streams = party
+            .get_devices()
+            .iter()
+            .map(|dev| dev.fork_default_stream().unwrap())
+            .collect::<Vec<_>>();
+        (party, streams)
+    }
+
+    #[test]
+    fn test_result_buffer_double_take() {
+        let (mut party, streams) = local_party();
+
+        let res = party.results().unwrap();
+        assert_eq!(party.results().err(), Some(ResultBufferError::AlreadyTaken));
+        assert_eq!(
+            party.compare_threshold_masked_many(&[], &[], &streams),
+            Err(ResultBufferError::NotReturned)
+        );
+
+        party.return_results(res, &streams).unwrap();
+        let res = party.results().unwrap();
+        party.discard_results(res).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_open_zero_chunk() {
+        let (mut party, streams) = local_party();
+        let res = party.results().unwrap();
+        assert_eq!(
+            party.open_results_streamed(&res, 0, &streams, |_, _| unreachable!()),
+            Err(StreamedOpenError::ZeroChunk)
+        );
+        party.discard_results(res).unwrap();
+    }
+
+    #[test]
+    fn test_result_buffer_wrong_shape() {
+        let (mut party, streams) = local_party();
+        let devices = party.get_devices();
+
+        let mut res = party.results().unwrap();
+        res[0] = ChunkShare::new(
+            devices[0].alloc_zeros(1).unwrap(),
+            devices[0].alloc_zeros(1).unwrap(),
+        );
+        assert!(matches!(
+            party.return_results(res, &streams),
+            Err(ResultBufferError::WrongShape { .. })
+        ));
+        // The buffer is not back, so no further comparison can overwrite it.
+        assert_eq!(
+            party.compare_threshold_masked_many(&[], &[], &streams),
+            Err(ResultBufferError::NotReturned)
+        );
+    }
 }
@@ -1,9 +1,13 @@
 #[cfg(feature = "gpu_dependent")]
 mod test_threshold_and_or_tree_test {
     use cudarc::driver::{CudaDevice, CudaStream};
-    use iris_mpc_common::iris_db::iris::{IrisCodeArray, MATCH_THRESHOLD_RATIO};
+    use iris_mpc_common::{
+        helpers::{reference, threshold::MatchThreshold},
+        iris_db::iris::IrisCodeArray,
+    };
     use iris_mpc_gpu::{
         helpers::{device_manager::DeviceManager, dtoh_on_stream_sync, htod_on_stream_sync},
+        server::seed_exchange,
         threshold_ring::protocol::{ChunkShare, Circuits},
     };
     use itertools::izip;
@@ -15,9 +19,6 @@ mod test_threshold_and_or_tree_test {
     // ceil(930 * 125_000 / 2048) * 2048
     const INPUTS_PER_GPU_SIZE: usize = 116_250_624;
     // const INPUTS_PER_GPU_SIZE: usize = 12_507_136;
-    const B_BITS: u64 = 16;
-    pub(crate) const B: u64 = 1 << B_BITS;
-    pub(crate) const A: u64 = ((1. - 2. * MATCH_THRESHOLD_RATIO) * B as f64) as u64;
 
     fn sample_code_dots<R: Rng>(size: usize, rng: &mut R) -> Vec<u16> {
         (0..size)
@@ -86,18 +87,6 @@ mod test_threshold_and_or_tree_test {
         result
     }
 
-    fn real_result_msb_reduce(code_input: Vec<u16>, mask_input: Vec<u16>) -> bool {
-        assert_eq!(code_input.len(), mask_input.len());
-        let mod_ = 1u64 << (16 + B_BITS);
-        let mut res = false;
-        for (c, m) in code_input.into_iter().zip(mask_input) {
-            let r = ((m as u64) * A - ((c as u64) << B_BITS)) % mod_;
-            let msb = r >> (B_BITS + 16 - 1) & 1 == 1;
-            res |= msb;
-        }
-        res
-    }
-
     fn open(party: &mut Circuits, result: &mut ChunkShare<u64>, streams: &[CudaStream]) -> bool {
         let res = result.get_offset(0, 1);
         let mut res_helper = result.get_offset(1, 1);
@@ -150,7 +139,9 @@ mod test_threshold_and_or_tree_test {
 
         let (code_share_a, code_share_b) = rep_share_vec(&code_dots, party_id, &mut rng);
         let (mask_share_a, mask_share_b) = rep_share_vec(&mask_dots, party_id, &mut rng);
-        let real_result = real_result_msb_reduce(code_dots, mask_dots);
+        let real_result =
+            reference::masked_match_many(&code_dots, &mask_dots, MatchThreshold::default())
+                .contains(&true);
         println!("Random shared inputs generated!");
 
         // Get Circuit Party
@@ -161,9 +152,10 @@ mod test_threshold_and_or_tree_test {
             party_id,
             INPUTS_PER_GPU_SIZE,
             INPUTS_PER_GPU_SIZE / 64,
-            ([party_id as u32; 8], [((party_id + 2) % 3) as u32; 8]),
+            seed_exchange::insecure_deterministic_seeds(party_id),
             device_manager.clone(),
             comms,
+            MatchThreshold::default(),
         );
         let devices = party.get_devices();
         let streams = devices
@@ -182,14 +174,16 @@ mod test_threshold_and_or_tree_test {
             let mask_gpu = mask_gpu.iter().map(|x| x.as_view()).collect_vec();
 
             let now = Instant::now();
-            party.compare_threshold_masked_many_with_or_tree(&code_gpu, &mask_gpu, &streams);
+            party
+                .compare_threshold_masked_many_with_or_tree(&code_gpu, &mask_gpu, &streams)
+                .unwrap();
             println!("compute time: {:?}", now.elapsed());
 
-            let mut res = party.take_result_buffer();
+            let mut res = party.results().unwrap();
             let now = Instant::now();
             let result = open(&mut party, &mut res[0], &streams);
             party.synchronize_streams(&streams);
-            party.return_result_buffer(res);
+            party.return_results(res, &streams).unwrap();
             println!("Open and transfer to CPU time: {:?}", now.elapsed());
 
             if result == real_result {
@@ -1,6 +1,6 @@
 use super::iris_code_array::PyIrisCodeArray;
 use iris_mpc_common::iris_db::iris::IrisCode;
-use pyo3::{prelude::*, types::PyDict};
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict};
 use rand::rngs::ThreadRng;
 
 #[pyclass]
@@ -40,9 +40,13 @@ impl PyIrisCode {
         version: Option<String>,
     ) -> PyResult<Bound<'py, PyDict>> {
         let dict = PyDict::new_bound(py);
+        let (iris_codes, mask_codes) = self
+            .0
+            .to_open_iris_template()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
 
-        dict.set_item("iris_codes", self.0.code.to_base64().unwrap())?;
-        dict.set_item("mask_codes", self.0.mask.to_base64().unwrap())?;
+        dict.set_item("iris_codes", iris_codes)?;
+        dict.set_item("mask_codes", mask_codes)?;
         dict.set_item("iris_code_version", version)?;
 
         Ok(dict)
@@ -54,15 +58,9 @@ impl PyIrisCode {
         let iris_codes_str: String = dict_obj.get_item("iris_codes")?.unwrap().extract()?;
         let mask_codes_str: String = dict_obj.get_item("mask_codes")?.unwrap().extract()?;
 
-        // Convert the base64 strings into PyIrisCodeArrays
-        let code = PyIrisCodeArray::from_base64(iris_codes_str);
-        let mask = PyIrisCodeArray::from_base64(mask_codes_str);
-
-        // Construct and return PyIrisCode
-        Ok(Self(IrisCode {
-            code: code.0,
-            mask: mask.0,
-        }))
+        IrisCode::from_open_iris_template(&iris_codes_str, &mask_codes_str)
+            .map(Self)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
     }
 }
 
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS share_hashes (
+    hashes TEXT PRIMARY KEY,
+    signup_id TEXT NOT NULL,
+    seen_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS share_hashes_seen_at ON share_hashes (seen_at);
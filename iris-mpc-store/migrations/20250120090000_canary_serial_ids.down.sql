@@ -0,0 +1 @@
+DROP TABLE canary_serial_ids;
@@ -0,0 +1,5 @@
+-- Serial ids assigned to requests of the end-to-end canary.
+CREATE TABLE IF NOT EXISTS canary_serial_ids (
+    id BIGINT PRIMARY KEY,
+    signup_id TEXT NOT NULL
+);
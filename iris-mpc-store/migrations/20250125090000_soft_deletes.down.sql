@@ -0,0 +1 @@
+ALTER TABLE irises DROP COLUMN purge_at;
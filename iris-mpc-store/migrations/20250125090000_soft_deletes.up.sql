@@ -0,0 +1,3 @@
+-- Purge time of an identity deleted with a grace period, in seconds since the
+-- epoch. The shares are kept until then, NULL for all other irises.
+ALTER TABLE irises ADD COLUMN IF NOT EXISTS purge_at BIGINT;
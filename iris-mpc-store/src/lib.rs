@@ -26,6 +26,11 @@ const MAX_CONNECTIONS: u32 = 100;
 
 static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
 
+/// The version of the latest migration, i.e. the schema the store expects.
+pub fn schema_version() -> i64 {
+    MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0)
+}
+
 fn sql_switch_schema(schema_name: &str) -> Result<String> {
     sanitize_identifier(schema_name)?;
     Ok(format!(
@@ -204,6 +209,18 @@ impl Store {
         .bind(i64::try_from(id_range.end).expect("id fits into i64"))
         .fetch(&self.pool)
     }
+    /// Fetches the irises with the given serial ids, in ascending order. Ids
+    /// not in the store are skipped.
+    pub async fn fetch_irises(&self, serial_ids: &[u32]) -> Result<Vec<StoredIris>> {
+        let ids = serial_ids.iter().map(|&id| id as i64).collect::<Vec<_>>();
+        Ok(
+            sqlx::query_as("SELECT * FROM irises WHERE id = ANY($1) ORDER BY id ASC")
+                .bind(&ids)
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
     /// Stream irises in parallel, without a particular order.
     pub async fn stream_irises_par(
         &self,
@@ -308,7 +325,8 @@ DO UPDATE SET left_code = EXCLUDED.left_code, left_mask = EXCLUDED.left_mask, ri
         Ok(())
     }
 
-    /// Update existing iris with given shares.
+    /// Update existing iris with given shares. This finalizes a soft-delete
+    /// of the iris, if any.
     pub async fn update_iris(
         &self,
         id: i64,
@@ -321,7 +339,7 @@ DO UPDATE SET left_code = EXCLUDED.left_code, left_mask = EXCLUDED.left_mask, ri
 
         let query = sqlx::query(
             r#"
-UPDATE irises SET (left_code, left_mask, right_code, right_mask) = ($2, $3, $4, $5)
+UPDATE irises SET (left_code, left_mask, right_code, right_mask, purge_at) = ($2, $3, $4, $5, NULL)
 WHERE id = $1;
 "#,
         )
@@ -336,6 +354,44 @@ WHERE id = $1;
         Ok(())
     }
 
+    /// Flags the iris to be purged at `purge_at`, in seconds since the epoch,
+    /// keeping its shares until then. Returns false if there is no such iris.
+    pub async fn soft_delete_iris(&self, id: i64, purge_at: i64) -> Result<bool> {
+        let result = sqlx::query("UPDATE irises SET purge_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(purge_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Clears the soft-delete flag of the iris and returns its shares, or
+    /// `None` if it is not soft-deleted.
+    pub async fn restore_iris(&self, id: i64) -> Result<Option<StoredIris>> {
+        Ok(sqlx::query_as(
+            r#"
+UPDATE irises SET purge_at = NULL
+WHERE id = $1 AND purge_at IS NOT NULL
+RETURNING id, left_code, left_mask, right_code, right_mask;
+"#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    /// The soft-deleted irises as pairs of serial id and purge time, in
+    /// ascending order of serial ids.
+    pub async fn soft_deleted_irises(&self) -> Result<Vec<(i64, i64)>> {
+        Ok(
+            sqlx::query_as(
+                "SELECT id, purge_at FROM irises WHERE purge_at IS NOT NULL ORDER BY id",
+            )
+            .fetch_all(&self.pool)
+            .await?,
+        )
+    }
+
     pub async fn insert_or_update_left_iris(
         &self,
         id: i64,
@@ -393,6 +449,10 @@ DO UPDATE SET right_code = EXCLUDED.right_code, right_mask = EXCLUDED.right_mask
             .bind(db_len as i64)
             .execute(&mut *tx)
             .await?;
+        sqlx::query("DELETE FROM canary_serial_ids WHERE id > $1")
+            .bind(db_len as i64)
+            .execute(&mut *tx)
+            .await?;
 
         tx.commit().await?;
         Ok(())
@@ -405,6 +465,20 @@ DO UPDATE SET right_code = EXCLUDED.right_code, right_mask = EXCLUDED.right_mask
         Ok(id.0 as usize)
     }
 
+    /// The number of irises with a serial id up to `db_len` and the latest
+    /// modification time among them. A DB snapshot taken from these irises is
+    /// stale if either changes.
+    pub async fn modification_watermark(&self, db_len: usize) -> Result<(usize, i64)> {
+        let (count, last_modified_at): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(MAX(last_modified_at), 0) FROM irises WHERE id >= 1 AND id \
+             <= $1",
+        )
+        .bind(db_len as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok((count as usize, last_modified_at))
+    }
+
     pub async fn insert_results(
         &self,
         tx: &mut Transaction<'_, Postgres>,
@@ -453,6 +527,76 @@ DO UPDATE SET right_code = EXCLUDED.right_code, right_mask = EXCLUDED.right_mask
         Ok(rows.into_iter().rev().map(|r| r.request_id).collect())
     }
 
+    /// Records the share file hashes of a uniqueness request seen at
+    /// `now_secs`. Returns `true` if the same hashes were seen under another
+    /// signup id less than `window_secs` ago, i.e. the request is a replay.
+    pub async fn record_share_hashes(
+        &self,
+        share_hashes: &[String; 3],
+        signup_id: &str,
+        now_secs: i64,
+        window_secs: i64,
+    ) -> Result<bool> {
+        // A redelivery under the same signup id keeps the first sighting.
+        let recorded: Option<String> = sqlx::query_scalar(
+            r#"
+INSERT INTO share_hashes (hashes, signup_id, seen_at)
+VALUES ($1, $2, $3)
+ON CONFLICT (hashes) DO UPDATE
+SET signup_id = EXCLUDED.signup_id,
+    seen_at = CASE WHEN share_hashes.signup_id = EXCLUDED.signup_id
+                   THEN share_hashes.seen_at ELSE EXCLUDED.seen_at END
+WHERE share_hashes.signup_id = EXCLUDED.signup_id
+   OR share_hashes.seen_at <= EXCLUDED.seen_at - $4
+RETURNING signup_id
+"#,
+        )
+        .bind(share_hashes.join(","))
+        .bind(signup_id)
+        .bind(now_secs)
+        .bind(window_secs)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(recorded.is_none())
+    }
+
+    /// Forgets the share file hashes seen before `before_secs`.
+    pub async fn prune_share_hashes(&self, before_secs: i64) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM share_hashes WHERE seen_at < $1")
+            .bind(before_secs)
+            .execute(&self.pool)
+            .await?;
+        Ok(deleted.rows_affected())
+    }
+
+    /// Tags the serial ids assigned to canary requests, see
+    /// [`iris_mpc_common::helpers::canary`].
+    pub async fn tag_canary_serial_ids(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        serial_ids: &[(i64, String)],
+    ) -> Result<()> {
+        if serial_ids.is_empty() {
+            return Ok(());
+        }
+        let mut query = sqlx::QueryBuilder::new("INSERT INTO canary_serial_ids (id, signup_id)");
+        query.push_values(serial_ids, |mut query, (id, signup_id)| {
+            query.push_bind(id).push_bind(signup_id);
+        });
+        query.push(" ON CONFLICT (id) DO NOTHING");
+        query.build().execute(tx.deref_mut()).await?;
+        Ok(())
+    }
+
+    /// The serial ids tagged as canary identities, in ascending order.
+    pub async fn canary_serial_ids(&self) -> Result<Vec<i64>> {
+        Ok(
+            sqlx::query_scalar("SELECT id FROM canary_serial_ids ORDER BY id")
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
     /// Initialize the database with random shares and masks. Cleans up the db
     /// before inserting new generated irises.
     pub async fn init_db_with_random_shares(
@@ -549,7 +693,13 @@ mod tests {
 
     use super::*;
     use futures::TryStreamExt;
-    use iris_mpc_common::helpers::smpc_response::UniquenessResult;
+    use iris_mpc_common::{
+        helpers::{
+            share_audit::{find_inconsistent, ShareAuditChallenge, ShareAuditContribution},
+            smpc_response::UniquenessResult,
+        },
+        id::PartyID,
+    };
 
     #[tokio::test]
     async fn test_store() -> Result<()> {
@@ -712,6 +862,43 @@ mod tests {
         cleanup(&store, &schema_name).await
     }
 
+    #[tokio::test]
+    async fn test_fetch_irises_for_share_audit() -> Result<()> {
+        let challenge = ShareAuditChallenge::new(&[0; 32], 0);
+        let mut contributions = vec![];
+        for party_id in 0..3 {
+            let schema_name = temporary_name();
+            let store = Store::new(&test_db_url()?, &schema_name).await?;
+            store
+                .init_db_with_random_shares(0, party_id, 10, true)
+                .await?;
+
+            let irises = store.fetch_irises(&[2, 5, 9, 11]).await?;
+            assert_eq!(
+                irises.iter().map(|iris| iris.id()).collect::<Vec<_>>(),
+                vec![2, 5, 9]
+            );
+            // The stored shares of the parties are consistent.
+            contributions.push(ShareAuditContribution::new(
+                &challenge,
+                PartyID::try_from(party_id)?,
+                irises.iter().map(|iris| {
+                    (iris.id() as u32, vec![
+                        iris.left_code(),
+                        iris.left_mask(),
+                        iris.right_code(),
+                        iris.right_mask(),
+                    ])
+                }),
+                || 0,
+            ));
+
+            cleanup(&store, &schema_name).await?;
+        }
+        assert_eq!(find_inconsistent(&contributions)?, Vec::<u32>::new());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_rollback() -> Result<()> {
         let schema_name = temporary_name();
@@ -742,6 +929,42 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_modification_watermark() -> Result<()> {
+        let schema_name = temporary_name();
+        let store = Store::new(&test_db_url()?, &schema_name).await?;
+
+        let iris = |id| StoredIrisRef {
+            id,
+            left_code: &[123_u16; 12800],
+            left_mask: &[456_u16; 6400],
+            right_code: &[789_u16; 12800],
+            right_mask: &[101_u16; 6400],
+        };
+        let mut tx = store.tx().await?;
+        store.insert_irises(&mut tx, &[iris(1), iris(2)]).await?;
+        tx.commit().await?;
+
+        let (count, last_modified_at) = store.modification_watermark(2).await?;
+        assert_eq!(count, 2);
+        assert!(last_modified_at > 0);
+
+        // Newer entries do not change the watermark of the older ones.
+        let mut tx = store.tx().await?;
+        store.insert_irises(&mut tx, &[iris(3)]).await?;
+        tx.commit().await?;
+        assert_eq!(
+            store.modification_watermark(2).await?,
+            (2, last_modified_at)
+        );
+
+        store.rollback(1).await?;
+        assert_eq!(store.modification_watermark(2).await?.0, 1);
+
+        cleanup(&store, &schema_name).await?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_results() -> Result<()> {
         let schema_name = temporary_name();
@@ -781,6 +1004,100 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_canary_serial_ids() -> Result<()> {
+        let schema_name = temporary_name();
+        let store = Store::new(&test_db_url()?, &schema_name).await?;
+
+        let mut tx = store.tx().await?;
+        store
+            .tag_canary_serial_ids(&mut tx, &[
+                (3, "canary-0-enroll".to_string()),
+                (1, "canary-0-probe".to_string()),
+            ])
+            .await?;
+        // Tagging again after a restart is a no-op.
+        store
+            .tag_canary_serial_ids(&mut tx, &[(3, "canary-0-enroll".to_string())])
+            .await?;
+        tx.commit().await?;
+        assert_eq!(store.canary_serial_ids().await?, vec![1, 3]);
+
+        store.rollback(2).await?;
+        assert_eq!(store.canary_serial_ids().await?, vec![1]);
+
+        cleanup(&store, &schema_name).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_share_hashes_replay() -> Result<()> {
+        const WINDOW: i64 = 3600;
+        let hashes = ["a", "b", "c"].map(|h| h.repeat(64));
+
+        // One store per party, all seeing the same requests.
+        let mut stores = vec![];
+        for _ in 0..3 {
+            let schema_name = temporary_name();
+            stores.push((
+                Store::new(&test_db_url()?, &schema_name).await?,
+                schema_name,
+            ));
+        }
+
+        for (store, _) in &stores {
+            // The original enrollment, and a redelivery of it.
+            assert!(
+                !store
+                    .record_share_hashes(&hashes, "signup-1", 1000, WINDOW)
+                    .await?
+            );
+            assert!(
+                !store
+                    .record_share_hashes(&hashes, "signup-1", 1010, WINDOW)
+                    .await?
+            );
+            // The same shares under a new signup id within the window.
+            assert!(
+                store
+                    .record_share_hashes(&hashes, "signup-2", 1020, WINDOW)
+                    .await?
+            );
+            assert!(
+                store
+                    .record_share_hashes(&hashes, "signup-2", 1000 + WINDOW - 1, WINDOW)
+                    .await?
+            );
+            // Other shares are not affected.
+            let other = ["d", "e", "f"].map(|h| h.repeat(64));
+            assert!(
+                !store
+                    .record_share_hashes(&other, "signup-3", 1030, WINDOW)
+                    .await?
+            );
+        }
+
+        for (store, _) in &stores {
+            // Once the window has passed, the shares can be enrolled again.
+            assert!(
+                !store
+                    .record_share_hashes(&hashes, "signup-4", 1000 + WINDOW, WINDOW)
+                    .await?
+            );
+            assert!(
+                store
+                    .record_share_hashes(&hashes, "signup-5", 1000 + WINDOW, WINDOW)
+                    .await?
+            );
+            assert_eq!(store.prune_share_hashes(1000 + WINDOW).await?, 1);
+        }
+
+        for (store, schema_name) in &stores {
+            cleanup(store, schema_name).await?;
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_insert_left_right() -> Result<()> {
         let schema_name = temporary_name();
@@ -921,6 +1238,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_soft_delete() -> Result<()> {
+        let schema_name = temporary_name();
+        let store = Store::new(&test_db_url()?, &schema_name).await?;
+
+        let codes_and_masks = (1..=3)
+            .map(|id| StoredIrisRef {
+                id,
+                left_code: &[123_u16; 12800],
+                left_mask: &[456_u16; 6400],
+                right_code: &[789_u16; 12800],
+                right_mask: &[101_u16; 6400],
+            })
+            .collect::<Vec<_>>();
+        let mut tx = store.tx().await?;
+        store.insert_irises(&mut tx, &codes_and_masks).await?;
+        tx.commit().await?;
+
+        assert!(store.soft_delete_iris(2, 1000).await?);
+        assert!(store.soft_delete_iris(3, 2000).await?);
+        assert!(!store.soft_delete_iris(4, 1000).await?);
+        assert_eq!(store.soft_deleted_irises().await?, vec![
+            (2, 1000),
+            (3, 2000)
+        ]);
+
+        // Restoring returns the kept shares, once.
+        let restored = store.restore_iris(2).await?.unwrap();
+        assert_eq!(restored.id(), 2);
+        assert_eq!(restored.left_code(), codes_and_masks[1].left_code);
+        assert_eq!(restored.right_mask(), codes_and_masks[1].right_mask);
+        assert!(store.restore_iris(2).await?.is_none());
+        assert!(store.restore_iris(1).await?.is_none());
+
+        // Purging overwrites the shares and clears the flag.
+        let dummy_code = GaloisRingIrisCodeShare {
+            id:    3,
+            coefs: [0_u16; 12800],
+        };
+        let dummy_mask = GaloisRingTrimmedMaskCodeShare {
+            id:    3,
+            coefs: [0_u16; 6400],
+        };
+        store
+            .update_iris(3, &dummy_code, &dummy_mask, &dummy_code, &dummy_mask)
+            .await?;
+        assert!(store.soft_deleted_irises().await?.is_empty());
+        assert!(store.restore_iris(3).await?.is_none());
+        let got = store.fetch_irises(&[3]).await?;
+        assert_eq!(got[0].left_code(), &dummy_code.coefs[..]);
+
+        cleanup(&store, &schema_name).await?;
+        Ok(())
+    }
+
     fn test_db_url() -> Result<String> {
         dotenvy::from_filename(DOTENV_TEST)?;
         Ok(Config::load_config(APP_NAME)?
@@ -1,8 +1,8 @@
 use clap::Parser;
 use futures::StreamExt;
 use iris_mpc_common::{
-    galois::degree4::{basis::Monomial, GaloisRingElement, ShamirGaloisRingShare},
-    galois_engine::degree4::GaloisRingIrisCodeShare,
+    galois::degree4::{basis::Monomial, GaloisRingElement},
+    galois_engine::degree4::{lagrange_coeffs, GaloisRingIrisCodeShare},
     id::PartyID,
     IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
 };
@@ -162,10 +162,8 @@ async fn main() -> eyre::Result<()> {
         assert_eq!(iris0.id(), iris1.id());
         assert_eq!(iris1.id(), iris2.id());
 
-        let poly01 =
-            ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID0, PartyID::ID1);
-        let poly10 =
-            ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID1, PartyID::ID0);
+        let lagrange = lagrange_coeffs(&[PartyID::ID0, PartyID::ID1]);
+        let (poly01, poly10) = (lagrange[0], lagrange[1]);
 
         let enc_left_bits =
             recombine_enc_bits(iris0.left_code(), iris1.left_code(), poly01, poly10);
@@ -176,10 +174,8 @@ async fn main() -> eyre::Result<()> {
             poly10,
         ));
 
-        let poly12 =
-            ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID1, PartyID::ID2);
-        let poly21 =
-            ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID2, PartyID::ID1);
+        let lagrange = lagrange_coeffs(&[PartyID::ID1, PartyID::ID2]);
+        let (poly12, poly21) = (lagrange[0], lagrange[1]);
 
         let enc_left_bits1 =
             recombine_enc_bits(iris1.left_code(), iris2.left_code(), poly12, poly21);
@@ -193,10 +189,8 @@ async fn main() -> eyre::Result<()> {
         assert_eq!(enc_left_bits, enc_left_bits1);
         assert_eq!(enc_left_masks, enc_left_masks1);
 
-        let poly20 =
-            ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID2, PartyID::ID0);
-        let poly02 =
-            ShamirGaloisRingShare::deg_1_lagrange_polys_at_zero(PartyID::ID0, PartyID::ID2);
+        let lagrange = lagrange_coeffs(&[PartyID::ID2, PartyID::ID0]);
+        let (poly20, poly02) = (lagrange[0], lagrange[1]);
 
         let enc_left_bits2 =
             recombine_enc_bits(iris2.left_code(), iris0.left_code(), poly20, poly02);
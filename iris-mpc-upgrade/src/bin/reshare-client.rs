@@ -56,9 +56,9 @@ async fn main() -> eyre::Result<()> {
     let mut iris_stream_chunks = iris_stream.chunks(config.batch_size as usize);
 
     let mut iris_reshare_helper = IrisCodeReshareSenderHelper::new(
-        config.party_id as usize,
-        config.other_party_id as usize,
-        config.target_party_id as usize,
+        config.party_id.into(),
+        config.other_party_id.into(),
+        config.target_party_id.into(),
         common_seed,
     );
 
@@ -100,19 +100,19 @@ async fn main() -> eyre::Result<()> {
             iris_reshare_helper.add_reshare_iris_to_batch(
                 iris_code.id(),
                 GaloisRingIrisCodeShare {
-                    id:    config.party_id as usize + 1,
+                    id:    config.party_id.share_id(),
                     coefs: iris_code.left_code().try_into().unwrap(),
                 },
                 GaloisRingTrimmedMaskCodeShare {
-                    id:    config.party_id as usize + 1,
+                    id:    config.party_id.share_id(),
                     coefs: iris_code.left_mask().try_into().unwrap(),
                 },
                 GaloisRingIrisCodeShare {
-                    id:    config.party_id as usize + 1,
+                    id:    config.party_id.share_id(),
                     coefs: iris_code.right_code().try_into().unwrap(),
                 },
                 GaloisRingTrimmedMaskCodeShare {
-                    id:    config.party_id as usize + 1,
+                    id:    config.party_id.share_id(),
                     coefs: iris_code.right_mask().try_into().unwrap(),
                 },
             );
@@ -38,9 +38,9 @@ async fn main() -> eyre::Result<()> {
     let store = Store::new(&config.db_url, &schema_name).await?;
 
     let receiver_helper = IrisCodeReshareReceiverHelper::new(
-        config.party_id as usize,
-        config.sender1_party_id as usize,
-        config.sender2_party_id as usize,
+        config.party_id.into(),
+        config.sender1_party_id.into(),
+        config.sender2_party_id.into(),
         config.max_buffer_size,
     );
 
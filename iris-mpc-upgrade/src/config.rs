@@ -138,15 +138,15 @@ pub struct ReShareClientConfig {
 
     /// the 0-indexed party ID of the client party
     #[clap(long)]
-    pub party_id: u8,
+    pub party_id: PartyID,
 
     /// the 0-indexed party ID of the other client party
     #[clap(long)]
-    pub other_party_id: u8,
+    pub other_party_id: PartyID,
 
     /// the 0-indexed party ID of the receiving party
     #[clap(long)]
-    pub target_party_id: u8,
+    pub target_party_id: PartyID,
 
     /// The batch size to use when sending reshare messages (i.e., how many iris
     /// code DB entries per message)
@@ -190,17 +190,17 @@ pub struct ReShareServerConfig {
 
     /// The 0-indexed party ID of the server party
     #[clap(long)]
-    pub party_id: u8,
+    pub party_id: PartyID,
 
     /// The 0-indexed party ID of the first client party (order of the two
     /// client parties does not matter)
     #[clap(long)]
-    pub sender1_party_id: u8,
+    pub sender1_party_id: PartyID,
 
     /// The 0-indexed party ID of the second client party (order of the two
     /// client parties does not matter)
     #[clap(long)]
-    pub sender2_party_id: u8,
+    pub sender2_party_id: PartyID,
 
     /// The maximum allowed batch size for reshare messages
     #[clap(long)]
@@ -0,0 +1,175 @@
+//! Bulk-enrolls identities whose shares already exist, see
+//! [`iris_mpc_common::helpers::backfill`].
+//!
+//! Every party runs the tool against its own server with its own shares, read
+//! from a share file or from the `irises` table of another database. The
+//! position of the next entry is stored in the checkpoint file after every
+//! accepted chunk, and a restarted backfill continues from there.
+
+use clap::Parser;
+use eyre::{bail, Context};
+use futures::TryStreamExt;
+use iris_mpc_common::helpers::backfill::{
+    read_share_file, BackfillCheckpoint, BackfillEntry, BackfillResponse, RateLimiter,
+    BACKFILL_PATH,
+};
+use iris_mpc_store::Store;
+use std::{path::PathBuf, time::Duration};
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Base URL of the backfill endpoint of the server, see
+    /// `backfill_listen_address` in the server config.
+    #[arg(long, env)]
+    server_url: String,
+
+    /// The party whose shares are sent.
+    #[arg(long, env)]
+    party_id: usize,
+
+    /// File holding one JSON encoded entry per line.
+    #[arg(long, env, conflicts_with = "database_url")]
+    share_file: Option<PathBuf>,
+
+    /// Database whose `irises` table holds the shares, keyed by serial id.
+    #[arg(long, env)]
+    database_url: Option<String>,
+
+    #[arg(long, env, default_value = "SMPC")]
+    schema_name: String,
+
+    #[arg(long, env)]
+    checkpoint: PathBuf,
+
+    /// Maximum number of entries sent per second, 0 for no limit.
+    #[arg(long, env, default_value_t = 100.0)]
+    rate: f64,
+
+    #[arg(long, env, default_value_t = 64)]
+    chunk_size: usize,
+
+    /// How long to wait before sending entries the server refused.
+    #[arg(long, env, default_value_t = 1000)]
+    retry_delay_ms: u64,
+}
+
+struct Backfill {
+    client:      reqwest::Client,
+    url:         String,
+    checkpoint:  BackfillCheckpoint,
+    path:        PathBuf,
+    limiter:     RateLimiter,
+    chunk_size:  usize,
+    retry_delay: Duration,
+    /// Entries that were not sent yet, with their position in the source.
+    pending:     Vec<(u64, BackfillEntry)>,
+}
+
+impl Backfill {
+    async fn push(&mut self, position: u64, entry: BackfillEntry) -> eyre::Result<()> {
+        self.pending.push((position, entry));
+        if self.pending.len() >= self.chunk_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the pending entries until the server accepted all of them.
+    async fn flush(&mut self) -> eyre::Result<()> {
+        self.limiter.acquire(self.pending.len()).await;
+        while !self.pending.is_empty() {
+            let entries = self
+                .pending
+                .iter()
+                .map(|(_, entry)| entry)
+                .collect::<Vec<_>>();
+            let response = self
+                .client
+                .post(&self.url)
+                .json(&entries)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .context("Failed to send backfill entries")?;
+            let BackfillResponse { accepted } = response.json().await?;
+            if accepted > self.pending.len() {
+                bail!(
+                    "The server accepted {} of {} entries",
+                    accepted,
+                    entries.len()
+                );
+            }
+            if accepted > 0 {
+                let last = self.pending[accepted - 1].0;
+                self.pending.drain(..accepted);
+                self.checkpoint.next = last + 1;
+                self.checkpoint.accepted += accepted as u64;
+                self.checkpoint.save(&self.path)?;
+                println!(
+                    "Accepted {} entries, resuming at {}",
+                    self.checkpoint.accepted, self.checkpoint.next
+                );
+            }
+            if !self.pending.is_empty() {
+                // The queue of the server is full.
+                tokio::time::sleep(self.retry_delay).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let opt = Opt::parse();
+    let checkpoint = BackfillCheckpoint::load(&opt.checkpoint)?;
+    println!(
+        "Starting at {} with {} entries accepted before",
+        checkpoint.next, checkpoint.accepted
+    );
+    let mut backfill = Backfill {
+        client: reqwest::Client::new(),
+        url: format!("{}{}", opt.server_url.trim_end_matches('/'), BACKFILL_PATH),
+        checkpoint,
+        path: opt.checkpoint.clone(),
+        limiter: RateLimiter::new(opt.rate),
+        chunk_size: opt.chunk_size.max(1),
+        retry_delay: Duration::from_millis(opt.retry_delay_ms),
+        pending: vec![],
+    };
+
+    match (&opt.share_file, &opt.database_url) {
+        (Some(path), None) => {
+            for entry in read_share_file(path, checkpoint.next as usize)? {
+                let (line, entry) = entry?;
+                // A share file of another party would only fail at the server.
+                entry
+                    .decode(opt.party_id)
+                    .with_context(|| format!("Invalid entry at line {}", line + 1))?;
+                backfill.push(line as u64, entry).await?;
+            }
+        }
+        (None, Some(url)) => {
+            let store = Store::new(url, &opt.schema_name).await?;
+            // Serial ids start at 1.
+            let start = checkpoint.next.max(1);
+            let mut irises = store.stream_irises_in_range(start..i64::MAX as u64);
+            while let Some(iris) = irises.try_next().await? {
+                let entry = BackfillEntry::from_stored(
+                    &iris.id().to_string(),
+                    opt.party_id,
+                    (iris.left_code(), iris.left_mask()),
+                    (iris.right_code(), iris.right_mask()),
+                )?;
+                backfill.push(iris.id() as u64, entry).await?;
+            }
+        }
+        _ => bail!("Exactly one of --share-file and --database-url is required"),
+    }
+    backfill.flush().await?;
+    println!(
+        "Backfill done, {} entries accepted",
+        backfill.checkpoint.accepted
+    );
+    Ok(())
+}
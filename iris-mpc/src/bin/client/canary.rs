@@ -0,0 +1,186 @@
+//! The end-to-end canary, selected with `--canary`.
+//!
+//! Runs the rounds of [`Canary`] against the deployment: requests are
+//! published to the request topic like the load test does, and every step
+//! waits until all parties sent their result to the response queue. The
+//! parties must agree on every result.
+
+use super::{publish_request, seal_shares, share_files, ResultEvent};
+use async_trait::async_trait;
+use aws_sdk_sns::Client;
+use eyre::{bail, Context};
+use iris_mpc_common::{
+    helpers::{
+        canary::{Canary, CanaryTarget},
+        key_pair::PublicKeySet,
+        results_consumer::ResultStream,
+        smpc_request::{
+            IdentityDeletionRequest, UniquenessRequest, IDENTITY_DELETION_MESSAGE_TYPE,
+            UNIQUENESS_MESSAGE_TYPE,
+        },
+        smpc_response::{IdentityDeletionResult, UniquenessResult},
+        sqs_s3_helper::upload_file_and_generate_presigned_url,
+    },
+    iris_db::iris::IrisCode,
+};
+use rand::{rngs::StdRng, SeedableRng};
+use serde::Serialize;
+use serde_json::to_string;
+use std::{fmt, time::Duration};
+
+const N_PARTIES: usize = 3;
+
+pub struct CanaryClient {
+    pub requests_sns_client: Client,
+    pub request_topic_arn: String,
+    pub results: ResultStream,
+    pub requests_bucket_name: String,
+    pub requests_bucket_region: String,
+    pub shares_encryption_public_keys: PublicKeySet,
+    /// How long a step waits for the results of all parties.
+    pub timeout: Duration,
+}
+
+impl CanaryClient {
+    /// Receives results until every party sent one that `pick` accepts.
+    /// Other results are stale and deleted.
+    async fn await_results<T>(
+        &mut self,
+        pick: impl Fn(ResultEvent) -> Option<eyre::Result<T>>,
+    ) -> eyre::Result<Vec<T>> {
+        let mut picked = vec![];
+        let receive = async {
+            while picked.len() < N_PARTIES {
+                let Some(received) = self.results.next_result().await else {
+                    bail!("The result stream was shut down");
+                };
+                let received = received.context("Failed to receive result")?;
+                let event = ResultEvent::from_message(received.message.clone());
+                received.ack().await?;
+                match event.and_then(&pick) {
+                    Some(result) => picked.push(result?),
+                    None => eprintln!("Dropping stale result"),
+                }
+            }
+            Ok(())
+        };
+        tokio::time::timeout(self.timeout, receive)
+            .await
+            .context("Timed out waiting for the results of all parties")??;
+        Ok(picked)
+    }
+}
+
+/// Fails unless all parties sent the same result, ignoring their node ids.
+fn agreed<T: Serialize + fmt::Debug>(mut results: Vec<T>) -> eyre::Result<T> {
+    let without_node_id = |result: &T| {
+        let mut value = serde_json::to_value(result)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("node_id");
+        }
+        eyre::Ok(value)
+    };
+    let first = without_node_id(&results[0])?;
+    for result in results.iter() {
+        if without_node_id(result)? != first {
+            bail!("The parties disagree: {:?}", results);
+        }
+    }
+    Ok(results.swap_remove(0))
+}
+
+#[async_trait]
+impl CanaryTarget for CanaryClient {
+    async fn uniqueness(
+        &mut self,
+        signup_id: &str,
+        left: IrisCode,
+        right: IrisCode,
+    ) -> eyre::Result<UniquenessResult> {
+        let mut rng = StdRng::from_entropy();
+        let left = super::self_check::encode_template(&left, &mut rng);
+        let right = super::self_check::encode_template(&right, &mut rng);
+        let shares = share_files(&left, &right);
+        let (iris_codes_shares_base64, iris_shares_file_hashes) =
+            seal_shares(&shares, &self.shares_encryption_public_keys);
+        let s3_key = upload_file_and_generate_presigned_url(
+            &self.requests_bucket_name,
+            signup_id,
+            Box::leak(self.requests_bucket_region.clone().into_boxed_str()),
+            &serde_json::to_vec(&iris_codes_shares_base64)?,
+        )
+        .await
+        .context("Failed to upload the shares")?;
+
+        let request = UniquenessRequest {
+            batch_size: None,
+            signup_id: signup_id.to_string(),
+            s3_key,
+            iris_shares: None,
+            iris_shares_file_hashes,
+            mirrored_check: None,
+            rotation_window: None,
+            or_rule_serial_ids: None,
+        };
+        publish_request(
+            &self.requests_sns_client,
+            &self.request_topic_arn,
+            UNIQUENESS_MESSAGE_TYPE,
+            to_string(&request)?,
+        )
+        .await?;
+
+        let results = self
+            .await_results(|event| match event {
+                ResultEvent::Uniqueness(result) if result.signup_id == signup_id => {
+                    Some(Ok(result))
+                }
+                ResultEvent::UniquenessFailure {
+                    node_id,
+                    signup_id: id,
+                    reason,
+                    error_code,
+                    ..
+                } if id == signup_id => Some(Err(eyre::eyre!(
+                    "Party {} failed to process the request: {} ({:?})",
+                    node_id,
+                    reason,
+                    error_code
+                ))),
+                _ => None,
+            })
+            .await?;
+        agreed(results)
+    }
+
+    async fn delete(&mut self, serial_id: u32) -> eyre::Result<IdentityDeletionResult> {
+        publish_request(
+            &self.requests_sns_client,
+            &self.request_topic_arn,
+            IDENTITY_DELETION_MESSAGE_TYPE,
+            to_string(&IdentityDeletionRequest {
+                serial_id,
+                grace_period_secs: None,
+            })?,
+        )
+        .await?;
+        let results = self
+            .await_results(|event| match event {
+                ResultEvent::IdentityDeletion(result) if result.serial_id == serial_id => {
+                    Some(Ok(result))
+                }
+                _ => None,
+            })
+            .await?;
+        agreed(results)
+    }
+}
+
+/// Runs canary rounds every `interval` until interrupted.
+pub async fn run(client: &mut CanaryClient, canary: &mut Canary, interval: Duration) {
+    canary
+        .run(client, interval, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+}
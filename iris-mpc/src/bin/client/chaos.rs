@@ -0,0 +1,418 @@
+//! Chaos scenarios of the test client, selected with `--chaos`.
+//!
+//! Every scenario sends something the server cannot process and waits for the
+//! documented reaction: a failure result with a specific reason, or a silent
+//! drop that only shows in the metrics of the server. To add a scenario, add a
+//! variant to [`ChaosScenario`] and return what it expects from
+//! [`ChaosScenario::send`].
+
+use super::{publish_request, seal_shares, share_template, ResultEvent, ENROLLMENT_REQUEST_TYPE};
+use aws_sdk_sns::Client;
+use aws_sdk_sqs::Client as SqsClient;
+use clap::ValueEnum;
+use eyre::Context;
+use iris_mpc_common::{
+    helpers::{
+        key_pair::PublicKeySet,
+        smpc_request::{
+            IdentityDeletionRequest, UniquenessRequest, IDENTITY_DELETION_MESSAGE_TYPE,
+            UNIQUENESS_MESSAGE_TYPE,
+        },
+        smpc_response::{UniquenessResult, ERROR_FAILED_TO_PROCESS_IRIS_SHARES},
+        sqs::BatchReceiver,
+        sqs_s3_helper::{generate_presigned_url, upload_file_and_generate_presigned_url},
+    },
+    iris_db::iris::IrisCode,
+};
+use rand::{rngs::StdRng, SeedableRng};
+use serde_json::to_string;
+use std::{collections::BTreeSet, time::Duration};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+const N_PARTIES: usize = 3;
+/// A serial id beyond any DB.
+const UNKNOWN_SERIAL_ID: u32 = u32::MAX;
+/// The party whose share file differs in [`ChaosScenario::DivergentShares`].
+const DIVERGENT_PARTY: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChaosScenario {
+    /// A result with an unknown signup id in the response queue. The client
+    /// drops it as stale.
+    StaleResult,
+    /// A uniqueness request whose presigned URL 404s. Every party sends a
+    /// `failed_to_process_iris_shares` result.
+    MissingShares,
+    /// A deletion of a serial id that does not exist. Every party sends an
+    /// unsuccessful deletion result and counts
+    /// `identity_deletion.unknown_serial_id`.
+    UnknownDeletion,
+    /// A uniqueness request whose share files disagree in one field. The party
+    /// holding the odd file sends a `failed_to_process_iris_shares` result,
+    /// the others drop the request and count `batch.entry_dropped`.
+    DivergentShares,
+}
+
+impl ChaosScenario {
+    pub const ALL: [Self; 4] = [
+        Self::StaleResult,
+        Self::MissingShares,
+        Self::UnknownDeletion,
+        Self::DivergentShares,
+    ];
+
+    fn name(&self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Sends the scenario, returns the results it expects.
+    async fn send(&self, client: &ChaosClient) -> eyre::Result<Expected> {
+        let signup_id = Uuid::new_v4().to_string();
+        match self {
+            Self::StaleResult => {
+                let result = UniquenessResult::new(
+                    0,
+                    Some(1),
+                    false,
+                    signup_id.clone(),
+                    None,
+                    None,
+                    None,
+                    None,
+                );
+                client.send_result(to_string(&result)?).await?;
+                Ok(Expected::Stale {
+                    signup_id,
+                    received: false,
+                })
+            }
+            Self::MissingShares => {
+                let s3_key = generate_presigned_url(
+                    &client.requests_bucket_name,
+                    &signup_id,
+                    client.bucket_region(),
+                )
+                .await?;
+                let request = UniquenessRequest {
+                    batch_size: None,
+                    signup_id: signup_id.clone(),
+                    s3_key,
+                    iris_shares: None,
+                    iris_shares_file_hashes: Default::default(),
+                    mirrored_check: None,
+                    rotation_window: None,
+                    or_rule_serial_ids: None,
+                };
+                client
+                    .publish(UNIQUENESS_MESSAGE_TYPE, to_string(&request)?)
+                    .await?;
+                Ok(Expected::failures(signup_id, (0..N_PARTIES).collect()))
+            }
+            Self::UnknownDeletion => {
+                let request = IdentityDeletionRequest {
+                    serial_id:         UNKNOWN_SERIAL_ID,
+                    grace_period_secs: None,
+                };
+                client
+                    .publish(IDENTITY_DELETION_MESSAGE_TYPE, to_string(&request)?)
+                    .await?;
+                Ok(Expected::FailedDeletions {
+                    serial_id: UNKNOWN_SERIAL_ID,
+                    parties:   BTreeSet::new(),
+                })
+            }
+            Self::DivergentShares => {
+                let mut rng = StdRng::from_entropy();
+                let mut shares = share_template(&IrisCode::random_rng(&mut rng), &mut rng);
+                let (_, iris_shares_file_hashes) =
+                    seal_shares(&shares, &client.shares_encryption_public_keys);
+                // The hashes are those of the original files.
+                shares[DIVERGENT_PARTY].iris_shares_version = "0.0".to_string();
+                let (iris_codes_shares_base64, _) =
+                    seal_shares(&shares, &client.shares_encryption_public_keys);
+
+                let s3_key = upload_file_and_generate_presigned_url(
+                    &client.requests_bucket_name,
+                    &signup_id,
+                    client.bucket_region(),
+                    &serde_json::to_vec(&iris_codes_shares_base64)?,
+                )
+                .await?;
+                let request = UniquenessRequest {
+                    batch_size: None,
+                    signup_id: signup_id.clone(),
+                    s3_key,
+                    iris_shares: None,
+                    iris_shares_file_hashes,
+                    mirrored_check: None,
+                    rotation_window: None,
+                    or_rule_serial_ids: None,
+                };
+                client
+                    .publish(UNIQUENESS_MESSAGE_TYPE, to_string(&request)?)
+                    .await?;
+                Ok(Expected::failures(signup_id, [DIVERGENT_PARTY].into()))
+            }
+        }
+    }
+}
+
+pub struct ChaosClient {
+    pub requests_sns_client:           Client,
+    pub request_topic_arn:             String,
+    pub results_sqs_client:            SqsClient,
+    pub response_queue_url:            String,
+    pub results_receiver:              BatchReceiver,
+    pub requests_bucket_name:          String,
+    pub requests_bucket_region:        String,
+    pub shares_encryption_public_keys: PublicKeySet,
+}
+
+impl ChaosClient {
+    fn bucket_region(&self) -> &'static str {
+        Box::leak(self.requests_bucket_region.clone().into_boxed_str())
+    }
+
+    async fn publish(&self, message_type: &str, message: String) -> eyre::Result<()> {
+        publish_request(
+            &self.requests_sns_client,
+            &self.request_topic_arn,
+            message_type,
+            message,
+        )
+        .await
+    }
+
+    /// Puts a result into the response queue, as if a party had sent it.
+    async fn send_result(&self, message: String) -> eyre::Result<()> {
+        let mut request = self
+            .results_sqs_client
+            .send_message()
+            .queue_url(&self.response_queue_url)
+            .message_body(message);
+        if self.response_queue_url.ends_with(".fifo") {
+            request = request
+                .message_group_id(ENROLLMENT_REQUEST_TYPE)
+                .message_deduplication_id(Uuid::new_v4().to_string());
+        }
+        request.send().await.context("Failed to send result")?;
+        Ok(())
+    }
+}
+
+/// The results a scenario waits for.
+#[derive(Debug)]
+enum Expected {
+    /// The result with this signup id, which the client drops as stale.
+    Stale { signup_id: String, received: bool },
+    /// Failure results for the request from the given parties, and no result
+    /// at all from the other parties.
+    UniquenessFailures {
+        signup_id: String,
+        expected:  BTreeSet<usize>,
+        received:  BTreeSet<usize>,
+    },
+    /// Unsuccessful deletion results from all parties.
+    FailedDeletions {
+        serial_id: u32,
+        parties:   BTreeSet<usize>,
+    },
+}
+
+/// How a received result relates to the running scenario.
+enum Observation {
+    Unrelated,
+    Expected,
+    Unexpected(String),
+}
+
+impl Expected {
+    fn failures(signup_id: String, expected: BTreeSet<usize>) -> Self {
+        Self::UniquenessFailures {
+            signup_id,
+            expected,
+            received: BTreeSet::new(),
+        }
+    }
+
+    fn observe(&mut self, event: &ResultEvent) -> Observation {
+        match (self, event) {
+            (
+                Self::Stale {
+                    signup_id,
+                    received,
+                },
+                ResultEvent::Uniqueness(result),
+            ) if &result.signup_id == signup_id => {
+                *received = true;
+                Observation::Expected
+            }
+            (
+                Self::UniquenessFailures {
+                    signup_id,
+                    expected,
+                    received,
+                },
+                ResultEvent::UniquenessFailure {
+                    node_id,
+                    signup_id: id,
+                    reason,
+                    ..
+                },
+            ) if id == signup_id => {
+                if !expected.contains(node_id) {
+                    Observation::Unexpected(format!("failure result from party {}", node_id))
+                } else if reason != ERROR_FAILED_TO_PROCESS_IRIS_SHARES {
+                    Observation::Unexpected(format!("failure reason {}", reason))
+                } else if !received.insert(*node_id) {
+                    Observation::Unexpected(format!("second result from party {}", node_id))
+                } else {
+                    Observation::Expected
+                }
+            }
+            (Self::UniquenessFailures { signup_id, .. }, ResultEvent::Uniqueness(result))
+                if &result.signup_id == signup_id =>
+            {
+                Observation::Unexpected(format!("uniqueness result from party {}", result.node_id))
+            }
+            (
+                Self::FailedDeletions { serial_id, parties },
+                ResultEvent::IdentityDeletion(result),
+            ) if result.serial_id == *serial_id => {
+                if result.success {
+                    Observation::Unexpected(format!(
+                        "successful deletion at party {}",
+                        result.node_id
+                    ))
+                } else if !parties.insert(result.node_id) {
+                    Observation::Unexpected(format!("second result from party {}", result.node_id))
+                } else {
+                    Observation::Expected
+                }
+            }
+            _ => Observation::Unrelated,
+        }
+    }
+
+    /// Whether all expected results arrived.
+    fn is_complete(&self) -> bool {
+        match self {
+            Self::Stale { received, .. } => *received,
+            Self::UniquenessFailures {
+                expected, received, ..
+            } => expected == received,
+            Self::FailedDeletions { parties, .. } => parties.len() == N_PARTIES,
+        }
+    }
+
+    /// Whether the scenario also expects some parties to stay silent, which
+    /// can only be confirmed at the timeout.
+    fn expects_silence(&self) -> bool {
+        matches!(self, Self::UniquenessFailures { expected, .. } if expected.len() < N_PARTIES)
+    }
+}
+
+struct Outcome {
+    scenario: ChaosScenario,
+    passed:   bool,
+    elapsed:  Duration,
+    detail:   String,
+}
+
+/// Runs the scenarios one after the other and prints a table of their
+/// outcomes. Fails if any scenario failed.
+pub async fn run(
+    client: &ChaosClient,
+    scenarios: &[ChaosScenario],
+    timeout: Duration,
+) -> eyre::Result<()> {
+    let mut outcomes = vec![];
+    for &scenario in scenarios {
+        println!("Running chaos scenario {}", scenario.name());
+        let start = Instant::now();
+        let (passed, detail) = match scenario.send(client).await {
+            Ok(expected) => await_expected(client, expected, timeout).await?,
+            Err(e) => (false, format!("failed to send: {:?}", e)),
+        };
+        outcomes.push(Outcome {
+            scenario,
+            passed,
+            elapsed: start.elapsed(),
+            detail,
+        });
+    }
+
+    println!();
+    println!("{:<20} {:<6} {:>8}  detail", "scenario", "result", "time");
+    for outcome in outcomes.iter() {
+        println!(
+            "{:<20} {:<6} {:>7.1}s  {}",
+            outcome.scenario.name(),
+            if outcome.passed { "PASS" } else { "FAIL" },
+            outcome.elapsed.as_secs_f64(),
+            outcome.detail
+        );
+    }
+
+    let failed = outcomes.iter().filter(|outcome| !outcome.passed).count();
+    if failed > 0 {
+        eyre::bail!("{} of {} chaos scenarios failed", failed, outcomes.len());
+    }
+    Ok(())
+}
+
+/// Receives results until the expected ones arrived, or the timeout elapsed.
+/// Results of earlier runs are deleted as stale.
+async fn await_expected(
+    client: &ChaosClient,
+    mut expected: Expected,
+    timeout: Duration,
+) -> eyre::Result<(bool, String)> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if expected.is_complete() && !expected.expects_silence() {
+            return Ok((true, String::new()));
+        }
+        let messages = client
+            .results_receiver
+            .receive_until(deadline)
+            .await
+            .context("Failed to receive message")?;
+
+        let observations = messages
+            .iter()
+            .map(|message| match ResultEvent::parse(message) {
+                Ok(event) => expected.observe(&event),
+                Err(e) => {
+                    eprintln!("Dropping unreadable result: {:?}", e);
+                    Observation::Unrelated
+                }
+            })
+            .collect::<Vec<_>>();
+        let receipt_handles = messages
+            .into_iter()
+            .filter_map(|message| message.receipt_handle)
+            .collect::<Vec<_>>();
+        client
+            .results_receiver
+            .delete_batch(&receipt_handles)
+            .await
+            .context("Failed to delete messages")?;
+        for observation in observations {
+            match observation {
+                Observation::Unrelated => eprintln!("Dropping stale result"),
+                Observation::Expected => {}
+                Observation::Unexpected(detail) => return Ok((false, detail)),
+            }
+        }
+    }
+
+    if expected.is_complete() {
+        Ok((true, String::new()))
+    } else {
+        Ok((false, format!("timed out waiting for {:?}", expected)))
+    }
+}
@@ -0,0 +1,941 @@
+#![allow(clippy::needless_range_loop)]
+use aws_config::retry::RetryConfig;
+use aws_sdk_sns::{config::Region, Client};
+use aws_sdk_sqs::{types::Message, Client as SqsClient};
+use canary::CanaryClient;
+use chaos::{ChaosClient, ChaosScenario};
+use clap::{Parser, ValueEnum};
+use eyre::{Context, ContextCompat};
+use iris_mpc_common::{
+    config::MatchPolicy,
+    errors::ErrorCode,
+    helpers::{
+        aws::{
+            construct_message_attributes, publish_batch_with_retry, PublishEntry,
+            PublishRetryPolicy, TraceContext,
+        },
+        canary::{Canary, CANARY_SEED},
+        key_pair::{download_public_key, PublicKeySet},
+        results_consumer::{
+            ResultKind, ResultMessage, ResultStream, ResultStreamConfig, SqsResultQueue,
+        },
+        smpc_request::{
+            IrisCodesJSON, SharesS3Object, UniquenessRequest, IDENTITY_DELETION_MESSAGE_TYPE,
+            UNIQUENESS_MESSAGE_TYPE,
+        },
+        smpc_response::{
+            create_message_type_attribute_map, IdentityDeletionResult, UniquenessResult,
+            SMPC_MESSAGE_TYPE_ATTRIBUTE,
+        },
+        sqs::{BatchReceiver, BatchReceiverConfig, SqsBatchQueue},
+        sqs_s3_helper::upload_file_and_generate_presigned_url,
+    },
+    iris_db::{
+        db::IrisDB,
+        iris::{IrisCode, MATCH_THRESHOLD_RATIO},
+    },
+};
+use metrics_exporter_statsd::StatsdBuilder;
+use rand::{rngs::StdRng, seq::IteratorRandom, thread_rng, Rng, SeedableRng};
+use self_check::{check_shares, encode_template, Encoder, SelfCheckMismatch, TemplateShares};
+use serde_json::to_string;
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    spawn,
+    sync::{Mutex, Semaphore},
+    time::sleep,
+};
+use uuid::Uuid;
+
+mod canary;
+mod chaos;
+mod self_check;
+
+const MAX_CONCURRENT_REQUESTS: usize = 16;
+const BATCH_SIZE: usize = 64;
+const N_BATCHES: usize = 100;
+const N_QUERIES: usize = BATCH_SIZE * N_BATCHES;
+const WAIT_AFTER_BATCH: Duration = Duration::from_secs(2);
+const RNG_SEED_SERVER: u64 = 42;
+const DB_SIZE: usize = 8 * 1_000;
+/// Morph steps and new captures closer than this to the match threshold are
+/// not sent, as the parties compare against a fixed-point approximation of the
+/// threshold.
+const THRESHOLD_MARGIN: f64 = 0.01;
+const ENROLLMENT_REQUEST_TYPE: &str = "enrollment";
+
+#[derive(Debug, Parser)]
+struct Opt {
+    #[arg(long, env, required = true)]
+    request_topic_arn: String,
+
+    #[arg(long, env, required = true)]
+    request_topic_region: String,
+
+    #[arg(long, env, required = true)]
+    response_queue_url: String,
+
+    #[arg(long, env, required = true)]
+    response_queue_region: String,
+
+    #[arg(long, env, required = true)]
+    requests_bucket_name: String,
+
+    #[arg(long, env, required = true)]
+    public_key_base_url: String,
+
+    #[arg(long, env, required = true)]
+    requests_bucket_region: String,
+
+    #[arg(long, env)]
+    db_index: Option<usize>,
+
+    #[arg(long, env)]
+    rng_seed: Option<u64>,
+
+    #[arg(long, env)]
+    n_repeat: Option<usize>,
+
+    #[arg(long, env)]
+    random: Option<bool>,
+
+    /// In random mode, also sends steps of morph sequences of DB entries with
+    /// this many steps, and checks that exactly the steps below the match
+    /// threshold match their DB entry.
+    #[arg(long, env)]
+    morph_steps: Option<usize>,
+
+    /// Fractional Hamming distance of the last step of a morph sequence.
+    #[arg(long, env, default_value_t = 0.5)]
+    morph_max_distance: f64,
+
+    /// In random mode, the probability to flip an unmasked code bit of a DB
+    /// entry sent as a new capture of the same eye.
+    #[arg(long, env, default_value_t = 0.05)]
+    capture_flip_prob: f64,
+
+    /// In random mode, the probability to drop a pair of mask bits of a DB
+    /// entry sent as a new capture of the same eye.
+    #[arg(long, env, default_value_t = 0.05)]
+    capture_mask_drop_prob: f64,
+
+    /// In random mode, also sends requests that share only one eye with a DB
+    /// entry, and checks which eye the parties attribute the match to.
+    #[arg(long, env)]
+    two_eyes: bool,
+
+    /// The match policy of the parties, which decides whether a request with
+    /// one matching eye is a match.
+    #[arg(long, env, value_enum, default_value_t = MatchPolicy::And)]
+    match_policy: MatchPolicy,
+
+    /// Loads the plaintext DB from this file instead of regenerating it, or
+    /// writes the regenerated DB to it if it does not exist yet.
+    #[arg(long, env)]
+    db_file: Option<PathBuf>,
+
+    /// Runs these chaos scenarios instead of the load test, or all of them if
+    /// none is given.
+    #[arg(long, env, value_enum, value_delimiter = ',', num_args = 0.., help_heading = "Chaos")]
+    chaos: Option<Vec<ChaosScenario>>,
+
+    /// Seconds to wait for the results of a chaos scenario.
+    #[arg(long, env, default_value_t = 60, help_heading = "Chaos")]
+    chaos_timeout_secs: u64,
+
+    /// Reconstructs the shares locally before sending a request, and aborts
+    /// the request if they do not decode to the template.
+    #[arg(long, env)]
+    self_check: bool,
+
+    /// Fraction of the requests that are self-checked.
+    #[arg(long, env, default_value_t = 0.1)]
+    self_check_rate: f64,
+
+    /// How the load test sends the shares of a uniqueness request.
+    #[arg(long, env, value_enum, default_value_t = RequestFormat::S3)]
+    request_format: RequestFormat,
+
+    /// Runs the end-to-end canary instead of the load test, until interrupted.
+    #[arg(long, env, help_heading = "Canary")]
+    canary: bool,
+
+    /// Seconds between the starts of two canary rounds.
+    #[arg(long, env, default_value_t = 300, help_heading = "Canary")]
+    canary_interval_secs: u64,
+
+    /// Seconds a canary step waits for the results of all parties.
+    #[arg(long, env, default_value_t = 120, help_heading = "Canary")]
+    canary_timeout_secs: u64,
+
+    /// Number of synthetic identities the canary cycles through.
+    #[arg(long, env, default_value_t = 4, help_heading = "Canary")]
+    canary_identities: usize,
+
+    /// StatsD endpoint for the canary metrics, as `host:port`.
+    #[arg(long, env, help_heading = "Canary")]
+    canary_statsd: Option<String>,
+}
+
+/// Where a uniqueness request carries its shares file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RequestFormat {
+    /// Uploaded to the requests bucket, with a presigned URL as the `s3_key`.
+    S3,
+    /// Inline in the `iris_shares` of the request. The message has to fit the
+    /// 256 KiB limit of SNS.
+    Inline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Eye {
+    Left,
+    Right,
+}
+
+/// The DB entry a request is expected to match.
+#[derive(Debug, Clone, Copy)]
+struct ExpectedMatch {
+    serial_id: u32,
+    /// The only eye the request shares with the entry, `None` for both.
+    eye:       Option<Eye>,
+}
+
+impl ExpectedMatch {
+    fn both_eyes(serial_id: u32) -> Self {
+        Self {
+            serial_id,
+            eye: None,
+        }
+    }
+}
+
+/// A message of the response queue.
+#[derive(Debug)]
+enum ResultEvent {
+    Uniqueness(UniquenessResult),
+    /// A party failed to process a uniqueness request.
+    UniquenessFailure {
+        node_id:    usize,
+        signup_id:  String,
+        reason:     String,
+        /// The specific reason, unset by parties that predate the codes.
+        error_code: Option<ErrorCode>,
+        message:    Option<String>,
+    },
+    IdentityDeletion(IdentityDeletionResult),
+}
+
+impl ResultEvent {
+    fn parse(message: &Message) -> eyre::Result<Self> {
+        let body = message.body.as_deref().context("No body found")?;
+        let message_type = message
+            .message_attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get(SMPC_MESSAGE_TYPE_ATTRIBUTE))
+            .and_then(|attribute| attribute.string_value());
+        match message_type {
+            Some(UNIQUENESS_MESSAGE_TYPE) => Ok(Self::from_uniqueness(
+                serde_json::from_str(body).context("Failed to parse uniqueness result")?,
+            )),
+            Some(IDENTITY_DELETION_MESSAGE_TYPE) => Ok(Self::IdentityDeletion(
+                serde_json::from_str(body).context("Failed to parse identity deletion result")?,
+            )),
+            Some(message_type) => Err(eyre::eyre!("Unexpected result type {}", message_type)),
+            // Without the attribute, tell the results apart by their fields.
+            None => match serde_json::from_str::<UniquenessResult>(body) {
+                Ok(result) => Ok(Self::from_uniqueness(result)),
+                Err(_) => Ok(Self::IdentityDeletion(
+                    serde_json::from_str(body).context("Failed to parse message body")?,
+                )),
+            },
+        }
+    }
+
+    /// Returns `None` for deletion batches, restores and statistics events.
+    fn from_message(message: ResultMessage) -> Option<Self> {
+        match message {
+            ResultMessage::Uniqueness(result) => Some(Self::from_uniqueness(result)),
+            ResultMessage::IdentityDeletion(result) => Some(Self::IdentityDeletion(result)),
+            ResultMessage::IdentityDeletionBatch(_)
+            | ResultMessage::IdentityRestore(_)
+            | ResultMessage::Stats(_) => None,
+        }
+    }
+
+    fn from_uniqueness(result: UniquenessResult) -> Self {
+        if result.error == Some(true) {
+            Self::UniquenessFailure {
+                node_id:    result.node_id,
+                signup_id:  result.signup_id,
+                reason:     result.error_reason.unwrap_or_default(),
+                error_code: result.error_code,
+                message:    result.error_message,
+            }
+        } else {
+            Self::Uniqueness(result)
+        }
+    }
+}
+
+/// Secret shares the template into the share files of the parties.
+fn share_template(template: &IrisCode, rng: &mut StdRng) -> [IrisCodesJSON; 3] {
+    let shares = encode_template(template, rng);
+    share_files(&shares, &shares)
+}
+
+/// Secret shares the template with `encoder`, with another template for the
+/// right eye if `right` is given. If `self_check` is set, fails if the shares
+/// do not reconstruct the templates.
+fn share_template_checked(
+    left: &IrisCode,
+    right: Option<&IrisCode>,
+    rng: &mut StdRng,
+    encoder: Encoder,
+    self_check: bool,
+) -> Result<[IrisCodesJSON; 3], SelfCheckMismatch> {
+    let mut encode = |template: &IrisCode| {
+        let shares = encoder(template, rng);
+        if self_check {
+            check_shares(template, &shares)?;
+        }
+        Ok(shares)
+    };
+    let left_shares = encode(left)?;
+    match right {
+        Some(right) => Ok(share_files(&left_shares, &encode(right)?)),
+        None => Ok(share_files(&left_shares, &left_shares)),
+    }
+}
+
+fn share_files(left: &TemplateShares, right: &TemplateShares) -> [IrisCodesJSON; 3] {
+    std::array::from_fn(|i| {
+        IrisCodesJSON::new(&left.code[i], &left.mask[i], &right.code[i], &right.mask[i])
+    })
+}
+
+/// Encrypts the share files for the parties. Returns the encrypted files and
+/// their hashes.
+fn seal_shares(
+    shares: &[IrisCodesJSON; 3],
+    public_keys: &PublicKeySet,
+) -> ([String; 3], [String; 3]) {
+    let (sealed, hashes) = public_keys.seal(shares);
+    let sealed = std::array::from_fn(|i| {
+        sealed
+            .get(i)
+            .expect("the share of every party is sealed")
+            .clone()
+    });
+    (sealed, hashes)
+}
+
+/// The entry of a request in a batch publish. Every request starts a trace,
+/// which the parties continue.
+fn request_entry(message_type: &str, message: String) -> eyre::Result<PublishEntry> {
+    let trace_context = TraceContext::random();
+    let mut message_attributes = create_message_type_attribute_map(message_type);
+    message_attributes.extend(construct_message_attributes(Some(&trace_context))?);
+    tracing::info!(
+        traceparent = %trace_context.traceparent(),
+        "Publishing {} request",
+        message_type
+    );
+    Ok(PublishEntry {
+        id: Uuid::new_v4().to_string(),
+        message,
+        message_group_id: Some(ENROLLMENT_REQUEST_TYPE.to_string()),
+        message_attributes,
+    })
+}
+
+async fn publish_request(
+    client: &Client,
+    topic_arn: &str,
+    message_type: &str,
+    message: String,
+) -> eyre::Result<()> {
+    let entry = request_entry(message_type, message)?;
+    publish_batch_with_retry(
+        client,
+        topic_arn,
+        vec![entry],
+        &PublishRetryPolicy::default(),
+    )
+    .await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let Opt {
+        public_key_base_url,
+
+        requests_bucket_name,
+        requests_bucket_region,
+
+        request_topic_arn,
+        request_topic_region,
+
+        response_queue_url,
+        response_queue_region,
+
+        db_index,
+        rng_seed,
+        n_repeat,
+        random,
+        morph_steps,
+        morph_max_distance,
+        capture_flip_prob,
+        capture_mask_drop_prob,
+        two_eyes,
+        match_policy,
+        db_file,
+        chaos,
+        chaos_timeout_secs,
+        self_check,
+        self_check_rate,
+        request_format,
+        canary,
+        canary_interval_secs,
+        canary_timeout_secs,
+        canary_identities,
+        canary_statsd,
+    } = Opt::parse();
+
+    let mut public_key_strings = vec![];
+    for i in 0..3 {
+        public_key_strings
+            .push(download_public_key(public_key_base_url.to_string(), i.to_string()).await?);
+    }
+    let shares_encryption_public_keys = PublicKeySet::from_b64_strings([
+        &public_key_strings[0],
+        &public_key_strings[1],
+        &public_key_strings[2],
+    ])
+    .context("Failed to parse public keys")?;
+
+    let n_repeat = n_repeat.unwrap_or(0);
+
+    let region_provider = Region::new(request_topic_region);
+
+    let requests_sns_config = aws_config::from_env()
+        .region(region_provider)
+        .retry_config(RetryConfig::standard().with_max_attempts(5))
+        .load()
+        .await;
+
+    let requests_sns_client = Client::new(&requests_sns_config);
+
+    if let Some(scenarios) = chaos {
+        let results_sqs_config = aws_config::from_env()
+            .region(Region::new(response_queue_region))
+            .load()
+            .await;
+        let results_sqs_client = SqsClient::new(&results_sqs_config);
+        let client = ChaosClient {
+            requests_sns_client,
+            request_topic_arn,
+            results_receiver: BatchReceiver::new(
+                Arc::new(SqsBatchQueue::new(
+                    results_sqs_client.clone(),
+                    response_queue_url.clone(),
+                )),
+                BatchReceiverConfig::default(),
+            ),
+            results_sqs_client,
+            response_queue_url,
+            requests_bucket_name,
+            requests_bucket_region,
+            shares_encryption_public_keys,
+        };
+        let scenarios = if scenarios.is_empty() {
+            ChaosScenario::ALL.to_vec()
+        } else {
+            scenarios
+        };
+        return chaos::run(&client, &scenarios, Duration::from_secs(chaos_timeout_secs)).await;
+    }
+
+    if canary {
+        if let Some(statsd) = canary_statsd {
+            let (host, port) = statsd
+                .rsplit_once(':')
+                .context("The StatsD endpoint must be host:port")?;
+            let recorder = StatsdBuilder::from(host, port.parse()?).build(Some("canary"))?;
+            metrics::set_global_recorder(recorder)?;
+        }
+        let results_sqs_config = aws_config::from_env()
+            .region(Region::new(response_queue_region))
+            .load()
+            .await;
+        let mut client = CanaryClient {
+            requests_sns_client,
+            request_topic_arn,
+            results: ResultStream::new(
+                Arc::new(SqsResultQueue::new(
+                    SqsClient::new(&results_sqs_config),
+                    response_queue_url,
+                )),
+                ResultStreamConfig {
+                    kinds: [ResultKind::Uniqueness, ResultKind::IdentityDeletion].into(),
+                    ..Default::default()
+                },
+            ),
+            requests_bucket_name,
+            requests_bucket_region,
+            shares_encryption_public_keys,
+            timeout: Duration::from_secs(canary_timeout_secs),
+        };
+        let mut canary = Canary::new(CANARY_SEED, canary_identities);
+        canary::run(
+            &mut client,
+            &mut canary,
+            Duration::from_secs(canary_interval_secs),
+        )
+        .await;
+        return Ok(());
+    }
+
+    let db = match db_file {
+        Some(path) if path.exists() => {
+            let db = IrisDB::load(&path)
+                .with_context(|| format!("Failed to load the DB from {}", path.display()))?;
+            if db.len() != DB_SIZE {
+                eyre::bail!(
+                    "The DB file {} holds {} irises, expected {}",
+                    path.display(),
+                    db.len(),
+                    DB_SIZE
+                );
+            }
+            db
+        }
+        db_file => {
+            let db = IrisDB::new_random_par(DB_SIZE, &mut StdRng::seed_from_u64(RNG_SEED_SERVER));
+            if let Some(path) = db_file {
+                db.save(&path)
+                    .with_context(|| format!("Failed to save the DB to {}", path.display()))?;
+            }
+            db
+        }
+    };
+
+    let expected_results: Arc<Mutex<HashMap<String, Option<ExpectedMatch>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let requests: Arc<Mutex<HashMap<String, IrisCode>>> = Arc::new(Mutex::new(HashMap::new()));
+    let responses: Arc<Mutex<HashMap<u32, IrisCode>>> = Arc::new(Mutex::new(HashMap::new()));
+    let db: Arc<Mutex<IrisDB>> = Arc::new(Mutex::new(db));
+    let requests_sns_client: Arc<Client> = Arc::new(requests_sns_client);
+
+    let thread_expected_results = expected_results.clone();
+    let thread_requests = requests.clone();
+    let thread_responses = responses.clone();
+
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+
+    let recv_thread = spawn(async move {
+        let region_provider = Region::new(response_queue_region);
+        let results_sqs_config = aws_config::from_env().region(region_provider).load().await;
+        let results_sqs_client = SqsClient::new(&results_sqs_config);
+        let mut results = ResultStream::new(
+            Arc::new(SqsResultQueue::new(results_sqs_client, response_queue_url)),
+            ResultStreamConfig {
+                kinds: [ResultKind::Uniqueness, ResultKind::IdentityDeletion].into(),
+                ..Default::default()
+            },
+        )
+        .on_error(|malformed| eprintln!("Dropping {}", malformed));
+        let mut counter = 0;
+        while counter < N_QUERIES * 3 {
+            let Some(received) = results.next_result().await else {
+                break;
+            };
+            let received = received.context("Failed to receive message")?;
+            counter += 1;
+
+            let result = match ResultEvent::from_message(received.message.clone()) {
+                Some(ResultEvent::Uniqueness(result)) => result,
+                Some(ResultEvent::UniquenessFailure {
+                    node_id,
+                    signup_id,
+                    reason,
+                    error_code,
+                    message,
+                }) => {
+                    eprintln!(
+                        "Party {} failed to process request_id {}: {} ({}){}",
+                        node_id,
+                        signup_id,
+                        reason,
+                        error_code.map_or_else(|| "no error code".to_string(), |c| c.to_string()),
+                        message.map(|m| format!(": {}", m)).unwrap_or_default()
+                    );
+                    received.ack().await?;
+                    continue;
+                }
+                Some(ResultEvent::IdentityDeletion(result)) => {
+                    eprintln!(
+                        "Unexpected identity deletion result, the SQS message is likely stale: \
+                         {:?}",
+                        result
+                    );
+                    received.ack().await?;
+                    continue;
+                }
+                None => unreachable!("only uniqueness and deletion results are received"),
+            };
+
+            println!("Received result: {:?}", result);
+
+            let expected_result_option = {
+                let tmp = thread_expected_results.lock().await;
+                tmp.get(&result.signup_id).cloned()
+            };
+            if expected_result_option.is_none() {
+                eprintln!(
+                    "No expected result found for request_id: {}, the SQS message is likely \
+                     stale, clear the queue",
+                    result.signup_id
+                );
+
+                received.ack().await?;
+                continue;
+            }
+            let expected_result = expected_result_option.unwrap();
+            let matched_left = result.matched_serial_ids_left.clone().unwrap_or_default();
+            let matched_right = result.matched_serial_ids_right.clone().unwrap_or_default();
+
+            match expected_result {
+                None => {
+                    // New insertion
+                    assert!(!result.is_match);
+                    let request = {
+                        let tmp = thread_requests.lock().await;
+                        tmp.get(&result.signup_id).unwrap().clone()
+                    };
+                    {
+                        let mut tmp = thread_responses.lock().await;
+                        tmp.insert(result.serial_id.unwrap(), request);
+                    }
+                }
+                Some(ExpectedMatch {
+                    serial_id,
+                    eye: None,
+                }) => {
+                    // Existing entry
+                    assert!(result.is_match);
+                    assert!(result.matched_serial_ids.is_some());
+                    let matched_ids = result.matched_serial_ids.unwrap();
+                    assert!(matched_ids.len() == 1);
+                    assert_eq!(serial_id, matched_ids[0]);
+                    if two_eyes {
+                        assert!(matched_left.contains(&serial_id));
+                        assert!(matched_right.contains(&serial_id));
+                    }
+                }
+                Some(ExpectedMatch {
+                    serial_id,
+                    eye: Some(eye),
+                }) => {
+                    // Existing entry on one eye only. The request is not
+                    // recorded as a fresh insertion, as its other eye is
+                    // random.
+                    let (matched, other) = match eye {
+                        Eye::Left => (&matched_left, &matched_right),
+                        Eye::Right => (&matched_right, &matched_left),
+                    };
+                    assert!(matched.contains(&serial_id));
+                    assert!(!other.contains(&serial_id));
+                    assert_eq!(result.is_match, match_policy.combine(true, false));
+                    if result.is_match {
+                        assert_eq!(result.matched_serial_ids, Some(vec![serial_id]));
+                    }
+                }
+            }
+
+            received.ack().await?;
+        }
+        eyre::Ok(())
+    });
+
+    // Prepare query
+    for batch_idx in 0..N_BATCHES {
+        let mut handles = Vec::new();
+        for batch_query_idx in 0..BATCH_SIZE {
+            let shares_encryption_public_keys2 = shares_encryption_public_keys.clone();
+            let thread_db2 = db.clone();
+            let thread_expected_results2 = expected_results.clone();
+            let thread_requests2 = requests.clone();
+            let thread_responses2 = responses.clone();
+            let requests_bucket_region = requests_bucket_region.clone();
+            let requests_bucket_name = requests_bucket_name.clone();
+            let semaphore = Arc::clone(&semaphore);
+
+            let handle = tokio::spawn(async move {
+                let _permit = semaphore.acquire().await;
+
+                let mut rng = if let Some(rng_seed) = rng_seed {
+                    StdRng::seed_from_u64(rng_seed)
+                } else {
+                    StdRng::from_entropy()
+                };
+
+                let request_id = Uuid::new_v4();
+                // The eye a request shares with a DB entry, and the random
+                // template of its other eye.
+                let mut single_eye = None;
+
+                let template = if random.is_some() {
+                    // Automatic random tests
+
+                    let responses_len = {
+                        let tmp = thread_responses2.lock().await;
+                        tmp.len()
+                    };
+
+                    let options = if responses_len == 0 { 2 } else { 3 };
+                    let morph_option = options;
+                    let single_eye_option = options + morph_steps.is_some() as usize;
+                    let choice = rng.gen_range(0..single_eye_option + two_eyes as usize);
+
+                    match choice {
+                        _ if choice == single_eye_option => {
+                            let eye = if rng.gen() { Eye::Left } else { Eye::Right };
+                            let (db_index, template) = {
+                                let tmp = thread_db2.lock().await;
+                                let db_index = rng.gen_range(0..tmp.db.len());
+                                (db_index, tmp.db[db_index].clone())
+                            };
+                            println!("Sending the {:?} eye of db entry {}", eye, db_index);
+                            {
+                                let mut tmp = thread_expected_results2.lock().await;
+                                tmp.insert(
+                                    request_id.to_string(),
+                                    Some(ExpectedMatch {
+                                        serial_id: db_index as u32 + 1,
+                                        eye:       Some(eye),
+                                    }),
+                                );
+                            }
+                            single_eye = Some((eye, IrisCode::random_rng(&mut rng)));
+                            template
+                        }
+                        _ if choice == morph_option => {
+                            let steps = morph_steps.expect("morph steps are enabled");
+                            let (db_index, base) = {
+                                let tmp = thread_db2.lock().await;
+                                let db_index = rng.gen_range(0..tmp.db.len());
+                                (db_index, tmp.db[db_index].clone())
+                            };
+                            // Step 0 is the entry itself, so there is always a
+                            // step to send.
+                            let (step, morph, distance) = base
+                                .morph_sequence(steps, morph_max_distance, &mut rng)
+                                .into_iter()
+                                .enumerate()
+                                .map(|(step, morph)| {
+                                    let distance = base.get_distance(&morph);
+                                    (step, morph, distance)
+                                })
+                                .filter(|(_, _, distance)| {
+                                    (distance - MATCH_THRESHOLD_RATIO).abs() > THRESHOLD_MARGIN
+                                })
+                                .choose(&mut rng)
+                                .expect("step 0 is far from the threshold");
+                            println!(
+                                "Sending step {} of a morph of db entry {} at distance {:.3}",
+                                step, db_index, distance
+                            );
+                            {
+                                let mut tmp = thread_expected_results2.lock().await;
+                                tmp.insert(
+                                    request_id.to_string(),
+                                    (distance < MATCH_THRESHOLD_RATIO)
+                                        .then_some(ExpectedMatch::both_eyes(db_index as u32 + 1)),
+                                );
+                            }
+                            morph
+                        }
+                        0 => {
+                            println!("Sending new iris code");
+                            {
+                                let mut tmp = thread_expected_results2.lock().await;
+                                tmp.insert(request_id.to_string(), None);
+                            }
+                            IrisCode::random_rng(&mut rng)
+                        }
+                        1 => {
+                            let (db_index, entry) = {
+                                let tmp = thread_db2.lock().await;
+                                let db_index = rng.gen_range(0..tmp.db.len());
+                                (db_index, tmp.db[db_index].clone())
+                            };
+                            // A capture too close to the threshold, or without
+                            // unmasked bits, is sent without noise.
+                            let (mut capture, mut flips) = entry.with_noise(
+                                capture_flip_prob,
+                                capture_mask_drop_prob,
+                                &mut rng,
+                            );
+                            let mut distance = entry.get_distance(&capture);
+                            if distance.is_nan()
+                                || (distance - MATCH_THRESHOLD_RATIO).abs() <= THRESHOLD_MARGIN
+                            {
+                                (capture, flips, distance) = (entry, 0, 0.0);
+                            }
+                            println!(
+                                "Sending a new capture of db entry {} with {} flipped bits at \
+                                 distance {:.3}",
+                                db_index, flips, distance
+                            );
+                            {
+                                let mut tmp = thread_expected_results2.lock().await;
+                                tmp.insert(
+                                    request_id.to_string(),
+                                    (distance < MATCH_THRESHOLD_RATIO)
+                                        .then_some(ExpectedMatch::both_eyes(db_index as u32 + 1)),
+                                );
+                            }
+                            capture
+                        }
+                        2 => {
+                            println!("Sending freshly inserted iris code");
+                            let (keys_vec, keys_idx) = {
+                                let tmp = thread_responses2.lock().await;
+                                let keys = tmp.keys().cloned().collect::<Vec<_>>();
+                                let idx = rng.gen_range(0..keys.len());
+                                (keys, idx)
+                            };
+                            let iris_code = {
+                                let tmp = thread_responses2.lock().await;
+                                tmp.get(&keys_vec[keys_idx]).unwrap().clone()
+                            };
+                            {
+                                let mut tmp = thread_expected_results2.lock().await;
+                                tmp.insert(
+                                    request_id.to_string(),
+                                    Some(ExpectedMatch::both_eyes(keys_vec[keys_idx])),
+                                );
+                            }
+                            iris_code
+                        }
+                        _ => unreachable!(),
+                    }
+                } else {
+                    // Manually passed cli arguments
+                    if let Some(db_index) = db_index {
+                        if batch_query_idx * batch_idx < n_repeat {
+                            let tmp = thread_db2.lock().await;
+                            tmp.db[db_index].clone()
+                        } else {
+                            IrisCode::random_rng(&mut rng)
+                        }
+                    } else {
+                        let mut rng = StdRng::seed_from_u64(1337); // TODO
+                        IrisCode::random_rng(&mut rng)
+                    }
+                };
+
+                {
+                    let mut tmp = thread_requests2.lock().await;
+                    tmp.insert(request_id.to_string(), template.clone());
+                }
+
+                let self_check =
+                    self_check && thread_rng().gen_bool(self_check_rate.clamp(0.0, 1.0));
+                let (left, right) = match single_eye {
+                    Some((Eye::Left, other)) => (template, Some(other)),
+                    Some((Eye::Right, other)) => (other, Some(template)),
+                    None => (template, None),
+                };
+                let shares = match share_template_checked(
+                    &left,
+                    right.as_ref(),
+                    &mut rng,
+                    encode_template,
+                    self_check,
+                ) {
+                    Ok(shares) => shares,
+                    Err(e) => {
+                        eprintln!("Self-check failed for request_id {}: {}", request_id, e);
+                        // abort this request and continue
+                        return Ok(None);
+                    }
+                };
+                let (iris_codes_shares_base64, iris_shares_file_hashes) =
+                    seal_shares(&shares, &shares_encryption_public_keys2);
+
+                let (s3_key, iris_shares) = match request_format {
+                    RequestFormat::S3 => {
+                        let contents = serde_json::to_vec(&iris_codes_shares_base64)?;
+                        let presigned_url = match upload_file_and_generate_presigned_url(
+                            &requests_bucket_name,
+                            &request_id.to_string(),
+                            Box::leak(requests_bucket_region.clone().into_boxed_str()),
+                            &contents,
+                        )
+                        .await
+                        {
+                            Ok(url) => url,
+                            Err(e) => {
+                                eprintln!("Failed to upload file: {}", e);
+                                // ignore the error and continue
+                                return Ok(None);
+                            }
+                        };
+                        (presigned_url, None)
+                    }
+                    RequestFormat::Inline => (
+                        String::new(),
+                        Some(SharesS3Object::from_shares(
+                            iris_codes_shares_base64.into_iter().enumerate(),
+                        )),
+                    ),
+                };
+
+                let request_message = UniquenessRequest {
+                    batch_size: None,
+                    signup_id: request_id.to_string(),
+                    s3_key,
+                    iris_shares,
+                    iris_shares_file_hashes,
+                    mirrored_check: None,
+                    rotation_window: None,
+                    or_rule_serial_ids: None,
+                };
+
+                eyre::Ok(Some(request_entry(
+                    UNIQUENESS_MESSAGE_TYPE,
+                    to_string(&request_message)?,
+                )?))
+            });
+            handles.push(handle);
+        }
+
+        // Wait for all tasks to complete, then publish the batch at once
+        let mut entries = Vec::new();
+        for handle in handles {
+            entries.extend(handle.await??);
+        }
+        publish_batch_with_retry(
+            requests_sns_client.as_ref(),
+            &request_topic_arn,
+            entries,
+            &PublishRetryPolicy::default(),
+        )
+        .await?;
+
+        println!("Batch {} sent!", batch_idx);
+
+        // Give it some time to get back results
+        sleep(WAIT_AFTER_BATCH).await;
+    }
+
+    // Receive all messages
+    recv_thread.await??;
+
+    Ok(())
+}
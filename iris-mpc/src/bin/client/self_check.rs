@@ -0,0 +1,157 @@
+//! Local reconstruction of the shares before they are sent, see
+//! `--self-check`.
+//!
+//! A broken encoding otherwise only shows up as unexpected non-matches on the
+//! server side. The shares are reconstructed from every pair of parties: if
+//! only one pair decodes to the template, the share of the third party is
+//! suspected, if no pair does, the encoding itself is wrong.
+
+use iris_mpc_common::{
+    galois_engine::degree4::GaloisRingIrisCodeShare, iris_db::iris::IrisCode, IRIS_CODE_LENGTH,
+};
+use rand::rngs::StdRng;
+use std::fmt;
+
+/// Encodes a template into the shares of the three parties.
+pub type Encoder = fn(&IrisCode, &mut StdRng) -> TemplateShares;
+
+pub struct TemplateShares {
+    pub code: [GaloisRingIrisCodeShare; 3],
+    pub mask: [GaloisRingIrisCodeShare; 3],
+}
+
+pub fn encode_template(template: &IrisCode, rng: &mut StdRng) -> TemplateShares {
+    TemplateShares {
+        code: GaloisRingIrisCodeShare::encode_iris_code(&template.code, &template.mask, rng),
+        mask: GaloisRingIrisCodeShare::encode_mask_code(&template.mask, rng),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfCheckMismatch {
+    /// Either "code" or "mask".
+    pub shares:          &'static str,
+    /// The first bit of the template that does not reconstruct.
+    pub bit:             usize,
+    pub expected:        u16,
+    pub reconstructed:   u16,
+    /// The only party whose share is inconsistent with the other two.
+    pub suspected_party: Option<usize>,
+}
+
+impl fmt::Display for SelfCheckMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} shares do not reconstruct the template: first differing bit {}, expected {}, \
+             reconstructed {}, ",
+            self.shares, self.bit, self.expected, self.reconstructed
+        )?;
+        match self.suspected_party {
+            Some(party) => write!(f, "suspected party {}", party),
+            None => write!(f, "all parties agree, the encoding itself is wrong"),
+        }
+    }
+}
+
+impl std::error::Error for SelfCheckMismatch {}
+
+/// Checks that the shares reconstruct the template.
+pub fn check_shares(template: &IrisCode, shares: &TemplateShares) -> Result<(), SelfCheckMismatch> {
+    let expected_code = (0..IRIS_CODE_LENGTH)
+        .map(|i| {
+            let mask = template.mask.get_bit(i) as u16;
+            let code = template.code.get_bit(i) as u16;
+            mask.wrapping_sub(2 * (code & mask))
+        })
+        .collect::<Vec<_>>();
+    let expected_mask = (0..IRIS_CODE_LENGTH)
+        .map(|i| template.mask.get_bit(i) as u16)
+        .collect::<Vec<_>>();
+    check_values("code", &shares.code, &expected_code)?;
+    check_values("mask", &shares.mask, &expected_mask)
+}
+
+fn check_values(
+    name: &'static str,
+    shares: &[GaloisRingIrisCodeShare; 3],
+    expected: &[u16],
+) -> Result<(), SelfCheckMismatch> {
+    // The pairs of parties, each with the party left out.
+    let mismatches = [(1, 2, 0), (0, 2, 1), (0, 1, 2)].map(|(a, b, excluded)| {
+        let values = GaloisRingIrisCodeShare::reconstruct_encoded_pair(&shares[a], &shares[b]);
+        let first_diff = values.iter().zip(expected).position(|(v, e)| v != e);
+        (excluded, first_diff.map(|bit| (bit, values[bit])))
+    });
+    let consistent = mismatches
+        .iter()
+        .filter(|(_, mismatch)| mismatch.is_none())
+        .map(|(excluded, _)| *excluded)
+        .collect::<Vec<_>>();
+    let Some((bit, reconstructed)) = mismatches
+        .iter()
+        .filter_map(|(_, mismatch)| *mismatch)
+        .min_by_key(|(bit, _)| *bit)
+    else {
+        return Ok(());
+    };
+    Err(SelfCheckMismatch {
+        shares: name,
+        bit,
+        expected: expected[bit],
+        reconstructed,
+        suspected_party: (consistent.len() == 1).then(|| consistent[0]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::share_template_checked;
+    use rand::SeedableRng;
+
+    /// Encodes the template with the bits of every byte in reverse order.
+    fn reversed_bit_order(template: &IrisCode, rng: &mut StdRng) -> TemplateShares {
+        let mut mangled = template.clone();
+        for i in 0..IRIS_CODE_LENGTH {
+            let j = i ^ 7;
+            mangled.code.set_bit(i, template.code.get_bit(j));
+            mangled.mask.set_bit(i, template.mask.get_bit(j));
+        }
+        encode_template(&mangled, rng)
+    }
+
+    /// Corrupts one coefficient of the code share of party 2.
+    fn corrupted_share(template: &IrisCode, rng: &mut StdRng) -> TemplateShares {
+        let mut shares = encode_template(template, rng);
+        shares.code[2].coefs[100] ^= 1;
+        shares
+    }
+
+    #[test]
+    fn test_self_check_passes() {
+        let rng = &mut StdRng::seed_from_u64(42);
+        let template = IrisCode::random_rng(rng);
+        assert!(share_template_checked(&template, None, rng, encode_template, true).is_ok());
+    }
+
+    #[test]
+    fn test_self_check_catches_mangled_encoder() {
+        let rng = &mut StdRng::seed_from_u64(42);
+        let template = IrisCode::random_rng(rng);
+
+        let mismatch = share_template_checked(&template, None, rng, reversed_bit_order, true)
+            .expect_err("reversed bit order must fail the self-check");
+        assert_eq!(mismatch.suspected_party, None);
+        let bits = |i| (template.code.get_bit(i), template.mask.get_bit(i));
+        assert_ne!(bits(mismatch.bit), bits(mismatch.bit ^ 7));
+
+        let mismatch = share_template_checked(&template, None, rng, corrupted_share, true)
+            .expect_err("corrupted share must fail the self-check");
+        assert_eq!(mismatch.shares, "code");
+        assert_eq!(mismatch.suspected_party, Some(2));
+
+        // Without the self-check, the mangled shares go through.
+        assert!(share_template_checked(&template, None, rng, corrupted_share, false).is_ok());
+    }
+}
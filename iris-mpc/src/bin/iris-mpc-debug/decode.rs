@@ -0,0 +1,325 @@
+//! The decoders of the subcommands. Each returns the report to print, or the
+//! error the artifact failed to parse with.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use eyre::{bail, eyre, Context};
+use iris_mpc_common::{
+    galois_engine::degree4::{
+        GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare, ShareEncodingError, ShareKind,
+    },
+    helpers::{
+        results_consumer::{ResultKind, ResultMessage},
+        sha256::calculate_sha256,
+        smpc_request::SQSMessage,
+        smpc_response::SMPC_MESSAGE_TYPE_ATTRIBUTE,
+        sync::SyncState,
+    },
+    IRIS_CODE_LENGTH, MASK_CODE_LENGTH,
+};
+use iris_mpc_gpu::helpers::id_wrapper::IdWrapper;
+use std::{fmt::Write, str::FromStr};
+
+/// The length of a share encoded before the header was introduced.
+const LEGACY_SHARE_LEN: usize = 8 + 2 * IRIS_CODE_LENGTH;
+
+/// Summarizes a base64 encoded share: the header, statistics of the
+/// coefficients and the checksum of the encoding. The coefficients themselves
+/// are only included with `unsafe_full`.
+pub fn decode_share(input: &str, kind: ShareKind, unsafe_full: bool) -> eyre::Result<String> {
+    let bytes = BASE64_STANDARD.decode(input).context("Invalid base64")?;
+    let (party_id, coefs) = match GaloisRingIrisCodeShare::from_bytes(&bytes, kind) {
+        Ok(share) => (share.id, share.coefs.to_vec()),
+        // Trimmed masks are encoded like masks, with fewer coefficients.
+        Err(ShareEncodingError::WrongLength { got, .. })
+            if kind == ShareKind::Mask && got == MASK_CODE_LENGTH =>
+        {
+            let share =
+                GaloisRingTrimmedMaskCodeShare::from_bytes(&bytes).context("Invalid share")?;
+            (share.id, share.coefs.to_vec())
+        }
+        Err(e) => return Err(e).context("Invalid share"),
+    };
+
+    let mut report = String::new();
+    if bytes.len() == LEGACY_SHARE_LEN {
+        writeln!(report, "version:      legacy, without header")?;
+    } else {
+        writeln!(report, "version:      {}", bytes[0])?;
+    }
+    writeln!(report, "party id:     {}", party_id)?;
+    writeln!(report, "kind:         {:?}", kind)?;
+    writeln!(report, "coefficients: {}", coefs.len())?;
+    let min = coefs.iter().min().copied().unwrap_or_default();
+    let max = coefs.iter().max().copied().unwrap_or_default();
+    let mean = coefs.iter().map(|&c| c as f64).sum::<f64>() / coefs.len().max(1) as f64;
+    writeln!(report, "min:          {}", min)?;
+    writeln!(report, "max:          {}", max)?;
+    writeln!(report, "mean:         {:.1}", mean)?;
+    writeln!(
+        report,
+        "zeros:        {}",
+        coefs.iter().filter(|&&c| c == 0).count()
+    )?;
+    write!(report, "sha256:       {}", calculate_sha256(&bytes))?;
+    if unsafe_full {
+        write!(report, "\ncoefs:        {:?}", coefs)?;
+    }
+    Ok(report)
+}
+
+/// Pretty-prints a result event. The input is JSON or base64 encoded JSON,
+/// either the SNS envelope or the bare result. Without `message_type`, the
+/// type is taken from the attributes of the envelope or told apart by the
+/// fields of the result.
+pub fn decode_result(input: &str, message_type: Option<&str>) -> eyre::Result<String> {
+    let json = if input.starts_with('{') {
+        input.to_string()
+    } else {
+        let bytes = BASE64_STANDARD
+            .decode(input)
+            .context("Neither JSON nor base64")?;
+        String::from_utf8(bytes).context("The base64 does not decode to UTF-8")?
+    };
+    let value: serde_json::Value = serde_json::from_str(&json).context("Invalid JSON")?;
+
+    let mut report = String::new();
+    let (message_type, body) = if value.get("Message").is_some() {
+        let envelope: SQSMessage = serde_json::from_value(value).context("Invalid SNS envelope")?;
+        writeln!(report, "message id:   {}", envelope.message_id)?;
+        writeln!(report, "timestamp:    {}", envelope.timestamp)?;
+        let message_type = message_type.map(str::to_string).or_else(|| {
+            envelope
+                .message_attributes
+                .get(SMPC_MESSAGE_TYPE_ATTRIBUTE)
+                .and_then(|value| value.string_value())
+                .map(str::to_string)
+        });
+        (message_type, envelope.message)
+    } else {
+        (message_type.map(str::to_string), json)
+    };
+    let kind = match message_type {
+        Some(message_type) => ResultKind::from_message_type(&message_type)
+            .ok_or_else(|| eyre!("Unknown message type {}", message_type))?,
+        None => ResultKind::from_body(&body).ok_or_else(|| {
+            eyre!("No message type, and the result is neither a uniqueness nor a deletion result")
+        })?,
+    };
+    let message = ResultMessage::parse(kind, &body)
+        .with_context(|| format!("Invalid {} result", kind.message_type()))?;
+    writeln!(report, "message type: {}", kind.message_type())?;
+    let pretty = match &message {
+        ResultMessage::Uniqueness(result) => serde_json::to_string_pretty(result),
+        ResultMessage::IdentityDeletion(result) => serde_json::to_string_pretty(result),
+        ResultMessage::IdentityDeletionBatch(result) => serde_json::to_string_pretty(result),
+        ResultMessage::IdentityRestore(result) => serde_json::to_string_pretty(result),
+        ResultMessage::Stats(stats) => serde_json::to_string_pretty(stats),
+    }?;
+    report.push_str(&pretty);
+    Ok(report)
+}
+
+/// Deserializes a hex encoded [`SyncState`], as exchanged in the startup
+/// sync. The zero padding to the fixed size of the buffer may be included.
+pub fn decode_sync(input: &str) -> eyre::Result<String> {
+    let bytes = hex::decode(input.trim_start_matches("0x")).context("Invalid hex")?;
+    let state: SyncState = bincode::deserialize(&bytes).context("Invalid SyncState")?;
+    let len = bincode::serialized_size(&state)? as usize;
+    if let Some(offset) = bytes[len..].iter().position(|&b| b != 0) {
+        bail!(
+            "Non-zero byte at offset {} after the SyncState of {} bytes",
+            len + offset,
+            len
+        );
+    }
+    Ok(format!("{:#?}", state))
+}
+
+/// Validates a hex encoded NCCL id. Only its checksum is printed, the id
+/// itself only with `unsafe_full`.
+pub fn decode_nccl_id(input: &str, unsafe_full: bool) -> eyre::Result<String> {
+    let id = IdWrapper::from_str(input).context("Invalid NCCL id")?;
+    let hex = id.to_string();
+    let mut report = format!("valid NCCL id\nsha256:       {}", calculate_sha256(&hex));
+    if unsafe_full {
+        write!(report, "\nid:           {}", hex)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iris_mpc_common::{
+        helpers::{
+            smpc_response::{
+                create_message_type_attribute_map, IdentityDeletionResult, UniquenessResult,
+            },
+            threshold::ThresholdSchedule,
+        },
+        iris_db::iris::IrisCode,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn shares() -> [GaloisRingIrisCodeShare; 3] {
+        let rng = &mut StdRng::seed_from_u64(42);
+        let iris = IrisCode::random_rng(rng);
+        GaloisRingIrisCodeShare::encode_iris_code(&iris.code, &iris.mask, rng)
+    }
+
+    #[test]
+    fn test_decode_share() {
+        let share = &shares()[1];
+        let encoded = share.to_base64(ShareKind::Code);
+        let report = decode_share(&encoded, ShareKind::Code, false).unwrap();
+        assert!(report.contains("version:      1"));
+        assert!(report.contains("party id:     2"));
+        assert!(report.contains("coefficients: 12800"));
+        assert!(!report.contains("coefs:"));
+        let full = decode_share(&encoded, ShareKind::Code, true).unwrap();
+        assert!(full.contains(&format!("coefs:        {:?}", share.coefs.to_vec())));
+
+        // A share encoded by bincode, before the header.
+        let legacy = BASE64_STANDARD.encode(bincode::serialize(share).unwrap());
+        let report = decode_share(&legacy, ShareKind::Code, false).unwrap();
+        assert!(report.contains("version:      legacy, without header"));
+
+        let trimmed = GaloisRingTrimmedMaskCodeShare::from(share.clone()).to_base64();
+        let report = decode_share(&trimmed, ShareKind::Mask, false).unwrap();
+        assert!(report.contains(&format!("coefficients: {}", MASK_CODE_LENGTH)));
+    }
+
+    #[test]
+    fn test_decode_corrupted_share() {
+        let mut bytes = shares()[0].to_bytes(ShareKind::Code);
+        let error = |bytes: &[u8], kind| {
+            let error = decode_share(&BASE64_STANDARD.encode(bytes), kind, false).unwrap_err();
+            error.root_cause().to_string()
+        };
+        assert_eq!(
+            error(&bytes, ShareKind::Mask),
+            "Expected a Mask share, got kind 0"
+        );
+        assert_eq!(
+            error(&bytes[..100], ShareKind::Code),
+            "Truncated share of 100 bytes"
+        );
+        bytes[0] = 7;
+        assert_eq!(
+            error(&bytes, ShareKind::Code),
+            "Unsupported share encoding version 7"
+        );
+        assert!(decode_share("not base64!", ShareKind::Code, false).is_err());
+    }
+
+    #[test]
+    fn test_decode_result() {
+        let result = IdentityDeletionResult::new(1, 7, true);
+        let body = serde_json::to_string(&result).unwrap();
+        let report = decode_result(&body, None).unwrap();
+        assert!(report.starts_with("message type: identity_deletion\n"));
+        assert!(report.contains("\"serial_id\": 7"));
+        assert_eq!(
+            decode_result(&BASE64_STANDARD.encode(&body), None).unwrap(),
+            report
+        );
+
+        let result = UniquenessResult::new(
+            0,
+            Some(3),
+            false,
+            "signup".to_string(),
+            None,
+            None,
+            None,
+            None,
+        );
+        let envelope = SQSMessage {
+            notification_type:  "Notification".to_string(),
+            message_id:         "message-1".to_string(),
+            sequence_number:    "1".to_string(),
+            topic_arn:          "arn".to_string(),
+            message:            serde_json::to_string(&result).unwrap(),
+            timestamp:          "2024-01-01T00:00:00Z".to_string(),
+            unsubscribe_url:    String::new(),
+            message_attributes: create_message_type_attribute_map("uniqueness"),
+        };
+        let report = decode_result(&serde_json::to_string(&envelope).unwrap(), None).unwrap();
+        assert!(report.starts_with("message id:   message-1\n"));
+        assert!(report.contains("message type: uniqueness\n"));
+        assert!(report.contains("\"signup_id\": \"signup\""));
+    }
+
+    #[test]
+    fn test_decode_corrupted_result() {
+        let body = serde_json::to_string(&IdentityDeletionResult::new(1, 7, true)).unwrap();
+        let error =
+            |input: &str, message_type| decode_result(input, message_type).unwrap_err().to_string();
+        assert_eq!(
+            error(&body, Some("unknown")),
+            "Unknown message type unknown"
+        );
+        assert_eq!(
+            error(&body, Some("uniqueness")),
+            "Invalid uniqueness result"
+        );
+        assert_eq!(error(&body[..10], None), "Invalid JSON");
+        assert_eq!(error("{\"Message\": \"{}\"}", None), "Invalid SNS envelope");
+        assert_eq!(error("###", None), "Neither JSON nor base64");
+    }
+
+    fn sync_state() -> SyncState {
+        SyncState {
+            db_len:              42,
+            deleted_request_ids: vec!["request-1".to_string()],
+            config_fingerprint:  "fingerprint".to_string(),
+            common_config:       "{}".to_string(),
+            threshold:           ThresholdSchedule::default().sync_state(3),
+        }
+    }
+
+    #[test]
+    fn test_decode_sync() {
+        let mut bytes = bincode::serialize(&sync_state()).unwrap();
+        let report = decode_sync(&hex::encode(&bytes)).unwrap();
+        assert_eq!(report, format!("{:#?}", sync_state()));
+        bytes.extend([0; 100]);
+        assert_eq!(decode_sync(&hex::encode(&bytes)).unwrap(), report);
+    }
+
+    #[test]
+    fn test_decode_corrupted_sync() {
+        let mut bytes = bincode::serialize(&sync_state()).unwrap();
+        let len = bytes.len();
+        assert_eq!(
+            decode_sync(&hex::encode(&bytes[..len - 4]))
+                .unwrap_err()
+                .to_string(),
+            "Invalid SyncState"
+        );
+        bytes.extend([0, 0, 1]);
+        assert_eq!(
+            decode_sync(&hex::encode(&bytes)).unwrap_err().to_string(),
+            format!(
+                "Non-zero byte at offset {} after the SyncState of {} bytes",
+                len + 2,
+                len
+            )
+        );
+        assert_eq!(decode_sync("xyz").unwrap_err().to_string(), "Invalid hex");
+    }
+
+    #[test]
+    fn test_decode_nccl_id() {
+        let id = "ab".repeat(128);
+        let report = decode_nccl_id(&id, false).unwrap();
+        assert!(!report.contains(&id));
+        assert!(decode_nccl_id(&id, true).unwrap().ends_with(&id));
+
+        assert_eq!(
+            decode_nccl_id(&id[2..], false).unwrap_err().to_string(),
+            "Invalid NCCL id"
+        );
+        assert!(decode_nccl_id(&"zz".repeat(128), false).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Decodes the wire artifacts pasted into tickets: share payloads, result
+//! events, sync states and NCCL ids.
+//!
+//! Every subcommand reads the artifact from the given file, or from stdin if
+//! there is none. Shares and NCCL ids are only summarized, the secret material
+//! itself is printed with `--unsafe-full` only.
+
+mod decode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use eyre::Context;
+use iris_mpc_common::galois_engine::degree4::ShareKind;
+use std::{io::Read, path::PathBuf};
+
+#[derive(Debug, Parser)]
+struct Opt {
+    /// Also print the coefficients of shares and the full NCCL ids.
+    #[arg(long, global = true)]
+    unsafe_full: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Summarizes a base64 encoded iris code or mask share.
+    DecodeShare {
+        /// The kind the share is expected to be of.
+        #[arg(long, value_enum, default_value_t = Kind::Code)]
+        kind:  Kind,
+        input: Option<PathBuf>,
+    },
+    /// Pretty-prints a result event, given as the SNS envelope or as the bare
+    /// result, in JSON or base64 encoded.
+    DecodeResult {
+        /// The message type of a bare result. Uniqueness and deletion results
+        /// are recognized without it.
+        #[arg(long)]
+        message_type: Option<String>,
+        input:        Option<PathBuf>,
+    },
+    /// Deserializes a hex encoded `SyncState` buffer.
+    DecodeSync { input: Option<PathBuf> },
+    /// Validates a hex encoded NCCL id.
+    NcclId { input: Option<PathBuf> },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Kind {
+    Code,
+    Mask,
+}
+
+impl From<Kind> for ShareKind {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Code => ShareKind::Code,
+            Kind::Mask => ShareKind::Mask,
+        }
+    }
+}
+
+/// Reads the file, or stdin without one, with surrounding whitespace removed.
+fn read_input(path: Option<&PathBuf>) -> eyre::Result<String> {
+    let mut input = String::new();
+    match path {
+        Some(path) => {
+            input = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+        }
+        None => {
+            std::io::stdin()
+                .read_to_string(&mut input)
+                .context("Failed to read stdin")?;
+        }
+    }
+    Ok(input.trim().to_string())
+}
+
+fn main() -> eyre::Result<()> {
+    let opt = Opt::parse();
+    let report = match &opt.command {
+        Command::DecodeShare { kind, input } => decode::decode_share(
+            &read_input(input.as_ref())?,
+            (*kind).into(),
+            opt.unsafe_full,
+        )?,
+        Command::DecodeResult {
+            message_type,
+            input,
+        } => decode::decode_result(&read_input(input.as_ref())?, message_type.as_deref())?,
+        Command::DecodeSync { input } => decode::decode_sync(&read_input(input.as_ref())?)?,
+        Command::NcclId { input } => {
+            decode::decode_nccl_id(&read_input(input.as_ref())?, opt.unsafe_full)?
+        }
+    };
+    println!("{}", report);
+    Ok(())
+}
@@ -4,43 +4,94 @@ use aws_config::retry::RetryConfig;
 use aws_sdk_s3::{config::Builder as S3ConfigBuilder, Client as S3Client};
 use aws_sdk_sns::{types::MessageAttributeValue, Client as SNSClient};
 use aws_sdk_sqs::{config::Region, Client};
-use axum::{response::IntoResponse, routing::get, Router};
+use axum::{
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use clap::Parser;
 use eyre::{eyre, Context};
 use futures::{stream::select_all, StreamExt, TryStreamExt};
+#[cfg(feature = "parquet_export")]
+use iris_mpc_common::helpers::decision_export::{
+    DecisionExporter, ExportedBatch, ExportedDecision, ExportedQuery,
+};
 use iris_mpc_common::{
-    config::{json_wrapper::JsonStrWrapper, Config, Opt},
-    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare},
+    config::{json_wrapper::JsonStrWrapper, node::NodeConfig, CommonConfig, Config, Opt},
+    errors::{error_code_of, error_message_of, ErrorCode, HasErrorCode},
+    galois_engine::degree4::{GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare, ShareKind},
     helpers::{
-        aws::{
-            construct_message_attributes, SPAN_ID_MESSAGE_ATTRIBUTE_NAME,
-            TRACE_ID_MESSAGE_ATTRIBUTE_NAME,
+        audit::{AuditBatch, AuditDecision, AuditHash, AuditLog},
+        aws::{construct_message_attributes, TraceContext},
+        backfill::{
+            backfill_signup_id, is_backfill_request, BackfillEntry, BackfillResponse, BACKFILL_PATH,
+        },
+        canary::is_canary_request,
+        intake::{
+            publish_deletion_batch_not_agreed, publish_error_result, publish_request_error,
+            publish_request_not_agreed, verify_threshold_update, BatchRequest, PendingRequest,
+            ReceivedRequests, RequestIntake,
         },
-        key_pair::SharesEncryptionKeyPairs,
+        key_provider::{KeyPairProvider, SecretsManagerKeySource},
         kms_dh::derive_shared_secret,
+        latency_budget::{
+            report_shedding, BatchDeadline, BudgetDecision, BudgetPhase, CommittedMessage,
+        },
+        memory_pressure::{MemoryMonitor, MemoryPressure},
+        priority_lanes::RequestLane,
+        queue::{
+            DeadLetterSender, PoisonMessagePolicy, ResultPublisher, SnsResultPublisher,
+            SqsDeadLetterSender, SqsRequestReceiver,
+        },
+        replay::{
+            BatchRecorder, RecordedBatch, RecordedRequest, RecordedSettings, RecordedShares,
+            BACKFILL_MESSAGE_TYPE,
+        },
+        share_audit::{
+            find_inconsistent, report_audit, zero_shares, ShareAuditChallenge,
+            ShareAuditContribution,
+        },
+        share_download::ShareDownloadConfig,
         shutdown_handler::ShutdownHandler,
         smpc_request::{
-            CircuitBreakerRequest, IdentityDeletionRequest, ReceiveRequestError, SQSMessage,
-            UniquenessRequest, CIRCUIT_BREAKER_MESSAGE_TYPE, IDENTITY_DELETION_MESSAGE_TYPE,
-            UNIQUENESS_MESSAGE_TYPE,
+            IdentityDeletionRequest, ReceiveRequestError, ResetUpdateRequest, SupportedIrisVersion,
+            UniquenessRequest, IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            IDENTITY_DELETION_MESSAGE_TYPE, IDENTITY_RESTORE_MESSAGE_TYPE, REAUTH_MESSAGE_TYPE,
+            RESET_CHECK_MESSAGE_TYPE, RESET_UPDATE_MESSAGE_TYPE, UNIQUENESS_MESSAGE_TYPE,
         },
         smpc_response::{
-            create_message_type_attribute_map, IdentityDeletionResult, UniquenessResult,
-            ERROR_FAILED_TO_PROCESS_IRIS_SHARES, SMPC_MESSAGE_TYPE_ATTRIBUTE,
+            create_message_type_attribute_map, IdentityDeletionBatchResult, IdentityDeletionResult,
+            IdentityRestoreResult, ReAuthResult, ResetCheckResult, ResetUpdateResult,
+            UniquenessResult,
         },
-        sync::SyncState,
+        soft_delete::SoftDeletions,
+        spans::{batch_span, record_batch_size, request_span, Phase},
+        state_exchange::{StateExchange, StateSyncTransport, TcpStateExchange},
+        sync::{SyncState, MAX_DELETION_BATCHES},
         task_monitor::TaskMonitor,
     },
+    id::PartyID,
 };
 use iris_mpc_gpu::{
-    helpers::device_manager::DeviceManager,
+    dot::snapshot::SnapshotDigest,
+    helpers::{
+        chunked_copy::{ChunkedTransfer, PinnedStagingPool},
+        comm::NcclComm,
+        device_manager::DeviceManager,
+        query_processor::batch_cost_model,
+    },
     server::{
-        get_dummy_shares_for_deletion, sync_nccl, BatchMetadata, BatchQuery,
-        BatchQueryEntriesPreprocessed, ServerActor, ServerJobResult,
+        get_dummy_shares_for_deletion, seed_exchange,
+        sync_nccl::{self, NcclStateExchange, SyncTimeout, STATE_SYNC_MAGIC},
+        AppliedDeletionBatch, BatchMetadata, BatchQuery, BatchQueryEntries,
+        BatchQueryEntriesPreprocessed, DeletionBatch, RestoredEntry, ServerActor,
+        ServerActorHandle, ServerJobResult,
     },
 };
 use iris_mpc_store::{
-    fetch_and_parse_chunks, last_snapshot_timestamp, IrisSource, S3Store, Store, StoredIrisRef,
+    fetch_and_parse_chunks, last_snapshot_timestamp, schema_version, IrisSource, S3Store, Store,
+    StoredIris, StoredIrisRef,
 };
 use metrics_exporter_statsd::StatsdBuilder;
 use reqwest::StatusCode;
@@ -49,12 +100,13 @@ use std::{
     backtrace::Backtrace,
     collections::{HashMap, HashSet},
     mem, panic,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, LazyLock, Mutex,
     },
     time,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use telemetry_batteries::tracing::{datadog::DatadogBattery, TracingShutdownHandle};
 use tokio::{
@@ -62,41 +114,57 @@ use tokio::{
     task::spawn_blocking,
     time::timeout,
 };
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::Instrument;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 const REGION: &str = "eu-north-1";
 const RNG_SEED_INIT_DB: u64 = 42;
 const SQS_POLLING_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the free device memory is sampled while intake is paused.
+const MEMORY_PAUSE_INTERVAL: Duration = Duration::from_secs(1);
 const MAX_CONCURRENT_REQUESTS: usize = 32;
+/// The exit code of a server whose startup gave up waiting for the other
+/// parties, see [`SyncTimeout`].
+const SYNC_TIMEOUT_EXIT_CODE: i32 = 3;
 
 static CURRENT_BATCH_SIZE: LazyLock<Mutex<usize>> = LazyLock::new(|| Mutex::new(0));
 
 fn decode_iris_message_shares(
     code_share: String,
     mask_share: String,
+    party_id: usize,
 ) -> eyre::Result<(GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare)> {
-    let iris_share = GaloisRingIrisCodeShare::from_base64(&code_share)
-        .context("Failed to base64 parse iris code")?;
+    let iris_share =
+        GaloisRingIrisCodeShare::decode_validated(&code_share, ShareKind::Code, party_id)
+            .context("Failed to decode iris code share")?;
     let mask_share: GaloisRingTrimmedMaskCodeShare =
-        GaloisRingIrisCodeShare::from_base64(&mask_share)
-            .context("Failed to base64 parse iris mask")?
+        GaloisRingIrisCodeShare::decode_validated(&mask_share, ShareKind::Mask, party_id)
+            .context("Failed to decode iris mask share")?
             .into();
 
     Ok((iris_share, mask_share))
 }
 
-#[allow(clippy::type_complexity)]
-fn preprocess_iris_message_shares(
-    code_share: GaloisRingIrisCodeShare,
-    mask_share: GaloisRingTrimmedMaskCodeShare,
-) -> eyre::Result<(
+/// The shares of one eye as needed for a batch entry: the original shares for
+/// storage, their rotations for the in-memory database and the preprocessed
+/// rotations for the query.
+type PreprocessedIrisShares = (
     GaloisRingIrisCodeShare,
     GaloisRingTrimmedMaskCodeShare,
     Vec<GaloisRingIrisCodeShare>,
     Vec<GaloisRingTrimmedMaskCodeShare>,
     Vec<GaloisRingIrisCodeShare>,
     Vec<GaloisRingTrimmedMaskCodeShare>,
-)> {
+);
+
+/// Preprocesses the shares of an eye for the batch. Only the query rotations
+/// within `rotation_window` are materialized, see
+/// [`BatchQuery::rotation_window`].
+fn preprocess_iris_message_shares(
+    code_share: GaloisRingIrisCodeShare,
+    mask_share: GaloisRingTrimmedMaskCodeShare,
+    rotation_window: usize,
+) -> eyre::Result<PreprocessedIrisShares> {
     let mut code_share = code_share;
     let mut mask_share = mask_share;
 
@@ -117,352 +185,1070 @@ fn preprocess_iris_message_shares(
         store_mask_shares,
         db_iris_shares,
         db_mask_shares,
-        code_share.all_rotations(),
-        mask_share.all_rotations(),
+        code_share.padded_rotations(rotation_window),
+        mask_share.padded_rotations(rotation_window),
     ))
 }
 
+/// Dummy shares standing in for a request whose shares could not be
+/// processed.
+fn dummy_preprocessed_iris_shares(party_id: usize) -> PreprocessedIrisShares {
+    let dummy_code_share = GaloisRingIrisCodeShare::default_for_party(party_id);
+    let dummy_mask_share = GaloisRingTrimmedMaskCodeShare::default_for_party(party_id);
+    (
+        dummy_code_share.clone(),
+        dummy_mask_share.clone(),
+        dummy_code_share.clone().all_rotations(),
+        dummy_mask_share.clone().all_rotations(),
+        dummy_code_share.clone().all_rotations(),
+        dummy_mask_share.clone().all_rotations(),
+    )
+}
+
+fn push_batch_entry(
+    batch_query: &mut BatchQuery,
+    (left, right): (PreprocessedIrisShares, PreprocessedIrisShares),
+) {
+    let (
+        store_iris_shares_left,
+        store_mask_shares_left,
+        db_iris_shares_left,
+        db_mask_shares_left,
+        iris_shares_left,
+        mask_shares_left,
+    ) = left;
+    let (
+        store_iris_shares_right,
+        store_mask_shares_right,
+        db_iris_shares_right,
+        db_mask_shares_right,
+        iris_shares_right,
+        mask_shares_right,
+    ) = right;
+
+    batch_query.store_left.code.push(store_iris_shares_left);
+    batch_query.store_left.mask.push(store_mask_shares_left);
+    batch_query.db_left.code.extend(db_iris_shares_left);
+    batch_query.db_left.mask.extend(db_mask_shares_left);
+    batch_query.query_left.code.extend(iris_shares_left);
+    batch_query.query_left.mask.extend(mask_shares_left);
+
+    batch_query.store_right.code.push(store_iris_shares_right);
+    batch_query.store_right.mask.push(store_mask_shares_right);
+    batch_query.db_right.code.extend(db_iris_shares_right);
+    batch_query.db_right.mask.extend(db_mask_shares_right);
+    batch_query.query_right.code.extend(iris_shares_right);
+    batch_query.query_right.mask.extend(mask_shares_right);
+}
+
+/// The inputs and decisions of a processed batch, see
+/// [`iris_mpc_common::helpers::replay`]. Requests that failed before the batch
+/// and threshold updates are not part of the job result, so they are not
+/// recorded.
+#[allow(clippy::too_many_arguments)]
+fn record_batch(
+    batch: &AuditBatch,
+    request_ids: &[String],
+    mirrored_checks: &[bool],
+    rotation_windows: &[usize],
+    or_rule_indices: &[Option<Vec<u32>>],
+    store_left: &BatchQueryEntries,
+    store_right: &BatchQueryEntries,
+    deleted_ids: &[u32],
+    unknown_deletion_ids: &[u32],
+) -> eyre::Result<RecordedBatch> {
+    let mut requests = vec![];
+    for (i, request_id) in request_ids.iter().enumerate() {
+        if mirrored_checks[i] {
+            continue;
+        }
+        let shares = RecordedShares::new(
+            &store_left.code[i],
+            &store_left.mask[i],
+            &store_right.code[i],
+            &store_right.mask[i],
+        );
+        let (message_type, message) = if is_backfill_request(request_id) {
+            (BACKFILL_MESSAGE_TYPE, None)
+        } else {
+            // The mirrored check of a request is an entry with the same id.
+            let mirrored_check = request_ids
+                .iter()
+                .zip(mirrored_checks)
+                .any(|(id, &mirrored)| mirrored && id == request_id);
+            let request = UniquenessRequest {
+                batch_size:              None,
+                signup_id:               request_id.clone(),
+                s3_key:                  String::new(),
+                iris_shares:             None,
+                iris_shares_file_hashes: Default::default(),
+                mirrored_check:          Some(mirrored_check),
+                rotation_window:         rotation_windows.get(i).copied(),
+                or_rule_serial_ids:      or_rule_indices
+                    .get(i)
+                    .cloned()
+                    .flatten()
+                    .map(|indices| indices.into_iter().map(|x| x + 1).collect()),
+            };
+            (
+                UNIQUENESS_MESSAGE_TYPE,
+                Some(serde_json::to_string(&request)?),
+            )
+        };
+        requests.push(RecordedRequest {
+            request_id: request_id.clone(),
+            message_type: message_type.to_string(),
+            message,
+            shares: Some(shares),
+            error: None,
+        });
+    }
+    for &deletion_index in deleted_ids.iter().chain(unknown_deletion_ids) {
+        let serial_id = deletion_index.wrapping_add(1);
+        requests.push(RecordedRequest {
+            request_id:   format!("deletion-{}", serial_id),
+            message_type: IDENTITY_DELETION_MESSAGE_TYPE.to_string(),
+            // Soft-deletes are recorded as plain deletions, the entry is
+            // tombstoned for matching either way.
+            message:      Some(serde_json::to_string(&IdentityDeletionRequest {
+                serial_id,
+                grace_period_secs: None,
+            })?),
+            shares:       None,
+            error:        None,
+        });
+    }
+    Ok(RecordedBatch {
+        batch_id: batch.batch_id,
+        requests,
+        decisions: batch.decisions.clone(),
+        deleted_serial_ids: batch.deleted_serial_ids.clone(),
+        // The soft-deletes are recorded as plain deletions, nothing is left
+        // to purge.
+        purged_serial_ids: vec![],
+        db_digest_before: batch.db_digest_before.clone(),
+        db_digest_after: batch.db_digest_after.clone(),
+    })
+}
+
+/// The deleted and unknown indices of the single deletions of a batch,
+/// followed by the ones of its deletion batches.
+fn with_deletion_batches(
+    deleted_ids: &[u32],
+    unknown_deletion_ids: &[u32],
+    applied_deletion_batches: &[AppliedDeletionBatch],
+) -> (Vec<u32>, Vec<u32>) {
+    let deleted = deleted_ids
+        .iter()
+        .chain(
+            applied_deletion_batches
+                .iter()
+                .flat_map(|applied| &applied.deleted_ids),
+        )
+        .copied()
+        .collect();
+    let unknown = unknown_deletion_ids
+        .iter()
+        .chain(
+            applied_deletion_batches
+                .iter()
+                .flat_map(|applied| &applied.unknown_ids),
+        )
+        .copied()
+        .collect();
+    (deleted, unknown)
+}
+
+/// Checks a sample of the stored shares for consistency with the other
+/// parties, see [`iris_mpc_common::helpers::share_audit`]. Inconsistent serial
+/// ids are alerted on, but do not stop the startup.
+fn audit_stored_shares(
+    comm: &NcclComm,
+    store: &Store,
+    party_id: usize,
+    chacha_seeds: ([u32; 8], [u32; 8]),
+    sample_size: usize,
+) -> eyre::Result<()> {
+    let seed = ShareAuditChallenge::joint_seed(&sync_nccl::sync_share_audit_seed(comm)?);
+    let challenge = ShareAuditChallenge::new(&seed, 0);
+    let handle = tokio::runtime::Handle::current();
+    let sample = challenge.sample(handle.block_on(store.count_irises())?, sample_size);
+    let irises = handle.block_on(store.fetch_irises(&sample))?;
+    let contribution = ShareAuditContribution::new(
+        &challenge,
+        PartyID::try_from(party_id)?,
+        irises.iter().map(|iris| {
+            (iris.id() as u32, vec![
+                iris.left_code(),
+                iris.left_mask(),
+                iris.right_code(),
+                iris.right_mask(),
+            ])
+        }),
+        zero_shares(
+            bytemuck::cast_slice(&chacha_seeds.0),
+            bytemuck::cast_slice(&chacha_seeds.1),
+        ),
+    );
+    let contributions = sync_nccl::sync_share_audit(comm, &contribution, sample_size)?;
+    report_audit(sample.len(), &find_inconsistent(&contributions)?);
+    Ok(())
+}
+
+/// The soft-deleted serial ids, which are loaded into the actor with the dummy
+/// shares of a deletion. They stay tombstoned for matching until they are
+/// restored.
+struct SoftDeletedShares {
+    serial_ids: HashSet<i64>,
+    dummy:      (GaloisRingIrisCodeShare, GaloisRingTrimmedMaskCodeShare),
+}
+
+impl SoftDeletedShares {
+    async fn new(store: &Store, party_id: usize) -> eyre::Result<Self> {
+        let serial_ids = store
+            .soft_deleted_irises()
+            .await?
+            .into_iter()
+            .map(|(serial_id, _)| serial_id)
+            .collect::<HashSet<_>>();
+        tracing::info!("Tombstoning {} soft-deleted entries", serial_ids.len());
+        Ok(Self {
+            serial_ids,
+            dummy: get_dummy_shares_for_deletion(party_id),
+        })
+    }
+
+    fn load(&self, actor: &mut ServerActor, iris: &StoredIris) {
+        if self.serial_ids.contains(&iris.id()) {
+            let (code, mask) = &self.dummy;
+            actor.load_single_record(
+                iris.index() - 1,
+                &code.coefs,
+                &mask.coefs,
+                &code.coefs,
+                &mask.coefs,
+            );
+        } else {
+            actor.load_single_record(
+                iris.index() - 1,
+                iris.left_code(),
+                iris.left_mask(),
+                iris.right_code(),
+                iris.right_mask(),
+            );
+        }
+    }
+}
+
+/// Restores the DB from the local snapshot in `dir` and appends the store
+/// entries added since it was taken. Returns false if there is no usable
+/// snapshot, in which case the DB has to be loaded from the store.
+async fn restore_db_snapshot(
+    actor: &mut ServerActor,
+    store: &Store,
+    soft_deleted: &SoftDeletedShares,
+    dir: &Path,
+    store_len: usize,
+) -> eyre::Result<bool> {
+    let header = match ServerActor::read_snapshot_header(dir) {
+        Ok(header) => header,
+        Err(e) => {
+            tracing::info!("No usable DB snapshot in {}: {}", dir.display(), e);
+            return Ok(false);
+        }
+    };
+    let snapshot_len = header.digest.db_len;
+    if snapshot_len > store_len {
+        tracing::warn!(
+            "DB snapshot has {} entries, but the store only {}",
+            snapshot_len,
+            store_len
+        );
+        metrics::counter!("db.snapshot.refused").increment(1);
+        return Ok(false);
+    }
+
+    let (db_len, last_modified_at) = store.modification_watermark(snapshot_len).await?;
+    let expected = SnapshotDigest {
+        db_len,
+        last_modified_at,
+    };
+    let now = Instant::now();
+    if let Err(e) = actor.restore_from(dir, &expected) {
+        tracing::warn!("Refusing DB snapshot: {}", e);
+        metrics::counter!("db.snapshot.refused").increment(1);
+        return Ok(false);
+    }
+    tracing::info!(
+        "Restored {} entries from the DB snapshot in {:?}",
+        snapshot_len,
+        now.elapsed()
+    );
+
+    let from_sizes = actor.current_db_sizes();
+    let mut delta = store.stream_irises_in_range(snapshot_len as u64 + 1..store_len as u64 + 1);
+    let mut n_appended = 0;
+    while let Some(iris) = delta.try_next().await? {
+        soft_deleted.load(actor, &iris);
+        actor.increment_db_size(iris.index() - 1);
+        n_appended += 1;
+    }
+    if snapshot_len + n_appended != store_len {
+        return Err(eyre!(
+            "Appended {} entries to a snapshot of {}, but the store has {}",
+            n_appended,
+            snapshot_len,
+            store_len
+        ));
+    }
+    actor.append_db(&from_sizes)?;
+    tracing::info!("Appended {} entries newer than the snapshot", n_appended);
+
+    if n_appended > 0 {
+        write_db_snapshot(actor, store, dir, store_len).await;
+    }
+    Ok(true)
+}
+
+/// Writes a snapshot of the loaded DB to `dir` for the next start. Failures
+/// are only logged, the snapshot is an optimization.
+async fn write_db_snapshot(actor: &ServerActor, store: &Store, dir: &Path, store_len: usize) {
+    let digest = match store.modification_watermark(store_len).await {
+        Ok((db_len, last_modified_at)) => SnapshotDigest {
+            db_len,
+            last_modified_at,
+        },
+        Err(e) => {
+            tracing::warn!("Failed to get the store digest for the DB snapshot: {}", e);
+            return;
+        }
+    };
+    let now = Instant::now();
+    match actor.snapshot_to(dir, digest) {
+        Ok(()) => tracing::info!(
+            "Wrote DB snapshot to {} in {:?}",
+            dir.display(),
+            now.elapsed()
+        ),
+        Err(e) => tracing::warn!("Failed to write DB snapshot to {}: {}", dir.display(), e),
+    }
+}
+
+/// Fetches and decodes the shares of a reset update.
+async fn fetch_reset_update(
+    request: &ResetUpdateRequest,
+    party_id: usize,
+    key_pairs: &KeyPairProvider,
+    s3_client: &Arc<S3Client>,
+    http_client: &reqwest::Client,
+    download: &ShareDownloadConfig,
+    supported_versions: &[SupportedIrisVersion],
+) -> eyre::Result<RestoredEntry> {
+    let payload = request
+        .get_iris_data_by_party_id(party_id, s3_client, http_client, download)
+        .await
+        .context("Failed to get iris shares")?;
+    let share = request
+        .decrypt_iris_share(payload, key_pairs, supported_versions)
+        .await
+        .context("Failed to decrypt iris shares")?;
+    request
+        .validate_iris_share(party_id, share.clone())
+        .context("Failed to validate iris shares")?;
+    let (left_code, left_mask) = decode_iris_message_shares(
+        share.left_iris_code_shares,
+        share.left_mask_code_shares,
+        party_id,
+    )?;
+    let (right_code, right_mask) = decode_iris_message_shares(
+        share.right_iris_code_shares,
+        share.right_mask_code_shares,
+        party_id,
+    )?;
+    Ok(RestoredEntry {
+        index: request.index(),
+        left_code,
+        left_mask,
+        right_code,
+        right_mask,
+    })
+}
+
+/// The metadata of a request, carrying the trace context it was received
+/// with.
+fn batch_metadata(trace_context: Option<TraceContext>) -> BatchMetadata {
+    let mut batch_metadata = BatchMetadata::default();
+    if let Some(trace_context) = trace_context {
+        batch_metadata.trace_id = trace_context.trace_id.to_string();
+        batch_metadata.span_id = trace_context.span_id.to_string();
+    }
+    batch_metadata
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn receive_batch(
     party_id: usize,
-    client: &Client,
-    sns_client: &SNSClient,
+    request_queues: &[(SqsRequestReceiver, Option<RequestLane>)],
+    result_publisher: &SnsResultPublisher,
     s3_client: &Arc<S3Client>,
+    http_client: &reqwest::Client,
     config: &Config,
     store: &Store,
     skip_request_ids: &[String],
-    shares_encryption_key_pairs: SharesEncryptionKeyPairs,
+    shares_encryption_key_pairs: &Arc<KeyPairProvider>,
     shutdown_handler: &ShutdownHandler,
-    error_result_attributes: &HashMap<String, MessageAttributeValue>,
-) -> eyre::Result<Option<BatchQuery>, ReceiveRequestError> {
+    intake: &mut RequestIntake,
+    backfill: &mut Option<mpsc::Receiver<BackfillEntry>>,
+    memory_monitor: &mut Option<MemoryMonitor>,
+    is_ready_flag: &AtomicBool,
+) -> eyre::Result<Option<(BatchQuery, Vec<CommittedMessage>)>, ReceiveRequestError> {
     let max_batch_size = config.clone().max_batch_size;
-    let queue_url = &config.clone().requests_queue_url;
+    let receivers: Vec<_> = request_queues
+        .iter()
+        .map(|(receiver, _)| receiver)
+        .collect();
     if shutdown_handler.is_shutting_down() {
         tracing::info!("Stopping batch receive due to shutdown signal...");
+        // Let the other consumers pick up the requests we did not get to.
+        intake.release_all(&receivers).await?;
         return Ok(None);
     }
 
-    let mut batch_query = BatchQuery::default();
+    // Close to the device capacity, batches shrink, and intake pauses while
+    // the devices are critically short of memory. The requests held so far
+    // stay hidden meanwhile.
+    let batch_size_limit = match memory_monitor.as_mut() {
+        Some(monitor) => loop {
+            match monitor.sample() {
+                Ok(MemoryPressure::Paused) => {
+                    is_ready_flag.store(false, Ordering::SeqCst);
+                    if shutdown_handler.is_shutting_down() {
+                        intake.release_all(&receivers).await?;
+                        return Ok(None);
+                    }
+                    intake.keep_alive(&receivers).await;
+                    tokio::time::sleep(MEMORY_PAUSE_INTERVAL).await;
+                }
+                Ok(pressure) => {
+                    is_ready_flag.store(true, Ordering::SeqCst);
+                    break Some(pressure.batch_size(max_batch_size));
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to sample the free device memory: {}", e);
+                    break None;
+                }
+            }
+        },
+        None => None,
+    };
+    let current_batch_size = || {
+        let batch_size = *CURRENT_BATCH_SIZE.lock().unwrap();
+        batch_size_limit.map_or(batch_size, |limit| batch_size.min(limit))
+    };
+
+    // Backfill batches are only formed while no requests are waiting, so that
+    // a request waits for at most one of them.
+    if intake.is_empty() {
+        if let Some(backfill) = backfill.as_mut() {
+            let mut entries = vec![];
+            let batch_size = current_batch_size();
+            while entries.len() < batch_size {
+                match backfill.try_recv() {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => break,
+                }
+            }
+            if !entries.is_empty() {
+                let mut batch_query =
+                    receive_backfill_batch(party_id, config.max_rotation_window, entries).await?;
+                batch_query.batch_size_limit = batch_size_limit;
+                return Ok(Some((batch_query, vec![])));
+            }
+        }
+    }
+
+    let mut batch_query = BatchQuery {
+        batch_size_limit,
+        ..Default::default()
+    };
 
     let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
     let mut handles = vec![];
-    let mut msg_counter = 0;
-
-    while msg_counter < *CURRENT_BATCH_SIZE.lock().unwrap() {
-        let rcv_message_output = client
-            .receive_message()
-            .max_number_of_messages(1)
-            .queue_url(queue_url)
-            .send()
-            .await
-            .map_err(ReceiveRequestError::FailedToReadFromSQS)?;
 
-        if let Some(messages) = rcv_message_output.messages {
-            for sqs_message in messages {
-                let message: SQSMessage = serde_json::from_str(sqs_message.body().unwrap())
-                    .map_err(|e| ReceiveRequestError::json_parse_error("SQS body", e))?;
+    // Poll all queues at least once, so that waiting interactive requests are
+    // seen even if the pending pools already hold a full batch.
+    loop {
+        intake.keep_alive(&receivers).await;
+
+        let mut received = ReceivedRequests::default();
+        let received_messages = intake
+            .poll(
+                request_queues,
+                result_publisher,
+                config,
+                skip_request_ids,
+                &mut received,
+            )
+            .await?;
+        if let Some(batch_size) = received.batch_size {
+            *CURRENT_BATCH_SIZE.lock().unwrap() = batch_size;
+        }
+        // Some party (maybe us) already meant to delete these requests.
+        if !received.skipped_request_ids.is_empty() {
+            store
+                .mark_requests_deleted(&received.skipped_request_ids)
+                .await
+                .map_err(ReceiveRequestError::FailedToMarkRequestAsDeleted)?;
+        }
+        apply_received_requests(
+            received,
+            &mut batch_query,
+            party_id,
+            result_publisher,
+            s3_client,
+            http_client,
+            config,
+            shares_encryption_key_pairs,
+        )
+        .await?;
 
-                // messages arrive to SQS through SNS. So, all the attributes set in SNS are
-                // moved into the SQS body.
-                let message_attributes = message.message_attributes;
+        if intake.len() >= current_batch_size() {
+            break;
+        }
+        if !received_messages {
+            tokio::time::sleep(SQS_POLLING_INTERVAL).await;
+        }
+    }
 
-                let mut batch_metadata = BatchMetadata::default();
+    intake.report_lane_depths();
+    let entries = intake.compose(current_batch_size(), &receivers).await?;
+    // The budget of the batch starts once its requests are chosen.
+    let deadline = Arc::new(Mutex::new(BatchDeadline::start(config.latency_budget)));
+    // Every mirrored check takes an extra slot in the batch.
+    let mut free_slots = batch_size_limit
+        .map_or(max_batch_size, |limit| limit.min(max_batch_size))
+        .saturating_sub(entries.len());
+    let mut mirrored_checks = vec![];
+    let mut rotation_windows = vec![];
+
+    // Replayed requests stay in the batch as invalid entries, so that a party
+    // whose window ends a moment earlier does not shift the batch.
+    let now_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the unix epoch")
+        .as_secs() as i64;
+    let replay_window_secs = config.replay_window_secs as i64;
+    if replay_window_secs > 0 {
+        store
+            .prune_share_hashes(now_secs - replay_window_secs)
+            .await
+            .map_err(ReceiveRequestError::FailedToCheckReplay)?;
+    }
+    let mut replayed_requests = vec![];
+    let mut requests = vec![];
+    let mut committed = vec![];
+    for entry in entries {
+        committed.push(entry.item.committed());
+        let PendingRequest {
+            request: smpc_request,
+            trace_context: received_trace_context,
+            ..
+        } = entry.item;
+        let batch_metadata = batch_metadata(received_trace_context);
+        let request_id = smpc_request.request_id().to_string();
+        requests.push(smpc_request.clone());
+        let lane = entry.lane;
+        metrics::histogram!("request_lane.queue_latency", "lane" => lane.as_str())
+            .record(entry.queued_for.as_secs_f64());
+
+        // Reauth requests are decided against their targets only. Like reset
+        // checks, they are compared with all rotations.
+        let (mirrored_check, rotation_window, reauth_targets, or_rule_indices) = match &smpc_request
+        {
+            BatchRequest::Uniqueness(request) => {
+                let mirrored_check =
+                    config.enable_mirrored_checks && request.mirrored_check.unwrap_or(false);
+                let mirrored_check = if mirrored_check && free_slots == 0 {
+                    tracing::warn!(
+                        "No space left in the batch, skipping mirrored check of {}",
+                        request_id
+                    );
+                    false
+                } else {
+                    free_slots -= mirrored_check as usize;
+                    mirrored_check
+                };
 
-                if let Some(trace_id) = message_attributes.get(TRACE_ID_MESSAGE_ATTRIBUTE_NAME) {
-                    let trace_id = trace_id.string_value().unwrap();
-                    batch_metadata.trace_id = trace_id.to_string();
-                }
-                if let Some(span_id) = message_attributes.get(SPAN_ID_MESSAGE_ATTRIBUTE_NAME) {
-                    let span_id = span_id.string_value().unwrap();
-                    batch_metadata.span_id = span_id.to_string();
+                let rotation_window = request.rotation_window(config.max_rotation_window);
+                if request.rotation_window > Some(rotation_window) {
+                    tracing::warn!(
+                        "Rotation window of {} is above the maximum, using {}",
+                        request_id,
+                        rotation_window
+                    );
                 }
+                (
+                    mirrored_check,
+                    rotation_window,
+                    None,
+                    request.or_rule_indices(),
+                )
+            }
+            BatchRequest::ReAuth(request) => (
+                false,
+                config.max_rotation_window,
+                Some(request.target_indices()),
+                None,
+            ),
+            BatchRequest::ResetCheck(_) => (false, config.max_rotation_window, None, None),
+        };
+        mirrored_checks.push(mirrored_check);
+        rotation_windows.push(rotation_window);
+        batch_query.reauth_targets.push(reauth_targets);
+        batch_query.or_rule_indices.push(or_rule_indices);
+        batch_query
+            .reset_checks
+            .push(matches!(smpc_request, BatchRequest::ResetCheck(_)));
+
+        let replayed = replay_window_secs > 0
+            && store
+                .record_share_hashes(
+                    smpc_request.iris_shares_file_hashes(),
+                    &request_id,
+                    now_secs,
+                    replay_window_secs,
+                )
+                .await
+                .map_err(ReceiveRequestError::FailedToCheckReplay)?;
+        if replayed {
+            tracing::warn!(
+                "Rejecting {}: its shares were already submitted under another signup id",
+                request_id
+            );
+            metrics::counter!("request.replayed").increment(1);
+        }
+        replayed_requests.push(replayed);
 
-                let request_type = message_attributes
-                    .get(SMPC_MESSAGE_TYPE_ATTRIBUTE)
-                    .ok_or(ReceiveRequestError::NoMessageTypeAttribute)?
-                    .string_value()
-                    .ok_or(ReceiveRequestError::NoMessageTypeAttribute)?;
-
-                match request_type {
-                    CIRCUIT_BREAKER_MESSAGE_TYPE => {
-                        let circuit_breaker_request: CircuitBreakerRequest =
-                            serde_json::from_str(&message.message).map_err(|e| {
-                                ReceiveRequestError::json_parse_error("circuit_breaker_request", e)
-                            })?;
-                        metrics::counter!("request.received", "type" => "circuit_breaker")
-                            .increment(1);
-                        client
-                            .delete_message()
-                            .queue_url(queue_url)
-                            .receipt_handle(sqs_message.receipt_handle.unwrap())
-                            .send()
-                            .await
-                            .map_err(ReceiveRequestError::FailedToDeleteFromSQS)?;
-                        if let Some(batch_size) = circuit_breaker_request.batch_size {
-                            // Updating the batch size to ensure we process the messages in the next
-                            // loop
-                            *CURRENT_BATCH_SIZE.lock().unwrap() =
-                                batch_size.clamp(1, max_batch_size);
-                            tracing::info!(
-                                "Updating batch size to {} due to circuit breaker message",
-                                batch_size
-                            );
-                        }
-                    }
+        let span = request_span(&request_id, &batch_metadata.trace_id);
+        if let Some(trace_context) = received_trace_context {
+            trace_context.attach(&span);
+        }
+        batch_query.request_ids.push(request_id);
+        batch_query.metadata.push(batch_metadata);
+        batch_query.request_lanes.push(lane);
+
+        let shares_encryption_key_pairs = Arc::clone(shares_encryption_key_pairs);
+        let semaphore = Arc::clone(&semaphore);
+        let s3_client_arc = Arc::clone(s3_client);
+        let bucket_name = config.shares_bucket_name.clone();
+        let http_client = http_client.clone();
+        let download = config.share_download;
+        let supported_versions = config.supported_iris_versions.0.clone();
+        let deadline = Arc::clone(&deadline);
+        let handle = tokio::spawn(
+            async move {
+                let _ = semaphore.acquire().await?;
+
+                if replayed {
+                    eyre::bail!("Replayed request");
+                }
 
-                    IDENTITY_DELETION_MESSAGE_TYPE => {
-                        // If it's a deletion request, we just store the serial_id and continue.
-                        // Deletion will take place when batch process starts.
-                        let identity_deletion_request: IdentityDeletionRequest =
-                            serde_json::from_str(&message.message).map_err(|e| {
-                                ReceiveRequestError::json_parse_error(
-                                    "Identity deletion request",
-                                    e,
-                                )
-                            })?;
-                        metrics::counter!("request.received", "type" => "identity_deletion")
-                            .increment(1);
-                        batch_query
-                            .deletion_requests_indices
-                            .push(identity_deletion_request.serial_id - 1); // serial_id is 1-indexed
-                        batch_query.deletion_requests_metadata.push(batch_metadata);
-                        client
-                            .delete_message()
-                            .queue_url(queue_url)
-                            .receipt_handle(sqs_message.receipt_handle.unwrap())
-                            .send()
-                            .await
-                            .map_err(ReceiveRequestError::FailedToDeleteFromSQS)?;
+                let fetch_start = Instant::now();
+                let base_64_encoded_message_payload = match smpc_request
+                    .get_iris_data_by_party_id(
+                        party_id,
+                        &bucket_name,
+                        &s3_client_arc,
+                        &http_client,
+                        &download,
+                    )
+                    .instrument(Phase::Fetch.span())
+                    .await
+                {
+                    Ok(iris_message_share) => iris_message_share,
+                    Err(e) => {
+                        tracing::error!("Failed to get iris shares: {:?}", e);
+                        return Err(e).context("Failed to get iris shares");
                     }
-                    UNIQUENESS_MESSAGE_TYPE => {
-                        msg_counter += 1;
-
-                        let shares_encryption_key_pairs = shares_encryption_key_pairs.clone();
-
-                        let smpc_request: UniquenessRequest =
-                            serde_json::from_str(&message.message).map_err(|e| {
-                                ReceiveRequestError::json_parse_error("Uniqueness request", e)
-                            })?;
-                        metrics::counter!("request.received", "type" => "uniqueness_verification")
-                            .increment(1);
-                        store
-                            .mark_requests_deleted(&[smpc_request.signup_id.clone()])
-                            .await
-                            .map_err(ReceiveRequestError::FailedToMarkRequestAsDeleted)?;
-
-                        client
-                            .delete_message()
-                            .queue_url(queue_url)
-                            .receipt_handle(sqs_message.receipt_handle.unwrap())
-                            .send()
-                            .await
-                            .map_err(ReceiveRequestError::FailedToDeleteFromSQS)?;
-
-                        if skip_request_ids.contains(&smpc_request.signup_id) {
-                            // Some party (maybe us) already meant to delete this request, so we
-                            // skip it. Ignore this message when calculating the batch size.
-                            msg_counter -= 1;
-                            continue;
+                };
+                let validate_start = Instant::now();
+                deadline
+                    .lock()
+                    .unwrap()
+                    .record(BudgetPhase::Fetch, validate_start - fetch_start);
+
+                let iris_message_share = async {
+                    let iris_message_share = match smpc_request
+                        .decrypt_iris_share(
+                            base_64_encoded_message_payload,
+                            &shares_encryption_key_pairs,
+                            &supported_versions,
+                        )
+                        .await
+                    {
+                        Ok(iris_data) => iris_data,
+                        Err(e) => {
+                            tracing::error!("Failed to decrypt iris shares: {:?}", e);
+                            return Err(e).context("Failed to decrypt iris shares");
                         }
+                    };
 
-                        if let Some(batch_size) = smpc_request.batch_size {
-                            // Updating the batch size instantly makes it a bit unpredictable, since
-                            // if we're already above the new limit, we'll still process the current
-                            // batch at the higher limit. On the other
-                            // hand, updating it after the batch is
-                            // processed would not let us "unblock" the protocol if we're stuck with
-                            // low throughput.
-                            *CURRENT_BATCH_SIZE.lock().unwrap() =
-                                batch_size.clamp(1, max_batch_size);
-                            tracing::info!("Updating batch size to {}", batch_size);
+                    match smpc_request.validate_iris_share(party_id, iris_message_share.clone()) {
+                        Ok(()) => {}
+                        Err(e) => {
+                            tracing::error!("Failed to validate iris shares: {:?}", e);
+                            return Err(e).context("Failed to validate iris shares");
                         }
-
-                        batch_query.request_ids.push(smpc_request.signup_id.clone());
-                        batch_query.metadata.push(batch_metadata);
-
-                        let semaphore = Arc::clone(&semaphore);
-                        let s3_client_arc = Arc::clone(s3_client);
-                        let bucket_name = config.shares_bucket_name.clone();
-                        let handle = tokio::spawn(async move {
-                            let _ = semaphore.acquire().await?;
-
-                            let base_64_encoded_message_payload = match smpc_request
-                                .get_iris_data_by_party_id(party_id, &bucket_name, &s3_client_arc)
-                                .await
-                            {
-                                Ok(iris_message_share) => iris_message_share,
-                                Err(e) => {
-                                    tracing::error!("Failed to get iris shares: {:?}", e);
-                                    eyre::bail!("Failed to get iris shares: {:?}", e);
-                                }
-                            };
-
-                            let iris_message_share = match smpc_request.decrypt_iris_share(
-                                base_64_encoded_message_payload,
-                                shares_encryption_key_pairs.clone(),
-                            ) {
-                                Ok(iris_data) => iris_data,
-                                Err(e) => {
-                                    tracing::error!("Failed to decrypt iris shares: {:?}", e);
-                                    eyre::bail!("Failed to decrypt iris shares: {:?}", e);
-                                }
-                            };
-
-                            match smpc_request
-                                .validate_iris_share(party_id, iris_message_share.clone())
-                            {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    tracing::error!("Failed to validate iris shares: {:?}", e);
-                                    eyre::bail!("Failed to validate iris shares: {:?}", e);
-                                }
-                            }
-
-                            let (left_code, left_mask) = decode_iris_message_shares(
-                                iris_message_share.left_iris_code_shares,
-                                iris_message_share.left_mask_code_shares,
-                            )?;
-
-                            let (right_code, right_mask) = decode_iris_message_shares(
-                                iris_message_share.right_iris_code_shares,
-                                iris_message_share.right_mask_code_shares,
-                            )?;
-
-                            // Preprocess shares for left eye.
-                            let left_future = spawn_blocking(move || {
-                                preprocess_iris_message_shares(left_code, left_mask)
-                            });
-
-                            // Preprocess shares for right eye.
-                            let right_future = spawn_blocking(move || {
-                                preprocess_iris_message_shares(right_code, right_mask)
-                            });
-
-                            let (left_result, right_result) =
-                                tokio::join!(left_future, right_future);
-
-                            Ok((
-                                left_result.context("while processing left iris shares")??,
-                                right_result.context("while processing right iris shares")??,
-                            ))
-                        });
-
-                        handles.push(handle);
-                    }
-                    _ => {
-                        client
-                            .delete_message()
-                            .queue_url(queue_url)
-                            .receipt_handle(sqs_message.receipt_handle.unwrap())
-                            .send()
-                            .await
-                            .map_err(ReceiveRequestError::FailedToDeleteFromSQS)?;
-                        tracing::error!("Error: {}", ReceiveRequestError::InvalidMessageType);
                     }
+                    Ok(iris_message_share)
                 }
+                .instrument(Phase::Validate.span())
+                .await?;
+                deadline
+                    .lock()
+                    .unwrap()
+                    .record(BudgetPhase::Validate, validate_start.elapsed());
+
+                let (left_code, left_mask) = decode_iris_message_shares(
+                    iris_message_share.left_iris_code_shares,
+                    iris_message_share.left_mask_code_shares,
+                    party_id,
+                )?;
+
+                let (right_code, right_mask) = decode_iris_message_shares(
+                    iris_message_share.right_iris_code_shares,
+                    iris_message_share.right_mask_code_shares,
+                    party_id,
+                )?;
+
+                // The mirrored left eye is compared against the right eyes in the
+                // database and vice versa.
+                let mirrored_shares = mirrored_check.then(|| {
+                    (
+                        (right_code.mirrored(), right_mask.mirrored()),
+                        (left_code.mirrored(), left_mask.mirrored()),
+                    )
+                });
+
+                // Preprocess shares for left eye.
+                let left_future = spawn_blocking(move || {
+                    preprocess_iris_message_shares(left_code, left_mask, rotation_window)
+                });
+
+                // Preprocess shares for right eye.
+                let right_future = spawn_blocking(move || {
+                    preprocess_iris_message_shares(right_code, right_mask, rotation_window)
+                });
+
+                let (left_result, right_result) = tokio::join!(left_future, right_future);
+
+                let mirrored = match mirrored_shares {
+                    Some(((left_code, left_mask), (right_code, right_mask))) => {
+                        let mirrored = spawn_blocking(move || {
+                            eyre::Ok((
+                                preprocess_iris_message_shares(
+                                    left_code,
+                                    left_mask,
+                                    rotation_window,
+                                )?,
+                                preprocess_iris_message_shares(
+                                    right_code,
+                                    right_mask,
+                                    rotation_window,
+                                )?,
+                            ))
+                        })
+                        .await
+                        .context("while processing mirrored iris shares")??;
+                        Some(mirrored)
+                    }
+                    None => None,
+                };
+
+                Ok((
+                    (
+                        left_result.context("while processing left iris shares")??,
+                        right_result.context("while processing right iris shares")??,
+                    ),
+                    mirrored,
+                ))
             }
-        } else {
-            tokio::time::sleep(SQS_POLLING_INTERVAL).await;
-        }
+            .instrument(span),
+        );
+
+        handles.push(handle);
     }
+    record_batch_size(&tracing::Span::current(), batch_query.request_ids.len());
+
+    let mut mirrored_entries = vec![];
     for (index, handle) in handles.into_iter().enumerate() {
-        let (
-            (
-                (
-                    store_iris_shares_left,
-                    store_mask_shares_left,
-                    db_iris_shares_left,
-                    db_mask_shares_left,
-                    iris_shares_left,
-                    mask_shares_left,
-                ),
-                (
-                    store_iris_shares_right,
-                    store_mask_shares_right,
-                    db_iris_shares_right,
-                    db_mask_shares_right,
-                    iris_shares_right,
-                    mask_shares_right,
-                ),
-            ),
-            valid_entry,
-        ) = match handle
+        let (entry, mirrored_entry, valid_entry) = match handle
             .await
             .map_err(ReceiveRequestError::FailedToJoinHandle)?
         {
-            Ok(res) => (res, true),
+            Ok((entry, mirrored_entry)) => (entry, mirrored_entry, true),
             Err(e) => {
                 tracing::error!("Failed to process iris shares: {:?}", e);
+                let error_code = if replayed_requests[index] {
+                    ErrorCode::ReplayedRequest
+                } else {
+                    error_code_of(&e)
+                };
+                metrics::counter!("request.failed", "code" => error_code.as_str()).increment(1);
                 // Return error message back to the signup-service if failed to process iris
                 // shares
-                send_error_results_to_sns(
-                    batch_query.request_ids[index].clone(),
-                    &batch_query.metadata[index],
-                    sns_client,
+                publish_request_error(
+                    &requests[index],
+                    trace_context(&batch_query.metadata[index]).as_ref(),
+                    result_publisher,
                     config,
-                    error_result_attributes,
-                    UNIQUENESS_MESSAGE_TYPE,
-                    ERROR_FAILED_TO_PROCESS_IRIS_SHARES,
+                    error_code,
+                    Some(error_message_of(&e)),
                 )
                 .await?;
                 // If we failed to process the iris shares, we include a dummy entry in the
                 // batch in order to keep the same order across nodes
-                let dummy_code_share = GaloisRingIrisCodeShare::default_for_party(party_id);
-                let dummy_mask_share = GaloisRingTrimmedMaskCodeShare::default_for_party(party_id);
-                (
-                    (
-                        (
-                            dummy_code_share.clone(),
-                            dummy_mask_share.clone(),
-                            dummy_code_share.clone().all_rotations(),
-                            dummy_mask_share.clone().all_rotations(),
-                            dummy_code_share.clone().all_rotations(),
-                            dummy_mask_share.clone().all_rotations(),
-                        ),
-                        (
-                            dummy_code_share.clone(),
-                            dummy_mask_share.clone(),
-                            dummy_code_share.clone().all_rotations(),
-                            dummy_mask_share.clone().all_rotations(),
-                            dummy_code_share.clone().all_rotations(),
-                            dummy_mask_share.clone().all_rotations(),
-                        ),
-                    ),
-                    false,
-                )
+                let dummy = dummy_preprocessed_iris_shares(party_id);
+                let mirrored_entry = mirrored_checks[index].then(|| (dummy.clone(), dummy.clone()));
+                ((dummy.clone(), dummy), mirrored_entry, false)
             }
         };
 
         batch_query.valid_entries.push(valid_entry);
+        batch_query.mirrored_checks.push(false);
+        batch_query.rotation_windows.push(rotation_windows[index]);
+        push_batch_entry(&mut batch_query, entry);
 
-        batch_query.store_left.code.push(store_iris_shares_left);
-        batch_query.store_left.mask.push(store_mask_shares_left);
-        batch_query.db_left.code.extend(db_iris_shares_left);
-        batch_query.db_left.mask.extend(db_mask_shares_left);
-        batch_query.query_left.code.extend(iris_shares_left);
-        batch_query.query_left.mask.extend(mask_shares_left);
-
-        batch_query.store_right.code.push(store_iris_shares_right);
-        batch_query.store_right.mask.push(store_mask_shares_right);
-        batch_query.db_right.code.extend(db_iris_shares_right);
-        batch_query.db_right.mask.extend(db_mask_shares_right);
-        batch_query.query_right.code.extend(iris_shares_right);
-        batch_query.query_right.mask.extend(mask_shares_right);
+        if let Some(mirrored_entry) = mirrored_entry {
+            mirrored_entries.push((index, mirrored_entry, valid_entry));
+        }
+    }
+
+    // The mirrored checks go after all requests, so that no request is compared
+    // against them within the batch.
+    for (index, entry, valid_entry) in mirrored_entries {
+        batch_query
+            .request_ids
+            .push(batch_query.request_ids[index].clone());
+        batch_query
+            .metadata
+            .push(batch_query.metadata[index].clone());
+        batch_query
+            .request_lanes
+            .push(batch_query.request_lanes[index]);
+        batch_query.valid_entries.push(valid_entry);
+        batch_query.mirrored_checks.push(true);
+        batch_query.rotation_windows.push(rotation_windows[index]);
+        batch_query.reauth_targets.push(None);
+        batch_query
+            .or_rule_indices
+            .push(batch_query.or_rule_indices[index].clone());
+        batch_query.reset_checks.push(false);
+        push_batch_entry(&mut batch_query, entry);
     }
 
     tracing::info!("batch signups ids in order: {:?}", batch_query.request_ids);
 
+    // All tasks holding the deadline are done.
+    let deadline = Arc::into_inner(deadline)
+        .expect("the request tasks are finished")
+        .into_inner()
+        .unwrap();
+    deadline.report_soft_breach(BudgetPhase::Fetch);
+    deadline.report_soft_breach(BudgetPhase::Validate);
+    let shed = match deadline.check_before_gpu() {
+        BudgetDecision::Proceed => false,
+        BudgetDecision::Shed { elapsed, projected } => {
+            tracing::warn!(
+                elapsed_ms = elapsed.as_millis() as u64,
+                projected_ms = projected.as_millis() as u64,
+                "Shedding batch of {} requests, it would exceed its latency budget",
+                committed.len()
+            );
+            true
+        }
+    };
+    batch_query.shed_entries = vec![shed; batch_query.request_ids.len()];
+    batch_query.deadline = Some(deadline);
+
     // Preprocess query shares here already to avoid blocking the actor
     batch_query.query_left_preprocessed =
-        BatchQueryEntriesPreprocessed::from(batch_query.query_left.clone());
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.query_left.clone());
     batch_query.query_right_preprocessed =
-        BatchQueryEntriesPreprocessed::from(batch_query.query_right.clone());
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.query_right.clone());
     batch_query.db_left_preprocessed =
-        BatchQueryEntriesPreprocessed::from(batch_query.db_left.clone());
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.db_left.clone());
     batch_query.db_right_preprocessed =
-        BatchQueryEntriesPreprocessed::from(batch_query.db_right.clone());
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.db_right.clone());
+
+    Ok(Some((batch_query, committed)))
+}
+
+/// Adds the control requests received while polling to the batch. Unlike the
+/// batch requests, they were already deleted from their queues.
+#[allow(clippy::too_many_arguments)]
+async fn apply_received_requests(
+    received: ReceivedRequests,
+    batch_query: &mut BatchQuery,
+    party_id: usize,
+    result_publisher: &SnsResultPublisher,
+    s3_client: &Arc<S3Client>,
+    http_client: &reqwest::Client,
+    config: &Config,
+    shares_encryption_key_pairs: &Arc<KeyPairProvider>,
+) -> Result<(), ReceiveRequestError> {
+    // Deletion will take place when batch process starts.
+    for deletion in received.deletions {
+        // serial_id is 1-indexed, 0 maps to an index beyond any DB.
+        batch_query
+            .deletion_requests_indices
+            .push(deletion.request.serial_id.wrapping_sub(1));
+        batch_query
+            .deletion_requests_metadata
+            .push(batch_metadata(deletion.trace_context));
+        batch_query.deletion_requests_purge_at.push(
+            deletion
+                .request
+                .grace_period_secs
+                .map(|grace_period_secs| unix_now_secs() + grace_period_secs),
+        );
+    }
+    for deletion_batch in received.deletion_batches {
+        batch_query.deletion_batches.push(DeletionBatch {
+            message_id: deletion_batch.message_id,
+            serial_ids: deletion_batch.request.serial_ids,
+            metadata:   batch_metadata(deletion_batch.trace_context),
+        });
+    }
+    for restore in received.restores {
+        batch_query
+            .restore_requests_indices
+            .push(restore.request.serial_id.wrapping_sub(1));
+        batch_query
+            .restore_requests_metadata
+            .push(batch_metadata(restore.trace_context));
+    }
+    for threshold_update in received.threshold_updates {
+        match verify_threshold_update(config, &threshold_update.request) {
+            Ok(scheduled) => {
+                tracing::info!(
+                    "Received threshold version {} for batch {}",
+                    scheduled.params.version,
+                    scheduled.activation_batch
+                );
+                batch_query.threshold_updates.push(scheduled);
+            }
+            Err(e) => {
+                tracing::warn!("Rejected threshold update: {}", e);
+                metrics::counter!(
+                    "threshold.update_rejected",
+                    "code" => e.error_code().as_str()
+                )
+                .increment(1);
+            }
+        }
+    }
+    for reset_update in received.reset_updates {
+        match fetch_reset_update(
+            &reset_update.request,
+            party_id,
+            shares_encryption_key_pairs,
+            s3_client,
+            http_client,
+            &config.share_download,
+            &config.supported_iris_versions.0,
+        )
+        .await
+        {
+            Ok(entry) => {
+                batch_query.reset_updates.push(entry);
+                batch_query
+                    .reset_updates_metadata
+                    .push(batch_metadata(reset_update.trace_context));
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to process reset update of serial id {}: {:?}",
+                    reset_update.request.serial_id,
+                    e
+                );
+                let mut result =
+                    ResetUpdateResult::new(party_id, reset_update.request.serial_id, false);
+                result.error_code = Some(error_code_of(&e));
+                publish_error_result(
+                    serde_json::to_string(&result).map_err(|e| {
+                        ReceiveRequestError::json_parse_error("Reset update result", e)
+                    })?,
+                    RESET_UPDATE_MESSAGE_TYPE,
+                    reset_update.trace_context.as_ref(),
+                    result_publisher,
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Builds a batch of backfilled entries, see
+/// [`iris_mpc_common::helpers::backfill`]. Their shares are already at hand, so
+/// nothing is fetched, and the batch is never shed since nothing waits for it.
+async fn receive_backfill_batch(
+    party_id: usize,
+    max_rotation_window: usize,
+    entries: Vec<BackfillEntry>,
+) -> Result<BatchQuery, ReceiveRequestError> {
+    let mut batch_query = BatchQuery::default();
+    let mut handles = vec![];
+    for entry in entries {
+        batch_query.request_ids.push(entry.signup_id.clone());
+        batch_query.metadata.push(BatchMetadata::default());
+        batch_query.request_lanes.push(RequestLane::Bulk);
+        handles.push(spawn_blocking(move || {
+            let ((left_code, left_mask), (right_code, right_mask)) = entry.decode(party_id)?;
+            eyre::Ok((
+                preprocess_iris_message_shares(left_code, left_mask, max_rotation_window)?,
+                preprocess_iris_message_shares(right_code, right_mask, max_rotation_window)?,
+            ))
+        }));
+    }
+    record_batch_size(&tracing::Span::current(), batch_query.request_ids.len());
+
+    for (index, handle) in handles.into_iter().enumerate() {
+        let (entry, valid_entry) = match handle
+            .await
+            .map_err(ReceiveRequestError::FailedToJoinHandle)?
+        {
+            Ok(entry) => (entry, true),
+            Err(e) => {
+                tracing::error!(
+                    "Failed to process backfill entry {}: {:?}",
+                    batch_query.request_ids[index],
+                    e
+                );
+                metrics::counter!("backfill.invalid_entries").increment(1);
+                let dummy = dummy_preprocessed_iris_shares(party_id);
+                ((dummy.clone(), dummy), false)
+            }
+        };
+        batch_query.valid_entries.push(valid_entry);
+        batch_query.mirrored_checks.push(false);
+        batch_query.rotation_windows.push(max_rotation_window);
+        push_batch_entry(&mut batch_query, entry);
+    }
+    metrics::counter!("backfill.received").increment(batch_query.request_ids.len() as u64);
+    tracing::info!("backfill batch ids in order: {:?}", batch_query.request_ids);
 
-    Ok(Some(batch_query))
+    batch_query.shed_entries = vec![false; batch_query.request_ids.len()];
+    batch_query.query_left_preprocessed =
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.query_left.clone());
+    batch_query.query_right_preprocessed =
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.query_right.clone());
+    batch_query.db_left_preprocessed =
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.db_left.clone());
+    batch_query.db_right_preprocessed =
+        BatchQueryEntriesPreprocessed::with_sums(batch_query.db_right.clone());
+    Ok(batch_query)
 }
 
 fn initialize_tracing(config: &Config) -> eyre::Result<TracingShutdownHandle> {
@@ -516,8 +1302,17 @@ fn initialize_tracing(config: &Config) -> eyre::Result<TracingShutdownHandle> {
         }));
         Ok(tracing_shutdown_handle)
     } else {
+        let fmt_layer = if config.json_logs {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .boxed()
+        } else {
+            tracing_subscriber::fmt::layer().pretty().compact().boxed()
+        };
         tracing_subscriber::registry()
-            .with(tracing_subscriber::fmt::layer().pretty().compact())
+            .with(fmt_layer)
             .with(
                 tracing_subscriber::EnvFilter::try_from_default_env()
                     .unwrap_or_else(|_| "info".into()),
@@ -528,10 +1323,12 @@ fn initialize_tracing(config: &Config) -> eyre::Result<TracingShutdownHandle> {
     }
 }
 
-async fn initialize_chacha_seeds(
+/// Derives the long-term secrets shared with the next and the previous party,
+/// which authenticate the seed exchange.
+async fn initialize_static_secrets(
     kms_key_arns: &JsonStrWrapper<Vec<String>>,
     party_id: usize,
-) -> eyre::Result<([u32; 8], [u32; 8])> {
+) -> eyre::Result<([u8; 32], [u8; 32])> {
     // Init RNGs
     let own_key_arn = kms_key_arns
         .0
@@ -553,73 +1350,60 @@ async fn initialize_chacha_seeds(
         .get(dh_pairs.1)
         .expect("Expected value not found in kms_key_arns");
 
-    let chacha_seeds = (
-        bytemuck::cast(derive_shared_secret(own_key_arn, dh_pair_0).await?),
-        bytemuck::cast(derive_shared_secret(own_key_arn, dh_pair_1).await?),
+    let static_secrets = (
+        derive_shared_secret(own_key_arn, dh_pair_0).await?,
+        derive_shared_secret(own_key_arn, dh_pair_1).await?,
     );
 
-    Ok(chacha_seeds)
+    Ok(static_secrets)
 }
 
-async fn send_error_results_to_sns(
-    signup_id: String,
-    metadata: &BatchMetadata,
-    sns_client: &SNSClient,
-    config: &Config,
-    base_message_attributes: &HashMap<String, MessageAttributeValue>,
-    message_type: &str,
-    error_reason: &str,
-) -> eyre::Result<()> {
-    let message: UniquenessResult = UniquenessResult {
-        node_id: config.party_id,
-        serial_id: None,
-        is_match: false,
-        signup_id,
-        matched_serial_ids: None,
-        matched_serial_ids_left: None,
-        matched_serial_ids_right: None,
-        matched_batch_request_ids: None,
-        error: Some(true),
-        error_reason: Some(String::from(error_reason)),
-    };
-    let message_serialised = serde_json::to_string(&message)?;
-    let mut message_attributes = base_message_attributes.clone();
-    let trace_attributes = construct_message_attributes(&metadata.trace_id, &metadata.span_id)?;
-    message_attributes.extend(trace_attributes);
-    sns_client
-        .publish()
-        .topic_arn(&config.results_topic_arn)
-        .message(message_serialised)
-        .message_group_id(format!("party-id-{}", config.party_id))
-        .set_message_attributes(Some(message_attributes))
-        .send()
-        .await?;
-    metrics::counter!("result.sent", "type" => message_type.to_owned()+"_error").increment(1);
+/// The trace context of a request, as received with it.
+fn trace_context(metadata: &BatchMetadata) -> Option<TraceContext> {
+    TraceContext::from_ids(&metadata.trace_id, &metadata.span_id)
+}
 
-    Ok(())
+/// Rebuilds the NCCL communicators after a failed batch and runs the batch
+/// once more. The actor rolled back what the failed attempt changed. Fails, so
+/// that the server exits, if the communicators cannot be rebuilt in time, the
+/// parties disagree on their generation, or the batch fails again.
+async fn retry_batch_after_reinit(
+    handle: &mut ServerActorHandle,
+    batch: BatchQuery,
+    processing_timeout: Duration,
+) -> eyre::Result<ServerJobResult> {
+    metrics::counter!("nccl.reinit").increment(1);
+    let generation = timeout(processing_timeout, handle.reinit_network().await)
+        .await
+        .map_err(|e| eyre!("NCCL reinit timeout: {:?}", e))??;
+    tracing::info!(
+        "Retrying the batch after reinitializing NCCL, generation {}",
+        generation
+    );
+    timeout(
+        processing_timeout,
+        handle.try_submit_batch_query(batch).await,
+    )
+    .await
+    .map_err(|e| eyre!("ServerActor processing timeout: {:?}", e))?
 }
+
 async fn send_results_to_sns(
     result_events: Vec<String>,
     metadata: &[BatchMetadata],
-    sns_client: &SNSClient,
-    config: &Config,
+    result_publisher: &SnsResultPublisher,
     base_message_attributes: &HashMap<String, MessageAttributeValue>,
     message_type: &str,
 ) -> eyre::Result<()> {
-    for (i, result_event) in result_events.iter().enumerate() {
+    for (i, result_event) in result_events.into_iter().enumerate() {
         let mut message_attributes = base_message_attributes.clone();
         if metadata.len() > i {
             let trace_attributes =
-                construct_message_attributes(&metadata[i].trace_id, &metadata[i].span_id)?;
+                construct_message_attributes(trace_context(&metadata[i]).as_ref())?;
             message_attributes.extend(trace_attributes);
         }
-        sns_client
-            .publish()
-            .topic_arn(&config.results_topic_arn)
-            .message(result_event)
-            .message_group_id(format!("party-id-{}", config.party_id))
-            .set_message_attributes(Some(message_attributes))
-            .send()
+        result_publisher
+            .publish(result_event, message_attributes)
             .await?;
         metrics::counter!("result.sent", "type" => message_type.to_owned()).increment(1);
     }
@@ -631,11 +1415,16 @@ async fn main() -> eyre::Result<()> {
     dotenvy::dotenv().ok();
 
     println!("Init config");
-    let mut config: Config = Config::load_config("SMPC").unwrap();
-    config.overwrite_defaults_with_cli_args(Opt::parse());
+    let node_config = match NodeConfig::load("SMPC", Opt::parse()) {
+        Ok(node_config) => node_config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(e.into());
+        }
+    };
 
     println!("Init tracing");
-    let _tracing_shutdown_handle = match initialize_tracing(&config) {
+    let _tracing_shutdown_handle = match initialize_tracing(&node_config.base) {
         Ok(handle) => handle,
         Err(e) => {
             eprintln!("Failed to initialize tracing: {:?}", e);
@@ -643,11 +1432,16 @@ async fn main() -> eyre::Result<()> {
         }
     };
 
-    match server_main(config).await {
+    match server_main(node_config).await {
         Ok(_) => {
             tracing::info!("Server exited normally");
         }
         Err(e) => {
+            if let Some(timeout) = e.downcast_ref::<SyncTimeout>() {
+                tracing::error!("Server failed to start: {}", timeout);
+                drop(_tracing_shutdown_handle);
+                std::process::exit(SYNC_TIMEOUT_EXIT_CODE);
+            }
             tracing::error!("Server exited with error: {:?}", e);
             return Err(e);
         }
@@ -655,7 +1449,9 @@ async fn main() -> eyre::Result<()> {
     Ok(())
 }
 
-async fn server_main(config: Config) -> eyre::Result<()> {
+async fn server_main(node_config: NodeConfig) -> eyre::Result<()> {
+    let config = node_config.base.clone();
+    let match_threshold = node_config.matching.match_threshold;
     let shutdown_handler = ShutdownHandler::new(config.shutdown_last_results_sync_timeout_secs);
     shutdown_handler.wait_for_shutdown_signal().await;
 
@@ -663,7 +1459,6 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     *CURRENT_BATCH_SIZE.lock().unwrap() = config.max_batch_size;
     let max_sync_lookback: usize = config.max_batch_size * 2;
     let max_rollback: usize = config.max_batch_size * 2;
-    assert!(max_sync_lookback <= sync_nccl::MAX_REQUESTS);
     tracing::info!("Set batch size to {}", config.max_batch_size);
 
     tracing::info!("Creating new storage from: {:?}", config);
@@ -676,6 +1471,35 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     let shared_config = aws_config::from_env().region(region_provider).load().await;
     let sqs_client = Client::new(&shared_config);
     let sns_client = SNSClient::new(&shared_config);
+    let result_publisher = SnsResultPublisher::new(
+        sns_client,
+        config.results_topic_arn.clone(),
+        config.party_id,
+    );
+
+    let poison_message_policy = PoisonMessagePolicy {
+        max_attempts: config.poison_message_max_attempts,
+        dead_letter:  config.dead_letter_queue_url.clone().map(|queue_url| {
+            Arc::new(SqsDeadLetterSender::new(sqs_client.clone(), queue_url))
+                as Arc<dyn DeadLetterSender>
+        }),
+    };
+
+    // Interactive requests either come through their own queue, or share the
+    // main queue and are told apart by their `priority` attribute.
+    let mut request_queues = vec![(
+        SqsRequestReceiver::new(sqs_client.clone(), config.requests_queue_url.clone()),
+        None,
+    )];
+    if let Some(interactive_queue_url) = &config.interactive_requests_queue_url {
+        request_queues.insert(
+            0,
+            (
+                SqsRequestReceiver::new(sqs_client, interactive_queue_url.clone()),
+                Some(RequestLane::Interactive),
+            ),
+        );
+    }
 
     // Increase S3 retries to 5
     let retry_config = RetryConfig::standard().with_max_attempts(5);
@@ -683,10 +1507,12 @@ async fn server_main(config: Config) -> eyre::Result<()> {
         .retry_config(retry_config)
         .build();
     let s3_client = Arc::new(S3Client::from_conf(s3_config));
+    let http_client = config.share_download.http_client()?;
     let s3_client_clone = Arc::clone(&s3_client);
+    let key_source = SecretsManagerKeySource::new(&config).await;
     let shares_encryption_key_pair =
-        match SharesEncryptionKeyPairs::from_storage(config.clone()).await {
-            Ok(key_pair) => key_pair,
+        match KeyPairProvider::new(key_source, config.key_refresh).await {
+            Ok(provider) => Arc::new(provider),
             Err(e) => {
                 tracing::error!("Failed to initialize shares encryption key pairs: {:?}", e);
                 return Ok(());
@@ -694,18 +1520,31 @@ async fn server_main(config: Config) -> eyre::Result<()> {
         };
 
     let party_id = config.party_id;
-    tracing::info!("Deriving shared secrets");
-    let chacha_seeds = initialize_chacha_seeds(&config.kms_key_arns, party_id).await?;
+    let party = PartyID::try_from(party_id)?;
+    let static_secrets = if config.insecure_deterministic_seeds {
+        tracing::warn!("Using insecure deterministic seeds, do not use in production!");
+        None
+    } else {
+        tracing::info!("Deriving shared secrets");
+        Some(initialize_static_secrets(&config.kms_key_arns, party_id).await?)
+    };
 
     let uniqueness_result_attributes = create_message_type_attribute_map(UNIQUENESS_MESSAGE_TYPE);
     let identity_deletion_result_attributes =
         create_message_type_attribute_map(IDENTITY_DELETION_MESSAGE_TYPE);
+    let identity_deletion_batch_result_attributes =
+        create_message_type_attribute_map(IDENTITY_DELETION_BATCH_MESSAGE_TYPE);
+    let identity_restore_result_attributes =
+        create_message_type_attribute_map(IDENTITY_RESTORE_MESSAGE_TYPE);
+    let reauth_result_attributes = create_message_type_attribute_map(REAUTH_MESSAGE_TYPE);
+    let reset_check_result_attributes = create_message_type_attribute_map(RESET_CHECK_MESSAGE_TYPE);
+    let reset_update_result_attributes =
+        create_message_type_attribute_map(RESET_UPDATE_MESSAGE_TYPE);
     tracing::info!("Replaying results");
     send_results_to_sns(
         store.last_results(max_sync_lookback).await?,
         &Vec::new(),
-        &sns_client,
-        &config,
+        &result_publisher,
         &uniqueness_result_attributes,
         UNIQUENESS_MESSAGE_TYPE,
     )
@@ -762,6 +1601,15 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     tracing::info!("Preparing task monitor");
     let mut background_tasks = TaskMonitor::new();
 
+    // Pick up rotations of the share encryption keys without a restart.
+    if let Some(interval) = config.key_refresh.interval() {
+        let key_pairs = Arc::clone(&shares_encryption_key_pair);
+        let _key_refresh_abort = background_tasks.spawn(async move {
+            key_pairs.refresh_every(interval).await;
+            Ok(())
+        });
+    }
+
     // --------------------------------------------------------------------------
     // ANCHOR: Starting Healthcheck and Readiness server
     // --------------------------------------------------------------------------
@@ -817,6 +1665,41 @@ async fn server_main(config: Config) -> eyre::Result<()> {
         }
     });
 
+    // Backfilled entries are queued here until a batch takes them, see
+    // `receive_batch`.
+    let mut backfill_receiver = None;
+    if let Some(address) = config.backfill_listen_address.clone() {
+        let (backfill_sender, receiver) = mpsc::channel(config.backfill_queue_size);
+        backfill_receiver = Some(receiver);
+        let _backfill_abort = background_tasks.spawn(async move {
+            let app = Router::new().route(
+                BACKFILL_PATH,
+                post(move |Json(entries): Json<Vec<BackfillEntry>>| {
+                    let backfill_sender = backfill_sender.clone();
+                    async move {
+                        let mut accepted = 0;
+                        for mut entry in entries {
+                            entry.signup_id = backfill_signup_id(&entry.signup_id);
+                            if backfill_sender.try_send(entry).is_err() {
+                                break;
+                            }
+                            accepted += 1;
+                        }
+                        Json(BackfillResponse { accepted })
+                    }
+                }),
+            );
+            let listener = tokio::net::TcpListener::bind(&address)
+                .await
+                .wrap_err("backfill listener bind error")?;
+            tracing::info!("Accepting backfill entries on {}", address);
+            axum::serve(listener, app)
+                .await
+                .wrap_err("backfill listener server launch error")?;
+            Ok::<(), eyre::Error>(())
+        });
+    }
+
     background_tasks.check_tasks();
     tracing::info!("Healthcheck and Readiness server running on port 3000.");
 
@@ -825,8 +1708,8 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     let all_nodes = config.node_hostnames.clone();
     let image_name = config.image_name.clone();
     let _heartbeat = background_tasks.spawn(async move {
-        let next_node = &all_nodes[(config.party_id + 1) % 3];
-        let prev_node = &all_nodes[(config.party_id + 2) % 3];
+        let next_node = &all_nodes[usize::from(party.next_id())];
+        let prev_node = &all_nodes[usize::from(party.prev_id())];
         let mut last_response = [String::default(), String::default()];
         let mut connected = [false, false];
         let mut retries = [0, 0];
@@ -895,10 +1778,9 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     tracing::info!("Heartbeat on all nodes started.");
     background_tasks.check_tasks();
 
-    let my_state = SyncState {
-        db_len:              store_len as u64,
-        deleted_request_ids: store.last_deleted_requests(max_sync_lookback).await?,
-    };
+    let deleted_request_ids = store.last_deleted_requests(max_sync_lookback).await?;
+    // The device count is filled in once the devices are initialized.
+    let mut common_config = CommonConfig::from_node(&node_config, schema_version(), 0);
 
     // Start the actor in separate task.
     // A bit convoluted, but we need to create the actor on the thread already,
@@ -913,30 +1795,100 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     let load_chunks_parallelism = config.load_chunks_parallelism;
     let db_chunks_bucket_name = config.db_chunks_bucket_name.clone();
     let db_chunks_folder_name = config.db_chunks_folder_name.clone();
+    let db_snapshot_dir = config.db_snapshot_dir.clone();
 
+    let mut chunked_transfer = ChunkedTransfer::new(config.transfer_chunk_size_bytes);
+    if config.pinned_transfer_staging {
+        chunked_transfer = chunked_transfer.with_staging_pool(Arc::new(PinnedStagingPool::new(
+            config.transfer_chunk_size_bytes,
+        )));
+    }
+
+    // Stop uploading the DB to the devices if we are asked to shut down while
+    // still loading it.
+    let db_load_cancellation = chunked_transfer.cancellation().clone();
+    let shutdown_handler_load = shutdown_handler.clone();
+    let db_load_watch = tokio::spawn(async move {
+        while !shutdown_handler_load.is_shutting_down() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        db_load_cancellation.cancel();
+    });
+
+    let key_generation = shares_encryption_key_pair.key_pairs().generation;
     let (tx, rx) = oneshot::channel();
     background_tasks.spawn_blocking(move || {
-        let device_manager = Arc::new(DeviceManager::init());
+        let device_manager = Arc::new(match &node_config.gpu.device_ids {
+            Some(device_ids) => DeviceManager::init_with_device_ids(device_ids)?,
+            None => DeviceManager::init(),
+        });
         let ids = device_manager.get_ids_from_magic(0);
-
-        // --------------------------------------------------------------------------
-        // ANCHOR: Starting NCCL
-        // --------------------------------------------------------------------------
-        tracing::info!("⚓️ ANCHOR: Starting NCCL");
-        let comms = device_manager.instantiate_network_from_ids(config.party_id, &ids)?;
-        // FYI: If any of the nodes die after this, all connections are broken.
+        let memory_devices = (*device_manager).clone();
+        common_config.device_count = device_manager.device_count();
+        let mut my_state = SyncState::new(store_len as u64, deleted_request_ids, &common_config);
+        my_state.key_generation = key_generation;
+        let sync_timeout = Duration::from_secs(config.state_sync_timeout_secs);
 
         // --------------------------------------------------------------------------
         // ANCHOR: Syncing latest node state
         // --------------------------------------------------------------------------
-        tracing::info!("⚓️ ANCHOR: Syncing latest node state");
-        let sync_result = match sync_nccl::sync(&comms[0], &my_state) {
+        // The state is exchanged before NCCL is started, so that a party that
+        // never comes up fails the sync rather than the NCCL startup.
+        let sync_result = match config.state_sync_transport {
+            StateSyncTransport::Tcp => {
+                tracing::info!("⚓️ ANCHOR: Syncing latest node state over TCP");
+                static_secrets
+                    .ok_or_else(|| eyre!("The state sync over TCP needs the shared secrets"))
+                    .and_then(|secrets| {
+                        TcpStateExchange::bind(
+                            config.party_id,
+                            config.state_sync_addresses.clone(),
+                            sync_timeout,
+                            &secrets,
+                        )
+                    })
+                    .and_then(|exchange| exchange.sync(&my_state))
+            }
+            StateSyncTransport::Nccl => {
+                tracing::info!("⚓️ ANCHOR: Syncing latest node state");
+                NcclStateExchange::new(
+                    device_manager.device(0),
+                    config.party_id,
+                    3,
+                    device_manager.get_ids_from_magic(STATE_SYNC_MAGIC)[0],
+                    sync_timeout,
+                )
+                .sync(&my_state)
+            }
+        };
+        let sync_result = match sync_result {
             Ok(res) => res,
             Err(e) => {
                 tx.send(Err(e)).unwrap();
                 return Ok(());
             }
         };
+
+        // --------------------------------------------------------------------------
+        // ANCHOR: Starting NCCL
+        // --------------------------------------------------------------------------
+        tracing::info!("⚓️ ANCHOR: Starting NCCL");
+        let comms = device_manager.instantiate_network_from_ids(config.party_id, &ids)?;
+        // FYI: If any of the nodes die after this, all connections are broken.
+
+        if let Some(report) = sync_result.divergence_report() {
+            tracing::warn!("{}", report);
+        }
+        if let Err(e) = sync_result.check_compatibility() {
+            tracing::error!("{}", e);
+            tx.send(Err(e.into())).unwrap();
+            return Ok(());
+        }
+        if let Err(e) = sync_result.check_threshold() {
+            tracing::error!("{}", e);
+            tx.send(Err(e.into())).unwrap();
+            return Ok(());
+        }
         tracing::info!("Database store length is: {}", store_len);
 
         if let Some(db_len) = sync_result.must_rollback_storage() {
@@ -957,6 +1909,46 @@ async fn server_main(config: Config) -> eyre::Result<()> {
             metrics::counter!("db.sync.rollback").increment(1);
         }
 
+        // --------------------------------------------------------------------------
+        // ANCHOR: Establishing the correlated randomness seeds
+        // --------------------------------------------------------------------------
+        tracing::info!("⚓️ ANCHOR: Establishing the correlated randomness seeds");
+        let chacha_seeds = match static_secrets {
+            Some(static_secrets) => {
+                let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                match seed_exchange::exchange_seeds(
+                    &comms[0],
+                    config.party_id,
+                    epoch,
+                    &static_secrets,
+                ) {
+                    Ok(seeds) => seeds,
+                    Err(e) => {
+                        tracing::error!("Seed exchange failed: {}", e);
+                        tx.send(Err(e)).unwrap();
+                        return Ok(());
+                    }
+                }
+            }
+            None => seed_exchange::insecure_deterministic_seeds(config.party_id),
+        };
+
+        // --------------------------------------------------------------------------
+        // ANCHOR: Auditing the stored shares
+        // --------------------------------------------------------------------------
+        if config.share_audit_sample_size > 0 {
+            tracing::info!("⚓️ ANCHOR: Auditing the stored shares");
+            if let Err(e) = audit_stored_shares(
+                &comms[0],
+                &store,
+                config.party_id,
+                chacha_seeds,
+                config.share_audit_sample_size,
+            ) {
+                tracing::error!("Share audit failed: {}", e);
+            }
+        }
+
         // --------------------------------------------------------------------------
         // ANCHOR: Load the database
         // --------------------------------------------------------------------------
@@ -975,6 +1967,10 @@ async fn server_main(config: Config) -> eyre::Result<()> {
             config.disable_persistence,
         ) {
             Ok((mut actor, handle)) => {
+                actor.set_chunked_transfer(chunked_transfer);
+                actor.set_device_mask_rotations(config.device_mask_rotations);
+                actor.set_match_policy(config.match_policy);
+                actor.set_match_threshold(match_threshold);
                 let res = if config.fake_db_size > 0 {
                     tracing::warn!(
                         "Faking db with {} entries, returned results will be random.",
@@ -993,6 +1989,26 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                     );
                     let s3_store = S3Store::new(s3_client_clone, db_chunks_bucket_name);
                     tokio::runtime::Handle::current().block_on(async {
+                        let soft_deleted = SoftDeletedShares::new(&store, config.party_id).await?;
+                        if let Some(dir) = &db_snapshot_dir {
+                            let dir = Path::new(dir);
+                            if restore_db_snapshot(
+                                &mut actor,
+                                &store,
+                                &soft_deleted,
+                                dir,
+                                store_len,
+                            )
+                            .await?
+                            {
+                                tracing::info!("Page-lock host memory");
+                                actor.register_host_memory();
+                                tracing::info!("Warming up the GPU pipeline");
+                                actor.warmup(config.warmup_rounds)?;
+                                return eyre::Ok(());
+                            }
+                        }
+
                         let mut stream = match config.enable_s3_importer {
                             true => {
                                 tracing::info!("S3 importer enabled. Fetching from s3 + db");
@@ -1087,13 +2103,7 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                                 return Err(eyre!("Invalid iris index {}", iris.index()));
                             }
 
-                            actor.load_single_record(
-                                iris.index() - 1,
-                                iris.left_code(),
-                                iris.left_mask(),
-                                iris.right_code(),
-                                iris.right_mask(),
-                            );
+                            soft_deleted.load(&mut actor, &iris);
 
                             // if the serial id hasn't been loaded before, count is as unique record
                             if all_serial_ids.contains(&(iris.index() as i64)) {
@@ -1130,7 +2140,11 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                         }
 
                         tracing::info!("Preprocessing db");
-                        actor.preprocess_db();
+                        actor.preprocess_db()?;
+
+                        if let Some(dir) = &db_snapshot_dir {
+                            write_db_snapshot(&actor, &store, Path::new(dir), store_len).await;
+                        }
 
                         tracing::info!("Page-lock host memory");
                         actor.register_host_memory();
@@ -1141,13 +2155,19 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                             actor.current_db_sizes()
                         );
 
+                        // Readiness only flips once this returns, so the first
+                        // real batch does not pay for the lazy initialization.
+                        tracing::info!("Warming up the GPU pipeline");
+                        actor.warmup(config.warmup_rounds)?;
+
                         eyre::Ok(())
                     })
                 };
 
                 match res {
                     Ok(_) => {
-                        tx.send(Ok((handle, sync_result, store))).unwrap();
+                        tx.send(Ok((handle, sync_result, store, memory_devices)))
+                            .unwrap();
                     }
                     Err(e) => {
                         tx.send(Err(e)).unwrap();
@@ -1165,7 +2185,8 @@ async fn server_main(config: Config) -> eyre::Result<()> {
         Ok(())
     });
 
-    let (mut handle, sync_result, store) = rx.await??;
+    let (mut handle, sync_result, store, memory_devices) = rx.await??;
+    db_load_watch.abort();
 
     let mut skip_request_ids = sync_result.deleted_request_ids();
 
@@ -1173,11 +2194,56 @@ async fn server_main(config: Config) -> eyre::Result<()> {
 
     // Start thread that will be responsible for communicating back the results
     let (tx, mut rx) = mpsc::channel::<ServerJobResult>(32); // TODO: pick some buffer value
-    let sns_client_bg = sns_client.clone();
+    let result_publisher_bg = result_publisher.clone();
     let config_bg = config.clone();
     let store_bg = store.clone();
     let shutdown_handler_bg = shutdown_handler.clone();
+    let mut audit_log = config
+        .audit_log_path
+        .as_ref()
+        .map(|path| AuditLog::open(path, party_id))
+        .transpose()?;
+    #[cfg(feature = "parquet_export")]
+    let mut decision_exporter = match (
+        &config.decision_export_dir,
+        &config.decision_export_hmac_key,
+    ) {
+        (Some(dir), Some(key)) => Some(DecisionExporter::new(dir, &STANDARD.decode(key)?)?),
+        _ => None,
+    };
+    #[cfg(not(feature = "parquet_export"))]
+    if config.decision_export_dir.is_some() {
+        return Err(eyre!(
+            "decision_export_dir is set, but the server is built without the parquet_export \
+             feature"
+        ));
+    }
+    let recorder = match (
+        &config.replay_recording_dir,
+        &config.replay_debug_public_key,
+    ) {
+        (Some(dir), Some(debug_key)) => {
+            let settings = RecordedSettings {
+                enable_mirrored_checks: config.enable_mirrored_checks,
+                result_mode: config.result_mode,
+                reveal_matched_serial_ids: config.reveal_matched_serial_ids,
+                match_policy: config.match_policy,
+                match_threshold,
+                max_rotation_window: Some(config.max_rotation_window),
+                threshold_operator_key: config
+                    .threshold_operator_public_key
+                    .as_ref()
+                    .and_then(|key| STANDARD.decode(key).ok()),
+            };
+            tracing::warn!("Recording every batch for replays into {}", dir);
+            Some(BatchRecorder::new(dir, party_id, settings, debug_key)?)
+        }
+        _ => None,
+    };
+    let result_signing_key = config.result_signing_key()?;
     let _result_sender_abort = background_tasks.spawn(async move {
+        // Results arrive in the order the batches were submitted.
+        let mut batch_id = 0;
         while let Some(ServerJobResult {
             merged_results,
             request_ids,
@@ -1189,15 +2255,103 @@ async fn server_main(config: Config) -> eyre::Result<()> {
             store_left,
             store_right,
             deleted_ids,
+            unknown_deletion_ids,
+            restored_ids,
+            unknown_restore_ids,
+            reset_update_ids,
+            unknown_reset_update_ids,
+            purged_serial_ids: _,
+            applied_deletion_batches,
+            deferred_deletion_batches: _,
             matched_batch_request_ids,
+            mirrored_checks,
+            rotation_windows,
+            reauth_targets,
+            or_rule_indices,
+            reset_checks,
+            db_digest_before,
+            db_digest_after,
+            threshold_version,
+            requeued_request_ids: _,
+            deferred_request_ids: _,
+            mut deadline,
+            span,
         }) = rx.recv().await
         {
+            // The mirrored checks are not published on their own, their matches
+            // are merged into the results of the requests they were derived from.
+            let mirrored_matches = mirrored_checks
+                .iter()
+                .enumerate()
+                .filter(|&(_, &mirrored_check)| mirrored_check)
+                .map(|(i, _)| {
+                    (
+                        request_ids[i].as_str(),
+                        (&match_ids[i], &matched_batch_request_ids[i]),
+                    )
+                })
+                .collect::<HashMap<_, _>>();
+
+            // Reauth entries are only published as reauth results.
+            let is_reauth = |i: usize| matches!(reauth_targets.get(i), Some(Some(_)));
+            let reauth_metadata = (0..request_ids.len())
+                .filter(|&i| is_reauth(i))
+                .map(|i| metadata[i].clone())
+                .collect::<Vec<_>>();
+            let reauth_results = reauth_targets
+                .iter()
+                .enumerate()
+                .filter_map(|(i, targets)| targets.as_ref().map(|targets| (i, targets)))
+                .map(|(i, targets)| {
+                    let matched_serial_ids = targets
+                        .iter()
+                        .filter(|target| match_ids[i].contains(target))
+                        .map(|x| x + 1)
+                        .collect();
+                    let mut result_event = ReAuthResult::new(
+                        party_id,
+                        request_ids[i].clone(),
+                        targets.iter().map(|x| x + 1).collect(),
+                        matched_serial_ids,
+                    );
+                    result_event.threshold_version = Some(threshold_version);
+                    result_event.match_policy = Some(config_bg.match_policy);
+                    serde_json::to_string(&result_event)
+                        .wrap_err("failed to serialize reauth result")
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+
+            // Reset checks are only published as reset check results, with the
+            // matches of both eyes.
+            let is_reset_check = |i: usize| reset_checks.get(i).copied().unwrap_or(false);
+            let reset_check_metadata = (0..request_ids.len())
+                .filter(|&i| is_reset_check(i))
+                .map(|i| metadata[i].clone())
+                .collect::<Vec<_>>();
+            let reset_check_results = (0..request_ids.len())
+                .filter(|&i| is_reset_check(i))
+                .map(|i| {
+                    let one_indexed = |ids: &[u32]| ids.iter().map(|x| x + 1).collect::<Vec<_>>();
+                    let mut result_event = ResetCheckResult::new(
+                        party_id,
+                        request_ids[i].clone(),
+                        one_indexed(&match_ids[i]),
+                        one_indexed(&partial_match_ids_left[i]),
+                        one_indexed(&partial_match_ids_right[i]),
+                    );
+                    result_event.threshold_version = Some(threshold_version);
+                    serde_json::to_string(&result_event)
+                        .wrap_err("failed to serialize reset check result")
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+
             // returned serial_ids are 0 indexed, but we want them to be 1 indexed
             let uniqueness_results = merged_results
                 .iter()
                 .enumerate()
+                .filter(|&(i, _)| !mirrored_checks[i] && !is_reauth(i) && !is_reset_check(i))
                 .map(|(i, &idx_result)| {
-                    let result_event = UniquenessResult::new(
+                    let mut result_event = UniquenessResult::new(
                         party_id,
                         match matches[i] {
                             true => None,
@@ -1205,7 +2359,9 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                         },
                         matches[i],
                         request_ids[i].clone(),
-                        match matches[i] {
+                        // A non-match still reports the matches of its OR-rule
+                        // serial ids.
+                        match matches[i] || !match_ids[i].is_empty() {
                             true => Some(match_ids[i].iter().map(|x| x + 1).collect::<Vec<_>>()),
                             false => None,
                         },
@@ -1229,12 +2385,41 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                         },
                         Some(matched_batch_request_ids[i].clone()),
                     );
+                    result_event.threshold_version = Some(threshold_version);
+                    result_event.match_policy = Some(config_bg.match_policy);
+                    if is_backfill_request(&request_ids[i]) {
+                        result_event.backfill = Some(true);
+                    }
+
+                    if let Some((mirrored_ids, mirrored_request_ids)) =
+                        mirrored_matches.get(request_ids[i].as_str())
+                    {
+                        let mirrored_ids = mirrored_ids.iter().map(|x| x + 1).collect::<Vec<_>>();
+                        if let Some(matched_serial_ids) = result_event.matched_serial_ids.as_mut() {
+                            for id in mirrored_ids.iter() {
+                                if !matched_serial_ids.contains(id) {
+                                    matched_serial_ids.push(*id);
+                                }
+                            }
+                        }
+                        if let Some(matched_batch_request_ids) =
+                            result_event.matched_batch_request_ids.as_mut()
+                        {
+                            matched_batch_request_ids.extend(mirrored_request_ids.iter().cloned());
+                        }
+                        result_event.matched_serial_ids_mirror = Some(mirrored_ids);
+                    }
+                    if let Some(key) = &result_signing_key {
+                        result_event.sign(key);
+                    }
 
                     serde_json::to_string(&result_event).wrap_err("failed to serialize result")
                 })
                 .collect::<eyre::Result<Vec<_>>>()?;
 
-            // Insert non-matching queries into the persistent store.
+            // Insert non-matching queries into the persistent store. Mirrored
+            // checks, reauth entries and reset checks are never inserted, they
+            // count as matches.
             let (memory_serial_ids, codes_and_masks): (Vec<i64>, Vec<StoredIrisRef>) = matches
                 .iter()
                 .enumerate()
@@ -1255,53 +2440,164 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                 })
                 .unzip();
 
-            let mut tx = store_bg.tx().await?;
-
-            store_bg
-                .insert_results(&mut tx, &uniqueness_results)
-                .await?;
+            // Identities enrolled by the canary are tagged, so that they can be
+            // purged and left out of the statistics.
+            let canary_serial_ids = matches
+                .iter()
+                .enumerate()
+                .filter(|&(i, &is_match)| !is_match && is_canary_request(&request_ids[i]))
+                .map(|(i, _)| ((merged_results[i] + 1) as i64, request_ids[i].clone()))
+                .collect::<Vec<_>>();
+
+            async {
+                let mut tx = store_bg.tx().await?;
+
+                store_bg
+                    .insert_results(&mut tx, &uniqueness_results)
+                    .await?;
+                store_bg
+                    .tag_canary_serial_ids(&mut tx, &canary_serial_ids)
+                    .await?;
+
+                if !codes_and_masks.is_empty() && !config_bg.disable_persistence {
+                    let db_serial_ids = store_bg.insert_irises(&mut tx, &codes_and_masks).await?;
+
+                    // Check if the serial_ids match between memory and db.
+                    if memory_serial_ids != db_serial_ids {
+                        tracing::error!(
+                            "Serial IDs do not match between memory and db: {:?} != {:?}",
+                            memory_serial_ids,
+                            db_serial_ids
+                        );
+                        return Err(eyre!(
+                            "Serial IDs do not match between memory and db: {:?} != {:?}",
+                            memory_serial_ids,
+                            db_serial_ids
+                        ));
+                    }
+                }
 
-            if !codes_and_masks.is_empty() && !config_bg.disable_persistence {
-                let db_serial_ids = store_bg.insert_irises(&mut tx, &codes_and_masks).await?;
+                tx.commit().await?;
+                Ok::<_, eyre::Report>(())
+            }
+            .instrument(Phase::Persist.child_of(&span))
+            .await?;
 
-                // Check if the serial_ids match between memory and db.
-                if memory_serial_ids != db_serial_ids {
-                    tracing::error!(
-                        "Serial IDs do not match between memory and db: {:?} != {:?}",
-                        memory_serial_ids,
-                        db_serial_ids
-                    );
-                    return Err(eyre!(
-                        "Serial IDs do not match between memory and db: {:?} != {:?}",
-                        memory_serial_ids,
-                        db_serial_ids
-                    ));
+            if audit_log.is_some() || recorder.is_some() {
+                let decisions = (0..request_ids.len())
+                    .filter(|&i| !mirrored_checks[i] && !is_reauth(i) && !is_reset_check(i))
+                    .map(|i| AuditDecision {
+                        request_id:   request_ids[i].clone(),
+                        request_hash: AuditHash::of_parts(&[
+                            request_ids[i].as_bytes(),
+                            bytemuck::cast_slice(&store_left.code[i].coefs[..]),
+                            bytemuck::cast_slice(&store_left.mask[i].coefs[..]),
+                            bytemuck::cast_slice(&store_right.code[i].coefs[..]),
+                            bytemuck::cast_slice(&store_right.mask[i].coefs[..]),
+                        ]),
+                        is_match:     matches[i],
+                        serial_id:    (!matches[i]).then(|| merged_results[i] + 1),
+                    })
+                    .collect();
+                // The deletion batches are audited and recorded like single
+                // deletions.
+                let (deleted_ids, unknown_deletion_ids) = with_deletion_batches(
+                    &deleted_ids,
+                    &unknown_deletion_ids,
+                    &applied_deletion_batches,
+                );
+                let batch = AuditBatch {
+                    batch_id,
+                    decisions,
+                    deleted_serial_ids: deleted_ids.iter().map(|id| id + 1).collect(),
+                    db_digest_before: AuditHash::from_digest(&db_digest_before),
+                    db_digest_after: AuditHash::from_digest(&db_digest_after),
+                };
+                if let Some(recorder) = recorder.as_ref() {
+                    let recorded = record_batch(
+                        &batch,
+                        &request_ids,
+                        &mirrored_checks,
+                        &rotation_windows,
+                        &or_rule_indices,
+                        &store_left,
+                        &store_right,
+                        &deleted_ids,
+                        &unknown_deletion_ids,
+                    )
+                    .and_then(|recorded| Ok(recorder.record(recorded)?));
+                    // The recording is for debugging only, the batch goes on.
+                    if let Err(e) = recorded {
+                        tracing::error!("Failed to record batch {}: {}", batch_id, e);
+                    }
+                }
+                if let Some(audit_log) = audit_log.as_mut() {
+                    let timestamp = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("system time is after the unix epoch")
+                        .as_secs() as i64;
+                    audit_log.append(batch, timestamp)?;
                 }
             }
 
-            tx.commit().await?;
-
             for memory_serial_id in memory_serial_ids {
+                if canary_serial_ids
+                    .iter()
+                    .any(|(id, _)| *id == memory_serial_id)
+                {
+                    tracing::info!("Inserted canary serial_id: {}", memory_serial_id);
+                    continue;
+                }
                 tracing::info!("Inserted serial_id: {}", memory_serial_id);
                 metrics::gauge!("results_inserted.latest_serial_id").set(memory_serial_id as f64);
             }
 
             tracing::info!("Sending {} uniqueness results", uniqueness_results.len());
+            let publish_start = Instant::now();
             send_results_to_sns(
                 uniqueness_results,
                 &metadata,
-                &sns_client_bg,
-                &config_bg,
+                &result_publisher_bg,
                 &uniqueness_result_attributes,
                 UNIQUENESS_MESSAGE_TYPE,
             )
+            .instrument(Phase::Publish.child_of(&span))
+            .await?;
+
+            tracing::info!("Sending {} reauth results", reauth_results.len());
+            send_results_to_sns(
+                reauth_results,
+                &reauth_metadata,
+                &result_publisher_bg,
+                &reauth_result_attributes,
+                REAUTH_MESSAGE_TYPE,
+            )
+            .instrument(Phase::Publish.child_of(&span))
+            .await?;
+
+            tracing::info!("Sending {} reset check results", reset_check_results.len());
+            send_results_to_sns(
+                reset_check_results,
+                &reset_check_metadata,
+                &result_publisher_bg,
+                &reset_check_result_attributes,
+                RESET_CHECK_MESSAGE_TYPE,
+            )
+            .instrument(Phase::Publish.child_of(&span))
             .await?;
 
-            // handling identity deletion results
+            // handling identity deletion results, deletions of unknown serial ids fail
             let identity_deletion_results = deleted_ids
                 .iter()
-                .map(|&serial_id| {
-                    let result_event = IdentityDeletionResult::new(party_id, serial_id + 1, true);
+                .map(|&serial_id| (serial_id, true))
+                .chain(
+                    unknown_deletion_ids
+                        .iter()
+                        .map(|&serial_id| (serial_id, false)),
+                )
+                .map(|(serial_id, success)| {
+                    let result_event =
+                        IdentityDeletionResult::new(party_id, serial_id.wrapping_add(1), success);
                     serde_json::to_string(&result_event)
                         .wrap_err("failed to serialize identity deletion result")
                 })
@@ -1314,12 +2610,154 @@ async fn server_main(config: Config) -> eyre::Result<()> {
             send_results_to_sns(
                 identity_deletion_results,
                 &metadata,
-                &sns_client_bg,
-                &config_bg,
+                &result_publisher_bg,
                 &identity_deletion_result_attributes,
                 IDENTITY_DELETION_MESSAGE_TYPE,
             )
+            .instrument(Phase::Publish.child_of(&span))
+            .await?;
+
+            // One result per deletion batch, with the serial ids it deleted and
+            // the ones beyond the database.
+            let identity_deletion_batch_metadata = applied_deletion_batches
+                .iter()
+                .map(|applied| applied.batch.metadata.clone())
+                .collect::<Vec<_>>();
+            let identity_deletion_batch_results = applied_deletion_batches
+                .into_iter()
+                .map(|applied| {
+                    let result_event = IdentityDeletionBatchResult::new(
+                        party_id,
+                        applied.batch.serial_ids,
+                        applied.deleted_ids.iter().map(|x| x + 1).collect(),
+                        applied
+                            .unknown_ids
+                            .iter()
+                            .map(|x| x.wrapping_add(1))
+                            .collect(),
+                    );
+                    serde_json::to_string(&result_event)
+                        .wrap_err("failed to serialize identity deletion batch result")
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            send_results_to_sns(
+                identity_deletion_batch_results,
+                &identity_deletion_batch_metadata,
+                &result_publisher_bg,
+                &identity_deletion_batch_result_attributes,
+                IDENTITY_DELETION_BATCH_MESSAGE_TYPE,
+            )
+            .instrument(Phase::Publish.child_of(&span))
+            .await?;
+
+            // Restores of serial ids that are not soft-deleted fail.
+            let identity_restore_results = restored_ids
+                .iter()
+                .map(|&serial_id| (serial_id, true))
+                .chain(
+                    unknown_restore_ids
+                        .iter()
+                        .map(|&serial_id| (serial_id, false)),
+                )
+                .map(|(serial_id, success)| {
+                    let result_event =
+                        IdentityRestoreResult::new(party_id, serial_id.wrapping_add(1), success);
+                    serde_json::to_string(&result_event)
+                        .wrap_err("failed to serialize identity restore result")
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            send_results_to_sns(
+                identity_restore_results,
+                &metadata,
+                &result_publisher_bg,
+                &identity_restore_result_attributes,
+                IDENTITY_RESTORE_MESSAGE_TYPE,
+            )
+            .instrument(Phase::Publish.child_of(&span))
+            .await?;
+
+            // Updates of serial ids beyond the database fail.
+            let reset_update_results = reset_update_ids
+                .iter()
+                .map(|&serial_id| (serial_id, true))
+                .chain(
+                    unknown_reset_update_ids
+                        .iter()
+                        .map(|&serial_id| (serial_id, false)),
+                )
+                .map(|(serial_id, success)| {
+                    let result_event =
+                        ResetUpdateResult::new(party_id, serial_id.wrapping_add(1), success);
+                    serde_json::to_string(&result_event)
+                        .wrap_err("failed to serialize reset update result")
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            send_results_to_sns(
+                reset_update_results,
+                &metadata,
+                &result_publisher_bg,
+                &reset_update_result_attributes,
+                RESET_UPDATE_MESSAGE_TYPE,
+            )
+            .instrument(Phase::Publish.child_of(&span))
             .await?;
+            if let Some(deadline) = deadline.as_mut() {
+                deadline.record(BudgetPhase::Publish, publish_start.elapsed());
+                deadline.report_soft_breach(BudgetPhase::Publish);
+            }
+
+            #[cfg(feature = "parquet_export")]
+            if let Some(exporter) = decision_exporter.as_mut() {
+                let finished_at_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("system time is after the unix epoch")
+                    .as_millis() as i64;
+                let queries = (0..request_ids.len())
+                    .filter(|&i| !mirrored_checks[i] && !is_reauth(i) && !is_reset_check(i))
+                    .map(|i| {
+                        let mirrored = mirrored_matches.get(request_ids[i].as_str());
+                        let serial_ids = match_ids[i]
+                            .iter()
+                            .chain(mirrored.into_iter().flat_map(|(ids, _)| ids.iter()))
+                            .collect::<HashSet<_>>();
+                        let batch_requests = matched_batch_request_ids[i].len()
+                            + mirrored.map_or(0, |(_, request_ids)| request_ids.len());
+                        ExportedQuery {
+                            signup_id:     request_ids[i].clone(),
+                            decision:      match matches[i] {
+                                true => ExportedDecision::Match,
+                                false => ExportedDecision::Unique,
+                            },
+                            matched_count: (serial_ids.len() + batch_requests) as u32,
+                        }
+                    })
+                    .collect();
+                let batch = ExportedBatch {
+                    batch_id,
+                    started_at_ms: finished_at_ms
+                        - deadline
+                            .as_ref()
+                            .map_or(0, |deadline| deadline.elapsed().as_millis() as i64),
+                    finished_at_ms,
+                    threshold_version,
+                    phase_timings_ms: deadline.as_ref().map_or(
+                        [0; BudgetPhase::ALL.len()],
+                        |deadline| {
+                            BudgetPhase::ALL.map(|phase| deadline.timing(phase).as_millis() as u64)
+                        },
+                    ),
+                    queries,
+                };
+                // The export is for analytics only, the batch goes on.
+                if let Err(e) = exporter.append(&batch) {
+                    tracing::error!(
+                        "Failed to export the decisions of batch {}: {}",
+                        batch_id,
+                        e
+                    );
+                }
+            }
+            batch_id += 1;
 
             shutdown_handler_bg.decrement_batches_pending_completion();
         }
@@ -1342,8 +2780,8 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     let mut readiness_tx = Some(readiness_tx);
     let all_nodes = config.node_hostnames.clone();
     let _heartbeat = background_tasks.spawn(async move {
-        let next_node = &all_nodes[(config.party_id + 1) % 3];
-        let prev_node = &all_nodes[(config.party_id + 2) % 3];
+        let next_node = &all_nodes[usize::from(party.next_id())];
+        let prev_node = &all_nodes[usize::from(party.prev_id())];
         let mut connected = [false, false];
 
         loop {
@@ -1376,7 +2814,6 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     tracing::info!("⚓️ ANCHOR: Start the main loop");
 
     let processing_timeout = Duration::from_secs(config.processing_timeout_secs);
-    let error_result_attribute = create_message_type_attribute_map(UNIQUENESS_MESSAGE_TYPE);
     let res: eyre::Result<()> = async {
         tracing::info!("Entering main loop");
         // **Tensor format of queries**
@@ -1394,23 +2831,50 @@ async fn server_main(config: Config) -> eyre::Result<()> {
 
         // Skip requests based on the startup sync, only in the first iteration.
         let skip_request_ids = mem::take(&mut skip_request_ids);
-        let shares_encryption_key_pair = shares_encryption_key_pair.clone();
+        let mut intake = RequestIntake::new(&config, poison_message_policy);
+        // Deletion batches not all parties held yet, offered again with the
+        // next batch.
+        let mut pending_deletion_batches: Vec<DeletionBatch> = vec![];
+        let mut memory_monitor =
+            (config.memory_headroom_bytes > 0 || config.memory_critical_bytes > 0).then(|| {
+                MemoryMonitor::new(
+                    Box::new(memory_devices),
+                    batch_cost_model(),
+                    config.memory_headroom_bytes,
+                    config.memory_critical_bytes,
+                    config.max_batch_size,
+                )
+            });
         // This batch can consist of N sets of iris_share + mask
         // It also includes a vector of request ids, mapping to the sets above
+        let mut batch_id = 0;
+        let mut next_batch_span = batch_span(batch_id, party_id);
         let mut next_batch = receive_batch(
             party_id,
-            &sqs_client,
-            &sns_client,
+            &request_queues,
+            &result_publisher,
             &s3_client,
+            &http_client,
             &config,
             &store,
             &skip_request_ids,
-            shares_encryption_key_pair.clone(),
+            &shares_encryption_key_pair,
             &shutdown_handler,
-            &error_result_attribute,
-        );
+            &mut intake,
+            &mut backfill_receiver,
+            &mut memory_monitor,
+            &is_ready_flag_cloned,
+        )
+        .instrument(next_batch_span.clone());
 
         let dummy_shares_for_deletions = get_dummy_shares_for_deletion(party_id);
+        let mut soft_deletions = SoftDeletions::from_pending(
+            store
+                .soft_deleted_irises()
+                .await?
+                .into_iter()
+                .map(|(serial_id, purge_at)| (serial_id as u32, purge_at as u64)),
+        );
 
         loop {
             let now = Instant::now();
@@ -1420,7 +2884,14 @@ async fn server_main(config: Config) -> eyre::Result<()> {
                 tracing::info!("No more batches to process, exiting main loop");
                 return Ok(());
             }
-            let batch = _batch.unwrap();
+            let (mut batch, committed) = _batch.unwrap();
+            // The deferred deletion batches go first, the rest wait for a later
+            // batch.
+            pending_deletion_batches.append(&mut batch.deletion_batches);
+            let offered = pending_deletion_batches.len().min(MAX_DELETION_BATCHES);
+            batch.deletion_batches = pending_deletion_batches.drain(..offered).collect();
+            let shed = batch.shed_entries.iter().any(|&shed| shed);
+            let current_batch_span = next_batch_span;
 
             // start trace span - with single TraceId and single ParentTraceID
             tracing::info!("Received batch in {:?}", now.elapsed());
@@ -1430,10 +2901,57 @@ async fn server_main(config: Config) -> eyre::Result<()> {
             process_identity_deletions(
                 &batch,
                 &store,
+                &mut soft_deletions,
+                &dummy_shares_for_deletions.0,
+                &dummy_shares_for_deletions.1,
+            )
+            .instrument(Phase::Persist.child_of(&current_batch_span))
+            .await?;
+            process_identity_restores(&mut batch, &store, &mut soft_deletions, party_id)
+                .instrument(Phase::Persist.child_of(&current_batch_span))
+                .await?;
+            process_reset_updates(&batch, &store, &mut soft_deletions)
+                .instrument(Phase::Persist.child_of(&current_batch_span))
+                .await?;
+            // Offered after the restores and updates of this batch, so that a
+            // restored entry is not purged.
+            batch.purge_due = soft_deletions.due(unix_now_secs());
+            // Checked after the deletions of this batch, so that a reauth never
+            // matches an entry deleted with it.
+            let rejected_reauths = check_reauth_targets(
+                &mut batch,
+                &store,
+                &soft_deletions,
+                &dummy_shares_for_deletions.0,
+                &dummy_shares_for_deletions.1,
+                party_id,
+            )
+            .instrument(Phase::Persist.child_of(&current_batch_span))
+            .await?;
+            drop_unknown_or_rule_serial_ids(
+                &mut batch,
+                &store,
+                &soft_deletions,
                 &dummy_shares_for_deletions.0,
                 &dummy_shares_for_deletions.1,
             )
+            .instrument(Phase::Persist.child_of(&current_batch_span))
             .await?;
+            for (index, result) in rejected_reauths {
+                tracing::warn!(
+                    "Rejecting reauth {}: unknown target serial ids {:?}",
+                    result.reauth_id,
+                    result.unknown_serial_ids
+                );
+                metrics::counter!("reauth.unknown_target").increment(1);
+                publish_error_result(
+                    serde_json::to_string(&result)?,
+                    REAUTH_MESSAGE_TYPE,
+                    trace_context(&batch.metadata[index]).as_ref(),
+                    &result_publisher,
+                )
+                .await?;
+            }
 
             // Iterate over a list of tracing payloads, and create logs with mappings to
             // payloads Log at least a "start" event using a log with trace.id and
@@ -1449,25 +2967,118 @@ async fn server_main(config: Config) -> eyre::Result<()> {
 
             background_tasks.check_tasks();
 
-            let result_future = handle.submit_batch_query(batch);
+            // Kept to run the batch again if it fails.
+            let retry_batch = batch.clone();
+            let retry_span = current_batch_span.clone();
+            let result_future = handle
+                .try_submit_batch_query(batch)
+                .instrument(current_batch_span);
+
+            // await the result
+            let outcome = timeout(processing_timeout, result_future.await).await;
+            let result = match outcome {
+                Ok(Ok(result)) => result,
+                failed => {
+                    match failed {
+                        Ok(Err(e)) => tracing::error!("Batch failed: {:?}", e),
+                        _ => {
+                            tracing::error!("ServerActor processing timeout");
+                            // Returns a batch stuck in a collective.
+                            handle.abort_network();
+                        }
+                    }
+                    retry_batch_after_reinit(&mut handle, retry_batch, processing_timeout)
+                        .instrument(retry_span)
+                        .await?
+                }
+            };
+
+            // Entries shed at any party were not processed, hand them to the
+            // next batch. Entries not all parties held yet wait for them in the
+            // queues, until they were deferred too often.
+            let receivers: Vec<_> = request_queues
+                .iter()
+                .map(|(receiver, _)| receiver)
+                .collect();
+            let settled = intake
+                .settle(
+                    &committed,
+                    &result.requeued_request_ids,
+                    &result.deferred_request_ids,
+                    &receivers,
+                )
+                .await?;
+            report_shedding(shed, settled.shed);
+            if !committed.is_empty() {
+                store
+                    .mark_requests_deleted(&settled.completed_request_ids(&committed))
+                    .await
+                    .map_err(ReceiveRequestError::FailedToMarkRequestAsDeleted)?;
+            }
+            // Only now the messages of the batch leave the queues. A party that
+            // fails before receives them again.
+            intake.complete(&committed, &receivers).await?;
+
+            purge_soft_deletions(
+                &result.purged_serial_ids,
+                &store,
+                &mut soft_deletions,
+                &dummy_shares_for_deletions.0,
+                &dummy_shares_for_deletions.1,
+            )
+            .await?;
+
+            persist_deletion_batches(
+                &result.applied_deletion_batches,
+                &store,
+                &mut soft_deletions,
+                &dummy_shares_for_deletions.0,
+                &dummy_shares_for_deletions.1,
+            )
+            .await?;
+            let (deferred_deletion_batches, dropped_deletion_batches) = intake
+                .defer_deletion_batches(
+                    result
+                        .applied_deletion_batches
+                        .iter()
+                        .map(|applied| applied.batch.message_id.as_str()),
+                    result.deferred_deletion_batches.clone(),
+                    |deletion_batch| deletion_batch.message_id.as_str(),
+                );
+            pending_deletion_batches.splice(0..0, deferred_deletion_batches);
+            for deletion_batch in dropped_deletion_batches {
+                publish_deletion_batch_not_agreed(
+                    &deletion_batch.message_id,
+                    deletion_batch.serial_ids,
+                    trace_context(&deletion_batch.metadata).as_ref(),
+                    &result_publisher,
+                    &config,
+                )
+                .await?;
+            }
+            for request_id in settled.dropped_request_ids {
+                publish_request_not_agreed(request_id, &result_publisher, &config).await?;
+            }
 
+            batch_id += 1;
+            next_batch_span = batch_span(batch_id, party_id);
             next_batch = receive_batch(
                 party_id,
-                &sqs_client,
-                &sns_client,
+                &request_queues,
+                &result_publisher,
                 &s3_client,
+                &http_client,
                 &config,
                 &store,
                 &skip_request_ids,
-                shares_encryption_key_pair.clone(),
+                &shares_encryption_key_pair,
                 &shutdown_handler,
-                &error_result_attribute,
-            );
-
-            // await the result
-            let result = timeout(processing_timeout, result_future.await)
-                .await
-                .map_err(|e| eyre!("ServerActor processing timeout: {:?}", e))?;
+                &mut intake,
+                &mut backfill_receiver,
+                &mut memory_monitor,
+                &is_ready_flag_cloned,
+            )
+            .instrument(next_batch_span.clone());
 
             tx.send(result).await?;
 
@@ -1503,9 +3114,17 @@ async fn server_main(config: Config) -> eyre::Result<()> {
     Ok(())
 }
 
+fn unix_now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the unix epoch")
+        .as_secs()
+}
+
 async fn process_identity_deletions(
     batch: &BatchQuery,
     store: &Store,
+    soft_deletions: &mut SoftDeletions,
     dummy_iris_share: &GaloisRingIrisCodeShare,
     dummy_mask_share: &GaloisRingTrimmedMaskCodeShare,
 ) -> eyre::Result<()> {
@@ -1513,12 +3132,13 @@ async fn process_identity_deletions(
         return Ok(());
     }
 
-    for (&entry_idx, tracing_payload) in batch
+    for (i, (&entry_idx, tracing_payload)) in batch
         .deletion_requests_indices
         .iter()
         .zip(batch.deletion_requests_metadata.iter())
+        .enumerate()
     {
-        let serial_id = entry_idx + 1; // DB serial_id is 1-indexed
+        let serial_id = entry_idx.wrapping_add(1); // DB serial_id is 1-indexed
         tracing::info!(
             node_id = tracing_payload.node_id,
             dd.trace_id = tracing_payload.trace_id,
@@ -1526,6 +3146,26 @@ async fn process_identity_deletions(
             "Started processing deletion request",
         );
 
+        // Keep the shares of a soft-delete until its grace period is over.
+        if let Some(purge_at) = batch.deletion_requests_purge_at.get(i).copied().flatten() {
+            if store
+                .soft_delete_iris(serial_id as i64, purge_at as i64)
+                .await?
+            {
+                soft_deletions.soft_delete(serial_id, purge_at);
+                tracing::info!(
+                    node_id = tracing_payload.node_id,
+                    dd.trace_id = tracing_payload.trace_id,
+                    dd.span_id = tracing_payload.span_id,
+                    "Soft-deleted identity with serial id {} until {}",
+                    serial_id,
+                    purge_at,
+                );
+            }
+            continue;
+        }
+        soft_deletions.purged(&[serial_id]);
+
         // overwrite postgres db with dummy values.
         // note that both serial_id and postgres db are 1-indexed.
         store
@@ -1549,3 +3189,263 @@ async fn process_identity_deletions(
 
     Ok(())
 }
+
+/// Overwrites the entries of the deletion batches all parties applied with
+/// dummy shares. Like single deletions without a grace period, they cancel
+/// pending soft-deletes of the same serial ids.
+async fn persist_deletion_batches(
+    applied_deletion_batches: &[AppliedDeletionBatch],
+    store: &Store,
+    soft_deletions: &mut SoftDeletions,
+    dummy_iris_share: &GaloisRingIrisCodeShare,
+    dummy_mask_share: &GaloisRingTrimmedMaskCodeShare,
+) -> eyre::Result<()> {
+    for applied in applied_deletion_batches {
+        let tracing_payload = &applied.batch.metadata;
+        for &entry_idx in applied.deleted_ids.iter() {
+            let serial_id = entry_idx + 1; // DB serial_id is 1-indexed
+            soft_deletions.purged(&[serial_id]);
+            store
+                .update_iris(
+                    serial_id as i64,
+                    dummy_iris_share,
+                    dummy_mask_share,
+                    dummy_iris_share,
+                    dummy_mask_share,
+                )
+                .await?;
+        }
+        tracing::info!(
+            node_id = tracing_payload.node_id,
+            dd.trace_id = tracing_payload.trace_id,
+            dd.span_id = tracing_payload.span_id,
+            "Deleted {} identities of deletion batch {}, {} serial ids not found",
+            applied.deleted_ids.len(),
+            applied.batch.message_id,
+            applied.unknown_ids.len(),
+        );
+    }
+    Ok(())
+}
+
+/// Marks the reauth entries with a target that is deleted or beyond the
+/// database as invalid, and returns their error results. Deleted entries hold
+/// the dummy shares, or are soft-deleted.
+async fn check_reauth_targets(
+    batch: &mut BatchQuery,
+    store: &Store,
+    soft_deletions: &SoftDeletions,
+    dummy_iris_share: &GaloisRingIrisCodeShare,
+    dummy_mask_share: &GaloisRingTrimmedMaskCodeShare,
+    party_id: usize,
+) -> eyre::Result<Vec<(usize, ReAuthResult)>> {
+    let mut rejected = vec![];
+    for i in 0..batch.request_ids.len() {
+        let Some(Some(targets)) = batch.reauth_targets.get(i) else {
+            continue;
+        };
+        if !batch.valid_entries[i] {
+            continue;
+        }
+        // serial_id is 1-indexed, so the index of 0 maps back to it.
+        let serial_ids = targets
+            .iter()
+            .map(|x| x.wrapping_add(1))
+            .collect::<Vec<_>>();
+        let known = store
+            .fetch_irises(&serial_ids)
+            .await?
+            .into_iter()
+            .filter(|iris| {
+                iris.left_code() != dummy_iris_share.coefs.as_slice()
+                    || iris.left_mask() != dummy_mask_share.coefs.as_slice()
+            })
+            .map(|iris| iris.id() as u32)
+            .collect::<HashSet<_>>();
+        let unknown = serial_ids
+            .iter()
+            .copied()
+            .filter(|id| !known.contains(id) || soft_deletions.is_soft_deleted(*id))
+            .collect::<Vec<_>>();
+        if unknown.is_empty() {
+            continue;
+        }
+        batch.valid_entries[i] = false;
+        let mut result = ReAuthResult::error(
+            party_id,
+            batch.request_ids[i].clone(),
+            serial_ids,
+            ErrorCode::UnknownReauthTarget,
+        );
+        result.unknown_serial_ids = Some(unknown);
+        rejected.push((i, result));
+    }
+    Ok(rejected)
+}
+
+/// Leaves the OR-rule serial ids that are deleted or beyond the database out
+/// of the batch entries, with a warning. Deleted entries hold the dummy
+/// shares, or are soft-deleted.
+async fn drop_unknown_or_rule_serial_ids(
+    batch: &mut BatchQuery,
+    store: &Store,
+    soft_deletions: &SoftDeletions,
+    dummy_iris_share: &GaloisRingIrisCodeShare,
+    dummy_mask_share: &GaloisRingTrimmedMaskCodeShare,
+) -> eyre::Result<()> {
+    for i in 0..batch.request_ids.len() {
+        let Some(Some(indices)) = batch.or_rule_indices.get_mut(i) else {
+            continue;
+        };
+        if indices.is_empty() {
+            continue;
+        }
+        // serial_id is 1-indexed, so the index of 0 maps back to it.
+        let serial_ids = indices
+            .iter()
+            .map(|x| x.wrapping_add(1))
+            .collect::<Vec<_>>();
+        let known = store
+            .fetch_irises(&serial_ids)
+            .await?
+            .into_iter()
+            .filter(|iris| {
+                iris.left_code() != dummy_iris_share.coefs.as_slice()
+                    || iris.left_mask() != dummy_mask_share.coefs.as_slice()
+            })
+            .map(|iris| iris.id() as u32)
+            .filter(|&id| !soft_deletions.is_soft_deleted(id))
+            .collect::<HashSet<_>>();
+        let (kept, unknown): (Vec<_>, Vec<_>) =
+            serial_ids.into_iter().partition(|id| known.contains(id));
+        if unknown.is_empty() {
+            continue;
+        }
+        tracing::warn!(
+            "Ignoring OR-rule serial ids of {} that are deleted or unknown: {:?}",
+            batch.request_ids[i],
+            unknown
+        );
+        metrics::counter!("or_rule.unknown_serial_ids").increment(unknown.len() as u64);
+        *indices = kept.into_iter().map(|id| id - 1).collect();
+    }
+    Ok(())
+}
+
+/// Clears the soft-delete flag of the entries to restore and hands their kept
+/// shares to the actor. Entries that are not soft-deleted are left out, the
+/// actor reports them as unknown.
+async fn process_identity_restores(
+    batch: &mut BatchQuery,
+    store: &Store,
+    soft_deletions: &mut SoftDeletions,
+    party_id: usize,
+) -> eyre::Result<()> {
+    for (&entry_idx, tracing_payload) in batch
+        .restore_requests_indices
+        .iter()
+        .zip(batch.restore_requests_metadata.iter())
+    {
+        let serial_id = entry_idx.wrapping_add(1); // DB serial_id is 1-indexed
+        if !soft_deletions.restore(serial_id) {
+            continue;
+        }
+        let Some(iris) = store.restore_iris(serial_id as i64).await? else {
+            continue;
+        };
+        batch
+            .restored_entries
+            .push(restored_entry(entry_idx, &iris, party_id)?);
+        tracing::info!(
+            node_id = tracing_payload.node_id,
+            dd.trace_id = tracing_payload.trace_id,
+            dd.span_id = tracing_payload.span_id,
+            "Restored identity with serial id {}",
+            serial_id,
+        );
+    }
+    Ok(())
+}
+
+/// Stores the shares of the reset updates. An updated entry that was
+/// soft-deleted is live again. Entries beyond the database are reported as
+/// unknown by the actor.
+async fn process_reset_updates(
+    batch: &BatchQuery,
+    store: &Store,
+    soft_deletions: &mut SoftDeletions,
+) -> eyre::Result<()> {
+    for (entry, tracing_payload) in batch
+        .reset_updates
+        .iter()
+        .zip(batch.reset_updates_metadata.iter())
+    {
+        let serial_id = entry.index.wrapping_add(1); // DB serial_id is 1-indexed
+        store
+            .update_iris(
+                serial_id as i64,
+                &entry.left_code,
+                &entry.left_mask,
+                &entry.right_code,
+                &entry.right_mask,
+            )
+            .await?;
+        soft_deletions.restore(serial_id);
+        tracing::info!(
+            node_id = tracing_payload.node_id,
+            dd.trace_id = tracing_payload.trace_id,
+            dd.span_id = tracing_payload.span_id,
+            "Updated identity with serial id {}",
+            serial_id,
+        );
+    }
+    Ok(())
+}
+
+fn restored_entry(index: u32, iris: &StoredIris, party_id: usize) -> eyre::Result<RestoredEntry> {
+    let code = |coefs: &[u16]| -> eyre::Result<GaloisRingIrisCodeShare> {
+        Ok(GaloisRingIrisCodeShare {
+            id:    party_id + 1,
+            coefs: coefs.try_into().wrap_err("Invalid restored code share")?,
+        })
+    };
+    let mask = |coefs: &[u16]| -> eyre::Result<GaloisRingTrimmedMaskCodeShare> {
+        Ok(GaloisRingTrimmedMaskCodeShare {
+            id:    party_id + 1,
+            coefs: coefs.try_into().wrap_err("Invalid restored mask share")?,
+        })
+    };
+    Ok(RestoredEntry {
+        index,
+        left_code: code(iris.left_code())?,
+        left_mask: mask(iris.left_mask())?,
+        right_code: code(iris.right_code())?,
+        right_mask: mask(iris.right_mask())?,
+    })
+}
+
+/// Finalizes the soft-deletes the parties agreed to purge. Their entries are
+/// tombstoned in memory already, only the kept shares are overwritten.
+async fn purge_soft_deletions(
+    serial_ids: &[u32],
+    store: &Store,
+    soft_deletions: &mut SoftDeletions,
+    dummy_iris_share: &GaloisRingIrisCodeShare,
+    dummy_mask_share: &GaloisRingTrimmedMaskCodeShare,
+) -> eyre::Result<()> {
+    for &serial_id in serial_ids {
+        store
+            .update_iris(
+                serial_id as i64,
+                dummy_iris_share,
+                dummy_mask_share,
+                dummy_iris_share,
+                dummy_mask_share,
+            )
+            .await?;
+        tracing::info!("Purged soft-deleted identity with serial id {}", serial_id);
+        metrics::counter!("soft_delete.purged").increment(1);
+    }
+    soft_deletions.purged(serial_ids);
+    Ok(())
+}